@@ -0,0 +1,310 @@
+//! Named B-trees kept in a database file alongside the by-id, by-seq and
+//! local document trees, so embedders can maintain their own secondary
+//! indexes which commit atomically with the documents. Their roots follow
+//! the other roots in the header, leaving files without any unchanged.
+
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+use std::{collections::BTreeMap, io};
+
+use crate::{
+    btree::CouchfileLookupRequest,
+    btree_modify::{CouchfileModifyAction, CouchfileModifyActionType, CouchfileModifyRequest},
+    constants::{MAX_DB_HEADER_SIZE, MAX_KEY_SIZE},
+    reduce::Reducer,
+    CouchstoreError, CouchstoreResult, Db, NodePointer, ROOT_BASE_SIZE,
+};
+
+/// Names are stored with a one byte length
+const MAX_TREE_NAME_SIZE: usize = 255;
+
+pub(crate) type AuxRoots = BTreeMap<String, Option<NodePointer>>;
+
+impl Db {
+    /// The names of the file's auxiliary trees, in order
+    pub fn aux_tree_names(&self) -> Vec<&str> {
+        self.header.aux_roots.keys().map(String::as_str).collect()
+    }
+
+    /// Add an empty auxiliary tree, unless one of the name exists. Like
+    /// document changes it isn't durable until the next commit.
+    pub fn create_aux_tree(&mut self, name: &str) -> CouchstoreResult<()> {
+        if name.len() > MAX_TREE_NAME_SIZE {
+            return Err(CouchstoreError::TooBig("tree name"));
+        }
+        if self.header.aux_roots.contains_key(name) {
+            return Ok(());
+        }
+        self.header.aux_roots.insert(name.to_string(), None);
+        // Leaving room for the trees' roots to be filled in
+        let (header_size, ..) = self.calculate_header_size();
+        if header_size + self.header.aux_roots.len() * 2 * ROOT_BASE_SIZE > MAX_DB_HEADER_SIZE {
+            self.header.aux_roots.remove(name);
+            return Err(CouchstoreError::TooBig("header"));
+        }
+        Ok(())
+    }
+
+    /// Remove an auxiliary tree and everything in it, returning whether it
+    /// existed
+    pub fn drop_aux_tree(&mut self, name: &str) -> bool {
+        self.header.aux_roots.remove(name).is_some()
+    }
+
+    /// Insert the keys with a value and remove those without. When a key is
+    /// given more than once the last change wins.
+    pub fn modify_aux_tree(
+        &mut self,
+        name: &str,
+        changes: Vec<(Vec<u8>, Option<Vec<u8>>)>,
+    ) -> CouchstoreResult<()> {
+        let root = self.aux_root(name)?.clone();
+        if changes.iter().any(|(key, _)| key.len() > MAX_KEY_SIZE) {
+            return Err(CouchstoreError::TooBig("key"));
+        }
+
+        let mut actions: Vec<CouchfileModifyAction> = changes
+            .into_iter()
+            .rev()
+            .map(|(key, data)| CouchfileModifyAction {
+                key,
+                action_type: if data.is_some() {
+                    CouchfileModifyActionType::Insert
+                } else {
+                    CouchfileModifyActionType::Remove
+                },
+                data,
+            })
+            .collect();
+        // Stable, so the first of each key is the last change given
+        actions.sort_by(|a, b| a.key.cmp(&b.key));
+        actions.dedup_by(|a, b| a.key == b.key);
+
+        let req = CouchfileModifyRequest {
+            actions,
+            context: (),
+            tuning: self.opts.tuning,
+            reducer: Reducer::None,
+        };
+        let root = self.file.modify_btree(req, root)?;
+        self.header.aux_roots.insert(name.to_string(), root);
+        Ok(())
+    }
+
+    pub fn get_aux(&self, name: &str, key: &[u8]) -> CouchstoreResult<Option<Vec<u8>>> {
+        let Some(root) = self.aux_root(name)?.clone() else {
+            return Ok(None);
+        };
+        let mut req = CouchfileLookupRequest::new(vec![key.to_vec()]);
+        let mut found = None;
+        self.btree_lookup(
+            &mut req,
+            |_, _, value| {
+                found = value.map(<[u8]>::to_vec);
+                Ok(())
+            },
+            root.pointer as usize,
+        )?;
+        Ok(found)
+    }
+
+    /// Call back with each key from start_key onwards and its value, in key
+    /// order
+    pub fn fold_aux_tree(
+        &self,
+        name: &str,
+        start_key: &[u8],
+        mut on_fetch: impl FnMut(&Self, &[u8], &[u8]) -> CouchstoreResult<()>,
+    ) -> CouchstoreResult<()> {
+        let Some(root) = self.aux_root(name)?.clone() else {
+            return Ok(());
+        };
+        let mut req = CouchfileLookupRequest::new(vec![start_key.to_vec()]).fold();
+        self.btree_lookup(
+            &mut req,
+            |db, key, value| match value {
+                Some(value) => on_fetch(db, key, value),
+                None => Ok(()),
+            },
+            root.pointer as usize,
+        )
+    }
+
+    /// As fold_aux_tree, stopping after end_key
+    pub fn fold_aux_tree_range(
+        &self,
+        name: &str,
+        start_key: &[u8],
+        end_key: &[u8],
+        mut on_fetch: impl FnMut(&Self, &[u8], &[u8]) -> CouchstoreResult<()>,
+    ) -> CouchstoreResult<()> {
+        let Some(root) = self.aux_root(name)?.clone() else {
+            return Ok(());
+        };
+        let mut req =
+            CouchfileLookupRequest::new(vec![start_key.to_vec(), end_key.to_vec()]).fold();
+        self.btree_lookup(
+            &mut req,
+            |db, key, value| match value {
+                Some(value) => on_fetch(db, key, value),
+                None => Ok(()),
+            },
+            root.pointer as usize,
+        )
+    }
+
+    fn aux_root(&self, name: &str) -> CouchstoreResult<&Option<NodePointer>> {
+        self.header
+            .aux_roots
+            .get(name)
+            .ok_or_else(|| CouchstoreError::NoSuchTree(name.to_string()))
+    }
+}
+
+/// The number of trees, then each one's name and root, sized like the
+/// other roots. Nothing at all without any trees.
+pub(crate) fn encode_aux_roots(roots: &AuxRoots, mut buf: impl io::Write) -> io::Result<()> {
+    if roots.is_empty() {
+        return Ok(());
+    }
+    buf.write_u16::<BigEndian>(roots.len() as u16)?;
+    for (name, root) in roots {
+        buf.write_u8(name.len() as u8)?;
+        buf.write_all(name.as_bytes())?;
+        match root {
+            Some(root) => {
+                buf.write_u16::<BigEndian>((ROOT_BASE_SIZE + root.reduce_value.len()) as u16)?;
+                root.encode_root(&mut buf)?;
+            }
+            None => buf.write_u16::<BigEndian>(0)?,
+        }
+    }
+    Ok(())
+}
+
+pub(crate) fn encoded_aux_roots_size(roots: &AuxRoots) -> usize {
+    if roots.is_empty() {
+        return 0;
+    }
+    2 + roots
+        .iter()
+        .map(|(name, root)| {
+            let root_size = root
+                .as_ref()
+                .map_or(0, |root| ROOT_BASE_SIZE + root.reduce_value.len());
+            1 + name.len() + 2 + root_size
+        })
+        .sum::<usize>()
+}
+
+/// Decode the roots following the standard ones, which must fill buf
+pub(crate) fn decode_aux_roots(mut buf: &[u8]) -> CouchstoreResult<AuxRoots> {
+    let mut roots = AuxRoots::new();
+    if buf.is_empty() {
+        return Ok(roots);
+    }
+    let truncated = |_| CouchstoreError::Corrupt("truncated auxiliary tree roots");
+    let count = buf.read_u16::<BigEndian>().map_err(truncated)?;
+    for _ in 0..count {
+        let name_len = buf.read_u8().map_err(truncated)? as usize;
+        if buf.len() < name_len {
+            return Err(CouchstoreError::Corrupt("truncated auxiliary tree roots"));
+        }
+        let (name, rest) = buf.split_at(name_len);
+        let name = String::from_utf8(name.to_vec())
+            .map_err(|_| CouchstoreError::Corrupt("auxiliary tree name not UTF-8"))?;
+        buf = rest;
+        let root_size = buf.read_u16::<BigEndian>().map_err(truncated)? as usize;
+        if buf.len() < root_size {
+            return Err(CouchstoreError::Corrupt("truncated auxiliary tree roots"));
+        }
+        let (root, rest) = buf.split_at(root_size);
+        roots.insert(name, NodePointer::read_root(root, root_size)?);
+        buf = rest;
+    }
+    if !buf.is_empty() {
+        return Err(CouchstoreError::Corrupt("header size mismatch"));
+    }
+    Ok(roots)
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{CouchstoreError, DBOpenOptions, Db};
+
+    #[test]
+    fn test_aux_trees() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("0.couch.1");
+        let mut db = Db::open(&path, DBOpenOptions::default()).unwrap();
+        db.set(Vec::from("doc"), Vec::from("value")).unwrap();
+        db.commit();
+        let plain_len = std::fs::metadata(&path).unwrap().len();
+
+        assert!(matches!(
+            db.modify_aux_tree("expiry", vec![]),
+            Err(CouchstoreError::NoSuchTree(_))
+        ));
+        db.create_aux_tree("expiry").unwrap();
+        db.create_aux_tree("empty").unwrap();
+        let changes = (0..1000u32)
+            .map(|i| {
+                (
+                    i.to_be_bytes().to_vec(),
+                    Some(format!("doc_{i}").into_bytes()),
+                )
+            })
+            .collect();
+        db.modify_aux_tree("expiry", changes).unwrap();
+        // The last change to a key wins
+        db.modify_aux_tree(
+            "expiry",
+            vec![
+                (1u32.to_be_bytes().to_vec(), Some(Vec::from("first"))),
+                (1u32.to_be_bytes().to_vec(), None),
+                (2u32.to_be_bytes().to_vec(), Some(Vec::from("replaced"))),
+            ],
+        )
+        .unwrap();
+        db.commit();
+        assert!(std::fs::metadata(&path).unwrap().len() > plain_len);
+
+        let mut db = Db::open(&path, DBOpenOptions::default()).unwrap();
+        assert_eq!(db.aux_tree_names(), vec!["empty", "expiry"]);
+        assert!(db.docinfo_by_id("doc").unwrap().is_some());
+        assert_eq!(db.get_aux("expiry", &1u32.to_be_bytes()).unwrap(), None);
+        assert_eq!(
+            db.get_aux("expiry", &2u32.to_be_bytes()).unwrap(),
+            Some(Vec::from("replaced"))
+        );
+        assert_eq!(db.get_aux("empty", b"key").unwrap(), None);
+
+        let mut keys = Vec::new();
+        db.fold_aux_tree("expiry", &990u32.to_be_bytes(), |_, key, value| {
+            let key = u32::from_be_bytes(key.try_into().unwrap());
+            assert_eq!(value, format!("doc_{key}").as_bytes());
+            keys.push(key);
+            Ok(())
+        })
+        .unwrap();
+        assert_eq!(keys, (990..1000).collect::<Vec<_>>());
+
+        let mut keys = Vec::new();
+        db.fold_aux_tree_range(
+            "expiry",
+            &100u32.to_be_bytes(),
+            &700u32.to_be_bytes(),
+            |_, key, _| {
+                keys.push(u32::from_be_bytes(key.try_into().unwrap()));
+                Ok(())
+            },
+        )
+        .unwrap();
+        assert_eq!(keys, (100..=700).collect::<Vec<_>>());
+
+        assert!(db.drop_aux_tree("expiry"));
+        assert!(!db.drop_aux_tree("expiry"));
+        db.commit();
+        let db = Db::open(&path, DBOpenOptions::default().read_only()).unwrap();
+        assert_eq!(db.aux_tree_names(), vec!["empty"]);
+    }
+}
@@ -0,0 +1,49 @@
+use couchstore::{DBOpenOptions, Db};
+use std::process::exit;
+
+/// Print each vbucket file's header summary and how fragmented it is
+fn main() {
+    let paths: Vec<String> = std::env::args().skip(1).collect();
+    if paths.is_empty() {
+        println!("Usage: couch_dbinfo <file.couch.N>...");
+        exit(1);
+    }
+
+    let mut failed = false;
+    for path in &paths {
+        let db = match Db::open(path, DBOpenOptions::default().read_only()) {
+            Ok(db) => db,
+            Err(e) => {
+                println!("Failed to open {path}: {e}");
+                failed = true;
+                continue;
+            }
+        };
+        let info = db.info();
+        println!("DB: {path}");
+        println!("   disk version: {}", u8::from(db.header().disk_version()));
+        println!("   block size: {}", db.header().block_size());
+        println!("   update_seq: {}", info.update_seq);
+        println!("   purge_seq: {}", info.purge_seq);
+        match info.doc_counts {
+            Some(counts) => {
+                println!("   doc count: {}", counts.live);
+                println!("   deleted doc count: {}", counts.deleted);
+            }
+            None => println!("   doc count: unknown, the file has no reductions"),
+        }
+        println!("   file size: {} bytes", info.file_size);
+        if let Some(used) = info.space_used {
+            println!("   space used: {used} bytes");
+        }
+        if let Some(fragmentation) = info.fragmentation() {
+            println!("   fragmentation: {:.1}%", fragmentation * 100.0);
+        }
+        if let Some(amplification) = info.space_amplification() {
+            println!("   space amplification: {amplification:.2}x");
+        }
+    }
+    if failed {
+        exit(1);
+    }
+}
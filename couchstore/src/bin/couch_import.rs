@@ -0,0 +1,103 @@
+use couchstore::{ContentMetaFlag, DBOpenOptions, Db, Doc, DocInfo, SaveOptions};
+use serde_json::Value;
+use std::{
+    io::{BufRead, BufReader},
+    process::exit,
+    time::Instant,
+};
+
+fn usage() -> ! {
+    println!("Usage: couch_import [--id-field <field>] <file.couch> [documents.jsonl]");
+    exit(1);
+}
+
+fn fail(message: String) -> ! {
+    println!("{message}");
+    exit(1);
+}
+
+/// Fail part way through the import, removing the partial file
+fn abandon(path: &str, message: String) -> ! {
+    let _ = std::fs::remove_file(path);
+    fail(message)
+}
+
+/// Import JSON documents, one per line, into a new couchstore file, each
+/// keyed by the string in its id field. The file's trees are built bottom-up
+/// in one pass rather than saved batch by batch.
+fn main() {
+    let mut id_field = "id".to_string();
+    let mut paths = Vec::new();
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--id-field" => id_field = args.next().unwrap_or_else(|| usage()),
+            _ if arg.starts_with("--") => usage(),
+            _ => paths.push(arg),
+        }
+    }
+    let (path, input) = match &paths[..] {
+        [path] => (path, None),
+        [path, input] => (path, Some(input)),
+        _ => usage(),
+    };
+    if std::path::Path::new(path).exists() {
+        fail(format!("{path} already exists"));
+    }
+    let input: Box<dyn BufRead> = match input {
+        Some(input) => match std::fs::File::open(input) {
+            Ok(file) => Box::new(BufReader::new(file)),
+            Err(e) => fail(format!("Failed to open {input}: {e}")),
+        },
+        None => Box::new(std::io::stdin().lock()),
+    };
+
+    let start = Instant::now();
+    let mut loader = Db::open(path, DBOpenOptions::default())
+        .and_then(|db| db.bulk_load(SaveOptions::COMPRESS_DOC_BODIES))
+        .unwrap_or_else(|e| fail(format!("Failed to open {path}: {e}")));
+    let mut seq = 0;
+    for (line_no, line) in input.lines().enumerate() {
+        let line = line.unwrap_or_else(|e| abandon(path, format!("Failed to read documents: {e}")));
+        if line.trim().is_empty() {
+            continue;
+        }
+        let value: Value = serde_json::from_str(&line)
+            .unwrap_or_else(|e| abandon(path, format!("Line {}: {e}", line_no + 1)));
+        let Some(id) = value.get(&id_field).and_then(Value::as_str) else {
+            abandon(
+                path,
+                format!("Line {}: no string {id_field} field", line_no + 1),
+            );
+        };
+
+        seq += 1;
+        let data = serde_json::to_vec(&value).unwrap();
+        let info = DocInfo {
+            id: id.as_bytes().to_vec(),
+            db_seq: seq,
+            rev_seq: 1,
+            rev_meta: vec![],
+            deleted: false,
+            content_meta: ContentMetaFlag::IS_JSON | ContentMetaFlag::IS_COMPRESSED,
+            bp: 0,
+            physical_size: data.len() as u32,
+        };
+        let doc = Doc {
+            id: info.id.clone(),
+            data,
+        };
+        if let Err(e) = loader.add(Some(&doc), &info) {
+            abandon(path, format!("Line {}: {e}", line_no + 1));
+        }
+    }
+
+    let result = loader.finish().and_then(|mut db| db.try_commit());
+    if let Err(e) = result {
+        abandon(path, format!("Failed to import into {path}: {e}"));
+    }
+    println!(
+        "Imported {seq} documents into {path} in {:.1}s",
+        start.elapsed().as_secs_f64()
+    );
+}
@@ -0,0 +1,86 @@
+use couchstore::{ContentMetaFlag, DBOpenOptions, Db, DiskVersion, Doc, DocInfo, SaveOptions};
+use std::{path::Path, process::exit};
+
+/// Keys of each scheme, sorted as the by-id tree holds them
+fn keys(scheme: &str, count: u64) -> Vec<Vec<u8>> {
+    let mut keys: Vec<Vec<u8>> = (0..count)
+        .map(|i| match scheme {
+            // A collection ID prefix, as the engine writes keys
+            "collection" => format!("\x08airline_{i}").into_bytes(),
+            "reverse-dns" => format!("com.example.service{}.user.{i}", i % 16).into_bytes(),
+            // Hashed, so neighbouring keys share little
+            _ => format!("{:016x}", i.wrapping_mul(0x9e37_79b9_7f4a_7c15)).into_bytes(),
+        })
+        .collect();
+    keys.sort();
+    keys
+}
+
+fn write(path: &Path, keys: &[Vec<u8>], version: DiskVersion) -> Db {
+    let _ = std::fs::remove_file(path);
+    let mut db = Db::open(path, DBOpenOptions::default().disk_version(version)).unwrap();
+    for (batch, chunk) in keys.chunks(1000).enumerate() {
+        let docs = chunk
+            .iter()
+            .map(|key| {
+                Some(Doc {
+                    id: key.clone(),
+                    data: b"{}".to_vec(),
+                })
+            })
+            .collect();
+        let infos = chunk
+            .iter()
+            .enumerate()
+            .map(|(i, key)| DocInfo {
+                id: key.clone(),
+                db_seq: (batch * 1000 + i) as u64 + 1,
+                rev_seq: 1,
+                rev_meta: vec![0; 18],
+                deleted: false,
+                content_meta: ContentMetaFlag::IS_JSON,
+                bp: 0,
+                physical_size: 2,
+            })
+            .collect();
+        db.save_documents(docs, infos, SaveOptions::SEQUENCE_AS_IS)
+            .unwrap();
+    }
+    db.commit();
+    db
+}
+
+/// Write the same documents with and without front-coded keys, for a few
+/// key schemes, and compare the size of the leaves
+fn main() {
+    let count = match std::env::args().nth(1).map(|count| count.parse()) {
+        None => 100_000,
+        Some(Ok(count)) => count,
+        Some(Err(_)) => {
+            println!("Usage: key_prefix_bench [documents]");
+            exit(1);
+        }
+    };
+    let path = std::env::temp_dir().join(format!("key_prefix_bench.{}", std::process::id()));
+
+    println!(
+        "{:<12} {:>14} {:>14} {:>10}",
+        "keys", "v13 KV bytes", "v14 KV bytes", "reduction"
+    );
+    for scheme in ["collection", "reverse-dns", "hashed"] {
+        let keys = keys(scheme, count);
+        let plain = write(&path, &keys, DiskVersion::Thirteen)
+            .node_stats()
+            .unwrap();
+        let front_coded = write(&path, &keys, DiskVersion::Fourteen)
+            .node_stats()
+            .unwrap();
+        println!(
+            "{scheme:<12} {:>14} {:>14} {:>9.1}%",
+            plain.kv_bytes,
+            front_coded.kv_bytes,
+            100.0 - front_coded.kv_bytes as f64 * 100.0 / plain.kv_bytes as f64
+        );
+    }
+    let _ = std::fs::remove_file(&path);
+}
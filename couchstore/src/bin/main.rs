@@ -1,4 +1,4 @@
-use couchstore::{DBOpenOptions, Db, OpenOptions};
+use couchstore::{DBOpenOptions, Db, DbNameLayout, OpenOptions};
 use serde_json::Value;
 use std::process::exit;
 
@@ -55,23 +55,25 @@ fn main() {
     std::fs::create_dir_all(PATH).unwrap();
 
     let mut db = Db::open(
-        format!("{PATH}/{vbucket}.couch.1"),
+        DbNameLayout::new(PATH).path(vbucket, 1),
         DBOpenOptions::default(),
-    );
+    )
+    .unwrap();
 
     let key = key.as_bytes().to_vec();
 
     match action.as_str() {
         "get" => {
-            let docinfo = db.docinfo_by_id(key).unwrap();
+            let docinfo = db.docinfo_by_id(key).unwrap().unwrap();
             let doc = db
                 .open_doc_with_docinfo(&docinfo, OpenOptions::DECOMPRESS_DOC_BODIES)
+                .unwrap()
                 .unwrap();
             let json = serde_json::from_slice::<Value>(doc.data.as_slice()).unwrap();
             println!("{}", json);
         }
         "set" => {
-            db.set(key, value);
+            db.set(key, value).unwrap();
             db.commit();
         }
         _ => panic!("Invalid action"),
@@ -0,0 +1,62 @@
+use couchstore::ViewFile;
+use std::process::exit;
+
+/// Print a view index file's header, then the rows of each view
+fn main() {
+    let Some(path) = std::env::args().nth(1) else {
+        println!("Usage: view_dump <file.view.N>");
+        exit(1);
+    };
+    let file = ViewFile::open(&path).unwrap_or_else(|e| {
+        println!("Failed to open {path}: {e}");
+        exit(1);
+    });
+
+    let header = file.header().clone();
+    println!("Signature: {}", hex::encode(header.signature));
+    println!("Version: {}", header.version);
+    println!("Partitions: {}", header.num_partitions);
+    println!("Active partitions: {:?}", header.active_partitions);
+    println!("Passive partitions: {:?}", header.passive_partitions);
+    println!("Cleanup partitions: {:?}", header.cleanup_partitions);
+    println!("Indexed seqnos: {:?}", header.seqs);
+    if let Ok(Some(reduction)) = header.id_btree_reduction() {
+        println!("Documents: {}", reduction.kv_count);
+    }
+
+    for view in 0..header.view_btree_states.len() {
+        println!();
+        match header.view_btree_reduction(view) {
+            Ok(Some(reduction)) => {
+                let values: Vec<_> = reduction
+                    .values
+                    .iter()
+                    .map(|value| String::from_utf8_lossy(value))
+                    .collect();
+                println!(
+                    "View {view}: {} rows, reductions {values:?}",
+                    reduction.kv_count
+                );
+            }
+            Ok(None) => println!("View {view}: empty"),
+            Err(e) => println!("View {view}: {e}"),
+        }
+        let result = file.fold_view_btree(view, |row| {
+            let values: Vec<_> = row
+                .values
+                .iter()
+                .map(|value| String::from_utf8_lossy(value))
+                .collect();
+            println!(
+                "  {} {} (partition {}) => {}",
+                String::from_utf8_lossy(&row.key),
+                String::from_utf8_lossy(&row.doc_id),
+                row.partition,
+                values.join(", ")
+            );
+        });
+        if let Err(e) = result {
+            println!("Failed to read view {view}: {e}");
+        }
+    }
+}
@@ -1,17 +1,73 @@
-use std::{cmp::Ordering, collections::VecDeque, fmt::Debug, io::Cursor};
+use std::{cmp::Ordering, collections::VecDeque, fmt::Debug};
 
 use byteorder::WriteBytesExt;
 
 use crate::{
     btree_read::NodeType,
-    node_types::{read_kv, write_kv},
-    NodePointer, TreeFile,
+    constants::{MAX_MIN_FANOUT, MAX_NODE_SIZE, MIN_NODE_SIZE},
+    node_types::{
+        front_coding_saves, write_front_coded_kv, write_kv, RawNode, FRONT_CODED_KV_NODE,
+    },
+    reduce::Reducer,
+    CouchstoreError, CouchstoreResult, NodePointer, TreeFile,
 };
 
+/// The shape of the B-trees written. A node is split once its items'
+/// encoded size passes its target, so larger targets give shallower trees
+/// of bigger nodes. Every node written holds at least min_fanout items
+/// (bar the last of a tree level), so with large keys the tree still
+/// branches out rather than growing a level per few keys.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BtreeTuning {
+    /// Target size in bytes of the leaves
+    pub kv_node_size: usize,
+    /// Target size in bytes of the interior nodes
+    pub kp_node_size: usize,
+    pub min_fanout: usize,
+}
+
+impl Default for BtreeTuning {
+    fn default() -> Self {
+        Self {
+            kv_node_size: 1279,
+            kp_node_size: 1279,
+            min_fanout: 2,
+        }
+    }
+}
+
+impl BtreeTuning {
+    /// Check the targets are within what the format can read back. Nodes
+    /// are read whole, so their size is kept far below the largest chunk
+    /// a reader decompresses even at the largest fan-out of the largest
+    /// keys, and an interior node needs at least two children.
+    pub fn validate(&self) -> CouchstoreResult<()> {
+        let node_sizes = MIN_NODE_SIZE..=MAX_NODE_SIZE;
+        for (name, size) in [
+            ("kv_node_size", self.kv_node_size),
+            ("kp_node_size", self.kp_node_size),
+        ] {
+            if !node_sizes.contains(&size) {
+                return Err(CouchstoreError::InvalidTuning(format!(
+                    "{name} {size} isn't between {MIN_NODE_SIZE} and {MAX_NODE_SIZE}"
+                )));
+            }
+        }
+        if !(2..=MAX_MIN_FANOUT).contains(&self.min_fanout) {
+            return Err(CouchstoreError::InvalidTuning(format!(
+                "min_fanout {} isn't between 2 and {MAX_MIN_FANOUT}",
+                self.min_fanout
+            )));
+        }
+        Ok(())
+    }
+}
+
 #[derive(Debug)]
-pub struct CouchfileModifyResult<'a, Ctx> {
+pub struct CouchfileModifyResult {
     pub node_type: NodeType,
-    pub req: &'a CouchfileModifyRequest<Ctx>,
+    pub tuning: BtreeTuning,
+    pub(crate) reducer: Reducer,
     pub values: VecDeque<Node>,
     pub node_length: usize,
     pub pointers: VecDeque<Node>,
@@ -19,11 +75,12 @@ pub struct CouchfileModifyResult<'a, Ctx> {
     pub compacting: bool,
 }
 
-impl<'a, Ctx> CouchfileModifyResult<'a, Ctx> {
-    fn new(req: &'a CouchfileModifyRequest<Ctx>) -> Self {
+impl CouchfileModifyResult {
+    fn new(tuning: BtreeTuning, reducer: Reducer) -> Self {
         Self {
             node_type: NodeType::default(),
-            req,
+            tuning,
+            reducer,
             values: VecDeque::new(),
             node_length: 0,
             pointers: VecDeque::new(),
@@ -33,24 +90,39 @@ impl<'a, Ctx> CouchfileModifyResult<'a, Ctx> {
     }
 }
 
-trait Modifier: Sized {
-    fn on_fetch(&mut self, req: CouchfileModifyRequest<Self>, key: &[u8], value: &[u8]);
-}
-
+/// Builds a B-tree bottom-up from items added in key order: leaves are
+/// written as they fill and the inner nodes once every item is in, so no
+/// node is ever read back or rewritten.
 #[derive(Debug)]
-pub struct UpdateIdContext {
-    pub seq_actions: Vec<CouchfileModifyAction>,
+pub(crate) struct TreeBuilder {
+    result: CouchfileModifyResult,
 }
 
-impl Modifier for UpdateIdContext {
-    fn on_fetch(&mut self, _req: CouchfileModifyRequest<Self>, _key: &[u8], value: &[u8]) {
-        let old_seq = value[0..6].to_vec();
+impl TreeBuilder {
+    pub(crate) fn new(tuning: BtreeTuning, reducer: Reducer) -> Self {
+        let mut result = CouchfileModifyResult::new(tuning, reducer);
+        result.node_type = NodeType::KVNode;
+        result.modified = true;
+        Self { result }
+    }
 
-        self.seq_actions.push(CouchfileModifyAction {
-            key: old_seq,
-            data: None,
-            action_type: CouchfileModifyActionType::Remove,
-        });
+    /// Add an item, which must sort after every item already added
+    pub(crate) fn add(&mut self, file: &mut TreeFile, key: &[u8], value: &[u8]) {
+        file.mr_push_item(key, value, &mut self.result);
+    }
+
+    /// Write the rest of the tree, returning its root, None if it's empty
+    pub(crate) fn finish(mut self, file: &mut TreeFile) -> Option<NodePointer> {
+        file.flush_mr(&mut self.result);
+        if self.result.pointers.len() <= 1 {
+            return self.result.pointers.pop_front()?.pointer;
+        }
+        // The inner nodes are built as a modify builds a new root
+        let mut root_result = CouchfileModifyResult::new(self.result.tuning, self.result.reducer);
+        root_result.node_type = NodeType::KPNode;
+        root_result.modified = true;
+        file.mr_move_pointers(&mut self.result, &mut root_result);
+        file.finish_root(&mut root_result)
     }
 }
 
@@ -58,8 +130,8 @@ impl Modifier for UpdateIdContext {
 pub struct CouchfileModifyRequest<Ctx> {
     pub actions: Vec<CouchfileModifyAction>,
     pub context: Ctx,
-    pub kv_chunk_threshold: usize,
-    pub kp_chunk_threshold: usize,
+    pub tuning: BtreeTuning,
+    pub(crate) reducer: Reducer,
 }
 
 #[derive(Debug)]
@@ -89,35 +161,34 @@ impl TreeFile {
         &mut self,
         req: CouchfileModifyRequest<Ctx>,
         mut root: Option<NodePointer>,
-    ) -> Option<NodePointer> {
+    ) -> CouchstoreResult<Option<NodePointer>> {
         let num_actions = req.actions.len();
-        let mut root_result = CouchfileModifyResult::new(&req);
+        let mut root_result = CouchfileModifyResult::new(req.tuning, req.reducer);
         root_result.node_type = NodeType::KPNode;
-        self.modify_node(&req, root.as_mut(), 0, num_actions, &mut root_result);
+        self.modify_node(&req, root.as_mut(), 0, num_actions, &mut root_result)?;
 
         let mut new_root = root;
 
         if root_result.modified {
-            if root_result.values.len() > 1 || !root_result.pointers.is_empty() {
+            if root_result.values.is_empty() && root_result.pointers.is_empty() {
+                // Every item was removed
+                new_root = None;
+            } else if root_result.values.len() > 1 || !root_result.pointers.is_empty() {
                 // The root was split
                 // Write it to disk and return the pointer to it.
-                new_root = self.finish_root(&req, &mut root_result);
+                new_root = self.finish_root(&mut root_result);
             } else {
                 new_root = root_result.values.back().unwrap().pointer.clone();
             }
         }
 
-        new_root
+        Ok(new_root)
     }
 
-    fn finish_root<'a, Ctx: Debug>(
-        &mut self,
-        req: &'a CouchfileModifyRequest<Ctx>,
-        root_result: &'a mut CouchfileModifyResult<'a, Ctx>,
-    ) -> Option<NodePointer> {
+    fn finish_root(&mut self, root_result: &mut CouchfileModifyResult) -> Option<NodePointer> {
         let new_root;
 
-        let mut collector = CouchfileModifyResult::new(req);
+        let mut collector = CouchfileModifyResult::new(root_result.tuning, root_result.reducer);
 
         collector.modified = true;
         collector.node_type = NodeType::KPNode;
@@ -144,33 +215,29 @@ impl TreeFile {
         new_root
     }
 
-    pub fn modify_node<'a, Ctx: Debug>(
+    pub fn modify_node<Ctx: Debug>(
         &mut self,
-        req: &'a CouchfileModifyRequest<Ctx>,
+        req: &CouchfileModifyRequest<Ctx>,
         node_pointer: Option<&mut NodePointer>,
         mut start: usize,
         end: usize,
-        dst: &mut CouchfileModifyResult<'a, Ctx>,
-    ) {
-        let mut node_buf = Vec::new();
-
-        if let Some(node_pointer) = &node_pointer {
-            node_buf = self.read_compressed(node_pointer.pointer as usize);
-        }
-
-        let mut cursor = Cursor::new(node_buf.as_ref());
-
-        let mut local_result = CouchfileModifyResult::new(req);
+        dst: &mut CouchfileModifyResult,
+    ) -> CouchstoreResult<()> {
+        let node_buf = match &node_pointer {
+            Some(node_pointer) => self.read_node(node_pointer.pointer as usize)?,
+            // A new tree starts with an empty KV node
+            None => vec![NodeType::KVNode.into()],
+        };
+        let node = RawNode::decode(&node_buf)?;
+        let mut items = node.items.into_iter().peekable();
 
-        if node_pointer.is_none() || node_buf[0] == NodeType::KVNode as u8 {
-            cursor.set_position(1);
+        let mut local_result = CouchfileModifyResult::new(req.tuning, req.reducer);
 
+        if node.node_type == NodeType::KVNode {
             // KV Node
             local_result.node_type = NodeType::KVNode;
 
-            while (cursor.position() as usize) < node_buf.len() {
-                let (cmp_key, value) = read_kv(&mut cursor).unwrap();
-
+            for (cmp_key, value) in items {
                 let mut advance = false;
 
                 while !advance && start < end {
@@ -180,23 +247,45 @@ impl TreeFile {
                             self.maybe_purge_kv(req, cmp_key, value, &mut local_result);
                         }
                         Ordering::Greater => {
-                            local_result.modified = true;
-                            self.mr_push_item(
-                                &req.actions[start].key[..],
-                                &req.actions[start].data.as_ref().unwrap()[..],
-                                &mut local_result,
-                            );
+                            // The action's key isn't in the tree, only inserts
+                            // have anything to do
+                            let action = &req.actions[start];
+                            if matches!(
+                                action.action_type,
+                                CouchfileModifyActionType::Insert
+                                    | CouchfileModifyActionType::FetchInsert
+                            ) {
+                                local_result.modified = true;
+                                self.mr_push_item(
+                                    &action.key[..],
+                                    &action.data.as_ref().unwrap()[..],
+                                    &mut local_result,
+                                );
+                            }
 
                             start += 1;
                             advance = false;
                         }
                         Ordering::Equal => {
-                            local_result.modified = true;
-                            self.mr_push_item(
-                                &req.actions[start].key[..],
-                                &req.actions[start].data.as_ref().unwrap()[..],
-                                &mut local_result,
-                            );
+                            let action = &req.actions[start];
+                            match action.action_type {
+                                CouchfileModifyActionType::Remove => {
+                                    // Drop the existing item
+                                    local_result.modified = true;
+                                }
+                                CouchfileModifyActionType::Fetch => {
+                                    self.maybe_purge_kv(req, cmp_key, value, &mut local_result);
+                                }
+                                CouchfileModifyActionType::Insert
+                                | CouchfileModifyActionType::FetchInsert => {
+                                    local_result.modified = true;
+                                    self.mr_push_item(
+                                        &action.key[..],
+                                        &action.data.as_ref().unwrap()[..],
+                                        &mut local_result,
+                                    );
+                                }
+                            }
                             start += 1;
                         }
                     }
@@ -214,9 +303,6 @@ impl TreeFile {
                     // not found to fetch callback
                 }
                 match req.actions[start].action_type {
-                    CouchfileModifyActionType::Remove => {
-                        local_result.modified = true;
-                    }
                     CouchfileModifyActionType::Insert | CouchfileModifyActionType::FetchInsert => {
                         local_result.modified = true;
                         self.mr_push_item(
@@ -229,19 +315,19 @@ impl TreeFile {
                 }
                 start += 1;
             }
-        } else if node_buf[0] == 0 {
-            cursor.set_position(1);
-
+        } else {
             // KP Node
             local_result.node_type = NodeType::KPNode;
-            while (cursor.position() as usize) < node_buf.len() && start < end {
-                let (cmp_key, value) = read_kv(&mut cursor).unwrap();
-                if cursor.position() as usize == node_buf.len() {
+            while start < end {
+                let Some((cmp_key, value)) = items.next() else {
+                    break;
+                };
+                if items.peek().is_none() {
                     //We're at the last item in the kpnode, must apply all our
                     //actions here.
-                    let mut desc = NodePointer::read_pointer(cmp_key, value);
+                    let mut desc = NodePointer::read_pointer(cmp_key, value)?;
 
-                    self.modify_node(req, Some(&mut desc), start, end, &mut local_result);
+                    self.modify_node(req, Some(&mut desc), start, end, &mut local_result)?;
 
                     break;
                 }
@@ -249,7 +335,7 @@ impl TreeFile {
                     Ordering::Less => {
                         //Key in node item less than action item and not at end
                         //position, so just add it and continue.
-                        let add = NodePointer::read_pointer(cmp_key, value);
+                        let add = NodePointer::read_pointer(cmp_key, value)?;
 
                         self.maybe_purge_kp(req, add, &mut local_result);
                     }
@@ -260,21 +346,24 @@ impl TreeFile {
                             range_end += 1;
                         }
 
-                        let mut desc = NodePointer::read_pointer(cmp_key, value);
+                        let mut desc = NodePointer::read_pointer(cmp_key, value)?;
 
-                        self.modify_node(req, Some(&mut desc), start, range_end, &mut local_result);
+                        self.modify_node(
+                            req,
+                            Some(&mut desc),
+                            start,
+                            range_end,
+                            &mut local_result,
+                        )?;
                         start = range_end;
                     }
                 }
             }
-            while (cursor.position() as usize) < node_buf.len() {
-                let (cmp_key, value) = read_kv(&mut cursor).unwrap();
-                let add = NodePointer::read_pointer(cmp_key, value);
+            for (cmp_key, value) in items {
+                let add = NodePointer::read_pointer(cmp_key, value)?;
 
                 self.maybe_purge_kp(req, add, &mut local_result);
             }
-        } else {
-            panic!("Invalid node type");
         }
 
         self.flush_mr(&mut local_result);
@@ -285,13 +374,11 @@ impl TreeFile {
             dst.modified = true;
             self.mr_move_pointers(&mut local_result, dst)
         }
+
+        Ok(())
     }
 
-    fn mr_push_pointerinfo<Ctx: Debug>(
-        &mut self,
-        ptr: NodePointer,
-        dst: &mut CouchfileModifyResult<Ctx>,
-    ) {
+    fn mr_push_pointerinfo(&mut self, ptr: NodePointer, dst: &mut CouchfileModifyResult) {
         let mut data = Vec::new();
         ptr.encode_pointer(&mut data).unwrap();
 
@@ -307,10 +394,10 @@ impl TreeFile {
         self.maybe_flush(dst);
     }
 
-    fn mr_move_pointers<Ctx: Debug>(
+    fn mr_move_pointers(
         &mut self,
-        src: &mut CouchfileModifyResult<Ctx>,
-        dst: &mut CouchfileModifyResult<Ctx>,
+        src: &mut CouchfileModifyResult,
+        dst: &mut CouchfileModifyResult,
     ) {
         while let Some(val) = src.pointers.pop_front() {
             dst.node_length += val.data.len() + val.key.len() + 5;
@@ -319,12 +406,7 @@ impl TreeFile {
         }
     }
 
-    pub fn mr_push_item<Ctx: Debug>(
-        &mut self,
-        key: &[u8],
-        value: &[u8],
-        result: &mut CouchfileModifyResult<Ctx>,
-    ) {
+    pub fn mr_push_item(&mut self, key: &[u8], value: &[u8], result: &mut CouchfileModifyResult) {
         result.values.push_back(Node {
             data: value.to_vec(),
             key: key.to_vec(),
@@ -339,7 +421,7 @@ impl TreeFile {
         _req: &CouchfileModifyRequest<Ctx>,
         key: &[u8],
         value: &[u8],
-        result: &mut CouchfileModifyResult<Ctx>,
+        result: &mut CouchfileModifyResult,
     ) {
         // TODO: Support purging???
 
@@ -350,7 +432,7 @@ impl TreeFile {
         &mut self,
         _req: &CouchfileModifyRequest<Ctx>,
         node: NodePointer,
-        result: &mut CouchfileModifyResult<Ctx>,
+        result: &mut CouchfileModifyResult,
     ) {
         // TODO: Support purging???
 
@@ -359,13 +441,13 @@ impl TreeFile {
 }
 
 impl TreeFile {
-    pub fn maybe_flush<Ctx: Debug>(&mut self, result: &mut CouchfileModifyResult<Ctx>) {
+    pub fn maybe_flush(&mut self, result: &mut CouchfileModifyResult) {
         if result.compacting {
             todo!()
-        } else if result.modified && result.values.len() > 3 {
+        } else if result.modified && result.values.len() >= 2 * result.tuning.min_fanout {
             let threshold = match result.node_type {
-                NodeType::KVNode => result.req.kv_chunk_threshold,
-                NodeType::KPNode => result.req.kp_chunk_threshold,
+                NodeType::KVNode => result.tuning.kv_node_size,
+                NodeType::KPNode => result.tuning.kp_node_size,
             };
             if result.node_length > threshold {
                 let quota = threshold * 2 / 3;
@@ -376,48 +458,59 @@ impl TreeFile {
 
     /// Write the current contents of the values list to disk as a node
     /// and add the resulting pointer to the pointers list.
-    pub fn flush_mr<Ctx: Debug>(&mut self, result: &mut CouchfileModifyResult<Ctx>) {
+    pub fn flush_mr(&mut self, result: &mut CouchfileModifyResult) {
         self.flush_mr_partial(result, result.node_length)
     }
 
     /// Write a node using enough items from the values list to create a node
     /// with uncompressed size of at least mr_quota
-    pub fn flush_mr_partial<Ctx: Debug>(
-        &mut self,
-        result: &mut CouchfileModifyResult<Ctx>,
-        mr_quota: usize,
-    ) {
+    pub fn flush_mr_partial(&mut self, result: &mut CouchfileModifyResult, mr_quota: usize) {
         if result.values.is_empty() || !result.modified {
             return;
         }
 
-        let mut nodebuf = Vec::with_capacity(result.node_length + 1);
-
-        nodebuf.write_u8(result.node_type.into()).unwrap();
-
         let mut diskpos = 0;
         let mut subtreesize = 0;
         let mut disksize = 0;
-        let mut item_count = 0;
-        let mut final_key = Vec::new();
+        let mut items = Vec::new();
+        // The items' size in the plain encoding, which node_length counts
+        let mut plain_length = 0;
 
         let mut mr_quota = mr_quota as isize;
 
-        while !result.values.is_empty()
-            && (mr_quota > 0 || item_count < 2 && result.node_type == NodeType::KPNode)
+        while !result.values.is_empty() && (mr_quota > 0 || items.len() < result.tuning.min_fanout)
         {
             let value = result.values.pop_front().unwrap();
 
-            write_kv(&mut nodebuf, &value.key, &value.data);
-
             if let Some(pointer) = &value.pointer {
                 subtreesize += pointer.subtree_size
             }
 
-            mr_quota -= (value.key.len() + value.data.len() + 5) as isize;
-            final_key = value.key.clone();
-            item_count += 1;
+            let length = value.key.len() + value.data.len() + 5;
+            mr_quota -= length as isize;
+            plain_length += length;
+            items.push(value);
+        }
+
+        let mut nodebuf = Vec::with_capacity(plain_length + 1);
+        if result.node_type == NodeType::KVNode
+            && self.front_code_keys
+            && front_coding_saves(items.iter().map(|item| &item.key[..]))
+        {
+            nodebuf.push(FRONT_CODED_KV_NODE);
+            let mut previous_key: &[u8] = &[];
+            for item in &items {
+                write_front_coded_kv(&mut nodebuf, previous_key, &item.key, &item.data);
+                previous_key = &item.key;
+            }
+        } else {
+            nodebuf.write_u8(result.node_type.into()).unwrap();
+            for item in &items {
+                write_kv(&mut nodebuf, &item.key, &item.data);
+            }
         }
+        let reduce_value = result.reducer.reduce(&items);
+        let final_key = items.pop().unwrap().key;
 
         self.db_write_buf_compressed(&nodebuf, &mut diskpos, &mut disksize);
 
@@ -425,7 +518,7 @@ impl TreeFile {
             pointer: diskpos,
             subtree_size: u64::from(disksize) + subtreesize,
             key: Some(final_key.clone()),
-            reduce_value: vec![],
+            reduce_value,
         };
 
         let mut data = Vec::new();
@@ -438,7 +531,7 @@ impl TreeFile {
             pointer: Some(ptr),
         };
 
-        result.node_length -= nodebuf.len() - 1;
+        result.node_length -= plain_length;
         result.pointers.push_back(raw_ptr);
     }
 }
@@ -1,7 +1,9 @@
-use crate::{btree::CouchfileLookupRequest, node_types::read_kv, Db};
+use crate::{
+    btree::CouchfileLookupRequest, node_types::RawNode, CouchstoreError, CouchstoreResult, Db,
+};
 use byteorder::ReadBytesExt;
 use num_enum::{IntoPrimitive, TryFromPrimitive};
-use std::{cmp::Ordering, io::Cursor};
+use std::cmp::Ordering;
 
 #[derive(Debug, PartialEq, Eq, Clone, Copy, TryFromPrimitive, IntoPrimitive, Default)]
 #[repr(u8)]
@@ -11,32 +13,36 @@ pub enum NodeType {
     KVNode = 1,
 }
 
+/// Called with each key and value of a range, returning whether to go on
+pub(crate) type RangeCallback<'a> = dyn FnMut(&[u8], &[u8]) -> CouchstoreResult<bool> + 'a;
+
 impl Db {
     // TODO: support multiple keys
     pub fn btree_lookup_inner<F>(
-        &mut self,
+        &self,
         req: &mut CouchfileLookupRequest,
         on_fetch: &mut F,
         diskpos: usize,
         mut current: usize,
         end: usize,
-    ) where
-        F: FnMut(&mut Self, &[u8], Option<&[u8]>),
+    ) -> CouchstoreResult<()>
+    where
+        F: FnMut(&Self, &[u8], Option<&[u8]>) -> CouchstoreResult<()>,
     {
         if current == end {
-            return;
+            return Ok(());
         }
 
-        let node = self.file.read_compressed(diskpos);
-
-        let mut cursor = Cursor::new(node.as_ref());
-
-        let node_type = NodeType::try_from_primitive(cursor.read_u8().unwrap()).unwrap();
+        let buf = self.file.read_node(diskpos)?;
+        let node = RawNode::decode(&buf)?;
+        let mut items = node.items.into_iter();
 
-        match node_type {
+        match node.node_type {
             NodeType::KPNode => {
-                while (cursor.position() as usize) < node.len() && current < end {
-                    let (cmp_key, value) = read_kv(&mut cursor).unwrap();
+                while current < end {
+                    let Some((cmp_key, value)) = items.next() else {
+                        break;
+                    };
 
                     let key = &req.keys[current][..];
 
@@ -62,11 +68,20 @@ impl Db {
                         }
                     }
 
-                    let pointer = (&value[..]).read_u48::<byteorder::BigEndian>().unwrap() as usize;
+                    let pointer = (&value[..])
+                        .read_u48::<byteorder::BigEndian>()
+                        .map_err(|_| CouchstoreError::Corrupt("truncated node pointer"))?
+                        as usize;
+                    // Children are written before their parents, so a
+                    // pointer that doesn't go backwards is corrupt and could
+                    // loop forever
+                    if pointer >= diskpos {
+                        return Err(CouchstoreError::Corrupt("node pointer out of order"));
+                    }
 
                     // In interior nodes the Value parts of these pairs are pointers to another
                     // B-tree node, where keys less than or equal to that pair's Key will be.
-                    self.btree_lookup_inner(req, on_fetch, pointer, current, last_item);
+                    self.btree_lookup_inner(req, on_fetch, pointer, current, last_item)?;
 
                     if !req.in_fold {
                         current = last_item;
@@ -81,11 +96,12 @@ impl Db {
                 while current < end {
                     // Only try and read the next-key if requested and we're still in
                     // the node length
-                    if next_key && (cursor.position() as usize) < node.len() {
-                        (cmp_key, value) = read_kv(&mut cursor).unwrap();
-                    } else if next_key {
-                        // else if next_key is true and we're out of buf space, break
-                        break;
+                    if next_key {
+                        // break if we're out of items
+                        let Some(item) = items.next() else {
+                            break;
+                        };
+                        (cmp_key, value) = item;
                     }
 
                     let key = &req.keys[current][..];
@@ -117,9 +133,9 @@ impl Db {
                     }
 
                     if cmp_val == Ordering::Equal || req.in_fold {
-                        on_fetch(self, cmp_key, Some(value));
+                        on_fetch(self, cmp_key, Some(value))?;
                     } else {
-                        on_fetch(self, key, None);
+                        on_fetch(self, key, None)?;
                     }
 
                     if !req.in_fold {
@@ -133,20 +149,67 @@ impl Db {
         }
 
         while current < end {
-            on_fetch(self, &req.keys[current], None);
+            on_fetch(self, &req.keys[current], None)?;
             current += 1;
         }
+
+        Ok(())
+    }
+
+    /// Call back with each item from start_key up to, not including,
+    /// end_key (or the last, if None) in key order, until on_fetch returns
+    /// false. Returns false once it has stopped, so the caller stops too.
+    pub(crate) fn btree_fold_range(
+        &self,
+        diskpos: usize,
+        start_key: &[u8],
+        end_key: Option<&[u8]>,
+        on_fetch: &mut RangeCallback,
+    ) -> CouchstoreResult<bool> {
+        let past_end = |key: &[u8]| end_key.is_some_and(|end_key| key >= end_key);
+        let buf = self.file.read_node(diskpos)?;
+        let node = RawNode::decode(&buf)?;
+        for (key, value) in node.items {
+            // Keys in interior nodes are the last key of their child
+            if key < start_key {
+                continue;
+            }
+            match node.node_type {
+                NodeType::KPNode => {
+                    let pointer = (&value[..])
+                        .read_u48::<byteorder::BigEndian>()
+                        .map_err(|_| CouchstoreError::Corrupt("truncated node pointer"))?
+                        as usize;
+                    if pointer >= diskpos {
+                        return Err(CouchstoreError::Corrupt("node pointer out of order"));
+                    }
+                    if !self.btree_fold_range(pointer, start_key, end_key, on_fetch)? {
+                        return Ok(false);
+                    }
+                }
+                NodeType::KVNode => {
+                    if past_end(key) || !on_fetch(key, value)? {
+                        return Ok(false);
+                    }
+                }
+            }
+            if past_end(key) {
+                return Ok(false);
+            }
+        }
+        Ok(true)
     }
 
     pub fn btree_lookup<F>(
-        &mut self,
+        &self,
         req: &mut CouchfileLookupRequest,
         mut on_fetch: F,
         root_pointer: usize,
-    ) where
-        F: Sized + FnMut(&mut Self, &[u8], Option<&[u8]>),
+    ) -> CouchstoreResult<()>
+    where
+        F: Sized + FnMut(&Self, &[u8], Option<&[u8]>) -> CouchstoreResult<()>,
     {
         req.in_fold = false;
-        self.btree_lookup_inner(req, &mut on_fetch, root_pointer, 0, req.keys.len());
+        self.btree_lookup_inner(req, &mut on_fetch, root_pointer, 0, req.keys.len())
     }
 }
@@ -0,0 +1,198 @@
+//! Loading documents into a new file, as restores and imports do. Rather
+//! than modifying the B-trees batch by batch, which reads and rewrites the
+//! nodes along each path, the trees are built bottom-up: each node is
+//! written once, when it fills.
+
+use crate::{
+    btree_modify::TreeBuilder, reduce::Reducer, save::encode_seq_key, CouchstoreError,
+    CouchstoreResult, Db, Doc, DocInfo, SaveOptions,
+};
+
+/// Loads documents, in seq order, into a file holding none. Built with
+/// [`Db::bulk_load`], and the loaded documents are in the file's trees once
+/// [`BulkLoader::finish`] returns, though not durable until the next commit.
+#[derive(Debug)]
+pub struct BulkLoader {
+    db: Db,
+    options: SaveOptions,
+    by_seq: TreeBuilder,
+    /// The by-id entries, which arrive in seq order and so can only be
+    /// built into a tree once all are in
+    by_id: Vec<(Vec<u8>, Vec<u8>)>,
+    last_seq: u64,
+}
+
+impl Db {
+    /// Start loading documents into the file, which mustn't hold any.
+    /// Only COMPRESS_DOC_BODIES of the options applies, documents always
+    /// keep their db_seq.
+    pub fn bulk_load(self, options: SaveOptions) -> CouchstoreResult<BulkLoader> {
+        if self.header.by_id_root.is_some() || self.header.by_seq_root.is_some() {
+            return Err(CouchstoreError::BulkLoad("file already holds documents"));
+        }
+        Ok(BulkLoader {
+            by_seq: TreeBuilder::new(self.opts.tuning, Reducer::None),
+            last_seq: self.header.update_seq,
+            db: self,
+            options,
+            by_id: Vec::new(),
+        })
+    }
+}
+
+impl BulkLoader {
+    /// Add a document, saved as a deletion if it has no body. Its db_seq
+    /// must be above those of the documents already added, and its id
+    /// must not be one of theirs.
+    pub fn add(&mut self, doc: Option<&Doc>, info: &DocInfo) -> CouchstoreResult<()> {
+        if info.db_seq <= self.last_seq {
+            return Err(CouchstoreError::BulkLoad("documents out of seq order"));
+        }
+        self.db.check_doc_size(doc, info)?;
+        let info = self.db.write_doc_body(doc, info, self.options);
+
+        let mut seq_value = Vec::new();
+        info.encode_seq_index_value(&mut seq_value);
+        self.by_seq
+            .add(&mut self.db.file, &encode_seq_key(info.db_seq), &seq_value);
+
+        let mut id_value = Vec::new();
+        info.encode_id_index_value(&mut id_value);
+        self.by_id.push((info.id, id_value));
+        self.last_seq = info.db_seq;
+        Ok(())
+    }
+
+    /// Carry over the purge seq of the file the documents come from
+    pub fn set_purge_seq(&mut self, purge_seq: u64) {
+        self.db.header.purge_seq = purge_seq;
+    }
+
+    /// Write the rest of the trees and point the file's header at them,
+    /// returning the file for the caller to commit
+    pub fn finish(mut self) -> CouchstoreResult<Db> {
+        self.by_id.sort_unstable_by(|(a, _), (b, _)| a.cmp(b));
+        if self.by_id.windows(2).any(|pair| pair[0].0 == pair[1].0) {
+            return Err(CouchstoreError::BulkLoad("duplicate document id"));
+        }
+
+        let mut by_id = TreeBuilder::new(self.db.opts.tuning, Reducer::DocCounts);
+        for (id, value) in &self.by_id {
+            by_id.add(&mut self.db.file, id, value);
+        }
+        self.db.header.by_id_root = by_id.finish(&mut self.db.file);
+        self.db.header.by_seq_root = self.by_seq.finish(&mut self.db.file);
+        self.db.header.update_seq = self.last_seq;
+        Ok(self.db)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{BtreeTuning, ContentMetaFlag, DBOpenOptions, OpenOptions};
+
+    fn doc_info(id: &str, db_seq: u64) -> DocInfo {
+        DocInfo {
+            id: id.into(),
+            db_seq,
+            rev_seq: 1,
+            rev_meta: vec![],
+            deleted: false,
+            content_meta: ContentMetaFlag::IS_COMPRESSED,
+            bp: 0,
+            physical_size: 0,
+        }
+    }
+
+    #[test]
+    fn test_bulk_load() {
+        let dir = tempfile::tempdir().unwrap();
+        // Small nodes, so the trees are a few levels deep
+        let opts = DBOpenOptions::default().btree_tuning(BtreeTuning {
+            kv_node_size: 256,
+            kp_node_size: 256,
+            min_fanout: 2,
+        });
+        let options = SaveOptions::COMPRESS_DOC_BODIES;
+
+        // Ids in seq order aren't in id order
+        let id = |seq: u64| format!("doc_{}", seq.wrapping_mul(7919) % 10_000);
+        let docs: Vec<_> = (1..=2000)
+            .map(|seq| {
+                let doc = (seq % 10 != 0).then(|| Doc {
+                    id: id(seq).into(),
+                    data: format!("body {seq}").into(),
+                });
+                (doc, doc_info(&id(seq), seq))
+            })
+            .collect();
+
+        let loaded = dir.path().join("loaded.couch.1");
+        let mut loader = Db::open(&loaded, opts).unwrap().bulk_load(options).unwrap();
+        for (doc, info) in &docs {
+            loader.add(doc.as_ref(), info).unwrap();
+        }
+        loader.finish().unwrap().commit();
+
+        let saved = dir.path().join("saved.couch.1");
+        let mut db = Db::open(&saved, opts).unwrap();
+        let (docs, infos) = docs.into_iter().unzip();
+        db.save_documents(docs, infos, options | SaveOptions::SEQUENCE_AS_IS)
+            .unwrap();
+        db.commit();
+
+        // Both files hold the same documents
+        let changes = |path| {
+            let db = Db::open(path, DBOpenOptions::default().read_only()).unwrap();
+            let mut changes = vec![];
+            db.changes_since(0, |db, mut info| {
+                let doc = db.open_doc_with_docinfo(&info, OpenOptions::DECOMPRESS_DOC_BODIES)?;
+                info.bp = 0;
+                changes.push((info, doc.map(|doc| doc.data)));
+                Ok(())
+            })
+            .unwrap();
+            let by_id = db.docinfo_by_id(id(1234)).unwrap().map(|info| info.db_seq);
+            (db.header().update_seq, changes, by_id)
+        };
+        let loaded = changes(&loaded);
+        assert_eq!(loaded.0, 2000);
+        assert_eq!(loaded.1.len(), 2000);
+        assert_eq!(loaded.2, Some(1234));
+        assert_eq!(loaded, changes(&saved));
+    }
+
+    #[test]
+    fn test_bulk_load_errors() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("0.couch.1");
+        let doc = |id: &str| Doc {
+            id: id.into(),
+            data: b"{}".to_vec(),
+        };
+
+        let mut loader = Db::open(&path, DBOpenOptions::default())
+            .unwrap()
+            .bulk_load(SaveOptions::empty())
+            .unwrap();
+        loader.add(Some(&doc("a")), &doc_info("a", 2)).unwrap();
+        assert!(matches!(
+            loader.add(Some(&doc("b")), &doc_info("b", 2)),
+            Err(CouchstoreError::BulkLoad(_))
+        ));
+        loader.add(Some(&doc("a")), &doc_info("a", 3)).unwrap();
+        assert!(matches!(
+            loader.finish(),
+            Err(CouchstoreError::BulkLoad("duplicate document id"))
+        ));
+
+        // Only empty files can be loaded
+        let mut db = Db::open(&path, DBOpenOptions::default()).unwrap();
+        db.set(b"a".to_vec(), b"{}".to_vec()).unwrap();
+        assert!(matches!(
+            db.bulk_load(SaveOptions::empty()),
+            Err(CouchstoreError::BulkLoad(_))
+        ));
+    }
+}
@@ -0,0 +1,141 @@
+use std::{
+    path::PathBuf,
+    time::{Duration, Instant},
+};
+
+use crate::{CouchstoreResult, DBOpenOptions, Db, Doc, DocInfo, OpenOptions};
+
+/// A committed document, with its body unless it's deleted
+#[derive(Debug)]
+pub struct Change {
+    pub info: DocInfo,
+    pub doc: Option<Doc>,
+}
+
+/// Follows a database file that another process is writing, returning the
+/// documents of each commit after the ones already seen. New commits are
+/// noticed by polling the file's size, which only grows as it's append only.
+/// A commit extends the file before writing its header, so a file which
+/// runs past its latest header is read again on the next poll.
+#[derive(Debug)]
+pub struct ChangesFeed {
+    path: PathBuf,
+    /// Changes up to and including this seqno have been returned
+    last_seq: u64,
+    /// The file size when last read, if it ended with a complete header
+    len: Option<u64>,
+}
+
+impl ChangesFeed {
+    /// Follow the file from the changes after since_seq
+    pub fn new(path: impl Into<PathBuf>, since_seq: u64) -> Self {
+        Self {
+            path: path.into(),
+            last_seq: since_seq,
+            len: None,
+        }
+    }
+
+    /// The seqno of the last change returned
+    pub fn last_seq(&self) -> u64 {
+        self.last_seq
+    }
+
+    /// The changes committed since the last poll, empty if the file hasn't
+    /// grown. A commit still being written is picked up by a later poll.
+    pub fn poll(&mut self) -> CouchstoreResult<Vec<Change>> {
+        let len = match std::fs::metadata(&self.path) {
+            Ok(metadata) => metadata.len(),
+            // Not created yet
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e.into()),
+        };
+        if self.len == Some(len) {
+            return Ok(Vec::new());
+        }
+
+        let db = Db::open(&self.path, DBOpenOptions::default().read_only())?;
+        self.len = (db.header_end() >= len).then_some(len);
+        let mut changes = Vec::new();
+        db.changes_since(self.last_seq + 1, |db, info| {
+            let doc = db.open_doc_with_docinfo(&info, OpenOptions::DECOMPRESS_DOC_BODIES)?;
+            changes.push(Change { info, doc });
+            Ok(())
+        })?;
+        if let Some(last) = changes.last() {
+            self.last_seq = last.info.db_seq;
+        }
+        Ok(changes)
+    }
+
+    /// Poll every poll_interval until there are changes, or the timeout
+    /// passes in which case no changes are returned
+    pub fn wait(
+        &mut self,
+        poll_interval: Duration,
+        timeout: Duration,
+    ) -> CouchstoreResult<Vec<Change>> {
+        let deadline = Instant::now() + timeout;
+        loop {
+            let changes = self.poll()?;
+            if !changes.is_empty() || Instant::now() >= deadline {
+                return Ok(changes);
+            }
+            std::thread::sleep(poll_interval);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{ContentMetaFlag, SaveOptions};
+
+    fn save(db: &mut Db, key: &str, seq: u64) {
+        let info = DocInfo {
+            id: key.as_bytes().to_vec(),
+            db_seq: seq,
+            rev_seq: 1,
+            rev_meta: vec![],
+            deleted: false,
+            content_meta: ContentMetaFlag::empty(),
+            bp: 0,
+            physical_size: 0,
+        };
+        let doc = Doc {
+            id: key.as_bytes().to_vec(),
+            data: b"value".to_vec(),
+        };
+        db.save_documents(vec![Some(doc)], vec![info], SaveOptions::SEQUENCE_AS_IS)
+            .unwrap();
+        db.commit();
+    }
+
+    #[test]
+    fn test_changes_feed() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("0.couch.1");
+        let mut feed = ChangesFeed::new(&path, 0);
+        assert!(feed.poll().unwrap().is_empty());
+
+        let mut db = Db::open(&path, DBOpenOptions::default()).unwrap();
+        save(&mut db, "a", 1);
+        save(&mut db, "b", 2);
+        let changes = feed.poll().unwrap();
+        assert_eq!(changes.len(), 2);
+        assert_eq!(changes[1].doc.as_ref().unwrap().data, b"value");
+        assert!(feed.poll().unwrap().is_empty());
+
+        // A commit from another thread while waiting
+        std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_millis(20));
+            save(&mut db, "a", 3);
+        });
+        let changes = feed
+            .wait(Duration::from_millis(5), Duration::from_secs(10))
+            .unwrap();
+        assert_eq!(changes.len(), 1);
+        assert_eq!(changes[0].info.id, b"a");
+        assert_eq!(feed.last_seq(), 3);
+    }
+}
@@ -0,0 +1,494 @@
+//! Copying a database into a new file, which leaves behind the space taken
+//! by old revisions and old headers, and writes the copy at the current disk
+//! version whatever the version of the original. The copy's trees are
+//! shaped by its own B-tree tuning, and node_stats shows the result.
+
+use std::path::Path;
+
+use crate::{
+    btree::CouchfileLookupRequest,
+    btree_read::NodeType,
+    node_types::{expand_node, RawNode},
+    CouchstoreResult, DBOpenOptions, Db, Doc, DocCounts, LocalDoc, NodePointer, SaveOptions,
+};
+
+/// Documents are copied in batches of this many, bounding the memory used
+const COMPACT_BATCH_SIZE: usize = 1000;
+
+/// The nodes of a database's trees, with their sizes as encoded (keys
+/// still front-coded) but not compressed, to compare with the tuning's
+/// targets
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct NodeStats {
+    pub kv_nodes: u64,
+    pub kv_bytes: u64,
+    pub kp_nodes: u64,
+    pub kp_bytes: u64,
+}
+
+impl NodeStats {
+    pub fn avg_kv_node_size(&self) -> u64 {
+        self.kv_bytes.checked_div(self.kv_nodes).unwrap_or(0)
+    }
+
+    pub fn avg_kp_node_size(&self) -> u64 {
+        self.kp_bytes.checked_div(self.kp_nodes).unwrap_or(0)
+    }
+}
+
+/// How much of a file its latest header still uses, and so how much
+/// compaction would reclaim
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DbInfo {
+    /// Up to the end of the latest header
+    pub file_size: u64,
+    /// The latest header's trees and the document bodies they point to.
+    /// None if the by-id tree has no reductions to give the bodies' sizes,
+    /// until the file is compacted.
+    pub space_used: Option<u64>,
+    pub doc_counts: Option<DocCounts>,
+    pub update_seq: u64,
+    pub purge_seq: u64,
+}
+
+impl DbInfo {
+    /// The share of the file which is stale, 0 for a freshly compacted
+    /// file and approaching 1 as old revisions pile up
+    pub fn fragmentation(&self) -> Option<f64> {
+        let used = self.space_used?;
+        if self.file_size == 0 {
+            return Some(0.0);
+        }
+        Some(self.file_size.saturating_sub(used) as f64 / self.file_size as f64)
+    }
+
+    /// The file's size as a multiple of the space it uses
+    pub fn space_amplification(&self) -> Option<f64> {
+        match self.space_used? {
+            0 => None,
+            used => Some(self.file_size as f64 / used as f64),
+        }
+    }
+}
+
+impl Db {
+    /// The file's size against the space its latest header uses, from the
+    /// trees' roots without reading the rest
+    pub fn info(&self) -> DbInfo {
+        let header = &self.header;
+        let trees: u64 = [
+            &header.by_id_root,
+            &header.by_seq_root,
+            &header.local_docs_root,
+        ]
+        .into_iter()
+        .chain(header.aux_roots.values())
+        .flatten()
+        .map(|root| root.subtree_size)
+        .sum();
+        let doc_counts = self.doc_counts();
+        DbInfo {
+            file_size: self.header_end,
+            space_used: doc_counts.map(|counts| trees + counts.size),
+            doc_counts,
+            update_seq: header.update_seq,
+            purge_seq: header.purge_seq,
+        }
+    }
+
+    /// Copy the latest commit into a new file at target, replacing anything
+    /// already there, and commit it. Every by-seq entry is kept, so a file
+    /// with history keeps it, along with the local documents, the auxiliary
+    /// trees and the update and purge seqs. The new file is created at the
+    /// version opts give, the current one unless set otherwise.
+    pub fn compact_to(
+        &self,
+        target: impl AsRef<Path>,
+        opts: DBOpenOptions,
+    ) -> CouchstoreResult<Db> {
+        let target = target.as_ref();
+        match std::fs::remove_file(target) {
+            Ok(()) => {}
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e.into()),
+        }
+        let mut new_db = Db::open(target, opts)?;
+
+        let mut docs = Vec::new();
+        let mut infos = Vec::new();
+        self.changes_since(0, |db, info| {
+            // Bodies are copied as stored, still compressed if they were
+            let doc = match info.bp {
+                0 => None,
+                bp => Some(Doc {
+                    id: info.id.clone(),
+                    data: db.file.read_uncompressed(bp as usize)?,
+                }),
+            };
+            docs.push(doc);
+            infos.push(info);
+            if infos.len() == COMPACT_BATCH_SIZE {
+                new_db.save_copied(std::mem::take(&mut docs), std::mem::take(&mut infos))?;
+            }
+            Ok(())
+        })?;
+        new_db.save_copied(docs, infos)?;
+
+        if let Some(root) = &self.header.local_docs_root {
+            let mut local_docs = Vec::new();
+            let mut req = CouchfileLookupRequest::new(vec![Vec::new()]).fold();
+            self.btree_lookup(
+                &mut req,
+                |_, key, value| {
+                    if let Some(value) = value {
+                        local_docs.push(LocalDoc::new(key, value.to_vec()));
+                    }
+                    Ok(())
+                },
+                root.pointer as usize,
+            )?;
+            for local_doc in local_docs {
+                new_db.save_local_document(local_doc)?;
+            }
+        }
+
+        for name in self.aux_tree_names() {
+            new_db.create_aux_tree(name)?;
+            let mut changes = Vec::new();
+            self.fold_aux_tree(name, &[], |_, key, value| {
+                changes.push((key.to_vec(), Some(value.to_vec())));
+                Ok(())
+            })?;
+            new_db.modify_aux_tree(name, changes)?;
+        }
+
+        // Purged documents may have had the highest seqs
+        new_db.header.update_seq = self.header.update_seq;
+        new_db.header.purge_seq = self.header.purge_seq;
+        new_db.try_commit()?;
+        Ok(new_db)
+    }
+
+    /// Read every node of the latest header's trees, e.g. to see the shape
+    /// compaction gave them
+    pub fn node_stats(&self) -> CouchstoreResult<NodeStats> {
+        let mut stats = NodeStats::default();
+        let header = &self.header;
+        let roots = [
+            &header.by_id_root,
+            &header.by_seq_root,
+            &header.local_docs_root,
+        ]
+        .into_iter()
+        .chain(header.aux_roots.values())
+        .flatten();
+        for root in roots {
+            self.add_node_stats(root.pointer, &mut stats)?;
+        }
+        Ok(stats)
+    }
+
+    fn add_node_stats(&self, pos: u64, stats: &mut NodeStats) -> CouchstoreResult<()> {
+        // Sized as written, before front-coded keys are expanded
+        let buf = self.file.read_compressed(pos as usize)?;
+        let size = buf.len() as u64;
+        let buf = expand_node(buf)?;
+        let node = RawNode::decode(&buf)?;
+        match node.node_type {
+            NodeType::KVNode => {
+                stats.kv_nodes += 1;
+                stats.kv_bytes += size;
+            }
+            NodeType::KPNode => {
+                stats.kp_nodes += 1;
+                stats.kp_bytes += size;
+                for (key, value) in node.items {
+                    let child = NodePointer::read_pointer(key, value)?;
+                    self.add_node_stats(child.pointer, stats)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn save_copied(
+        &mut self,
+        docs: Vec<Option<Doc>>,
+        infos: Vec<crate::DocInfo>,
+    ) -> CouchstoreResult<()> {
+        if infos.is_empty() {
+            return Ok(());
+        }
+        self.save_documents(
+            docs,
+            infos,
+            SaveOptions::SEQUENCE_AS_IS | SaveOptions::KEEP_HISTORY,
+        )
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{BtreeTuning, ContentMetaFlag, CouchstoreError, DiskVersion, DocInfo, OpenOptions};
+
+    fn doc_info(id: &str, db_seq: u64, deleted: bool) -> DocInfo {
+        DocInfo {
+            id: id.as_bytes().to_vec(),
+            db_seq,
+            rev_seq: 1,
+            rev_meta: vec![0; 16],
+            deleted,
+            content_meta: ContentMetaFlag::IS_COMPRESSED,
+            bp: 0,
+            physical_size: 0,
+        }
+    }
+
+    #[test]
+    fn test_compact_upgrades() {
+        let dir = tempfile::tempdir().unwrap();
+        for old_version in [DiskVersion::Eleven, DiskVersion::Twelve] {
+            let old_path = dir.path().join("0.couch.1");
+            let opts = DBOpenOptions::default().disk_version(old_version);
+            let mut db = Db::open(&old_path, opts).unwrap();
+            db.save_documents(
+                vec![
+                    Some(Doc {
+                        id: b"a".to_vec(),
+                        data: b"value".to_vec(),
+                    }),
+                    Some(Doc {
+                        id: b"empty".to_vec(),
+                        data: Vec::new(),
+                    }),
+                    None,
+                ],
+                vec![
+                    doc_info("a", 1, false),
+                    doc_info("empty", 2, false),
+                    doc_info("gone", 3, true),
+                ],
+                SaveOptions::SEQUENCE_AS_IS | SaveOptions::COMPRESS_DOC_BODIES,
+            )
+            .unwrap();
+            db.save_local_document(LocalDoc::new("_local/vbstate", b"{}".to_vec()))
+                .unwrap();
+            db.create_aux_tree("expiry").unwrap();
+            db.modify_aux_tree("expiry", vec![(b"k".to_vec(), Some(b"v".to_vec()))])
+                .unwrap();
+            db.commit();
+            drop(db);
+
+            // The old format reads back
+            let db = Db::open(&old_path, DBOpenOptions::default().read_only()).unwrap();
+            assert_eq!(db.header().disk_version(), old_version);
+            assert_eq!(db.header().timestamp(), 0);
+            assert_eq!(db.header().update_seq, 3);
+
+            let new_db = db
+                .compact_to(dir.path().join("0.couch.2"), DBOpenOptions::default())
+                .unwrap();
+            drop(new_db);
+            let new_db = Db::open(
+                dir.path().join("0.couch.2"),
+                DBOpenOptions::default().read_only(),
+            )
+            .unwrap();
+            assert_eq!(new_db.header().disk_version(), DiskVersion::Thirteen);
+            assert_ne!(new_db.header().timestamp(), 0);
+            assert_eq!(new_db.header().update_seq, 3);
+
+            let info = new_db.docinfo_by_id("a").unwrap().unwrap();
+            let doc = new_db
+                .open_doc_with_docinfo(&info, OpenOptions::DECOMPRESS_DOC_BODIES)
+                .unwrap()
+                .unwrap();
+            assert_eq!(doc.data, b"value");
+            let info = new_db.docinfo_by_id("empty").unwrap().unwrap();
+            assert!(!info.deleted);
+            assert_ne!(info.bp, 0);
+            assert!(new_db.docinfo_by_id("gone").unwrap().unwrap().deleted);
+            assert_eq!(
+                new_db
+                    .open_local_document("_local/vbstate")
+                    .unwrap()
+                    .unwrap()
+                    .json,
+                Some(b"{}".to_vec())
+            );
+            assert_eq!(new_db.get_aux("expiry", b"k").unwrap(), Some(b"v".to_vec()));
+            std::fs::remove_file(old_path).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_db_info() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut db = Db::open(dir.path().join("0.couch.1"), DBOpenOptions::default()).unwrap();
+        // Rewriting the same documents leaves the old revisions behind
+        for round in 0..10 {
+            let (docs, infos) = (0..50)
+                .map(|i| {
+                    let id = format!("doc_{i}");
+                    let doc = Doc {
+                        id: id.clone().into_bytes(),
+                        data: format!("round {round}").repeat(10).into_bytes(),
+                    };
+                    (Some(doc), doc_info(&id, 0, false))
+                })
+                .unzip();
+            db.save_documents(docs, infos, SaveOptions::empty())
+                .unwrap();
+            db.commit();
+        }
+        let info = db.info();
+        assert_eq!(info.file_size, db.header_end());
+        assert_eq!(info.doc_counts.unwrap().live, 50);
+        assert_eq!(info.update_seq, 500);
+        let fragmentation = info.fragmentation().unwrap();
+        assert!(fragmentation > 0.8, "{info:?}");
+        assert!(info.space_amplification().unwrap() > 5.0);
+
+        let compacted = db
+            .compact_to(dir.path().join("0.couch.2"), DBOpenOptions::default())
+            .unwrap();
+        let compacted_info = compacted.info();
+        assert!(compacted_info.file_size < info.file_size / 4);
+        assert!(compacted_info.fragmentation().unwrap() < fragmentation);
+        assert!(compacted_info.space_amplification().unwrap() < 2.0);
+    }
+
+    #[test]
+    fn test_btree_tuning() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("0.couch.1");
+        for tuning in [
+            BtreeTuning {
+                kv_node_size: 100,
+                ..Default::default()
+            },
+            BtreeTuning {
+                kp_node_size: 2 * 1024 * 1024,
+                ..Default::default()
+            },
+            BtreeTuning {
+                min_fanout: 1,
+                ..Default::default()
+            },
+        ] {
+            let opts = DBOpenOptions::default().btree_tuning(tuning);
+            assert!(matches!(
+                Db::open(&path, opts),
+                Err(CouchstoreError::InvalidTuning(_))
+            ));
+        }
+
+        let mut db = Db::open(&path, DBOpenOptions::default()).unwrap();
+        let ids: Vec<String> = (1..=2000).map(|i| format!("key_{i:05}")).collect();
+        db.save_documents(
+            ids.iter()
+                .map(|id| {
+                    Some(Doc {
+                        id: id.as_bytes().to_vec(),
+                        data: b"value".to_vec(),
+                    })
+                })
+                .collect(),
+            ids.iter()
+                .enumerate()
+                .map(|(i, id)| doc_info(id, i as u64 + 1, false))
+                .collect(),
+            SaveOptions::SEQUENCE_AS_IS,
+        )
+        .unwrap();
+        db.commit();
+        let stats = db.node_stats().unwrap();
+        assert!(stats.avg_kv_node_size() <= 1279 + 100, "{stats:?}");
+
+        // Bigger nodes make for fewer of them
+        let big = BtreeTuning {
+            kv_node_size: 16 * 1024,
+            kp_node_size: 16 * 1024,
+            min_fanout: 4,
+        };
+        let new_db = db
+            .compact_to(
+                dir.path().join("0.couch.2"),
+                DBOpenOptions::default().btree_tuning(big),
+            )
+            .unwrap();
+        let big_stats = new_db.node_stats().unwrap();
+        assert!(big_stats.kv_nodes * 4 < stats.kv_nodes, "{big_stats:?}");
+        assert!(big_stats.avg_kv_node_size() > 4 * 1024, "{big_stats:?}");
+        assert_eq!(
+            new_db.docinfo_by_id("key_01000").unwrap().unwrap().db_seq,
+            1000
+        );
+    }
+
+    #[test]
+    fn test_front_coded_keys() {
+        let dir = tempfile::tempdir().unwrap();
+        // Keys in a collection, as the engine prefixes them
+        let ids: Vec<String> = (1..=1000).map(|i| format!("\x08airline_{i:05}")).collect();
+        let write = |name: &str, version| {
+            let opts = DBOpenOptions::default().disk_version(version);
+            let mut db = Db::open(dir.path().join(name), opts).unwrap();
+            db.save_documents(
+                ids.iter()
+                    .map(|id| {
+                        Some(Doc {
+                            id: id.as_bytes().to_vec(),
+                            data: b"value".to_vec(),
+                        })
+                    })
+                    .collect(),
+                ids.iter()
+                    .enumerate()
+                    .map(|(i, id)| doc_info(id, i as u64 + 1, false))
+                    .collect(),
+                SaveOptions::SEQUENCE_AS_IS,
+            )
+            .unwrap();
+            db.commit();
+            db
+        };
+        let plain = write("0.couch.1", DiskVersion::Thirteen)
+            .node_stats()
+            .unwrap();
+        let db = write("1.couch.1", DiskVersion::Fourteen);
+        let front_coded = db.node_stats().unwrap();
+        assert!(
+            front_coded.kv_bytes < plain.kv_bytes * 9 / 10,
+            "{front_coded:?} {plain:?}"
+        );
+
+        // Everything reads back the same
+        assert!(db.scrub(|_| {}).findings.is_empty());
+        let info = db.docinfo_by_id("\x08airline_00500").unwrap().unwrap();
+        assert_eq!(info.db_seq, 500);
+        let mut seqs = 0;
+        db.changes_since(0, |_, _| {
+            seqs += 1;
+            Ok(())
+        })
+        .unwrap();
+        assert_eq!(seqs, 1000);
+
+        // And compacting at an older version writes the keys out in full
+        let new_db = db
+            .compact_to(dir.path().join("1.couch.2"), DBOpenOptions::default())
+            .unwrap();
+        assert_eq!(new_db.header().disk_version(), DiskVersion::Thirteen);
+        assert_eq!(new_db.node_stats().unwrap().kv_bytes, plain.kv_bytes);
+        assert_eq!(
+            new_db
+                .docinfo_by_id("\x08airline_00500")
+                .unwrap()
+                .unwrap()
+                .db_seq,
+            500
+        );
+    }
+}
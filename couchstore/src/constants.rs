@@ -1,2 +1,21 @@
 pub(crate) const COUCH_BLOCK_SIZE: usize = 4096;
+/// Largest block size a version 15 file may declare
+pub(crate) const MAX_BLOCK_SIZE: usize = 1024 * 1024;
 pub(crate) const MAX_DB_HEADER_SIZE: usize = 1024;
+
+/// Largest chunk we'll decompress, well above the maximum document size
+pub(crate) const MAX_DECOMPRESSED_SIZE: usize = 64 * 1024 * 1024;
+
+/// B-tree nodes store key lengths in 12 bits
+pub(crate) const MAX_KEY_SIZE: usize = 4095;
+
+/// Bounds on the B-tree node size targets
+pub(crate) const MIN_NODE_SIZE: usize = 256;
+pub(crate) const MAX_NODE_SIZE: usize = 1024 * 1024;
+
+/// Largest minimum fan-out, which with the longest keys still keeps nodes
+/// to a few MiB
+pub(crate) const MAX_MIN_FANOUT: usize = 256;
+
+/// Default limit on a document body's uncompressed size
+pub(crate) const DEFAULT_MAX_VALUE_SIZE: usize = 20 * 1024 * 1024;
@@ -0,0 +1,255 @@
+//! Writing a file through O_DIRECT, so a compaction writing gigabytes
+//! doesn't push the pages the rest of the node reads out of the page cache.
+//!
+//! Direct writes have to be aligned, so the file's appends are gathered in
+//! an aligned buffer and written a whole buffer at a time. Flushing it part
+//! way through a block pads the block with zeros and truncates the file
+//! back to where its data ends, and the partial block stays in the buffer
+//! to be written again once there is more of it. Until the buffer is
+//! written reads are served from it.
+
+use std::{fs::File, io, path::Path};
+
+use crate::file_ops;
+
+/// What direct writes are aligned to. The logical block size of most
+/// devices is 512 bytes or 4 KiB, so this suits either.
+pub(crate) const DIRECT_IO_ALIGNMENT: usize = 4096;
+
+/// How much is gathered before being written
+const DIRECT_IO_BUFFER_SIZE: usize = 1024 * 1024;
+
+#[derive(Debug)]
+pub(crate) struct DirectWriter {
+    /// The file opened with O_DIRECT
+    file: File,
+    /// Allocated with room to start the buffer on an aligned address
+    alloc: Vec<u8>,
+    /// Where the aligned buffer starts in alloc
+    offset: usize,
+    /// Where the buffer goes in the file, aligned
+    start: u64,
+    /// How much of the buffer is data, the rest is zeros
+    len: usize,
+    /// How much of the data is already in the file
+    written: usize,
+    /// The file's length, without the padding of the latest flush
+    end: u64,
+}
+
+impl DirectWriter {
+    pub(crate) fn open(path: &Path, end: u64) -> io::Result<Self> {
+        Ok(Self::new(file_ops::open_direct(path)?, end))
+    }
+
+    fn new(file: File, end: u64) -> Self {
+        let alloc = vec![0; DIRECT_IO_BUFFER_SIZE + DIRECT_IO_ALIGNMENT];
+        let offset = alloc.as_ptr().align_offset(DIRECT_IO_ALIGNMENT);
+        Self {
+            file,
+            alloc,
+            offset,
+            start: align_down(end),
+            len: 0,
+            written: 0,
+            end,
+        }
+    }
+
+    fn buf(&self) -> &[u8] {
+        &self.alloc[self.offset..self.offset + DIRECT_IO_BUFFER_SIZE]
+    }
+
+    fn buf_mut(&mut self) -> &mut [u8] {
+        &mut self.alloc[self.offset..self.offset + DIRECT_IO_BUFFER_SIZE]
+    }
+
+    /// Write data at pos, returning how many bytes went to the file with
+    /// O_DIRECT. Anything but an append to the buffered data flushes it
+    /// and starts buffering again at pos, reading in the start of its block
+    /// through the cached file.
+    pub(crate) fn write(&mut self, cached: &File, mut data: &[u8], pos: u64) -> io::Result<u64> {
+        let mut direct_bytes = 0;
+        if pos != self.start + self.len as u64 {
+            direct_bytes += self.flush()?;
+            let len = self.len;
+            self.buf_mut()[..len].fill(0);
+            self.start = align_down(pos);
+            self.len = (pos - self.start) as usize;
+            let head = self.len;
+            let mut filled = 0;
+            while filled < head {
+                let got = file_ops::read_at(
+                    cached,
+                    &mut self.alloc[self.offset + filled..self.offset + head],
+                    self.start + filled as u64,
+                )?;
+                if got == 0 {
+                    // Past the end of the file, which reads as zeros
+                    break;
+                }
+                filled += got;
+            }
+            self.written = self.len;
+        }
+        while !data.is_empty() {
+            let copied = data.len().min(DIRECT_IO_BUFFER_SIZE - self.len);
+            let len = self.len;
+            self.buf_mut()[len..len + copied].copy_from_slice(&data[..copied]);
+            self.len += copied;
+            data = &data[copied..];
+            self.end = self.end.max(self.start + self.len as u64);
+            if self.len == DIRECT_IO_BUFFER_SIZE {
+                file_ops::write_all_at(&self.file, self.buf(), self.start)?;
+                direct_bytes += (self.len - self.written) as u64;
+                self.start += DIRECT_IO_BUFFER_SIZE as u64;
+                self.len = 0;
+                self.written = 0;
+                self.buf_mut().fill(0);
+            }
+        }
+        Ok(direct_bytes)
+    }
+
+    /// Write out the buffered data, padded to a whole block, keeping any
+    /// partial block buffered. Returns how many bytes weren't in the file
+    /// before.
+    pub(crate) fn flush(&mut self) -> io::Result<u64> {
+        if self.len == self.written {
+            return Ok(0);
+        }
+        let padded = align_up(self.len);
+        file_ops::write_all_at(&self.file, &self.buf()[..padded], self.start)?;
+        if self.start + padded as u64 > self.end {
+            self.file.set_len(self.end)?;
+        }
+        let direct_bytes = (self.len - self.written) as u64;
+
+        let tail = align_down(self.len as u64) as usize;
+        let len = self.len;
+        self.buf_mut().copy_within(tail..len, 0);
+        self.buf_mut()[len - tail..len].fill(0);
+        self.start += tail as u64;
+        self.len -= tail;
+        self.written = self.len;
+        Ok(direct_bytes)
+    }
+
+    /// The data not yet written, to write through the cached file when
+    /// direct writes fail part way
+    pub(crate) fn unwritten(&self) -> (&[u8], u64) {
+        (
+            &self.buf()[self.written..self.len],
+            self.start + self.written as u64,
+        )
+    }
+
+    /// Copy what is buffered of buf's range at pos over it, returning how
+    /// much of buf is then filled given the file filled the first got bytes
+    pub(crate) fn overlay(&self, buf: &mut [u8], pos: u64, got: usize) -> usize {
+        let from = pos.max(self.start);
+        let to = (pos + buf.len() as u64).min(self.start + self.len as u64);
+        if from >= to || from > pos + got as u64 {
+            return got;
+        }
+        let src = (from - self.start) as usize..(to - self.start) as usize;
+        buf[(from - pos) as usize..(to - pos) as usize].copy_from_slice(&self.buf()[src]);
+        got.max((to - pos) as usize)
+    }
+
+    /// The file's length, counting what is still buffered
+    pub(crate) fn end(&self) -> u64 {
+        self.end
+    }
+}
+
+fn align_down(pos: u64) -> u64 {
+    pos - pos % DIRECT_IO_ALIGNMENT as u64
+}
+
+fn align_up(len: usize) -> usize {
+    len.next_multiple_of(DIRECT_IO_ALIGNMENT)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{DBOpenOptions, Db, OpenOptions};
+
+    #[test]
+    fn test_direct_writer() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("file");
+        std::fs::write(&path, b"head").unwrap();
+        // The buffering is the same whether or not the file has O_DIRECT
+        let cached = file_ops::open(&path, false, false, false).unwrap();
+        let mut writer = DirectWriter::new(file_ops::open(&path, false, false, false).unwrap(), 4);
+        let mut expected = b"head".to_vec();
+        let data: Vec<u8> = (0..DIRECT_IO_BUFFER_SIZE + 5000)
+            .map(|i| (i % 251) as u8)
+            .collect();
+        for chunk in data.chunks(999) {
+            let pos = expected.len() as u64;
+            writer.write(&cached, chunk, pos).unwrap();
+            expected.extend_from_slice(chunk);
+        }
+        // Reads see what is still buffered
+        let mut buf = vec![0; 100];
+        let pos = expected.len() as u64 - 50;
+        let got = file_ops::read_at(&cached, &mut buf, pos).unwrap();
+        assert_eq!(writer.overlay(&mut buf, pos, got), 50);
+        assert_eq!(&buf[..50], &expected[expected.len() - 50..]);
+
+        // A flush pads to the block, but the file ends where its data does
+        assert!(writer.flush().unwrap() > 0);
+        assert_eq!(writer.flush().unwrap(), 0);
+        assert_eq!(std::fs::read(&path).unwrap(), expected);
+
+        // A write past the end starts a new block, zero filling the gap
+        let pos = expected.len() as u64 + 10;
+        writer.write(&cached, b"tail", pos).unwrap();
+        writer.flush().unwrap();
+        expected.extend_from_slice(&[0; 10]);
+        expected.extend_from_slice(b"tail");
+        assert_eq!(std::fs::read(&path).unwrap(), expected);
+    }
+
+    #[test]
+    fn test_direct_io_db() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("0.couch.1");
+        let mut db = Db::open(&path, DBOpenOptions::default().direct_io()).unwrap();
+        let value = |i: usize| format!("value {i} ").repeat(i % 50 + 1).into_bytes();
+        for batch in 0..3 {
+            for i in batch * 500..(batch + 1) * 500 {
+                db.set(format!("key{i}").into_bytes(), value(i)).unwrap();
+            }
+            db.commit();
+            // Each commit is in the file for other handles, without padding
+            let len = std::fs::metadata(&path).unwrap().len();
+            assert_eq!(len, db.header_end());
+            let reader = Db::open(&path, DBOpenOptions::default().read_only()).unwrap();
+            assert_eq!(reader.header().update_seq, (batch as u64 + 1) * 500);
+        }
+        // Before a commit, what is buffered reads back
+        db.set(b"uncommitted".to_vec(), b"value".to_vec()).unwrap();
+        assert!(db.docinfo_by_id("uncommitted").unwrap().is_some());
+        db.commit();
+
+        let stats = db.file_stats();
+        if cfg!(target_os = "linux") && !stats.direct_io_fallback {
+            assert!(stats.direct_bytes_written > 0, "{stats:?}");
+            assert!(stats.direct_bytes_written <= stats.bytes_written);
+        }
+        drop(db);
+        let db = Db::open(&path, DBOpenOptions::default().read_only()).unwrap();
+        for i in (0..1500).step_by(7) {
+            let info = db.docinfo_by_id(format!("key{i}")).unwrap().unwrap();
+            let doc = db
+                .open_doc_with_docinfo(&info, OpenOptions::DECOMPRESS_DOC_BODIES)
+                .unwrap()
+                .unwrap();
+            assert_eq!(doc.data, value(i));
+        }
+    }
+}
@@ -0,0 +1,38 @@
+use std::io;
+
+use thiserror::Error;
+
+pub type CouchstoreResult<T> = Result<T, CouchstoreError>;
+
+#[derive(Error, Debug)]
+pub enum CouchstoreError {
+    #[error("checksum mismatch")]
+    ChecksumFail,
+    #[error("corrupt file ({0})")]
+    Corrupt(&'static str),
+    #[error("no valid header found")]
+    NoHeader,
+    /// A document's key or body is over the database's size limit
+    #[error("{0} too big")]
+    TooBig(&'static str),
+    /// B-tree tuning outside the format's limits
+    #[error("invalid tuning: {0}")]
+    InvalidTuning(String),
+    /// A block size the file format can't have
+    #[error("invalid block size: {0}")]
+    InvalidBlockSize(String),
+    /// Documents given to a bulk load that it can't take
+    #[error("bulk load: {0}")]
+    BulkLoad(&'static str),
+    #[error("no auxiliary tree named {0}")]
+    NoSuchTree(String),
+    #[error(transparent)]
+    Read {
+        #[from]
+        source: io::Error,
+    },
+    /// Writing to the file failed (ENOSPC, EIO...), so nothing written since
+    /// the last commit can be relied on
+    #[error("write failed: {0}")]
+    Write(io::Error),
+}
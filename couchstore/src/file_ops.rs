@@ -0,0 +1,134 @@
+//! The platform specific parts of the file handling, so the rest of the
+//! crate is the same on Unix and Windows.
+//!
+//! On Windows a file is opened with every share mode, so like on Unix
+//! other handles may read, write, rename or delete it while it's open. Any
+//! number of readers and a writer can then share a vbucket's file, and
+//! compaction can replace it under them.
+
+use std::{
+    fs::{File, OpenOptions},
+    io,
+    path::Path,
+};
+
+#[cfg(windows)]
+const FILE_SHARE_READ: u32 = 0x1;
+#[cfg(windows)]
+const FILE_SHARE_WRITE: u32 = 0x2;
+#[cfg(windows)]
+const FILE_SHARE_DELETE: u32 = 0x4;
+#[cfg(windows)]
+const FILE_FLAG_WRITE_THROUGH: u32 = 0x8000_0000;
+
+/// Open a database file. With dsync each write is durable before it returns
+/// (O_DSYNC on Unix, FILE_FLAG_WRITE_THROUGH on Windows).
+pub(crate) fn open(path: &Path, read_only: bool, create: bool, dsync: bool) -> io::Result<File> {
+    let mut options = OpenOptions::new();
+    options
+        .read(true)
+        .write(!read_only)
+        .create(!read_only && create);
+    #[cfg(unix)]
+    if dsync {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.custom_flags(libc::O_DSYNC);
+    }
+    #[cfg(windows)]
+    {
+        use std::os::windows::fs::OpenOptionsExt;
+        options.share_mode(FILE_SHARE_READ | FILE_SHARE_WRITE | FILE_SHARE_DELETE);
+        if dsync {
+            options.custom_flags(FILE_FLAG_WRITE_THROUGH);
+        }
+    }
+    options.open(path)
+}
+
+/// Open a database file for writes that bypass the page cache, O_DIRECT on
+/// Linux. Those writes must be aligned to DIRECT_IO_ALIGNMENT in offset,
+/// length and memory. Elsewhere, and on filesystems which don't support it
+/// such as tmpfs, this fails and the file has to be written through the
+/// cache.
+#[cfg(target_os = "linux")]
+pub(crate) fn open_direct(path: &Path) -> io::Result<File> {
+    use std::os::unix::fs::OpenOptionsExt;
+    OpenOptions::new()
+        .write(true)
+        .custom_flags(libc::O_DIRECT)
+        .open(path)
+}
+
+#[cfg(not(target_os = "linux"))]
+pub(crate) fn open_direct(_path: &Path) -> io::Result<File> {
+    Err(io::ErrorKind::Unsupported.into())
+}
+
+/// Positional writes, which like positional reads leave the cursor alone
+#[cfg(unix)]
+pub(crate) fn write_all_at(file: &File, buf: &[u8], pos: u64) -> io::Result<()> {
+    std::os::unix::fs::FileExt::write_all_at(file, buf, pos)
+}
+
+#[cfg(windows)]
+pub(crate) fn write_all_at(file: &File, mut buf: &[u8], mut pos: u64) -> io::Result<()> {
+    while !buf.is_empty() {
+        match std::os::windows::fs::FileExt::seek_write(file, buf, pos)? {
+            0 => return Err(io::ErrorKind::WriteZero.into()),
+            written => {
+                buf = &buf[written..];
+                pos += written as u64;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Positional reads leave the file's cursor alone, so any number of
+/// readers can share a file without taking turns
+#[cfg(unix)]
+pub(crate) fn read_at(file: &File, buf: &mut [u8], pos: u64) -> io::Result<usize> {
+    std::os::unix::fs::FileExt::read_at(file, buf, pos)
+}
+
+#[cfg(windows)]
+pub(crate) fn read_at(file: &File, buf: &mut [u8], pos: u64) -> io::Result<usize> {
+    std::os::windows::fs::FileExt::seek_read(file, buf, pos)
+}
+
+/// Make the file's data durable. This is fdatasync on Unix and
+/// FlushFileBuffers on Windows, which also flushes the metadata as it has
+/// no data-only variant.
+pub(crate) fn sync(file: &File) -> io::Result<()> {
+    file.sync_data()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::io::Write;
+
+    #[test]
+    fn test_shared_access() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("0.couch.1");
+        let mut writer = open(&path, false, true, true).unwrap();
+        writer.write_all(b"data").unwrap();
+        sync(&writer).unwrap();
+
+        // Readers share the file with the writer
+        let reader = open(&path, true, false, false).unwrap();
+        let mut buf = [0; 2];
+        assert_eq!(read_at(&reader, &mut buf, 2).unwrap(), 2);
+        assert_eq!(&buf, b"ta");
+
+        // And it can be replaced while they have it open
+        let renamed = dir.path().join("0.couch.2");
+        std::fs::rename(&path, &renamed).unwrap();
+        std::fs::remove_file(&renamed).unwrap();
+        assert_eq!(
+            open(&path, true, false, false).unwrap_err().kind(),
+            io::ErrorKind::NotFound
+        );
+    }
+}
@@ -1,79 +1,241 @@
 use byteorder::{BigEndian, ReadBytesExt};
-use crc32c::crc32c;
-use std::io::{Cursor, Read, Seek, SeekFrom};
+use std::{io::Cursor, sync::atomic::Ordering};
 
-use crate::{constants::COUCH_BLOCK_SIZE, TreeFile};
+use crate::{
+    constants::MAX_DECOMPRESSED_SIZE, file_ops, node_types::expand_node, CouchstoreError,
+    CouchstoreResult, CrcMode, DiskBlockType, DiskVersion, TreeFile,
+};
 
 impl TreeFile {
-    pub fn read_compressed(&mut self, pos: usize) -> Vec<u8> {
-        let compressed_buf = self.read(pos, None);
+    pub fn read_compressed(&self, pos: usize) -> CouchstoreResult<Vec<u8>> {
+        let compressed_buf = self.read(&mut { pos }, None, |_| Ok(self.crc_mode))?;
+
+        // Don't trust the length in a corrupt chunk enough to allocate it
+        let len = snap::raw::decompress_len(&compressed_buf)
+            .map_err(|_| CouchstoreError::Corrupt("invalid compressed chunk"))?;
+        if len > MAX_DECOMPRESSED_SIZE {
+            return Err(CouchstoreError::Corrupt("compressed chunk too large"));
+        }
 
         // Couchstore does not use the frame format so we need the raw decoder.
         snap::raw::Decoder::new()
             .decompress_vec(&compressed_buf)
-            .unwrap()
+            .map_err(|_| CouchstoreError::Corrupt("invalid compressed chunk"))
+    }
+
+    /// Read the B-tree node at pos, in the plain encoding whichever it was
+    /// written in
+    pub fn read_node(&self, pos: usize) -> CouchstoreResult<Vec<u8>> {
+        expand_node(self.read_compressed(pos)?)
     }
 
-    pub fn read_uncompressed(&mut self, pos: usize) -> Vec<u8> {
-        self.read(pos, None)
+    pub fn read_uncompressed(&self, pos: usize) -> CouchstoreResult<Vec<u8>> {
+        self.read(&mut { pos }, None, |_| Ok(self.crc_mode))
     }
 
-    fn read(&mut self, mut pos: usize, max_header_size: Option<usize>) -> Vec<u8> {
+    /// Read the chunk at pos, the equivalent of couchstore's
+    /// pread_bin_internal. Leaves pos at the end of the chunk. crc_mode
+    /// gives the chunk's checksum algorithm given its contents.
+    fn read(
+        &self,
+        pos: &mut usize,
+        max_header_size: Option<usize>,
+        crc_mode: impl FnOnce(&[u8]) -> CouchstoreResult<CrcMode>,
+    ) -> CouchstoreResult<Vec<u8>> {
         let mut info = [0u8; 8];
 
-        self.read_skipping_prefixes(&mut pos, &mut info);
+        self.read_skipping_prefixes(pos, &mut info)?;
 
         let mut cursor = Cursor::new(&info);
         // something is stored in the highest bit of the first byte
-        let mut chunk_len = cursor.read_u32::<BigEndian>().unwrap() & !0x80000000;
-        let crc32 = cursor.read_u32::<BigEndian>().unwrap();
+        let mut chunk_len = cursor.read_u32::<BigEndian>()? & !0x80000000;
+        let crc32 = cursor.read_u32::<BigEndian>()?;
 
         if let Some(max_header_size) = max_header_size {
-            assert!(chunk_len as usize <= max_header_size);
-            chunk_len -= 4; // Header len includes CRC len.
+            if chunk_len as usize > max_header_size {
+                return Err(CouchstoreError::Corrupt("header too large"));
+            }
+            // Header len includes CRC len.
+            chunk_len = chunk_len
+                .checked_sub(4)
+                .ok_or(CouchstoreError::Corrupt("header too small"))?;
+        }
+
+        // The chunk can't extend past the end of the file, which bounds the
+        // allocation for a corrupt length
+        let file_len = self.file_len()? as usize;
+        if chunk_len as usize > file_len.saturating_sub(*pos) {
+            return Err(CouchstoreError::Corrupt("chunk extends past end of file"));
         }
 
         // TODO: Reuse buffer
         let mut buf = vec![0u8; chunk_len as usize];
 
-        self.read_skipping_prefixes(&mut pos, &mut buf);
+        self.read_skipping_prefixes(pos, &mut buf)?;
+
+        let crc32_calc = crc_mode(&buf)?.checksum(&buf);
+
+        if crc32 != crc32_calc {
+            return Err(CouchstoreError::ChecksumFail);
+        }
+
+        Ok(buf)
+    }
 
-        let crc32_calc = crc32c(&buf);
+    /// Read at pos, including what direct IO has yet to write out
+    fn read_at(&self, buf: &mut [u8], pos: u64) -> std::io::Result<usize> {
+        let got = match &self.read_ahead {
+            Some(read_ahead) => read_ahead
+                .lock()
+                .unwrap_or_else(|e| e.into_inner())
+                .read_at(self, buf, pos)?,
+            None => self.read_file_at(buf, pos)?,
+        };
+        Ok(match &self.direct {
+            Some(direct) => direct.overlay(buf, pos, got),
+            None => got,
+        })
+    }
 
-        assert_eq!(crc32, crc32_calc);
+    /// Read at pos from the file itself, counting the read
+    fn read_file_at(&self, buf: &mut [u8], pos: u64) -> std::io::Result<usize> {
+        let got = file_ops::read_at(&self.file, buf, pos)?;
+        self.reads.fetch_add(1, Ordering::Relaxed);
+        self.bytes_read.fetch_add(got as u64, Ordering::Relaxed);
+        Ok(got)
+    }
 
-        buf
+    fn file_len(&self) -> std::io::Result<u64> {
+        match &self.direct {
+            Some(direct) => Ok(direct.end()),
+            None => Ok(self.file.metadata()?.len()),
+        }
     }
 
-    pub fn read_header(&mut self, pos: usize, max_header_size: usize) -> Vec<u8> {
-        self.read(pos + 1, Some(max_header_size))
+    /// Whether the block at pos starts a header, judging by its prefix
+    pub fn is_header_block(&self, pos: usize) -> CouchstoreResult<bool> {
+        let mut prefix = [0u8];
+        if self.read_at(&mut prefix, pos as u64)? == 0 {
+            return Err(CouchstoreError::Corrupt("unexpected end of file"));
+        }
+        Ok(DiskBlockType::try_from(prefix[0]) == Ok(DiskBlockType::Header))
     }
 
-    pub fn read_skipping_prefixes(&mut self, pos: &mut usize, mut buf: &mut [u8]) {
-        if *pos % COUCH_BLOCK_SIZE == 0 {
+    /// Read the header at pos, returning it and where it ends
+    pub fn read_header(
+        &self,
+        pos: usize,
+        max_header_size: usize,
+    ) -> CouchstoreResult<(Vec<u8>, usize)> {
+        let mut pos = pos + 1;
+        let buf = self.read(&mut pos, Some(max_header_size), |_| Ok(self.crc_mode))?;
+        Ok((buf, pos))
+    }
+
+    /// As read_header, for a database header. Its version, in its first
+    /// byte, says how it's checksummed, so the file's checksums can be
+    /// found from its header.
+    pub fn read_db_header(
+        &self,
+        pos: usize,
+        max_header_size: usize,
+    ) -> CouchstoreResult<(Vec<u8>, usize)> {
+        let mut pos = pos + 1;
+        let buf = self.read(&mut pos, Some(max_header_size), |buf| {
+            let version = buf
+                .first()
+                .ok_or(CouchstoreError::Corrupt("truncated header"))?;
+            DiskVersion::try_from(*version)
+                .map(DiskVersion::crc_mode)
+                .map_err(|_| CouchstoreError::Corrupt("unknown disk version"))
+        })?;
+        Ok((buf, pos))
+    }
+
+    /// Fill buf from pos, skipping the block prefix byte at the start of
+    /// each block. Fails if the file ends first.
+    pub fn read_skipping_prefixes(
+        &self,
+        pos: &mut usize,
+        mut buf: &mut [u8],
+    ) -> CouchstoreResult<()> {
+        if (*pos).is_multiple_of(self.block_size) {
             *pos += 1;
         }
 
         while !buf.is_empty() {
-            let mut read_size = COUCH_BLOCK_SIZE - (*pos % COUCH_BLOCK_SIZE);
+            let mut read_size = self.block_size - (*pos % self.block_size);
             if read_size > buf.len() {
                 read_size = buf.len();
             }
 
-            self.file.seek(SeekFrom::Start(*pos as u64)).unwrap();
-            let got_bytes = self.file.read(&mut buf[..read_size]).unwrap();
+            let got_bytes = self.read_at(&mut buf[..read_size], *pos as u64)?;
 
             if got_bytes == 0 {
-                break;
+                return Err(CouchstoreError::Corrupt("unexpected end of file"));
             }
 
             *pos += got_bytes;
 
             buf = &mut buf[got_bytes..];
 
-            if *pos % COUCH_BLOCK_SIZE == 0 {
+            if (*pos).is_multiple_of(self.block_size) {
                 *pos += 1;
             }
         }
+
+        Ok(())
+    }
+}
+
+/// A chunk of the file read ahead of the reads which follow
+#[derive(Debug)]
+pub(crate) struct ReadAhead {
+    /// How much each read of the file reads
+    size: usize,
+    buf: Vec<u8>,
+    /// Where buf is in the file
+    pos: u64,
+}
+
+impl ReadAhead {
+    pub(crate) fn new(size: usize) -> Self {
+        Self {
+            size,
+            buf: Vec::new(),
+            pos: 0,
+        }
+    }
+
+    /// Read at pos from the chunk, first reading the chunk starting at pos
+    /// if it doesn't hold all of buf's range. The file is only appended to,
+    /// so what was read ahead doesn't change.
+    pub(crate) fn read_at(
+        &mut self,
+        file: &TreeFile,
+        buf: &mut [u8],
+        pos: u64,
+    ) -> std::io::Result<usize> {
+        let end = pos + buf.len() as u64;
+        if pos < self.pos || end > self.pos + self.buf.len() as u64 {
+            if buf.len() >= self.size {
+                return file.read_file_at(buf, pos);
+            }
+            self.buf.resize(self.size, 0);
+            let mut filled = 0;
+            while filled < self.size {
+                let got = file.read_file_at(&mut self.buf[filled..], pos + filled as u64)?;
+                if got == 0 {
+                    break;
+                }
+                filled += got;
+            }
+            self.buf.truncate(filled);
+            self.pos = pos;
+        }
+        let start = (pos - self.pos) as usize;
+        let len = buf.len().min(self.buf.len() - start);
+        buf[..len].copy_from_slice(&self.buf[start..start + len]);
+        Ok(len)
     }
 }
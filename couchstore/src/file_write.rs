@@ -1,13 +1,90 @@
 use byteorder::{BigEndian, WriteBytesExt};
 use std::io::{Cursor, Seek, SeekFrom, Write};
 
-use crate::{constants::COUCH_BLOCK_SIZE, utils::align_to_next_block, DiskBlockType, TreeFile};
+use crate::{
+    file_ops, utils::align_to_next_block, CouchstoreError, CouchstoreResult, DiskBlockType,
+    TreeFile,
+};
 
 impl TreeFile {
     pub fn write_entire_buffer(&mut self, buf: &[u8], offset: usize) {
-        self.file.seek(SeekFrom::Start(offset as u64)).unwrap();
-        self.file.write_all(buf).unwrap();
-        self.file.flush().unwrap();
+        if self.write_error.is_some() {
+            return;
+        }
+        if let Some(direct) = &mut self.direct {
+            match direct.write(&self.file, buf, offset as u64) {
+                Ok(direct_bytes) => {
+                    self.stats.bytes_written += buf.len() as u64;
+                    self.stats.direct_bytes_written += direct_bytes;
+                    return;
+                }
+                Err(e) if is_direct_io_refused(&e) => self.stop_direct_io(),
+                Err(e) => {
+                    self.write_error = Some(e);
+                    return;
+                }
+            }
+        }
+        let result = self
+            .file
+            .seek(SeekFrom::Start(offset as u64))
+            .and_then(|_| self.file.write_all(buf))
+            .and_then(|_| self.file.flush());
+        match result {
+            Ok(()) => self.stats.bytes_written += buf.len() as u64,
+            Err(e) => self.write_error = Some(e),
+        }
+    }
+
+    /// Make everything written so far durable
+    pub fn sync(&mut self) {
+        self.flush_direct();
+        if self.write_error.is_some() {
+            return;
+        }
+        match file_ops::sync(&self.file) {
+            Ok(()) => self.stats.syncs += 1,
+            Err(e) => self.write_error = Some(e),
+        }
+    }
+
+    /// Write out what direct IO has buffered, so other handles on the file
+    /// can read it
+    pub fn flush_direct(&mut self) {
+        if self.write_error.is_some() {
+            return;
+        }
+        let Some(direct) = &mut self.direct else {
+            return;
+        };
+        match direct.flush() {
+            Ok(direct_bytes) => self.stats.direct_bytes_written += direct_bytes,
+            Err(e) if is_direct_io_refused(&e) => self.stop_direct_io(),
+            Err(e) => self.write_error = Some(e),
+        }
+    }
+
+    /// Write what direct IO hadn't yet through the page cache, and carry on
+    /// that way. Some filesystems accept O_DIRECT but refuse the writes.
+    fn stop_direct_io(&mut self) {
+        let Some(direct) = self.direct.take() else {
+            return;
+        };
+        self.stats.direct_io_fallback = true;
+        let (unwritten, pos) = direct.unwritten();
+        let result = file_ops::write_all_at(&self.file, unwritten, pos)
+            .and_then(|_| self.file.set_len(direct.end()));
+        if let Err(e) = result {
+            self.write_error = Some(e);
+        }
+    }
+
+    /// Whether every write so far succeeded. The error is reported once.
+    pub fn check_writes(&mut self) -> CouchstoreResult<()> {
+        match self.write_error.take() {
+            Some(e) => Err(CouchstoreError::Write(e)),
+            None => Ok(()),
+        }
     }
 
     pub fn raw_write(
@@ -20,12 +97,12 @@ impl TreeFile {
         let mut block_remain;
         // break up the write buffer into blocks adding the block prefix as needed
         while !buf.is_empty() {
-            block_remain = COUCH_BLOCK_SIZE - (write_pos % COUCH_BLOCK_SIZE);
+            block_remain = self.block_size - (write_pos % self.block_size);
             if block_remain > buf.len() {
                 block_remain = buf.len();
             }
 
-            if write_pos % COUCH_BLOCK_SIZE == 0 {
+            if write_pos.is_multiple_of(self.block_size) {
                 self.write_entire_buffer(&[disk_block_type.into()], write_pos);
                 write_pos += 1;
                 continue;
@@ -40,10 +117,10 @@ impl TreeFile {
     }
 
     pub fn write_header(&mut self, buf: &[u8]) -> usize {
-        let mut write_pos = align_to_next_block(self.pos);
+        let mut write_pos = align_to_next_block(self.pos, self.block_size);
 
         let size = (buf.len() + 4) as u32; // Len before header includes hash len.
-        let crc32 = crc32c::crc32c(buf);
+        let crc32 = self.crc_mode.checksum(buf);
 
         let mut header_buf = [0u8; 9];
         let mut cursor = Cursor::new(&mut header_buf[..]);
@@ -63,6 +140,9 @@ impl TreeFile {
         self.raw_write(DiskBlockType::Header, buf, write_pos);
         write_pos += buf.len();
         self.pos = write_pos;
+        // Readers on other handles need the commit in the file, even when
+        // it isn't synced yet
+        self.flush_direct();
 
         pos
     }
@@ -73,7 +153,7 @@ impl TreeFile {
         let mut written;
 
         let size = buf.len() | 0x8000_0000;
-        let crc32 = crc32c::crc32c(buf);
+        let crc32 = self.crc_mode.checksum(buf);
 
         let mut header_buf = [0u8; 8];
         let mut cursor = Cursor::new(&mut header_buf[..]);
@@ -102,3 +182,11 @@ impl TreeFile {
         self.db_write_buf(&compressed_buf, pos, disk_size)
     }
 }
+
+/// EINVAL from a direct write means the filesystem doesn't support it
+fn is_direct_io_refused(e: &std::io::Error) -> bool {
+    matches!(
+        e.kind(),
+        std::io::ErrorKind::InvalidInput | std::io::ErrorKind::Unsupported
+    )
+}
@@ -0,0 +1,183 @@
+//! How vbucket files are named in a data directory. Each vbucket's data is
+//! in `<vbid>.couch.<revision>`, the revision going up each time the file is
+//! replaced, with a suffix for the files written while replacing it.
+
+use std::{
+    fmt::{self, Display},
+    io,
+    path::{Path, PathBuf},
+};
+
+/// What a vbucket file holds
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum DbFileKind {
+    /// The vbucket's data
+    Data,
+    /// Compaction's output, renamed over the data file once complete
+    Compact,
+    /// A file being written to replace the data file wholesale, renamed
+    /// into place once complete
+    Prepare,
+}
+
+impl DbFileKind {
+    fn suffix(self) -> &'static str {
+        match self {
+            DbFileKind::Data => "",
+            DbFileKind::Compact => ".compact",
+            DbFileKind::Prepare => ".prepare",
+        }
+    }
+}
+
+/// The parts of a vbucket file's name
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct DbFileName {
+    pub vbid: u16,
+    pub revision: u64,
+    pub kind: DbFileKind,
+}
+
+impl DbFileName {
+    /// The name of a vbucket's data file
+    pub fn new(vbid: u16, revision: u64) -> Self {
+        Self {
+            vbid,
+            revision,
+            kind: DbFileKind::Data,
+        }
+    }
+
+    /// Parse a file name, None if it isn't a vbucket file. Only the exact
+    /// form Display gives is accepted, so `05.couch.1` isn't taken for
+    /// vbucket 5's file, and other files (`master.couch.1`) are ignored.
+    pub fn parse(file_name: &str) -> Option<Self> {
+        let (vbid, rest) = file_name.split_once(".couch.")?;
+        let (revision, kind) = [DbFileKind::Compact, DbFileKind::Prepare]
+            .into_iter()
+            .find_map(|kind| Some((rest.strip_suffix(kind.suffix())?, kind)))
+            .unwrap_or((rest, DbFileKind::Data));
+        let name = Self {
+            vbid: vbid.parse().ok()?,
+            revision: revision.parse().ok()?,
+            kind,
+        };
+        (name.to_string() == file_name).then_some(name)
+    }
+}
+
+impl Display for DbFileName {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}.couch.{}{}",
+            self.vbid,
+            self.revision,
+            self.kind.suffix()
+        )
+    }
+}
+
+/// Composes and finds the paths of the vbucket files in a data directory
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DbNameLayout {
+    dir: PathBuf,
+}
+
+impl DbNameLayout {
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        Self { dir: dir.into() }
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// Path of the vbucket's data file at the revision
+    pub fn path(&self, vbid: u16, revision: u64) -> PathBuf {
+        self.path_of(&DbFileName::new(vbid, revision))
+    }
+
+    pub fn path_of(&self, name: &DbFileName) -> PathBuf {
+        self.dir.join(name.to_string())
+    }
+
+    /// Every vbucket file in the directory, of every kind, in no particular
+    /// order
+    pub fn list(&self) -> io::Result<Vec<DbFileName>> {
+        let mut names = Vec::new();
+        for entry in std::fs::read_dir(&self.dir)? {
+            let entry = entry?;
+            if let Some(name) = entry.file_name().to_str().and_then(DbFileName::parse) {
+                names.push(name);
+            }
+        }
+        Ok(names)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let name = |vbid, revision, kind| {
+            Some(DbFileName {
+                vbid,
+                revision,
+                kind,
+            })
+        };
+        for (file_name, expected) in [
+            ("0.couch.1", name(0, 1, DbFileKind::Data)),
+            (
+                "65535.couch.18446744073709551615",
+                name(65535, u64::MAX, DbFileKind::Data),
+            ),
+            ("5.couch.2.compact", name(5, 2, DbFileKind::Compact)),
+            ("5.couch.2.prepare", name(5, 2, DbFileKind::Prepare)),
+            ("master.couch.1", None),
+            ("05.couch.1", None),
+            ("+5.couch.1", None),
+            ("5.couch.01", None),
+            ("65536.couch.1", None),
+            ("-1.couch.1", None),
+            ("5.couch.", None),
+            ("5.couch", None),
+            ("5.couch.1.compact.compact", None),
+            ("5.couch.1.tmp", None),
+            ("5.couch.1.prepare.compact", None),
+            ("rocksdb.0", None),
+        ] {
+            assert_eq!(DbFileName::parse(file_name), expected, "{file_name}");
+            if let Some(parsed) = expected {
+                assert_eq!(parsed.to_string(), file_name);
+            }
+        }
+    }
+
+    #[test]
+    fn test_list() {
+        let dir = tempfile::tempdir().unwrap();
+        let layout = DbNameLayout::new(dir.path());
+        for file_name in ["0.couch.1", "0.couch.2.compact", "master.couch.1", "x"] {
+            std::fs::write(dir.path().join(file_name), b"").unwrap();
+        }
+        assert_eq!(layout.path(0, 1), dir.path().join("0.couch.1"));
+
+        let mut names = layout.list().unwrap();
+        names.sort_by_key(|name| name.revision);
+        assert_eq!(
+            names,
+            [
+                DbFileName::new(0, 1),
+                DbFileName {
+                    vbid: 0,
+                    revision: 2,
+                    kind: DbFileKind::Compact,
+                },
+            ]
+        );
+    }
+}
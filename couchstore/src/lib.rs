@@ -1,27 +1,82 @@
 use std::{
     cmp::Ordering,
     fs::File,
-    io::{self, Cursor, Read, Seek, SeekFrom, Write},
+    io::{self, Cursor, Read, Seek, SeekFrom},
     path::Path,
+    sync::{atomic::AtomicU64, Mutex},
 };
+mod aux_trees;
 mod btree;
 mod btree_modify;
 mod btree_read;
+mod bulk_load;
+mod changes_feed;
+mod compact;
 mod constants;
+mod direct_io;
+mod error;
+mod file_ops;
 mod file_read;
 mod file_write;
+mod layout;
 mod node_types;
+mod reduce;
 mod save;
+mod scrub;
+mod sync;
 mod utils;
+mod views;
 
+pub use btree_modify::BtreeTuning;
 use btree_modify::{CouchfileModifyAction, CouchfileModifyActionType, CouchfileModifyRequest};
+pub use bulk_load::BulkLoader;
 use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
-use constants::COUCH_BLOCK_SIZE;
+use constants::{
+    COUCH_BLOCK_SIZE, DEFAULT_MAX_VALUE_SIZE, MAX_BLOCK_SIZE, MAX_DECOMPRESSED_SIZE, MAX_KEY_SIZE,
+};
 use node_types::{decode_kv_length, RawFileHeaderV13};
 use num_enum::{IntoPrimitive, TryFromPrimitive};
 use utils::align_to_next_block;
 
 use crate::{btree::CouchfileLookupRequest, constants::MAX_DB_HEADER_SIZE};
+pub use changes_feed::{Change, ChangesFeed};
+pub use compact::{DbInfo, NodeStats};
+pub use error::{CouchstoreError, CouchstoreResult};
+pub use layout::{DbFileKind, DbFileName, DbNameLayout};
+pub use reduce::DocCounts;
+use reduce::Reducer;
+pub use scrub::{ScrubFinding, ScrubReport};
+pub use sync::{SyncPolicy, SyncState};
+pub use views::{
+    BtreeState, IdBtreeEntry, IdBtreeReduction, IndexHeader, PendingTransition, ViewBtreeEntry,
+    ViewBtreeReduction, ViewFile,
+};
+
+/// Entry points into the on-disk decoders for the fuzz targets
+#[cfg(feature = "fuzzing")]
+pub mod fuzzing {
+    use crate::{
+        node_types::{expand_node, RawNode},
+        CouchstoreResult, DocInfo,
+    };
+
+    /// Decode a node, expanding front-coded keys, and its items as both
+    /// by-id and by-seq index values
+    pub fn decode_node(buf: &[u8]) -> CouchstoreResult<()> {
+        let buf = expand_node(buf.to_vec())?;
+        let node = RawNode::decode(&buf)?;
+        for (key, value) in node.items {
+            let _ = DocInfo::decode_id_index_value(key.to_vec(), value);
+            let _ = DocInfo::decode_by_seq_index_value(key, value);
+        }
+        Ok(())
+    }
+
+    /// Decode the body of a header chunk found at pos
+    pub fn decode_header(buf: &[u8], pos: usize) -> CouchstoreResult<()> {
+        crate::decode_header(buf, pos).map(|_| ())
+    }
+}
 
 #[derive(Debug, Clone, Copy, IntoPrimitive, TryFromPrimitive, PartialEq, Eq)]
 #[repr(u8)]
@@ -30,10 +85,18 @@ pub enum DiskBlockType {
     Header = 0x01,
 }
 
+/// A database file. Reads take &self and use positional IO, so one Db can
+/// serve readers on several threads at once, while writes need it to
+/// themselves.
 #[derive(Debug)]
 pub struct Db {
     file: TreeFile,
     header: Header,
+    /// Where the latest header ends, anything after it is a commit still
+    /// being written or one that was torn
+    header_end: u64,
+    /// The file's bytes_written as of the latest commit
+    committed_bytes: u64,
     opts: DBOpenOptions,
 }
 
@@ -50,9 +113,34 @@ pub struct Header {
     purge_ptr: u64,
     position: u64,
     timestamp: u64,
+    block_size: usize,
+    aux_roots: aux_trees::AuxRoots,
 }
 
 impl Header {
+    /// When the header was committed, in nanoseconds since the epoch. A
+    /// file's initial header has a timestamp of 0.
+    pub fn timestamp(&self) -> u64 {
+        self.timestamp
+    }
+
+    /// Offset of the header in the file
+    pub fn position(&self) -> u64 {
+        self.position
+    }
+
+    /// The version of the file's format. Files of older versions are
+    /// written in their own format until compaction rewrites them.
+    pub fn disk_version(&self) -> DiskVersion {
+        self.disk_version
+    }
+
+    /// The size of the file's blocks, which only version 15 files may have
+    /// other than 4 KiB
+    pub fn block_size(&self) -> usize {
+        self.block_size
+    }
+
     fn _reset(&mut self) {
         self.by_id_root = None;
         self.by_seq_root = None;
@@ -60,13 +148,74 @@ impl Header {
     }
 }
 
-#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, IntoPrimitive, TryFromPrimitive)]
+#[derive(
+    Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, IntoPrimitive, TryFromPrimitive,
+)]
 #[repr(u8)]
 pub enum DiskVersion {
     Eleven = 11,
     Twelve = 12,
     #[default]
     Thirteen = 13,
+    /// Front-codes the keys of KV nodes where that makes them smaller.
+    /// Opt in with DBOpenOptions::disk_version, as older engines can't
+    /// read it.
+    Fourteen = 14,
+    /// Declares its block size in each header, so files can be written
+    /// with blocks larger than 4 KiB to evaluate them. Experimental, opt in
+    /// with DBOpenOptions::disk_version and block_size.
+    Fifteen = 15,
+}
+
+impl DiskVersion {
+    /// Every version, oldest first
+    pub const ALL: [DiskVersion; 5] = [
+        DiskVersion::Eleven,
+        DiskVersion::Twelve,
+        DiskVersion::Thirteen,
+        DiskVersion::Fourteen,
+        DiskVersion::Fifteen,
+    ];
+
+    /// Versions before 13 have no commit timestamp in their headers
+    fn has_timestamp(self) -> bool {
+        self >= DiskVersion::Thirteen
+    }
+
+    /// Version 11 files checksum with CRC32, later ones with CRC32C
+    fn crc_mode(self) -> CrcMode {
+        match self {
+            DiskVersion::Eleven => CrcMode::Crc32,
+            _ => CrcMode::Crc32c,
+        }
+    }
+
+    /// Version 14 files may have KV nodes with front-coded keys
+    fn front_codes_keys(self) -> bool {
+        self >= DiskVersion::Fourteen
+    }
+
+    /// Version 15 headers say how big the file's blocks are, earlier
+    /// versions' blocks are always COUCH_BLOCK_SIZE
+    fn declares_block_size(self) -> bool {
+        self >= DiskVersion::Fifteen
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+enum CrcMode {
+    Crc32,
+    #[default]
+    Crc32c,
+}
+
+impl CrcMode {
+    fn checksum(self, buf: &[u8]) -> u32 {
+        match self {
+            CrcMode::Crc32 => crc32fast::hash(buf),
+            CrcMode::Crc32c => crc32c::crc32c(buf),
+        }
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -78,39 +227,45 @@ pub struct NodePointer {
 }
 
 impl NodePointer {
-    fn read_root(mut buf: impl io::Read, root_size: usize) -> Option<NodePointer> {
+    fn read_root(
+        mut buf: impl io::Read,
+        root_size: usize,
+    ) -> CouchstoreResult<Option<NodePointer>> {
         if root_size == 0 {
-            return None;
+            return Ok(None);
         }
-        let position = buf.read_u48::<BigEndian>().unwrap();
-        let subtree_size = buf.read_u48::<BigEndian>().unwrap();
+        let redsize = root_size
+            .checked_sub(ROOT_BASE_SIZE)
+            .ok_or(CouchstoreError::Corrupt("root too small"))?;
 
-        let redsize = if root_size > 0 { root_size - 12 } else { 0 };
+        let position = buf.read_u48::<BigEndian>()?;
+        let subtree_size = buf.read_u48::<BigEndian>()?;
 
         let mut reduce_value = vec![0; redsize];
-        buf.read_exact(&mut reduce_value).unwrap();
+        buf.read_exact(&mut reduce_value)?;
 
-        Some(NodePointer {
+        Ok(Some(NodePointer {
             key: None,
             pointer: position,
             reduce_value,
             subtree_size,
-        })
+        }))
     }
 
-    fn read_pointer(key: &[u8], mut buf: impl io::Read) -> NodePointer {
-        let pointer = buf.read_u48::<BigEndian>().unwrap();
-        let subtree_size = buf.read_u48::<BigEndian>().unwrap();
-        let reduce_value_len = buf.read_u16::<BigEndian>().unwrap() as usize;
+    fn read_pointer(key: &[u8], mut buf: &[u8]) -> CouchstoreResult<NodePointer> {
+        let truncated = |_| CouchstoreError::Corrupt("truncated node pointer");
+        let pointer = buf.read_u48::<BigEndian>().map_err(truncated)?;
+        let subtree_size = buf.read_u48::<BigEndian>().map_err(truncated)?;
+        let reduce_value_len = buf.read_u16::<BigEndian>().map_err(truncated)? as usize;
         let mut reduce_value = vec![0; reduce_value_len];
-        buf.read_exact(&mut reduce_value).unwrap();
+        buf.read_exact(&mut reduce_value).map_err(truncated)?;
 
-        NodePointer {
+        Ok(NodePointer {
             key: Some(key.to_vec()),
             pointer,
             reduce_value,
             subtree_size,
-        }
+        })
     }
 
     fn encode_root(&self, mut buf: impl io::Write) -> io::Result<()> {
@@ -135,6 +290,17 @@ pub struct LocalDoc {
     deleted: bool,
 }
 
+impl LocalDoc {
+    pub fn new(id: impl Into<Vec<u8>>, json: Vec<u8>) -> Self {
+        Self {
+            id: id.into(),
+            json: Some(json),
+            deleted: false,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
 pub struct Doc {
     pub id: Vec<u8>,
     pub data: Vec<u8>,
@@ -170,21 +336,35 @@ pub struct DocInfo {
 
 const BP_DELETED_FLAG: u64 = 0x800000000000;
 
+fn truncated_index_value(_: io::Error) -> CouchstoreError {
+    CouchstoreError::Corrupt("truncated index value")
+}
+
+fn decode_content_meta(bits: u8) -> CouchstoreResult<ContentMetaFlag> {
+    ContentMetaFlag::from_bits(bits).ok_or(CouchstoreError::Corrupt("unknown content meta"))
+}
+
 impl DocInfo {
-    fn decode_id_index_value(key: Vec<u8>, mut value: &[u8]) -> DocInfo {
-        let db_seq = value.read_u48::<BigEndian>().unwrap();
-        let data_size = value.read_u32::<BigEndian>().unwrap();
-        let bp = value.read_u48::<BigEndian>().unwrap();
+    fn decode_id_index_value(key: Vec<u8>, mut value: &[u8]) -> CouchstoreResult<DocInfo> {
+        let db_seq = value
+            .read_u48::<BigEndian>()
+            .map_err(truncated_index_value)?;
+        let data_size = value
+            .read_u32::<BigEndian>()
+            .map_err(truncated_index_value)?;
+        let bp = value
+            .read_u48::<BigEndian>()
+            .map_err(truncated_index_value)?;
         let deleted = bp & BP_DELETED_FLAG != 0;
         let bp = bp & !BP_DELETED_FLAG;
-        let content_meta = ContentMetaFlag::from_bits(value.read_u8().unwrap()).unwrap();
-        let rev_seq: u64 = value.read_u48::<BigEndian>().unwrap();
+        let content_meta = decode_content_meta(value.read_u8().map_err(truncated_index_value)?)?;
+        let rev_seq: u64 = value
+            .read_u48::<BigEndian>()
+            .map_err(truncated_index_value)?;
 
-        let rev_meta_len = value.len();
-        let mut rev_meta = vec![0; rev_meta_len];
-        value.read_exact(&mut rev_meta).unwrap();
+        let rev_meta = value.to_vec();
 
-        DocInfo {
+        Ok(DocInfo {
             id: key,
             db_seq,
             rev_seq,
@@ -193,29 +373,31 @@ impl DocInfo {
             content_meta,
             bp,
             physical_size: data_size,
-        }
+        })
     }
 
-    fn decode_by_seq_index_value(mut key: &[u8], mut value: &[u8]) -> DocInfo {
+    fn decode_by_seq_index_value(mut key: &[u8], mut value: &[u8]) -> CouchstoreResult<DocInfo> {
         let mut raw = [0; 5];
-        value.read_exact(&mut raw).unwrap();
+        value.read_exact(&mut raw).map_err(truncated_index_value)?;
         let (id_size, data_size) = decode_kv_length(&raw);
 
-        let bp = value.read_u48::<BigEndian>().unwrap();
+        let bp = value
+            .read_u48::<BigEndian>()
+            .map_err(truncated_index_value)?;
         let deleted = bp & BP_DELETED_FLAG != 0;
         let bp = bp & !BP_DELETED_FLAG;
-        let content_meta = ContentMetaFlag::from_bits(value.read_u8().unwrap()).unwrap();
-        let rev_seq = value.read_u48::<BigEndian>().unwrap();
-        let db_seq = key.read_u48::<BigEndian>().unwrap();
+        let content_meta = decode_content_meta(value.read_u8().map_err(truncated_index_value)?)?;
+        let rev_seq = value
+            .read_u48::<BigEndian>()
+            .map_err(truncated_index_value)?;
+        let db_seq = key.read_u48::<BigEndian>().map_err(truncated_index_value)?;
 
         let mut id = vec![0; id_size as usize];
-        value.read_exact(&mut id).unwrap();
+        value.read_exact(&mut id).map_err(truncated_index_value)?;
 
-        let rev_meta_len = value.len();
-        let mut rev_meta = vec![0; rev_meta_len];
-        value.read_exact(&mut rev_meta).unwrap();
+        let rev_meta = value.to_vec();
 
-        DocInfo {
+        Ok(DocInfo {
             id,
             db_seq,
             rev_seq,
@@ -224,7 +406,7 @@ impl DocInfo {
             content_meta,
             bp,
             physical_size: data_size,
-        }
+        })
     }
 }
 
@@ -244,11 +426,16 @@ bitflags! {
         /// sequence number as given. The update_seq for the DB will be set to
         /// at least this sequence.
         const SEQUENCE_AS_IS = 2;
+
+        /// Keep the previous revisions of the saved documents in the by-seq
+        /// index, including earlier revisions within the batch, so
+        /// changes_since returns each document's history. The by-id index
+        /// only has the latest revision.
+        const KEEP_HISTORY = 4;
     }
 }
 
 use bitflags::bitflags;
-use std::convert::TryFrom;
 
 bitflags! {
     #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
@@ -288,6 +475,40 @@ pub struct TreeFile {
     pos: usize,
     file: File,
     _options: DBOpenOptions,
+    /// How the file's chunks are checksummed, which depends on its version
+    crc_mode: CrcMode,
+    /// Whether KV nodes are written with front-coded keys, which depends
+    /// on the file's version
+    front_code_keys: bool,
+    /// Every block starts with a prefix byte saying whether it holds data
+    /// or a header, and headers start on a block boundary
+    block_size: usize,
+    stats: FileStats,
+    /// The first write to fail. Later writes are skipped, and the next
+    /// commit reports it rather than writing a header.
+    write_error: Option<std::io::Error>,
+    /// Writes go through O_DIRECT, buffered here until they are aligned
+    direct: Option<direct_io::DirectWriter>,
+    /// Reads are served from the latest chunk read ahead
+    read_ahead: Option<Mutex<file_read::ReadAhead>>,
+    reads: AtomicU64,
+    bytes_read: AtomicU64,
+}
+
+/// IO performed on a file since it was opened
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct FileStats {
+    pub bytes_written: u64,
+    /// How much was written with O_DIRECT rather than through the page
+    /// cache, not counting padding
+    pub direct_bytes_written: u64,
+    pub syncs: u64,
+    /// Direct IO was asked for but the filesystem refused it, so the file
+    /// was written through the page cache
+    pub direct_io_fallback: bool,
+    /// Reads made of the file, which reading ahead makes fewer and larger
+    pub reads: u64,
+    pub bytes_read: u64,
 }
 
 impl TreeFile {
@@ -296,41 +517,139 @@ impl TreeFile {
             pos: 0,
             file,
             _options: options,
+            crc_mode: options.disk_version.crc_mode(),
+            front_code_keys: options.disk_version.front_codes_keys(),
+            block_size: options.block_size,
+            stats: FileStats::default(),
+            write_error: None,
+            direct: None,
+            read_ahead: None,
+            reads: AtomicU64::new(0),
+            bytes_read: AtomicU64::new(0),
         }
     }
 }
 
 const ROOT_BASE_SIZE: usize = 12;
 
+/// Decode the body of the header chunk found at pos
+pub(crate) fn decode_header(buf: &[u8], pos: usize) -> CouchstoreResult<Header> {
+    let mut cursor = Cursor::new(buf);
+
+    let header = RawFileHeaderV13::decode(&mut cursor)
+        .map_err(|_| CouchstoreError::Corrupt("truncated header"))?;
+
+    if header.purge_ptr > pos as u64 {
+        return Err(CouchstoreError::Corrupt("purge pointer past header"));
+    }
+    // A header starts a block, so a candidate found part way through one of
+    // a larger block size is data that happens to look like a header
+    if validate_block_size(header.block_size as usize).is_err()
+        || !pos.is_multiple_of(header.block_size as usize)
+    {
+        return Err(CouchstoreError::Corrupt("bad block size"));
+    }
+    // Any auxiliary tree roots follow the standard ones
+    if buf.len()
+        < RawFileHeaderV13::on_disk_size(header.version)
+            + (header.seqrootsize as usize)
+            + (header.idrootsize as usize)
+            + (header.localrootsize as usize)
+    {
+        return Err(CouchstoreError::Corrupt("header size mismatch"));
+    }
+
+    let by_seq_root = NodePointer::read_root(&mut cursor, header.seqrootsize as usize)?;
+    let by_id_root = NodePointer::read_root(&mut cursor, header.idrootsize as usize)?;
+    let local_docs_root = NodePointer::read_root(&mut cursor, header.localrootsize as usize)?;
+    let aux_roots = aux_trees::decode_aux_roots(&buf[cursor.position() as usize..])?;
+
+    // The roots were written before the header
+    let roots = [&by_seq_root, &by_id_root, &local_docs_root]
+        .into_iter()
+        .chain(aux_roots.values());
+    for root in roots {
+        if root.as_ref().is_some_and(|root| root.pointer >= pos as u64) {
+            return Err(CouchstoreError::Corrupt("root past header"));
+        }
+    }
+
+    Ok(Header {
+        disk_version: header.version,
+        update_seq: header.update_seq,
+        by_id_root,
+        by_seq_root,
+        local_docs_root,
+        purge_seq: header.purge_seq,
+        purge_ptr: header.purge_ptr,
+        position: pos as u64,
+        timestamp: header.timestamp,
+        block_size: header.block_size as usize,
+        aux_roots,
+    })
+}
+
 impl Db {
-    pub fn open(filename: impl AsRef<Path>, opts: DBOpenOptions) -> Db {
-        let file = std::fs::OpenOptions::new()
-            .read(true)
-            .write(!opts.read_only)
-            .create(!opts.read_only && opts.create)
-            .open(filename)
-            .unwrap();
+    pub fn open(filename: impl AsRef<Path>, opts: DBOpenOptions) -> CouchstoreResult<Db> {
+        opts.tuning.validate()?;
+        validate_block_size(opts.block_size)?;
+        if opts.block_size != COUCH_BLOCK_SIZE && !opts.disk_version.declares_block_size() {
+            return Err(CouchstoreError::InvalidBlockSize(format!(
+                "version {} files can't have {} byte blocks",
+                u8::from(opts.disk_version),
+                opts.block_size
+            )));
+        }
+        let file = file_ops::open(filename.as_ref(), opts.read_only, opts.create, opts.dsync)?;
 
         let mut tree_file = TreeFile::new(file, opts);
 
-        tree_file.pos = tree_file.file.seek(SeekFrom::End(0)).unwrap() as usize;
+        tree_file.pos = tree_file.file.seek(SeekFrom::End(0))? as usize;
+        if opts.direct_io && !opts.read_only {
+            match direct_io::DirectWriter::open(filename.as_ref(), tree_file.pos as u64) {
+                Ok(direct) => tree_file.direct = Some(direct),
+                Err(_) => tree_file.stats.direct_io_fallback = true,
+            }
+        }
 
         let mut db = Db {
             file: tree_file,
             header: Header::default(),
+            header_end: 0,
+            committed_bytes: 0,
             opts,
         };
 
         if db.file.pos == 0 {
+            if opts.read_only {
+                return Err(CouchstoreError::NoHeader);
+            }
             db.create_header();
         } else {
-            db.find_header(db.file.pos - 2);
+            db.find_header(db.file.pos.saturating_sub(2))?;
+        }
+        // Started once the header is found, which is read backwards
+        if opts.read_ahead > 0 && opts.read_only {
+            db.file.read_ahead = Some(Mutex::new(file_read::ReadAhead::new(opts.read_ahead)));
         }
 
-        db
+        Ok(db)
+    }
+
+    /// Read the latest header of an existing file and its local document
+    /// with the id, closing the file again, for scanning many files as at
+    /// startup without keeping each open. The file is opened read only as
+    /// by Db::open, so the header is found and validated the same way.
+    pub fn open_header_only(
+        filename: impl AsRef<Path>,
+        local_id: &str,
+    ) -> CouchstoreResult<(Header, Option<LocalDoc>)> {
+        let db = Db::open(filename, DBOpenOptions::default().read_only())?;
+        let local_doc = db.open_local_document(local_id)?;
+        Ok((db.header, local_doc))
     }
 
-    pub fn set(&mut self, key: Vec<u8>, value: Vec<u8>) {
+    pub fn set(&mut self, key: Vec<u8>, value: Vec<u8>) -> CouchstoreResult<()> {
         let doc = Doc {
             id: key.clone(),
             data: value.clone(),
@@ -349,13 +668,16 @@ impl Db {
             physical_size,
         };
 
-        self.couchstore_save_document(Some(doc), doc_info, SaveOptions::COMPRESS_DOC_BODIES);
+        self.couchstore_save_document(Some(doc), doc_info, SaveOptions::COMPRESS_DOC_BODIES)
     }
 
-    pub fn docinfo_by_id(&mut self, key: impl Into<Vec<u8>>) -> Option<DocInfo> {
+    pub fn docinfo_by_id(&self, key: impl Into<Vec<u8>>) -> CouchstoreResult<Option<DocInfo>> {
         let key = key.into();
 
-        let root_pointer = self.header.by_id_root.as_ref()?.pointer as usize;
+        let Some(root) = self.header.by_id_root.as_ref() else {
+            return Ok(None);
+        };
+        let root_pointer = root.pointer as usize;
 
         let mut req = CouchfileLookupRequest::new(vec![key.clone()]);
 
@@ -365,23 +687,24 @@ impl Db {
             &mut req,
             |_, _, value| {
                 if let Some(value) = value {
-                    docinfo = Some(DocInfo::decode_id_index_value(key.clone(), value));
+                    docinfo = Some(DocInfo::decode_id_index_value(key.clone(), value)?);
                 }
+                Ok(())
             },
             root_pointer,
-        );
+        )?;
 
-        docinfo
+        Ok(docinfo)
     }
 
     pub fn docinfos_by_id(
-        &mut self,
+        &self,
         mut keys: Vec<Vec<u8>>,
         mut on_fetch: impl FnMut(&[u8], Option<DocInfo>),
-    ) {
+    ) -> CouchstoreResult<()> {
         let root_pointer = match self.header.by_id_root {
             Some(ref root) => root.pointer as usize,
-            None => return,
+            None => return Ok(()),
         };
 
         keys.sort_unstable();
@@ -391,16 +714,45 @@ impl Db {
         self.btree_lookup(
             &mut req,
             |_, key, value| {
-                let docinfo =
-                    value.map(|value| DocInfo::decode_id_index_value(Vec::from(key), value));
+                let docinfo = value
+                    .map(|value| DocInfo::decode_id_index_value(Vec::from(key), value))
+                    .transpose()?;
                 on_fetch(key, docinfo);
+                Ok(())
             },
             root_pointer,
-        );
+        )
+    }
+
+    /// Call back with the info of each document from start_key up to, not
+    /// including, end_key (or the last, if None) in key order, until
+    /// on_fetch returns false. Only the by-id index is read, not the bodies.
+    pub fn docinfos_by_id_range(
+        &self,
+        start_key: &[u8],
+        end_key: Option<&[u8]>,
+        mut on_fetch: impl FnMut(DocInfo) -> bool,
+    ) -> CouchstoreResult<()> {
+        let Some(root) = self.header.by_id_root.as_ref() else {
+            return Ok(());
+        };
+        self.btree_fold_range(
+            root.pointer as usize,
+            start_key,
+            end_key,
+            &mut |key, value| {
+                let docinfo = DocInfo::decode_id_index_value(key.to_vec(), value)?;
+                Ok(on_fetch(docinfo))
+            },
+        )?;
+        Ok(())
     }
 
-    pub fn docinfo_by_sequence(&mut self, sequence: u64) -> Option<DocInfo> {
-        let root_pointer = self.header.by_seq_root.as_ref()?.pointer as usize;
+    pub fn docinfo_by_sequence(&self, sequence: u64) -> CouchstoreResult<Option<DocInfo>> {
+        let Some(root) = self.header.by_seq_root.as_ref() else {
+            return Ok(None);
+        };
+        let root_pointer = root.pointer as usize;
 
         let key = sequence.to_be_bytes()[2..].to_vec();
 
@@ -412,19 +764,24 @@ impl Db {
             &mut req,
             |_, key, value| {
                 if let Some(value) = value {
-                    docinfo = Some(DocInfo::decode_by_seq_index_value(key, value));
+                    docinfo = Some(DocInfo::decode_by_seq_index_value(key, value)?);
                 }
+                Ok(())
             },
             root_pointer,
-        );
+        )?;
 
-        docinfo
+        Ok(docinfo)
     }
 
-    pub fn changes_since(&mut self, sequence: u64, mut on_fetch: impl FnMut(&mut Self, DocInfo)) {
+    pub fn changes_since(
+        &self,
+        sequence: u64,
+        mut on_fetch: impl FnMut(&Self, DocInfo) -> CouchstoreResult<()>,
+    ) -> CouchstoreResult<()> {
         let root_pointer = match self.header.by_seq_root.as_ref() {
             Some(root) => root.pointer as usize,
-            None => return,
+            None => return Ok(()),
         };
 
         let key = sequence.to_be_bytes()[2..].to_vec();
@@ -435,17 +792,15 @@ impl Db {
 
         self.btree_lookup(
             &mut req,
-            |db, key, value| {
-                if let Some(value) = value {
-                    let docinfo = DocInfo::decode_by_seq_index_value(key, value);
-                    on_fetch(db, docinfo);
-                }
+            |db, key, value| match value {
+                Some(value) => on_fetch(db, DocInfo::decode_by_seq_index_value(key, value)?),
+                None => Ok(()),
             },
             root_pointer,
-        );
+        )
     }
 
-    pub fn save_local_document(&mut self, local_doc: LocalDoc) {
+    pub fn save_local_document(&mut self, local_doc: LocalDoc) -> CouchstoreResult<()> {
         let action_type = if local_doc.deleted {
             CouchfileModifyActionType::Remove
         } else {
@@ -461,19 +816,25 @@ impl Db {
         let req = CouchfileModifyRequest {
             actions: vec![action],
             context: (),
-            kv_chunk_threshold: self.opts.kv_chunk_threshold,
-            kp_chunk_threshold: self.opts.kp_chunk_threshold,
+            tuning: self.opts.tuning,
+            reducer: Reducer::None,
         };
 
         let root = self.header.local_docs_root.clone();
 
-        self.header.local_docs_root = self.file.modify_btree(req, root);
+        self.header.local_docs_root = self.file.modify_btree(req, root)?;
+        Ok(())
     }
 
-    pub fn open_local_document(&mut self, id: impl Into<Vec<u8>>) -> Option<LocalDoc> {
+    pub fn open_local_document(
+        &self,
+        id: impl Into<Vec<u8>>,
+    ) -> CouchstoreResult<Option<LocalDoc>> {
         let id = id.into();
 
-        let root = self.header.local_docs_root.clone()?;
+        let Some(root) = self.header.local_docs_root.clone() else {
+            return Ok(None);
+        };
 
         let mut req = CouchfileLookupRequest::new(vec![id]);
 
@@ -489,26 +850,33 @@ impl Db {
                         deleted: false,
                     });
                 }
+                Ok(())
             },
             root.pointer as usize,
-        );
+        )?;
 
-        local_doc
+        Ok(local_doc)
     }
 
+    /// Commit, panicking if any write since the last commit failed
     pub fn commit(&mut self) {
-        self.precommit();
+        self.try_commit()
+            .unwrap_or_else(|e| panic!("Failed to commit: {e}"));
+    }
 
-        let _pre_flush_pos = self.file.pos;
+    /// Commit, unless a write since the last commit failed. After a
+    /// failure the Db must be dropped and the file reopened, which finds
+    /// the last header committed.
+    pub fn try_commit(&mut self) -> CouchstoreResult<()> {
+        self.precommit();
 
         // Flush header to kernel buffer
         self.header.timestamp = utils::now();
         self.write_header();
 
         // Sync header to disk
-        self.file.file.flush().unwrap();
-
-        // TODO: Handle flush failures, retry and reset file.pos to pre_flush_pos
+        self.file.sync();
+        self.file.check_writes()
     }
 
     /// Precommit should occur before writing a header, it has two
@@ -525,7 +893,7 @@ impl Db {
     fn precommit(&mut self) {
         let curpos = self.file.pos;
 
-        self.file.pos = align_to_next_block(self.file.pos);
+        self.file.pos = align_to_next_block(self.file.pos, self.file.block_size);
 
         let (header_size, ..) = self.calculate_header_size();
 
@@ -535,7 +903,7 @@ impl Db {
         // TODO: Fix the mut 0s lol
         self.file.db_write_buf(&[0], &mut 0, &mut 0);
 
-        self.file.file.flush().unwrap();
+        self.file.sync();
 
         // Move cursor back to where it was
         self.file.pos = curpos;
@@ -545,12 +913,12 @@ impl Db {
     /// The DocInfo must have been filled in with valid values by an API call such
     /// as docinfo_by_id().
     pub fn open_doc_with_docinfo(
-        &mut self,
+        &self,
         docinfo: &DocInfo,
         mut options: OpenOptions,
-    ) -> Option<Doc> {
+    ) -> CouchstoreResult<Option<Doc>> {
         if docinfo.bp == 0 {
-            return None;
+            return Ok(None);
         }
 
         let bp = docinfo.bp as usize;
@@ -563,13 +931,13 @@ impl Db {
         }
 
         let docbody = if options.contains(OpenOptions::DECOMPRESS_DOC_BODIES) {
-            self.file.read_compressed(bp)
+            self.file.read_compressed(bp)?
         } else {
-            self.file.read_uncompressed(bp)
+            self.file.read_uncompressed(bp)?
         };
 
         if docbody.is_empty() {
-            return None;
+            return Ok(None);
         }
 
         let doc = Doc {
@@ -577,56 +945,47 @@ impl Db {
             data: docbody,
         };
 
-        Some(doc)
+        Ok(Some(doc))
     }
 
-    fn find_header(&mut self, start_pos: usize) {
-        let mut pos = start_pos;
-
-        pos -= pos % COUCH_BLOCK_SIZE;
-
-        self.find_header_at_pos(pos);
-
-        // TODO: loop until good header found or end of file
+    /// Use the last valid header at or before start_pos. A header torn by a
+    /// crash part way through a commit is skipped, falling back to the
+    /// previous commit. Larger blocks are multiples of COUCH_BLOCK_SIZE, so
+    /// stepping back by it finds their headers too.
+    fn find_header(&mut self, start_pos: usize) -> CouchstoreResult<()> {
+        let mut pos = start_pos - start_pos % COUCH_BLOCK_SIZE;
+
+        loop {
+            if self.find_header_at_pos(pos).is_ok() {
+                return Ok(());
+            }
+            if pos == 0 {
+                return Err(CouchstoreError::NoHeader);
+            }
+            pos -= COUCH_BLOCK_SIZE;
+        }
     }
 
-    fn find_header_at_pos(&mut self, pos: usize) {
-        self.file.file.seek(SeekFrom::Start(pos as u64)).unwrap();
-        let disk_block_type = DiskBlockType::try_from(self.file.file.read_u8().unwrap()).unwrap();
-
-        assert_eq!(disk_block_type, DiskBlockType::Header);
-
-        let header_buf = self.file.read_header(pos, MAX_DB_HEADER_SIZE);
-
-        let mut cursor = Cursor::new(&header_buf[..]);
+    fn find_header_at_pos(&mut self, pos: usize) -> CouchstoreResult<()> {
+        if !self.file.is_header_block(pos)? {
+            return Err(CouchstoreError::NoHeader);
+        }
 
-        let header = RawFileHeaderV13::decode(&mut cursor);
+        // A header fits in the first COUCH_BLOCK_SIZE bytes of its block,
+        // so it reads the same whatever the file's block size turns out to be
+        let (header_buf, header_end) = self.file.read_db_header(pos, MAX_DB_HEADER_SIZE)?;
 
-        assert!(header.purge_ptr <= pos as u64);
-        assert_eq!(
-            header_buf.len(),
-            RawFileHeaderV13::ON_DISK_SIZE
-                + (header.seqrootsize as usize)
-                + (header.idrootsize as usize)
-                + (header.localrootsize as usize)
-        );
+        self.header = decode_header(&header_buf, pos)?;
+        self.header_end = header_end as u64;
+        self.file.crc_mode = self.header.disk_version.crc_mode();
+        self.file.front_code_keys = self.header.disk_version.front_codes_keys();
+        self.file.block_size = self.header.block_size;
 
-        let by_seq_root = NodePointer::read_root(&mut cursor, header.seqrootsize as usize);
-        let by_id_root = NodePointer::read_root(&mut cursor, header.idrootsize as usize);
-        let local_docs_root = NodePointer::read_root(&mut cursor, header.localrootsize as usize);
-
-        self.header.update_seq = header.update_seq;
-        self.header.by_id_root = by_id_root;
-        self.header.by_seq_root = by_seq_root;
-        self.header.local_docs_root = local_docs_root;
-        self.header.purge_seq = header.purge_seq;
-        self.header.purge_ptr = header.purge_ptr;
-        self.header.position = pos as u64;
-        self.header.timestamp = header.timestamp;
+        Ok(())
     }
 
     fn create_header(&mut self) {
-        self.header.disk_version = DiskVersion::Thirteen;
+        self.header.disk_version = self.opts.disk_version;
         self.header.update_seq = 0;
         self.header.by_id_root = None;
         self.header.by_seq_root = None;
@@ -635,6 +994,8 @@ impl Db {
         self.header.purge_ptr = 0;
         self.header.position = 0;
         self.header.timestamp = 0;
+        self.header.block_size = self.opts.block_size;
+        self.header.aux_roots.clear();
 
         self.write_header();
     }
@@ -651,7 +1012,13 @@ impl Db {
         b.write_u16::<BigEndian>(seqrootsize as u16).unwrap();
         b.write_u16::<BigEndian>(idrootsize as u16).unwrap();
         b.write_u16::<BigEndian>(localrootsize as u16).unwrap();
-        b.write_u64::<BigEndian>(self.header.timestamp).unwrap();
+        if self.header.disk_version.has_timestamp() {
+            b.write_u64::<BigEndian>(self.header.timestamp).unwrap();
+        }
+        if self.header.disk_version.declares_block_size() {
+            b.write_u32::<BigEndian>(self.header.block_size as u32)
+                .unwrap();
+        }
         if let Some(by_seq_root) = &self.header.by_seq_root {
             by_seq_root.encode_root(&mut b).unwrap();
         }
@@ -661,9 +1028,11 @@ impl Db {
         if let Some(local_docs_root) = &self.header.local_docs_root {
             local_docs_root.encode_root(&mut b).unwrap();
         }
+        aux_trees::encode_aux_roots(&self.header.aux_roots, &mut b).unwrap();
 
         let header_pos = self.file.write_header(&b);
         self.header.position = header_pos as u64;
+        self.header_end = self.file.pos as u64;
     }
 
     fn calculate_header_size(&self) -> (usize, usize, usize, usize) {
@@ -682,7 +1051,11 @@ impl Db {
             localrootsize = ROOT_BASE_SIZE + local_docs_root.reduce_value.len();
         }
 
-        let total = RawFileHeaderV13::ON_DISK_SIZE + seqrootsize + idrootsize + localrootsize;
+        let total = RawFileHeaderV13::on_disk_size(self.header.disk_version)
+            + seqrootsize
+            + idrootsize
+            + localrootsize
+            + aux_trees::encoded_aux_roots_size(&self.header.aux_roots);
 
         (total, seqrootsize, idrootsize, localrootsize)
     }
@@ -690,6 +1063,50 @@ impl Db {
     pub fn header(&self) -> &Header {
         &self.header
     }
+
+    /// The counts of the documents as of the current header, read from the
+    /// root of the by-id tree. None if the tree has nodes written before
+    /// reductions were kept, until the file is compacted.
+    pub fn doc_counts(&self) -> Option<DocCounts> {
+        match &self.header.by_id_root {
+            None => Some(DocCounts::default()),
+            Some(root) => DocCounts::decode(&root.reduce_value),
+        }
+    }
+
+    /// Move back to the header before the current one, so reads see the
+    /// file as of that earlier commit. Fails with NoHeader at the file's
+    /// first header, leaving the current one in place. Only for databases
+    /// opened read only, as a commit would discard everything after it.
+    pub fn rewind_header(&mut self) -> CouchstoreResult<()> {
+        assert!(self.opts.read_only, "Can't rewind a writable database");
+        match self.header.position as usize {
+            0 => Err(CouchstoreError::NoHeader),
+            position => self.find_header(position - 1),
+        }
+    }
+
+    pub fn file_stats(&self) -> FileStats {
+        FileStats {
+            reads: self.file.reads.load(std::sync::atomic::Ordering::Relaxed),
+            bytes_read: self
+                .file
+                .bytes_read
+                .load(std::sync::atomic::Ordering::Relaxed),
+            ..self.file.stats
+        }
+    }
+
+    /// Where the next write goes, which after a commit is the end of the
+    /// header just written
+    pub fn file_pos(&self) -> u64 {
+        self.file.pos as u64
+    }
+
+    /// Where the latest complete header ends
+    pub fn header_end(&self) -> u64 {
+        self.header_end
+    }
 }
 
 #[derive(Debug, Copy, Clone)]
@@ -700,14 +1117,36 @@ pub struct DBOpenOptions {
     /// Open the database in read only mode
     read_only: bool,
 
-    kv_chunk_threshold: usize,
+    /// The shape of the B-trees written
+    tuning: BtreeTuning,
+
+    /// Saving a document with a longer key fails
+    max_key_size: usize,
 
-    kp_chunk_threshold: usize,
+    /// Saving a document with a larger uncompressed body fails
+    max_value_size: usize,
+
+    /// Open the file with O_DSYNC (write-through on Windows), so each write
+    /// is durable before it returns
+    dsync: bool,
+
+    /// The version a new file is created at
+    disk_version: DiskVersion,
+
+    /// The block size a new file is created with
+    block_size: usize,
+
+    /// Write with O_DIRECT, bypassing the page cache
+    direct_io: bool,
+
+    /// Bytes read ahead of each read of a read only file, 0 to not
+    read_ahead: usize,
 }
 
 fn seq_no_compare(mut a: &[u8], mut b: &[u8]) -> Ordering {
-    let a_seq = a.read_u48::<BigEndian>().unwrap();
-    let b_seq = b.read_u48::<BigEndian>().unwrap();
+    // A truncated key sorts first, decoding its item reports the corruption
+    let a_seq = a.read_u48::<BigEndian>().unwrap_or(0);
+    let b_seq = b.read_u48::<BigEndian>().unwrap_or(0);
 
     a_seq.cmp(&b_seq)
 }
@@ -717,8 +1156,14 @@ impl Default for DBOpenOptions {
         Self {
             create: true,
             read_only: false,
-            kv_chunk_threshold: 1279,
-            kp_chunk_threshold: 1279,
+            tuning: BtreeTuning::default(),
+            max_key_size: MAX_KEY_SIZE,
+            max_value_size: DEFAULT_MAX_VALUE_SIZE,
+            dsync: false,
+            disk_version: DiskVersion::default(),
+            block_size: COUCH_BLOCK_SIZE,
+            direct_io: false,
+            read_ahead: 0,
         }
     }
 }
@@ -728,6 +1173,78 @@ impl DBOpenOptions {
         self.read_only = true;
         self
     }
+
+    /// Limit the length of saved documents' keys, at most the 4095 bytes
+    /// the file format can hold
+    pub fn max_key_size(mut self, max_key_size: usize) -> Self {
+        self.max_key_size = max_key_size.min(MAX_KEY_SIZE);
+        self
+    }
+
+    /// Limit the uncompressed size of saved documents' bodies, at most the
+    /// largest body that can be read back
+    pub fn max_value_size(mut self, max_value_size: usize) -> Self {
+        self.max_value_size = max_value_size.min(MAX_DECOMPRESSED_SIZE);
+        self
+    }
+
+    /// Make every write durable as it is made, which doesn't need a sync
+    /// per commit but slows down each write.
+    pub fn dsync(mut self) -> Self {
+        self.dsync = true;
+        self
+    }
+
+    /// Shape the B-trees written with the tuning, which Db::open checks is
+    /// within the format's limits. Trees already written keep their shape
+    /// until rewritten.
+    pub fn btree_tuning(mut self, tuning: BtreeTuning) -> Self {
+        self.tuning = tuning;
+        self
+    }
+
+    /// Create new files at an older version, which older engines can read.
+    /// Existing files keep their version.
+    pub fn disk_version(mut self, disk_version: DiskVersion) -> Self {
+        self.disk_version = disk_version;
+        self
+    }
+
+    /// Create new files with blocks of this size, a power of two from 4 KiB
+    /// to 1 MiB, to evaluate larger blocks. Only version 15 files record
+    /// their block size, so Db::open rejects anything but 4 KiB at earlier
+    /// versions. Existing files keep their block size.
+    pub fn block_size(mut self, block_size: usize) -> Self {
+        self.block_size = block_size;
+        self
+    }
+
+    /// Write the file with O_DIRECT, so writing a large one, as compaction
+    /// does, doesn't evict what else is in the page cache. Where the
+    /// filesystem refuses it the file is written through the cache, which
+    /// FileStats::direct_io_fallback reports.
+    pub fn direct_io(mut self) -> Self {
+        self.direct_io = true;
+        self
+    }
+
+    /// Read a read only file in chunks of at least this many bytes, keeping
+    /// the latest to serve the reads after it. A file is appended to, so a
+    /// scan of its by-seqno index reads it mostly in order and makes far
+    /// fewer, larger reads, which suits spinning disks.
+    pub fn read_ahead(mut self, bytes: usize) -> Self {
+        self.read_ahead = bytes;
+        self
+    }
+}
+
+fn validate_block_size(block_size: usize) -> CouchstoreResult<()> {
+    if !block_size.is_power_of_two() || !(COUCH_BLOCK_SIZE..=MAX_BLOCK_SIZE).contains(&block_size) {
+        return Err(CouchstoreError::InvalidBlockSize(format!(
+            "{block_size} isn't a power of two between {COUCH_BLOCK_SIZE} and {MAX_BLOCK_SIZE}"
+        )));
+    }
+    Ok(())
 }
 
 #[cfg(test)]
@@ -740,46 +1257,424 @@ mod test {
             read_only: true,
             ..Default::default()
         };
-        let mut db = Db::open("../test-data/travel-sample/0.couch.1", opts);
+        let db = Db::open("../test-data/travel-sample/0.couch.1", opts).unwrap();
 
-        let info_by_id: DocInfo = db.docinfo_by_id("\0route_24983").unwrap();
-        let info_by_seq = db.docinfo_by_sequence(info_by_id.db_seq).unwrap();
+        let info_by_id: DocInfo = db.docinfo_by_id("\0route_24983").unwrap().unwrap();
+        let info_by_seq = db.docinfo_by_sequence(info_by_id.db_seq).unwrap().unwrap();
 
         assert_eq!(info_by_id, info_by_seq);
     }
 
+    #[test]
+    fn test_open_header_only() {
+        let path = "../test-data/travel-sample/0.couch.1";
+        let db = Db::open(path, DBOpenOptions::default().read_only()).unwrap();
+        let (header, local_doc) = Db::open_header_only(path, "_local/vbstate").unwrap();
+        assert_eq!(header.update_seq, db.header().update_seq);
+        assert_eq!(header.position(), db.header().position());
+        assert_eq!(
+            local_doc.unwrap().json,
+            db.open_local_document("_local/vbstate")
+                .unwrap()
+                .unwrap()
+                .json
+        );
+        let (_, missing) = Db::open_header_only(path, "_local/none").unwrap();
+        assert!(missing.is_none());
+    }
+
     #[test]
     fn test_get_multiple_keys() {
         let opts = DBOpenOptions {
             read_only: true,
             ..Default::default()
         };
-        let mut db = Db::open("../test-data/travel-sample/0.couch.1", opts);
+        let db = Db::open("../test-data/travel-sample/0.couch.1", opts).unwrap();
 
         let keys: Vec<Vec<u8>> = vec![Vec::from("\0route_24983"), Vec::from("\0landmark_37519")];
 
         let mut doc_infos = vec![];
         db.docinfos_by_id(keys.clone(), |_, doc_info| {
             doc_infos.push(doc_info.unwrap());
-        });
+        })
+        .unwrap();
 
         // we get keys back in sorted order
         assert_eq!(doc_infos[0].id, keys[1]);
         assert_eq!(doc_infos[1].id, keys[0]);
     }
 
+    #[test]
+    fn test_save_documents() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("0.couch.1");
+        let mut db = Db::open(&path, DBOpenOptions::default()).unwrap();
+
+        let docinfo = |id: &str, db_seq| DocInfo {
+            id: Vec::from(id),
+            db_seq,
+            rev_seq: 1,
+            rev_meta: vec![],
+            deleted: false,
+            content_meta: ContentMetaFlag::IS_COMPRESSED,
+            bp: 0,
+            physical_size: 0,
+        };
+        let doc = |id: &str| {
+            Some(Doc {
+                id: Vec::from(id),
+                data: Vec::from("value"),
+            })
+        };
+        db.save_documents(
+            vec![doc("a"), doc("b"), doc("c")],
+            vec![docinfo("a", 1), docinfo("b", 2), docinfo("c", 3)],
+            SaveOptions::SEQUENCE_AS_IS | SaveOptions::COMPRESS_DOC_BODIES,
+        )
+        .unwrap();
+        // Overwrite one doc and delete another, their old seqnos must go
+        db.save_documents(
+            vec![doc("a"), None],
+            vec![docinfo("a", 4), docinfo("b", 5)],
+            SaveOptions::SEQUENCE_AS_IS | SaveOptions::COMPRESS_DOC_BODIES,
+        )
+        .unwrap();
+        db.commit();
+        // The data and then the header are synced
+        assert_eq!(db.file_stats().syncs, 2);
+        assert!(db.file_stats().bytes_written > 0);
+
+        let db = Db::open(&path, DBOpenOptions::default().read_only()).unwrap();
+        assert_eq!(db.header().update_seq, 5);
+        let mut changes = vec![];
+        db.changes_since(0, |_, docinfo| {
+            changes.push((docinfo.id, docinfo.db_seq, docinfo.deleted));
+            Ok(())
+        })
+        .unwrap();
+        assert_eq!(
+            changes,
+            vec![
+                (Vec::from("c"), 3, false),
+                (Vec::from("a"), 4, false),
+                (Vec::from("b"), 5, true),
+            ]
+        );
+
+        let docinfo = db.docinfo_by_id("a").unwrap().unwrap();
+        let doc = db
+            .open_doc_with_docinfo(&docinfo, OpenOptions::DECOMPRESS_DOC_BODIES)
+            .unwrap()
+            .unwrap();
+        assert_eq!(doc.data, b"value");
+    }
+
+    #[test]
+    fn test_purge_documents() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("0.couch.1");
+        let mut db = Db::open(&path, DBOpenOptions::default()).unwrap();
+        let docinfo = |id: &str| DocInfo {
+            id: Vec::from(id),
+            db_seq: 0,
+            rev_seq: 1,
+            rev_meta: vec![],
+            deleted: false,
+            content_meta: ContentMetaFlag::empty(),
+            bp: 0,
+            physical_size: 0,
+        };
+        let doc = Doc {
+            id: Vec::from("a"),
+            data: Vec::from("value"),
+        };
+        db.save_documents(
+            vec![Some(doc), None, None],
+            vec![docinfo("a"), docinfo("b"), docinfo("c")],
+            SaveOptions::empty(),
+        )
+        .unwrap();
+        db.commit();
+
+        let b = db.docinfo_by_id("b").unwrap().unwrap();
+        db.purge_documents(&[b]).unwrap();
+        db.commit();
+
+        let db = Db::open(&path, DBOpenOptions::default().read_only()).unwrap();
+        assert_eq!(db.header().purge_seq, 2);
+        assert_eq!(db.header().update_seq, 3);
+        assert!(db.docinfo_by_id("b").unwrap().is_none());
+        let mut changes = vec![];
+        db.changes_since(0, |_, docinfo| {
+            changes.push(docinfo.db_seq);
+            Ok(())
+        })
+        .unwrap();
+        assert_eq!(changes, vec![1, 3]);
+    }
+
+    #[test]
+    fn test_rewind_header() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("0.couch.1");
+        let mut db = Db::open(&path, DBOpenOptions::default()).unwrap();
+        for key in ["a", "b"] {
+            db.set(Vec::from(key), Vec::from("value")).unwrap();
+            db.commit();
+        }
+
+        let mut db = Db::open(&path, DBOpenOptions::default().read_only()).unwrap();
+        assert_eq!(db.header().update_seq, 2);
+        let latest = db.header().timestamp();
+        db.rewind_header().unwrap();
+        assert_eq!(db.header().update_seq, 1);
+        assert!(db.header().timestamp() <= latest);
+        assert!(db.docinfo_by_id("a").unwrap().is_some());
+        assert!(db.docinfo_by_id("b").unwrap().is_none());
+
+        // The header written when the file was created
+        db.rewind_header().unwrap();
+        assert_eq!(db.header().update_seq, 0);
+        assert_eq!(db.header().timestamp(), 0);
+        assert!(matches!(db.rewind_header(), Err(CouchstoreError::NoHeader)));
+        assert_eq!(db.header().position(), 0);
+    }
+
+    #[test]
+    fn test_save_too_big() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("0.couch.1");
+        let opts = DBOpenOptions::default().max_key_size(4).max_value_size(8);
+        let mut db = Db::open(&path, opts).unwrap();
+        let save = |db: &mut Db, id: &str, data: &[u8]| {
+            let info = DocInfo {
+                id: Vec::from(id),
+                db_seq: 0,
+                rev_seq: 1,
+                rev_meta: vec![],
+                deleted: false,
+                content_meta: ContentMetaFlag::empty(),
+                bp: 0,
+                physical_size: 0,
+            };
+            let doc = Doc {
+                id: Vec::from(id),
+                data: data.to_vec(),
+            };
+            db.save_documents(vec![Some(doc)], vec![info], SaveOptions::empty())
+        };
+        assert!(matches!(
+            save(&mut db, "key_0", b"value"),
+            Err(CouchstoreError::TooBig("key"))
+        ));
+        assert!(matches!(
+            save(&mut db, "key", b"long value"),
+            Err(CouchstoreError::TooBig("value"))
+        ));
+        save(&mut db, "key", b"value").unwrap();
+        assert_eq!(db.header().update_seq, 1);
+    }
+
+    #[test]
+    fn test_torn_header() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("0.couch.1");
+        let mut db = Db::open(&path, DBOpenOptions::default()).unwrap();
+        db.set(Vec::from("a"), Vec::from("1")).unwrap();
+        db.commit();
+        let committed_len = std::fs::metadata(&path).unwrap().len();
+        db.set(Vec::from("b"), Vec::from("2")).unwrap();
+        db.commit();
+
+        // A crash part way through writing the second commit's header
+        let file = std::fs::OpenOptions::new().write(true).open(&path).unwrap();
+        let len = file.metadata().unwrap().len();
+        file.set_len(len - 8).unwrap();
+
+        let db = Db::open(&path, DBOpenOptions::default().read_only()).unwrap();
+        assert_eq!(db.header().update_seq, 1);
+        assert!(db.docinfo_by_id("a").unwrap().is_some());
+        assert!(db.docinfo_by_id("b").unwrap().is_none());
+
+        // Nothing but garbage
+        std::fs::write(&path, vec![0xff; committed_len as usize]).unwrap();
+        assert!(matches!(
+            Db::open(&path, DBOpenOptions::default().read_only()),
+            Err(CouchstoreError::NoHeader)
+        ));
+    }
+
+    #[test]
+    fn test_block_size() {
+        let dir = tempfile::tempdir().unwrap();
+        assert!(matches!(
+            Db::open(
+                dir.path().join("bad"),
+                DBOpenOptions::default().block_size(16 * 1024)
+            ),
+            Err(CouchstoreError::InvalidBlockSize(_))
+        ));
+        let v15 = DBOpenOptions::default().disk_version(DiskVersion::Fifteen);
+        assert!(matches!(
+            Db::open(dir.path().join("bad"), v15.block_size(12 * 1024)),
+            Err(CouchstoreError::InvalidBlockSize(_))
+        ));
+
+        for block_size in [4096, 16 * 1024, 64 * 1024] {
+            let path = dir.path().join(format!("{block_size}.couch.1"));
+            let mut db = Db::open(&path, v15.block_size(block_size)).unwrap();
+            // Incompressible values spanning several blocks, so reads skip
+            // their prefixes
+            let mut state = 1u32;
+            let value: Vec<u8> = (0..3 * block_size)
+                .map(|_| {
+                    state = state.wrapping_mul(1_103_515_245).wrapping_add(12345);
+                    (state >> 24) as u8
+                })
+                .collect();
+            for i in 0..20 {
+                db.set(format!("key{i}").into_bytes(), value.clone())
+                    .unwrap();
+            }
+            db.commit();
+            assert_eq!(db.header().position() % block_size as u64, 0);
+            db.set(b"last".to_vec(), b"value".to_vec()).unwrap();
+            db.commit();
+            drop(db);
+
+            // The header's block size is used whatever the options say
+            let db = Db::open(&path, DBOpenOptions::default().read_only()).unwrap();
+            assert_eq!(db.header().disk_version(), DiskVersion::Fifteen);
+            assert_eq!(db.header().block_size(), block_size);
+            assert_eq!(db.header().update_seq, 21);
+            let info = db.docinfo_by_id("key7").unwrap().unwrap();
+            let doc = db
+                .open_doc_with_docinfo(&info, OpenOptions::DECOMPRESS_DOC_BODIES)
+                .unwrap()
+                .unwrap();
+            assert_eq!(doc.data, value);
+            drop(db);
+
+            // A torn header falls back to the previous one, a block earlier
+            let file = std::fs::OpenOptions::new().write(true).open(&path).unwrap();
+            file.set_len(file.metadata().unwrap().len() - 8).unwrap();
+            let mut db = Db::open(&path, DBOpenOptions::default()).unwrap();
+            assert_eq!(db.header().update_seq, 20);
+            db.set(b"after".to_vec(), b"value".to_vec()).unwrap();
+            db.commit();
+            assert_eq!(db.header().position() % block_size as u64, 0);
+            drop(db);
+            let db = Db::open(&path, DBOpenOptions::default().read_only()).unwrap();
+            assert_eq!(db.header().update_seq, 21);
+            assert!(db.docinfo_by_id("after").unwrap().is_some());
+        }
+    }
+
     #[test]
     fn test_changes_since() {
         let opts = DBOpenOptions {
             read_only: true,
             ..Default::default()
         };
-        let mut db = Db::open("../test-data/travel-sample/0.couch.1", opts);
+        let db = Db::open("../test-data/travel-sample/0.couch.1", opts).unwrap();
         let mut seq = 1;
         db.changes_since(0, |_, doc_info| {
             assert_eq!(doc_info.db_seq, seq);
             seq += 1;
-        });
+            Ok(())
+        })
+        .unwrap();
         assert_eq!(seq, 98);
     }
+
+    #[test]
+    fn test_docinfos_by_id_range() {
+        let opts = DBOpenOptions {
+            read_only: true,
+            ..Default::default()
+        };
+        let db = Db::open("../test-data/travel-sample/0.couch.1", opts).unwrap();
+        let mut routes = Vec::new();
+        db.changes_since(0, |_, doc_info| {
+            if doc_info.id.starts_with(b"\0route_") {
+                routes.push(doc_info.id);
+            }
+            Ok(())
+        })
+        .unwrap();
+        routes.sort();
+        assert!(routes.len() > 3);
+
+        let mut ids = Vec::new();
+        db.docinfos_by_id_range(b"\0route_", Some(b"\0route`"), |doc_info| {
+            ids.push(doc_info.id);
+            true
+        })
+        .unwrap();
+        assert_eq!(ids, routes);
+
+        // Stopping early, from inside the range
+        let mut ids = Vec::new();
+        db.docinfos_by_id_range(&routes[1], None, |doc_info| {
+            ids.push(doc_info.id);
+            ids.len() < 2
+        })
+        .unwrap();
+        assert_eq!(ids, routes[1..3]);
+    }
+
+    #[test]
+    fn test_concurrent_reads() {
+        let db = Db::open(
+            "../test-data/travel-sample/0.couch.1",
+            DBOpenOptions::default().read_only(),
+        )
+        .unwrap();
+        let mut infos = Vec::new();
+        db.changes_since(0, |_, info| {
+            infos.push(info);
+            Ok(())
+        })
+        .unwrap();
+
+        std::thread::scope(|scope| {
+            for thread in 0..4 {
+                let (db, infos) = (&db, &infos);
+                scope.spawn(move || {
+                    for info in infos.iter().skip(thread) {
+                        let by_id = db.docinfo_by_id(info.id.clone()).unwrap().unwrap();
+                        assert_eq!(&by_id, info);
+                        let doc = db
+                            .open_doc_with_docinfo(info, OpenOptions::DECOMPRESS_DOC_BODIES)
+                            .unwrap();
+                        assert!(doc.is_some() || info.deleted);
+                    }
+                });
+            }
+        });
+    }
+
+    #[test]
+    fn test_read_ahead() {
+        let path = "../test-data/travel-sample/0.couch.1";
+        let scan = |opts: DBOpenOptions| {
+            let db = Db::open(path, opts.read_only()).unwrap();
+            let before = db.file_stats();
+            let mut docs = Vec::new();
+            db.changes_since(0, |db, info| {
+                let doc = db.open_doc_with_docinfo(&info, OpenOptions::DECOMPRESS_DOC_BODIES)?;
+                docs.push((info.id, doc.map(|doc| doc.data)));
+                Ok(())
+            })
+            .unwrap();
+            let after = db.file_stats();
+            (docs, after.reads - before.reads)
+        };
+        let (docs, reads) = scan(DBOpenOptions::default());
+        let (read_ahead_docs, read_ahead_reads) =
+            scan(DBOpenOptions::default().read_ahead(1 << 20));
+        assert!(!docs.is_empty());
+        assert_eq!(docs, read_ahead_docs);
+        assert!(read_ahead_reads * 10 < reads, "{read_ahead_reads} {reads}");
+    }
 }
@@ -1,6 +1,10 @@
 use std::io::{self, Cursor, Read};
 
-use crate::{DiskVersion, DocInfo};
+use crate::{
+    btree_read::NodeType,
+    constants::{COUCH_BLOCK_SIZE, MAX_KEY_SIZE},
+    CouchstoreError, CouchstoreResult, DiskVersion, DocInfo,
+};
 use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
 
 #[derive(Default, Debug, PartialEq, Eq, Clone, Copy)]
@@ -13,21 +17,44 @@ pub struct RawFileHeaderV13 {
     pub seqrootsize: u16,
     pub idrootsize: u16,
     pub localrootsize: u16,
+    pub block_size: u32,
 }
 
 impl RawFileHeaderV13 {
     pub const ON_DISK_SIZE: usize = 33;
 
-    pub fn decode(mut buf: impl io::Read) -> RawFileHeaderV13 {
-        let version = DiskVersion::try_from(buf.read_u8().unwrap()).unwrap();
-        let update_seq = buf.read_u48::<BigEndian>().unwrap();
-        let purge_seq = buf.read_u48::<BigEndian>().unwrap();
-        let purge_ptr = buf.read_u48::<BigEndian>().unwrap();
-        let seqrootsize = buf.read_u16::<BigEndian>().unwrap();
-        let idrootsize = buf.read_u16::<BigEndian>().unwrap();
-        let localrootsize = buf.read_u16::<BigEndian>().unwrap();
-        let timestamp = buf.read_u64::<BigEndian>().unwrap();
-        RawFileHeaderV13 {
+    /// Size of the fixed part of a header of the version
+    pub fn on_disk_size(version: DiskVersion) -> usize {
+        let mut size = Self::ON_DISK_SIZE;
+        if !version.has_timestamp() {
+            size -= 8;
+        }
+        if version.declares_block_size() {
+            size += 4;
+        }
+        size
+    }
+
+    pub fn decode(mut buf: impl io::Read) -> CouchstoreResult<RawFileHeaderV13> {
+        let version = DiskVersion::try_from(buf.read_u8()?)
+            .map_err(|_| CouchstoreError::Corrupt("unknown disk version"))?;
+        let update_seq = buf.read_u48::<BigEndian>()?;
+        let purge_seq = buf.read_u48::<BigEndian>()?;
+        let purge_ptr = buf.read_u48::<BigEndian>()?;
+        let seqrootsize = buf.read_u16::<BigEndian>()?;
+        let idrootsize = buf.read_u16::<BigEndian>()?;
+        let localrootsize = buf.read_u16::<BigEndian>()?;
+        let timestamp = if version.has_timestamp() {
+            buf.read_u64::<BigEndian>()?
+        } else {
+            0
+        };
+        let block_size = if version.declares_block_size() {
+            buf.read_u32::<BigEndian>()?
+        } else {
+            COUCH_BLOCK_SIZE as u32
+        };
+        Ok(RawFileHeaderV13 {
             version,
             update_seq,
             purge_seq,
@@ -36,7 +63,8 @@ impl RawFileHeaderV13 {
             seqrootsize,
             idrootsize,
             localrootsize,
-        }
+            block_size,
+        })
     }
 
     pub fn _encode(&self, mut buf: impl io::Write) {
@@ -47,7 +75,12 @@ impl RawFileHeaderV13 {
         buf.write_u16::<BigEndian>(self.seqrootsize).unwrap();
         buf.write_u16::<BigEndian>(self.idrootsize).unwrap();
         buf.write_u16::<BigEndian>(self.localrootsize).unwrap();
-        buf.write_u64::<BigEndian>(self.timestamp).unwrap();
+        if self.version.has_timestamp() {
+            buf.write_u64::<BigEndian>(self.timestamp).unwrap();
+        }
+        if self.version.declares_block_size() {
+            buf.write_u32::<BigEndian>(self.block_size).unwrap();
+        }
     }
 }
 
@@ -81,23 +114,117 @@ pub fn encode_kv_length(key_length: u32, value_length: u32) -> RawKvLength {
     kv
 }
 
-pub fn read_kv<'a>(buf: &mut Cursor<&'a [u8]>) -> Option<(&'a [u8], &'a [u8])> {
+pub fn read_kv<'a>(buf: &mut Cursor<&'a [u8]>) -> CouchstoreResult<(&'a [u8], &'a [u8])> {
     let mut kv = [0; 5];
-    buf.read_exact(&mut kv).unwrap();
+    buf.read_exact(&mut kv)
+        .map_err(|_| CouchstoreError::Corrupt("truncated node item"))?;
     let (klen, vlen) = decode_kv_length(&kv);
 
-    let key = buf
-        .get_ref()
-        .get(buf.position() as usize..(buf.position() + klen as u64) as usize)
-        .unwrap();
-    buf.set_position(buf.position() + klen as u64);
-    let value = buf
-        .get_ref()
-        .get(buf.position() as usize..(buf.position() + vlen as u64) as usize)
-        .unwrap();
-    buf.set_position(buf.position() + vlen as u64);
-
-    Some((key, value))
+    let data: &'a [u8] = buf.get_ref();
+    let key_start = buf.position() as usize;
+    let value_start = key_start + klen as usize;
+    let value_end = value_start + vlen as usize;
+    if value_end > data.len() {
+        return Err(CouchstoreError::Corrupt("truncated node item"));
+    }
+    buf.set_position(value_end as u64);
+
+    Ok((&data[key_start..value_start], &data[value_start..value_end]))
+}
+
+/// Type byte of a KV node with front-coded keys: each item starts with the
+/// length (u16) of the prefix its key shares with the key before, and only
+/// the rest of the key follows. Written to files from disk version 14.
+pub(crate) const FRONT_CODED_KV_NODE: u8 = 2;
+
+/// Length of the prefix two keys share
+fn shared_prefix(a: &[u8], b: &[u8]) -> usize {
+    a.iter().zip(b).take_while(|(a, b)| a == b).count()
+}
+
+/// Whether front-coding the keys, in order, makes a smaller node. Each
+/// item costs two bytes more, so the keys must share more than that.
+pub(crate) fn front_coding_saves<'a>(keys: impl Iterator<Item = &'a [u8]>) -> bool {
+    let mut previous: &[u8] = &[];
+    let mut saved = 0isize;
+    for key in keys {
+        saved += shared_prefix(previous, key) as isize - 2;
+        previous = key;
+    }
+    saved > 0
+}
+
+/// Write an item of a front-coded KV node
+pub(crate) fn write_front_coded_kv<W: io::Write>(
+    mut buf: W,
+    previous_key: &[u8],
+    key: &[u8],
+    value: &[u8],
+) {
+    let shared = shared_prefix(previous_key, key);
+    buf.write_u16::<BigEndian>(shared as u16).unwrap();
+    write_kv(buf, &key[shared..], value);
+}
+
+/// Rewrite a front-coded KV node in the plain encoding RawNode decodes.
+/// Other nodes are already plain.
+pub(crate) fn expand_node(buf: Vec<u8>) -> CouchstoreResult<Vec<u8>> {
+    if buf.first() != Some(&FRONT_CODED_KV_NODE) {
+        return Ok(buf);
+    }
+    let mut plain = Vec::with_capacity(buf.len() * 2);
+    plain.push(NodeType::KVNode.into());
+    let mut cursor = Cursor::new(&buf[..]);
+    cursor.set_position(1);
+    let mut key = Vec::new();
+    while (cursor.position() as usize) < buf.len() {
+        let shared = cursor
+            .read_u16::<BigEndian>()
+            .map_err(|_| CouchstoreError::Corrupt("truncated node item"))?
+            as usize;
+        let (suffix, value) = read_kv(&mut cursor)?;
+        if shared > key.len() {
+            return Err(CouchstoreError::Corrupt(
+                "front-coded key shares more than the previous key",
+            ));
+        }
+        key.truncate(shared);
+        key.extend_from_slice(suffix);
+        if key.len() > MAX_KEY_SIZE {
+            return Err(CouchstoreError::Corrupt("front-coded key too long"));
+        }
+        write_kv(&mut plain, &key, value);
+    }
+    Ok(plain)
+}
+
+/// A B-tree node, borrowing its keys and values from the node's chunk
+#[derive(Debug)]
+pub struct RawNode<'a> {
+    pub node_type: NodeType,
+    pub items: Vec<(&'a [u8], &'a [u8])>,
+}
+
+impl<'a> RawNode<'a> {
+    pub fn decode(buf: &'a [u8]) -> CouchstoreResult<RawNode<'a>> {
+        let (&node_type, _) = buf
+            .split_first()
+            .ok_or(CouchstoreError::Corrupt("empty node"))?;
+        let node_type = NodeType::try_from(node_type)
+            .map_err(|_| CouchstoreError::Corrupt("unknown node type"))?;
+
+        let mut cursor = Cursor::new(buf);
+        cursor.set_position(1);
+        let mut items = Vec::new();
+        while (cursor.position() as usize) < buf.len() {
+            items.push(read_kv(&mut cursor)?);
+        }
+        if node_type == NodeType::KPNode && items.is_empty() {
+            return Err(CouchstoreError::Corrupt("empty KP node"));
+        }
+
+        Ok(RawNode { node_type, items })
+    }
 }
 
 pub fn write_kv<W: io::Write>(mut buf: W, key: &[u8], value: &[u8]) {
@@ -111,31 +238,38 @@ impl DocInfo {
     pub fn encode_id_index_value<W: io::Write>(&self, mut buf: W) {
         buf.write_u48::<BigEndian>(self.db_seq).unwrap();
         buf.write_u32::<BigEndian>(self.physical_size).unwrap();
-        buf.write_u48::<BigEndian>(
-            self.bp | {
-                // set the first bit of the first byte to 1 if deleted
-                if self.deleted {
-                    1 << 47
-                } else {
-                    0
-                }
-            },
-        )
-        .unwrap();
+        buf.write_u48::<BigEndian>(self.encode_bp()).unwrap();
         buf.write_u8(self.content_meta.bits()).unwrap();
         buf.write_u48::<BigEndian>(self.rev_seq).unwrap();
         buf.write_all(&self.rev_meta).unwrap();
     }
 
+    /// The body pointer with its top bit set if the doc is deleted
+    fn encode_bp(&self) -> u64 {
+        if self.deleted {
+            self.bp | crate::BP_DELETED_FLAG
+        } else {
+            self.bp
+        }
+    }
+
     pub fn encode_seq_index_value<W: io::Write>(&self, mut buf: W) {
         let sizes = encode_kv_length(self.id.len() as u32, self.physical_size);
         buf.write_all(&sizes).unwrap();
-        buf.write_u48::<BigEndian>(self.bp).unwrap();
+        buf.write_u48::<BigEndian>(self.encode_bp()).unwrap();
         buf.write_u8(self.content_meta.bits()).unwrap();
         buf.write_u48::<BigEndian>(self.rev_seq).unwrap();
         buf.write_all(&self.id).unwrap();
         buf.write_all(&self.rev_meta).unwrap();
     }
+
+    /// The space the document takes in the by-id tree and for its body
+    pub fn disk_size(&self) -> u64 {
+        // The id index value's fixed fields: db_seq, size, bp, content meta
+        // and rev_seq
+        const FIXED_SIZE: usize = 6 + 4 + 6 + 1 + 6;
+        (self.id.len() + FIXED_SIZE + self.rev_meta.len()) as u64 + u64::from(self.physical_size)
+    }
 }
 
 #[cfg(test)]
@@ -149,4 +283,51 @@ mod test {
         assert_eq!(klen, 1234);
         assert_eq!(vlen, 5678);
     }
+
+    #[test]
+    fn test_decode_malformed_node() {
+        let mut node = vec![NodeType::KVNode.into()];
+        write_kv(&mut node, b"key", b"value");
+        let decoded = RawNode::decode(&node).unwrap();
+        assert_eq!(decoded.items, vec![(&b"key"[..], &b"value"[..])]);
+
+        for len in 2..node.len() {
+            assert!(RawNode::decode(&node[..len]).is_err());
+        }
+        assert!(RawNode::decode(&[]).is_err());
+        assert!(RawNode::decode(&[3]).is_err());
+        assert!(RawNode::decode(&[NodeType::KPNode.into()]).is_err());
+    }
+
+    #[test]
+    fn test_front_coded_node() {
+        let items: [(&[u8], &[u8]); 3] = [
+            (b"\x08airline_10", b"a"),
+            (b"\x08airline_1001", b"b"),
+            (b"\x08airport_1254", b"c"),
+        ];
+        assert!(front_coding_saves(items.iter().map(|(key, _)| *key)));
+        assert!(!front_coding_saves([&b"a"[..], b"b"].into_iter()));
+
+        let mut node = vec![FRONT_CODED_KV_NODE];
+        let mut previous: &[u8] = &[];
+        for (key, value) in items {
+            write_front_coded_kv(&mut node, previous, key, value);
+            previous = key;
+        }
+        let mut plain = vec![NodeType::KVNode.into()];
+        for (key, value) in items {
+            write_kv(&mut plain, key, value);
+        }
+        assert!(node.len() < plain.len());
+        assert_eq!(expand_node(node.clone()).unwrap(), plain);
+
+        // A key can't share more than the one before it has
+        let mut bad = vec![FRONT_CODED_KV_NODE];
+        write_front_coded_kv(&mut bad, b"", b"key", b"value");
+        bad.extend_from_slice(&[0, 4]);
+        write_kv(&mut bad, b"x", b"value");
+        assert!(expand_node(bad).is_err());
+        assert!(expand_node(node[..node.len() - 1].to_vec()).is_err());
+    }
 }
@@ -0,0 +1,209 @@
+//! Reductions held in the pointers to B-tree nodes, summarising the items
+//! below each, so a summary of a whole tree is read from its root without
+//! visiting the rest. Like couchstore, only the by-id tree is reduced, to
+//! the counts of its documents and the space its tombstones take. Nodes written before reductions were kept
+//! have empty ones, and a node with such a child can't be reduced either,
+//! so files predating them have none until compacted.
+
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+
+use crate::{btree_modify::Node, BP_DELETED_FLAG};
+
+/// How a tree's nodes are reduced
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub(crate) enum Reducer {
+    #[default]
+    None,
+    /// The by-id tree's document counts
+    DocCounts,
+}
+
+impl Reducer {
+    /// The reduction of a node's items: the by-id entries of a leaf, or the
+    /// pointers to the children of an interior node. Empty when it can't be
+    /// reduced.
+    pub(crate) fn reduce(self, items: &[Node]) -> Vec<u8> {
+        match self {
+            Reducer::None => Vec::new(),
+            Reducer::DocCounts => {
+                let counts = items.iter().try_fold(DocCounts::default(), |sum, item| {
+                    let counts = match &item.pointer {
+                        Some(pointer) => DocCounts::decode(&pointer.reduce_value)?,
+                        None => DocCounts::from_id_index_entry(&item.key, &item.data)?,
+                    };
+                    Some(sum.add(&counts))
+                });
+                counts.map_or_else(Vec::new, |counts| counts.encode())
+            }
+        }
+    }
+}
+
+/// The documents in a file, from the by-id tree's reduction
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct DocCounts {
+    pub live: u64,
+    pub deleted: u64,
+    /// The bodies' sizes on disk
+    pub size: u64,
+    /// The deleted documents' by-id entries and bodies, what purging them
+    /// would free from the tree and file once compacted
+    pub deleted_size: u64,
+}
+
+impl DocCounts {
+    /// 40 bits for each count, 48 for each size
+    const ENCODED_SIZE: usize = 22;
+
+    pub(crate) fn decode(mut buf: &[u8]) -> Option<Self> {
+        if buf.len() != Self::ENCODED_SIZE {
+            return None;
+        }
+        Some(Self {
+            live: buf.read_uint::<BigEndian>(5).ok()?,
+            deleted: buf.read_uint::<BigEndian>(5).ok()?,
+            size: buf.read_u48::<BigEndian>().ok()?,
+            deleted_size: buf.read_u48::<BigEndian>().ok()?,
+        })
+    }
+
+    fn encode(&self) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(Self::ENCODED_SIZE);
+        buf.write_uint::<BigEndian>(self.live, 5).unwrap();
+        buf.write_uint::<BigEndian>(self.deleted, 5).unwrap();
+        buf.write_u48::<BigEndian>(self.size).unwrap();
+        buf.write_u48::<BigEndian>(self.deleted_size).unwrap();
+        buf
+    }
+
+    /// The counts of the one document a by-id entry is for
+    fn from_id_index_entry(key: &[u8], mut value: &[u8]) -> Option<Self> {
+        let entry_size = (key.len() + value.len()) as u64;
+        let _db_seq = value.read_u48::<BigEndian>().ok()?;
+        let size = value.read_u32::<BigEndian>().ok()?;
+        let deleted = value.read_u48::<BigEndian>().ok()? & BP_DELETED_FLAG != 0;
+        Some(Self {
+            live: u64::from(!deleted),
+            deleted: u64::from(deleted),
+            size: u64::from(size),
+            deleted_size: if deleted {
+                entry_size + u64::from(size)
+            } else {
+                0
+            },
+        })
+    }
+
+    fn add(&self, other: &Self) -> Self {
+        Self {
+            live: self.live + other.live,
+            deleted: self.deleted + other.deleted,
+            size: self.size + other.size,
+            deleted_size: self.deleted_size + other.deleted_size,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{BtreeTuning, ContentMetaFlag, DBOpenOptions, Db, Doc, DocInfo, SaveOptions};
+
+    fn doc_info(id: &str, db_seq: u64, deleted: bool) -> DocInfo {
+        DocInfo {
+            id: id.into(),
+            db_seq,
+            rev_seq: 1,
+            rev_meta: vec![],
+            deleted,
+            content_meta: ContentMetaFlag::empty(),
+            bp: 0,
+            physical_size: 0,
+        }
+    }
+
+    /// The counts as found by reading every document
+    fn count_docs(db: &Db) -> DocCounts {
+        let mut counts = DocCounts::default();
+        db.changes_since(0, |_, info| {
+            match info.deleted {
+                true => {
+                    counts.deleted += 1;
+                    counts.deleted_size += info.disk_size();
+                }
+                false => counts.live += 1,
+            }
+            counts.size += u64::from(info.physical_size);
+            Ok(())
+        })
+        .unwrap();
+        counts
+    }
+
+    #[test]
+    fn test_doc_counts() {
+        let dir = tempfile::tempdir().unwrap();
+        // Small nodes, so the trees are a few levels deep
+        let opts = DBOpenOptions::default().btree_tuning(BtreeTuning {
+            kv_node_size: 256,
+            kp_node_size: 256,
+            min_fanout: 2,
+        });
+        let mut db = Db::open(dir.path().join("0.couch.1"), opts).unwrap();
+        assert_eq!(db.doc_counts(), Some(DocCounts::default()));
+
+        let id = |i: u64| format!("doc_{}", i * 7919 % 1000);
+        for batch in 0..10 {
+            let (docs, infos) = (batch * 100..(batch + 1) * 100)
+                .map(|i| {
+                    let doc = Doc {
+                        id: id(i).into(),
+                        data: format!("body {i}").into(),
+                    };
+                    (Some(doc), doc_info(&id(i), 0, false))
+                })
+                .unzip();
+            db.save_documents(docs, infos, SaveOptions::empty())
+                .unwrap();
+        }
+        // Deleting a quarter of them updates the counts along their paths
+        let (docs, infos) = (0..1000)
+            .filter(|i| i % 4 == 0)
+            .map(|i| (None, doc_info(&id(i), 0, true)))
+            .unzip();
+        db.save_documents(docs, infos, SaveOptions::empty())
+            .unwrap();
+        db.commit();
+        let counts = db.doc_counts().unwrap();
+        assert_eq!((counts.live, counts.deleted), (750, 250));
+        assert_eq!(counts, count_docs(&db));
+
+        let gone = db.docinfo_by_id(id(0)).unwrap().unwrap();
+        db.purge_documents(&[gone]).unwrap();
+        let after_purge = db.doc_counts().unwrap();
+        assert_eq!(after_purge.deleted, 249);
+        assert!(after_purge.deleted_size < counts.deleted_size);
+        assert_eq!(after_purge, count_docs(&db));
+
+        let compacted = db.compact_to(dir.path().join("0.couch.2"), opts).unwrap();
+        assert_eq!(compacted.doc_counts(), db.doc_counts());
+
+        let mut loader = Db::open(dir.path().join("1.couch.1"), opts)
+            .unwrap()
+            .bulk_load(SaveOptions::empty())
+            .unwrap();
+        for seq in 1..=300 {
+            let doc = (seq % 3 != 0).then(|| Doc {
+                id: id(seq).into(),
+                data: b"body".to_vec(),
+            });
+            loader
+                .add(doc.as_ref(), &doc_info(&id(seq), seq, doc.is_none()))
+                .unwrap();
+        }
+        let loaded = loader.finish().unwrap();
+        let counts = loaded.doc_counts().unwrap();
+        assert_eq!((counts.live, counts.deleted), (200, 100));
+        assert_eq!(counts, count_docs(&loaded));
+    }
+}
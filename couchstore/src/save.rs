@@ -1,8 +1,7 @@
 use crate::{
-    btree_modify::{
-        CouchfileModifyAction, CouchfileModifyActionType, CouchfileModifyRequest, UpdateIdContext,
-    },
-    ContentMetaFlag, Db, Doc, DocInfo, SaveOptions,
+    btree_modify::{CouchfileModifyAction, CouchfileModifyActionType, CouchfileModifyRequest},
+    reduce::Reducer,
+    ContentMetaFlag, CouchstoreError, CouchstoreResult, Db, Doc, DocInfo, SaveOptions,
 };
 
 impl Db {
@@ -11,16 +10,24 @@ impl Db {
         doc: Option<Doc>,
         info: DocInfo,
         options: SaveOptions,
-    ) {
-        self.save_documents_and_callback(doc.map(|doc| vec![doc]), vec![info], options);
+    ) -> CouchstoreResult<()> {
+        self.save_documents(vec![doc], vec![info], options)
     }
 
-    fn save_documents_and_callback(
+    /// Save a batch of documents. A document without a body is saved as a
+    /// deletion. The changes aren't durable until the next commit.
+    pub fn save_documents(
         &mut self,
-        docs: Option<Vec<Doc>>,
+        docs: Vec<Option<Doc>>,
         mut infos: Vec<DocInfo>,
         options: SaveOptions,
-    ) {
+    ) -> CouchstoreResult<()> {
+        assert_eq!(docs.len(), infos.len());
+        // Checked up front so a rejected batch writes nothing
+        for (doc, info) in docs.iter().zip(&infos) {
+            self.check_doc_size(doc.as_ref(), info)?;
+        }
+
         // TODO: Reduce allocations, couchstore uses 1 buffer for all the data
         let mut ids: Vec<Vec<u8>> = Vec::new();
         let mut seqs: Vec<u64> = Vec::new();
@@ -31,21 +38,16 @@ impl Db {
 
         for i in 0..infos.len() {
             let info = &mut infos[i];
-            let mut doc = None;
 
             if options.contains(SaveOptions::SEQUENCE_AS_IS) {
-                seq = info.db_seq;
+                seq = seq.max(info.db_seq);
             } else {
                 seq += 1;
                 info.db_seq = seq;
             }
 
-            if let Some(docs) = &docs {
-                doc = Some(&docs[i])
-            }
-
             self.add_doc_to_update_list(
-                doc,
+                docs[i].as_ref(),
                 info,
                 &mut seqs,
                 &mut ids,
@@ -55,9 +57,65 @@ impl Db {
             );
         }
 
-        self.update_indexes(seqs, ids, seq_idx, id_idx, infos.len());
+        let keep_history = options.contains(SaveOptions::KEEP_HISTORY);
+        self.update_indexes(seqs, ids, seq_idx, id_idx, keep_history)?;
 
         self.header.update_seq = seq;
+
+        Ok(())
+    }
+
+    /// Remove documents from both indexes entirely, as compaction does with
+    /// old deletions, and raise the purge seq to the highest seq removed.
+    /// The changes aren't durable until the next commit.
+    pub fn purge_documents(&mut self, infos: &[DocInfo]) -> CouchstoreResult<()> {
+        let remove = |key| CouchfileModifyAction {
+            key,
+            data: None,
+            action_type: CouchfileModifyActionType::Remove,
+        };
+        let mut id_actions: Vec<_> = infos.iter().map(|info| remove(info.id.clone())).collect();
+        id_actions.sort_by(|a, b| a.key.cmp(&b.key));
+        let mut seq_actions: Vec<_> = infos
+            .iter()
+            .map(|info| remove(encode_seq_key(info.db_seq)))
+            .collect();
+        seq_actions.sort_by(|a, b| a.key.cmp(&b.key));
+
+        let id_req = CouchfileModifyRequest {
+            actions: id_actions,
+            context: (),
+            tuning: self.opts.tuning,
+            reducer: Reducer::DocCounts,
+        };
+        self.header.by_id_root = self
+            .file
+            .modify_btree(id_req, self.header.by_id_root.clone())?;
+
+        let seq_req = CouchfileModifyRequest {
+            actions: seq_actions,
+            context: (),
+            tuning: self.opts.tuning,
+            reducer: Reducer::None,
+        };
+        self.header.by_seq_root = self
+            .file
+            .modify_btree(seq_req, self.header.by_seq_root.clone())?;
+
+        if let Some(max_seq) = infos.iter().map(|info| info.db_seq).max() {
+            self.header.purge_seq = self.header.purge_seq.max(max_seq);
+        }
+        Ok(())
+    }
+
+    pub(crate) fn check_doc_size(&self, doc: Option<&Doc>, info: &DocInfo) -> CouchstoreResult<()> {
+        if info.id.len() > self.opts.max_key_size {
+            return Err(CouchstoreError::TooBig("key"));
+        }
+        if doc.is_some_and(|doc| doc.data.len() > self.opts.max_value_size) {
+            return Err(CouchstoreError::TooBig("value"));
+        }
+        Ok(())
     }
 
     #[allow(clippy::too_many_arguments)]
@@ -69,11 +127,32 @@ impl Db {
         ids: &mut Vec<Vec<u8>>,
         seq_idx: &mut Vec<Vec<u8>>,
         id_idx: &mut Vec<Vec<u8>>,
-        mut options: SaveOptions,
+        options: SaveOptions,
     ) {
-        let mut updated = info.clone();
+        let updated = self.write_doc_body(doc, info, options);
 
         seqs.push(updated.db_seq);
+        ids.push(updated.id.clone());
+
+        let mut seq_index_value = Vec::new();
+        let mut id_index_value = Vec::new();
+
+        updated.encode_id_index_value(&mut id_index_value);
+        updated.encode_seq_index_value(&mut seq_index_value);
+
+        id_idx.push(id_index_value);
+        seq_idx.push(seq_index_value);
+    }
+
+    /// Write the document's body, if it has one, returning its info updated
+    /// with where the body is
+    pub(crate) fn write_doc_body(
+        &mut self,
+        doc: Option<&Doc>,
+        info: &DocInfo,
+        mut options: SaveOptions,
+    ) -> DocInfo {
+        let mut updated = info.clone();
 
         if let Some(doc) = doc {
             let mut disk_size = 0;
@@ -91,53 +170,105 @@ impl Db {
             updated.bp = 0;
             updated.physical_size = 0;
         }
-
-        ids.push(updated.id.clone());
-
-        let mut seq_index_value = Vec::new();
-        let mut id_index_value = Vec::new();
-
-        updated.encode_id_index_value(&mut id_index_value);
-        updated.encode_seq_index_value(&mut seq_index_value);
-
-        id_idx.push(id_index_value);
-        seq_idx.push(seq_index_value);
+        updated
     }
 
     fn update_indexes(
         &mut self,
-        _seqs: Vec<u64>,
+        seqs: Vec<u64>,
         ids: Vec<Vec<u8>>,
-        _seq_idx: Vec<Vec<u8>>,
+        seq_idx: Vec<Vec<u8>>,
         id_idx: Vec<Vec<u8>>,
-        _num_docs: usize,
-    ) {
-        let mut id_keys_and_data = ids.into_iter().zip(id_idx).collect::<Vec<_>>();
-        id_keys_and_data.sort_unstable_by(|(key_a, _), (key_b, _)| key_a.cmp(key_b));
+        keep_history: bool,
+    ) -> CouchstoreResult<()> {
+        // With history every revision stays in the by-seq index
+        let history = if keep_history {
+            seqs.iter().copied().zip(seq_idx.iter().cloned()).collect()
+        } else {
+            Vec::new()
+        };
 
-        let id_actions = id_keys_and_data
+        // Only the last revision of a doc saved more than once in the batch
+        // is indexed by id
+        let mut docs = ids
             .into_iter()
-            .map(|(key, data)| CouchfileModifyAction {
-                key,
-                data: Some(data),
-                action_type: CouchfileModifyActionType::FetchInsert,
-            })
+            .zip(id_idx)
+            .zip(seqs.into_iter().zip(seq_idx))
+            .rev()
             .collect::<Vec<_>>();
+        docs.sort_by(|((key_a, _), _), ((key_b, _), _)| key_a.cmp(key_b));
+        docs.dedup_by(|((key_a, _), _), ((key_b, _), _)| key_a == key_b);
+
+        // Otherwise the previous revisions' entries must be removed from the
+        // by-seq index
+        let new_seqs = docs.iter().map(|(_, (seq, _))| *seq).collect::<Vec<_>>();
+        let mut old_seqs = Vec::new();
+        if !keep_history {
+            self.docinfos_by_id(
+                docs.iter().map(|((key, _), _)| key.clone()).collect(),
+                |_, docinfo| {
+                    if let Some(docinfo) = docinfo {
+                        if !new_seqs.contains(&docinfo.db_seq) {
+                            old_seqs.push(docinfo.db_seq);
+                        }
+                    }
+                },
+            )?;
+        }
+
+        let mut id_actions = Vec::with_capacity(docs.len());
+        let mut seq_actions = Vec::with_capacity(docs.len() + old_seqs.len() + history.len());
+        for ((key, id_data), (seq, seq_data)) in docs {
+            id_actions.push(CouchfileModifyAction {
+                key,
+                data: Some(id_data),
+                action_type: CouchfileModifyActionType::Insert,
+            });
+            if !keep_history {
+                seq_actions.push(CouchfileModifyAction {
+                    key: encode_seq_key(seq),
+                    data: Some(seq_data),
+                    action_type: CouchfileModifyActionType::Insert,
+                });
+            }
+        }
+        for (seq, seq_data) in history {
+            seq_actions.push(CouchfileModifyAction {
+                key: encode_seq_key(seq),
+                data: Some(seq_data),
+                action_type: CouchfileModifyActionType::Insert,
+            });
+        }
+        for seq in old_seqs {
+            seq_actions.push(CouchfileModifyAction {
+                key: encode_seq_key(seq),
+                data: None,
+                action_type: CouchfileModifyActionType::Remove,
+            });
+        }
+        seq_actions.sort_by(|a, b| a.key.cmp(&b.key));
 
         let id_req = CouchfileModifyRequest {
             actions: id_actions,
-            context: UpdateIdContext {
-                seq_actions: vec![],
-            },
-            kv_chunk_threshold: self.opts.kv_chunk_threshold,
-            kp_chunk_threshold: self.opts.kp_chunk_threshold,
+            context: (),
+            tuning: self.opts.tuning,
+            reducer: Reducer::DocCounts,
         };
+        self.header.by_id_root = self
+            .file
+            .modify_btree(id_req, self.header.by_id_root.clone())?;
 
-        let new_id_root = self
+        let seq_req = CouchfileModifyRequest {
+            actions: seq_actions,
+            context: (),
+            tuning: self.opts.tuning,
+            reducer: Reducer::None,
+        };
+        self.header.by_seq_root = self
             .file
-            .modify_btree(id_req, self.header.by_id_root.clone());
+            .modify_btree(seq_req, self.header.by_seq_root.clone())?;
 
-        self.header.by_id_root = new_id_root;
+        Ok(())
     }
 
     fn write_doc(&mut self, doc: &Doc, bp: &mut u64, disk_size: &mut u32, options: SaveOptions) {
@@ -148,3 +279,8 @@ impl Db {
         }
     }
 }
+
+/// By-seq index keys are 48 bit big endian seqnos, so they sort numerically
+pub(crate) fn encode_seq_key(seq: u64) -> Vec<u8> {
+    seq.to_be_bytes()[2..].to_vec()
+}
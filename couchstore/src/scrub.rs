@@ -0,0 +1,206 @@
+//! Scrubbing a database file: reading every node its trees reach, and the
+//! document bodies, to check their CRCs and that the trees' keys are in
+//! order, so corruption is found before a read trips over it.
+
+use std::cmp::Ordering;
+
+use crate::{btree_read::NodeType, node_types::RawNode, seq_no_compare, Db, DocInfo, NodePointer};
+
+/// A chunk which couldn't be read, or a node breaking its tree's invariants
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ScrubFinding {
+    /// "by_id", "by_seq", "local", or the name of an auxiliary tree
+    pub tree: String,
+    /// Offset of the chunk in the file
+    pub position: u64,
+    pub problem: String,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ScrubReport {
+    pub nodes: u64,
+    pub documents: u64,
+    /// Bytes of chunk data checked
+    pub bytes: u64,
+    pub findings: Vec<ScrubFinding>,
+}
+
+struct TreeScrub<'a, F> {
+    tree: &'a str,
+    compare: fn(&[u8], &[u8]) -> Ordering,
+    /// Read the bodies of the documents in the leaves
+    bodies: bool,
+    report: &'a mut ScrubReport,
+    on_read: &'a mut F,
+}
+
+impl Db {
+    /// Scrub the trees of the latest header, calling on_read with the size
+    /// of each chunk read so the caller can limit the bandwidth used. Only
+    /// the by-seq tree's documents are read, which covers the by-id tree's.
+    pub fn scrub(&self, mut on_read: impl FnMut(usize)) -> ScrubReport {
+        let mut report = ScrubReport::default();
+        let header = self.header.clone();
+        let mut trees = vec![
+            ("by_id", header.by_id_root, false),
+            ("by_seq", header.by_seq_root, true),
+            ("local", header.local_docs_root, false),
+        ];
+        for (name, root) in &header.aux_roots {
+            trees.push((name.as_str(), root.clone(), false));
+        }
+
+        for (tree, root, bodies) in trees {
+            let Some(root) = root else {
+                continue;
+            };
+            let compare = if tree == "by_seq" {
+                seq_no_compare
+            } else {
+                |a: &[u8], b: &[u8]| a.cmp(b)
+            };
+            let mut scrub = TreeScrub {
+                tree,
+                compare,
+                bodies,
+                report: &mut report,
+                on_read: &mut on_read,
+            };
+            self.scrub_node(&mut scrub, root.pointer, header.position, None, None);
+        }
+        report
+    }
+
+    /// Check the node at pos, written before parent_pos, whose keys must be
+    /// above lower and at most upper
+    fn scrub_node<F: FnMut(usize)>(
+        &self,
+        scrub: &mut TreeScrub<F>,
+        pos: u64,
+        parent_pos: u64,
+        lower: Option<&[u8]>,
+        upper: Option<&[u8]>,
+    ) {
+        let find = |scrub: &mut TreeScrub<F>, position, problem: String| {
+            scrub.report.findings.push(ScrubFinding {
+                tree: scrub.tree.to_string(),
+                position,
+                problem,
+            })
+        };
+        // Children are written before their parents, so this also stops
+        // a corrupt pointer from looping
+        if pos >= parent_pos {
+            find(scrub, pos, "node pointer out of order".to_string());
+            return;
+        }
+        let buf = match self.file.read_node(pos as usize) {
+            Ok(buf) => buf,
+            Err(e) => {
+                find(scrub, pos, e.to_string());
+                return;
+            }
+        };
+        scrub.report.nodes += 1;
+        scrub.report.bytes += buf.len() as u64;
+        (scrub.on_read)(buf.len());
+        let node = match RawNode::decode(&buf) {
+            Ok(node) => node,
+            Err(e) => {
+                find(scrub, pos, e.to_string());
+                return;
+            }
+        };
+
+        let compare = scrub.compare;
+        let mut previous = lower;
+        for (key, value) in node.items {
+            let in_order = previous.is_none_or(|previous| compare(previous, key).is_lt())
+                && upper.is_none_or(|upper| compare(key, upper).is_le());
+            if !in_order {
+                find(scrub, pos, "keys out of order".to_string());
+            }
+            match node.node_type {
+                NodeType::KPNode => match NodePointer::read_pointer(key, value) {
+                    Ok(child) => self.scrub_node(scrub, child.pointer, pos, previous, Some(key)),
+                    Err(e) => find(scrub, pos, e.to_string()),
+                },
+                NodeType::KVNode if scrub.bodies => self.scrub_document(scrub, pos, key, value),
+                NodeType::KVNode => {}
+            }
+            previous = Some(key);
+        }
+    }
+
+    fn scrub_document<F: FnMut(usize)>(
+        &self,
+        scrub: &mut TreeScrub<F>,
+        pos: u64,
+        key: &[u8],
+        value: &[u8],
+    ) {
+        let (position, result) = match DocInfo::decode_by_seq_index_value(key, value) {
+            Ok(info) if info.bp == 0 => return,
+            Ok(info) => (info.bp, self.file.read_uncompressed(info.bp as usize)),
+            Err(e) => (pos, Err(e)),
+        };
+        match result {
+            Ok(body) => {
+                scrub.report.documents += 1;
+                scrub.report.bytes += body.len() as u64;
+                (scrub.on_read)(body.len());
+            }
+            Err(e) => scrub.report.findings.push(ScrubFinding {
+                tree: scrub.tree.to_string(),
+                position,
+                problem: e.to_string(),
+            }),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{DBOpenOptions, Db};
+    use std::{
+        fs::OpenOptions,
+        io::{Seek, SeekFrom, Write},
+    };
+
+    #[test]
+    fn test_scrub() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("0.couch.1");
+        let mut db = Db::open(&path, DBOpenOptions::default()).unwrap();
+        for i in 0..500 {
+            db.set(format!("doc_{i:03}").into(), format!("value_{i}").into())
+                .unwrap();
+        }
+        db.commit();
+        db.create_aux_tree("expiry").unwrap();
+        db.modify_aux_tree("expiry", vec![(Vec::from("key"), Some(vec![]))])
+            .unwrap();
+        db.commit();
+
+        let mut read = 0;
+        let report = db.scrub(|bytes| read += bytes);
+        assert!(report.findings.is_empty(), "{:?}", report.findings);
+        assert_eq!(report.documents, 500);
+        assert!(report.nodes > 3);
+        assert_eq!(read as u64, report.bytes);
+
+        // Flip a byte in the middle of a document body
+        let bp = db.docinfo_by_id("doc_123").unwrap().unwrap().bp;
+        let mut file = OpenOptions::new().write(true).open(&path).unwrap();
+        file.seek(SeekFrom::Start(bp + 10)).unwrap();
+        file.write_all(b"X").unwrap();
+
+        let db = Db::open(&path, DBOpenOptions::default().read_only()).unwrap();
+        let report = db.scrub(|_| {});
+        assert_eq!(report.documents, 499);
+        assert_eq!(report.findings.len(), 1);
+        assert_eq!(report.findings[0].tree, "by_seq");
+        assert_eq!(report.findings[0].position, bp);
+        assert_eq!(report.findings[0].problem, "checksum mismatch");
+    }
+}
@@ -0,0 +1,157 @@
+//! Choosing when commits are made durable. By default every commit syncs
+//! its data then its header, which is safe but makes each commit wait for
+//! the disk. Syncing less often trades some durability for throughput.
+
+use std::time::{Duration, Instant};
+
+use crate::{utils, CouchstoreResult, Db};
+
+/// When a commit syncs what has been written. Without a sync on every
+/// commit, a crash can lose the commits since the last sync, or leave them
+/// unreadable as their header may reach the disk before their data.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum SyncPolicy {
+    #[default]
+    EveryCommit,
+    /// Once at least this many bytes have been written since the last sync
+    Bytes(u64),
+    /// Once at least this long has passed since the last sync
+    Interval(Duration),
+}
+
+/// What has been written since the last sync, to decide when the policy
+/// calls for the next one. It is kept apart from the Db so it can outlive
+/// the file handle.
+#[derive(Debug, Clone, Copy)]
+pub struct SyncState {
+    unsynced_bytes: u64,
+    last_sync: Instant,
+}
+
+impl Default for SyncState {
+    fn default() -> Self {
+        Self {
+            unsynced_bytes: 0,
+            last_sync: Instant::now(),
+        }
+    }
+}
+
+impl SyncState {
+    /// Bytes written by commits which haven't been synced
+    pub fn unsynced_bytes(&self) -> u64 {
+        self.unsynced_bytes
+    }
+
+    /// Count bytes written outside of a Db, for stores which sync other
+    /// files by the same policy
+    pub fn wrote(&mut self, bytes: u64) {
+        self.unsynced_bytes += bytes;
+    }
+
+    /// Whether the policy calls for a sync after what has been written
+    pub fn is_due(&self, policy: SyncPolicy) -> bool {
+        match policy {
+            SyncPolicy::EveryCommit => true,
+            SyncPolicy::Bytes(bytes) => self.unsynced_bytes >= bytes,
+            SyncPolicy::Interval(interval) => self.last_sync.elapsed() >= interval,
+        }
+    }
+
+    pub fn synced(&mut self) {
+        self.unsynced_bytes = 0;
+        self.last_sync = Instant::now();
+    }
+}
+
+impl Db {
+    /// Commit, only syncing when the policy calls for it. Returns whether
+    /// it synced, or the error if a write since the last commit failed.
+    pub fn commit_with_policy(
+        &mut self,
+        policy: SyncPolicy,
+        state: &mut SyncState,
+    ) -> CouchstoreResult<bool> {
+        if policy == SyncPolicy::EveryCommit {
+            self.try_commit()?;
+            self.committed_bytes = self.file.stats.bytes_written;
+            state.synced();
+            return Ok(true);
+        }
+
+        self.header.timestamp = utils::now();
+        self.write_header();
+        self.file.check_writes()?;
+        state.wrote(self.file.stats.bytes_written - self.committed_bytes);
+        self.committed_bytes = self.file.stats.bytes_written;
+        if !state.is_due(policy) {
+            return Ok(false);
+        }
+        self.sync(state)?;
+        Ok(true)
+    }
+
+    /// Make every commit so far durable
+    pub fn sync(&mut self, state: &mut SyncState) -> CouchstoreResult<()> {
+        self.file.sync();
+        self.file.check_writes()?;
+        state.synced();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{CouchstoreError, DBOpenOptions};
+
+    #[test]
+    fn test_commit_with_policy() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("0.couch.1");
+        let mut db = Db::open(&path, DBOpenOptions::default().dsync()).unwrap();
+        let mut state = SyncState::default();
+        let policy = SyncPolicy::Bytes(16 * 1024);
+
+        let mut synced = 0;
+        for i in 0..100 {
+            db.set(format!("doc_{i}").into(), vec![b'x'; 1024]).unwrap();
+            if db.commit_with_policy(policy, &mut state).unwrap() {
+                synced += 1;
+            }
+        }
+        // Each commit writes a body, the tree nodes and a header block
+        assert!(synced > 0 && synced < 100, "{synced} syncs");
+        assert!(state.unsynced_bytes() < 16 * 1024);
+        assert_eq!(db.file_stats().syncs, synced);
+        db.sync(&mut state).unwrap();
+        assert_eq!(state.unsynced_bytes(), 0);
+
+        // Every commit is readable, synced or not
+        let db = Db::open(&path, DBOpenOptions::default().read_only()).unwrap();
+        assert_eq!(db.header().update_seq, 100);
+        assert!(db.docinfo_by_id("doc_99").unwrap().is_some());
+
+        let mut db = Db::open(&path, DBOpenOptions::default()).unwrap();
+        db.set(Vec::from("doc"), Vec::from("value")).unwrap();
+        assert!(!db
+            .commit_with_policy(SyncPolicy::Interval(Duration::from_secs(60)), &mut state)
+            .unwrap());
+        assert!(db
+            .commit_with_policy(SyncPolicy::Interval(Duration::ZERO), &mut state)
+            .unwrap());
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn test_write_failure() {
+        // Every write to /dev/full fails with ENOSPC
+        let mut db = Db::open("/dev/full", DBOpenOptions::default()).unwrap();
+        db.set(Vec::from("doc"), Vec::from("value")).unwrap();
+        let mut state = SyncState::default();
+        let err = db
+            .commit_with_policy(SyncPolicy::EveryCommit, &mut state)
+            .unwrap_err();
+        assert!(matches!(err, CouchstoreError::Write(e) if e.raw_os_error() == Some(28)));
+    }
+}
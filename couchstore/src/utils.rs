@@ -1,9 +1,8 @@
-use crate::constants::COUCH_BLOCK_SIZE;
 use std::time::SystemTime;
 
-pub(crate) fn align_to_next_block(offset: usize) -> usize {
-    if offset % COUCH_BLOCK_SIZE != 0 {
-        return offset + COUCH_BLOCK_SIZE - (offset % COUCH_BLOCK_SIZE);
+pub(crate) fn align_to_next_block(offset: usize, block_size: usize) -> usize {
+    if !offset.is_multiple_of(block_size) {
+        return offset + block_size - (offset % block_size);
     }
     offset
 }
@@ -0,0 +1,545 @@
+//! Read support for view group index files (`.view.<rev>`), as written by
+//! the view engine. A view file uses the same blocks and chunks as a data
+//! file but has its own header, an id btree mapping each document to the
+//! keys it emitted, and one btree per view from JSON keys to their values.
+
+use byteorder::{BigEndian, ReadBytesExt};
+use std::{
+    io::{Read, Seek, SeekFrom},
+    path::Path,
+};
+
+use crate::{
+    btree_read::NodeType,
+    constants::{COUCH_BLOCK_SIZE, MAX_DECOMPRESSED_SIZE},
+    node_types::RawNode,
+    CouchstoreError, CouchstoreResult, DBOpenOptions, TreeFile,
+};
+
+/// Index headers carry bitmasks and seqnos for every partition, so they're
+/// larger than a data file's
+const MAX_INDEX_HEADER_SIZE: usize = 64 * 1024;
+
+/// Partitions are tracked in 1024 bit masks
+const BITMASK_SIZE: usize = 1024 / 8;
+
+/// A btree's root as recorded in the index header
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BtreeState {
+    pub pointer: u64,
+    pub subtree_size: u64,
+    /// The reduction of the whole tree
+    pub reduction: Vec<u8>,
+}
+
+/// Partitions moving between states once their indexing catches up
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PendingTransition {
+    pub active: Vec<u16>,
+    pub passive: Vec<u16>,
+    pub unindexable: Vec<u16>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct IndexHeader {
+    /// Hash of the design document the index was built from
+    pub signature: [u8; 16],
+    pub version: u8,
+    pub num_partitions: u16,
+    pub active_partitions: Vec<u16>,
+    pub passive_partitions: Vec<u16>,
+    /// Partitions whose entries are still being removed
+    pub cleanup_partitions: Vec<u16>,
+    /// The seqno each partition has been indexed up to
+    pub seqs: Vec<(u16, u64)>,
+    pub id_btree_state: Option<BtreeState>,
+    pub view_btree_states: Vec<Option<BtreeState>>,
+    pub has_replica: bool,
+    pub replicas_on_transfer: Vec<u16>,
+    pub pending_transition: PendingTransition,
+    pub unindexable_seqs: Vec<(u16, u64)>,
+    /// Each partition's failover log of (uuid, seqno), from version 2
+    pub partition_versions: Vec<(u16, Vec<(u64, u64)>)>,
+}
+
+/// The reduction of (part of) the id btree
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct IdBtreeReduction {
+    pub kv_count: u64,
+    pub partitions: Vec<u16>,
+}
+
+/// The reduction of (part of) a view btree, with one value per reduce
+/// function of the view
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ViewBtreeReduction {
+    pub kv_count: u64,
+    pub partitions: Vec<u16>,
+    pub values: Vec<Vec<u8>>,
+}
+
+/// The keys a document emitted into each view, by view number
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct IdBtreeEntry {
+    pub partition: u16,
+    pub doc_id: Vec<u8>,
+    pub view_keys: Vec<(u8, Vec<Vec<u8>>)>,
+}
+
+/// A row of a view. A document emitting the same key more than once has
+/// every value in the one row.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ViewBtreeEntry {
+    /// JSON
+    pub key: Vec<u8>,
+    pub doc_id: Vec<u8>,
+    pub partition: u16,
+    /// JSON
+    pub values: Vec<Vec<u8>>,
+}
+
+#[derive(Debug)]
+pub struct ViewFile {
+    file: TreeFile,
+    header: IndexHeader,
+}
+
+impl ViewFile {
+    /// Open a view file read only at its latest header
+    pub fn open(filename: impl AsRef<Path>) -> CouchstoreResult<ViewFile> {
+        let file = std::fs::File::open(filename)?;
+        let mut file = TreeFile::new(file, DBOpenOptions::default().read_only());
+        let len = file.file.seek(SeekFrom::End(0))? as usize;
+        file.pos = len;
+
+        let mut pos = len.saturating_sub(1) / COUCH_BLOCK_SIZE * COUCH_BLOCK_SIZE;
+        loop {
+            if let Ok(header) = Self::read_header_at(&file, pos) {
+                return Ok(ViewFile { file, header });
+            }
+            if pos == 0 {
+                return Err(CouchstoreError::NoHeader);
+            }
+            pos -= COUCH_BLOCK_SIZE;
+        }
+    }
+
+    fn read_header_at(file: &TreeFile, pos: usize) -> CouchstoreResult<IndexHeader> {
+        if !file.is_header_block(pos)? {
+            return Err(CouchstoreError::NoHeader);
+        }
+        let (buf, _) = file.read_header(pos, MAX_INDEX_HEADER_SIZE)?;
+        IndexHeader::decode(&buf)
+    }
+
+    pub fn header(&self) -> &IndexHeader {
+        &self.header
+    }
+
+    /// Call back with every document in the id btree, in key order
+    pub fn fold_id_btree(&self, mut callback: impl FnMut(IdBtreeEntry)) -> CouchstoreResult<()> {
+        let Some(root) = self.header.id_btree_state.as_ref() else {
+            return Ok(());
+        };
+        self.fold_node(root.pointer as usize, &mut |key, value| {
+            callback(IdBtreeEntry::decode(key, value)?);
+            Ok(())
+        })
+    }
+
+    /// Call back with every row of the view, in the order the view engine
+    /// collated the keys. Panics if the file has no such view.
+    pub fn fold_view_btree(
+        &self,
+        view: usize,
+        mut callback: impl FnMut(ViewBtreeEntry),
+    ) -> CouchstoreResult<()> {
+        let Some(root) = self.header.view_btree_states[view].as_ref() else {
+            return Ok(());
+        };
+        self.fold_node(root.pointer as usize, &mut |key, value| {
+            callback(ViewBtreeEntry::decode(key, value)?);
+            Ok(())
+        })
+    }
+
+    fn fold_node<F>(&self, diskpos: usize, on_kv: &mut F) -> CouchstoreResult<()>
+    where
+        F: FnMut(&[u8], &[u8]) -> CouchstoreResult<()>,
+    {
+        let buf = self.file.read_node(diskpos)?;
+        let node = RawNode::decode(&buf)?;
+        for (key, value) in node.items {
+            match node.node_type {
+                NodeType::KVNode => on_kv(key, value)?,
+                NodeType::KPNode => {
+                    let pointer = (&value[..])
+                        .read_u48::<BigEndian>()
+                        .map_err(|_| CouchstoreError::Corrupt("truncated node pointer"))?
+                        as usize;
+                    // Children are written before their parents
+                    if pointer >= diskpos {
+                        return Err(CouchstoreError::Corrupt("node pointer out of order"));
+                    }
+                    self.fold_node(pointer, on_kv)?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+impl IndexHeader {
+    /// The signature followed by the snappy compressed header
+    pub fn decode(buf: &[u8]) -> CouchstoreResult<IndexHeader> {
+        if buf.len() < 16 {
+            return Err(CouchstoreError::Corrupt("truncated index header"));
+        }
+        let (signature, compressed) = buf.split_at(16);
+        let len = snap::raw::decompress_len(compressed)
+            .map_err(|_| CouchstoreError::Corrupt("invalid compressed index header"))?;
+        if len > MAX_DECOMPRESSED_SIZE {
+            return Err(CouchstoreError::Corrupt("index header too large"));
+        }
+        let body = snap::raw::Decoder::new()
+            .decompress_vec(compressed)
+            .map_err(|_| CouchstoreError::Corrupt("invalid compressed index header"))?;
+        Self::decode_body(signature.try_into().unwrap(), &body)
+            .map_err(|_| CouchstoreError::Corrupt("truncated index header"))
+    }
+
+    fn decode_body(signature: [u8; 16], mut buf: &[u8]) -> std::io::Result<IndexHeader> {
+        let version = buf.read_u8()?;
+        let num_partitions = buf.read_u16::<BigEndian>()?;
+        let active_partitions = read_bitmask(&mut buf)?;
+        let passive_partitions = read_bitmask(&mut buf)?;
+        let cleanup_partitions = read_bitmask(&mut buf)?;
+        let seqs = read_seqs(&mut buf)?;
+        let id_btree_state = read_btree_state(&mut buf)?;
+        let num_views = buf.read_u8()?;
+        let view_btree_states = (0..num_views)
+            .map(|_| read_btree_state(&mut buf))
+            .collect::<std::io::Result<_>>()?;
+        let has_replica = buf.read_u8()? != 0;
+        let replicas_on_transfer = read_partitions(&mut buf)?;
+        let pending_transition = PendingTransition {
+            active: read_partitions(&mut buf)?,
+            passive: read_partitions(&mut buf)?,
+            unindexable: read_partitions(&mut buf)?,
+        };
+        let unindexable_seqs = read_seqs(&mut buf)?;
+        let mut partition_versions = Vec::new();
+        if version >= 2 {
+            for _ in 0..buf.read_u16::<BigEndian>()? {
+                let partition = buf.read_u16::<BigEndian>()?;
+                let failover_log = (0..buf.read_u16::<BigEndian>()?)
+                    .map(|_| Ok((buf.read_u64::<BigEndian>()?, buf.read_u64::<BigEndian>()?)))
+                    .collect::<std::io::Result<_>>()?;
+                partition_versions.push((partition, failover_log));
+            }
+        }
+
+        Ok(IndexHeader {
+            signature,
+            version,
+            num_partitions,
+            active_partitions,
+            passive_partitions,
+            cleanup_partitions,
+            seqs,
+            id_btree_state,
+            view_btree_states,
+            has_replica,
+            replicas_on_transfer,
+            pending_transition,
+            unindexable_seqs,
+            partition_versions,
+        })
+    }
+
+    /// The document count and partitions of the whole id btree
+    pub fn id_btree_reduction(&self) -> CouchstoreResult<Option<IdBtreeReduction>> {
+        let Some(state) = &self.id_btree_state else {
+            return Ok(None);
+        };
+        let mut buf = &state.reduction[..];
+        let reduction = (|| {
+            Ok(IdBtreeReduction {
+                kv_count: buf.read_uint::<BigEndian>(5)?,
+                partitions: read_bitmask(&mut buf)?,
+            })
+        })();
+        reduction
+            .map(Some)
+            .map_err(|_: std::io::Error| CouchstoreError::Corrupt("truncated id btree reduction"))
+    }
+
+    /// The row count, partitions and reduce values of the whole view.
+    /// Panics if the file has no such view.
+    pub fn view_btree_reduction(
+        &self,
+        view: usize,
+    ) -> CouchstoreResult<Option<ViewBtreeReduction>> {
+        let Some(state) = &self.view_btree_states[view] else {
+            return Ok(None);
+        };
+        let mut buf = &state.reduction[..];
+        let reduction = (|| {
+            let kv_count = buf.read_uint::<BigEndian>(5)?;
+            let partitions = read_bitmask(&mut buf)?;
+            let mut values = Vec::new();
+            while !buf.is_empty() {
+                values.push(read_sized(&mut buf, 2)?);
+            }
+            Ok(ViewBtreeReduction {
+                kv_count,
+                partitions,
+                values,
+            })
+        })();
+        reduction
+            .map(Some)
+            .map_err(|_: std::io::Error| CouchstoreError::Corrupt("truncated view btree reduction"))
+    }
+}
+
+impl IdBtreeEntry {
+    /// The key is the partition then the document id, the value the
+    /// partition then each view's keys
+    fn decode(key: &[u8], value: &[u8]) -> CouchstoreResult<IdBtreeEntry> {
+        let truncated = |_| CouchstoreError::Corrupt("truncated id btree entry");
+        if key.len() < 2 {
+            return Err(CouchstoreError::Corrupt("truncated id btree entry"));
+        }
+        let doc_id = key[2..].to_vec();
+        let mut buf = value;
+        let partition = buf.read_u16::<BigEndian>().map_err(truncated)?;
+        let mut view_keys = Vec::new();
+        while !buf.is_empty() {
+            let view = buf.read_u8().map_err(truncated)?;
+            let keys = (0..buf.read_u16::<BigEndian>().map_err(truncated)?)
+                .map(|_| read_sized(&mut buf, 2))
+                .collect::<std::io::Result<_>>()
+                .map_err(truncated)?;
+            view_keys.push((view, keys));
+        }
+        Ok(IdBtreeEntry {
+            partition,
+            doc_id,
+            view_keys,
+        })
+    }
+}
+
+impl ViewBtreeEntry {
+    /// The key is the sized JSON key then the document id, the value the
+    /// partition then each sized JSON value
+    fn decode(key: &[u8], value: &[u8]) -> CouchstoreResult<ViewBtreeEntry> {
+        let truncated = |_| CouchstoreError::Corrupt("truncated view btree entry");
+        let mut buf = key;
+        let json_key = read_sized(&mut buf, 2).map_err(truncated)?;
+        let doc_id = buf.to_vec();
+        let mut buf = value;
+        let partition = buf.read_u16::<BigEndian>().map_err(truncated)?;
+        let mut values = Vec::new();
+        while !buf.is_empty() {
+            values.push(read_sized(&mut buf, 3).map_err(truncated)?);
+        }
+        Ok(ViewBtreeEntry {
+            key: json_key,
+            doc_id,
+            partition,
+            values,
+        })
+    }
+}
+
+/// A big endian length of len_size bytes followed by that many bytes
+fn read_sized(buf: &mut &[u8], len_size: usize) -> std::io::Result<Vec<u8>> {
+    let len = buf.read_uint::<BigEndian>(len_size)? as usize;
+    if len > buf.len() {
+        return Err(std::io::ErrorKind::UnexpectedEof.into());
+    }
+    let (data, rest) = buf.split_at(len);
+    *buf = rest;
+    Ok(data.to_vec())
+}
+
+/// The set bits of a 1024 bit big endian mask, partition 0 being the last
+/// byte's lowest bit
+fn read_bitmask(buf: &mut &[u8]) -> std::io::Result<Vec<u16>> {
+    let mut mask = [0u8; BITMASK_SIZE];
+    buf.read_exact(&mut mask)?;
+    Ok((0..(BITMASK_SIZE * 8) as u16)
+        .filter(|&partition| {
+            let byte = mask[BITMASK_SIZE - 1 - partition as usize / 8];
+            byte & (1 << (partition % 8)) != 0
+        })
+        .collect())
+}
+
+fn read_partitions(buf: &mut &[u8]) -> std::io::Result<Vec<u16>> {
+    (0..buf.read_u16::<BigEndian>()?)
+        .map(|_| buf.read_u16::<BigEndian>())
+        .collect()
+}
+
+fn read_seqs(buf: &mut &[u8]) -> std::io::Result<Vec<(u16, u64)>> {
+    (0..buf.read_u16::<BigEndian>()?)
+        .map(|_| Ok((buf.read_u16::<BigEndian>()?, buf.read_u48::<BigEndian>()?)))
+        .collect()
+}
+
+/// A pointer and subtree size, then the reduction, none if empty
+fn read_btree_state(buf: &mut &[u8]) -> std::io::Result<Option<BtreeState>> {
+    let state = read_sized(buf, 2)?;
+    if state.is_empty() {
+        return Ok(None);
+    }
+    let mut state = &state[..];
+    Ok(Some(BtreeState {
+        pointer: state.read_u48::<BigEndian>()?,
+        subtree_size: state.read_u48::<BigEndian>()?,
+        reduction: state.to_vec(),
+    }))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::node_types::write_kv;
+    use byteorder::WriteBytesExt;
+    use std::io::Write;
+
+    fn bitmask(partitions: &[u16]) -> [u8; BITMASK_SIZE] {
+        let mut mask = [0u8; BITMASK_SIZE];
+        for &partition in partitions {
+            mask[BITMASK_SIZE - 1 - partition as usize / 8] |= 1 << (partition % 8);
+        }
+        mask
+    }
+
+    fn write_node(file: &mut TreeFile, items: &[(Vec<u8>, Vec<u8>)]) -> u64 {
+        let mut node = vec![NodeType::KVNode.into()];
+        for (key, value) in items {
+            write_kv(&mut node, key, value);
+        }
+        let (mut pos, mut size) = (0, 0);
+        file.db_write_buf_compressed(&node, &mut pos, &mut size);
+        pos
+    }
+
+    fn btree_state(pointer: u64, reduction: &[u8]) -> Vec<u8> {
+        let mut state = Vec::new();
+        state.write_u48::<BigEndian>(pointer).unwrap();
+        state.write_u48::<BigEndian>(100).unwrap();
+        state.write_all(reduction).unwrap();
+        state
+    }
+
+    #[test]
+    fn test_read_view_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("main_0123.view.1");
+        let file = std::fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&path)
+            .unwrap();
+        let mut file = TreeFile::new(file, DBOpenOptions::default());
+
+        // doc_a in partition 3 emitted ["x", 1] once into view 0
+        let mut id_key = vec![0, 3];
+        id_key.extend_from_slice(b"doc_a");
+        let mut id_value = vec![0, 3, 0, 0, 1];
+        id_value.write_u16::<BigEndian>(7).unwrap();
+        id_value.extend_from_slice(br#"["x",1]"#);
+        let id_root = write_node(&mut file, &[(id_key, id_value)]);
+
+        let mut view_key = Vec::new();
+        view_key.write_u16::<BigEndian>(7).unwrap();
+        view_key.extend_from_slice(br#"["x",1]doc_a"#);
+        let mut view_value = vec![0, 3];
+        view_value.write_u24::<BigEndian>(2).unwrap();
+        view_value.extend_from_slice(b"42");
+        let view_root = write_node(&mut file, &[(view_key, view_value)]);
+
+        let mut id_reduction = vec![0, 0, 0, 0, 1];
+        id_reduction.extend_from_slice(&bitmask(&[3]));
+        let mut view_reduction = id_reduction.clone();
+        view_reduction.write_u16::<BigEndian>(1).unwrap();
+        view_reduction.push(b'1');
+
+        let mut body = vec![1];
+        body.write_u16::<BigEndian>(4).unwrap();
+        body.extend_from_slice(&bitmask(&[3]));
+        body.extend_from_slice(&bitmask(&[]));
+        body.extend_from_slice(&bitmask(&[2]));
+        body.write_u16::<BigEndian>(1).unwrap();
+        body.write_u16::<BigEndian>(3).unwrap();
+        body.write_u48::<BigEndian>(17).unwrap();
+        let state = btree_state(id_root, &id_reduction);
+        body.write_u16::<BigEndian>(state.len() as u16).unwrap();
+        body.extend_from_slice(&state);
+        // One view which has rows and one which is empty
+        body.push(2);
+        let state = btree_state(view_root, &view_reduction);
+        body.write_u16::<BigEndian>(state.len() as u16).unwrap();
+        body.extend_from_slice(&state);
+        body.write_u16::<BigEndian>(0).unwrap();
+        // No replica nor partitions in transition
+        body.extend_from_slice(&[0; 11]);
+
+        let mut header = vec![0xab; 16];
+        header.extend(snap::raw::Encoder::new().compress_vec(&body).unwrap());
+        file.write_header(&header);
+
+        let view_file = ViewFile::open(&path).unwrap();
+        let header = view_file.header().clone();
+        assert_eq!(header.signature, [0xab; 16]);
+        assert_eq!(header.num_partitions, 4);
+        assert_eq!(header.active_partitions, vec![3]);
+        assert_eq!(header.cleanup_partitions, vec![2]);
+        assert_eq!(header.seqs, vec![(3, 17)]);
+        assert_eq!(header.view_btree_states.len(), 2);
+        assert!(header.view_btree_states[1].is_none());
+        assert_eq!(
+            header.id_btree_reduction().unwrap(),
+            Some(IdBtreeReduction {
+                kv_count: 1,
+                partitions: vec![3]
+            })
+        );
+        assert_eq!(
+            header.view_btree_reduction(0).unwrap().unwrap().values,
+            vec![b"1".to_vec()]
+        );
+
+        let mut ids = Vec::new();
+        view_file.fold_id_btree(|entry| ids.push(entry)).unwrap();
+        assert_eq!(
+            ids,
+            vec![IdBtreeEntry {
+                partition: 3,
+                doc_id: b"doc_a".to_vec(),
+                view_keys: vec![(0, vec![br#"["x",1]"#.to_vec()])],
+            }]
+        );
+        let mut rows = Vec::new();
+        view_file
+            .fold_view_btree(0, |entry| rows.push(entry))
+            .unwrap();
+        assert_eq!(
+            rows,
+            vec![ViewBtreeEntry {
+                key: br#"["x",1]"#.to_vec(),
+                doc_id: b"doc_a".to_vec(),
+                partition: 3,
+                values: vec![b"42".to_vec()],
+            }]
+        );
+        view_file.fold_view_btree(1, |_| panic!()).unwrap();
+    }
+}
@@ -0,0 +1,1100 @@
+//! A stable API for embedding the engine.
+//!
+//! An [`Engine`] manages a set of named buckets, each stored in its own
+//! subdirectory of the configured `dbname` and of each of the `data_paths`.
+//! A bucket is warmed up from disk
+//! the first time it is opened, and any vbuckets it doesn't have on disk are
+//! created active, so every key can be read and written through it.
+//!
+//! Work done through a [`Bucket`] handle is charged to that bucket's memory
+//! (see [`crate::memory_tracker`]), so with the tracking allocator each
+//! bucket's mem_used and watermarks only count its own data.
+//!
+//! When the config names an audit log, the engine records buckets being
+//! opened and shut down there, and the document accesses through them if
+//! those events are enabled.
+//!
+//! The modules behind this one (vbuckets, stores, checkpoints) remain public
+//! for tools and tests, but may change between releases.
+
+use parking_lot::Mutex;
+use serde::{de::DeserializeOwned, Serialize};
+use std::{
+    collections::{BTreeMap, HashMap},
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
+
+use crate::{
+    audit::{AuditEvent, AuditLog, DocumentEvent},
+    data_dir,
+    ep_bucket::{EPBucket, EPBucketPtr},
+    failover_table::FailoverTable,
+    item::Datatype,
+    kv_store,
+    stored_value::StoredValue,
+    vbucket::{State, Vbid},
+    vbucket_hash::vbucket_for_key,
+    warmup::Warmup,
+    Config,
+};
+
+pub use crate::{
+    compaction::{CompactAllOptions, CompactAllProgress, CompactAllState},
+    dcp::{
+        consumer::DcpConsumer,
+        producer::{DcpOpenFlags, DcpProducer, StreamRequest, StreamRequestFlags},
+        response::{DcpResponse, EndStreamStatus},
+    },
+    doc_trace::{DiskEntry, DocTrace, HashTableEntry},
+    ep_bucket::{Mutation, MutationInfo, MutationToken},
+    error::{EngineError, EngineResult},
+    health::{BucketHealth, EngineHealth, ShardHealth, TaskHealth},
+    typed_bucket::{TypedBucket, TypedDocument, TypedError, TypedResult},
+    Config as EngineConfig,
+};
+
+/// A value read from a bucket
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Document {
+    pub value: Vec<u8>,
+    pub cas: u64,
+    pub flags: u32,
+    /// Absolute expiry time in seconds, 0 if the document doesn't expire
+    pub expiry_time: u32,
+}
+
+impl From<StoredValue> for Document {
+    fn from(value: StoredValue) -> Self {
+        Self {
+            value: value.value.unwrap_or_default(),
+            cas: value.cas,
+            flags: value.flags,
+            expiry_time: value.expiry_time,
+        }
+    }
+}
+
+/// An operation in a batch run by [`Bucket::execute`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Op {
+    Get {
+        key: Vec<u8>,
+    },
+    Set {
+        key: Vec<u8>,
+        value: Vec<u8>,
+        flags: u32,
+        expiry_time: u32,
+    },
+    Delete {
+        key: Vec<u8>,
+    },
+}
+
+impl Op {
+    fn key(&self) -> &[u8] {
+        match self {
+            Op::Get { key } | Op::Set { key, .. } | Op::Delete { key } => key,
+        }
+    }
+}
+
+/// The result of a successful operation in a batch
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum OpResult {
+    /// The document read by a get
+    Document(Document),
+    /// The new CAS after a set or delete
+    Cas(u64),
+}
+
+pub struct Engine {
+    config: Config,
+    buckets: Mutex<HashMap<String, EPBucketPtr>>,
+    audit: Option<Arc<AuditLog>>,
+}
+
+impl Engine {
+    /// Create an engine whose buckets all use the given config. Nothing is
+    /// read from disk until a bucket is opened, but the audit log is. If it
+    /// can't be opened auditing is disabled, with an alert logged.
+    pub fn new(config: Config) -> Self {
+        let audit = config.audit_log.as_ref().and_then(|path| {
+            AuditLog::open(
+                path,
+                config.audit_rotate_size,
+                &config.audit_disabled_events,
+            )
+            .map(Arc::new)
+            .inspect_err(|e| {
+                println!("ALERT: auditing is disabled, failed to open the audit log {path}: {e}");
+            })
+            .ok()
+        });
+        Self {
+            config,
+            buckets: Mutex::new(HashMap::new()),
+            audit,
+        }
+    }
+
+    /// Record an event in the audit log, if there is one. This is for the
+    /// events the engine doesn't see itself, such as authentication.
+    pub fn audit(&self, event: AuditEvent) {
+        if let Some(audit) = &self.audit {
+            audit.record(event);
+        }
+    }
+
+    /// Get a handle to the named bucket, opening it if needed. Bucket names
+    /// are directory names, so must be non-empty and can't contain path
+    /// separators.
+    pub fn bucket(&self, name: &str) -> EngineResult<Bucket> {
+        check_bucket_name(name)?;
+        let mut buckets = self.buckets.lock();
+        let bucket = match buckets.get(name) {
+            Some(bucket) => bucket.clone(),
+            None => {
+                let bucket = self.open_bucket(name)?;
+                buckets.insert(name.to_string(), bucket.clone());
+                self.audit(AuditEvent::BucketOpen {
+                    bucket: name.to_string(),
+                });
+                bucket
+            }
+        };
+        Ok(Bucket {
+            inner: bucket,
+            name: name.to_string(),
+            audit: self.audit.clone(),
+        })
+    }
+
+    /// Whether each open bucket is fit to serve, for readiness probes
+    pub fn health(&self) -> EngineHealth {
+        let buckets = self.buckets.lock();
+        EngineHealth {
+            buckets: buckets
+                .iter()
+                .map(|(name, bucket)| (name.clone(), bucket.health()))
+                .collect(),
+        }
+    }
+
+    /// Names of the open buckets, in no particular order
+    pub fn bucket_names(&self) -> Vec<String> {
+        self.buckets.lock().keys().cloned().collect()
+    }
+
+    /// Shut down the named bucket without persisting outstanding writes and
+    /// remove its files. Fails with KeyNotFound if it has none.
+    pub fn delete_bucket(&self, name: &str) -> EngineResult<()> {
+        check_bucket_name(name)?;
+        let mut buckets = self.buckets.lock();
+        if let Some(bucket) = buckets.remove(name) {
+            bucket.shutdown(true);
+            self.audit(AuditEvent::BucketShutdown {
+                bucket: name.to_string(),
+            });
+        }
+        let dirs: Vec<PathBuf> = std::iter::once(&self.config.dbname)
+            .chain(&self.config.data_paths)
+            .map(|path| Path::new(path).join(name))
+            .filter(|dir| dir.exists())
+            .collect();
+        if dirs.is_empty() {
+            return Err(EngineError::KeyNotFound);
+        }
+        for dir in dirs {
+            std::fs::remove_dir_all(&dir).map_err(|e| {
+                println!("Failed to remove {}: {e}", dir.display());
+                EngineError::TemporaryFailure
+            })?;
+        }
+        Ok(())
+    }
+
+    /// Shut down every open bucket, persisting outstanding writes. Handles
+    /// to the buckets fail with TemporaryFailure afterwards.
+    pub fn shutdown(&self) {
+        for (name, bucket) in self.buckets.lock().drain() {
+            bucket.shutdown(false);
+            self.audit(AuditEvent::BucketShutdown { bucket: name });
+        }
+    }
+
+    fn open_bucket(&self, name: &str) -> EngineResult<EPBucketPtr> {
+        if let Err(e) = self.config.btree_tuning.validate() {
+            println!("Refusing to open bucket {name}: {e}");
+            return Err(EngineError::InvalidArguments);
+        }
+        let dbname = Path::new(&self.config.dbname).join(name);
+        std::fs::create_dir_all(&dbname).map_err(|e| {
+            println!("Failed to create {}: {e}", dbname.display());
+            EngineError::TemporaryFailure
+        })?;
+        let data_paths = self
+            .config
+            .data_paths
+            .iter()
+            .map(|path| {
+                let path = Path::new(path).join(name);
+                std::fs::create_dir_all(&path).map_err(|e| {
+                    println!("Failed to create {}: {e}", path.display());
+                    EngineError::TemporaryFailure
+                })?;
+                Ok(path.to_string_lossy().into_owned())
+            })
+            .collect::<EngineResult<Vec<String>>>()?;
+        // The data paths must belong to the same bucket as dbname
+        let disk_version = kv_store::couchstore_disk_version(self.config.front_coded_keys);
+        let check = |dir: &Path, uuid| {
+            data_dir::check_data_dir(dir, name, self.config.backend, uuid, disk_version).map_err(
+                |e| {
+                    println!(
+                        "Refusing to open bucket {name}, data directory {}: {e}",
+                        dir.display()
+                    );
+                    EngineError::IncompatibleDataDir
+                },
+            )
+        };
+        let marker = check(&dbname, None)?;
+        for path in &data_paths {
+            check(Path::new(path), Some(&marker.bucket_uuid))?;
+        }
+        let config = Config {
+            dbname: dbname.to_string_lossy().into_owned(),
+            data_paths,
+            ..self.config.clone()
+        };
+        let bucket = EPBucket::new(config.clone());
+        let _memory = bucket.memory_scope();
+        Warmup::new(bucket.clone(), config.clone()).warmup();
+        for vbid in 0..config.max_vbuckets {
+            let vbid = Vbid::new(vbid);
+            if bucket.get_vbucket(vbid).is_none() {
+                bucket.vbucket_map.add_bucket(bucket.make_vbucket(
+                    vbid,
+                    State::Active,
+                    FailoverTable::new_empty(25),
+                    0,
+                    0,
+                ));
+            }
+        }
+        EPBucket::start_scrubber(&bucket);
+        EPBucket::start_syncer(&bucket);
+        EPBucket::start_expiry_pager(&bucket);
+        EPBucket::start_reconciler(&bucket);
+        EPBucket::start_disk_monitor(&bucket);
+        Ok(bucket)
+    }
+}
+
+/// Fails unless the name can be a directory of its own
+fn check_bucket_name(name: &str) -> EngineResult<()> {
+    if name.is_empty() || name.starts_with('.') || name.contains(['/', '\\']) {
+        return Err(EngineError::InvalidArguments);
+    }
+    Ok(())
+}
+
+/// A handle to an open bucket. Handles are cheap to clone and stay valid
+/// until the engine is shut down.
+#[derive(Clone)]
+pub struct Bucket {
+    inner: EPBucketPtr,
+    name: String,
+    audit: Option<Arc<AuditLog>>,
+}
+
+impl Bucket {
+    /// Get a document, with its value decompressed. A value which isn't in
+    /// memory is read from disk.
+    pub fn get(&self, key: &[u8]) -> EngineResult<Document> {
+        self.get_multi(&[key]).remove(0)
+    }
+
+    /// Get a document once the bucket has caught up with a write, given
+    /// the token the write returned: a session reads its own writes this
+    /// way even from a replica or after a failover. A token of another
+    /// vbucket says nothing about the key, so doesn't hold the read up.
+    /// Fails with TemporaryFailure if the bucket hasn't caught up within
+    /// the timeout, and with Rollback if the write was lost to a failover.
+    pub fn get_after(
+        &self,
+        key: &[u8],
+        token: &MutationToken,
+        timeout: Duration,
+    ) -> EngineResult<Document> {
+        if vbucket_for_key(key, self.inner.config().max_vbuckets) == token.vbid {
+            self.inner.wait_for_token(token, timeout)?;
+        }
+        self.get(key)
+    }
+
+    /// Get several documents, in the order of the keys. Keys in the same
+    /// vbucket are looked up together and any of their values which aren't
+    /// in memory are read from disk in one go.
+    pub fn get_multi(&self, keys: &[&[u8]]) -> Vec<EngineResult<Document>> {
+        let _memory = self.inner.memory_scope();
+        self.inner
+            .get_multi(keys.iter().map(|key| key.to_vec()).collect())
+            .into_iter()
+            .zip(keys)
+            .map(|(value, key)| {
+                self.audit_result(key, AuditEvent::DocumentRead, value)
+                    .map(Document::from)
+            })
+            .collect()
+    }
+
+    /// Run a batch of operations, returning their results in order. The
+    /// operations are grouped by vbucket, and consecutive gets within a
+    /// vbucket are run as one get_multi. Operations on the same key still
+    /// run in the order given.
+    pub fn execute(&self, ops: Vec<Op>) -> Vec<EngineResult<OpResult>> {
+        let _memory = self.inner.memory_scope();
+        let max_vbuckets = self.inner.config().max_vbuckets;
+        let mut by_vbucket: BTreeMap<Vbid, Vec<(usize, Op)>> = BTreeMap::new();
+        for (i, op) in ops.into_iter().enumerate() {
+            let vbid = vbucket_for_key(op.key(), max_vbuckets);
+            by_vbucket.entry(vbid).or_default().push((i, op));
+        }
+
+        let mut results = BTreeMap::new();
+        for ops in by_vbucket.into_values() {
+            let mut gets = Vec::new();
+            for (i, op) in ops {
+                let result = match op {
+                    Op::Get { key } => {
+                        gets.push((i, key));
+                        continue;
+                    }
+                    Op::Set {
+                        key,
+                        value,
+                        flags,
+                        expiry_time,
+                    } => {
+                        self.run_gets(&mut gets, &mut results);
+                        self.audit_result(
+                            &key,
+                            AuditEvent::DocumentModify,
+                            self.inner.set(key.clone(), value, flags, expiry_time),
+                        )
+                    }
+                    Op::Delete { key } => {
+                        self.run_gets(&mut gets, &mut results);
+                        self.audit_result(
+                            &key,
+                            AuditEvent::DocumentDelete,
+                            self.inner.delete(key.clone(), 0),
+                        )
+                    }
+                };
+                results.insert(i, result.map(OpResult::Cas));
+            }
+            self.run_gets(&mut gets, &mut results);
+        }
+        results.into_values().collect()
+    }
+
+    fn run_gets(
+        &self,
+        gets: &mut Vec<(usize, Vec<u8>)>,
+        results: &mut BTreeMap<usize, EngineResult<OpResult>>,
+    ) {
+        if gets.is_empty() {
+            return;
+        }
+        let (indexes, keys): (Vec<usize>, Vec<Vec<u8>>) = gets.drain(..).unzip();
+        let values = self.inner.get_multi(keys.clone());
+        for ((i, key), value) in indexes.into_iter().zip(keys).zip(values) {
+            let value = self.audit_result(&key, AuditEvent::DocumentRead, value);
+            results.insert(i, value.map(|value| OpResult::Document(value.into())));
+        }
+    }
+
+    /// Record an access to the document in the audit log
+    fn audit_document(&self, key: &[u8], event: fn(DocumentEvent) -> AuditEvent) {
+        if let Some(audit) = &self.audit {
+            audit.record(event(DocumentEvent {
+                bucket: self.name.clone(),
+                key: String::from_utf8_lossy(key).into_owned(),
+            }));
+        }
+    }
+
+    /// Audit the access if the operation succeeded
+    fn audit_result<T>(
+        &self,
+        key: &[u8],
+        event: fn(DocumentEvent) -> AuditEvent,
+        result: EngineResult<T>,
+    ) -> EngineResult<T> {
+        result.inspect(|_| self.audit_document(key, event))
+    }
+
+    /// Store a document, returning its new CAS. An expiry time of up to 30
+    /// days is relative to now, a larger one is an absolute time.
+    pub fn set(&self, key: &[u8], value: &[u8], flags: u32, expiry_time: u32) -> EngineResult<u64> {
+        let _memory = self.inner.memory_scope();
+        self.audit_result(
+            key,
+            AuditEvent::DocumentModify,
+            self.inner
+                .set(key.to_vec(), value.to_vec(), flags, expiry_time),
+        )
+    }
+
+    /// As set, also returning the token a read can wait for with get_after
+    pub fn set_with_token(
+        &self,
+        key: &[u8],
+        value: &[u8],
+        flags: u32,
+        expiry_time: u32,
+    ) -> EngineResult<MutationInfo> {
+        let _memory = self.inner.memory_scope();
+        let result = self.inner.set_with_token(
+            key.to_vec(),
+            value.to_vec(),
+            Datatype::empty(),
+            flags,
+            expiry_time,
+            0,
+        );
+        self.audit_result(key, AuditEvent::DocumentModify, result)
+    }
+
+    /// As set, but only returns once the document is persisted. Fails with
+    /// TemporaryFailure while its vbucket can't be persisted.
+    pub fn set_durable(
+        &self,
+        key: &[u8],
+        value: &[u8],
+        flags: u32,
+        expiry_time: u32,
+    ) -> EngineResult<u64> {
+        let _memory = self.inner.memory_scope();
+        self.audit_result(
+            key,
+            AuditEvent::DocumentModify,
+            self.inner
+                .set_durable(key.to_vec(), value.to_vec(), flags, expiry_time),
+        )
+    }
+
+    /// As set, but only if the document's CAS matches. A locked document
+    /// can only be stored with the CAS of its lock, which releases it.
+    pub fn set_with_cas(
+        &self,
+        key: &[u8],
+        value: &[u8],
+        flags: u32,
+        expiry_time: u32,
+        cas: u64,
+    ) -> EngineResult<u64> {
+        self.set_with_datatype(key, value, Datatype::empty(), flags, expiry_time, cas)
+    }
+
+    pub(crate) fn set_with_datatype(
+        &self,
+        key: &[u8],
+        value: &[u8],
+        datatype: Datatype,
+        flags: u32,
+        expiry_time: u32,
+        cas: u64,
+    ) -> EngineResult<u64> {
+        let _memory = self.inner.memory_scope();
+        let result = self.inner.set_with_datatype(
+            key.to_vec(),
+            value.to_vec(),
+            datatype,
+            flags,
+            expiry_time,
+            cas,
+        );
+        self.audit_result(key, AuditEvent::DocumentModify, result)
+    }
+
+    /// A view of the bucket whose documents are values of type T, stored
+    /// as JSON
+    pub fn typed<T: Serialize + DeserializeOwned>(&self) -> TypedBucket<T> {
+        TypedBucket::new(self.clone())
+    }
+
+    /// Delete a document, returning the CAS of the deletion
+    pub fn delete(&self, key: &[u8]) -> EngineResult<u64> {
+        self.delete_with_cas(key, 0)
+    }
+
+    /// As delete, also returning the token a read can wait for with
+    /// get_after
+    pub fn delete_with_token(&self, key: &[u8]) -> EngineResult<MutationInfo> {
+        let _memory = self.inner.memory_scope();
+        let result = self.inner.delete_with_token(key.to_vec(), 0);
+        self.audit_result(key, AuditEvent::DocumentDelete, result)
+    }
+
+    /// As delete, but only if the document's CAS matches
+    pub fn delete_with_cas(&self, key: &[u8], cas: u64) -> EngineResult<u64> {
+        let _memory = self.inner.memory_scope();
+        let result = self.inner.delete(key.to_vec(), cas);
+        self.audit_result(key, AuditEvent::DocumentDelete, result)
+    }
+
+    /// Apply sets and deletes to keys of one vbucket all together or not at
+    /// all, returning each one's new CAS. If any fails, say on a CAS
+    /// mismatch, none are applied and the first failure is returned.
+    pub fn multi_mutate(&self, vbid: Vbid, mutations: Vec<Mutation>) -> EngineResult<Vec<u64>> {
+        let _memory = self.inner.memory_scope();
+        let keys: Vec<(Vec<u8>, bool)> = mutations
+            .iter()
+            .map(|mutation| match mutation {
+                Mutation::Set { key, .. } => (key.clone(), false),
+                Mutation::Delete { key, .. } => (key.clone(), true),
+            })
+            .collect();
+        let result = self.inner.multi_mutate(vbid, mutations);
+        if result.is_ok() {
+            for (key, deleted) in keys {
+                let event = if deleted {
+                    AuditEvent::DocumentDelete
+                } else {
+                    AuditEvent::DocumentModify
+                };
+                self.audit_document(&key, event);
+            }
+        }
+        result
+    }
+
+    /// Move a document to new_key, keeping its value, flags and expiry
+    /// time, and return its new CAS. Both keys must be in the same vbucket,
+    /// and new_key mustn't have a document.
+    pub fn rename(&self, key: &[u8], new_key: &[u8]) -> EngineResult<u64> {
+        let _memory = self.inner.memory_scope();
+        let result = self.inner.rename(key.to_vec(), new_key.to_vec(), 0);
+        if result.is_ok() {
+            self.audit_document(key, AuditEvent::DocumentDelete);
+        }
+        self.audit_result(new_key, AuditEvent::DocumentModify, result)
+    }
+
+    /// Get a document and lock it for lock_timeout seconds, or the bucket's
+    /// default if 0. Until the lock expires or is released, the document
+    /// can only be modified with the returned CAS, and other readers see a
+    /// CAS of u64::MAX.
+    pub fn get_locked(&self, key: &[u8], lock_timeout: u32) -> EngineResult<Document> {
+        let _memory = self.inner.memory_scope();
+        let result = self.inner.get_locked(key.to_vec(), lock_timeout);
+        self.audit_result(key, AuditEvent::DocumentRead, result)
+            .map(Document::from)
+    }
+
+    /// Release a lock taken with get_locked
+    pub fn unlock(&self, key: &[u8], cas: u64) -> EngineResult<()> {
+        let _memory = self.inner.memory_scope();
+        self.inner.unlock(key.to_vec(), cas)
+    }
+
+    /// Where the document is in memory, the replication queues and on
+    /// disk, for debugging
+    pub fn trace_doc(&self, key: &[u8]) -> EngineResult<DocTrace> {
+        let _memory = self.inner.memory_scope();
+        self.inner.trace_doc(key.to_vec())
+    }
+
+    /// The bucket's stats, by name
+    pub fn stats(&self) -> BTreeMap<String, String> {
+        let mut stats = BTreeMap::new();
+        self.inner.get_stats(&mut |key, value| {
+            stats.insert(key.to_string(), value.to_string());
+        });
+        stats
+    }
+
+    /// The stats of a group, such as "kvstore" or "compact_all", by name.
+    /// Fails with KeyNotFound for an unknown group.
+    pub fn stats_group(&self, group: &str) -> EngineResult<BTreeMap<String, String>> {
+        let mut stats = BTreeMap::new();
+        self.inner.get_stats_group(group, &mut |key, value| {
+            stats.insert(key.to_string(), value.to_string());
+        })?;
+        Ok(stats)
+    }
+
+    /// Start compacting every vbucket in the background, returning its
+    /// progress, which also cancels it. Fails with TemporaryFailure while
+    /// one is running or the disk is full.
+    pub fn compact_all(&self, options: CompactAllOptions) -> EngineResult<Arc<CompactAllProgress>> {
+        EPBucket::compact_all(&self.inner, options)
+    }
+
+    /// How many vbuckets the bucket has
+    pub fn num_vbuckets(&self) -> u16 {
+        self.inner.config().max_vbuckets
+    }
+
+    /// Change the bucket's memory quota, its watermarks keeping their
+    /// fractions of it
+    pub fn set_memory_quota(&self, bytes: usize) {
+        let config = self.inner.config();
+        self.inner
+            .stats()
+            .set_max_data_size(bytes, config.mem_low_wat, config.mem_high_wat);
+    }
+
+    /// Move a vbucket to the state, as the cluster manager does when it
+    /// rebalances or fails over
+    pub fn set_vbucket_state(&self, vbid: Vbid, state: State) -> EngineResult<()> {
+        // Checked first, as there is no lock to take for a vbucket out of
+        // range
+        if self.inner.get_vbucket(vbid).is_none() {
+            return Err(EngineError::NotMyVbucket);
+        }
+        let _memory = self.inner.memory_scope();
+        self.inner.set_vbucket_state(vbid, state)
+    }
+
+    /// The vbuckets quarantined for failing to persist too many times
+    pub fn quarantined_vbuckets(&self) -> Vec<Vbid> {
+        self.inner.quarantined_vbuckets()
+    }
+
+    /// Return a quarantined vbucket to service once its disk is fixed
+    pub fn release_quarantine(&self, vbid: Vbid) -> EngineResult<()> {
+        // As for set_vbucket_state
+        if self.inner.get_vbucket(vbid).is_none() {
+            return Err(EngineError::NotMyVbucket);
+        }
+        let _memory = self.inner.memory_scope();
+        self.inner.release_quarantine(vbid)
+    }
+
+    /// Open a DCP producer streaming the bucket's vbuckets, as a client
+    /// connection to the server would
+    pub fn dcp_producer(&self, name: &str, flags: DcpOpenFlags) -> Arc<DcpProducer> {
+        DcpProducer::new(name, flags, self.inner.clone())
+    }
+
+    /// Open a DCP consumer applying a producer's streams to the bucket's
+    /// replica and pending vbuckets
+    pub fn dcp_consumer(&self, name: &str) -> DcpConsumer {
+        DcpConsumer::new(name, self.inner.clone())
+    }
+
+    /// Write every vbucket's state to disk with its outstanding mutations,
+    /// creating the files of vbuckets which have none. Does nothing while
+    /// the bucket is paused.
+    pub fn persist(&self) {
+        if self.inner.is_paused() {
+            return;
+        }
+        let _memory = self.inner.memory_scope();
+        self.inner.persist_all();
+    }
+
+    /// Persist what is outstanding, then write nothing to disk until resume,
+    /// so the bucket's files can be copied by an external backup. Reads and
+    /// writes carry on in memory. Returns false if it was already paused.
+    pub fn pause(&self) -> bool {
+        let _memory = self.inner.memory_scope();
+        self.inner.pause()
+    }
+
+    /// Let persistence continue after a pause, writing what was held back.
+    /// Returns false if the bucket wasn't paused.
+    pub fn resume(&self) -> bool {
+        let _memory = self.inner.memory_scope();
+        self.inner.resume()
+    }
+
+    /// Write all outstanding mutations to disk
+    pub fn flush(&self) {
+        let _memory = self.inner.memory_scope();
+        for vbid in self.inner.vbucket_map.get_buckets() {
+            self.inner.flush_vbucket(vbid);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn make_engine(dir: &tempfile::TempDir) -> Engine {
+        Engine::new(Config {
+            max_vbuckets: 4,
+            max_shards: 1,
+            dbname: dir.path().to_str().unwrap().to_string(),
+            ..Default::default()
+        })
+    }
+
+    #[test]
+    fn test_bucket_ops() {
+        let dir = tempfile::tempdir().unwrap();
+        let engine = make_engine(&dir);
+        assert_eq!(
+            engine.bucket("../x").err(),
+            Some(EngineError::InvalidArguments)
+        );
+
+        let bucket = engine.bucket("x").unwrap();
+        let cas = bucket.set(b"key", b"value", 7, 0).unwrap();
+        let doc = bucket.get(b"key").unwrap();
+        assert_eq!(doc.value, b"value");
+        assert_eq!(doc.cas, cas);
+        assert_eq!(doc.flags, 7);
+
+        // Buckets are independent
+        let other = engine.bucket("y").unwrap();
+        assert_eq!(other.get(b"key"), Err(EngineError::KeyNotFound));
+
+        bucket.delete(b"key").unwrap();
+        assert_eq!(bucket.get(b"key"), Err(EngineError::KeyNotFound));
+        assert_eq!(bucket.delete(b"key"), Err(EngineError::KeyNotFound));
+        bucket.set(b"key2", b"value2", 0, 0).unwrap();
+        assert!(bucket.stats().contains_key("ep_value_bytes_stored"));
+        engine.shutdown();
+
+        // Writes were persisted at shutdown and are warmed up on reopen
+        let engine = make_engine(&dir);
+        let bucket = engine.bucket("x").unwrap();
+        assert_eq!(bucket.get(b"key2").unwrap().value, b"value2");
+        assert_eq!(bucket.get(b"key"), Err(EngineError::KeyNotFound));
+        let mut names = engine.bucket_names();
+        names.sort();
+        assert_eq!(names, ["x"]);
+
+        // A replica vbucket's keys can't be written through it
+        let vbid = vbucket_for_key(b"key2", 4);
+        bucket.set_vbucket_state(vbid, State::Replica).unwrap();
+        assert_eq!(
+            bucket.set(b"key2", b"value3", 0, 0),
+            Err(EngineError::NotMyVbucket)
+        );
+        // Nor can vbuckets the bucket doesn't have be changed
+        assert_eq!(
+            bucket.set_vbucket_state(Vbid::new(4), State::Active),
+            Err(EngineError::NotMyVbucket)
+        );
+        assert_eq!(
+            bucket.release_quarantine(Vbid::new(4)),
+            Err(EngineError::NotMyVbucket)
+        );
+
+        // A deleted bucket's files are removed, so it reopens empty
+        engine.delete_bucket("x").unwrap();
+        assert!(engine.bucket_names().is_empty());
+        assert_eq!(engine.delete_bucket("x"), Err(EngineError::KeyNotFound));
+        let bucket = engine.bucket("x").unwrap();
+        assert_eq!(bucket.get(b"key2"), Err(EngineError::KeyNotFound));
+
+        // Another bucket's files aren't opened in its place
+        std::fs::create_dir(dir.path().join("z")).unwrap();
+        std::fs::copy(
+            dir.path().join("x").join(data_dir::MARKER_FILE),
+            dir.path().join("z").join(data_dir::MARKER_FILE),
+        )
+        .unwrap();
+        assert_eq!(
+            engine.bucket("z").err(),
+            Some(EngineError::IncompatibleDataDir)
+        );
+
+        // A bucket directory which can't be created fails the open
+        std::fs::write(dir.path().join("f"), b"").unwrap();
+        assert_eq!(
+            engine.bucket("f").err(),
+            Some(EngineError::TemporaryFailure)
+        );
+
+        // As does a data path's
+        let engine = Engine::new(Config {
+            dbname: dir.path().to_str().unwrap().to_string(),
+            data_paths: vec![dir.path().join("f").to_str().unwrap().to_string()],
+            ..Default::default()
+        });
+        assert_eq!(
+            engine.bucket("w").err(),
+            Some(EngineError::TemporaryFailure)
+        );
+
+        // Nor with B-tree tuning the file format can't hold
+        let engine = Engine::new(Config {
+            dbname: dir.path().to_str().unwrap().to_string(),
+            btree_tuning: couchstore::BtreeTuning {
+                min_fanout: 0,
+                ..Default::default()
+            },
+            ..Default::default()
+        });
+        assert_eq!(
+            engine.bucket("x").err(),
+            Some(EngineError::InvalidArguments)
+        );
+    }
+
+    #[test]
+    fn test_batch() {
+        let dir = tempfile::tempdir().unwrap();
+        let engine = Engine::new(Config {
+            max_vbuckets: 4,
+            max_shards: 1,
+            dbname: dir.path().to_str().unwrap().to_string(),
+            // Traffic is enabled after the first value is loaded, leaving
+            // the rest on disk
+            warmup_min_items_threshold: 0,
+            ..Default::default()
+        });
+        let bucket = engine.bucket("x").unwrap();
+        let keys: Vec<Vec<u8>> = (0..20).map(|i| format!("key{i}").into_bytes()).collect();
+        for key in &keys {
+            bucket.set(key, key, 0, 0).unwrap();
+        }
+        engine.shutdown();
+
+        let engine = Engine::new(Config {
+            max_vbuckets: 4,
+            max_shards: 1,
+            dbname: dir.path().to_str().unwrap().to_string(),
+            warmup_min_items_threshold: 0,
+            ..Default::default()
+        });
+        let bucket = engine.bucket("x").unwrap();
+        let mut lookup: Vec<&[u8]> = keys.iter().map(Vec::as_slice).collect();
+        lookup.push(b"missing");
+        let results = bucket.get_multi(&lookup);
+        for (key, result) in keys.iter().zip(&results) {
+            assert_eq!(&result.as_ref().unwrap().value, key);
+        }
+        assert_eq!(results[20], Err(EngineError::KeyNotFound));
+        // One bulk read per vbucket
+        let stats = bucket.stats();
+        assert_eq!(stats["ep_bg_fetched"], "19");
+        assert_eq!(stats["ep_bg_fetch_batches"], "4");
+
+        let results = bucket.execute(vec![
+            Op::Get {
+                key: b"key0".to_vec(),
+            },
+            Op::Delete {
+                key: b"key0".to_vec(),
+            },
+            Op::Get {
+                key: b"key0".to_vec(),
+            },
+            Op::Set {
+                key: b"key0".to_vec(),
+                value: b"new".to_vec(),
+                flags: 0,
+                expiry_time: 0,
+            },
+            Op::Get {
+                key: b"key0".to_vec(),
+            },
+        ]);
+        assert!(matches!(&results[0], Ok(OpResult::Document(doc)) if doc.value == b"key0"));
+        assert!(matches!(results[1], Ok(OpResult::Cas(_))));
+        assert_eq!(results[2], Err(EngineError::KeyNotFound));
+        assert!(matches!(results[3], Ok(OpResult::Cas(_))));
+        assert!(matches!(&results[4], Ok(OpResult::Document(doc)) if doc.value == b"new"));
+    }
+
+    #[test]
+    fn test_audit() {
+        let dir = tempfile::tempdir().unwrap();
+        let audit_log = dir.path().join("audit.log");
+        let engine = Engine::new(Config {
+            max_vbuckets: 4,
+            max_shards: 1,
+            dbname: dir.path().to_str().unwrap().to_string(),
+            audit_log: Some(audit_log.to_str().unwrap().to_string()),
+            audit_disabled_events: vec![crate::audit::AuditEventId::DocumentRead],
+            ..Default::default()
+        });
+        let bucket = engine.bucket("x").unwrap();
+        bucket.set(b"key", b"value", 0, 0).unwrap();
+        bucket.get(b"key").unwrap();
+        bucket.delete(b"key").unwrap();
+        // Failed accesses aren't recorded
+        assert!(bucket.delete(b"key").is_err());
+        engine.audit(AuditEvent::PrivilegeFailure {
+            user: "reader".to_string(),
+            bucket: Some("x".to_string()),
+            privilege: "data.write".to_string(),
+        });
+        engine.shutdown();
+
+        let records: Vec<serde_json::Value> = std::fs::read_to_string(&audit_log)
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        let ids: Vec<&str> = records.iter().map(|r| r["id"].as_str().unwrap()).collect();
+        assert_eq!(
+            ids,
+            [
+                "bucket_open",
+                "document_modify",
+                "document_delete",
+                "privilege_failure",
+                "bucket_shutdown"
+            ]
+        );
+        assert_eq!(records[1]["bucket"], "x");
+        assert_eq!(records[1]["key"], "key");
+
+        // An audit log which can't be opened disables auditing
+        let engine = Engine::new(Config {
+            max_vbuckets: 4,
+            max_shards: 1,
+            dbname: dir.path().to_str().unwrap().to_string(),
+            audit_log: Some(
+                dir.path()
+                    .join("none/audit.log")
+                    .to_str()
+                    .unwrap()
+                    .to_string(),
+            ),
+            ..Default::default()
+        });
+        assert!(engine.audit.is_none());
+        engine.bucket("x").unwrap().get(b"key").unwrap_err();
+        engine.shutdown();
+    }
+
+    #[test]
+    fn test_shutdown_quarantined() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = Config {
+            max_vbuckets: 4,
+            max_shards: 1,
+            dbname: dir.path().to_str().unwrap().to_string(),
+            flusher_commit_retries: 0,
+            flusher_retry_backoff_ms: 1,
+            quarantine_flush_failures: 1,
+            ..Default::default()
+        };
+        let engine = Engine::new(config.clone());
+        let bucket = engine.bucket("x").unwrap();
+        let vbid = vbucket_for_key(b"key_0", 4);
+        let key = (1..)
+            .map(|i| format!("key_{i}"))
+            .find(|key| vbucket_for_key(key.as_bytes(), 4) == vbid)
+            .unwrap();
+        bucket.set(b"key_0", b"value", 0, 0).unwrap();
+        bucket.inner.flush_vbucket(vbid);
+
+        // The vbucket's file can't be written, so it is quarantined
+        let path = dir.path().join(format!("x/{vbid}.couch.1"));
+        let backup = path.with_extension("bak");
+        std::fs::rename(&path, &backup).unwrap();
+        std::fs::create_dir(&path).unwrap();
+        bucket.set(key.as_bytes(), b"value", 0, 0).unwrap();
+        bucket.inner.flush_vbucket(vbid);
+        assert_eq!(bucket.inner.quarantined_vbuckets(), [vbid]);
+
+        // Shutting down leaves it be, keeping what it had persisted
+        engine.shutdown();
+        std::fs::remove_dir(&path).unwrap();
+        std::fs::rename(&backup, &path).unwrap();
+        let engine = Engine::new(config);
+        let bucket = engine.bucket("x").unwrap();
+        assert_eq!(bucket.get(b"key_0").unwrap().value, b"value");
+        assert_eq!(bucket.get(key.as_bytes()), Err(EngineError::KeyNotFound));
+        engine.shutdown();
+    }
+
+    #[test]
+    fn test_pause() {
+        let dir = tempfile::tempdir().unwrap();
+        let engine = make_engine(&dir);
+        let bucket = engine.bucket("x").unwrap();
+        bucket.set(b"key", b"value", 0, 0).unwrap();
+        let files = || {
+            let mut files: Vec<(String, u64)> = std::fs::read_dir(dir.path().join("x"))
+                .unwrap()
+                .map(|entry| {
+                    let entry = entry.unwrap();
+                    let name = entry.file_name().to_string_lossy().into_owned();
+                    (name, entry.metadata().unwrap().len())
+                })
+                .collect();
+            files.sort();
+            files
+        };
+
+        // Nothing more is written while paused, not even vbucket states
+        assert!(bucket.pause());
+        assert!(!bucket.pause());
+        let paused = files();
+        let vbid = vbucket_for_key(b"key", 4);
+        bucket.set(b"key", b"value2", 0, 0).unwrap();
+        bucket.set_vbucket_state(vbid, State::Replica).unwrap();
+        bucket.flush();
+        bucket.persist();
+        assert_eq!(files(), paused);
+        let store = bucket.inner.get_store_by_shard(0);
+        assert_eq!(
+            store.get_cached_vb_state(vbid).unwrap().state,
+            State::Active
+        );
+
+        // Until it resumes
+        assert!(bucket.resume());
+        assert!(!bucket.resume());
+        assert_ne!(files(), paused);
+        assert_eq!(
+            store.get_cached_vb_state(vbid).unwrap().state,
+            State::Replica
+        );
+        engine.shutdown();
+    }
+
+    #[test]
+    fn test_dcp_producer() {
+        let dir = tempfile::tempdir().unwrap();
+        let engine = make_engine(&dir);
+        let bucket = engine.bucket("x").unwrap();
+        for i in 0..20 {
+            bucket
+                .set(format!("key{i}").as_bytes(), b"value", 0, 0)
+                .unwrap();
+        }
+        let producer = bucket.dcp_producer("drain", DcpOpenFlags::empty());
+        let request = StreamRequest {
+            flags: StreamRequestFlags::LATEST,
+            start_seqno: 0,
+            end_seqno: 0,
+            vb_uuid: 0,
+            snap_start_seqno: 0,
+            snap_end_seqno: 0,
+        };
+        for vbid in 0..4 {
+            producer
+                .stream_request(vbid as u32, Vbid::new(vbid), request, None)
+                .unwrap();
+        }
+        let (mut mutations, mut ended) = (0, 0);
+        while ended < 4 {
+            match producer.step().unwrap() {
+                Some(DcpResponse::Mutation { .. }) => mutations += 1,
+                Some(DcpResponse::StreamEnd { status, .. }) => {
+                    assert_eq!(status, EndStreamStatus::Ok);
+                    ended += 1;
+                }
+                _ => {}
+            }
+        }
+        assert_eq!(mutations, 20);
+    }
+}
@@ -0,0 +1,275 @@
+//! The audit log: a record of who did what, for security reviews. Each event
+//! is appended to the log file as a line of JSON, and once the file reaches
+//! the configured size it is renamed with the next number (audit.log.1,
+//! audit.log.2, ...) and a new one started. Events can be filtered out by
+//! id; the document events are off by default as there is one per access.
+//!
+//! The engine records its own bucket and document events. Authentication
+//! and authorization happen in the network front-end, which records those
+//! through [`crate::api::Engine::audit`].
+
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashSet,
+    fs::{File, OpenOptions},
+    io::{self, Write},
+    path::{Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+/// Identifies a kind of event, for filtering
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AuditEventId {
+    AuthenticationSucceeded,
+    AuthenticationFailed,
+    PrivilegeFailure,
+    BucketOpen,
+    BucketShutdown,
+    DocumentRead,
+    DocumentModify,
+    DocumentDelete,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct DocumentEvent {
+    pub bucket: String,
+    /// The key, with any bytes which aren't UTF-8 replaced
+    pub key: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(tag = "id", rename_all = "snake_case")]
+pub enum AuditEvent {
+    AuthenticationSucceeded {
+        user: String,
+        mechanism: String,
+        remote: String,
+    },
+    AuthenticationFailed {
+        user: String,
+        mechanism: String,
+        remote: String,
+        reason: String,
+    },
+    /// The user tried something their roles don't allow
+    PrivilegeFailure {
+        user: String,
+        bucket: Option<String>,
+        privilege: String,
+    },
+    BucketOpen {
+        bucket: String,
+    },
+    BucketShutdown {
+        bucket: String,
+    },
+    DocumentRead(DocumentEvent),
+    DocumentModify(DocumentEvent),
+    DocumentDelete(DocumentEvent),
+}
+
+impl AuditEvent {
+    pub fn id(&self) -> AuditEventId {
+        match self {
+            AuditEvent::AuthenticationSucceeded { .. } => AuditEventId::AuthenticationSucceeded,
+            AuditEvent::AuthenticationFailed { .. } => AuditEventId::AuthenticationFailed,
+            AuditEvent::PrivilegeFailure { .. } => AuditEventId::PrivilegeFailure,
+            AuditEvent::BucketOpen { .. } => AuditEventId::BucketOpen,
+            AuditEvent::BucketShutdown { .. } => AuditEventId::BucketShutdown,
+            AuditEvent::DocumentRead(_) => AuditEventId::DocumentRead,
+            AuditEvent::DocumentModify(_) => AuditEventId::DocumentModify,
+            AuditEvent::DocumentDelete(_) => AuditEventId::DocumentDelete,
+        }
+    }
+}
+
+/// A line of the log
+#[derive(Serialize)]
+struct AuditRecord<'a> {
+    /// Milliseconds since the Unix epoch
+    timestamp: u64,
+    #[serde(flatten)]
+    event: &'a AuditEvent,
+}
+
+struct AuditFile {
+    file: File,
+    size: u64,
+    /// Number the file will be given when it is rotated
+    next_number: u64,
+}
+
+pub struct AuditLog {
+    path: PathBuf,
+    rotate_size: u64,
+    disabled: HashSet<AuditEventId>,
+    file: Mutex<AuditFile>,
+}
+
+impl AuditLog {
+    /// Append to the log at path, rotating it once it reaches rotate_size
+    /// bytes. Events with the disabled ids aren't recorded.
+    pub fn open(
+        path: impl AsRef<Path>,
+        rotate_size: u64,
+        disabled: &[AuditEventId],
+    ) -> io::Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        let size = file.metadata()?.len();
+        let next_number = last_rotated_number(&path)? + 1;
+        Ok(Self {
+            path,
+            rotate_size,
+            disabled: disabled.iter().copied().collect(),
+            file: Mutex::new(AuditFile {
+                file,
+                size,
+                next_number,
+            }),
+        })
+    }
+
+    pub fn is_enabled(&self, id: AuditEventId) -> bool {
+        !self.disabled.contains(&id)
+    }
+
+    /// Append the event to the log, unless its id is filtered out. Events
+    /// are written straight to the file so none are lost if the process
+    /// dies.
+    pub fn record(&self, event: AuditEvent) {
+        if !self.is_enabled(event.id()) {
+            return;
+        }
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_millis() as u64;
+        let mut line = serde_json::to_vec(&AuditRecord {
+            timestamp,
+            event: &event,
+        })
+        .unwrap();
+        line.push(b'\n');
+
+        let mut file = self.file.lock();
+        if let Err(e) = self.write(&mut file, &line) {
+            println!("Failed to write audit event {:?}: {e}", event.id());
+        }
+    }
+
+    fn write(&self, file: &mut AuditFile, line: &[u8]) -> io::Result<()> {
+        if file.size > 0 && file.size + line.len() as u64 > self.rotate_size {
+            self.rotate(file)?;
+        }
+        file.file.write_all(line)?;
+        file.size += line.len() as u64;
+        Ok(())
+    }
+
+    fn rotate(&self, file: &mut AuditFile) -> io::Result<()> {
+        std::fs::rename(&self.path, rotated_path(&self.path, file.next_number))?;
+        file.next_number += 1;
+        file.file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?;
+        file.size = 0;
+        Ok(())
+    }
+}
+
+fn rotated_path(path: &Path, number: u64) -> PathBuf {
+    let mut name = path.file_name().unwrap_or_default().to_os_string();
+    name.push(format!(".{number}"));
+    path.with_file_name(name)
+}
+
+/// The highest number of the log's rotated files, 0 if there are none
+fn last_rotated_number(path: &Path) -> io::Result<u64> {
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    let prefix = format!(
+        "{}.",
+        path.file_name().unwrap_or_default().to_string_lossy()
+    );
+    let mut last = 0;
+    for entry in std::fs::read_dir(dir)? {
+        let name = entry?.file_name();
+        let number = name
+            .to_str()
+            .and_then(|name| name.strip_prefix(&prefix))
+            .and_then(|number| number.parse().ok());
+        last = last.max(number.unwrap_or(0));
+    }
+    Ok(last)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn read_ids(path: &Path) -> Vec<String> {
+        std::fs::read_to_string(path)
+            .unwrap()
+            .lines()
+            .map(|line| {
+                let record: serde_json::Value = serde_json::from_str(line).unwrap();
+                assert!(record["timestamp"].as_u64().unwrap() > 0);
+                record["id"].as_str().unwrap().to_string()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_audit_log() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("audit.log");
+        let log = AuditLog::open(&path, 250, &[AuditEventId::DocumentRead]).unwrap();
+        let document = DocumentEvent {
+            bucket: "default".to_string(),
+            key: "key".to_string(),
+        };
+        log.record(AuditEvent::AuthenticationFailed {
+            user: "admin".to_string(),
+            mechanism: "PLAIN".to_string(),
+            remote: "127.0.0.1:50000".to_string(),
+            reason: "unknown user".to_string(),
+        });
+        log.record(AuditEvent::DocumentRead(document.clone()));
+        log.record(AuditEvent::DocumentModify(document.clone()));
+        assert_eq!(
+            read_ids(&path),
+            ["authentication_failed", "document_modify"]
+        );
+
+        let line = std::fs::read_to_string(&path).unwrap();
+        let record: serde_json::Value = serde_json::from_str(line.lines().nth(1).unwrap()).unwrap();
+        assert_eq!(record["bucket"], "default");
+        assert_eq!(record["key"], "key");
+
+        // The log is past 250 bytes with the next event, so is rotated
+        log.record(AuditEvent::DocumentDelete(document.clone()));
+        assert_eq!(read_ids(&path), ["document_delete"]);
+        assert_eq!(
+            read_ids(&dir.path().join("audit.log.1")),
+            ["authentication_failed", "document_modify"]
+        );
+
+        // Reopening appends, and carries on the numbering
+        drop(log);
+        let log = AuditLog::open(&path, 0, &[]).unwrap();
+        log.record(AuditEvent::BucketShutdown {
+            bucket: "default".to_string(),
+        });
+        assert_eq!(read_ids(&path), ["bucket_shutdown"]);
+        assert_eq!(
+            read_ids(&dir.path().join("audit.log.2")),
+            ["document_delete"]
+        );
+    }
+}
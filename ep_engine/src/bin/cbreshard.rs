@@ -0,0 +1,37 @@
+//! Change the number of vbuckets of a stopped bucket's data directory.
+//!
+//! `cbreshard <source dir> <source vbuckets> <destination dir> <destination vbuckets>`
+//! rehashes every key into a new directory, then reads it back to check
+//! nothing was lost. The source directory is left as it was.
+
+use std::process::exit;
+
+use ep_engine::reshard::reshard;
+
+fn main() {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let [src_dir, src_vbuckets, dest_dir, dest_vbuckets] = &args[..] else {
+        println!(
+            "Usage: cbreshard <source dir> <source vbuckets> <destination dir> <destination vbuckets>"
+        );
+        exit(1);
+    };
+    let parse = |count: &str| {
+        count.parse::<u16>().unwrap_or_else(|_| {
+            println!("Invalid vbucket count {count}");
+            exit(1);
+        })
+    };
+    let (src_vbuckets, dest_vbuckets) = (parse(src_vbuckets), parse(dest_vbuckets));
+
+    match reshard(src_dir, src_vbuckets, dest_dir, dest_vbuckets) {
+        Ok(summary) => println!(
+            "Resharded {src_dir} from {src_vbuckets} to {dest_vbuckets} vbuckets into {dest_dir}: {} items, {} collection events per vbucket",
+            summary.items, summary.collection_events
+        ),
+        Err(e) => {
+            println!("Failed to reshard {src_dir}: {e}");
+            exit(1);
+        }
+    }
+}
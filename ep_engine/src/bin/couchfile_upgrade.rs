@@ -0,0 +1,61 @@
+//! Upgrade vbucket files written before collections, so the engine can
+//! read them. Their keys are moved into the default collection.
+//!
+//! `couchfile_upgrade <data dir>` upgrades each vbucket in a stopped
+//! bucket's data directory, replacing its file.
+//!
+//! `couchfile_upgrade <input file> <output file>` writes the upgrade of a
+//! single file to a new one.
+
+use std::{path::Path, process::exit};
+
+use ep_engine::namespace_upgrade::{upgrade_data_dir, upgrade_file};
+
+fn main() {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    match &args[..] {
+        [dir] if Path::new(dir).is_dir() => {
+            let upgraded = upgrade_data_dir(Path::new(dir)).unwrap_or_else(|e| {
+                println!("Failed to list {dir}: {e}");
+                exit(1);
+            });
+            let mut failed = false;
+            for (vbid, result) in upgraded {
+                match result {
+                    Ok(summary) => println!(
+                        "Upgraded vbucket {vbid}: {} documents, high seqno {}",
+                        summary.documents, summary.high_seqno
+                    ),
+                    Err(e) => {
+                        println!("Failed to upgrade vbucket {vbid}: {e}");
+                        failed = true;
+                    }
+                }
+            }
+            if failed {
+                exit(1);
+            }
+        }
+        [input, output] => {
+            if Path::new(output).exists() {
+                println!("{output} already exists");
+                exit(1);
+            }
+            match upgrade_file(Path::new(input), Path::new(output)) {
+                Ok(summary) => println!(
+                    "Upgraded {input} into {output}: {} documents, high seqno {}",
+                    summary.documents, summary.high_seqno
+                ),
+                Err(e) => {
+                    let _ = std::fs::remove_file(output);
+                    println!("Failed to upgrade {input}: {e}");
+                    exit(1);
+                }
+            }
+        }
+        _ => {
+            println!("Usage: couchfile_upgrade <data dir> | <input file> <output file>");
+            exit(1);
+        }
+    }
+}
@@ -0,0 +1,151 @@
+//! Workload for the crash recovery tests, which kill it at random points.
+//!
+//! `crash_workload run <dir> <seed> <run>` warms up from the directory then
+//! writes random keys until killed, flushing after each batch. Once a batch
+//! is persisted it prints `ack <key> <run> <counter>` for each write and
+//! `persisted <vbid> <high seqno>` for each vbucket.
+//!
+//! `crash_workload dump <dir>` prints `vb <vbid> <high seqno>` for each
+//! persisted vbucket and `item <vbid> <seqno> <key> <value>` for each item.
+
+use std::process::exit;
+
+use ep_engine::{
+    collections,
+    ep_bucket::EPBucket,
+    failover_table::FailoverTable,
+    item::{DEFAULT_MAX_ITEM_SIZE, DEFAULT_MAX_KEY_SIZE},
+    kv_store::{CouchKVStore, CouchKVStoreConfig, KVStore, ScanErrorPolicy, ValueFilter},
+    vbucket::{State, Vbid},
+    warmup::Warmup,
+    Config,
+};
+use rand::{Rng, SeedableRng};
+
+const MAX_VBUCKETS: u16 = 4;
+const NUM_KEYS: usize = 100;
+
+fn config(dir: &str) -> Config {
+    Config {
+        max_vbuckets: MAX_VBUCKETS,
+        max_shards: 1,
+        dbname: dir.to_string(),
+        ..Default::default()
+    }
+}
+
+fn run(dir: &str, seed: u64, run: u64) {
+    let config = config(dir);
+    let bucket = EPBucket::new(config.clone());
+    Warmup::new(bucket.clone(), config).warmup();
+    for vbid in 0..MAX_VBUCKETS {
+        let vbid = Vbid::new(vbid);
+        if bucket.get_vbucket(vbid).is_none() {
+            bucket.vbucket_map.add_bucket(bucket.make_vbucket(
+                vbid,
+                State::Active,
+                FailoverTable::new_empty(25),
+                0,
+                0,
+            ));
+        }
+    }
+
+    let mut rng = rand::rngs::StdRng::seed_from_u64(seed);
+    let mut counter = 0u64;
+    loop {
+        let batch_size = rng.gen_range(1..20);
+        let mut batch = Vec::with_capacity(batch_size);
+        for _ in 0..batch_size {
+            counter += 1;
+            let key = format!("key_{}", rng.gen_range(0..NUM_KEYS));
+            let value = format!("{run}:{counter}");
+            // Values vary in size so commits cross block boundaries
+            let padding = "x".repeat(rng.gen_range(0..5000));
+            bucket
+                .set(
+                    key.clone().into_bytes(),
+                    format!("{value}:{padding}").into_bytes(),
+                    0,
+                    0,
+                )
+                .unwrap();
+            batch.push((key, counter));
+        }
+
+        for vbid in bucket.vbucket_map.get_buckets() {
+            bucket.flush_vbucket(vbid);
+        }
+        for (key, counter) in batch {
+            println!("ack {key} {run} {counter}");
+        }
+        for vbid in bucket.vbucket_map.get_buckets() {
+            let store = bucket.vbucket_map.get_shard_by_vb_id(vbid).store();
+            if let Some(state) = store.get_cached_vb_state(vbid) {
+                println!("persisted {} {}", u16::from(vbid), state.high_seqno);
+            }
+        }
+    }
+}
+
+fn dump(dir: &str) {
+    let store = CouchKVStore::new(CouchKVStoreConfig {
+        max_vbuckets: MAX_VBUCKETS,
+        db_name: dir.to_string(),
+        data_paths: Vec::new(),
+        max_shards: 1,
+        shard_id: 0,
+        history_retention: false,
+        max_key_size: DEFAULT_MAX_KEY_SIZE,
+        max_item_size: DEFAULT_MAX_ITEM_SIZE,
+        pitr_max_history_age: None,
+        sync_policy: couchstore::SyncPolicy::EveryCommit,
+        dsync: false,
+        btree_tuning: couchstore::BtreeTuning::default(),
+        front_coded_keys: false,
+        compaction_direct_io: false,
+    });
+    for vbid in 0..MAX_VBUCKETS {
+        let vbid = Vbid::new(vbid);
+        let Some(state) = store.get_cached_vb_state(vbid) else {
+            continue;
+        };
+        println!("vb {} {}", u16::from(vbid), state.high_seqno);
+        store.scan(
+            vbid,
+            0,
+            ValueFilter::ValuesDecompressed,
+            ScanErrorPolicy::Abort,
+            &mut |item| {
+                let key = collections::split_key(&item.key).map_or(&item.key[..], |(_, key)| key);
+                let value = item.value.as_deref().unwrap_or_default();
+                // Drop the padding
+                let value = String::from_utf8_lossy(value);
+                let value = value
+                    .rsplit_once(':')
+                    .map_or(&value[..], |(value, _)| value);
+                println!(
+                    "item {} {} {} {}",
+                    u16::from(vbid),
+                    item.by_seqno,
+                    String::from_utf8_lossy(key),
+                    value
+                );
+            },
+        );
+    }
+}
+
+fn main() {
+    let args: Vec<String> = std::env::args().collect();
+    match args.get(1).map(String::as_str) {
+        Some("run") if args.len() == 5 => {
+            run(&args[2], args[3].parse().unwrap(), args[4].parse().unwrap())
+        }
+        Some("dump") if args.len() == 3 => dump(&args[2]),
+        _ => {
+            println!("Usage: {} <run <dir> <seed> <run> | dump <dir>>", args[0]);
+            exit(1);
+        }
+    }
+}
@@ -0,0 +1,84 @@
+//! Generate a synthetic travel-sample-like dataset into a new data
+//! directory, for benchmarks and tests.
+//!
+//! `gen_dataset <dir> [options]` writes the documents straight into the
+//! directory's vbucket files, which a bucket warms up from.
+
+use std::{ops::Range, process::exit, str::FromStr};
+
+use ep_engine::gen_dataset::{generate, DatasetSpec};
+
+const USAGE: &str = "Usage: gen_dataset <dir> [options]
+
+Options:
+  --documents <count>       Number of documents (default: 31591)
+  --vbuckets <count>        Number of vbuckets (default: 1024)
+  --collections             Put each type of document in its own collection
+  --binary-fraction <0-1>   Fraction of documents with binary values (default: 0)
+  --binary-size <min-max>   Sizes of the binary values in bytes (default: 16-4096)
+  --ttl-fraction <0-1>      Fraction of documents which expire (default: 0)
+  --ttl <min-max>           Seconds until they expire (default: 3600-86400)
+  --seed <seed>             Seed for the documents (default: 0)";
+
+fn parse_range<T: FromStr>(value: &str) -> Option<Range<T>> {
+    let (min, max) = value.split_once('-')?;
+    Some(min.parse().ok()?..max.parse().ok()?)
+}
+
+fn parse_args() -> Result<(String, DatasetSpec), String> {
+    let mut args = std::env::args().skip(1);
+    let mut positional = Vec::new();
+    let mut spec = DatasetSpec::default();
+
+    while let Some(arg) = args.next() {
+        if !arg.starts_with("--") {
+            positional.push(arg);
+            continue;
+        }
+        if arg == "--collections" {
+            spec.collections = true;
+            continue;
+        }
+        let value = args
+            .next()
+            .ok_or_else(|| format!("Missing value for {arg}"))?;
+        let invalid = || format!("Invalid value for {arg}: {value}");
+        match arg.as_str() {
+            "--documents" => spec.documents = value.parse().map_err(|_| invalid())?,
+            "--vbuckets" => spec.num_vbuckets = value.parse().map_err(|_| invalid())?,
+            "--binary-fraction" => spec.binary_fraction = value.parse().map_err(|_| invalid())?,
+            "--binary-size" => spec.binary_size = parse_range(&value).ok_or_else(invalid)?,
+            "--ttl-fraction" => spec.ttl_fraction = value.parse().map_err(|_| invalid())?,
+            "--ttl" => spec.ttl = parse_range(&value).ok_or_else(invalid)?,
+            "--seed" => spec.seed = value.parse().map_err(|_| invalid())?,
+            _ => return Err(format!("Unknown option {arg}")),
+        }
+    }
+
+    match <[String; 1]>::try_from(positional) {
+        Ok([dir]) => Ok((dir, spec)),
+        Err(_) => Err(USAGE.to_string()),
+    }
+}
+
+fn main() {
+    let (dir, spec) = parse_args().unwrap_or_else(|e| {
+        println!("{e}");
+        exit(1);
+    });
+    let start = std::time::Instant::now();
+    match generate(&dir, &spec) {
+        Ok(summary) => println!(
+            "Generated {} documents ({} binary, {} with a TTL) in {} collections into {dir} in {:.1}s",
+            summary.documents,
+            summary.binary,
+            summary.with_ttl,
+            summary.collections + 1,
+            start.elapsed().as_secs_f64()
+        ),
+        Err(e) => {
+            println!("Failed to generate into {dir}: {e}");
+            exit(1);
+        }
+    }
+}
@@ -0,0 +1,670 @@
+use crate::{item::Item, memory_tracker::MemoryDomain, stats::EPStatsPtr};
+use parking_lot::Mutex;
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::Arc,
+};
+
+/// Name of the cursor used by the flusher
+pub const PERSISTENCE_CURSOR: &str = "persistence";
+
+pub type QueuedItem = Arc<Item>;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CheckpointState {
+    /// Items are being added to the checkpoint
+    Open,
+    /// No more items will be added to the checkpoint
+    Closed,
+}
+
+/// A range of mutations in seqno order, forming one snapshot for
+/// replication and persistence.
+#[derive(Debug)]
+pub struct Checkpoint {
+    pub id: u64,
+    pub snap_start: u64,
+    pub snap_end: u64,
+    state: CheckpointState,
+    /// Items that have been expelled from the start of the checkpoint. Cursor
+    /// positions count these so they remain valid after an expel.
+    num_expelled: usize,
+    items: VecDeque<QueuedItem>,
+    mem_usage: usize,
+}
+
+impl Checkpoint {
+    fn new(id: u64, snap_start: u64) -> Self {
+        Self {
+            id,
+            snap_start,
+            snap_end: snap_start,
+            state: CheckpointState::Open,
+            num_expelled: 0,
+            items: VecDeque::new(),
+            mem_usage: 0,
+        }
+    }
+
+    /// Position one past the last item in the checkpoint
+    fn end_position(&self) -> usize {
+        self.num_expelled + self.items.len()
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct CheckpointCursor {
+    checkpoint_id: u64,
+    /// Position of the next item to read in the checkpoint
+    position: usize,
+    /// Only replication cursors may be dropped to free memory, the
+    /// persistence cursor must always see every item.
+    droppable: bool,
+    /// The consumer has every item up to this seqno
+    read_seqno: u64,
+}
+
+/// Where a cursor has read up to
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CursorPosition {
+    pub checkpoint_id: u64,
+    /// Position of the next item to read in the checkpoint
+    pub position: usize,
+    /// The consumer has every item up to this seqno
+    pub read_seqno: u64,
+}
+
+/// Items read by a cursor together with the snapshot they belong to
+#[derive(Debug, Default)]
+pub struct ItemsForCursor {
+    pub items: Vec<QueuedItem>,
+    pub snap_start: u64,
+    pub snap_end: u64,
+    /// Checkpoint the last item was read from
+    pub checkpoint_id: u64,
+    /// Where the cursor moves to once the items are consumed
+    end: Option<CheckpointCursor>,
+}
+
+/// One of a key's mutations still queued in a checkpoint
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize)]
+pub struct QueuedMutation {
+    pub checkpoint_id: u64,
+    pub by_seqno: u64,
+    pub cas: u64,
+    pub deleted: bool,
+    /// The persistence cursor has read it, so it has been flushed
+    pub persisted: bool,
+}
+
+/// Where a newly registered cursor will start reading
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CursorRegistration {
+    /// Items between the requested start and next_seqno are no longer in
+    /// memory and must be read from disk
+    pub try_backfill: bool,
+    /// Seqno of the first item the cursor will read
+    pub next_seqno: u64,
+}
+
+/// Position of a vbucket in its replication stream
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SnapshotInfo {
+    /// Last seqno received
+    pub start: u64,
+    pub snap_start: u64,
+    pub snap_end: u64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CheckpointConfig {
+    /// Maximum number of items in a checkpoint before it is closed
+    pub max_items: usize,
+}
+
+#[derive(Debug)]
+struct State {
+    checkpoints: VecDeque<Checkpoint>,
+    cursors: HashMap<String, CheckpointCursor>,
+    next_checkpoint_id: u64,
+    last_seqno: u64,
+}
+
+/// Per vbucket queue of mutations which haven't yet been persisted or
+/// replicated. Consumers (the flusher and DCP streams) read items through
+/// named cursors.
+#[derive(Debug)]
+pub struct CheckpointManager {
+    state: Mutex<State>,
+    stats: EPStatsPtr,
+    config: CheckpointConfig,
+}
+
+impl CheckpointManager {
+    pub fn new(stats: EPStatsPtr, last_seqno: u64, config: CheckpointConfig) -> Self {
+        let mut checkpoints = VecDeque::new();
+        checkpoints.push_back(Checkpoint::new(1, last_seqno));
+
+        let mut cursors = HashMap::new();
+        cursors.insert(
+            PERSISTENCE_CURSOR.to_string(),
+            CheckpointCursor {
+                checkpoint_id: 1,
+                position: 0,
+                droppable: false,
+                read_seqno: last_seqno,
+            },
+        );
+
+        Self {
+            state: Mutex::new(State {
+                checkpoints,
+                cursors,
+                next_checkpoint_id: 2,
+                last_seqno,
+            }),
+            stats,
+            config,
+        }
+    }
+
+    /// Number the checkpoints on from a previous run's, whose last
+    /// checkpoint was checkpoint_id. Only a new manager, with nothing
+    /// queued, can be renumbered.
+    pub fn resume_from(&self, checkpoint_id: u64) {
+        let mut state = self.state.lock();
+        assert!(state.checkpoints.len() == 1 && state.checkpoints[0].items.is_empty());
+        let id = checkpoint_id + 1;
+        state.checkpoints[0].id = id;
+        state.next_checkpoint_id = id + 1;
+        for cursor in state.cursors.values_mut() {
+            cursor.checkpoint_id = id;
+        }
+    }
+
+    /// Add a mutation to the open checkpoint
+    pub fn queue_dirty(&self, item: QueuedItem) {
+        self.queue_dirty_batch(vec![item]);
+    }
+
+    /// Add mutations to the open checkpoint together, so a cursor reads all
+    /// of them or none. A new checkpoint is opened first if they wouldn't
+    /// fit in this one, keeping them in one snapshot.
+    pub fn queue_dirty_batch(&self, items: Vec<QueuedItem>) {
+        let mut state = self.state.lock();
+
+        let needs_new_checkpoint = state.checkpoints.back().is_some_and(|checkpoint| {
+            let len = checkpoint.items.len();
+            len >= self.config.max_items || (len > 0 && len + items.len() > self.config.max_items)
+        });
+        if needs_new_checkpoint {
+            Self::add_open_checkpoint(&mut state);
+        }
+
+        for item in items {
+            let size = item_size(&item);
+            state.last_seqno = item.by_seqno;
+            let checkpoint = state.checkpoints.back_mut().unwrap();
+            // The first item of a checkpoint starts its snapshot, unless the
+            // checkpoint was created for a snapshot which already covers it
+            if checkpoint.end_position() == 0 && item.by_seqno > checkpoint.snap_end {
+                checkpoint.snap_start = item.by_seqno;
+            }
+            checkpoint.snap_end = checkpoint.snap_end.max(item.by_seqno);
+            checkpoint.mem_usage += size;
+            checkpoint.items.push_back(item);
+
+            self.stats
+                .memory
+                .mem_allocated(MemoryDomain::Checkpoint, size);
+        }
+    }
+
+    /// Close the open checkpoint (if it has any items) and open a new one
+    pub fn create_new_checkpoint(&self) {
+        let mut state = self.state.lock();
+        let open = state.checkpoints.back().unwrap();
+        if open.end_position() > 0 {
+            Self::add_open_checkpoint(&mut state);
+        }
+    }
+
+    fn add_open_checkpoint(state: &mut State) -> &mut Checkpoint {
+        let id = state.next_checkpoint_id;
+        state.next_checkpoint_id += 1;
+        let snap_start = state.last_seqno + 1;
+        let previous = state.checkpoints.back_mut().unwrap();
+        previous.state = CheckpointState::Closed;
+        // A snapshot split across checkpoints keeps its end seqno
+        let snap_end = previous.snap_end;
+        let mut checkpoint = Checkpoint::new(id, snap_start);
+        checkpoint.snap_end = snap_end;
+        state.checkpoints.push_back(checkpoint);
+        state.checkpoints.back_mut().unwrap()
+    }
+
+    /// Start a new snapshot received from a replication stream. The items
+    /// that follow are queued into a checkpoint covering the whole snapshot
+    /// range, even if only part of it has been received.
+    pub fn create_snapshot(&self, snap_start: u64, snap_end: u64) {
+        assert!(snap_start <= snap_end);
+        let mut state = self.state.lock();
+        let open = state.checkpoints.back_mut().unwrap();
+        let checkpoint = if open.end_position() == 0 {
+            open
+        } else {
+            Self::add_open_checkpoint(&mut state)
+        };
+        checkpoint.snap_start = snap_start;
+        checkpoint.snap_end = snap_end;
+    }
+
+    /// Where a replication stream into this vbucket should resume from: the
+    /// last seqno received and the snapshot it belongs to. If that snapshot
+    /// was fully received the stream resumes from a snapshot boundary.
+    pub fn get_snapshot_info(&self) -> SnapshotInfo {
+        let state = self.state.lock();
+        let open = state.checkpoints.back().unwrap();
+        let start = state.last_seqno;
+        if start >= open.snap_end {
+            SnapshotInfo {
+                start,
+                snap_start: start,
+                snap_end: start,
+            }
+        } else {
+            SnapshotInfo {
+                start,
+                snap_start: open.snap_start,
+                snap_end: open.snap_end,
+            }
+        }
+    }
+
+    pub fn get_open_checkpoint_id(&self) -> u64 {
+        self.state.lock().checkpoints.back().unwrap().id
+    }
+
+    pub fn get_num_checkpoints(&self) -> usize {
+        self.state.lock().checkpoints.len()
+    }
+
+    /// Total number of items held in memory by all checkpoints
+    pub fn get_num_items(&self) -> usize {
+        let state = self.state.lock();
+        state.checkpoints.iter().map(|c| c.items.len()).sum()
+    }
+
+    pub fn get_memory_usage(&self) -> usize {
+        let state = self.state.lock();
+        state.checkpoints.iter().map(|c| c.mem_usage).sum()
+    }
+
+    /// Register a droppable (replication) cursor which will read items with a
+    /// seqno greater than start_seqno. If some of those items are no longer
+    /// in memory the caller must backfill them from disk first.
+    pub fn register_cursor(&self, name: &str, start_seqno: u64) -> CursorRegistration {
+        let mut state = self.state.lock();
+
+        let lowest_available = Self::lowest_available_seqno(&state);
+        let try_backfill = start_seqno + 1 < lowest_available;
+
+        // Place the cursor before the first item it hasn't seen
+        let cursor = state
+            .checkpoints
+            .iter()
+            .find(|checkpoint| {
+                checkpoint
+                    .items
+                    .back()
+                    .is_some_and(|item| item.by_seqno > start_seqno)
+            })
+            .map(|checkpoint| CheckpointCursor {
+                checkpoint_id: checkpoint.id,
+                position: checkpoint.num_expelled
+                    + checkpoint
+                        .items
+                        .iter()
+                        .take_while(|item| item.by_seqno <= start_seqno)
+                        .count(),
+                droppable: true,
+                read_seqno: start_seqno,
+            })
+            .unwrap_or_else(|| {
+                let open = state.checkpoints.back().unwrap();
+                CheckpointCursor {
+                    checkpoint_id: open.id,
+                    position: open.end_position(),
+                    droppable: true,
+                    read_seqno: start_seqno,
+                }
+            });
+        state.cursors.insert(name.to_string(), cursor);
+
+        CursorRegistration {
+            try_backfill,
+            next_seqno: lowest_available.max(start_seqno + 1),
+        }
+    }
+
+    /// The lowest seqno which can still be read from the checkpoints
+    fn lowest_available_seqno(state: &State) -> u64 {
+        state
+            .checkpoints
+            .iter()
+            .find_map(|checkpoint| checkpoint.items.front())
+            .map_or(state.last_seqno + 1, |item| item.by_seqno)
+    }
+
+    pub fn remove_cursor(&self, name: &str) -> bool {
+        self.state.lock().cursors.remove(name).is_some()
+    }
+
+    pub fn has_cursor(&self, name: &str) -> bool {
+        self.state.lock().cursors.contains_key(name)
+    }
+
+    /// The lowest seqno read by a replication cursor, None without any
+    pub fn get_min_replication_cursor_seqno(&self) -> Option<u64> {
+        let state = self.state.lock();
+        state
+            .cursors
+            .values()
+            .filter(|cursor| cursor.droppable)
+            .map(|cursor| cursor.read_seqno)
+            .min()
+    }
+
+    pub fn get_num_cursors(&self) -> usize {
+        self.state.lock().cursors.len()
+    }
+
+    pub fn get_cursor_position(&self, name: &str) -> Option<CursorPosition> {
+        let state = self.state.lock();
+        let cursor = state.cursors.get(name)?;
+        Some(CursorPosition {
+            checkpoint_id: cursor.checkpoint_id,
+            position: cursor.position,
+            read_seqno: cursor.read_seqno,
+        })
+    }
+
+    /// Number of items the cursor has yet to read
+    pub fn get_num_items_for_cursor(&self, name: &str) -> Option<usize> {
+        let state = self.state.lock();
+        let cursor = state.cursors.get(name)?;
+        let mut remaining = 0;
+        for checkpoint in &state.checkpoints {
+            if checkpoint.id < cursor.checkpoint_id {
+                continue;
+            }
+            remaining += if checkpoint.id == cursor.checkpoint_id {
+                checkpoint.end_position() - cursor.position
+            } else {
+                checkpoint.items.len()
+            };
+        }
+        Some(remaining)
+    }
+
+    /// The key's mutations still in the checkpoints, oldest first
+    pub fn find_key(&self, key: &[u8]) -> Vec<QueuedMutation> {
+        let state = self.state.lock();
+        let persistence = state.cursors.get(PERSISTENCE_CURSOR);
+        let mut found = Vec::new();
+        for checkpoint in &state.checkpoints {
+            for (i, item) in checkpoint.items.iter().enumerate() {
+                if item.key != key {
+                    continue;
+                }
+                let position = checkpoint.num_expelled + i;
+                let persisted = persistence.is_some_and(|cursor| {
+                    checkpoint.id < cursor.checkpoint_id
+                        || (checkpoint.id == cursor.checkpoint_id && position < cursor.position)
+                });
+                found.push(QueuedMutation {
+                    checkpoint_id: checkpoint.id,
+                    by_seqno: item.by_seqno,
+                    cas: item.cas,
+                    deleted: item.value.is_none(),
+                    persisted,
+                });
+            }
+        }
+        found
+    }
+
+    /// Read all items after the cursor, moving the cursor to the end of the
+    /// open checkpoint. Returns None if the cursor doesn't exist, e.g.
+    /// because it was dropped to free memory.
+    pub fn get_items_for_cursor(&self, name: &str) -> Option<ItemsForCursor> {
+        let items = self.peek_items_for_cursor(name)?;
+        self.advance_cursor(name, &items);
+        Some(items)
+    }
+
+    /// Read all items after the cursor without moving it, so a consumer
+    /// which may fail to handle them (the flusher failing to commit) reads
+    /// them again next time. Call advance_cursor once they are consumed.
+    pub fn peek_items_for_cursor(&self, name: &str) -> Option<ItemsForCursor> {
+        let state = self.state.lock();
+        let cursor = *state.cursors.get(name)?;
+
+        let mut result = ItemsForCursor::default();
+        let mut first = true;
+        for checkpoint in &state.checkpoints {
+            if checkpoint.id < cursor.checkpoint_id {
+                continue;
+            }
+            let skip = if checkpoint.id == cursor.checkpoint_id {
+                cursor.position - checkpoint.num_expelled
+            } else {
+                0
+            };
+            if skip == checkpoint.items.len() {
+                continue;
+            }
+            if first {
+                result.snap_start = checkpoint.snap_start;
+                first = false;
+            }
+            result.snap_end = checkpoint.snap_end;
+            result.checkpoint_id = checkpoint.id;
+            result
+                .items
+                .extend(checkpoint.items.iter().skip(skip).cloned());
+        }
+
+        let open = state.checkpoints.back().unwrap();
+        result.end = Some(CheckpointCursor {
+            checkpoint_id: open.id,
+            position: open.end_position(),
+            droppable: cursor.droppable,
+            read_seqno: result
+                .items
+                .last()
+                .map_or(cursor.read_seqno, |item| item.by_seqno),
+        });
+
+        Some(result)
+    }
+
+    /// Move the cursor past items it peeked. Does nothing if the cursor has
+    /// since been removed.
+    pub fn advance_cursor(&self, name: &str, items: &ItemsForCursor) {
+        let mut state = self.state.lock();
+        if let (Some(cursor), Some(end)) = (state.cursors.get_mut(name), items.end) {
+            *cursor = end;
+        }
+    }
+
+    /// Remove closed checkpoints which no cursor needs any more. Returns the
+    /// number of bytes freed.
+    pub fn remove_closed_unref_checkpoints(&self) -> usize {
+        let mut state = self.state.lock();
+        let lowest_cursor = state
+            .cursors
+            .values()
+            .map(|cursor| cursor.checkpoint_id)
+            .min();
+
+        let mut freed = 0;
+        while let Some(oldest) = state.checkpoints.front() {
+            if oldest.state == CheckpointState::Open
+                || lowest_cursor.is_some_and(|lowest| lowest <= oldest.id)
+            {
+                break;
+            }
+            let checkpoint = state.checkpoints.pop_front().unwrap();
+            freed += checkpoint.mem_usage;
+        }
+
+        self.stats
+            .memory
+            .mem_deallocated(MemoryDomain::Checkpoint, freed);
+        freed
+    }
+
+    /// Expel items from the oldest checkpoint which every cursor has already
+    /// read. Returns the number of items expelled and the bytes freed.
+    pub fn expel_unreferenced_items(&self) -> (usize, usize) {
+        let mut state = self.state.lock();
+        let oldest_id = state.checkpoints.front().unwrap().id;
+
+        // Items before the lowest cursor in the oldest checkpoint can go. If
+        // no cursor is in the oldest checkpoint then every item can go.
+        let lowest_position = state
+            .cursors
+            .values()
+            .filter(|cursor| cursor.checkpoint_id == oldest_id)
+            .map(|cursor| cursor.position)
+            .min();
+
+        let oldest = state.checkpoints.front_mut().unwrap();
+        let expel_to = lowest_position.unwrap_or(oldest.end_position());
+        let mut expelled = 0;
+        let mut freed = 0;
+        while oldest.num_expelled < expel_to {
+            let item = oldest.items.pop_front().unwrap();
+            oldest.num_expelled += 1;
+            freed += item_size(&item);
+            expelled += 1;
+        }
+        oldest.mem_usage -= freed;
+
+        self.stats
+            .memory
+            .mem_deallocated(MemoryDomain::Checkpoint, freed);
+        (expelled, freed)
+    }
+
+    /// Drop the replication cursors in the oldest checkpoint that still has
+    /// cursors, so that checkpoint can be released. The streams owning them
+    /// will have to backfill from disk. Returns the names of the dropped
+    /// cursors.
+    pub fn drop_slowest_cursors(&self) -> Vec<String> {
+        let mut state = self.state.lock();
+        let slowest = state
+            .cursors
+            .values()
+            .filter(|cursor| cursor.droppable)
+            .map(|cursor| cursor.checkpoint_id)
+            .min();
+        let Some(slowest) = slowest else {
+            return Vec::new();
+        };
+
+        let dropped: Vec<String> = state
+            .cursors
+            .iter()
+            .filter(|(_, cursor)| cursor.droppable && cursor.checkpoint_id == slowest)
+            .map(|(name, _)| name.clone())
+            .collect();
+        for name in &dropped {
+            state.cursors.remove(name);
+        }
+        dropped
+    }
+}
+
+impl Drop for CheckpointManager {
+    fn drop(&mut self) {
+        let freed = self.get_memory_usage();
+        self.stats
+            .memory
+            .mem_deallocated(MemoryDomain::Checkpoint, freed);
+    }
+}
+
+fn item_size(item: &Item) -> usize {
+    std::mem::size_of::<Item>() + item.key.len() + item.value.as_ref().map_or(0, |v| v.len())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{
+        item::{Datatype, DeleteSource},
+        stats::EPStats,
+        Config,
+    };
+
+    fn make_item(seqno: u64) -> QueuedItem {
+        Arc::new(Item {
+            key: format!("key_{seqno}").into_bytes(),
+            value: Some(vec![0; 100]),
+            cas: seqno,
+            expiry_time: 0,
+            flags: 0,
+            by_seqno: seqno,
+            rev_seqno: 1,
+            delete_source: DeleteSource::Explicit,
+            datatype: Datatype::empty(),
+        })
+    }
+
+    #[test]
+    fn test_expel_and_cursor_drop() {
+        let stats = EPStatsPtr::new(EPStats::new(&Config::default()));
+        let manager = CheckpointManager::new(stats.clone(), 0, CheckpointConfig { max_items: 5 });
+        assert!(!manager.register_cursor("replication", 0).try_backfill);
+
+        for seqno in 1..=8 {
+            manager.queue_dirty(make_item(seqno));
+        }
+        assert_eq!(manager.get_num_checkpoints(), 2);
+        assert_eq!(
+            stats.memory.domain_used(MemoryDomain::Checkpoint),
+            manager.get_memory_usage()
+        );
+
+        // Persist everything, the replication cursor still holds the first
+        // checkpoint so nothing can be removed or expelled
+        let items = manager.get_items_for_cursor(PERSISTENCE_CURSOR).unwrap();
+        assert_eq!(items.items.len(), 8);
+        assert_eq!((items.snap_start, items.snap_end), (1, 8));
+        assert_eq!(manager.remove_closed_unref_checkpoints(), 0);
+        assert_eq!(manager.expel_unreferenced_items(), (0, 0));
+
+        // Drop the slow cursor, it must backfill when it re-registers
+        assert_eq!(manager.drop_slowest_cursors(), vec!["replication"]);
+        assert!(manager.get_items_for_cursor("replication").is_none());
+        assert!(manager.remove_closed_unref_checkpoints() > 0);
+        assert_eq!(manager.get_num_checkpoints(), 1);
+        let registration = manager.register_cursor("replication", 0);
+        assert!(registration.try_backfill);
+        assert_eq!(registration.next_seqno, 6);
+
+        // Items in the open checkpoint before every cursor can be expelled
+        manager.get_items_for_cursor("replication").unwrap();
+        let (expelled, freed) = manager.expel_unreferenced_items();
+        assert_eq!(expelled, 3);
+        assert!(freed > 0);
+        assert_eq!(manager.get_num_items(), 0);
+
+        manager.queue_dirty(make_item(9));
+        let items = manager.get_items_for_cursor("replication").unwrap();
+        assert_eq!(items.items.len(), 1);
+        assert_eq!((items.snap_start, items.snap_end), (6, 9));
+    }
+}
@@ -0,0 +1,110 @@
+//! Where the engine gets the time from. Everything driven by the time (CAS
+//! values and expiry through the HLC, tombstone purging, the background
+//! tasks' intervals) reads the clock in the bucket's config, so tests can
+//! swap in a [`VirtualClock`] and move time forward themselves instead of
+//! sleeping.
+
+use parking_lot::{Condvar, Mutex};
+use std::{
+    fmt::Debug,
+    sync::Arc,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+pub trait Clock: Send + Sync + Debug {
+    /// Time since the Unix epoch
+    fn now(&self) -> Duration;
+
+    /// Wait for the time to pass. It may return early, so callers wanting
+    /// to wait for a deadline should check the time and wait again.
+    fn sleep(&self, duration: Duration);
+}
+
+pub type ClockPtr = Arc<dyn Clock>;
+
+/// The system's wall clock
+#[derive(Debug, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Duration {
+        SystemTime::now().duration_since(UNIX_EPOCH).unwrap()
+    }
+
+    fn sleep(&self, duration: Duration) {
+        std::thread::sleep(duration);
+    }
+}
+
+/// A clock which only moves when advanced
+#[derive(Debug)]
+pub struct VirtualClock {
+    now: Mutex<Duration>,
+    advanced: Condvar,
+}
+
+impl VirtualClock {
+    /// Sleepers wait at most this long in real time, so a task waiting on
+    /// a clock nobody advances still notices the bucket shutting down
+    const MAX_REAL_WAIT: Duration = Duration::from_millis(100);
+
+    /// A clock reading start since the Unix epoch
+    pub fn new(start: Duration) -> Arc<Self> {
+        Arc::new(Self {
+            now: Mutex::new(start),
+            advanced: Condvar::new(),
+        })
+    }
+
+    /// Move the time forward, waking the sleepers it passes
+    pub fn advance(&self, by: Duration) {
+        *self.now.lock() += by;
+        self.advanced.notify_all();
+    }
+}
+
+impl Clock for VirtualClock {
+    fn now(&self) -> Duration {
+        *self.now.lock()
+    }
+
+    fn sleep(&self, duration: Duration) {
+        let mut now = self.now.lock();
+        let deadline = *now + duration;
+        if *now < deadline {
+            self.advanced.wait_for(&mut now, Self::MAX_REAL_WAIT);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::time::Instant;
+
+    #[test]
+    fn test_virtual_clock() {
+        let clock = VirtualClock::new(Duration::from_secs(1_000_000));
+        assert_eq!(clock.now(), Duration::from_secs(1_000_000));
+
+        // A sleeper wakes as soon as the clock passes its deadline
+        let start = Instant::now();
+        std::thread::scope(|scope| {
+            let sleeper = scope.spawn(|| {
+                let deadline = clock.now() + Duration::from_secs(3600);
+                while clock.now() < deadline {
+                    clock.sleep(deadline - clock.now());
+                }
+            });
+            while !sleeper.is_finished() {
+                clock.advance(Duration::from_secs(600));
+                std::thread::sleep(Duration::from_millis(1));
+            }
+        });
+        assert!(clock.now() >= Duration::from_secs(1_003_600));
+        assert!(start.elapsed() < Duration::from_secs(10));
+
+        // Without an advance, sleep gives up after a short real wait
+        clock.sleep(Duration::from_secs(3600));
+    }
+}
@@ -0,0 +1,212 @@
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+use crate::item::Item;
+
+/// Identifies a collection, keys are prefixed with their collection's ID
+/// encoded as unsigned LEB128
+pub type CollectionId = u32;
+
+/// Identifies a scope, a group of collections
+pub type ScopeId = u32;
+
+pub const DEFAULT_COLLECTION: CollectionId = 0;
+
+pub const DEFAULT_SCOPE: ScopeId = 0;
+
+/// The longest LEB128 encoding of a collection ID
+pub const MAX_PREFIX_SIZE: usize = 5;
+
+/// The keys of system events, which record collections being created and
+/// dropped in the seqno index, are in this collection
+pub const SYSTEM_COLLECTION: CollectionId = 1;
+
+/// IDs below this are reserved, clients can create collections from here
+pub const FIRST_USER_COLLECTION: CollectionId = 8;
+
+/// The ID of the collection the key belongs to. Returns None if the key
+/// doesn't start with a valid prefix.
+pub fn collection_id(key: &[u8]) -> Option<CollectionId> {
+    split_key(key).map(|(id, _)| id)
+}
+
+/// Split the key into its collection ID and the key the client uses
+pub fn split_key(key: &[u8]) -> Option<(CollectionId, &[u8])> {
+    let mut id: u32 = 0;
+    for (i, byte) in key.iter().take(5).enumerate() {
+        id |= ((byte & 0x7f) as u32) << (7 * i);
+        if byte & 0x80 == 0 {
+            return Some((id, &key[i + 1..]));
+        }
+    }
+    None
+}
+
+/// The key stored for a client's key in the collection
+pub fn make_key(id: CollectionId, key: &[u8]) -> Vec<u8> {
+    let mut prefixed = Vec::with_capacity(MAX_PREFIX_SIZE + key.len());
+    push_leb128(&mut prefixed, id);
+    prefixed.extend_from_slice(key);
+    prefixed
+}
+
+fn push_leb128(key: &mut Vec<u8>, mut id: u32) {
+    loop {
+        let byte = (id & 0x7f) as u8;
+        id >>= 7;
+        if id == 0 {
+            key.push(byte);
+            return;
+        }
+        key.push(byte | 0x80);
+    }
+}
+
+/// The key of the system event for the collection. The event is a mutation
+/// when the collection is created or modified and a deletion when it is
+/// dropped.
+pub fn collection_event_key(id: CollectionId) -> Vec<u8> {
+    let mut key = Vec::with_capacity(6);
+    push_leb128(&mut key, SYSTEM_COLLECTION);
+    push_leb128(&mut key, id);
+    key
+}
+
+/// The collection a system event is for, None if the key isn't a system
+/// event's
+pub fn collection_event_id(key: &[u8]) -> Option<CollectionId> {
+    match split_key(key)? {
+        (SYSTEM_COLLECTION, rest) => collection_id(rest),
+        _ => None,
+    }
+}
+
+/// The flags of a system event recording a collection's settings changing,
+/// rather than it being created
+pub const MODIFIED_EVENT_FLAGS: u32 = 1;
+
+/// The value of a collection's create or modify event
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CollectionEntry {
+    pub name: String,
+    pub scope_id: ScopeId,
+    /// Seconds until documents written without an expiry expire, 0 for
+    /// never
+    #[serde(rename = "maxTTL", default, skip_serializing_if = "is_zero")]
+    pub max_ttl: u32,
+}
+
+fn is_zero(value: &u32) -> bool {
+    *value == 0
+}
+
+/// The collections of a vbucket, as recorded by its system events
+#[derive(Debug, Serialize)]
+pub struct VBucketManifest {
+    collections: HashMap<CollectionId, CollectionEntry>,
+}
+
+impl Default for VBucketManifest {
+    /// Only the default collection
+    fn default() -> Self {
+        let default = CollectionEntry {
+            name: "_default".to_string(),
+            scope_id: DEFAULT_SCOPE,
+            max_ttl: 0,
+        };
+        Self {
+            collections: HashMap::from([(DEFAULT_COLLECTION, default)]),
+        }
+    }
+}
+
+impl VBucketManifest {
+    pub fn get(&self, id: CollectionId) -> Option<&CollectionEntry> {
+        self.collections.get(&id)
+    }
+
+    pub fn exists(&self, id: CollectionId) -> bool {
+        self.collections.contains_key(&id)
+    }
+
+    pub fn ids(&self) -> Vec<CollectionId> {
+        self.collections.keys().copied().collect()
+    }
+
+    /// The maxTTL of the collection the key is in, 0 if it has none
+    pub fn max_ttl(&self, key: &[u8]) -> u32 {
+        collection_id(key)
+            .and_then(|id| self.get(id))
+            .map_or(0, |entry| entry.max_ttl)
+    }
+
+    /// Update the manifest from a system event, which is ignored if it has
+    /// no collection ID or an unreadable value
+    pub fn apply_event(&mut self, item: &Item) {
+        let Some(id) = collection_event_id(&item.key) else {
+            return;
+        };
+        match &item.value {
+            None => {
+                self.collections.remove(&id);
+            }
+            Some(value) => match serde_json::from_slice(value) {
+                Ok(entry) => {
+                    self.collections.insert(id, entry);
+                }
+                Err(e) => println!("Invalid system event for collection {id:#x}: {e}"),
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::item::{Datatype, DeleteSource};
+
+    #[test]
+    fn test_collection_events() {
+        let key = collection_event_key(0x8a);
+        assert_eq!(key, b"\x01\x8a\x01");
+        assert_eq!(collection_event_id(&key), Some(0x8a));
+        assert_eq!(collection_id(&key), Some(SYSTEM_COLLECTION));
+        assert_eq!(collection_event_id(b"\0key"), None);
+        assert_eq!(make_key(0x8a, b"key"), b"\x8a\x01key");
+        assert_eq!(
+            split_key(&make_key(DEFAULT_COLLECTION, b"key")),
+            Some((0, &b"key"[..]))
+        );
+
+        let mut manifest = VBucketManifest::default();
+        let entry = CollectionEntry {
+            name: "beers".to_string(),
+            scope_id: DEFAULT_SCOPE,
+            max_ttl: 0,
+        };
+        let mut event = Item {
+            key,
+            value: Some(serde_json::to_vec(&entry).unwrap()),
+            cas: 0,
+            expiry_time: 0,
+            flags: 0,
+            by_seqno: 1,
+            rev_seqno: 1,
+            delete_source: DeleteSource::Explicit,
+            datatype: Datatype::empty(),
+        };
+        manifest.apply_event(&event);
+        assert_eq!(manifest.get(0x8a), Some(&entry));
+        assert_eq!(manifest.max_ttl(&make_key(0x8a, b"key")), 0);
+
+        // A modify event changes the maxTTL
+        event.value = Some(br#"{"name":"beers","scope_id":0,"maxTTL":100}"#.to_vec());
+        manifest.apply_event(&event);
+        assert_eq!(manifest.max_ttl(&make_key(0x8a, b"key")), 100);
+        assert_eq!(manifest.max_ttl(b"\0key"), 0);
+        event.value = None;
+        manifest.apply_event(&event);
+        assert!(!manifest.exists(0x8a));
+        assert!(manifest.exists(DEFAULT_COLLECTION));
+    }
+}
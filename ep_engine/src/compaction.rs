@@ -0,0 +1,393 @@
+//! Compacting every vbucket of the bucket, as an admin operation. A
+//! background task purges each vbucket's old tombstones and rewrites its
+//! file without the space superseded versions take, a few vbuckets at a
+//! time so the disk isn't swamped. Its progress is kept for the caller and
+//! the compact_all stats group, and it can be cancelled between vbuckets.
+//! Pausing the bucket stops it the same way, as its files mustn't change.
+
+use parking_lot::Mutex;
+use std::{
+    sync::{
+        atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+        Weak,
+    },
+    time::{Duration, Instant},
+};
+
+use crate::{ep_bucket::EPBucket, error::EngineError, vbucket::Vbid};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CompactAllOptions {
+    /// How many vbuckets are compacted at once
+    pub concurrency: usize,
+    /// Purge the tombstones older than the metadata purge interval first
+    pub purge_tombstones: bool,
+    /// Only compact the vbuckets whose tombstones are worth purging, as
+    /// tombstone_purge_candidates chooses them
+    pub only_candidates: bool,
+}
+
+impl Default for CompactAllOptions {
+    fn default() -> Self {
+        Self {
+            concurrency: 1,
+            purge_tombstones: true,
+            only_candidates: false,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CompactAllState {
+    Running,
+    Done,
+    /// Cancelled before every vbucket was compacted
+    Cancelled,
+    /// The bucket shut down or was paused before every vbucket was
+    /// compacted
+    Stopped,
+}
+
+impl CompactAllState {
+    pub fn name(&self) -> &'static str {
+        match self {
+            CompactAllState::Running => "running",
+            CompactAllState::Done => "done",
+            CompactAllState::Cancelled => "cancelled",
+            CompactAllState::Stopped => "stopped",
+        }
+    }
+}
+
+/// How far a compaction of every vbucket has got. Also its cancellation
+/// handle: the vbuckets being compacted when it is cancelled are finished,
+/// and the rest are left alone.
+#[derive(Debug)]
+pub struct CompactAllProgress {
+    pub options: CompactAllOptions,
+    state: Mutex<(CompactAllState, Option<Duration>)>,
+    started: Instant,
+    vbuckets: Vec<Vbid>,
+    next: AtomicUsize,
+    cancelled: AtomicBool,
+    vbuckets_done: AtomicUsize,
+    bytes_reclaimed: AtomicU64,
+    tombstones_purged: AtomicU64,
+    /// Vbuckets which couldn't be compacted, as the disk was full
+    vbuckets_failed: AtomicUsize,
+}
+
+impl CompactAllProgress {
+    pub(crate) fn new(options: CompactAllOptions, vbuckets: Vec<Vbid>) -> Self {
+        Self {
+            options,
+            state: Mutex::new((CompactAllState::Running, None)),
+            started: Instant::now(),
+            vbuckets,
+            next: AtomicUsize::new(0),
+            cancelled: AtomicBool::new(false),
+            vbuckets_done: AtomicUsize::new(0),
+            bytes_reclaimed: AtomicU64::new(0),
+            tombstones_purged: AtomicU64::new(0),
+            vbuckets_failed: AtomicUsize::new(0),
+        }
+    }
+
+    pub fn state(&self) -> CompactAllState {
+        self.state.lock().0
+    }
+
+    pub fn is_running(&self) -> bool {
+        self.state() == CompactAllState::Running
+    }
+
+    /// Stop once the vbuckets being compacted now are done
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::Relaxed)
+    }
+
+    pub fn vbuckets_total(&self) -> usize {
+        self.vbuckets.len()
+    }
+
+    pub fn vbuckets_done(&self) -> usize {
+        self.vbuckets_done.load(Ordering::Relaxed)
+    }
+
+    pub fn vbuckets_failed(&self) -> usize {
+        self.vbuckets_failed.load(Ordering::Relaxed)
+    }
+
+    pub fn bytes_reclaimed(&self) -> u64 {
+        self.bytes_reclaimed.load(Ordering::Relaxed)
+    }
+
+    pub fn tombstones_purged(&self) -> u64 {
+        self.tombstones_purged.load(Ordering::Relaxed)
+    }
+
+    fn finish(&self, state: CompactAllState) {
+        *self.state.lock() = (state, Some(self.started.elapsed()));
+    }
+
+    /// The compact_all stats group
+    pub fn add_stats(&self, add_stat: &mut dyn FnMut(&str, &str)) {
+        let (state, duration) = *self.state.lock();
+        add_stat("ep_compact_all_state", state.name());
+        add_stat(
+            "ep_compact_all_concurrency",
+            &self.options.concurrency.to_string(),
+        );
+        add_stat(
+            "ep_compact_all_vbuckets_total",
+            &self.vbuckets_total().to_string(),
+        );
+        add_stat(
+            "ep_compact_all_vbuckets_done",
+            &self.vbuckets_done().to_string(),
+        );
+        add_stat(
+            "ep_compact_all_vbuckets_failed",
+            &self.vbuckets_failed().to_string(),
+        );
+        add_stat(
+            "ep_compact_all_bytes_reclaimed",
+            &self.bytes_reclaimed().to_string(),
+        );
+        add_stat(
+            "ep_compact_all_tombstones_purged",
+            &self.tombstones_purged().to_string(),
+        );
+        let duration = duration.unwrap_or_else(|| self.started.elapsed());
+        add_stat("ep_compact_all_time_us", &duration.as_micros().to_string());
+    }
+}
+
+/// The background task: compact the vbuckets on up to the configured number
+/// of threads, stopping if cancelled or the bucket shuts down or is paused
+pub(crate) fn run(bucket: Weak<EPBucket>, progress: &CompactAllProgress) {
+    let workers = progress
+        .options
+        .concurrency
+        .clamp(1, progress.vbuckets_total().max(1));
+    std::thread::scope(|scope| {
+        for _ in 0..workers {
+            scope.spawn(|| compact_vbuckets(&bucket, progress));
+        }
+    });
+    let state = if progress.vbuckets_done() == progress.vbuckets_total() {
+        CompactAllState::Done
+    } else if progress.is_cancelled() {
+        CompactAllState::Cancelled
+    } else {
+        CompactAllState::Stopped
+    };
+    println!(
+        "Compaction of all vbuckets {}: {} of {} compacted, {} bytes reclaimed, {} tombstones purged",
+        state.name(),
+        progress.vbuckets_done(),
+        progress.vbuckets_total(),
+        progress.bytes_reclaimed(),
+        progress.tombstones_purged()
+    );
+    progress.finish(state);
+}
+
+/// One worker, taking the next vbucket to compact until there are none left
+fn compact_vbuckets(bucket: &Weak<EPBucket>, progress: &CompactAllProgress) {
+    while !progress.is_cancelled() {
+        let Some(bucket) = bucket.upgrade() else {
+            return;
+        };
+        if bucket.is_shutting_down() || bucket.is_paused() {
+            return;
+        }
+        let Some(&vbid) = progress
+            .vbuckets
+            .get(progress.next.fetch_add(1, Ordering::Relaxed))
+        else {
+            return;
+        };
+        let _memory = bucket.memory_scope();
+        match bucket.compact_vbucket(vbid, progress.options.purge_tombstones) {
+            Ok((purged, reclaimed)) => {
+                progress
+                    .tombstones_purged
+                    .fetch_add(purged, Ordering::Relaxed);
+                progress
+                    .bytes_reclaimed
+                    .fetch_add(reclaimed, Ordering::Relaxed);
+            }
+            // Moved away since the vbuckets were listed
+            Err(EngineError::NotMyVbucket) => {}
+            Err(_) => {
+                progress.vbuckets_failed.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+        progress.vbuckets_done.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{failover_table::FailoverTable, vbucket::State, Config};
+    use std::{collections::HashMap, sync::Arc};
+
+    #[test]
+    fn test_compact_all() {
+        let dir = tempfile::tempdir().unwrap();
+        let bucket = EPBucket::new(Config {
+            max_vbuckets: 4,
+            max_shards: 2,
+            dbname: dir.path().to_str().unwrap().to_string(),
+            metadata_purge_interval: 0,
+            ..Default::default()
+        });
+        for vbid in 0..4u16 {
+            bucket.vbucket_map.add_bucket(bucket.make_vbucket(
+                Vbid::from(vbid),
+                State::Active,
+                FailoverTable::new_empty(25),
+                0,
+                0,
+            ));
+        }
+        bucket.enable_traffic();
+        // Each key written several times, then most deleted, leaves stale
+        // versions and tombstones in every file
+        for round in 0..5 {
+            for i in 0..200 {
+                let key = format!("key{i}").into_bytes();
+                bucket
+                    .set(key, format!("value {round}").repeat(20).into_bytes(), 0, 0)
+                    .unwrap();
+            }
+            for vbid in 0..4u16 {
+                bucket.flush_vbucket(Vbid::from(vbid));
+            }
+        }
+        for i in 0..150 {
+            bucket.delete(format!("key{i}").into_bytes(), 0).unwrap();
+        }
+        bucket
+            .set(b"last".to_vec(), b"value".to_vec(), 0, 0)
+            .unwrap();
+        for vbid in 0..4u16 {
+            bucket.flush_vbucket(Vbid::from(vbid));
+        }
+
+        let options = CompactAllOptions {
+            concurrency: 2,
+            ..Default::default()
+        };
+        let progress = EPBucket::compact_all(&bucket, options).unwrap();
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while progress.is_running() {
+            assert!(Instant::now() < deadline);
+            std::thread::sleep(Duration::from_millis(1));
+        }
+        assert_eq!(progress.state(), CompactAllState::Done);
+        assert_eq!(progress.vbuckets_done(), 4);
+        assert_eq!(progress.vbuckets_failed(), 0);
+        assert!(progress.tombstones_purged() >= 140);
+        assert!(progress.bytes_reclaimed() > 0);
+        for i in 150..200 {
+            let value = bucket.get(format!("key{i}").into_bytes()).unwrap();
+            assert_eq!(value.value.unwrap(), "value 4".repeat(20).as_bytes());
+        }
+
+        let mut stats = HashMap::new();
+        bucket
+            .get_stats_group("compact_all", &mut |key, value| {
+                stats.insert(key.to_string(), value.to_string());
+            })
+            .unwrap();
+        assert_eq!(stats["ep_compact_all_state"], "done");
+        assert_eq!(stats["ep_compact_all_vbuckets_done"], "4");
+
+        // Cancelled before a worker takes a vbucket, none are compacted
+        let progress = Arc::new(CompactAllProgress::new(
+            CompactAllOptions::default(),
+            vec![Vbid::from(0u16), Vbid::from(1u16)],
+        ));
+        progress.cancel();
+        run(Arc::downgrade(&bucket), &progress);
+        assert_eq!(progress.state(), CompactAllState::Cancelled);
+        assert_eq!(progress.vbuckets_done(), 0);
+    }
+
+    #[test]
+    fn test_compact_paused() {
+        let dir = tempfile::tempdir().unwrap();
+        let bucket = EPBucket::new(Config {
+            max_vbuckets: 4,
+            max_shards: 1,
+            dbname: dir.path().to_str().unwrap().to_string(),
+            metadata_purge_interval: 0,
+            ..Default::default()
+        });
+        for vbid in 0..4u16 {
+            bucket.vbucket_map.add_bucket(bucket.make_vbucket(
+                Vbid::from(vbid),
+                State::Active,
+                FailoverTable::new_empty(25),
+                0,
+                0,
+            ));
+        }
+        bucket.enable_traffic();
+        for i in 0..100 {
+            let key = format!("key{i}").into_bytes();
+            bucket.set(key.clone(), b"value".to_vec(), 0, 0).unwrap();
+            bucket.delete(key, 0).unwrap();
+        }
+        let files = || {
+            let mut files: Vec<(String, u64)> = std::fs::read_dir(dir.path())
+                .unwrap()
+                .map(|entry| {
+                    let entry = entry.unwrap();
+                    let name = entry.file_name().to_string_lossy().into_owned();
+                    (name, entry.metadata().unwrap().len())
+                })
+                .collect();
+            files.sort();
+            files
+        };
+
+        // Nothing is compacted or purged while paused
+        assert!(bucket.pause());
+        let paused = files();
+        assert_eq!(
+            bucket.compact_vbucket(Vbid::from(0u16), true),
+            Err(EngineError::TemporaryFailure)
+        );
+        assert_eq!(
+            EPBucket::compact_all(&bucket, CompactAllOptions::default()).err(),
+            Some(EngineError::TemporaryFailure)
+        );
+        // Nor by a compaction which was already running
+        let progress = CompactAllProgress::new(
+            CompactAllOptions::default(),
+            bucket.vbucket_map.get_buckets(),
+        );
+        run(Arc::downgrade(&bucket), &progress);
+        assert_eq!(progress.state(), CompactAllState::Stopped);
+        assert_eq!(progress.vbuckets_done(), 0);
+        assert_eq!(files(), paused);
+
+        assert!(bucket.resume());
+        let progress = EPBucket::compact_all(&bucket, CompactAllOptions::default()).unwrap();
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while progress.is_running() {
+            assert!(Instant::now() < deadline);
+            std::thread::sleep(Duration::from_millis(1));
+        }
+        assert_eq!(progress.state(), CompactAllState::Done);
+        assert_ne!(files(), paused);
+    }
+}
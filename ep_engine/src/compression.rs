@@ -0,0 +1,38 @@
+//! Snappy compression of values, which clients may send and receive
+//! compressed once they have negotiated it
+
+use crate::item::Datatype;
+
+/// What a bucket does with values written to it
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum CompressionMode {
+    /// Values are stored uncompressed, compressed ones are inflated on write
+    Off,
+    /// Values are stored as the client sent them
+    #[default]
+    Passive,
+    /// Values are compressed on write when it saves enough space
+    Active,
+}
+
+pub fn compress(value: &[u8]) -> Vec<u8> {
+    snap::raw::Encoder::new()
+        .compress_vec(value)
+        .expect("the input is below snappy's size limit")
+}
+
+/// The decompressed value, None if it isn't valid snappy
+pub fn inflate(value: &[u8]) -> Option<Vec<u8>> {
+    snap::raw::Decoder::new().decompress_vec(value).ok()
+}
+
+/// Decompress the value if the datatype says it's snappy compressed
+pub(crate) fn inflate_in_place(value: &mut Option<Vec<u8>>, datatype: &mut Datatype) {
+    if !datatype.contains(Datatype::SNAPPY) {
+        return;
+    }
+    if let Some(value) = value {
+        *value = inflate(value).expect("snappy values are checked on write");
+    }
+    datatype.remove(Datatype::SNAPPY);
+}
@@ -0,0 +1,244 @@
+//! A marker file in each of a bucket's data directories recording what
+//! wrote it: the engine version, the on-disk formats and the bucket's name
+//! and UUID. It's checked when the bucket is opened, so an engine never
+//! reads files in a format it doesn't know, with a backend that didn't
+//! write them, or that belong to another bucket (e.g. a directory copied or
+//! mounted in the wrong place).
+
+use crate::kv_store::Backend;
+use serde::{Deserialize, Serialize};
+use std::{
+    io::{self, Write},
+    path::Path,
+};
+use thiserror::Error;
+
+pub const MARKER_FILE: &str = "engine.json";
+
+/// Bumped when the layout of a data directory changes incompatibly
+pub const FORMAT_VERSION: u32 = 1;
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DataDirMarker {
+    /// Version of the engine which last opened the directory
+    pub engine_version: String,
+    pub format_version: u32,
+    pub backend: String,
+    /// Latest version of the couchstore files written
+    pub couchstore_disk_version: u8,
+    pub bucket_name: String,
+    /// Tells apart buckets of the same name, and ties a bucket's data
+    /// directories together
+    pub bucket_uuid: String,
+}
+
+#[derive(Error, Debug)]
+pub enum DataDirError {
+    #[error("failed to access {MARKER_FILE}: {0}")]
+    Io(#[from] io::Error),
+    #[error("unreadable {MARKER_FILE}: {0}")]
+    Corrupt(#[from] serde_json::Error),
+    #[error("format version {found} is newer than this engine's {FORMAT_VERSION} (written by engine {engine_version})")]
+    NewerFormat { found: u32, engine_version: String },
+    #[error("couchstore disk version {0} isn't supported")]
+    UnsupportedDiskVersion(u8),
+    #[error("written by the {found} backend, not {expected}")]
+    WrongBackend { found: String, expected: String },
+    #[error("belongs to bucket {name} with uuid {uuid}")]
+    ForeignBucket { name: String, uuid: String },
+}
+
+/// Check the directory may be opened as the bucket's, and record this
+/// engine's version in its marker, along with the disk version it writes
+/// files at if that's newer. A directory without a marker (new, or written
+/// before markers) is given one, with the given UUID or else a new one. If
+/// a UUID is given the marker must have it.
+pub fn check_data_dir(
+    dir: &Path,
+    bucket_name: &str,
+    backend: Backend,
+    bucket_uuid: Option<&str>,
+    disk_version: couchstore::DiskVersion,
+) -> Result<DataDirMarker, DataDirError> {
+    let path = dir.join(MARKER_FILE);
+    let marker = match std::fs::read(&path) {
+        Ok(json) => {
+            let marker: DataDirMarker = serde_json::from_slice(&json)?;
+            validate(&marker, bucket_name, backend, bucket_uuid)?;
+            marker
+        }
+        Err(e) if e.kind() == io::ErrorKind::NotFound => DataDirMarker {
+            engine_version: String::new(),
+            format_version: FORMAT_VERSION,
+            backend: backend.name().to_string(),
+            couchstore_disk_version: disk_version.into(),
+            bucket_name: bucket_name.to_string(),
+            bucket_uuid: bucket_uuid.map_or_else(
+                || format!("{:032x}", rand::random::<u128>()),
+                str::to_string,
+            ),
+        },
+        Err(e) => return Err(e.into()),
+    };
+
+    let marker = DataDirMarker {
+        engine_version: env!("CARGO_PKG_VERSION").to_string(),
+        couchstore_disk_version: marker.couchstore_disk_version.max(disk_version.into()),
+        ..marker
+    };
+    write_marker(&path, &marker)?;
+    Ok(marker)
+}
+
+fn validate(
+    marker: &DataDirMarker,
+    bucket_name: &str,
+    backend: Backend,
+    bucket_uuid: Option<&str>,
+) -> Result<(), DataDirError> {
+    if marker.format_version > FORMAT_VERSION {
+        return Err(DataDirError::NewerFormat {
+            found: marker.format_version,
+            engine_version: marker.engine_version.clone(),
+        });
+    }
+    if couchstore::DiskVersion::try_from(marker.couchstore_disk_version).is_err() {
+        return Err(DataDirError::UnsupportedDiskVersion(
+            marker.couchstore_disk_version,
+        ));
+    }
+    if marker.backend != backend.name() {
+        return Err(DataDirError::WrongBackend {
+            found: marker.backend.clone(),
+            expected: backend.name().to_string(),
+        });
+    }
+    if marker.bucket_name != bucket_name
+        || bucket_uuid.is_some_and(|uuid| uuid != marker.bucket_uuid)
+    {
+        return Err(DataDirError::ForeignBucket {
+            name: marker.bucket_name.clone(),
+            uuid: marker.bucket_uuid.clone(),
+        });
+    }
+    Ok(())
+}
+
+/// Replace the marker, so a crash leaves either the old or the new one
+fn write_marker(path: &Path, marker: &DataDirMarker) -> io::Result<()> {
+    let tmp = path.with_extension("json.tmp");
+    let mut file = std::fs::File::create(&tmp)?;
+    file.write_all(&serde_json::to_vec_pretty(marker).unwrap())?;
+    file.sync_all()?;
+    std::fs::rename(&tmp, path)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use couchstore::DiskVersion;
+
+    #[test]
+    fn test_check_data_dir() {
+        let dir = tempfile::tempdir().unwrap();
+        let marker = check_data_dir(
+            dir.path(),
+            "default",
+            Backend::Couchstore,
+            None,
+            DiskVersion::Thirteen,
+        )
+        .unwrap();
+        assert_eq!(marker.engine_version, env!("CARGO_PKG_VERSION"));
+        assert_eq!(marker.bucket_uuid.len(), 32);
+
+        // The same bucket opens it again, keeping its UUID
+        assert_eq!(
+            check_data_dir(
+                dir.path(),
+                "default",
+                Backend::Couchstore,
+                Some(&marker.bucket_uuid),
+                DiskVersion::Thirteen
+            )
+            .unwrap(),
+            marker
+        );
+
+        // Writing files at a newer version is recorded, and sticks
+        for version in [DiskVersion::Fourteen, DiskVersion::Thirteen] {
+            let marker =
+                check_data_dir(dir.path(), "default", Backend::Couchstore, None, version).unwrap();
+            assert_eq!(marker.couchstore_disk_version, 14);
+        }
+
+        // Other buckets and backends are refused
+        assert!(matches!(
+            check_data_dir(dir.path(), "other", Backend::Couchstore, None, DiskVersion::Thirteen),
+            Err(DataDirError::ForeignBucket { name, .. }) if name == "default"
+        ));
+        assert!(matches!(
+            check_data_dir(
+                dir.path(),
+                "default",
+                Backend::Couchstore,
+                Some("0"),
+                DiskVersion::Thirteen
+            ),
+            Err(DataDirError::ForeignBucket { .. })
+        ));
+        assert!(matches!(
+            check_data_dir(
+                dir.path(),
+                "default",
+                Backend::Memory,
+                None,
+                DiskVersion::Thirteen
+            ),
+            Err(DataDirError::WrongBackend { .. })
+        ));
+
+        // As are the directories of newer engines
+        let newer = DataDirMarker {
+            format_version: FORMAT_VERSION + 1,
+            ..marker.clone()
+        };
+        write_marker(&dir.path().join(MARKER_FILE), &newer).unwrap();
+        let err = check_data_dir(
+            dir.path(),
+            "default",
+            Backend::Couchstore,
+            None,
+            DiskVersion::Thirteen,
+        )
+        .unwrap_err();
+        assert!(matches!(err, DataDirError::NewerFormat { found: 2, .. }));
+        let unsupported = DataDirMarker {
+            couchstore_disk_version: 16,
+            ..marker
+        };
+        write_marker(&dir.path().join(MARKER_FILE), &unsupported).unwrap();
+        assert!(matches!(
+            check_data_dir(
+                dir.path(),
+                "default",
+                Backend::Couchstore,
+                None,
+                DiskVersion::Thirteen
+            ),
+            Err(DataDirError::UnsupportedDiskVersion(16))
+        ));
+
+        std::fs::write(dir.path().join(MARKER_FILE), b"{").unwrap();
+        assert!(matches!(
+            check_data_dir(
+                dir.path(),
+                "default",
+                Backend::Couchstore,
+                None,
+                DiskVersion::Thirteen
+            ),
+            Err(DataDirError::Corrupt(_))
+        ));
+    }
+}
@@ -0,0 +1,279 @@
+//! Backfills read a stream's items from disk on a background task, handing
+//! them to the stream as it sends them rather than reading the whole
+//! snapshot into memory first. The items read but not yet sent are bounded
+//! by a byte budget shared by the producer's streams: once it is used up a
+//! scan pauses until the streams have sent enough for it to resume.
+
+use parking_lot::{Condvar, Mutex};
+use std::{
+    collections::VecDeque,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use crate::{
+    checkpoint_manager::QueuedItem,
+    ep_bucket::{EPBucket, EPBucketPtr},
+    kv_store::{ScanErrorPolicy, ScanResult, ValueFilter},
+    vbucket::Vbid,
+};
+
+/// How often a paused scan checks whether the bucket is shutting down
+const PAUSE_CHECK_INTERVAL: Duration = Duration::from_millis(100);
+
+/// The bytes of items a producer's backfills have read which its streams
+/// haven't yet taken to send
+#[derive(Debug)]
+pub struct BackfillBudget {
+    state: Mutex<BudgetState>,
+    /// Signalled when bytes are released or the limit raised
+    space: Condvar,
+    /// Times a scan paused for the budget
+    pauses: AtomicU64,
+}
+
+#[derive(Debug)]
+struct BudgetState {
+    max_bytes: usize,
+    bytes: usize,
+}
+
+impl BackfillBudget {
+    pub fn new(max_bytes: usize) -> Self {
+        Self {
+            state: Mutex::new(BudgetState {
+                max_bytes,
+                bytes: 0,
+            }),
+            space: Condvar::new(),
+            pauses: AtomicU64::new(0),
+        }
+    }
+
+    pub fn max_bytes(&self) -> usize {
+        self.state.lock().max_bytes
+    }
+
+    pub fn set_max_bytes(&self, max_bytes: usize) {
+        self.state.lock().max_bytes = max_bytes;
+        self.space.notify_all();
+    }
+
+    pub fn bytes(&self) -> usize {
+        self.state.lock().bytes
+    }
+
+    pub fn pauses(&self) -> u64 {
+        self.pauses.load(Ordering::Relaxed)
+    }
+
+    fn release(&self, bytes: usize) {
+        if bytes == 0 {
+            return;
+        }
+        self.state.lock().bytes -= bytes;
+        self.space.notify_all();
+    }
+}
+
+/// Why a backfill ended before reading every item
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum BackfillEnd {
+    /// The file is corrupt, the result says where
+    Failed(ScanResult),
+    /// The bucket shut down
+    Stopped,
+}
+
+#[derive(Debug)]
+struct Shared {
+    queue: Mutex<Queue>,
+    /// Signalled when an item is queued or the scan finishes
+    ready: Condvar,
+    /// The stream has gone, so the scan stops
+    cancelled: AtomicBool,
+    budget: Arc<BackfillBudget>,
+}
+
+#[derive(Debug, Default)]
+struct Queue {
+    /// Items read and not yet taken, with their sizes
+    items: VecDeque<(QueuedItem, usize)>,
+    /// Set once the scan has finished
+    end: Option<Result<(), BackfillEnd>>,
+}
+
+impl Shared {
+    /// Wait until the item fits in the budget. A backfill with nothing
+    /// queued can always take one, so every stream makes progress however
+    /// the budget is shared. False if the scan should stop instead.
+    fn reserve(&self, bucket: &EPBucket, size: usize) -> bool {
+        let mut budget = self.budget.state.lock();
+        let mut paused = false;
+        loop {
+            if self.cancelled.load(Ordering::SeqCst) || bucket.is_shutting_down() {
+                return false;
+            }
+            if budget.bytes + size <= budget.max_bytes || self.queue.lock().items.is_empty() {
+                budget.bytes += size;
+                return true;
+            }
+            if !paused {
+                paused = true;
+                self.budget.pauses.fetch_add(1, Ordering::Relaxed);
+            }
+            self.budget
+                .space
+                .wait_for(&mut budget, PAUSE_CHECK_INTERVAL);
+        }
+    }
+
+    fn push(&self, item: QueuedItem, size: usize) {
+        let mut queue = self.queue.lock();
+        if self.cancelled.load(Ordering::SeqCst) {
+            drop(queue);
+            self.budget.release(size);
+            return;
+        }
+        queue.items.push_back((item, size));
+        self.ready.notify_one();
+    }
+
+    fn finish(&self, end: Result<(), BackfillEnd>) {
+        let mut queue = self.queue.lock();
+        if queue.end.is_none() {
+            queue.end = Some(end);
+            self.ready.notify_one();
+        }
+    }
+}
+
+/// Marks the scan stopped if its task ends without finishing it, so the
+/// stream isn't left waiting
+struct FinishOnDrop(Arc<Shared>);
+
+impl Drop for FinishOnDrop {
+    fn drop(&mut self) {
+        self.0.finish(Err(BackfillEnd::Stopped));
+    }
+}
+
+/// A stream's backfill of the items from start_seqno to end_seqno. The
+/// scan is cancelled when it is dropped.
+#[derive(Debug)]
+pub(crate) struct Backfill {
+    shared: Arc<Shared>,
+    pub start_seqno: u64,
+    pub end_seqno: u64,
+    /// The snapshot marker has been sent, which waits for the first item
+    /// the stream's filter selects
+    pub marker_sent: bool,
+}
+
+impl Backfill {
+    pub(crate) fn start(
+        bucket: &EPBucketPtr,
+        vbid: Vbid,
+        start_seqno: u64,
+        end_seqno: u64,
+        budget: Arc<BackfillBudget>,
+    ) -> Self {
+        let shared = Arc::new(Shared {
+            queue: Mutex::new(Queue::default()),
+            ready: Condvar::new(),
+            cancelled: AtomicBool::new(false),
+            budget,
+        });
+        let guard = FinishOnDrop(shared.clone());
+        let weak = Arc::downgrade(bucket);
+        bucket.schedule_task(format!("backfill {vbid}"), move || {
+            let end = match weak.upgrade() {
+                Some(bucket) => scan(&bucket, vbid, start_seqno, end_seqno, &guard.0),
+                None => Err(BackfillEnd::Stopped),
+            };
+            guard.0.finish(end);
+        });
+        Self {
+            shared,
+            start_seqno,
+            end_seqno,
+            marker_sent: false,
+        }
+    }
+
+    /// The next item, waiting for the scan to read it. None once every
+    /// item has been taken.
+    pub(crate) fn take(&self) -> Result<Option<QueuedItem>, BackfillEnd> {
+        let mut queue = self.shared.queue.lock();
+        loop {
+            if let Some((item, size)) = queue.items.pop_front() {
+                drop(queue);
+                self.shared.budget.release(size);
+                return Ok(Some(item));
+            }
+            if let Some(end) = &queue.end {
+                return end.clone().map(|_| None);
+            }
+            self.shared.ready.wait(&mut queue);
+        }
+    }
+
+    /// Items read and waiting to be taken
+    pub(crate) fn buffered_items(&self) -> usize {
+        self.shared.queue.lock().items.len()
+    }
+}
+
+impl Drop for Backfill {
+    fn drop(&mut self) {
+        self.shared.cancelled.store(true, Ordering::SeqCst);
+        let items = std::mem::take(&mut self.shared.queue.lock().items);
+        self.shared
+            .budget
+            .release(items.iter().map(|(_, size)| size).sum());
+        // Wake the scan if it's paused, so it sees it's cancelled
+        self.shared.budget.space.notify_all();
+    }
+}
+
+/// Read the items into the queue, in seqno order. A snapshot can't have
+/// gaps, so any corruption fails the backfill.
+fn scan(
+    bucket: &EPBucket,
+    vbid: Vbid,
+    start_seqno: u64,
+    end_seqno: u64,
+    shared: &Shared,
+) -> Result<(), BackfillEnd> {
+    let store = bucket.vbucket_map.get_shard_by_vb_id(vbid).store();
+    let mut stopped = false;
+    let result = store.scan_while(
+        vbid,
+        start_seqno,
+        ValueFilter::ValuesDecompressed,
+        ScanErrorPolicy::Abort,
+        &mut |item| {
+            if item.by_seqno > end_seqno {
+                return false;
+            }
+            let size = item.key.len() + item.value.as_ref().map_or(0, Vec::len);
+            bucket.io_throttle().acquire(size);
+            if !shared.reserve(bucket, size) {
+                stopped = true;
+                return false;
+            }
+            shared.push(QueuedItem::new(item), size);
+            true
+        },
+    );
+    if stopped {
+        return Err(BackfillEnd::Stopped);
+    }
+    if !result.is_complete() {
+        return Err(BackfillEnd::Failed(result));
+    }
+    Ok(())
+}
@@ -0,0 +1,391 @@
+use parking_lot::Mutex;
+use std::collections::{BTreeMap, VecDeque};
+
+use crate::{
+    dcp::response::DcpResponse,
+    ep_bucket::EPBucketPtr,
+    error::{EngineError, EngineResult},
+    item::Item,
+    vbucket::{State, Vbid},
+};
+
+/// The client side of a DCP connection, applying the messages of a
+/// producer's streams to the bucket's replica and pending vbuckets.
+///
+/// The producer's flow control buffer is only acknowledged as the messages
+/// are applied and their items persisted, and while replication is
+/// throttled the messages are buffered instead of applied. Either way the
+/// unacknowledged bytes build up until the producer pauses, so a replica
+/// short of memory or behind on disk slows down the replication.
+pub struct DcpConsumer {
+    name: String,
+    bucket: EPBucketPtr,
+    state: Mutex<ConsumerState>,
+}
+
+#[derive(Default)]
+struct ConsumerState {
+    /// Messages received while replication was throttled, applied in order
+    /// once it no longer is
+    buffered: VecDeque<DcpResponse>,
+    /// The seqnos and sizes of the items applied to each vbucket which have
+    /// yet to be persisted
+    unpersisted: BTreeMap<Vbid, VecDeque<(u64, usize)>>,
+    /// Bytes processed which the producer hasn't been told of
+    bytes_to_ack: usize,
+}
+
+impl DcpConsumer {
+    pub fn new(name: impl Into<String>, bucket: EPBucketPtr) -> Self {
+        Self {
+            name: name.into(),
+            bucket,
+            state: Mutex::default(),
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Handle a message received from the producer, applying it unless
+    /// replication is throttled. A set vbucket state message must be
+    /// acknowledged to the producer once applied, which for a takeover
+    /// stream lets it go on with the handover.
+    pub fn handle(&self, message: &DcpResponse) -> EngineResult<()> {
+        let mut state = self.state.lock();
+        if !state.buffered.is_empty() || self.bucket.is_replication_throttled() {
+            state.buffered.push_back(message.clone());
+            return Ok(());
+        }
+        self.apply(&mut state, message)
+    }
+
+    /// Apply the messages buffered while replication was throttled, until
+    /// it is throttled again. Returns the number applied.
+    pub fn process_buffered(&self) -> EngineResult<usize> {
+        let mut state = self.state.lock();
+        let mut applied = 0;
+        while !self.bucket.is_replication_throttled() {
+            let Some(message) = state.buffered.pop_front() else {
+                break;
+            };
+            self.apply(&mut state, &message)?;
+            applied += 1;
+        }
+        Ok(applied)
+    }
+
+    /// Messages waiting for replication to no longer be throttled
+    pub fn num_buffered(&self) -> usize {
+        self.state.lock().buffered.len()
+    }
+
+    /// Bytes to acknowledge to the producer: those of the messages applied
+    /// since the last call, counting items only once they are persisted
+    pub fn take_buffer_acknowledgement(&self) -> usize {
+        let mut state = self.state.lock();
+        let ConsumerState {
+            unpersisted,
+            bytes_to_ack,
+            ..
+        } = &mut *state;
+        for (vbid, items) in unpersisted.iter_mut() {
+            // A vbucket which has gone has nothing left to persist
+            let persisted = self
+                .bucket
+                .get_vbucket(*vbid)
+                .map_or(u64::MAX, |vb| vb.get_persisted_seqno());
+            while let Some(&(seqno, bytes)) = items.front() {
+                if seqno > persisted {
+                    break;
+                }
+                *bytes_to_ack += bytes;
+                items.pop_front();
+            }
+        }
+        unpersisted.retain(|_, items| !items.is_empty());
+        std::mem::take(bytes_to_ack)
+    }
+
+    fn apply(&self, state: &mut ConsumerState, message: &DcpResponse) -> EngineResult<()> {
+        let Some(vbid) = message.vbid() else {
+            return Ok(());
+        };
+        let vb = self
+            .bucket
+            .get_vbucket(vbid)
+            .ok_or(EngineError::NotMyVbucket)?;
+        match message {
+            DcpResponse::SnapshotMarker {
+                start_seqno,
+                end_seqno,
+                ..
+            } => vb.create_snapshot(*start_seqno, *end_seqno)?,
+            DcpResponse::Mutation { item, .. }
+            | DcpResponse::Deletion { item, .. }
+            | DcpResponse::Expiration { item, .. }
+            | DcpResponse::SystemEvent { item, .. } => {
+                vb.set_with_meta(Item::clone(item))?;
+                state
+                    .unpersisted
+                    .entry(vbid)
+                    .or_default()
+                    .push_back((item.by_seqno, message.message_size()));
+                return Ok(());
+            }
+            DcpResponse::StreamEnd { status, .. } => {
+                println!("{} ({}) Stream ended with {:?}", self.name, vbid, status);
+            }
+            DcpResponse::SetVBucketState { state, .. } => {
+                // Writes accepted from now on are on a new branch of history
+                if *state == State::Active {
+                    vb.failover_table.create_entry(vb.get_high_seqno());
+                }
+                println!(
+                    "{} ({}) Changing vbucket state to {:?} for takeover",
+                    self.name, vbid, state
+                );
+                self.bucket.set_vbucket_state(vbid, *state)?;
+            }
+            DcpResponse::Noop { .. } => {}
+        }
+        state.bytes_to_ack += message.message_size();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{
+        checkpoint_manager::QueuedItem,
+        collections::{self, FIRST_USER_COLLECTION},
+        dcp::{
+            producer::{DcpOpenFlags, DcpProducer, StreamRequest, StreamRequestFlags},
+            response::{SnapshotMarkerFlags, SystemEventId},
+        },
+        ep_bucket::EPBucket,
+        failover_table::FailoverTable,
+        item::{Datatype, DeleteSource},
+        Config,
+    };
+
+    #[test]
+    fn test_replication_throttle() {
+        let dirs = [tempfile::tempdir().unwrap(), tempfile::tempdir().unwrap()];
+        let [active, replica] = dirs.each_ref().map(|dir| {
+            EPBucket::new(Config {
+                max_vbuckets: 4,
+                max_shards: 1,
+                dbname: dir.path().to_str().unwrap().to_string(),
+                ..Default::default()
+            })
+        });
+        let vbid = Vbid::from(0usize);
+        for (bucket, state) in [(&active, State::Active), (&replica, State::Replica)] {
+            bucket.vbucket_map.add_bucket(bucket.make_vbucket(
+                vbid,
+                state,
+                FailoverTable::new_empty(25),
+                0,
+                0,
+            ));
+        }
+        let vb = active.get_vbucket(vbid).unwrap();
+        let set = |key: &str| {
+            vb.set(Item {
+                key: key.as_bytes().to_vec(),
+                value: Some(vec![0; 50]),
+                cas: 0,
+                expiry_time: 0,
+                flags: 0,
+                by_seqno: 0,
+                rev_seqno: 0,
+                delete_source: DeleteSource::Explicit,
+                datatype: Datatype::empty(),
+            })
+            .unwrap();
+        };
+        set("a");
+
+        let producer = DcpProducer::new("replica", DcpOpenFlags::empty(), active.clone());
+        producer.control("connection_buffer_size", "300").unwrap();
+        let consumer = DcpConsumer::new("replica", replica.clone());
+        let req = StreamRequest {
+            flags: StreamRequestFlags::empty(),
+            start_seqno: 0,
+            end_seqno: u64::MAX,
+            vb_uuid: 0,
+            snap_start_seqno: 0,
+            snap_end_seqno: 0,
+        };
+        producer.stream_request(1, vbid, req, None).unwrap();
+        let mut sizes = Vec::new();
+        let mut pump = || {
+            sizes.clear();
+            while let Some(message) = producer.step().unwrap() {
+                consumer.handle(&message).unwrap();
+                sizes.push(message.message_size());
+            }
+            sizes.clone()
+        };
+
+        // The item is only acknowledged once persisted
+        let [marker, mutation] = pump()[..] else {
+            panic!("expected a marker and a mutation");
+        };
+        assert_eq!(consumer.take_buffer_acknowledgement(), marker);
+        replica.flush_vbucket(vbid);
+        assert_eq!(consumer.take_buffer_acknowledgement(), mutation);
+        producer.buffer_acknowledgement(marker + mutation);
+
+        // Short of memory the replica buffers what it receives and holds
+        // back the acknowledgements, until the producer's buffer is full
+        let config = replica.config();
+        replica
+            .stats()
+            .set_max_data_size(1, config.mem_low_wat, config.mem_high_wat);
+        assert!(replica.is_replication_throttled());
+        for key in ["b", "c", "d", "e", "f"] {
+            set(key);
+        }
+        assert_eq!(pump().len(), 4);
+        assert!(producer.is_paused());
+        assert_eq!(consumer.num_buffered(), 4);
+        assert_eq!(consumer.process_buffered().unwrap(), 0);
+        assert_eq!(consumer.take_buffer_acknowledgement(), 0);
+        let replica_vb = replica.get_vbucket(vbid).unwrap();
+        assert_eq!(replica_vb.get_high_seqno(), 1);
+
+        replica
+            .stats()
+            .set_max_data_size(config.max_size, config.mem_low_wat, config.mem_high_wat);
+        assert_eq!(consumer.process_buffered().unwrap(), 4);
+        assert_eq!(replica_vb.get_high_seqno(), 4);
+        replica.flush_vbucket(vbid);
+        producer.buffer_acknowledgement(consumer.take_buffer_acknowledgement());
+        assert_eq!(pump().len(), 2);
+        assert_eq!(replica_vb.get_high_seqno(), 6);
+    }
+
+    #[test]
+    fn test_collection_max_ttl() {
+        let dirs = [tempfile::tempdir().unwrap(), tempfile::tempdir().unwrap()];
+        let [active, replica] = dirs.each_ref().map(|dir| {
+            EPBucket::new(Config {
+                max_vbuckets: 4,
+                max_shards: 1,
+                dbname: dir.path().to_str().unwrap().to_string(),
+                ..Default::default()
+            })
+        });
+        let vbid = Vbid::from(0usize);
+        for (bucket, state) in [(&active, State::Active), (&replica, State::Replica)] {
+            bucket.vbucket_map.add_bucket(bucket.make_vbucket(
+                vbid,
+                state,
+                FailoverTable::new_empty(25),
+                0,
+                0,
+            ));
+        }
+        let key = |key: &str| collections::make_key(FIRST_USER_COLLECTION, key.as_bytes());
+        let item = |key: Vec<u8>, expiry_time: u32, by_seqno: u64| Item {
+            key,
+            value: Some(b"value".to_vec()),
+            cas: by_seqno,
+            expiry_time,
+            flags: 0,
+            by_seqno,
+            rev_seqno: 1,
+            delete_source: DeleteSource::Explicit,
+            datatype: Datatype::empty(),
+        };
+
+        // The maxTTL is added while the collection is being written to
+        let vb = active.get_vbucket(vbid).unwrap();
+        let now = vb.now_secs();
+        active
+            .create_collection(FIRST_USER_COLLECTION, 0, "sessions", 0)
+            .unwrap();
+        vb.set(item(key("a"), 0, 0)).unwrap();
+        active
+            .set_collection_max_ttl(FIRST_USER_COLLECTION, 100)
+            .unwrap();
+        vb.set(item(key("b"), 0, 0)).unwrap();
+        vb.set(item(key("c"), now + 10, 0)).unwrap();
+        assert_eq!(
+            active.set_collection_max_ttl(FIRST_USER_COLLECTION + 1, 100),
+            Err(EngineError::UnknownCollection)
+        );
+
+        let producer = DcpProducer::new("replica", DcpOpenFlags::empty(), active.clone());
+        let consumer = DcpConsumer::new("replica", replica.clone());
+        let req = StreamRequest {
+            flags: StreamRequestFlags::empty(),
+            start_seqno: 0,
+            end_seqno: u64::MAX,
+            vb_uuid: 0,
+            snap_start_seqno: 0,
+            snap_end_seqno: 0,
+        };
+        producer.stream_request(1, vbid, req, None).unwrap();
+        let mut events = Vec::new();
+        while let Some(message) = producer.step().unwrap() {
+            if let DcpResponse::SystemEvent { event, .. } = &message {
+                events.push(*event);
+            }
+            consumer.handle(&message).unwrap();
+        }
+        assert_eq!(
+            events,
+            [
+                SystemEventId::CreateCollection,
+                SystemEventId::ModifyCollection
+            ]
+        );
+
+        // The replica's manifest has followed the stream
+        let replica_vb = replica.get_vbucket(vbid).unwrap();
+        let expiry = |key: &str| {
+            replica_vb
+                .get(&collections::make_key(
+                    FIRST_USER_COLLECTION,
+                    key.as_bytes(),
+                ))
+                .unwrap()
+                .expiry_time
+        };
+        assert_eq!(expiry("a"), 0);
+        assert!((now + 100..=now + 101).contains(&expiry("b")));
+        assert_eq!(expiry("c"), now + 10);
+        assert_eq!(
+            replica_vb
+                .get_collection(FIRST_USER_COLLECTION)
+                .unwrap()
+                .max_ttl,
+            100
+        );
+
+        // A replicated mutation without an expiry gets the maxTTL too
+        let seqno = vb.get_high_seqno() + 1;
+        for message in [
+            DcpResponse::SnapshotMarker {
+                opaque: 1,
+                vbid,
+                start_seqno: seqno,
+                end_seqno: seqno,
+                flags: SnapshotMarkerFlags::MEMORY,
+            },
+            DcpResponse::Mutation {
+                opaque: 1,
+                vbid,
+                item: QueuedItem::new(item(key("d"), 0, seqno)),
+            },
+        ] {
+            consumer.handle(&message).unwrap();
+        }
+        assert!((now + 100..=now + 101).contains(&expiry("d")));
+    }
+}
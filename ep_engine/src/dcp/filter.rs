@@ -0,0 +1,104 @@
+use serde::Deserialize;
+use std::collections::HashSet;
+
+use crate::{
+    collections::{self, CollectionId, DEFAULT_COLLECTION, DEFAULT_SCOPE},
+    error::{EngineError, EngineResult},
+};
+
+/// The JSON filter a client may send with a stream request, IDs are hex
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct FilterJson {
+    collections: Option<Vec<String>>,
+    scope: Option<String>,
+}
+
+/// Selects the collections a stream sends items for
+#[derive(Debug, Clone, Default)]
+pub struct CollectionFilter {
+    /// None if every collection is streamed
+    collections: Option<HashSet<CollectionId>>,
+}
+
+impl CollectionFilter {
+    /// Parse the stream request's filter, no filter streams everything
+    pub fn new(json: Option<&str>) -> EngineResult<Self> {
+        let json = match json {
+            Some(json) if !json.is_empty() => json,
+            _ => return Ok(Self::default()),
+        };
+        let filter: FilterJson =
+            serde_json::from_str(json).map_err(|_| EngineError::InvalidArguments)?;
+
+        let collections = match (filter.collections, filter.scope) {
+            (Some(ids), None) => ids
+                .iter()
+                .map(|id| parse_id(id))
+                .collect::<EngineResult<HashSet<_>>>()?,
+            (None, Some(scope)) => {
+                // Without a manifest the default scope, holding only the
+                // default collection, is the only scope known
+                if parse_id(&scope)? != DEFAULT_SCOPE {
+                    return Err(EngineError::UnknownScope);
+                }
+                HashSet::from([DEFAULT_COLLECTION])
+            }
+            _ => return Err(EngineError::InvalidArguments),
+        };
+        Ok(Self {
+            collections: Some(collections),
+        })
+    }
+
+    pub fn is_pass_through(&self) -> bool {
+        self.collections.is_none()
+    }
+
+    /// Should the item with this key be sent. A system event is sent if
+    /// the collection it's for is.
+    pub fn check_key(&self, key: &[u8]) -> bool {
+        match &self.collections {
+            None => true,
+            Some(collections) => collections::collection_event_id(key)
+                .or_else(|| collections::collection_id(key))
+                .is_some_and(|id| collections.contains(&id)),
+        }
+    }
+}
+
+fn parse_id(id: &str) -> EngineResult<u32> {
+    u32::from_str_radix(id, 16).map_err(|_| EngineError::InvalidArguments)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_collection_filter() {
+        let filter = CollectionFilter::new(None).unwrap();
+        assert!(filter.is_pass_through());
+
+        let filter = CollectionFilter::new(Some(r#"{"collections":["0","8a"]}"#)).unwrap();
+        assert!(filter.check_key(b"\0key"));
+        // 0x8a as LEB128
+        assert!(filter.check_key(b"\x8a\x01key"));
+        assert!(!filter.check_key(b"\x08key"));
+        assert!(filter.check_key(&collections::collection_event_key(0x8a)));
+        assert!(!filter.check_key(&collections::collection_event_key(0x08)));
+
+        let filter = CollectionFilter::new(Some(r#"{"scope":"0"}"#)).unwrap();
+        assert!(filter.check_key(b"\0key"));
+        assert!(!filter.check_key(b"\x08key"));
+
+        assert_eq!(
+            CollectionFilter::new(Some(r#"{"scope":"8"}"#)).unwrap_err(),
+            EngineError::UnknownScope
+        );
+        assert_eq!(
+            CollectionFilter::new(Some(r#"{"collections":["0"],"scope":"0"}"#)).unwrap_err(),
+            EngineError::InvalidArguments
+        );
+    }
+}
@@ -0,0 +1,102 @@
+use std::time::{Duration, Instant};
+
+/// Tracks the bytes a producer has sent which the client has not yet
+/// acknowledged. Once the client's buffer is full the producer pauses until
+/// the client acknowledges some of it.
+#[derive(Debug, Default)]
+pub struct BufferLog {
+    /// Size of the client's buffer, 0 if flow control is disabled
+    max_bytes: usize,
+    bytes_outstanding: usize,
+    total_acked_bytes: u64,
+}
+
+impl BufferLog {
+    pub fn is_enabled(&self) -> bool {
+        self.max_bytes > 0
+    }
+
+    pub fn set_buffer_size(&mut self, max_bytes: usize) {
+        self.max_bytes = max_bytes;
+    }
+
+    pub fn is_full(&self) -> bool {
+        self.is_enabled() && self.bytes_outstanding >= self.max_bytes
+    }
+
+    /// Record a message sent to the client
+    pub fn insert(&mut self, bytes: usize) {
+        if self.is_enabled() {
+            self.bytes_outstanding += bytes;
+        }
+    }
+
+    /// Record bytes the client has processed. Returns true if the buffer
+    /// was full and now has space.
+    pub fn acknowledge(&mut self, bytes: usize) -> bool {
+        let was_full = self.is_full();
+        // The client may ack bytes sent before flow control was enabled
+        self.bytes_outstanding = self.bytes_outstanding.saturating_sub(bytes);
+        self.total_acked_bytes += bytes as u64;
+        was_full && !self.is_full()
+    }
+
+    pub fn bytes_outstanding(&self) -> usize {
+        self.bytes_outstanding
+    }
+
+    pub fn total_acked_bytes(&self) -> u64 {
+        self.total_acked_bytes
+    }
+
+    pub fn max_bytes(&self) -> usize {
+        self.max_bytes
+    }
+}
+
+/// No-ops let both ends of an otherwise idle connection detect that the
+/// other has gone away
+#[derive(Debug)]
+pub struct NoopContext {
+    pub enabled: bool,
+    pub interval: Duration,
+    /// When the last no-op was sent
+    send_time: Instant,
+    opaque: u32,
+    /// A no-op has been sent and the client hasn't responded yet
+    pending_recv: bool,
+}
+
+impl NoopContext {
+    pub fn new(interval: Duration, now: Instant) -> Self {
+        Self {
+            enabled: false,
+            interval,
+            send_time: now,
+            opaque: 0,
+            pending_recv: false,
+        }
+    }
+
+    /// Returns the opaque of a no-op to send if one is due. Only one no-op
+    /// is outstanding at a time.
+    pub fn maybe_send(&mut self, now: Instant) -> Option<u32> {
+        if !self.enabled || self.pending_recv || now < self.send_time + self.interval {
+            return None;
+        }
+        self.opaque = self.opaque.wrapping_add(1);
+        self.send_time = now;
+        self.pending_recv = true;
+        Some(self.opaque)
+    }
+
+    /// The client responded to a no-op. Returns false if the response
+    /// doesn't match the outstanding no-op.
+    pub fn received(&mut self, opaque: u32) -> bool {
+        if !self.pending_recv || opaque != self.opaque {
+            return false;
+        }
+        self.pending_recv = false;
+        true
+    }
+}
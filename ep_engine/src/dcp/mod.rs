@@ -0,0 +1,7 @@
+pub mod backfill;
+pub mod consumer;
+pub mod filter;
+pub mod flow_control;
+pub mod producer;
+pub mod response;
+pub mod stream;
@@ -0,0 +1,1104 @@
+use bitflags::bitflags;
+use parking_lot::Mutex;
+use std::{
+    collections::BTreeMap,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use crate::{
+    dcp::{
+        backfill::BackfillBudget,
+        filter::CollectionFilter,
+        flow_control::{BufferLog, NoopContext},
+        response::{DcpResponse, EndStreamStatus},
+        stream::{ActiveStream, StreamOptions, ValueMode},
+    },
+    ep_bucket::EPBucketPtr,
+    error::{EngineError, EngineResult},
+    failover_table::FailoverEntry,
+    vbucket::{State, Vbid},
+};
+
+bitflags! {
+    #[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Hash)]
+    pub struct StreamRequestFlags: u32 {
+        const TAKEOVER = 0x01;
+        const DISK_ONLY = 0x02;
+        /// Stream up to the vbucket's current high seqno
+        const LATEST = 0x04;
+        /// Mutations carry only their key, seqnos and CAS
+        const KEY_ONLY = 0x08;
+        const ACTIVE_ONLY = 0x10;
+        /// Check the client's vb_uuid even when it starts from seqno 0
+        const STRICT_VB_UUID = 0x20;
+    }
+}
+
+/// A client's request to stream a vbucket's mutations, resuming from the
+/// point it reached on the branch of history identified by vb_uuid
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StreamRequest {
+    pub flags: StreamRequestFlags,
+    pub start_seqno: u64,
+    pub end_seqno: u64,
+    pub vb_uuid: u64,
+    pub snap_start_seqno: u64,
+    pub snap_end_seqno: u64,
+}
+
+bitflags! {
+    /// Flags given by the client when opening the connection
+    #[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Hash)]
+    pub struct DcpOpenFlags: u32 {
+        /// Mutations are sent without their values
+        const NO_VALUE = 0x08;
+        /// Deletions carry the time the item was deleted
+        const INCLUDE_DELETE_TIMES = 0x20;
+    }
+}
+
+/// The server side of a DCP connection, streaming mutations to a client
+pub struct DcpProducer {
+    name: String,
+    flags: DcpOpenFlags,
+    bucket: EPBucketPtr,
+    state: Mutex<ProducerState>,
+    /// Bounds the items the streams' backfills read ahead of sending
+    backfill_budget: Arc<BackfillBudget>,
+}
+
+struct ProducerState {
+    streams: BTreeMap<Vbid, ActiveStream>,
+    /// Streams are sent from in turn, starting after the last one sent from
+    last_sent_vbid: Option<Vbid>,
+    buffer_log: BufferLog,
+    /// The client's buffer was full the last time the producer stepped
+    paused: bool,
+    noop: NoopContext,
+    last_receive_time: Instant,
+    idle_timeout: Duration,
+    enable_expiry_opcode: bool,
+    /// Mutations, deletions and system events sent
+    items_sent: u64,
+    /// Bytes of the messages sent, not counting no-ops
+    bytes_sent: u64,
+}
+
+impl DcpProducer {
+    /// Open a producer, which is listed in the bucket's dcp stats until it
+    /// is dropped
+    pub fn new(name: impl Into<String>, flags: DcpOpenFlags, bucket: EPBucketPtr) -> Arc<Self> {
+        let now = Instant::now();
+        let config = bucket.config();
+        let state = ProducerState {
+            streams: BTreeMap::new(),
+            last_sent_vbid: None,
+            buffer_log: BufferLog::default(),
+            paused: false,
+            noop: NoopContext::new(Duration::from_secs(config.dcp_noop_tx_interval), now),
+            last_receive_time: now,
+            idle_timeout: Duration::from_secs(config.dcp_idle_timeout),
+            enable_expiry_opcode: false,
+            items_sent: 0,
+            bytes_sent: 0,
+        };
+        let producer = Arc::new(Self {
+            name: name.into(),
+            flags,
+            bucket: bucket.clone(),
+            state: Mutex::new(state),
+            backfill_budget: Arc::new(BackfillBudget::new(config.dcp_backfill_byte_limit)),
+        });
+        bucket.register_dcp_producer(&producer);
+        producer
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Handle a DCP control message from the client
+    pub fn control(&self, key: &str, value: &str) -> EngineResult<()> {
+        let mut state = self.state.lock();
+        state.last_receive_time = Instant::now();
+        match key {
+            "connection_buffer_size" => {
+                let size = value.parse().map_err(|_| EngineError::InvalidArguments)?;
+                state.buffer_log.set_buffer_size(size);
+            }
+            "enable_noop" => {
+                state.noop.enabled = value == "true";
+            }
+            "set_noop_interval" => {
+                let interval: u64 = value.parse().map_err(|_| EngineError::InvalidArguments)?;
+                if interval == 0 {
+                    return Err(EngineError::InvalidArguments);
+                }
+                state.noop.interval = Duration::from_secs(interval);
+            }
+            "enable_expiry_opcode" => {
+                // Expirations are encoded like V2 deletions so need the
+                // delete times
+                let enable = value == "true";
+                if enable && !self.flags.contains(DcpOpenFlags::INCLUDE_DELETE_TIMES) {
+                    return Err(EngineError::InvalidArguments);
+                }
+                state.enable_expiry_opcode = enable;
+            }
+            "backfill_byte_limit" => {
+                let limit: usize = value.parse().map_err(|_| EngineError::InvalidArguments)?;
+                if limit == 0 {
+                    return Err(EngineError::InvalidArguments);
+                }
+                self.backfill_budget.set_max_bytes(limit);
+            }
+            // Accepted for compatibility, all connections have the same
+            // priority
+            "set_priority" => {}
+            _ => {
+                println!(
+                    "{} Invalid ctrl parameter '{}' for DCP producer",
+                    self.name, key
+                );
+                return Err(EngineError::InvalidArguments);
+            }
+        }
+        Ok(())
+    }
+
+    /// The client has processed the given number of bytes
+    pub fn buffer_acknowledgement(&self, bytes: usize) {
+        let mut state = self.state.lock();
+        state.last_receive_time = Instant::now();
+        state.buffer_log.acknowledge(bytes);
+    }
+
+    /// The client responded to a no-op
+    pub fn noop_response(&self, opaque: u32) {
+        let mut state = self.state.lock();
+        state.last_receive_time = Instant::now();
+        state.noop.received(opaque);
+    }
+
+    /// Is the producer waiting for the client to acknowledge data before
+    /// sending more
+    pub fn is_paused(&self) -> bool {
+        self.state.lock().paused
+    }
+
+    /// The next message to send to the client, if any. Fails with
+    /// Disconnect if the client has stopped responding.
+    pub fn step(&self) -> EngineResult<Option<DcpResponse>> {
+        self.step_at(Instant::now())
+    }
+
+    fn step_at(&self, now: Instant) -> EngineResult<Option<DcpResponse>> {
+        let mut state = self.state.lock();
+
+        if state.noop.enabled && now > state.last_receive_time + state.idle_timeout {
+            println!(
+                "{} Disconnecting because a message has not been received for the DCP idle timeout of {}s",
+                self.name,
+                state.idle_timeout.as_secs()
+            );
+            return Err(EngineError::Disconnect);
+        }
+
+        if let Some(opaque) = state.noop.maybe_send(now) {
+            return Ok(Some(DcpResponse::Noop { opaque }));
+        }
+
+        if state.buffer_log.is_full() {
+            state.paused = true;
+            return Ok(None);
+        }
+        state.paused = false;
+
+        let response = self.next_response(&mut state);
+        if let Some(response) = &response {
+            state.buffer_log.insert(response.message_size());
+            state.bytes_sent += response.message_size() as u64;
+            if response.item().is_some() {
+                state.items_sent += 1;
+            }
+        }
+        Ok(response)
+    }
+
+    /// Take the next message from the streams in turn
+    fn next_response(&self, state: &mut ProducerState) -> Option<DcpResponse> {
+        let vbids: Vec<Vbid> = match state.last_sent_vbid {
+            Some(last) => state
+                .streams
+                .range(last..)
+                .skip(1)
+                .chain(state.streams.range(..=last))
+                .map(|(&vbid, _)| vbid)
+                .collect(),
+            None => state.streams.keys().copied().collect(),
+        };
+
+        for vbid in vbids {
+            let stream = state.streams.get_mut(&vbid).unwrap();
+            let response = match self.bucket.get_vbucket(vbid) {
+                Some(vb) => stream.next(&vb, &self.bucket),
+                None => None,
+            };
+            if stream.is_dead() && response.is_none() {
+                state.streams.remove(&vbid);
+                continue;
+            }
+            if response.is_some() {
+                state.last_sent_vbid = Some(vbid);
+                return response;
+            }
+        }
+        None
+    }
+
+    /// Close the stream for the vbucket at the client's request
+    pub fn close_stream(&self, vbid: Vbid) -> EngineResult<()> {
+        let mut state = self.state.lock();
+        let mut stream = state
+            .streams
+            .remove(&vbid)
+            .ok_or(EngineError::KeyNotFound)?;
+        if let Some(vb) = self.bucket.get_vbucket(vbid) {
+            stream.end_stream(&vb, EndStreamStatus::Closed);
+        }
+        Ok(())
+    }
+
+    /// The client has applied the vbucket state sent by the takeover stream
+    /// with the opaque
+    pub fn set_vbucket_state_response(&self, opaque: u32, vbid: Vbid) -> EngineResult<()> {
+        let mut state = self.state.lock();
+        state.last_receive_time = Instant::now();
+        let vb = self
+            .bucket
+            .get_vbucket(vbid)
+            .ok_or(EngineError::NotMyVbucket)?;
+        let stream = state
+            .streams
+            .get_mut(&vbid)
+            .filter(|stream| stream.opaque() == opaque)
+            .ok_or(EngineError::KeyNotFound)?;
+        stream.set_vbucket_state_ack(&vb, &self.bucket)
+    }
+
+    pub fn num_streams(&self) -> usize {
+        self.state.lock().streams.len()
+    }
+
+    /// The connection's stats as eq_dcpq:<name>:<stat>, and each stream's
+    /// as eq_dcpq:<name>:stream_<vbid>_<field>
+    pub fn add_stats(&self, add_stat: &mut dyn FnMut(&str, &str)) {
+        let state = self.state.lock();
+        let mut stat = |key: &str, value: &str| {
+            add_stat(&format!("eq_dcpq:{}:{key}", self.name), value);
+        };
+        stat("type", "producer");
+        stat("num_streams", &state.streams.len().to_string());
+        stat("paused", &state.paused.to_string());
+        stat("items_sent", &state.items_sent.to_string());
+        stat("total_bytes_sent", &state.bytes_sent.to_string());
+        stat("flow_control", &state.buffer_log.is_enabled().to_string());
+        stat(
+            "max_buffer_bytes",
+            &state.buffer_log.max_bytes().to_string(),
+        );
+        stat(
+            "unacked_bytes",
+            &state.buffer_log.bytes_outstanding().to_string(),
+        );
+        stat(
+            "total_acked_bytes",
+            &state.buffer_log.total_acked_bytes().to_string(),
+        );
+        stat("noop_enabled", &state.noop.enabled.to_string());
+        stat(
+            "backfill_byte_limit",
+            &self.backfill_budget.max_bytes().to_string(),
+        );
+        stat(
+            "backfill_buffer_bytes",
+            &self.backfill_budget.bytes().to_string(),
+        );
+        stat(
+            "backfill_pauses",
+            &self.backfill_budget.pauses().to_string(),
+        );
+        for (&vbid, stream) in &state.streams {
+            let vb = self.bucket.get_vbucket(vbid);
+            stream.add_stats(vb.as_deref(), &mut |field, value| {
+                stat(&format!("stream_{}_{field}", u16::from(vbid)), value);
+            });
+        }
+    }
+
+    /// Validate a stream request against the vbucket's failover table. On
+    /// success returns the failover log, which the client stores so it can
+    /// resume the stream later. If the client's history has diverged from
+    /// ours fails with the seqno it must roll back to. The optional JSON
+    /// filter selects the collections to stream.
+    pub fn stream_request(
+        &self,
+        opaque: u32,
+        vbid: Vbid,
+        mut req: StreamRequest,
+        filter: Option<&str>,
+    ) -> EngineResult<Vec<FailoverEntry>> {
+        let vb = self
+            .bucket
+            .get_vbucket(vbid)
+            .ok_or(EngineError::NotMyVbucket)?;
+        let filter = CollectionFilter::new(filter)?;
+
+        let mut state = self.state.lock();
+        state.last_receive_time = Instant::now();
+        if state
+            .streams
+            .get(&vbid)
+            .is_some_and(|stream| !stream.is_dead())
+        {
+            return Err(EngineError::KeyExists);
+        }
+
+        // Only the active vbucket can be taken over
+        if req
+            .flags
+            .intersects(StreamRequestFlags::ACTIVE_ONLY | StreamRequestFlags::TAKEOVER)
+            && vb.state() != State::Active
+        {
+            return Err(EngineError::NotMyVbucket);
+        }
+
+        let high_seqno = vb.get_high_seqno();
+        if req.flags.contains(StreamRequestFlags::LATEST) {
+            req.end_seqno = high_seqno;
+        }
+        // A takeover stream runs until the vbucket is handed over
+        if req.flags.contains(StreamRequestFlags::TAKEOVER) {
+            req.end_seqno = u64::MAX;
+        }
+
+        if req.start_seqno > req.end_seqno {
+            return Err(EngineError::OutOfRange);
+        }
+        if !(req.snap_start_seqno <= req.start_seqno && req.start_seqno <= req.snap_end_seqno) {
+            return Err(EngineError::OutOfRange);
+        }
+
+        if let Some((rollback_seqno, reason)) = vb.failover_table.needs_rollback(
+            req.start_seqno,
+            high_seqno,
+            req.vb_uuid,
+            req.snap_start_seqno,
+            req.snap_end_seqno,
+            vb.get_purge_seqno(),
+            req.flags.contains(StreamRequestFlags::STRICT_VB_UUID),
+        ) {
+            println!(
+                "{} ({}) Stream request requires rollback to seqno:{} because {}. Client requested seqnos:{{{},{}}} snapshot:{{{},{}}} uuid:{}",
+                self.name,
+                vbid,
+                rollback_seqno,
+                reason,
+                req.start_seqno,
+                req.end_seqno,
+                req.snap_start_seqno,
+                req.snap_end_seqno,
+                req.vb_uuid
+            );
+            return Err(EngineError::Rollback(rollback_seqno));
+        }
+
+        let options = StreamOptions {
+            include_delete_times: self.flags.contains(DcpOpenFlags::INCLUDE_DELETE_TIMES),
+            enable_expiry_opcode: state.enable_expiry_opcode,
+            value_mode: if req.flags.contains(StreamRequestFlags::KEY_ONLY) {
+                ValueMode::KeyOnly
+            } else if self.flags.contains(DcpOpenFlags::NO_VALUE) {
+                ValueMode::NoValue
+            } else {
+                ValueMode::All
+            },
+        };
+        let stream = ActiveStream::new(
+            &self.name,
+            opaque,
+            &vb,
+            &req,
+            &self.bucket,
+            options,
+            filter,
+            self.backfill_budget.clone(),
+        );
+        state.streams.insert(vbid, stream);
+
+        Ok(vb.failover_table.get_failover_log())
+    }
+}
+
+impl Drop for DcpProducer {
+    fn drop(&mut self) {
+        for (vbid, mut stream) in std::mem::take(&mut self.state.lock().streams) {
+            if let Some(vb) = self.bucket.get_vbucket(vbid) {
+                stream.end_stream(&vb, EndStreamStatus::Disconnected);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{
+        dcp::{consumer::DcpConsumer, response::SystemEventId},
+        ep_bucket::EPBucket,
+        failover_table::FailoverTable,
+        item::{Datatype, DeleteSource, Item},
+        vbucket::VBucketState,
+        Config,
+    };
+    use std::collections::HashMap;
+
+    #[test]
+    fn test_stream_request() {
+        let dir = tempfile::tempdir().unwrap();
+        let bucket = EPBucket::new(Config {
+            max_vbuckets: 4,
+            max_shards: 1,
+            dbname: dir.path().to_str().unwrap().to_string(),
+            ..Default::default()
+        });
+        let vbid = Vbid::from(0usize);
+        bucket.vbucket_map.add_bucket(bucket.make_vbucket(
+            vbid,
+            State::Active,
+            FailoverTable::new_empty(25),
+            10,
+            0,
+        ));
+        let uuid = bucket
+            .get_vbucket(vbid)
+            .unwrap()
+            .failover_table
+            .get_latest_uuid();
+        let producer = DcpProducer::new("test", DcpOpenFlags::empty(), bucket);
+
+        let req = StreamRequest {
+            flags: StreamRequestFlags::LATEST,
+            start_seqno: 5,
+            end_seqno: 0,
+            vb_uuid: uuid,
+            snap_start_seqno: 5,
+            snap_end_seqno: 5,
+        };
+        let log = producer.stream_request(1, vbid, req, None).unwrap();
+        assert_eq!(log[0].vb_uuid, uuid);
+        producer.close_stream(vbid).unwrap();
+
+        let out_of_snapshot = StreamRequest {
+            snap_start_seqno: 6,
+            snap_end_seqno: 8,
+            ..req
+        };
+        assert_eq!(
+            producer.stream_request(1, vbid, out_of_snapshot, None),
+            Err(EngineError::OutOfRange)
+        );
+
+        // The client received part of a snapshot we never had
+        let ahead = StreamRequest {
+            flags: StreamRequestFlags::empty(),
+            start_seqno: 12,
+            end_seqno: u64::MAX,
+            snap_start_seqno: 9,
+            snap_end_seqno: 15,
+            ..req
+        };
+        assert_eq!(
+            producer.stream_request(1, vbid, ahead, None),
+            Err(EngineError::Rollback(9))
+        );
+    }
+
+    #[test]
+    fn test_flow_control() {
+        let dir = tempfile::tempdir().unwrap();
+        let bucket = EPBucket::new(Config {
+            max_vbuckets: 4,
+            max_shards: 1,
+            dbname: dir.path().to_str().unwrap().to_string(),
+            ..Default::default()
+        });
+        let vbid = Vbid::from(0usize);
+        bucket.vbucket_map.add_bucket(bucket.make_vbucket(
+            vbid,
+            State::Active,
+            FailoverTable::new_empty(25),
+            0,
+            0,
+        ));
+        let vb = bucket.get_vbucket(vbid).unwrap();
+        for i in 0..3 {
+            vb.set(Item {
+                key: format!("key_{i}").into_bytes(),
+                value: Some(vec![0; 50]),
+                cas: 0,
+                expiry_time: 0,
+                flags: 0,
+                by_seqno: 0,
+                rev_seqno: 0,
+                delete_source: DeleteSource::Explicit,
+                datatype: Datatype::empty(),
+            })
+            .unwrap();
+        }
+        let producer = DcpProducer::new("test", DcpOpenFlags::empty(), bucket);
+        producer.control("connection_buffer_size", "100").unwrap();
+        assert_eq!(
+            producer.control("unknown", "true"),
+            Err(EngineError::InvalidArguments)
+        );
+
+        let req = StreamRequest {
+            flags: StreamRequestFlags::empty(),
+            start_seqno: 0,
+            end_seqno: u64::MAX,
+            vb_uuid: 0,
+            snap_start_seqno: 0,
+            snap_end_seqno: 0,
+        };
+        producer.stream_request(1, vbid, req, None).unwrap();
+        assert_eq!(
+            producer.stream_request(1, vbid, req, None),
+            Err(EngineError::KeyExists)
+        );
+
+        let marker = producer.step().unwrap().unwrap();
+        assert!(matches!(marker, DcpResponse::SnapshotMarker { .. }));
+        let mutation = producer.step().unwrap().unwrap();
+        assert_eq!(mutation.by_seqno(), Some(1));
+
+        // The client's buffer is full until it acknowledges what it received
+        assert!(producer.step().unwrap().is_none());
+        assert!(producer.is_paused());
+        producer.buffer_acknowledgement(marker.message_size() + mutation.message_size());
+        assert_eq!(producer.step().unwrap().unwrap().by_seqno(), Some(2));
+        assert!(!producer.is_paused());
+
+        producer.control("enable_noop", "true").unwrap();
+        producer.control("set_noop_interval", "1").unwrap();
+        let now = Instant::now();
+        let Some(DcpResponse::Noop { opaque }) =
+            producer.step_at(now + Duration::from_secs(2)).unwrap()
+        else {
+            panic!("expected a no-op");
+        };
+        producer.noop_response(opaque);
+
+        // A client which stops responding is disconnected
+        assert!(matches!(
+            producer.step_at(now + Duration::from_secs(400)),
+            Err(EngineError::Disconnect)
+        ));
+    }
+
+    #[test]
+    fn test_deletion_output() {
+        let dir = tempfile::tempdir().unwrap();
+        let bucket = EPBucket::new(Config {
+            max_vbuckets: 4,
+            max_shards: 1,
+            dbname: dir.path().to_str().unwrap().to_string(),
+            ..Default::default()
+        });
+        let vbid = Vbid::from(0usize);
+        let item = |seqno: u64, delete_source| {
+            Arc::new(Item {
+                key: format!("key_{seqno}").into_bytes(),
+                value: None,
+                cas: seqno,
+                expiry_time: 1000 + seqno as u32,
+                flags: 0,
+                by_seqno: seqno,
+                rev_seqno: 1,
+                delete_source,
+                datatype: Datatype::empty(),
+            })
+        };
+        // Only on disk, so the stream backfills the deletions and their
+        // metadata from the couchstore file
+        let items = [item(1, DeleteSource::Explicit), item(2, DeleteSource::Ttl)];
+        let store = bucket.vbucket_map.get_shard_by_vb_id(vbid).store();
+        store
+            .commit(vbid, &items, &VBucketState::new(State::Active))
+            .unwrap();
+        bucket.vbucket_map.add_bucket(bucket.make_vbucket(
+            vbid,
+            State::Active,
+            FailoverTable::new_empty(25),
+            2,
+            2,
+        ));
+
+        let req = StreamRequest {
+            flags: StreamRequestFlags::empty(),
+            start_seqno: 0,
+            end_seqno: 2,
+            vb_uuid: 0,
+            snap_start_seqno: 0,
+            snap_end_seqno: 0,
+        };
+
+        // Without delete times expirations are sent as V1 deletions
+        let producer = DcpProducer::new("v1", DcpOpenFlags::empty(), bucket.clone());
+        assert_eq!(
+            producer.control("enable_expiry_opcode", "true"),
+            Err(EngineError::InvalidArguments)
+        );
+        producer.stream_request(1, vbid, req, None).unwrap();
+        producer.step().unwrap().unwrap();
+        for _ in 0..2 {
+            assert!(matches!(
+                producer.step().unwrap(),
+                Some(DcpResponse::Deletion {
+                    include_delete_time: false,
+                    ..
+                })
+            ));
+        }
+
+        let producer = DcpProducer::new("v2", DcpOpenFlags::INCLUDE_DELETE_TIMES, bucket);
+        producer.control("enable_expiry_opcode", "true").unwrap();
+        producer.stream_request(1, vbid, req, None).unwrap();
+        producer.step().unwrap().unwrap();
+        let Some(DcpResponse::Deletion {
+            item,
+            include_delete_time: true,
+            ..
+        }) = producer.step().unwrap()
+        else {
+            panic!("expected a V2 deletion");
+        };
+        assert_eq!(item.expiry_time, 1001);
+        let Some(DcpResponse::Expiration { item, .. }) = producer.step().unwrap() else {
+            panic!("expected an expiration");
+        };
+        assert_eq!(item.expiry_time, 1002);
+    }
+
+    #[test]
+    fn test_stream_filter() {
+        let dir = tempfile::tempdir().unwrap();
+        let bucket = EPBucket::new(Config {
+            max_vbuckets: 4,
+            max_shards: 1,
+            dbname: dir.path().to_str().unwrap().to_string(),
+            ..Default::default()
+        });
+        let vbid = Vbid::from(0usize);
+        bucket.vbucket_map.add_bucket(bucket.make_vbucket(
+            vbid,
+            State::Active,
+            FailoverTable::new_empty(25),
+            0,
+            0,
+        ));
+        let vb = bucket.get_vbucket(vbid).unwrap();
+        // The default collection and collection 8
+        for key in [&b"\0a"[..], b"\x08b", b"\0c"] {
+            vb.set(Item {
+                key: key.to_vec(),
+                value: Some(vec![0; 50]),
+                cas: 0,
+                expiry_time: 0,
+                flags: 0xdead,
+                by_seqno: 0,
+                rev_seqno: 0,
+                delete_source: DeleteSource::Explicit,
+                datatype: Datatype::empty(),
+            })
+            .unwrap();
+        }
+
+        let producer = DcpProducer::new("test", DcpOpenFlags::empty(), bucket);
+        let req = StreamRequest {
+            flags: StreamRequestFlags::LATEST | StreamRequestFlags::KEY_ONLY,
+            start_seqno: 0,
+            end_seqno: 0,
+            vb_uuid: 0,
+            snap_start_seqno: 0,
+            snap_end_seqno: 0,
+        };
+        assert_eq!(
+            producer.stream_request(1, vbid, req, Some(r#"{"scope":"8"}"#)),
+            Err(EngineError::UnknownScope)
+        );
+        producer
+            .stream_request(1, vbid, req, Some(r#"{"collections":["8"]}"#))
+            .unwrap();
+
+        producer.step().unwrap().unwrap();
+        let Some(DcpResponse::Mutation { item, .. }) = producer.step().unwrap() else {
+            panic!("expected a mutation");
+        };
+        assert_eq!(item.key, b"\x08b");
+        assert_eq!(item.value.as_deref(), Some(&[][..]));
+        assert_eq!(item.flags, 0);
+        assert!(matches!(
+            producer.step().unwrap(),
+            Some(DcpResponse::StreamEnd {
+                status: EndStreamStatus::Ok,
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn test_dcp_stats() {
+        let dir = tempfile::tempdir().unwrap();
+        let bucket = EPBucket::new(Config {
+            max_vbuckets: 4,
+            max_shards: 1,
+            dbname: dir.path().to_str().unwrap().to_string(),
+            ..Default::default()
+        });
+        let vbid = Vbid::from(2usize);
+        let item = |key: &str, by_seqno| Item {
+            key: key.as_bytes().to_vec(),
+            value: Some(vec![0; 50]),
+            cas: 0,
+            expiry_time: 0,
+            flags: 0,
+            by_seqno,
+            rev_seqno: 0,
+            delete_source: DeleteSource::Explicit,
+            datatype: Datatype::empty(),
+        };
+        // Only on disk, so the stream starts by backfilling
+        let items = [item("a", 1), item("b", 2)].map(Arc::new);
+        let store = bucket.vbucket_map.get_shard_by_vb_id(vbid).store();
+        store
+            .commit(vbid, &items, &VBucketState::new(State::Active))
+            .unwrap();
+        bucket.vbucket_map.add_bucket(bucket.make_vbucket(
+            vbid,
+            State::Active,
+            FailoverTable::new_empty(25),
+            2,
+            2,
+        ));
+
+        let stats = |bucket: &EPBucket| {
+            let mut stats = HashMap::new();
+            bucket
+                .get_stats_group("dcp", &mut |key, value| {
+                    stats.insert(key.to_string(), value.to_string());
+                })
+                .unwrap();
+            stats
+        };
+        let producer = DcpProducer::new("replica", DcpOpenFlags::empty(), bucket.clone());
+        let req = StreamRequest {
+            flags: StreamRequestFlags::empty(),
+            start_seqno: 0,
+            end_seqno: u64::MAX,
+            vb_uuid: 0,
+            snap_start_seqno: 0,
+            snap_end_seqno: 0,
+        };
+        producer.stream_request(7, vbid, req, None).unwrap();
+        producer.step().unwrap().unwrap();
+        assert_eq!(producer.step().unwrap().unwrap().by_seqno(), Some(1));
+
+        // The backfill reads ahead on its own task
+        let deadline = Instant::now() + Duration::from_secs(10);
+        let mut before = stats(&bucket);
+        while before["eq_dcpq:replica:stream_2_backfill_buffered_items"] != "1" {
+            assert!(Instant::now() < deadline, "{before:?}");
+            std::thread::sleep(Duration::from_millis(1));
+            before = stats(&bucket);
+        }
+        assert_eq!(before["ep_dcp_count"], "1");
+        assert_eq!(before["eq_dcpq:replica:type"], "producer");
+        assert_eq!(before["eq_dcpq:replica:num_streams"], "1");
+        assert_eq!(before["eq_dcpq:replica:items_sent"], "1");
+        assert_eq!(before["eq_dcpq:replica:stream_2_opaque"], "7");
+        assert_eq!(before["eq_dcpq:replica:stream_2_state"], "backfilling");
+        assert_eq!(before["eq_dcpq:replica:stream_2_last_sent_seqno"], "1");
+        assert_eq!(before["eq_dcpq:replica:stream_2_items_remaining"], "1");
+
+        // Once the backfill is sent the stream reads from memory
+        assert_eq!(producer.step().unwrap().unwrap().by_seqno(), Some(2));
+        assert!(producer.step().unwrap().is_none());
+        let vb = bucket.get_vbucket(vbid).unwrap();
+        vb.set(item("c", 0)).unwrap();
+        let after = stats(&bucket);
+        assert_eq!(after["eq_dcpq:replica:stream_2_state"], "in-memory");
+        assert_eq!(after["eq_dcpq:replica:stream_2_last_sent_seqno"], "2");
+        assert_eq!(after["eq_dcpq:replica:stream_2_items_remaining"], "1");
+        assert!(after.contains_key("eq_dcpq:replica:stream_2_cursor_checkpoint_id"));
+
+        // Closed connections are no longer listed
+        drop(producer);
+        assert_eq!(stats(&bucket)["ep_dcp_count"], "0");
+    }
+
+    #[test]
+    fn test_backfill_byte_limit() {
+        let dir = tempfile::tempdir().unwrap();
+        let bucket = EPBucket::new(Config {
+            max_vbuckets: 4,
+            max_shards: 1,
+            dbname: dir.path().to_str().unwrap().to_string(),
+            ..Default::default()
+        });
+        let vbid = Vbid::from(1usize);
+        let items: Vec<Arc<Item>> = (1..=200)
+            .map(|by_seqno| {
+                Arc::new(Item {
+                    key: format!("key{by_seqno}").into_bytes(),
+                    value: Some(vec![0; 1000]),
+                    cas: 0,
+                    expiry_time: 0,
+                    flags: 0,
+                    by_seqno,
+                    rev_seqno: 0,
+                    delete_source: DeleteSource::Explicit,
+                    datatype: Datatype::empty(),
+                })
+            })
+            .collect();
+        let store = bucket.vbucket_map.get_shard_by_vb_id(vbid).store();
+        store
+            .commit(vbid, &items, &VBucketState::new(State::Active))
+            .unwrap();
+        bucket.vbucket_map.add_bucket(bucket.make_vbucket(
+            vbid,
+            State::Active,
+            FailoverTable::new_empty(25),
+            200,
+            200,
+        ));
+
+        let producer = DcpProducer::new("replica", DcpOpenFlags::empty(), bucket.clone());
+        assert_eq!(
+            producer.control("backfill_byte_limit", "0"),
+            Err(EngineError::InvalidArguments)
+        );
+        producer.control("backfill_byte_limit", "4096").unwrap();
+        let stat = |name: &str| {
+            let mut value = String::new();
+            producer.add_stats(&mut |key, stat| {
+                if key == format!("eq_dcpq:replica:{name}") {
+                    value = stat.to_string();
+                }
+            });
+            value.parse::<u64>().unwrap()
+        };
+        let req = StreamRequest {
+            flags: StreamRequestFlags::empty(),
+            start_seqno: 0,
+            end_seqno: u64::MAX,
+            vb_uuid: 0,
+            snap_start_seqno: 0,
+            snap_end_seqno: 0,
+        };
+        producer.stream_request(1, vbid, req, None).unwrap();
+
+        // With nothing sent the scan reads a few items ahead, then waits
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while stat("backfill_pauses") == 0 {
+            assert!(Instant::now() < deadline);
+            std::thread::sleep(Duration::from_millis(1));
+        }
+        assert_eq!(stat("backfill_byte_limit"), 4096);
+        assert!(stat("backfill_buffer_bytes") <= 4096);
+        assert!(stat("stream_1_backfill_buffered_items") <= 4);
+
+        // Sending resumes it, until the whole snapshot is streamed in order
+        let Some(DcpResponse::SnapshotMarker { end_seqno, .. }) = producer.step().unwrap() else {
+            panic!("expected a snapshot marker");
+        };
+        assert_eq!(end_seqno, 200);
+        for by_seqno in 1..=200 {
+            let response = producer.step().unwrap().unwrap();
+            assert_eq!(response.by_seqno(), Some(by_seqno));
+            assert!(stat("backfill_buffer_bytes") <= 4096);
+        }
+        assert!(producer.step().unwrap().is_none());
+        assert_eq!(stat("backfill_buffer_bytes"), 0);
+        assert!(stat("backfill_pauses") > 1);
+    }
+
+    #[test]
+    fn test_takeover() {
+        let dirs = [tempfile::tempdir().unwrap(), tempfile::tempdir().unwrap()];
+        let [active, pending] = dirs.each_ref().map(|dir| {
+            EPBucket::new(Config {
+                max_vbuckets: 4,
+                max_shards: 1,
+                dbname: dir.path().to_str().unwrap().to_string(),
+                ..Default::default()
+            })
+        });
+        let vbid = Vbid::from(0usize);
+        for (bucket, state) in [(&active, State::Active), (&pending, State::Pending)] {
+            bucket.vbucket_map.add_bucket(bucket.make_vbucket(
+                vbid,
+                state,
+                FailoverTable::new_empty(25),
+                0,
+                0,
+            ));
+        }
+        let item = |key: &str| Item {
+            key: key.as_bytes().to_vec(),
+            value: Some(vec![0; 50]),
+            cas: 0,
+            expiry_time: 0,
+            flags: 0,
+            by_seqno: 0,
+            rev_seqno: 0,
+            delete_source: DeleteSource::Explicit,
+            datatype: Datatype::empty(),
+        };
+        let vb = active.get_vbucket(vbid).unwrap();
+        vb.set(item("a")).unwrap();
+        vb.set(item("b")).unwrap();
+
+        let producer = DcpProducer::new("takeover", DcpOpenFlags::empty(), active.clone());
+        let consumer = DcpConsumer::new("takeover", pending.clone());
+        let req = StreamRequest {
+            flags: StreamRequestFlags::TAKEOVER,
+            start_seqno: 0,
+            end_seqno: 0,
+            vb_uuid: 0,
+            snap_start_seqno: 0,
+            snap_end_seqno: 0,
+        };
+        assert_eq!(
+            producer.stream_request(1, Vbid::from(1usize), req, None),
+            Err(EngineError::NotMyVbucket)
+        );
+        producer.stream_request(1, vbid, req, None).unwrap();
+        // Apply the messages until the stream sends a vbucket state
+        let pump = || loop {
+            let message = producer.step().unwrap()?;
+            consumer.handle(&message).unwrap();
+            if let DcpResponse::SetVBucketState { opaque, state, .. } = message {
+                return Some((opaque, state));
+            }
+        };
+
+        assert_eq!(pump(), Some((1, State::Pending)));
+        assert_eq!(pending.get_vbucket(vbid).unwrap().get_high_seqno(), 2);
+        // Writes are accepted until the client acknowledges
+        vb.set(item("c")).unwrap();
+        assert!(producer.step().unwrap().is_none());
+        producer.set_vbucket_state_response(1, vbid).unwrap();
+        assert_eq!(vb.state(), State::Dead);
+        assert_eq!(vb.set(item("d")), Err(EngineError::NotMyVbucket));
+
+        // What was written meanwhile is sent before the client takes over
+        assert_eq!(pump(), Some((1, State::Active)));
+        let mut stats = HashMap::new();
+        producer.add_stats(&mut |key, value| {
+            stats.insert(key.to_string(), value.to_string());
+        });
+        assert_eq!(stats["eq_dcpq:takeover:stream_0_state"], "takeover-wait");
+        assert_eq!(stats["eq_dcpq:takeover:stream_0_takeover"], "true");
+        producer.set_vbucket_state_response(1, vbid).unwrap();
+        assert!(matches!(
+            producer.step().unwrap(),
+            Some(DcpResponse::StreamEnd {
+                status: EndStreamStatus::Ok,
+                ..
+            })
+        ));
+        assert!(producer.step().unwrap().is_none());
+        assert_eq!(producer.num_streams(), 0);
+        assert_eq!(
+            producer.set_vbucket_state_response(1, vbid),
+            Err(EngineError::KeyNotFound)
+        );
+
+        let new_vb = pending.get_vbucket(vbid).unwrap();
+        assert_eq!(new_vb.state(), State::Active);
+        assert_eq!(new_vb.get_high_seqno(), 3);
+        assert_eq!(new_vb.failover_table.get_failover_log().len(), 2);
+        new_vb.set(item("d")).unwrap();
+    }
+
+    #[test]
+    fn test_system_events() {
+        let dir = tempfile::tempdir().unwrap();
+        let bucket = EPBucket::new(Config {
+            max_vbuckets: 4,
+            max_shards: 1,
+            dbname: dir.path().to_str().unwrap().to_string(),
+            ..Default::default()
+        });
+        let vbid = Vbid::from(0usize);
+        bucket.vbucket_map.add_bucket(bucket.make_vbucket(
+            vbid,
+            State::Active,
+            FailoverTable::new_empty(25),
+            0,
+            0,
+        ));
+        bucket.create_collection(8, 0, "beers", 0).unwrap();
+        assert_eq!(
+            bucket.create_collection(8, 0, "beers", 0),
+            Err(EngineError::KeyExists)
+        );
+        let vb = bucket.get_vbucket(vbid).unwrap();
+        vb.set(Item {
+            key: b"\x08a".to_vec(),
+            value: Some(vec![0; 50]),
+            cas: 0,
+            expiry_time: 0,
+            flags: 0,
+            by_seqno: 0,
+            rev_seqno: 0,
+            delete_source: DeleteSource::Explicit,
+            datatype: Datatype::empty(),
+        })
+        .unwrap();
+        bucket.drop_collection(8).unwrap();
+        assert_eq!(
+            bucket.drop_collection(8),
+            Err(EngineError::UnknownCollection)
+        );
+        // The events are persisted like mutations, so the drop supersedes
+        // the create in the same batch
+        assert_eq!(bucket.flush_vbucket(vbid), 2);
+
+        let producer = DcpProducer::new("test", DcpOpenFlags::empty(), bucket);
+        let req = StreamRequest {
+            flags: StreamRequestFlags::empty(),
+            start_seqno: 0,
+            end_seqno: 3,
+            vb_uuid: 0,
+            snap_start_seqno: 0,
+            snap_end_seqno: 0,
+        };
+        producer
+            .stream_request(1, vbid, req, Some(r#"{"collections":["8"]}"#))
+            .unwrap();
+        producer.step().unwrap().unwrap();
+        let Some(DcpResponse::SystemEvent { item, event, .. }) = producer.step().unwrap() else {
+            panic!("expected a system event");
+        };
+        assert_eq!((item.by_seqno, event), (1, SystemEventId::CreateCollection));
+        assert_eq!(producer.step().unwrap().unwrap().by_seqno(), Some(2));
+        let Some(DcpResponse::SystemEvent { item, event, .. }) = producer.step().unwrap() else {
+            panic!("expected a system event");
+        };
+        assert_eq!((item.by_seqno, event), (3, SystemEventId::DropCollection));
+    }
+}
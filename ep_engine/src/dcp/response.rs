@@ -0,0 +1,166 @@
+use bitflags::bitflags;
+
+use crate::{
+    checkpoint_manager::QueuedItem,
+    vbucket::{State, Vbid},
+};
+
+/// Size of the memcached binary protocol header
+const HEADER_SIZE: usize = 24;
+
+bitflags! {
+    #[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Hash)]
+    pub struct SnapshotMarkerFlags: u32 {
+        /// The snapshot was read from memory
+        const MEMORY = 0x01;
+        /// The snapshot was read from disk
+        const DISK = 0x02;
+        /// The snapshot is a whole checkpoint
+        const CHECKPOINT = 0x04;
+        const ACK = 0x08;
+    }
+}
+
+/// Why a stream ended
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u32)]
+pub enum EndStreamStatus {
+    /// The stream reached its end seqno
+    Ok = 0,
+    /// The client closed the stream
+    Closed = 1,
+    /// The vbucket changed state
+    StateChanged = 2,
+    Disconnected = 3,
+    /// The stream could not keep up and its cursor was dropped
+    Slow = 4,
+    /// The items to send couldn't be read from disk
+    BackfillFailed = 5,
+}
+
+/// The change a DCP system event describes
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u32)]
+pub enum SystemEventId {
+    CreateCollection = 0,
+    DropCollection = 1,
+    /// The collection's settings, such as its maxTTL, changed
+    ModifyCollection = 5,
+}
+
+/// A message sent by a DCP producer
+#[derive(Debug, Clone)]
+pub enum DcpResponse {
+    SnapshotMarker {
+        opaque: u32,
+        vbid: Vbid,
+        start_seqno: u64,
+        end_seqno: u64,
+        flags: SnapshotMarkerFlags,
+    },
+    Mutation {
+        opaque: u32,
+        vbid: Vbid,
+        item: QueuedItem,
+    },
+    Deletion {
+        opaque: u32,
+        vbid: Vbid,
+        item: QueuedItem,
+        /// Send the V2 deletion, which carries the delete time instead of
+        /// the unused extended metadata length
+        include_delete_time: bool,
+    },
+    /// A deletion caused by the item's expiry time passing, carrying the
+    /// delete time
+    Expiration {
+        opaque: u32,
+        vbid: Vbid,
+        item: QueuedItem,
+    },
+    /// A collection was created or dropped, the item is the system event
+    SystemEvent {
+        opaque: u32,
+        vbid: Vbid,
+        item: QueuedItem,
+        event: SystemEventId,
+    },
+    StreamEnd {
+        opaque: u32,
+        vbid: Vbid,
+        status: EndStreamStatus,
+    },
+    /// Sent by a takeover stream, telling the client to move its vbucket to
+    /// the state. The client must acknowledge it before the stream goes on.
+    SetVBucketState {
+        opaque: u32,
+        vbid: Vbid,
+        state: State,
+    },
+    Noop {
+        opaque: u32,
+    },
+}
+
+impl DcpResponse {
+    /// Bytes the message occupies on the wire, which count towards the
+    /// connection's flow control buffer
+    pub fn message_size(&self) -> usize {
+        HEADER_SIZE
+            + match self {
+                // start, end, flags
+                DcpResponse::SnapshotMarker { .. } => 20,
+                // by_seqno, rev_seqno, flags, expiry, lock time, nmeta, nru
+                DcpResponse::Mutation { item, .. } => {
+                    31 + item.key.len() + item.value.as_ref().map_or(0, |value| value.len())
+                }
+                // by_seqno, rev_seqno, delete time, unused
+                DcpResponse::Deletion {
+                    item,
+                    include_delete_time: true,
+                    ..
+                } => 21 + item.key.len(),
+                // by_seqno, rev_seqno, nmeta
+                DcpResponse::Deletion { item, .. } => 18 + item.key.len(),
+                // by_seqno, rev_seqno, delete time
+                DcpResponse::Expiration { item, .. } => 20 + item.key.len(),
+                // by_seqno, event, version
+                DcpResponse::SystemEvent { item, .. } => {
+                    13 + item.key.len() + item.value.as_ref().map_or(0, |value| value.len())
+                }
+                DcpResponse::StreamEnd { .. } => 4,
+                DcpResponse::SetVBucketState { .. } => 1,
+                DcpResponse::Noop { .. } => 0,
+            }
+    }
+
+    pub fn vbid(&self) -> Option<Vbid> {
+        match self {
+            DcpResponse::SnapshotMarker { vbid, .. }
+            | DcpResponse::Mutation { vbid, .. }
+            | DcpResponse::Deletion { vbid, .. }
+            | DcpResponse::Expiration { vbid, .. }
+            | DcpResponse::SystemEvent { vbid, .. }
+            | DcpResponse::StreamEnd { vbid, .. }
+            | DcpResponse::SetVBucketState { vbid, .. } => Some(*vbid),
+            DcpResponse::Noop { .. } => None,
+        }
+    }
+
+    /// Seqno of the mutation, deletion or system event carried by the
+    /// message
+    pub fn by_seqno(&self) -> Option<u64> {
+        self.item().map(|item| item.by_seqno)
+    }
+
+    /// The item carried by a mutation, deletion or system event
+    pub fn item(&self) -> Option<&QueuedItem> {
+        match self {
+            DcpResponse::Mutation { item, .. }
+            | DcpResponse::Deletion { item, .. }
+            | DcpResponse::Expiration { item, .. }
+            | DcpResponse::SystemEvent { item, .. } => Some(item),
+            _ => None,
+        }
+    }
+}
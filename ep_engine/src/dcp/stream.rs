@@ -0,0 +1,453 @@
+use std::{collections::VecDeque, sync::Arc};
+
+use crate::{
+    checkpoint_manager::QueuedItem,
+    collections,
+    dcp::{
+        backfill::{Backfill, BackfillBudget, BackfillEnd},
+        filter::CollectionFilter,
+        producer::{StreamRequest, StreamRequestFlags},
+        response::{DcpResponse, EndStreamStatus, SnapshotMarkerFlags, SystemEventId},
+    },
+    ep_bucket::{EPBucket, EPBucketPtr},
+    error::{EngineError, EngineResult},
+    item::{DeleteSource, Item},
+    vbucket::{State, VBucket, Vbid},
+};
+
+/// How the stream's messages are encoded, as negotiated by the client
+#[derive(Debug, Clone, Copy, Default)]
+pub struct StreamOptions {
+    /// Send V2 deletions carrying the delete time
+    pub include_delete_times: bool,
+    /// Send deletions caused by expiry as expirations, requires delete times
+    pub enable_expiry_opcode: bool,
+    pub value_mode: ValueMode,
+}
+
+/// How much of each mutation the stream sends
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ValueMode {
+    #[default]
+    All,
+    /// Mutations are sent without their values
+    NoValue,
+    /// Mutations carry only their key, seqnos and CAS
+    KeyOnly,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StreamState {
+    /// Sending items read from disk, which were no longer in the
+    /// checkpoints
+    Backfilling,
+    /// Sending items from the vbucket's checkpoints
+    InMemory,
+    /// A takeover stream which has caught up, sending what is left before
+    /// telling the client to change the vbucket's state
+    TakeoverSend,
+    /// Waiting for the client to acknowledge the vbucket state sent
+    TakeoverWait,
+    /// The stream has ended, once its ready queue is drained it is removed
+    Dead,
+}
+
+impl StreamState {
+    pub fn name(self) -> &'static str {
+        match self {
+            StreamState::Backfilling => "backfilling",
+            StreamState::InMemory => "in-memory",
+            StreamState::TakeoverSend => "takeover-send",
+            StreamState::TakeoverWait => "takeover-wait",
+            StreamState::Dead => "dead",
+        }
+    }
+}
+
+/// A stream of a vbucket's mutations from a producer to its client. Items
+/// are read from the checkpoints through a cursor named after the producer,
+/// with items no longer in memory backfilled from disk.
+///
+/// A takeover stream hands the vbucket over to its client once it has
+/// caught up: it tells the client to make its vbucket pending, then makes
+/// the vbucket here dead so no more writes are accepted, sends the items
+/// written meanwhile and finally tells the client to make its vbucket
+/// active.
+#[derive(Debug)]
+pub struct ActiveStream {
+    name: String,
+    opaque: u32,
+    vbid: Vbid,
+    start_seqno: u64,
+    end_seqno: u64,
+    /// Seqno of the last item read into the ready queue
+    last_read_seqno: u64,
+    /// Seqno of the last item sent to the client
+    last_sent_seqno: u64,
+    /// The client asked to take the vbucket over once streamed
+    takeover: bool,
+    /// The vbucket state last sent to the client by a takeover stream
+    vb_state_sent: Option<State>,
+    state: StreamState,
+    ready_queue: VecDeque<DcpResponse>,
+    options: StreamOptions,
+    filter: CollectionFilter,
+    /// Reading the items no longer in memory while backfilling
+    backfill: Option<Backfill>,
+    /// The producer's bound on the items its backfills have read ahead
+    backfill_budget: Arc<BackfillBudget>,
+}
+
+impl ActiveStream {
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        name: &str,
+        opaque: u32,
+        vb: &VBucket,
+        req: &StreamRequest,
+        bucket: &EPBucketPtr,
+        options: StreamOptions,
+        filter: CollectionFilter,
+        backfill_budget: Arc<BackfillBudget>,
+    ) -> Self {
+        let mut stream = Self {
+            name: name.to_string(),
+            opaque,
+            vbid: vb.id,
+            start_seqno: req.start_seqno,
+            end_seqno: req.end_seqno,
+            last_read_seqno: req.start_seqno,
+            last_sent_seqno: req.start_seqno,
+            takeover: req.flags.contains(StreamRequestFlags::TAKEOVER),
+            vb_state_sent: None,
+            state: StreamState::InMemory,
+            ready_queue: VecDeque::new(),
+            options,
+            filter,
+            backfill: None,
+            backfill_budget,
+        };
+        stream.register_cursor(vb, bucket);
+        stream
+    }
+
+    pub fn opaque(&self) -> u32 {
+        self.opaque
+    }
+
+    pub fn state(&self) -> StreamState {
+        self.state
+    }
+
+    pub fn is_dead(&self) -> bool {
+        self.state == StreamState::Dead
+    }
+
+    /// Start reading from the checkpoints after the last item read, first
+    /// backfilling anything that is no longer in memory
+    fn register_cursor(&mut self, vb: &VBucket, bucket: &EPBucketPtr) {
+        let registration = vb
+            .checkpoint_manager
+            .register_cursor(&self.name, self.last_read_seqno);
+        if registration.try_backfill {
+            let backfill_end = (registration.next_seqno - 1).min(self.end_seqno);
+            self.backfill(vb, bucket, backfill_end);
+        }
+    }
+
+    /// Start reading the items between the last item read and backfill_end
+    /// from disk as a single snapshot, queueing the first of them
+    fn backfill(&mut self, vb: &VBucket, bucket: &EPBucketPtr, backfill_end: u64) {
+        if backfill_end <= self.last_read_seqno {
+            return;
+        }
+        self.backfill = Some(Backfill::start(
+            bucket,
+            self.vbid,
+            self.last_read_seqno + 1,
+            backfill_end,
+            self.backfill_budget.clone(),
+        ));
+        self.state = StreamState::Backfilling;
+        self.next_backfilled(vb, bucket);
+    }
+
+    /// Queue the next backfilled item the filter selects, preceded by the
+    /// snapshot's marker if it is the first, waiting for the scan to read
+    /// it. A snapshot can't have gaps, so if the file is corrupt the stream
+    /// ends instead and the vbucket is marked to be re-replicated.
+    fn next_backfilled(&mut self, vb: &VBucket, bucket: &EPBucket) {
+        let Some(backfill) = &mut self.backfill else {
+            return;
+        };
+        let item = loop {
+            match backfill.take() {
+                Ok(Some(item)) => {
+                    self.last_read_seqno = item.by_seqno;
+                    if self.filter.check_key(&item.key) {
+                        break item;
+                    }
+                }
+                Ok(None) => {
+                    self.last_read_seqno = self.last_read_seqno.max(backfill.end_seqno);
+                    self.backfill = None;
+                    return;
+                }
+                Err(BackfillEnd::Failed(result)) => {
+                    bucket.on_scan_errors(self.vbid, &result);
+                    self.end_stream(vb, EndStreamStatus::BackfillFailed);
+                    return;
+                }
+                Err(BackfillEnd::Stopped) => {
+                    self.end_stream(vb, EndStreamStatus::Disconnected);
+                    return;
+                }
+            }
+        };
+        if !backfill.marker_sent {
+            backfill.marker_sent = true;
+            let marker = DcpResponse::SnapshotMarker {
+                opaque: self.opaque,
+                vbid: self.vbid,
+                start_seqno: backfill.start_seqno,
+                end_seqno: backfill.end_seqno,
+                flags: SnapshotMarkerFlags::DISK | SnapshotMarkerFlags::CHECKPOINT,
+            };
+            self.ready_queue.push_back(marker);
+        }
+        let response = self.make_response(item);
+        self.ready_queue.push_back(response);
+    }
+
+    /// Queue the items the filter selects, with a marker for the snapshot
+    /// unless the filter removed all of them
+    fn queue_snapshot(
+        &mut self,
+        start_seqno: u64,
+        end_seqno: u64,
+        flags: SnapshotMarkerFlags,
+        items: Vec<QueuedItem>,
+    ) {
+        let Some(last_seqno) = items.last().map(|item| item.by_seqno) else {
+            return;
+        };
+        self.last_read_seqno = last_seqno;
+
+        let responses: Vec<DcpResponse> = items
+            .into_iter()
+            .filter(|item| self.filter.check_key(&item.key))
+            .map(|item| self.make_response(item))
+            .collect();
+        if responses.is_empty() {
+            return;
+        }
+        self.ready_queue.push_back(DcpResponse::SnapshotMarker {
+            opaque: self.opaque,
+            vbid: self.vbid,
+            start_seqno,
+            end_seqno,
+            flags,
+        });
+        self.ready_queue.extend(responses);
+    }
+
+    fn make_response(&self, item: QueuedItem) -> DcpResponse {
+        let opaque = self.opaque;
+        let vbid = self.vbid;
+        if collections::collection_event_id(&item.key).is_some() {
+            let event = match (&item.value, item.flags) {
+                (None, _) => SystemEventId::DropCollection,
+                (Some(_), collections::MODIFIED_EVENT_FLAGS) => SystemEventId::ModifyCollection,
+                (Some(_), _) => SystemEventId::CreateCollection,
+            };
+            DcpResponse::SystemEvent {
+                opaque,
+                vbid,
+                item,
+                event,
+            }
+        } else if item.value.is_some() {
+            let item = match self.options.value_mode {
+                ValueMode::All => item,
+                ValueMode::NoValue => Arc::new(Item {
+                    key: item.key.clone(),
+                    value: Some(Vec::new()),
+                    ..*item
+                }),
+                ValueMode::KeyOnly => Arc::new(Item {
+                    key: item.key.clone(),
+                    value: Some(Vec::new()),
+                    expiry_time: 0,
+                    flags: 0,
+                    ..*item
+                }),
+            };
+            DcpResponse::Mutation { opaque, vbid, item }
+        } else if item.delete_source == DeleteSource::Ttl && self.options.enable_expiry_opcode {
+            DcpResponse::Expiration { opaque, vbid, item }
+        } else {
+            DcpResponse::Deletion {
+                opaque,
+                vbid,
+                item,
+                include_delete_time: self.options.include_delete_times,
+            }
+        }
+    }
+
+    /// Move the next items from the checkpoints into the ready queue
+    fn next_checkpoint_items(&mut self, vb: &VBucket, bucket: &EPBucketPtr) {
+        let Some(result) = vb.checkpoint_manager.get_items_for_cursor(&self.name) else {
+            // The cursor was dropped to free memory, what it missed must now
+            // be read from disk
+            self.register_cursor(vb, bucket);
+            return;
+        };
+
+        let items: Vec<QueuedItem> = result
+            .items
+            .into_iter()
+            .filter(|item| item.by_seqno > self.last_read_seqno && item.by_seqno <= self.end_seqno)
+            .collect();
+        let start_seqno = result.snap_start.max(self.last_read_seqno + 1);
+        let end_seqno = result.snap_end.min(self.end_seqno);
+        self.queue_snapshot(
+            start_seqno,
+            end_seqno,
+            SnapshotMarkerFlags::MEMORY | SnapshotMarkerFlags::CHECKPOINT,
+            items,
+        );
+    }
+
+    /// The next message to send, if any
+    pub fn next(&mut self, vb: &VBucket, bucket: &EPBucketPtr) -> Option<DcpResponse> {
+        if self.ready_queue.is_empty() && self.state == StreamState::Backfilling {
+            self.next_backfilled(vb, bucket);
+            if self.ready_queue.is_empty() && self.backfill.is_none() {
+                self.state = StreamState::InMemory;
+            }
+        }
+        if self.ready_queue.is_empty() && self.state == StreamState::InMemory {
+            if self.last_read_seqno >= self.end_seqno {
+                self.end_stream(vb, EndStreamStatus::Ok);
+            } else {
+                self.next_checkpoint_items(vb, bucket);
+                if self.takeover && self.ready_queue.is_empty() {
+                    self.state = StreamState::TakeoverSend;
+                }
+            }
+        }
+        if self.ready_queue.is_empty() && self.state == StreamState::TakeoverSend {
+            self.next_checkpoint_items(vb, bucket);
+            if self.ready_queue.is_empty() {
+                self.send_vbucket_state();
+            }
+        }
+        let response = self.ready_queue.pop_front();
+        if let Some(seqno) = response.as_ref().and_then(DcpResponse::by_seqno) {
+            self.last_sent_seqno = seqno;
+        }
+        response
+    }
+
+    /// Tell the client to make its vbucket pending, or active once the
+    /// vbucket here is dead and everything it had has been sent
+    fn send_vbucket_state(&mut self) {
+        let state = match self.vb_state_sent {
+            None => State::Pending,
+            Some(_) => State::Active,
+        };
+        self.vb_state_sent = Some(state);
+        self.ready_queue.push_back(DcpResponse::SetVBucketState {
+            opaque: self.opaque,
+            vbid: self.vbid,
+            state,
+        });
+        self.state = StreamState::TakeoverWait;
+    }
+
+    /// The client has moved its vbucket to the state sent. Once it is
+    /// pending the vbucket here is made dead, which waits for writes in
+    /// progress to be queued, so the stream then sends every item the
+    /// vbucket will ever have. Once it is active the stream ends.
+    pub fn set_vbucket_state_ack(&mut self, vb: &VBucket, bucket: &EPBucket) -> EngineResult<()> {
+        if self.state != StreamState::TakeoverWait {
+            println!(
+                "{} ({}) Unexpected set vbucket state acknowledgement in state {}",
+                self.name,
+                self.vbid,
+                self.state.name()
+            );
+            return Err(EngineError::InvalidArguments);
+        }
+        match self.vb_state_sent {
+            Some(State::Pending) => {
+                bucket.set_vbucket_state(self.vbid, State::Dead)?;
+                self.state = StreamState::TakeoverSend;
+            }
+            _ => {
+                println!("{} ({}) Vbucket takeover complete", self.name, self.vbid);
+                self.end_stream(vb, EndStreamStatus::Ok);
+            }
+        }
+        Ok(())
+    }
+
+    /// The stream's state and progress, for the dcp stats group. Items
+    /// remaining counts those queued to send, those backfilled and waiting
+    /// to be queued and those the stream's cursor has yet to read, some of
+    /// which its filter may drop.
+    pub fn add_stats(&self, vb: Option<&VBucket>, add_stat: &mut dyn FnMut(&str, &str)) {
+        add_stat("opaque", &self.opaque.to_string());
+        add_stat("state", self.state.name());
+        add_stat("takeover", &self.takeover.to_string());
+        add_stat("start_seqno", &self.start_seqno.to_string());
+        add_stat("end_seqno", &self.end_seqno.to_string());
+        add_stat("last_read_seqno", &self.last_read_seqno.to_string());
+        add_stat("last_sent_seqno", &self.last_sent_seqno.to_string());
+        let ready = self
+            .ready_queue
+            .iter()
+            .filter(|response| response.by_seqno().is_some())
+            .count();
+        add_stat("ready_queue_items", &ready.to_string());
+        let backfilled = self.backfill.as_ref().map_or(0, Backfill::buffered_items);
+        if self.backfill.is_some() {
+            add_stat("backfill_buffered_items", &backfilled.to_string());
+        }
+        let Some(vb) = vb else {
+            return;
+        };
+        // Ended streams and those whose cursor was dropped have none
+        let unread = vb
+            .checkpoint_manager
+            .get_num_items_for_cursor(&self.name)
+            .unwrap_or(0);
+        add_stat(
+            "items_remaining",
+            &(ready + backfilled + unread).to_string(),
+        );
+        if let Some(cursor) = vb.checkpoint_manager.get_cursor_position(&self.name) {
+            add_stat("cursor_checkpoint_id", &cursor.checkpoint_id.to_string());
+            add_stat("cursor_position", &cursor.position.to_string());
+            add_stat("cursor_read_seqno", &cursor.read_seqno.to_string());
+        }
+    }
+
+    /// End the stream, sending the client a stream end message with the
+    /// reason after any messages already queued
+    pub fn end_stream(&mut self, vb: &VBucket, status: EndStreamStatus) {
+        if self.state == StreamState::Dead {
+            return;
+        }
+        self.state = StreamState::Dead;
+        // Dropping the backfill stops its scan
+        self.backfill = None;
+        vb.checkpoint_manager.remove_cursor(&self.name);
+        self.ready_queue.push_back(DcpResponse::StreamEnd {
+            opaque: self.opaque,
+            vbid: self.vbid,
+            status,
+        });
+    }
+}
@@ -0,0 +1,64 @@
+//! Free space on the disk holding the data files. When it runs low the
+//! bucket stops writing before the disk fills, as a full disk leaves commits
+//! failing part way through.
+
+use std::io;
+
+/// Bytes available to the engine on the filesystem holding path
+#[cfg(unix)]
+pub fn free_space(path: &str) -> io::Result<u64> {
+    use std::{ffi::CString, mem::MaybeUninit};
+
+    let path = CString::new(path).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+    let mut stat = MaybeUninit::<libc::statvfs>::uninit();
+    // SAFETY: path is a valid C string and stat is written by the call
+    if unsafe { libc::statvfs(path.as_ptr(), stat.as_mut_ptr()) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    // SAFETY: statvfs succeeded so filled it in
+    let stat = unsafe { stat.assume_init() };
+    // The fields' types vary by platform
+    #[allow(clippy::unnecessary_cast)]
+    Ok(stat.f_bavail as u64 * stat.f_frsize as u64)
+}
+
+/// Bytes available to the engine on the volume holding path
+#[cfg(windows)]
+pub fn free_space(path: &str) -> io::Result<u64> {
+    use std::{ffi::OsStr, os::windows::ffi::OsStrExt, ptr};
+
+    #[link(name = "kernel32")]
+    extern "system" {
+        fn GetDiskFreeSpaceExW(
+            directory: *const u16,
+            free_bytes_available_to_caller: *mut u64,
+            total_bytes: *mut u64,
+            total_free_bytes: *mut u64,
+        ) -> i32;
+    }
+
+    let path: Vec<u16> = OsStr::new(path).encode_wide().chain([0]).collect();
+    let mut free = 0;
+    // SAFETY: path is nul terminated and the totals may be null
+    if unsafe { GetDiskFreeSpaceExW(path.as_ptr(), &mut free, ptr::null_mut(), ptr::null_mut()) }
+        == 0
+    {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(free)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_free_space() {
+        let dir = tempfile::tempdir().unwrap();
+        assert!(free_space(dir.path().to_str().unwrap()).unwrap() > 0);
+        assert_eq!(
+            free_space("/nonexistent/path").unwrap_err().kind(),
+            io::ErrorKind::NotFound
+        );
+    }
+}
@@ -0,0 +1,143 @@
+//! Tracing where one document is across the engine's layers, for support
+//! debugging: the hash table's copy, the mutations of it still queued in
+//! the checkpoints, and the version in the vbucket's file. Comparing them
+//! shows whether a write is still waiting for the flusher, whether the
+//! value was evicted, or whether memory and disk disagree. The engine keeps
+//! no Bloom filters or access frequencies, so there are none to report.
+
+use serde::Serialize;
+
+use crate::{
+    checkpoint_manager::QueuedMutation,
+    item::Item,
+    stored_value::StoredValue,
+    vbucket::{State, Vbid},
+};
+
+/// The key's entry in the hash table
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct HashTableEntry {
+    pub cas: u64,
+    pub by_seqno: u64,
+    pub rev_seqno: u64,
+    pub expiry_time: u32,
+    pub flags: u32,
+    /// The value is in memory, not evicted
+    pub resident: bool,
+    /// Modified since it was last persisted
+    pub dirty: bool,
+    pub deleted: bool,
+    /// GET_LOCKED holds a lock on it
+    pub locked: bool,
+}
+
+impl HashTableEntry {
+    pub(crate) fn new(value: &StoredValue, now: u32) -> Self {
+        Self {
+            cas: value.cas,
+            by_seqno: value.by_seqno,
+            rev_seqno: value.rev_seqno,
+            expiry_time: value.expiry_time,
+            flags: value.flags,
+            resident: value.is_resident(),
+            dirty: value.is_dirty(),
+            deleted: value.is_deleted(),
+            locked: value.is_locked(now),
+        }
+    }
+}
+
+/// The key's document in the vbucket's file
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct DiskEntry {
+    pub cas: u64,
+    pub by_seqno: u64,
+    pub rev_seqno: u64,
+    pub expiry_time: u32,
+    pub flags: u32,
+    pub deleted: bool,
+}
+
+impl From<&Item> for DiskEntry {
+    fn from(item: &Item) -> Self {
+        Self {
+            cas: item.cas,
+            by_seqno: item.by_seqno,
+            rev_seqno: item.rev_seqno,
+            expiry_time: item.expiry_time,
+            flags: item.flags,
+            deleted: item.value.is_none(),
+        }
+    }
+}
+
+/// Where a key is, layer by layer. None at a layer means it isn't there.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct DocTrace {
+    pub vbid: Vbid,
+    pub vbucket_state: State,
+    pub hash_table: Option<HashTableEntry>,
+    /// Mutations of the key not yet removed from the checkpoints, oldest
+    /// first
+    pub checkpoints: Vec<QueuedMutation>,
+    /// None too for a quarantined vbucket, whose file isn't read
+    pub disk: Option<DiskEntry>,
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{ep_bucket::EPBucket, failover_table::FailoverTable, Config};
+
+    #[test]
+    fn test_trace_doc() {
+        let dir = tempfile::tempdir().unwrap();
+        let bucket = EPBucket::new(Config {
+            max_vbuckets: 1,
+            max_shards: 1,
+            dbname: dir.path().to_str().unwrap().to_string(),
+            ..Default::default()
+        });
+        bucket.vbucket_map.add_bucket(bucket.make_vbucket(
+            Vbid::new(0),
+            State::Active,
+            FailoverTable::new_empty(25),
+            0,
+            0,
+        ));
+        bucket.enable_traffic();
+
+        let trace = bucket.trace_doc(b"key".to_vec()).unwrap();
+        assert_eq!(trace.vbucket_state, State::Active);
+        assert!(trace.hash_table.is_none() && trace.disk.is_none());
+        assert!(trace.checkpoints.is_empty());
+
+        // Written, but only in memory
+        let cas = bucket.set(b"key".to_vec(), b"v1".to_vec(), 3, 0).unwrap();
+        let trace = bucket.trace_doc(b"key".to_vec()).unwrap();
+        let entry = trace.hash_table.unwrap();
+        assert_eq!((entry.cas, entry.by_seqno, entry.flags), (cas, 1, 3));
+        assert!(entry.resident && entry.dirty && !entry.deleted);
+        assert_eq!(trace.checkpoints.len(), 1);
+        assert!(!trace.checkpoints[0].persisted);
+        assert!(trace.disk.is_none());
+
+        // Persisted, then deleted in memory
+        bucket.flush_vbucket(Vbid::new(0));
+        bucket.delete(b"key".to_vec(), 0).unwrap();
+        let trace = bucket.trace_doc(b"key".to_vec()).unwrap();
+        assert!(trace.hash_table.unwrap().deleted);
+        let queued: Vec<(u64, bool, bool)> = trace
+            .checkpoints
+            .iter()
+            .map(|queued| (queued.by_seqno, queued.deleted, queued.persisted))
+            .collect();
+        assert_eq!(queued, [(1, false, true), (2, true, false)]);
+        let disk = trace.disk.unwrap();
+        assert_eq!((disk.cas, disk.by_seqno, disk.deleted), (cas, 1, false));
+
+        let json = serde_json::to_value(bucket.trace_doc(b"key".to_vec()).unwrap()).unwrap();
+        assert_eq!(json["vbucket_state"], "active");
+        assert_eq!(json["disk"]["by_seqno"], 1);
+    }
+}
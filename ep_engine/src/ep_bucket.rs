@@ -1,63 +1,2552 @@
-use parking_lot::{Mutex, MutexGuard};
-use std::{ops::Deref, sync::Arc};
+use parking_lot::{Mutex, MutexGuard, RwLock};
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    ops::Deref,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Weak,
+    },
+    thread::JoinHandle,
+    time::{Duration, Instant},
+};
 
 use crate::{
-    kv_store::CouchKVStore,
+    checkpoint_manager::{CheckpointConfig, QueuedItem, PERSISTENCE_CURSOR},
+    collections::{self, CollectionEntry, CollectionId, ScopeId, DEFAULT_COLLECTION},
+    compaction::{self, CompactAllOptions, CompactAllProgress},
+    compression::{self, CompressionMode},
+    dcp::producer::DcpProducer,
+    disk_space,
+    doc_trace::{DiskEntry, DocTrace, HashTableEntry},
+    error::{EngineError, EngineResult},
+    failover_table::FailoverTable,
+    health::{BucketHealth, ShardHealth, TaskHealth},
+    io_throttle::IOThrottle,
+    item::{self, Datatype, DeleteSource, Item},
+    kv_store::{
+        CommitError, KVStore, PurgeResult, RetainedHeader, ScanErrorPolicy, ScanResult,
+        TruncatedCommits, ValueFilter,
+    },
+    memory_tracker::{MemoryDomain, MemoryScope},
+    observer::{EngineObserver, Observers},
+    op_trace::OpTrace,
+    reconcile::{Drift, VBucketCounts},
+    stats::{EPStats, EPStatsPtr},
     stored_value::StoredValue,
-    vbucket::{VBucketPtr, Vbid},
+    ttl_update::{self, TtlUpdateProgress},
+    validation::{CollectionValidator, WriteValidatorPtr},
+    vbucket::{KeyState, State, VBucket, VBucketPtr, VBucketState, Vbid},
+    vbucket_hash::vbucket_for_key,
     vbucket_map::VBucketMap,
-    Config,
+    warmup, Config,
 };
 
 pub struct EPBucket {
     pub vbucket_map: VBucketMap,
     vb_mutexes: Vec<Mutex<()>>,
+    pub(crate) stats: EPStatsPtr,
+    config: Config,
+    /// Front-end operations are rejected until warmup has loaded enough data
+    traffic_enabled: AtomicBool,
+    io_throttle: IOThrottle,
+    /// The scrubber's own limit, on top of io_throttle
+    scrub_throttle: IOThrottle,
+    /// Set once shutdown starts, background tasks should stop when they see
+    /// it
+    shutting_down: AtomicBool,
+    /// Nothing is written to disk while paused
+    paused: AtomicBool,
+    /// Writes are rejected and nothing is written to disk while the disk is
+    /// nearly full
+    read_only: AtomicBool,
+    tasks: Mutex<Vec<(String, JoinHandle<()>)>>,
+    /// The periodic tasks, with when each last finished a run on the
+    /// bucket's clock, for the health report
+    periodic_tasks: Mutex<BTreeMap<String, Option<Duration>>>,
+    observers: Observers,
+    /// The open DCP producers, for the dcp stats group
+    dcp_producers: Mutex<Vec<Weak<DcpProducer>>>,
+    /// The latest update of a collection's expiry times, for the
+    /// ttl_update stats group
+    ttl_update: Mutex<Option<Arc<TtlUpdateProgress>>>,
+    /// The latest compaction of every vbucket, for the compact_all stats
+    /// group
+    compact_all: Mutex<Option<Arc<CompactAllProgress>>>,
+    /// The collections' write validators
+    validators: RwLock<HashMap<CollectionId, Arc<CollectionValidator>>>,
 }
 
 impl EPBucket {
     pub fn new(config: Config) -> EPBucketPtr {
+        let stats = EPStatsPtr::new(EPStats::new(&config));
+        let _memory = stats.memory.enter();
         let mut vb_mutexes = Vec::with_capacity(config.max_vbuckets as usize);
         vb_mutexes.resize_with(config.max_vbuckets as usize, Default::default);
         EPBucketPtr::new(EPBucket {
+            stats,
             vbucket_map: VBucketMap::new(config.clone()),
             vb_mutexes,
+            io_throttle: IOThrottle::new(
+                config.background_io_bytes_per_sec,
+                config.background_io_ops_per_sec,
+            ),
+            scrub_throttle: IOThrottle::new(config.scrubber_bytes_per_sec, 0),
+            config,
+            traffic_enabled: AtomicBool::new(false),
+            shutting_down: AtomicBool::new(false),
+            paused: AtomicBool::new(false),
+            read_only: AtomicBool::new(false),
+            tasks: Mutex::default(),
+            periodic_tasks: Mutex::default(),
+            observers: Observers::default(),
+            dcp_producers: Mutex::default(),
+            ttl_update: Mutex::default(),
+            compact_all: Mutex::default(),
+            validators: RwLock::default(),
         })
     }
 
-    pub fn get_store_by_shard(&self, shard_id: usize) -> &CouchKVStore {
+    pub fn config(&self) -> &Config {
+        &self.config
+    }
+
+    /// Charge the thread's allocations to this bucket until the scope is
+    /// dropped. Callers working on the bucket's data enter it, so the
+    /// bucket's mem_used counts what it holds and not other buckets' data.
+    pub fn memory_scope(&self) -> MemoryScope {
+        self.stats.memory.enter()
+    }
+
+    /// Create a vbucket configured for this bucket. The vbucket isn't
+    /// visible until it is added to the vbucket map.
+    pub fn make_vbucket(
+        &self,
+        vbid: Vbid,
+        state: State,
+        failover_table: FailoverTable,
+        last_seqno: u64,
+        max_cas: u64,
+    ) -> VBucketPtr {
+        VBucketPtr::new(VBucket::new(
+            vbid,
+            state,
+            failover_table,
+            self.stats.clone(),
+            last_seqno,
+            max_cas,
+            CheckpointConfig {
+                max_items: self.config.checkpoint_max_items,
+            },
+            self.config.clock.clone(),
+        ))
+    }
+
+    /// Allow front-end operations to be served. Returns false if traffic was
+    /// already enabled.
+    pub fn enable_traffic(&self) -> bool {
+        !self.traffic_enabled.swap(true, Ordering::SeqCst)
+    }
+
+    pub fn is_traffic_enabled(&self) -> bool {
+        self.traffic_enabled.load(Ordering::SeqCst)
+    }
+
+    /// Have the observer told of the engine's events from now on
+    pub fn register_observer(&self, observer: Arc<dyn EngineObserver>) {
+        self.observers.register(observer);
+    }
+
+    /// List the producer in the dcp stats until it is dropped
+    pub(crate) fn register_dcp_producer(&self, producer: &Arc<DcpProducer>) {
+        let mut producers = self.dcp_producers.lock();
+        producers.retain(|producer| producer.strong_count() > 0);
+        producers.push(Arc::downgrade(producer));
+    }
+
+    /// Move the vbucket to a new state, persisting it, or while the bucket
+    /// is paused once it resumes
+    pub fn set_vbucket_state(&self, vbid: Vbid, state: State) -> EngineResult<()> {
+        let locked_vb = self.get_locked_vbucket(vbid);
+        let vb = locked_vb.vb.as_ref().ok_or(EngineError::NotMyVbucket)?;
+        let old = vb.state();
+        if old == state {
+            return Ok(());
+        }
+        // Persisted first, so a failure leaves the vbucket as it was
+        if !self.is_paused() {
+            let mut vb_state = self.vb_state_to_persist(vb);
+            vb_state.state = state;
+            self.snapshot_vbucket(vb, &vb_state)?;
+        }
+        vb.set_state(state);
+        self.vbucket_map.dec_vb_state_count(old);
+        self.vbucket_map.inc_vb_state_count(state);
+        self.observers
+            .notify(|observer| observer.on_vbucket_state_change(vbid, old, state));
+        Ok(())
+    }
+
+    pub fn is_shutting_down(&self) -> bool {
+        self.shutting_down.load(Ordering::SeqCst)
+    }
+
+    /// Run a background task on its own thread, in the bucket's memory
+    /// scope. Long running tasks must check is_shutting_down and return
+    /// once it is set.
+    pub fn schedule_task(&self, name: impl Into<String>, task: impl FnOnce() + Send + 'static) {
+        let name = name.into();
+        let stats = self.stats.clone();
+        let handle = std::thread::Builder::new()
+            .name(name.clone())
+            .spawn(move || {
+                let _memory = stats.memory.enter();
+                task();
+            })
+            .unwrap();
+        let mut tasks = self.tasks.lock();
+        tasks.retain(|(_, handle)| !handle.is_finished());
+        tasks.push((name, handle));
+    }
+
+    /// Stop the bucket. Front-end operations are rejected, background tasks
+    /// are given until the shutdown timeout to finish, then the outstanding
+    /// mutations and every vbucket's state are persisted. A forced shutdown
+    /// doesn't wait for tasks and drops unpersisted mutations.
+    pub fn shutdown(&self, force: bool) {
+        if self.shutting_down.swap(true, Ordering::SeqCst) {
+            return;
+        }
+        println!("Shutting down bucket (force: {force})");
+        self.traffic_enabled.store(false, Ordering::SeqCst);
+
+        let deadline = if force {
+            Instant::now()
+        } else {
+            Instant::now() + Duration::from_secs(self.config.shutdown_timeout)
+        };
+        self.join_tasks(deadline);
+
+        if force {
+            return;
+        }
+        self.persist_all();
+        println!("Bucket shut down");
+    }
+
+    /// Quiesce persistence so the data files can be copied by an external
+    /// backup. The outstanding mutations and every vbucket's state are
+    /// persisted, then nothing is written to disk until resume. Front-end
+    /// operations carry on. Returns false if the bucket was already paused.
+    pub fn pause(&self) -> bool {
+        if self.paused.swap(true, Ordering::SeqCst) {
+            return false;
+        }
+        println!("Pausing bucket");
+        // A compaction of every vbucket stops once the vbuckets it's on are
+        // done, and those are waited for as they rewrite files
+        self.wait_for_compact_all();
+        // A flush already under way holds its vbucket's lock, so this waits
+        // for it
+        self.persist_all();
+        println!("Bucket paused");
+        true
+    }
+
+    /// Let persistence continue after a pause, persisting the mutations and
+    /// vbucket states held back meanwhile. Returns false if the bucket
+    /// wasn't paused.
+    pub fn resume(&self) -> bool {
+        let was_paused = self.paused.swap(false, Ordering::SeqCst);
+        if was_paused {
+            self.persist_all();
+            println!("Bucket resumed");
+        }
+        was_paused
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.paused.load(Ordering::SeqCst)
+    }
+
+    fn wait_for_compact_all(&self) {
+        let Some(progress) = self.compact_all.lock().clone() else {
+            return;
+        };
+        while progress.is_running() {
+            std::thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    /// Whether the disk is too full to write to
+    pub fn is_read_only(&self) -> bool {
+        self.read_only.load(Ordering::SeqCst)
+    }
+
+    /// Measure the free space on the data directories' disks, switching the
+    /// bucket to or from read-only mode on the fullest
+    pub fn check_disk_space(&self) {
+        if let Some(free) = self.min_free_disk_space() {
+            self.set_free_disk_space(free);
+        }
+    }
+
+    /// The free space on the fullest of the data directories' disks, None
+    /// if none of them could be read
+    fn min_free_disk_space(&self) -> Option<u64> {
+        let mut min_free = None;
+        for path in std::iter::once(&self.config.dbname).chain(&self.config.data_paths) {
+            match disk_space::free_space(path) {
+                Ok(free) => min_free = Some(min_free.map_or(free, |min: u64| min.min(free))),
+                Err(e) => println!("Failed to read the free space of {path}: {e}"),
+            }
+        }
+        min_free
+    }
+
+    /// Whether the bucket is fit to serve, and what is wrong if it isn't.
+    /// The disk's free space is measured afresh.
+    pub fn health(&self) -> BucketHealth {
+        let disk_free_bytes = self.min_free_disk_space();
+        let now = self.config.clock.now();
+        let tasks = {
+            let running = self.tasks.lock();
+            self.periodic_tasks
+                .lock()
+                .iter()
+                .map(|(name, last_run)| TaskHealth {
+                    name: name.clone(),
+                    alive: running
+                        .iter()
+                        .any(|(task, handle)| task == name && !handle.is_finished()),
+                    last_run_age_ms: last_run
+                        .map(|last_run| now.saturating_sub(last_run).as_millis() as u64),
+                })
+                .collect()
+        };
+        let shards = self
+            .vbucket_map
+            .shards
+            .iter()
+            .enumerate()
+            .map(|(shard, kv_shard)| ShardHealth {
+                shard,
+                last_commit_age_ms: kv_shard
+                    .store()
+                    .get_stats()
+                    .last_commit_age()
+                    .map(|age| age.as_millis() as u64),
+            })
+            .collect();
+        BucketHealth {
+            warmup: self.stats.warmup.phase(),
+            traffic_enabled: self.is_traffic_enabled(),
+            shutting_down: self.is_shutting_down(),
+            read_only: self.is_read_only(),
+            quarantined_vbuckets: self.quarantined_vbuckets(),
+            vbuckets_persistence_failing: self
+                .stats
+                .vbuckets_persistence_failing
+                .load(Ordering::Relaxed),
+            disk_free_bytes,
+            disk_headroom_bytes: disk_free_bytes
+                .map(|free| free as i64 - self.config.disk_min_free_bytes as i64),
+            tasks,
+            shards,
+            problems: Vec::new(),
+        }
+        .diagnose()
+    }
+
+    /// Turn read-only once the free space drops below the minimum, and
+    /// writable again once it has a tenth more than that, so the bucket
+    /// doesn't flip back and forth as the usage hovers around the minimum
+    pub fn set_free_disk_space(&self, free: u64) {
+        self.stats.disk_free_bytes.store(free, Ordering::Relaxed);
+        let min_free = self.config.disk_min_free_bytes;
+        if free < min_free {
+            if !self.read_only.swap(true, Ordering::SeqCst) {
+                println!(
+                    "ALERT: {free} bytes free on the fullest data disk, the bucket is read-only until space is freed"
+                );
+            }
+        } else if free >= min_free + min_free / 10 && self.read_only.swap(false, Ordering::SeqCst) {
+            println!("{free} bytes free on the fullest data disk, the bucket is writable again");
+        }
+    }
+
+    /// Persist the outstanding mutations and every vbucket's state, even
+    /// while paused, and sync them whatever the sync policy. Quarantined
+    /// vbuckets are left alone, as their files can't be written, and
+    /// vbuckets which fail to persist are logged and skipped.
+    pub(crate) fn persist_all(&self) {
+        for vbid in self.vbucket_map.get_buckets() {
+            while self.flush_with_retries(vbid, true) > 0 {}
+            let locked_vb = self.get_locked_vbucket(vbid);
+            let Some(vb) = &locked_vb.vb else {
+                continue;
+            };
+            if vb.is_quarantined() {
+                println!("Not persisting {vbid}, it is quarantined");
+                continue;
+            }
+            // Logged by snapshot_vbucket
+            let _ = self.snapshot_vbucket(vb, &self.vb_state_to_persist(vb));
+        }
+        self.sync_pending_commits();
+    }
+
+    /// Persist the vbucket's state without any items. A failure counts as
+    /// a failed flush, and fails with TemporaryFailure. The caller holds
+    /// the vbucket's lock.
+    fn snapshot_vbucket(&self, vb: &VBucket, vb_state: &VBucketState) -> EngineResult<()> {
+        let store = self.vbucket_map.get_shard_by_vb_id(vb.id).store();
+        match store.snapshot_vbucket(vb.id, vb_state) {
+            Ok(()) => {
+                self.persistence_succeeded(vb);
+                Ok(())
+            }
+            Err(e) => {
+                self.stats
+                    .item_commit_failed
+                    .fetch_add(1, Ordering::Relaxed);
+                println!("Failed to persist the state of {}: {e}", vb.id);
+                self.persistence_failed(vb, &e);
+                Err(EngineError::TemporaryFailure)
+            }
+        }
+    }
+
+    /// Sync what commits have left unsynced in every shard
+    pub fn sync_pending_commits(&self) {
+        for shard in &self.vbucket_map.shards {
+            shard.store().sync_pending_commits();
+        }
+    }
+
+    /// Wait for the background tasks to finish, abandoning any still
+    /// running at the deadline
+    fn join_tasks(&self, deadline: Instant) {
+        let mut tasks = std::mem::take(&mut *self.tasks.lock());
+        loop {
+            let (finished, running): (Vec<_>, Vec<_>) = tasks
+                .into_iter()
+                .partition(|(_, handle)| handle.is_finished());
+            for (name, handle) in finished {
+                if handle.join().is_err() {
+                    println!("Background task {name} panicked");
+                }
+            }
+            tasks = running;
+            if tasks.is_empty() {
+                return;
+            }
+            if Instant::now() >= deadline {
+                for (name, _) in &tasks {
+                    println!("Background task {name} did not stop before the shutdown deadline");
+                }
+                return;
+            }
+            std::thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    /// The bucket is in degraded mode while warmup is still loading data, in
+    /// which case front-end operations are temporarily rejected.
+    pub fn is_degraded_mode(&self) -> bool {
+        !self.is_traffic_enabled()
+    }
+
+    /// Is there enough memory left below the mutation threshold to store a
+    /// new value of the given size
+    pub fn has_memory_for_mutation(&self, size: usize) -> bool {
+        let threshold =
+            (self.stats.get_max_data_size() as f64 * self.config.mutation_mem_threshold) as usize;
+        self.stats.get_precise_total_memory_used() + size <= threshold
+    }
+
+    /// Should DCP consumers hold off applying replicated messages, because
+    /// memory is over the replication threshold or the flusher is too far
+    /// behind
+    pub fn is_replication_throttled(&self) -> bool {
+        let threshold = (self.stats.get_max_data_size() as f64
+            * self.config.replication_throttle_threshold) as usize;
+        if self.stats.get_precise_total_memory_used() > threshold {
+            return true;
+        }
+        let cap = self.config.replication_throttle_queue_cap;
+        cap > 0 && self.get_disk_queue_size() > cap
+    }
+
+    /// Items waiting to be persisted, over all the vbuckets
+    pub fn get_disk_queue_size(&self) -> usize {
+        self.vbucket_map
+            .get_buckets()
+            .into_iter()
+            .filter_map(|vbid| self.get_vbucket(vbid))
+            .filter_map(|vb| {
+                vb.checkpoint_manager
+                    .get_num_items_for_cursor(PERSISTENCE_CURSOR)
+            })
+            .sum()
+    }
+
+    pub fn get_store_by_shard(&self, shard_id: usize) -> &dyn KVStore {
         self.vbucket_map.shards[shard_id].store()
     }
 
+    /// The vbucket's commits available for point in time recovery, newest
+    /// first. Fails with TemporaryFailure, logging why, if the store can't
+    /// read them.
+    pub fn list_retained_headers(&self, vbid: Vbid) -> EngineResult<Vec<RetainedHeader>> {
+        self.vbucket_map
+            .get_shard_by_vb_id(vbid)
+            .store()
+            .list_retained_headers(vbid)
+            .map_err(|e| {
+                println!("Failed to read the headers of {vbid}: {e}");
+                EngineError::TemporaryFailure
+            })
+    }
+
+    /// Fails with TemporaryFailure, logging why, if the store can't read
+    /// them
+    pub fn get_backup_cursors(&self, vbid: Vbid) -> EngineResult<BTreeMap<String, u64>> {
+        self.vbucket_map
+            .get_shard_by_vb_id(vbid)
+            .store()
+            .get_backup_cursors(vbid)
+            .map_err(|e| {
+                println!("Failed to read the backup cursors of {vbid}: {e}");
+                EngineError::TemporaryFailure
+            })
+    }
+
+    /// Record that the named backup has copied the vbucket up to seqno
+    pub fn set_backup_cursor(&self, vbid: Vbid, name: &str, seqno: u64) -> EngineResult<()> {
+        // Holding the vbucket keeps the flusher from writing it meanwhile
+        let _vb = self.get_locked_vbucket(vbid);
+        let mut cursors = self.get_backup_cursors(vbid)?;
+        cursors.insert(name.to_string(), seqno);
+        self.write_backup_cursors(vbid, &cursors)
+    }
+
+    pub fn remove_backup_cursor(&self, vbid: Vbid, name: &str) -> EngineResult<()> {
+        let _vb = self.get_locked_vbucket(vbid);
+        let mut cursors = self.get_backup_cursors(vbid)?;
+        cursors.remove(name).ok_or(EngineError::KeyNotFound)?;
+        self.write_backup_cursors(vbid, &cursors)
+    }
+
+    fn write_backup_cursors(
+        &self,
+        vbid: Vbid,
+        cursors: &BTreeMap<String, u64>,
+    ) -> EngineResult<()> {
+        self.vbucket_map
+            .get_shard_by_vb_id(vbid)
+            .store()
+            .set_backup_cursors(vbid, cursors)
+            .map_err(|e| {
+                println!("Failed to write the backup cursors of {vbid}: {e}");
+                EngineError::TemporaryFailure
+            })
+    }
+
+    /// Account for the corruption a scan of the vbucket's file found. The
+    /// file has lost what it persisted, so the vbucket is marked to be
+    /// rebuilt from another copy by re-replication.
+    pub fn on_scan_errors(&self, vbid: Vbid, result: &ScanResult) {
+        if result.is_complete() {
+            return;
+        }
+        self.stats
+            .scan_corrupt_items
+            .fetch_add(result.errors.len() as u64, Ordering::Relaxed);
+        let Some(vb) = self.get_vbucket(vbid) else {
+            return;
+        };
+        if !vb.set_needs_rereplication(true) {
+            self.stats
+                .vbuckets_need_rereplication
+                .fetch_add(1, Ordering::Relaxed);
+            println!(
+                "ALERT: {vbid} needs re-replication, its file is corrupt: {}",
+                result.errors.join(", ")
+            );
+        }
+    }
+
+    /// The oldest seqno any backup cursor of the vbucket has reached. Items
+    /// after it are still needed by that backup's next incremental.
+    pub fn min_backup_cursor_seqno(&self, vbid: Vbid) -> EngineResult<Option<u64>> {
+        Ok(self.get_backup_cursors(vbid)?.into_values().min())
+    }
+
+    /// Purge the vbucket's deletions older than the metadata purge interval,
+    /// keeping those a DCP stream or backup cursor has yet to read. The
+    /// latest item is always kept as it gives the vbucket its high seqno.
+    /// Fails with TemporaryFailure while the bucket is paused, as the purge
+    /// writes to the file, or if the store fails to purge.
+    pub fn purge_tombstones(&self, vbid: Vbid) -> EngineResult<PurgeResult> {
+        let locked_vb = self.get_locked_vbucket(vbid);
+        let vb = locked_vb.vb.as_ref().ok_or(EngineError::NotMyVbucket)?;
+        if self.is_paused() || vb.is_quarantined() {
+            return Err(EngineError::TemporaryFailure);
+        }
+        // Every version is kept for history, deletions included
+        if self.config.history_retention {
+            return Ok(PurgeResult::default());
+        }
+        // Compaction writes a new file, which the disk may have no room for
+        if self.is_read_only() {
+            return Ok(PurgeResult::default());
+        }
+        let store = self.vbucket_map.get_shard_by_vb_id(vbid).store();
+        let Some(vb_state) = store.get_cached_vb_state(vbid) else {
+            return Ok(PurgeResult::default());
+        };
+
+        let purge_before = vb
+            .now_secs()
+            .saturating_sub(self.config.metadata_purge_interval as u32);
+        let max_seqno = [
+            vb.checkpoint_manager.get_min_replication_cursor_seqno(),
+            // Purging what a backup needs can't be undone
+            self.min_backup_cursor_seqno(vbid)?,
+            Some((vb_state.high_seqno as u64).saturating_sub(1)),
+        ]
+        .into_iter()
+        .flatten()
+        .min()
+        .unwrap();
+        self.observers
+            .notify(|observer| observer.on_compaction_start(vbid));
+        let result = store.purge_tombstones(vbid, purge_before, max_seqno);
+        self.observers.notify(|observer| {
+            observer.on_compaction_end(vbid, result.as_ref().unwrap_or(&PurgeResult::default()))
+        });
+        let result = result.map_err(|e| {
+            println!("Failed to purge the tombstones of {vbid}: {e}");
+            EngineError::TemporaryFailure
+        })?;
+
+        if let Some(vb_state) = store.get_cached_vb_state(vbid) {
+            vb.set_purge_seqno(vb_state.purge_seqno);
+        }
+        vb.set_retained_tombstones(result.retained);
+        self.update_on_disk_deletes(vbid, vb, Some(&result));
+        self.stats
+            .tombstones_purged
+            .fetch_add(result.purged, Ordering::Relaxed);
+        Ok(result)
+    }
+
+    /// Compact the vbucket, first purging its old tombstones if asked to,
+    /// returning how many were purged and the bytes the rewrite reclaimed
+    pub fn compact_vbucket(&self, vbid: Vbid, purge_tombstones: bool) -> EngineResult<(u64, u64)> {
+        let purged = match purge_tombstones {
+            true => self.purge_tombstones(vbid)?.purged,
+            false => 0,
+        };
+        let locked_vb = self.get_locked_vbucket(vbid);
+        let vb = locked_vb.vb.as_ref().ok_or(EngineError::NotMyVbucket)?;
+        // The new file may not fit on the disk, and a paused bucket's files
+        // are being copied
+        if self.is_paused() || self.is_read_only() || vb.is_quarantined() {
+            return Err(EngineError::TemporaryFailure);
+        }
+        let store = self.vbucket_map.get_shard_by_vb_id(vbid).store();
+        let reclaimed = store.compact(vbid);
+        self.update_on_disk_deletes(vbid, vb, None);
+        Ok((purged, reclaimed))
+    }
+
+    /// Update the vbucket's count of its tombstones on disk from the
+    /// store's document counts, or failing those from what a purge saw
+    fn update_on_disk_deletes(&self, vbid: Vbid, vb: &VBucket, purge: Option<&PurgeResult>) {
+        let store = self.vbucket_map.get_shard_by_vb_id(vbid).store();
+        match (store.get_doc_counts(vbid), purge) {
+            (Some(counts), _) => vb.set_on_disk_deletes(counts.deleted, counts.deleted_size),
+            (None, Some(result)) => {
+                vb.set_on_disk_deletes(result.remaining, result.remaining_bytes)
+            }
+            (None, None) => {}
+        }
+    }
+
+    /// The vbuckets worth compacting to purge their tombstones, those with
+    /// the most bytes of them first. A vbucket is when the tombstones which
+    /// no consumer is waiting for are at least the tombstone purge ratio of
+    /// its documents, the live ones counted from the hash table.
+    pub fn tombstone_purge_candidates(&self) -> Vec<Vbid> {
+        if self.config.history_retention {
+            return Vec::new();
+        }
+        let mut candidates = Vec::new();
+        for vbid in self.vbucket_map.get_buckets() {
+            let Some(vb) = self.get_vbucket(vbid) else {
+                continue;
+            };
+            self.update_on_disk_deletes(vbid, &vb, None);
+            let deletes = vb.get_on_disk_deletes();
+            let purgeable = deletes.saturating_sub(vb.get_retained_tombstones());
+            let live = vb.hash_table.lock().num_items() as u64;
+            if purgeable > 0
+                && purgeable as f64 >= self.config.tombstone_purge_ratio * (deletes + live) as f64
+            {
+                candidates.push((vb.get_tombstone_bytes(), vbid));
+            }
+        }
+        candidates.sort_by(|a, b| b.cmp(a));
+        candidates.into_iter().map(|(_, vbid)| vbid).collect()
+    }
+
+    /// Delete the items in active vbuckets whose expiry time has passed,
+    /// returning how many. The stores' expiry indexes give the due keys, so
+    /// items expiring before they're persisted wait for the next run.
+    pub fn run_expiry_pager(&self) -> usize {
+        let mut expired = 0;
+        for vbid in self.vbucket_map.get_buckets_in_state(State::Active) {
+            let Some(vb) = self.get_vbucket(vbid) else {
+                continue;
+            };
+            let store = self.vbucket_map.get_shard_by_vb_id(vbid).store();
+            // The next run retries the vbucket
+            let keys = match store.get_expired_keys(vbid, vb.now_secs()) {
+                Ok(keys) => keys,
+                Err(e) => {
+                    println!("Failed to read the expiry index of {vbid}: {e}");
+                    continue;
+                }
+            };
+            for key in keys {
+                if self.expire_if_needed(&vb, &key) {
+                    expired += 1;
+                }
+            }
+        }
+        self.stats
+            .expired_pager
+            .fetch_add(expired as u64, Ordering::Relaxed);
+        expired
+    }
+
+    /// Scrub each vbucket's files once, returning how many corrupt chunks
+    /// were found. Vbuckets with mutations waiting to be persisted are busy
+    /// and left for the next pass. Stops early if the bucket shuts down.
+    pub fn scrub_vbuckets(&self) -> usize {
+        self.stats.scrub_vbuckets_done.store(0, Ordering::Relaxed);
+        let mut corrupt = 0;
+        for vbid in self.vbucket_map.get_buckets() {
+            if self.is_shutting_down() {
+                return corrupt;
+            }
+            let Some(vb) = self.get_vbucket(vbid) else {
+                continue;
+            };
+            let pending = vb
+                .checkpoint_manager
+                .get_num_items_for_cursor(PERSISTENCE_CURSOR);
+            if pending.is_some_and(|pending| pending > 0) {
+                continue;
+            }
+
+            let store = self.vbucket_map.get_shard_by_vb_id(vbid).store();
+            let result = store.scrub(vbid, &mut |bytes| {
+                self.io_throttle.acquire(bytes);
+                self.scrub_throttle.acquire(bytes);
+            });
+            for chunk in &result.corrupt_chunks {
+                println!("Scrub of {vbid} found corruption: {chunk}");
+            }
+            if !result.corrupt_chunks.is_empty() && self.config.corrupt_file_recovery {
+                // The vbucket may have gone since
+                let _ = self.recover_corrupt_vbucket(vbid);
+            }
+            corrupt += result.corrupt_chunks.len();
+            self.stats
+                .scrub_bytes_read
+                .fetch_add(result.bytes_read, Ordering::Relaxed);
+            self.stats
+                .scrub_corrupt_chunks
+                .fetch_add(result.corrupt_chunks.len() as u64, Ordering::Relaxed);
+            self.stats
+                .scrub_vbuckets_done
+                .fetch_add(1, Ordering::Relaxed);
+        }
+        self.stats.scrub_passes.fetch_add(1, Ordering::Relaxed);
+        corrupt
+    }
+
+    /// Check that each vbucket's counts of its documents agree, warning of
+    /// and returning the drifts found. Vbuckets with mutations waiting to be
+    /// persisted are busy, as their files are behind their hash tables, and
+    /// are left for the next pass.
+    pub fn reconcile_counts(&self) -> BTreeMap<Vbid, Vec<Drift>> {
+        let mut found = BTreeMap::new();
+        for vbid in self.vbucket_map.get_buckets() {
+            if self.is_shutting_down() {
+                return found;
+            }
+            // Held so the vbucket is neither flushed nor written to while
+            // it is counted
+            let locked_vb = self.get_locked_vbucket(vbid);
+            let Some(vb) = locked_vb.as_ref() else {
+                continue;
+            };
+            let _state_lock = vb.get_state_lock();
+            let pending = vb
+                .checkpoint_manager
+                .get_num_items_for_cursor(PERSISTENCE_CURSOR);
+            if pending.is_some_and(|pending| pending > 0) || vb.is_persistence_failing() {
+                self.stats.reconcile.record_skipped();
+                continue;
+            }
+
+            let mut counts = VBucketCounts::default();
+            {
+                let hash_table = vb.hash_table.lock();
+                for (key, value) in &hash_table.map {
+                    if !value.is_deleted() {
+                        counts.items += 1;
+                        let collection = collections::collection_id(key).unwrap_or_default();
+                        *counts.collection_items.entry(collection).or_default() += 1;
+                    }
+                }
+                counts.tracked_items = hash_table.num_items();
+                counts.tracked_collection_items = hash_table.collection_items().clone();
+            }
+            let store = self.vbucket_map.get_shard_by_vb_id(vbid).store();
+            counts.disk_items = store.get_doc_counts(vbid).and_then(|disk| {
+                vb.set_on_disk_deletes(disk.deleted, disk.deleted_size);
+                // The system events of the collections which exist are live
+                // documents too, though not in the hash table
+                let events: Vec<Vec<u8>> = vb
+                    .collection_ids()
+                    .into_iter()
+                    .map(collections::collection_event_key)
+                    .collect();
+                let live_events = match store.get_multi(vbid, &events) {
+                    Ok(events) => events
+                        .into_iter()
+                        .flatten()
+                        .filter(|event| event.value.is_some())
+                        .count(),
+                    // The disk count is left unchecked
+                    Err(e) => {
+                        println!("Failed to read the collection events of {vbid}: {e}");
+                        return None;
+                    }
+                };
+                Some(disk.live.saturating_sub(live_events as u64))
+            });
+            self.stats.reconcile.record_checked();
+
+            let drifts = counts.drifts();
+            for drift in &drifts {
+                println!("Document counts of {vbid} drifted: {drift}");
+            }
+            if !drifts.is_empty() {
+                found.insert(vbid, drifts);
+            }
+        }
+        self.stats.reconcile.record_pass(found.clone());
+        found
+    }
+
+    /// Re-read the vbucket's persisted state from disk, after a tool changed
+    /// its files behind the store's back, and tell the observers. None if
+    /// the vbucket has no files. Fails with TemporaryFailure, logging why,
+    /// if they can't be read.
+    pub fn refresh_vbucket_state(&self, vbid: Vbid) -> EngineResult<Option<VBucketState>> {
+        // Not while it is flushed
+        let locked_vb = self.get_locked_vbucket(vbid);
+        let store = self.vbucket_map.get_shard_by_vb_id(vbid).store();
+        let vb_state = store.refresh_vbucket_state(vbid).map_err(|e| {
+            println!("Failed to refresh the state of {vbid}: {e}");
+            EngineError::TemporaryFailure
+        })?;
+        if let Some(vb_state) = &vb_state {
+            if let Some(vb) = locked_vb.as_ref() {
+                vb.set_purge_seqno(vb_state.purge_seqno);
+            }
+            self.observers
+                .notify(|observer| observer.on_vbucket_state_refreshed(vbid, vb_state));
+        }
+        Ok(vb_state)
+    }
+
+    /// Recover from corruption in a vbucket's file by truncating it to its
+    /// latest clean commit, if corrupt_file_recovery allows, returning the
+    /// seqnos lost. The vbucket is rebuilt from what's left with a new
+    /// failover entry, so DCP clients which saw the lost seqnos roll back.
+    /// The old vbucket is marked dead to turn away operations still on it.
+    /// Fails with TemporaryFailure while the bucket is paused, or if the new
+    /// vbucket's state can't then be persisted, leaving it to the next flush
+    /// or shutdown.
+    pub fn recover_corrupt_vbucket(&self, vbid: Vbid) -> EngineResult<Option<TruncatedCommits>> {
+        let locked_vb = self.get_locked_vbucket(vbid);
+        let old_vb = locked_vb.vb.as_ref().ok_or(EngineError::NotMyVbucket)?;
+        if self.is_paused() {
+            return Err(EngineError::TemporaryFailure);
+        }
+        if !self.config.corrupt_file_recovery {
+            println!("Not recovering {vbid} as corrupt file recovery is disabled");
+            return Ok(None);
+        }
+        let store = self.vbucket_map.get_shard_by_vb_id(vbid).store();
+        let Some(truncated) = store.truncate_to_clean_commit(vbid) else {
+            println!("Unable to recover {vbid} from corruption");
+            return Ok(None);
+        };
+        if truncated.new_high_seqno == truncated.old_high_seqno {
+            println!("Latest commit of {vbid} is clean, nothing to recover");
+            return Ok(Some(truncated));
+        }
+
+        let vb_state = store
+            .get_cached_vb_state(vbid)
+            .unwrap_or_else(|| VBucketState::new(old_vb.state()));
+        // TODO: Get from config
+        let max_entries = 25;
+        let failover_table = if vb_state.failover_table.is_null() {
+            FailoverTable::new_empty(max_entries)
+        } else {
+            FailoverTable::new(
+                vb_state.failover_table.clone(),
+                max_entries,
+                vb_state.high_seqno,
+            )
+        };
+        failover_table.create_entry(truncated.new_high_seqno);
+        let state = old_vb.state();
+        // CASes carry on from the old vbucket's, as clients have seen those
+        let vb = self.make_vbucket(
+            vbid,
+            state,
+            failover_table,
+            truncated.new_high_seqno,
+            vb_state.max_cas.max(old_vb.get_max_cas()),
+        );
+        vb.set_purge_seqno(vb_state.purge_seqno);
+        warmup::dump_keys(self, store, &vb);
+        let vb_state = self.vb_state_to_persist(&vb);
+
+        old_vb.set_state(State::Dead);
+        self.vbucket_map.dec_vb_state_count(state);
+        self.vbucket_map.add_bucket(vb.clone());
+        // The file is truncated whatever happens, so the new vbucket is in
+        // place before its failover entry is persisted
+        self.snapshot_vbucket(&vb, &vb_state)?;
+
+        let lost = truncated.old_high_seqno - truncated.new_high_seqno;
+        println!(
+            "Recovered {vbid} from corruption by truncating it to seqno {}, losing seqnos {} to {}",
+            truncated.new_high_seqno,
+            truncated.new_high_seqno + 1,
+            truncated.old_high_seqno
+        );
+        self.stats
+            .corrupt_vbuckets_recovered
+            .fetch_add(1, Ordering::Relaxed);
+        self.stats
+            .corruption_seqnos_lost
+            .fetch_add(lost, Ordering::Relaxed);
+        Ok(Some(truncated))
+    }
+
+    /// Run task every interval on the bucket's clock, until the bucket
+    /// shuts down. The task doesn't keep the bucket alive.
+    fn schedule_periodic(
+        bucket: &EPBucketPtr,
+        name: &str,
+        interval: Duration,
+        task: impl Fn(&EPBucket) + Send + 'static,
+    ) {
+        let weak = Arc::downgrade(bucket);
+        let clock = bucket.config.clock.clone();
+        let task_name = name.to_string();
+        bucket.periodic_tasks.lock().insert(task_name.clone(), None);
+        // From when the task is scheduled, not when its thread gets going
+        let mut deadline = clock.now() + interval;
+        bucket.schedule_task(name, move || loop {
+            loop {
+                match weak.upgrade() {
+                    Some(bucket) if !bucket.is_shutting_down() => {}
+                    _ => return,
+                }
+                let now = clock.now();
+                if now >= deadline {
+                    break;
+                }
+                // Waking regularly to notice a shutdown
+                clock.sleep((deadline - now).min(Duration::from_millis(100)));
+            }
+            let Some(bucket) = weak.upgrade() else {
+                return;
+            };
+            task(&bucket);
+            deadline = clock.now() + interval;
+            bucket
+                .periodic_tasks
+                .lock()
+                .insert(task_name.clone(), Some(clock.now()));
+        });
+    }
+
+    /// Scrub the files in the background, scrubber_interval seconds apart,
+    /// unless scrubbing is disabled
+    pub fn start_scrubber(bucket: &EPBucketPtr) {
+        let interval = bucket.config.scrubber_interval;
+        if interval != 0 {
+            Self::schedule_periodic(
+                bucket,
+                "scrubber",
+                Duration::from_secs(interval),
+                |bucket| {
+                    bucket.scrub_vbuckets();
+                },
+            );
+        }
+    }
+
+    /// Reconcile the document counts in the background,
+    /// reconcile_interval seconds apart, unless it's disabled
+    pub fn start_reconciler(bucket: &EPBucketPtr) {
+        let interval = bucket.config.reconcile_interval;
+        if interval != 0 {
+            Self::schedule_periodic(
+                bucket,
+                "reconciler",
+                Duration::from_secs(interval),
+                |bucket| {
+                    bucket.reconcile_counts();
+                },
+            );
+        }
+    }
+
+    /// With an interval sync policy, sync on the interval even when there
+    /// are no commits to do it
+    pub fn start_syncer(bucket: &EPBucketPtr) {
+        if let couchstore::SyncPolicy::Interval(interval) = bucket.config.sync_policy {
+            Self::schedule_periodic(bucket, "syncer", interval, |bucket| {
+                bucket.sync_pending_commits();
+            });
+        }
+    }
+
+    /// Run the expiry pager every exp_pager_stime seconds, unless it's
+    /// disabled
+    pub fn start_expiry_pager(bucket: &EPBucketPtr) {
+        let interval = bucket.config.exp_pager_stime;
+        if interval != 0 {
+            Self::schedule_periodic(
+                bucket,
+                "expiry_pager",
+                Duration::from_secs(interval),
+                |bucket| {
+                    bucket.run_expiry_pager();
+                },
+            );
+        }
+    }
+
+    /// Check the free disk space now and every disk_check_interval seconds,
+    /// unless checking is disabled
+    pub fn start_disk_monitor(bucket: &EPBucketPtr) {
+        let interval = bucket.config.disk_check_interval;
+        if interval != 0 {
+            bucket.check_disk_space();
+            Self::schedule_periodic(
+                bucket,
+                "disk_monitor",
+                Duration::from_secs(interval),
+                |bucket| bucket.check_disk_space(),
+            );
+        }
+    }
+
     pub fn get_vbucket(&self, vbid: Vbid) -> Option<VBucketPtr> {
         self.vbucket_map.get_bucket(vbid)
     }
 
+    pub fn stats(&self) -> &EPStats {
+        &self.stats
+    }
+
+    pub fn get_stats(&self, add_stat: &mut dyn FnMut(&str, &str)) {
+        self.stats.add_stats(add_stat);
+        self.io_throttle.add_stats(add_stat);
+
+        // Disk activity summed over the shards
+        let (mut commits, mut commit_time_us, mut bytes_written) = (0, 0, 0);
+        for shard in &self.vbucket_map.shards {
+            let stats = shard.store().get_stats();
+            commits += stats.commits.load(Ordering::Relaxed);
+            commit_time_us += stats.commit_time_us.load(Ordering::Relaxed);
+            bytes_written += stats.bytes_written.load(Ordering::Relaxed);
+        }
+        add_stat("ep_commit_num", &commits.to_string());
+        add_stat("ep_commit_time_total_us", &commit_time_us.to_string());
+        add_stat("ep_io_total_write_bytes", &bytes_written.to_string());
+        add_stat("ep_disk_read_only", &self.is_read_only().to_string());
+        add_stat(
+            "ep_diskqueue_items",
+            &self.get_disk_queue_size().to_string(),
+        );
+        add_stat(
+            "ep_replication_throttled",
+            &self.is_replication_throttled().to_string(),
+        );
+
+        // How far an upgrade of the files to the current disk version is
+        let mut files_by_version = BTreeMap::new();
+        for shard in &self.vbucket_map.shards {
+            for (version, files) in shard.store().get_stats().files_by_disk_version() {
+                *files_by_version.entry(version).or_insert(0) += files;
+            }
+        }
+        for (version, files) in files_by_version {
+            add_stat(
+                &format!("ep_db_files_disk_version_{version}"),
+                &files.to_string(),
+            );
+        }
+
+        let retained: u64 = self
+            .vbucket_map
+            .get_buckets()
+            .into_iter()
+            .filter_map(|vbid| self.get_vbucket(vbid))
+            .map(|vb| vb.get_retained_tombstones())
+            .sum();
+        add_stat("ep_tombstones_retained", &retained.to_string());
+
+        let (deletes, bytes) = self
+            .vbucket_map
+            .get_buckets()
+            .into_iter()
+            .filter_map(|vbid| self.get_vbucket(vbid))
+            .fold((0, 0), |(deletes, bytes), vb| {
+                (
+                    deletes + vb.get_on_disk_deletes(),
+                    bytes + vb.get_tombstone_bytes(),
+                )
+            });
+        add_stat("ep_on_disk_deletes", &deletes.to_string());
+        add_stat("ep_tombstone_bytes", &bytes.to_string());
+    }
+
+    /// The stats of a group, the empty group being the default stats
+    pub fn get_stats_group(
+        &self,
+        group: &str,
+        add_stat: &mut dyn FnMut(&str, &str),
+    ) -> EngineResult<()> {
+        match group {
+            "" => self.get_stats(add_stat),
+            "kvstore" => self.get_kvstore_stats(add_stat),
+            "headers" => self.get_headers_stats(add_stat),
+            "warmup" => self.stats.warmup.add_stats(add_stat),
+            "reconcile" => self.stats.reconcile.add_stats(add_stat),
+            "dcp" => self.get_dcp_stats(add_stat),
+            "validation" => self.get_validation_stats(add_stat),
+            "tombstones" => self.get_tombstone_stats(add_stat),
+            "ttl_update" => {
+                if let Some(progress) = &*self.ttl_update.lock() {
+                    progress.add_stats(add_stat);
+                }
+            }
+            "compact_all" => {
+                if let Some(progress) = &*self.compact_all.lock() {
+                    progress.add_stats(add_stat);
+                }
+            }
+            _ => return Err(EngineError::KeyNotFound),
+        }
+        Ok(())
+    }
+
+    /// Each DCP connection and its streams
+    fn get_dcp_stats(&self, add_stat: &mut dyn FnMut(&str, &str)) {
+        let producers: Vec<Arc<DcpProducer>> = self
+            .dcp_producers
+            .lock()
+            .iter()
+            .filter_map(Weak::upgrade)
+            .collect();
+        add_stat("ep_dcp_count", &producers.len().to_string());
+        for producer in producers {
+            producer.add_stats(add_stat);
+        }
+    }
+
+    /// Each vbucket's tombstones on disk, counted afresh where the store
+    /// can, and which vbuckets are worth purging them from
+    fn get_tombstone_stats(&self, add_stat: &mut dyn FnMut(&str, &str)) {
+        let candidates = self.tombstone_purge_candidates();
+        add_stat(
+            "ep_tombstone_purge_candidates",
+            &candidates.len().to_string(),
+        );
+        for vbid in self.vbucket_map.get_buckets() {
+            let Some(vb) = self.get_vbucket(vbid) else {
+                continue;
+            };
+            add_stat(
+                &format!("vb_{vbid}:on_disk_deletes"),
+                &vb.get_on_disk_deletes().to_string(),
+            );
+            add_stat(
+                &format!("vb_{vbid}:tombstone_bytes"),
+                &vb.get_tombstone_bytes().to_string(),
+            );
+            add_stat(
+                &format!("vb_{vbid}:tombstones_retained"),
+                &vb.get_retained_tombstones().to_string(),
+            );
+            add_stat(
+                &format!("vb_{vbid}:purge_candidate"),
+                &candidates.contains(&vbid).to_string(),
+            );
+        }
+    }
+
+    /// Each shard's disk activity, prefixed with rw_<shard>:
+    fn get_kvstore_stats(&self, add_stat: &mut dyn FnMut(&str, &str)) {
+        for (shard_id, shard) in self.vbucket_map.shards.iter().enumerate() {
+            shard.store().get_stats().add_stats(&mut |key, value| {
+                add_stat(&format!("rw_{shard_id}:{key}"), value);
+            });
+        }
+    }
+
+    /// Each vbucket's latest commits, newest first, as
+    /// vb_<vbid>:header_<n>:<field>. For checking how often vbuckets are
+    /// committed, and which headers a rollback could have gone back to.
+    fn get_headers_stats(&self, add_stat: &mut dyn FnMut(&str, &str)) {
+        for vbid in self.vbucket_map.get_buckets() {
+            let store = self.vbucket_map.get_shard_by_vb_id(vbid).store();
+            for (n, header) in store.get_stats().recent_headers(vbid).iter().enumerate() {
+                let prefix = format!("vb_{vbid}:header_{n}");
+                add_stat(
+                    &format!("{prefix}:file_revision"),
+                    &header.file_revision.to_string(),
+                );
+                add_stat(&format!("{prefix}:position"), &header.position.to_string());
+                add_stat(
+                    &format!("{prefix}:high_seqno"),
+                    &header.high_seqno.to_string(),
+                );
+                add_stat(
+                    &format!("{prefix}:timestamp"),
+                    &header.timestamp.to_string(),
+                );
+            }
+        }
+    }
+
+    /// Limits the disk IO of the bucket's background tasks
+    pub fn io_throttle(&self) -> &IOThrottle {
+        &self.io_throttle
+    }
+
     pub fn get_vbuckets(&self) -> &VBucketMap {
         &self.vbucket_map
     }
 
     /// Return a pointer to the given VBucket, acquiring the appropriate VB
     /// mutex lock at the same time.
-    pub fn get_locked_vbucket(&self, vbid: Vbid) -> LockedVbucketPtr {
+    pub fn get_locked_vbucket(&self, vbid: Vbid) -> LockedVbucketPtr<'_> {
         let _guard = self.vb_mutexes[usize::from(vbid)].lock();
         let vb = self.vbucket_map.get_bucket(vbid);
         LockedVbucketPtr { vb, _guard }
     }
 
-    pub fn flush_vbucket_unlocked(&self, _vb: &LockedVbucketPtr) {}
+    /// Memory available to checkpoints across all vbuckets
+    pub fn get_checkpoint_memory_quota(&self) -> usize {
+        (self.stats.get_max_data_size() as f64 * self.config.checkpoint_memory_ratio) as usize
+    }
+
+    /// Free checkpoint memory once it exceeds the recovery upper mark, until
+    /// it drops below the lower mark. Closed checkpoints no cursor needs are
+    /// released first, then items every cursor has read are expelled, and
+    /// as a last resort the slowest replication cursors are dropped (their
+    /// streams must backfill from disk). Returns the bytes freed.
+    pub fn recover_checkpoint_memory(&self) -> usize {
+        let quota = self.get_checkpoint_memory_quota() as f64;
+        let upper_mark = (quota * self.config.checkpoint_memory_recovery_upper_mark) as usize;
+        let lower_mark = (quota * self.config.checkpoint_memory_recovery_lower_mark) as usize;
+        let checkpoint_memory = || self.stats.memory.domain_used(MemoryDomain::Checkpoint);
+
+        if checkpoint_memory() <= upper_mark {
+            return 0;
+        }
+
+        let vbuckets: Vec<VBucketPtr> = self
+            .vbucket_map
+            .get_buckets()
+            .into_iter()
+            .filter_map(|vbid| self.get_vbucket(vbid))
+            .collect();
+
+        let mut freed = 0;
+        for vb in &vbuckets {
+            let removed = vb.checkpoint_manager.remove_closed_unref_checkpoints();
+            self.stats
+                .mem_freed_by_checkpoint_removal
+                .fetch_add(removed as u64, Ordering::Relaxed);
+            freed += removed;
+        }
+        if checkpoint_memory() <= lower_mark {
+            return freed;
+        }
+
+        for vb in &vbuckets {
+            let (expelled, expel_freed) = vb.checkpoint_manager.expel_unreferenced_items();
+            self.stats
+                .items_expelled_from_checkpoints
+                .fetch_add(expelled as u64, Ordering::Relaxed);
+            self.stats
+                .mem_freed_by_checkpoint_item_expel
+                .fetch_add(expel_freed as u64, Ordering::Relaxed);
+            freed += expel_freed;
+        }
+
+        for vb in &vbuckets {
+            if checkpoint_memory() <= lower_mark {
+                break;
+            }
+            let dropped = vb.checkpoint_manager.drop_slowest_cursors();
+            if dropped.is_empty() {
+                continue;
+            }
+            println!("{}: dropped cursors {:?} to free memory", vb.id, dropped);
+            self.stats
+                .cursors_dropped
+                .fetch_add(dropped.len() as u64, Ordering::Relaxed);
+            let removed = vb.checkpoint_manager.remove_closed_unref_checkpoints();
+            let (expelled, expel_freed) = vb.checkpoint_manager.expel_unreferenced_items();
+            self.stats
+                .mem_freed_by_checkpoint_removal
+                .fetch_add(removed as u64, Ordering::Relaxed);
+            self.stats
+                .items_expelled_from_checkpoints
+                .fetch_add(expelled as u64, Ordering::Relaxed);
+            self.stats
+                .mem_freed_by_checkpoint_item_expel
+                .fetch_add(expel_freed as u64, Ordering::Relaxed);
+            freed += removed + expel_freed;
+        }
+
+        freed
+    }
+
+    /// Persist the vbucket's outstanding mutations. Returns the number of
+    /// items flushed.
+    pub fn flush_vbucket(&self, vbid: Vbid) -> usize {
+        self.flush_with_retries(vbid, false)
+    }
+
+    /// As flush_vbucket, for a caller already holding the vbucket's lock.
+    /// A failed commit isn't retried, as that would hold the lock while
+    /// backing off.
+    pub fn flush_vbucket_unlocked(&self, locked_vb: &LockedVbucketPtr) -> usize {
+        if self.is_paused() || self.is_read_only() {
+            return 0;
+        }
+        if locked_vb.vb.as_ref().is_some_and(|vb| vb.is_quarantined()) {
+            return 0;
+        }
+        self.flush_locked_vbucket(locked_vb)
+    }
 
-    pub fn get(&self, key: Vec<u8>) -> Option<StoredValue> {
-        let vbid = v_bucket_hash(&key, 1024);
-        // TODO: This is a hack to get around the fact that we don't have
-        // collection support yet. We need to add support for collections
-        let key_with_collection_id = {
-            let mut key_with_collection_id = Vec::from("\0");
-            key_with_collection_id.extend(key);
-            key_with_collection_id
+    /// Flush the vbucket, retrying a failed commit with exponential backoff
+    /// up to flusher_commit_retries times. The vbucket's lock is let go
+    /// while backing off, so its operations aren't held up. Unless forced,
+    /// nothing is flushed while the bucket is paused or read-only.
+    fn flush_with_retries(&self, vbid: Vbid, force: bool) -> usize {
+        let mut backoff = Duration::from_millis(self.config.flusher_retry_backoff_ms);
+        let mut retries = 0;
+        loop {
+            if !force && (self.is_paused() || self.is_read_only()) {
+                return 0;
+            }
+            let locked_vb = self.get_locked_vbucket(vbid);
+            let Some(vb) = &locked_vb.vb else {
+                return 0;
+            };
+            if vb.is_quarantined() {
+                return 0;
+            }
+            let e = match self.try_flush_vbucket(vb) {
+                Ok(flushed) => return flushed,
+                Err(e) => e,
+            };
+            if retries == self.config.flusher_commit_retries {
+                self.persistence_failed(vb, &e);
+                return 0;
+            }
+            drop(locked_vb);
+            println!("Failed to commit {vbid}, retrying in {backoff:?}: {e}");
+            std::thread::sleep(backoff);
+            backoff *= 2;
+            retries += 1;
+        }
+    }
+
+    /// Flush the vbucket once, without retrying a failed commit, even if it
+    /// is quarantined
+    fn flush_locked_vbucket(&self, locked_vb: &LockedVbucketPtr) -> usize {
+        let Some(vb) = &locked_vb.vb else {
+            return 0;
         };
-        let vb = self.get_vbucket(Vbid::from(vbid)).unwrap();
-        vb.get(&key_with_collection_id)
+        self.try_flush_vbucket(vb).unwrap_or_else(|e| {
+            self.persistence_failed(vb, &e);
+            0
+        })
     }
+
+    /// Commit the vbucket's outstanding mutations, returning how many. The
+    /// caller holds the vbucket's lock. If the commit fails the persistence
+    /// cursor stays where it was, so they are flushed again next time.
+    fn try_flush_vbucket(&self, vb: &VBucket) -> Result<usize, CommitError> {
+        let mut to_flush = vb
+            .checkpoint_manager
+            .peek_items_for_cursor(PERSISTENCE_CURSOR)
+            .expect("the persistence cursor is never dropped");
+        let Some(last) = to_flush.items.last() else {
+            vb.checkpoint_manager
+                .advance_cursor(PERSISTENCE_CURSOR, &to_flush);
+            return Ok(0);
+        };
+        let high_seqno = last.by_seqno;
+
+        let store = self.vbucket_map.get_shard_by_vb_id(vb.id).store();
+
+        // Only the latest mutation of each key needs persisting, unless the
+        // store keeps history
+        let properties = store.get_storage_properties();
+        let keep_history = self.config.history_retention && properties.historical_snapshots;
+        let items = if properties.automatic_deduplication || keep_history {
+            std::mem::take(&mut to_flush.items)
+        } else {
+            let mut seen = HashSet::new();
+            let mut items: Vec<QueuedItem> = to_flush
+                .items
+                .iter()
+                .rev()
+                .filter(|item| seen.insert(&item.key))
+                .cloned()
+                .collect();
+            items.reverse();
+            items
+        };
+        let mut vb_state = self.vb_state_to_persist(vb);
+        vb_state.max_visible_seqno = high_seqno;
+        vb_state.checkpoint_id = to_flush.checkpoint_id;
+        vb_state.persisted_seqno = high_seqno;
+
+        // If the batch ends part way through a snapshot (a replica which has
+        // not yet received all of it) the snapshot range must be persisted,
+        // so after a restart the replica knows its data is only consistent
+        // once it has received the rest of the snapshot.
+        if high_seqno < to_flush.snap_end {
+            vb_state.snap_start = to_flush.snap_start;
+            vb_state.snap_end = to_flush.snap_end;
+        } else {
+            vb_state.snap_start = high_seqno;
+            vb_state.snap_end = high_seqno;
+        }
+
+        if let Err(e) = store.commit(vb.id, &items, &vb_state) {
+            self.stats
+                .item_commit_failed
+                .fetch_add(1, Ordering::Relaxed);
+            return Err(e);
+        }
+        vb.checkpoint_manager
+            .advance_cursor(PERSISTENCE_CURSOR, &to_flush);
+        self.persistence_succeeded(vb);
+        vb.mark_persisted(&items);
+        vb.set_persisted_seqno(high_seqno);
+        self.observers
+            .notify(|observer| observer.on_flush_complete(vb.id, items.len(), high_seqno));
+
+        Ok(items.len())
+    }
+
+    /// Account for a failed flush or state snapshot of the vbucket, marking
+    /// it as failing to persist and quarantining it once too many have
+    /// failed in a row. The caller holds the vbucket's lock.
+    fn persistence_failed(&self, vb: &VBucket, e: &CommitError) {
+        if !vb.set_persistence_failing(true) {
+            self.stats
+                .vbuckets_persistence_failing
+                .fetch_add(1, Ordering::Relaxed);
+            println!(
+                "ALERT: {} is failing to persist, its mutations are only in memory: {e}",
+                vb.id
+            );
+        }
+        let failures = vb.record_flush_failure();
+        let limit = self.config.quarantine_flush_failures;
+        if limit > 0 && failures >= limit {
+            self.quarantine_vbucket(vb, failures);
+        }
+    }
+
+    fn persistence_succeeded(&self, vb: &VBucket) {
+        vb.reset_flush_failures();
+        if vb.set_persistence_failing(false) {
+            self.stats
+                .vbuckets_persistence_failing
+                .fetch_sub(1, Ordering::Relaxed);
+            println!("{} is persisting again", vb.id);
+        }
+    }
+
+    /// Mark the vbucket dead, so it takes no traffic and isn't flushed or
+    /// compacted, rather than retrying its file for ever. Its mutations stay
+    /// in memory, to be persisted once it is released. The caller holds the
+    /// vbucket's lock.
+    fn quarantine_vbucket(&self, vb: &VBucket, failures: u32) {
+        let old = vb.state();
+        if !vb.quarantine(old) {
+            return;
+        }
+        vb.set_state(State::Dead);
+        self.vbucket_map.dec_vb_state_count(old);
+        self.vbucket_map.inc_vb_state_count(State::Dead);
+        self.stats
+            .vbuckets_quarantined
+            .fetch_add(1, Ordering::Relaxed);
+        self.observers
+            .notify(|observer| observer.on_vbucket_state_change(vb.id, old, State::Dead));
+        println!(
+            "ALERT: quarantined {} after {failures} failed flushes, it was {old:?}",
+            vb.id
+        );
+    }
+
+    /// Release a quarantined vbucket once its disk is fixed, returning it
+    /// to the state it had. Its outstanding mutations are flushed first, and
+    /// if that fails it stays quarantined and this fails with
+    /// TemporaryFailure, as it does while the bucket is paused.
+    pub fn release_quarantine(&self, vbid: Vbid) -> EngineResult<()> {
+        let locked_vb = self.get_locked_vbucket(vbid);
+        let vb = locked_vb.vb.as_ref().ok_or(EngineError::NotMyVbucket)?;
+        let state = vb.quarantined_from().ok_or(EngineError::InvalidArguments)?;
+        if self.is_paused() || self.is_read_only() {
+            return Err(EngineError::TemporaryFailure);
+        }
+        self.flush_locked_vbucket(&locked_vb);
+        if vb.is_persistence_failing() {
+            return Err(EngineError::TemporaryFailure);
+        }
+
+        let mut vb_state = self.vb_state_to_persist(vb);
+        vb_state.state = state;
+        self.snapshot_vbucket(vb, &vb_state)?;
+        vb.release_quarantine();
+        vb.set_state(state);
+        self.vbucket_map.dec_vb_state_count(State::Dead);
+        self.vbucket_map.inc_vb_state_count(state);
+        self.stats
+            .vbuckets_quarantined
+            .fetch_sub(1, Ordering::Relaxed);
+        self.observers
+            .notify(|observer| observer.on_vbucket_state_change(vbid, State::Dead, state));
+        println!("Released {vbid} from quarantine as {state:?}");
+        Ok(())
+    }
+
+    /// The vbuckets quarantined for failing to persist
+    pub fn quarantined_vbuckets(&self) -> Vec<Vbid> {
+        self.vbucket_map
+            .get_buckets()
+            .into_iter()
+            .filter(|vbid| {
+                self.get_vbucket(*vbid)
+                    .is_some_and(|vb| vb.is_quarantined())
+            })
+            .collect()
+    }
+
+    /// Delete the key if it has expired, telling the observers
+    fn expire_if_needed(&self, vb: &VBucket, key: &[u8]) -> bool {
+        let expired = vb.expire_if_needed(key);
+        if expired {
+            self.observers
+                .notify(|observer| observer.on_item_expired(vb.id, key));
+        }
+        expired
+    }
+
+    /// The vbucket's current state, based on the state last persisted
+    fn vb_state_to_persist(&self, vb: &VBucket) -> VBucketState {
+        let store = self.vbucket_map.get_shard_by_vb_id(vb.id).store();
+        let mut vb_state = store
+            .get_cached_vb_state(vb.id)
+            .unwrap_or_else(|| VBucketState::new(vb.state()));
+        vb_state.state = vb.state();
+        vb_state.max_cas = vb.get_max_cas();
+        vb_state.failover_table = vb.failover_table.to_json();
+        vb_state
+    }
+
+    /// Get a value, decompressed if it's stored compressed
+    pub fn get(&self, key: Vec<u8>) -> EngineResult<StoredValue> {
+        self.get_for_client(key, false)
+    }
+
+    /// Get a value for a client, which receives compressed values as they
+    /// are stored only if it negotiated snappy
+    pub fn get_for_client(&self, key: Vec<u8>, snappy_enabled: bool) -> EngineResult<StoredValue> {
+        let mut trace = self.trace_op("get");
+        if self.is_degraded_mode() {
+            return Err(EngineError::TemporaryFailure);
+        }
+        let vbid = vbucket_for_key(&key, self.config.max_vbuckets);
+        let key = self.stored_key(key);
+        // A quarantined vbucket's file can't be read from
+        let vb = self
+            .get_vbucket(vbid)
+            .filter(|vb| !vb.is_quarantined())
+            .ok_or(EngineError::NotMyVbucket)?;
+        let mut value = trace.phase("hash_table", || {
+            if self.expire_if_needed(&vb, &key) {
+                self.stats.expired_access.fetch_add(1, Ordering::Relaxed);
+                return Err(EngineError::KeyNotFound);
+            }
+            vb.get(&key)
+                .filter(|value| !value.is_deleted())
+                .ok_or(EngineError::KeyNotFound)
+        })?;
+        if !value.is_resident() {
+            // The value needs fetching from disk
+            return Err(EngineError::WouldBlock);
+        }
+        if !snappy_enabled && value.datatype.contains(Datatype::SNAPPY) {
+            value.inflate();
+            self.stats.values_inflated.fetch_add(1, Ordering::Relaxed);
+        }
+        Ok(hide_lock_cas(&vb, value))
+    }
+
+    /// Get a value and lock the key for lock_timeout seconds (0 for the
+    /// default). The returned CAS must be given to modify or unlock the key
+    /// while it is locked.
+    pub fn get_locked(&self, key: Vec<u8>, lock_timeout: u32) -> EngineResult<StoredValue> {
+        let mut trace = self.trace_op("get_locked");
+        if self.is_degraded_mode() {
+            return Err(EngineError::TemporaryFailure);
+        }
+        let lock_timeout = if lock_timeout == 0 || lock_timeout > self.config.getl_max_timeout {
+            self.config.getl_default_timeout
+        } else {
+            lock_timeout
+        };
+        let vbid = vbucket_for_key(&key, self.config.max_vbuckets);
+        let key = self.stored_key(key);
+        let vb = self.get_vbucket(vbid).ok_or(EngineError::NotMyVbucket)?;
+        let mut value = trace.phase("hash_table", || {
+            if self.expire_if_needed(&vb, &key) {
+                self.stats.expired_access.fetch_add(1, Ordering::Relaxed);
+                return Err(EngineError::KeyNotFound);
+            }
+            self.count_lock_error(vb.get_locked(&key, lock_timeout))
+        })?;
+        self.stats.locks_taken.fetch_add(1, Ordering::Relaxed);
+        if value.datatype.contains(Datatype::SNAPPY) {
+            value.inflate();
+            self.stats.values_inflated.fetch_add(1, Ordering::Relaxed);
+        }
+        Ok(value)
+    }
+
+    /// Whether the key's latest mutation has been persisted, and its CAS
+    pub fn observe(&self, key: Vec<u8>) -> EngineResult<(KeyState, u64)> {
+        let vbid = vbucket_for_key(&key, self.config.max_vbuckets);
+        let vb = self.get_vbucket(vbid).ok_or(EngineError::NotMyVbucket)?;
+        vb.observe(&self.stored_key(key))
+    }
+
+    /// Where the key is in the hash table, the checkpoints and the
+    /// vbucket's file, for debugging. Reads the file, so isn't for the
+    /// front end's hot path.
+    pub fn trace_doc(&self, key: Vec<u8>) -> EngineResult<DocTrace> {
+        let vbid = vbucket_for_key(&key, self.config.max_vbuckets);
+        let key = self.stored_key(key);
+        let vb = self.get_vbucket(vbid).ok_or(EngineError::NotMyVbucket)?;
+        let hash_table = vb
+            .get(&key)
+            .map(|value| HashTableEntry::new(&value, vb.now_secs()));
+        let checkpoints = vb.checkpoint_manager.find_key(&key);
+        let disk = match vb.is_quarantined() {
+            true => None,
+            false => {
+                let store = self.vbucket_map.get_shard_by_vb_id(vbid).store();
+                store
+                    .get_multi(vbid, std::slice::from_ref(&key))
+                    .map_err(|e| {
+                        println!("Failed to read the key from {vbid}: {e}");
+                        EngineError::TemporaryFailure
+                    })?
+                    .pop()
+                    .flatten()
+                    .map(|item| DiskEntry::from(&item))
+            }
+        };
+        Ok(DocTrace {
+            vbid,
+            vbucket_state: vb.state(),
+            hash_table,
+            checkpoints,
+            disk,
+        })
+    }
+
+    /// How far the vbucket's mutations have been persisted, as
+    /// OBSERVE_SEQNO reports it
+    pub fn observe_seqno(&self, vbid: Vbid) -> EngineResult<ObserveSeqno> {
+        let vb = self
+            .get_vbucket(vbid)
+            .filter(|vb| vb.state() != State::Dead)
+            .ok_or(EngineError::NotMyVbucket)?;
+        Ok(ObserveSeqno {
+            vb_uuid: vb.failover_table.get_latest_uuid(),
+            last_persisted_seqno: vb.get_persisted_seqno(),
+            current_seqno: vb.get_high_seqno(),
+        })
+    }
+
+    /// The vb_uuid and seqno of every active vbucket at one instant. All the
+    /// vbuckets are locked while they are read, so no flush is part way
+    /// through and no write is being queued: the persisted seqnos are what
+    /// a backup of the files would hold, and the high seqnos a point an
+    /// at_plus query can wait for.
+    pub fn get_mutation_tokens(&self, seqno: TokenSeqno) -> MutationTokenVector {
+        let locked: Vec<LockedVbucketPtr> = self
+            .vbucket_map
+            .get_buckets()
+            .into_iter()
+            .map(|vbid| self.get_locked_vbucket(vbid))
+            .collect();
+        let vbuckets: Vec<&VBucketPtr> = locked
+            .iter()
+            .filter_map(|locked| locked.vb.as_ref())
+            .collect();
+        let _state_locks: Vec<_> = vbuckets.iter().map(|vb| vb.get_state_lock()).collect();
+        let tokens = vbuckets
+            .iter()
+            .filter(|vb| vb.state() == State::Active)
+            .map(|vb| MutationToken {
+                vbid: vb.id,
+                vb_uuid: vb.failover_table.get_latest_uuid(),
+                seqno: match seqno {
+                    TokenSeqno::High => vb.get_high_seqno(),
+                    TokenSeqno::Persisted => vb.get_persisted_seqno(),
+                },
+            })
+            .collect();
+        MutationTokenVector { tokens }
+    }
+
+    /// Wait until the token's vbucket has reached its seqno, for a read
+    /// which must see the write the token came from, such as a session's
+    /// own write read from a replica. Fails with TemporaryFailure if it
+    /// hasn't within the timeout, and with Rollback if the write was lost
+    /// to a failover, giving the seqno at which its history was left. A
+    /// replica doesn't share its active's failover table, so there the
+    /// seqno alone is waited for.
+    pub fn wait_for_token(&self, token: &MutationToken, timeout: Duration) -> EngineResult<()> {
+        let vb = self
+            .get_vbucket(token.vbid)
+            .filter(|vb| vb.state() != State::Dead)
+            .ok_or(EngineError::NotMyVbucket)?;
+        if let Some(branch_end) = vb.failover_table.lost_at(token.vb_uuid, token.seqno) {
+            return Err(EngineError::Rollback(branch_end));
+        }
+        if !vb.wait_for_seqno(token.seqno, Instant::now() + timeout) {
+            return Err(EngineError::TemporaryFailure);
+        }
+        Ok(())
+    }
+
+    /// Release a lock taken with get_locked
+    pub fn unlock(&self, key: Vec<u8>, cas: u64) -> EngineResult<()> {
+        let mut trace = self.trace_op("unlock");
+        if self.is_degraded_mode() {
+            return Err(EngineError::TemporaryFailure);
+        }
+        let vbid = vbucket_for_key(&key, self.config.max_vbuckets);
+        let vb = self.get_vbucket(vbid).ok_or(EngineError::NotMyVbucket)?;
+        trace.phase("hash_table", || {
+            self.count_lock_error(vb.unlock(&self.stored_key(key), cas))
+        })
+    }
+
+    /// The key a client's key is stored under, in the default collection:
+    /// transformed, if the bucket has a key transform
+    fn stored_key(&self, key: Vec<u8>) -> Vec<u8> {
+        match &self.config.key_transform {
+            Some(transform) => key_with_default_collection(transform.encode(&key)),
+            None => key_with_default_collection(key),
+        }
+    }
+
+    /// The client's key of a key stored in the default collection
+    fn client_key(&self, stored: &[u8]) -> Option<Vec<u8>> {
+        let key = stored.strip_prefix(b"\0")?;
+        match &self.config.key_transform {
+            Some(transform) => transform.decode(key),
+            None => Some(key.to_vec()),
+        }
+    }
+
+    /// The keys of the vbucket's live documents in the default collection,
+    /// as clients know them, read from the vbucket file in seqno order.
+    /// Mutations not yet persisted are left out, and so are items which
+    /// can't be read, which mark the vbucket for re-replication.
+    pub fn scan_keys(&self, vbid: Vbid) -> EngineResult<Vec<Vec<u8>>> {
+        self.get_vbucket(vbid).ok_or(EngineError::NotMyVbucket)?;
+        let store = self.vbucket_map.get_shard_by_vb_id(vbid).store();
+        let mut keys = Vec::new();
+        // The values tell live documents from deletions, left compressed
+        // as they're not needed
+        let result = store.scan(
+            vbid,
+            0,
+            ValueFilter::ValuesCompressed,
+            ScanErrorPolicy::SkipAndReport,
+            &mut |item| {
+                if item.value.is_none() {
+                    return;
+                }
+                match self.client_key(&item.key) {
+                    Some(key) => keys.push(key),
+                    None if item.key.starts_with(b"\0") => {
+                        println!("{vbid}: skipping a key the key transform can't decode");
+                    }
+                    None => {}
+                }
+            },
+        );
+        if !result.is_complete() {
+            self.on_scan_errors(vbid, &result);
+        }
+        Ok(keys)
+    }
+
+    /// A page of the keys starting with prefix in the default collection,
+    /// in key order, listed from the active vbuckets' by-id indexes without
+    /// reading values. Pass the page's continuation back to get the next.
+    /// As with scan_keys, mutations not yet persisted are left out. Keys
+    /// stored through a key transform aren't ordered by the client's keys,
+    /// so can't be listed by prefix. Fails with TemporaryFailure, logging
+    /// why, if a vbucket's keys can't be read.
+    pub fn list_keys(
+        &self,
+        prefix: &[u8],
+        limit: usize,
+        continuation: Option<&[u8]>,
+    ) -> EngineResult<KeyPage> {
+        if limit == 0 || self.config.key_transform.is_some() {
+            return Err(EngineError::InvalidArguments);
+        }
+        let stored_prefix = key_with_default_collection(prefix.to_vec());
+        // The end of the prefix, or of the default collection
+        let end_key = prefix_end(&stored_prefix);
+        let start_key = match continuation {
+            // Just after the key the previous page ended with
+            Some(last) => {
+                let mut start_key = key_with_default_collection(last.to_vec());
+                start_key.push(0);
+                start_key.max(stored_prefix)
+            }
+            None => stored_prefix,
+        };
+
+        // Each vbucket's first keys, of which the first of all are the page
+        let mut keys = Vec::new();
+        let mut more = false;
+        for vbid in self.vbucket_map.get_buckets_in_state(State::Active) {
+            let store = self.vbucket_map.get_shard_by_vb_id(vbid).store();
+            let vb_keys = store
+                .list_keys(vbid, &start_key, end_key.as_deref(), limit)
+                .map_err(|e| {
+                    println!("Failed to read the keys of {vbid}: {e}");
+                    EngineError::TemporaryFailure
+                })?;
+            more |= vb_keys.len() == limit;
+            keys.extend(vb_keys);
+        }
+        keys.sort_unstable();
+        more |= keys.len() > limit;
+        keys.truncate(limit);
+        let keys: Vec<Vec<u8>> = keys.into_iter().map(|key| key[1..].to_vec()).collect();
+        let continuation = more.then(|| keys.last().cloned()).flatten();
+        Ok(KeyPage { keys, continuation })
+    }
+
+    /// Time a front-end operation, to log it if it's slow
+    fn trace_op(&self, op: &'static str) -> OpTrace<'_> {
+        let threshold = (self.config.slow_op_threshold_ms != 0)
+            .then(|| Duration::from_millis(self.config.slow_op_threshold_ms));
+        OpTrace::new(op, threshold, &self.stats.slow_ops)
+    }
+
+    fn count_lock_error<T>(&self, result: EngineResult<T>) -> EngineResult<T> {
+        if matches!(result, Err(EngineError::Locked)) {
+            self.stats.lock_errors.fetch_add(1, Ordering::Relaxed);
+        }
+        result
+    }
+
+    /// Get several values, decompressed. The keys are looked up a vbucket at
+    /// a time, and the values of each vbucket which aren't resident are
+    /// fetched from disk with one bulk read.
+    pub fn get_multi(&self, keys: Vec<Vec<u8>>) -> Vec<EngineResult<StoredValue>> {
+        let mut trace = self.trace_op("get_multi");
+        if self.is_degraded_mode() {
+            return vec![Err(EngineError::TemporaryFailure); keys.len()];
+        }
+        let mut results = vec![Err(EngineError::KeyNotFound); keys.len()];
+        let mut by_vbucket: BTreeMap<Vbid, Vec<usize>> = BTreeMap::new();
+        for (i, key) in keys.iter().enumerate() {
+            let vbid = vbucket_for_key(key, self.config.max_vbuckets);
+            by_vbucket.entry(vbid).or_default().push(i);
+        }
+        let keys: Vec<Vec<u8>> = keys.into_iter().map(|key| self.stored_key(key)).collect();
+
+        for (vbid, indexes) in by_vbucket {
+            let Some(vb) = self.get_vbucket(vbid).filter(|vb| !vb.is_quarantined()) else {
+                for i in indexes {
+                    results[i] = Err(EngineError::NotMyVbucket);
+                }
+                continue;
+            };
+            let vb_keys: Vec<Vec<u8>> = indexes.iter().map(|&i| keys[i].clone()).collect();
+            let mut to_fetch = Vec::new();
+            let values = trace.phase("hash_table", || vb.get_multi(&vb_keys));
+            for (&i, value) in indexes.iter().zip(values) {
+                results[i] = match value {
+                    Some(value) if value.is_deleted() => Err(EngineError::KeyNotFound),
+                    Some(value)
+                        if value.expiry_time != 0 && self.expire_if_needed(&vb, &keys[i]) =>
+                    {
+                        self.stats.expired_access.fetch_add(1, Ordering::Relaxed);
+                        Err(EngineError::KeyNotFound)
+                    }
+                    Some(value) if !value.is_resident() => {
+                        to_fetch.push(i);
+                        Err(EngineError::WouldBlock)
+                    }
+                    Some(value) => Ok(hide_lock_cas(&vb, value)),
+                    None => Err(EngineError::KeyNotFound),
+                };
+            }
+            if to_fetch.is_empty() {
+                continue;
+            }
+
+            let fetch_keys: Vec<Vec<u8>> = to_fetch.iter().map(|&i| keys[i].clone()).collect();
+            let store = self.vbucket_map.get_shard_by_vb_id(vbid).store();
+            let fetched = match trace.phase("bg_fetch", || store.get_multi(vbid, &fetch_keys)) {
+                Ok(fetched) => fetched,
+                Err(e) => {
+                    println!("Failed to fetch {} keys from {vbid}: {e}", fetch_keys.len());
+                    for i in to_fetch {
+                        results[i] = Err(EngineError::TemporaryFailure);
+                    }
+                    continue;
+                }
+            };
+            self.stats.bg_fetch_batches.fetch_add(1, Ordering::Relaxed);
+            self.stats
+                .bg_fetched
+                .fetch_add(fetched.iter().flatten().count() as u64, Ordering::Relaxed);
+            vb.restore_fetched(fetched.into_iter().flatten());
+            // Anything modified since it was fetched is read as it is now
+            for (i, value) in to_fetch.into_iter().zip(vb.get_multi(&fetch_keys)) {
+                results[i] = match value {
+                    Some(value) if value.is_deleted() => Err(EngineError::KeyNotFound),
+                    Some(value) if !value.is_resident() => Err(EngineError::WouldBlock),
+                    Some(value) => Ok(hide_lock_cas(&vb, value)),
+                    None => Err(EngineError::KeyNotFound),
+                };
+            }
+        }
+
+        for value in results.iter_mut().flatten() {
+            if value.datatype.contains(Datatype::SNAPPY) {
+                value.inflate();
+                self.stats.values_inflated.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+        results
+    }
+
+    /// Store an uncompressed value, returning its new CAS
+    pub fn set(
+        &self,
+        key: Vec<u8>,
+        value: Vec<u8>,
+        flags: u32,
+        expiry_time: u32,
+    ) -> EngineResult<u64> {
+        self.set_with_datatype(key, value, Datatype::empty(), flags, expiry_time, 0)
+    }
+
+    /// As set, but only returns once the mutation is persisted. While the
+    /// vbucket is failing to persist it fails with TemporaryFailure, and the
+    /// mutation (if stored) is persisted once the disk recovers.
+    pub fn set_durable(
+        &self,
+        key: Vec<u8>,
+        value: Vec<u8>,
+        flags: u32,
+        expiry_time: u32,
+    ) -> EngineResult<u64> {
+        let vbid = vbucket_for_key(&key, self.config.max_vbuckets);
+        let vb = self.get_vbucket(vbid).ok_or(EngineError::NotMyVbucket)?;
+        if vb.is_persistence_failing() {
+            return Err(EngineError::TemporaryFailure);
+        }
+        let cas = self.set(key, value, flags, expiry_time)?;
+        let seqno = vb.get_high_seqno();
+        self.flush_vbucket(vbid);
+        if vb.get_persisted_seqno() < seqno {
+            return Err(EngineError::TemporaryFailure);
+        }
+        Ok(cas)
+    }
+
+    /// Store a value with the datatype the client sent it with, returning
+    /// its new CAS. The value is stored compressed or not according to the
+    /// bucket's compression mode. A non-zero cas must match the key's, and
+    /// a locked key can only be stored with the CAS of its lock.
+    pub fn set_with_datatype(
+        &self,
+        key: Vec<u8>,
+        value: Vec<u8>,
+        datatype: Datatype,
+        flags: u32,
+        expiry_time: u32,
+        cas: u64,
+    ) -> EngineResult<u64> {
+        self.set_with_token(key, value, datatype, flags, expiry_time, cas)
+            .map(|info| info.cas)
+    }
+
+    /// As set_with_datatype, also returning the mutation's token
+    pub fn set_with_token(
+        &self,
+        key: Vec<u8>,
+        mut value: Vec<u8>,
+        mut datatype: Datatype,
+        flags: u32,
+        expiry_time: u32,
+        cas: u64,
+    ) -> EngineResult<MutationInfo> {
+        let mut trace = self.trace_op("set");
+        if self.is_degraded_mode() || self.is_read_only() {
+            return Err(EngineError::TemporaryFailure);
+        }
+        let uncompressed_len;
+        (value, datatype, uncompressed_len) = self.prepare_value(&key, value, datatype)?;
+        let stored_len = value.len();
+        if !self.has_memory_for_mutation(key.len() + value.len()) {
+            return Err(EngineError::TemporaryFailure);
+        }
+        let vbid = vbucket_for_key(&key, self.config.max_vbuckets);
+        let vb = self.get_vbucket(vbid).ok_or(EngineError::NotMyVbucket)?;
+        let key = self.stored_key(key);
+        // The collection's maxTTL is capped by the bucket's too
+        let expiry_time = self.cap_expiry(vb.default_expiry(&key, expiry_time), vb.now_secs());
+        let item = Item {
+            key,
+            value: Some(value),
+            cas,
+            expiry_time,
+            flags,
+            by_seqno: 0,
+            rev_seqno: 0,
+            delete_source: DeleteSource::Explicit,
+            datatype,
+        };
+        let info = trace.phase("hash_table", || self.count_lock_error(vb.set(item)))?;
+        self.stats
+            .value_bytes_uncompressed
+            .fetch_add(uncompressed_len as u64, Ordering::Relaxed);
+        self.stats
+            .value_bytes_stored
+            .fetch_add(stored_len as u64, Ordering::Relaxed);
+        self.recover_checkpoint_memory();
+        Ok(info)
+    }
+
+    /// Check a client's value for the key and put it in the form it is
+    /// stored in, compressed or not according to the compression mode.
+    /// Returns it with its datatype and the length the client reads back.
+    fn prepare_value(
+        &self,
+        key: &[u8],
+        mut value: Vec<u8>,
+        mut datatype: Datatype,
+    ) -> EngineResult<(Vec<u8>, Datatype, usize)> {
+        let inflated = if datatype.contains(Datatype::SNAPPY) {
+            Some(compression::inflate(&value).ok_or(EngineError::InvalidArguments)?)
+        } else {
+            None
+        };
+        // The limit applies to the value the client will read back
+        let uncompressed_len = inflated.as_ref().map_or(value.len(), Vec::len);
+        if key.len() > self.config.max_key_size || uncompressed_len > self.config.max_item_size {
+            return Err(EngineError::TooBig);
+        }
+        // Front-end keys are all in the default collection
+        self.validate_write(
+            DEFAULT_COLLECTION,
+            key,
+            inflated.as_deref().unwrap_or(&value),
+            datatype - Datatype::SNAPPY,
+        )?;
+        match (self.config.compression_mode, inflated) {
+            (CompressionMode::Off, Some(inflated)) => {
+                value = inflated;
+                datatype.remove(Datatype::SNAPPY);
+            }
+            (CompressionMode::Active, None) => {
+                let compressed = compression::compress(&value);
+                if compressed.len() as f64 * self.config.min_compression_ratio <= value.len() as f64
+                {
+                    value = compressed;
+                    datatype.insert(Datatype::SNAPPY);
+                }
+            }
+            _ => {}
+        }
+        Ok((value, datatype, uncompressed_len))
+    }
+
+    /// Apply several sets and deletes to keys of one vbucket as a unit:
+    /// either all of them are applied or, if any fails its checks, none
+    /// are and the first failure is returned. They take consecutive seqnos
+    /// and are persisted in one flush commit, and no reader sees some of
+    /// them without the others. Returns each mutation's new CAS, in order.
+    pub fn multi_mutate(&self, vbid: Vbid, mutations: Vec<Mutation>) -> EngineResult<Vec<u64>> {
+        let mut trace = self.trace_op("multi_mutate");
+        if self.is_degraded_mode() || self.is_read_only() {
+            return Err(EngineError::TemporaryFailure);
+        }
+        let vb = self.get_vbucket(vbid).ok_or(EngineError::NotMyVbucket)?;
+        let now = vb.now_secs();
+        let mut items = Vec::with_capacity(mutations.len());
+        let (mut uncompressed_bytes, mut stored_bytes, mut size) = (0, 0, 0);
+        for mutation in mutations {
+            if vbucket_for_key(mutation.key(), self.config.max_vbuckets) != vbid {
+                return Err(EngineError::InvalidArguments);
+            }
+            let item = match mutation {
+                Mutation::Set {
+                    key,
+                    value,
+                    flags,
+                    expiry_time,
+                    cas,
+                } => {
+                    let (value, datatype, uncompressed_len) =
+                        self.prepare_value(&key, value, Datatype::empty())?;
+                    uncompressed_bytes += uncompressed_len;
+                    stored_bytes += value.len();
+                    let key = self.stored_key(key);
+                    let expiry_time = self.cap_expiry(vb.default_expiry(&key, expiry_time), now);
+                    Item {
+                        key,
+                        value: Some(value),
+                        cas,
+                        expiry_time,
+                        flags,
+                        by_seqno: 0,
+                        rev_seqno: 0,
+                        delete_source: DeleteSource::Explicit,
+                        datatype,
+                    }
+                }
+                Mutation::Delete { key, cas } => Item {
+                    key: self.stored_key(key),
+                    value: None,
+                    cas,
+                    expiry_time: 0,
+                    flags: 0,
+                    by_seqno: 0,
+                    rev_seqno: 0,
+                    delete_source: DeleteSource::Explicit,
+                    datatype: Datatype::empty(),
+                },
+            };
+            size += item.key.len() + item.value.as_ref().map_or(0, Vec::len);
+            items.push(item);
+        }
+        if !self.has_memory_for_mutation(size) {
+            return Err(EngineError::TemporaryFailure);
+        }
+        let cas = trace.phase("hash_table", || {
+            self.count_lock_error(vb.multi_mutate(items))
+        })?;
+        self.stats
+            .value_bytes_uncompressed
+            .fetch_add(uncompressed_bytes as u64, Ordering::Relaxed);
+        self.stats
+            .value_bytes_stored
+            .fetch_add(stored_bytes as u64, Ordering::Relaxed);
+        self.recover_checkpoint_memory();
+        Ok(cas)
+    }
+
+    /// Delete a key, returning the CAS of the deletion. A non-zero cas must
+    /// match the key's, and a locked key can only be deleted with the CAS of
+    /// its lock.
+    pub fn delete(&self, key: Vec<u8>, cas: u64) -> EngineResult<u64> {
+        self.delete_with_token(key, cas).map(|info| info.cas)
+    }
+
+    /// As delete, also returning the deletion's token
+    pub fn delete_with_token(&self, key: Vec<u8>, cas: u64) -> EngineResult<MutationInfo> {
+        let mut trace = self.trace_op("delete");
+        if self.is_degraded_mode() || self.is_read_only() {
+            return Err(EngineError::TemporaryFailure);
+        }
+        let vbid = vbucket_for_key(&key, self.config.max_vbuckets);
+        let vb = self.get_vbucket(vbid).ok_or(EngineError::NotMyVbucket)?;
+        let key = self.stored_key(key);
+        let info = trace.phase("hash_table", || self.count_lock_error(vb.delete(&key, cas)))?;
+        self.recover_checkpoint_memory();
+        Ok(info)
+    }
+
+    /// Rename a key, moving its document to new_key with the same value,
+    /// flags and expiry time, for tools migrating keys to a new scheme. The
+    /// two keys must be in the same vbucket, so the new key's mutation and
+    /// the old key's deletion are persisted and replicated together.
+    /// Fails with KeyExists if new_key has a document. A non-zero cas must
+    /// match the old key's. Returns the new key's CAS.
+    pub fn rename(&self, key: Vec<u8>, new_key: Vec<u8>, cas: u64) -> EngineResult<u64> {
+        let mut trace = self.trace_op("rename");
+        if self.is_degraded_mode() || self.is_read_only() {
+            return Err(EngineError::TemporaryFailure);
+        }
+        if new_key.len() > self.config.max_key_size {
+            return Err(EngineError::TooBig);
+        }
+        let vbid = vbucket_for_key(&key, self.config.max_vbuckets);
+        if key == new_key || vbucket_for_key(&new_key, self.config.max_vbuckets) != vbid {
+            return Err(EngineError::InvalidArguments);
+        }
+        let vb = self.get_vbucket(vbid).ok_or(EngineError::NotMyVbucket)?;
+        let (key, new_key) = (self.stored_key(key), self.stored_key(new_key));
+        if self.expire_if_needed(&vb, &key) {
+            self.stats.expired_access.fetch_add(1, Ordering::Relaxed);
+            return Err(EngineError::KeyNotFound);
+        }
+        let rename = || self.count_lock_error(vb.rename(&key, &new_key, cas));
+        let result = match trace.phase("hash_table", rename) {
+            // The value is written again, so it is fetched back first
+            Err(EngineError::WouldBlock) => {
+                let store = self.vbucket_map.get_shard_by_vb_id(vbid).store();
+                let fetched = trace.phase("bg_fetch", || {
+                    store.get_multi(vbid, std::slice::from_ref(&key))
+                });
+                let fetched = fetched.map_err(|e| {
+                    println!("Failed to fetch the key from {vbid}: {e}");
+                    EngineError::TemporaryFailure
+                })?;
+                vb.restore_fetched(fetched.into_iter().flatten());
+                trace.phase("hash_table", rename)
+            }
+            result => result,
+        };
+        self.recover_checkpoint_memory();
+        result
+    }
+
+    /// Create the collection in every active vbucket, each queueing a
+    /// system event. Replicas create it when the event is replicated.
+    /// Documents written to it without an expiry expire max_ttl seconds
+    /// later, unless it is 0.
+    pub fn create_collection(
+        &self,
+        id: CollectionId,
+        scope_id: ScopeId,
+        name: &str,
+        max_ttl: u32,
+    ) -> EngineResult<()> {
+        let entry = CollectionEntry {
+            name: name.to_string(),
+            scope_id,
+            max_ttl,
+        };
+        self.for_each_active_vbucket(|vb| vb.create_collection(id, entry.clone()))
+    }
+
+    /// Change the collection's maxTTL in every active vbucket, each
+    /// queueing a system event. Documents already written keep their
+    /// expiry times.
+    pub fn set_collection_max_ttl(&self, id: CollectionId, max_ttl: u32) -> EngineResult<()> {
+        self.for_each_active_vbucket(|vb| {
+            let entry = vb
+                .get_collection(id)
+                .ok_or(EngineError::UnknownCollection)?;
+            vb.modify_collection(id, CollectionEntry { max_ttl, ..entry })
+        })
+    }
+
+    /// Validate the writes to a collection with the validator, replacing
+    /// any it had, or stop validating them with None. The collection needn't
+    /// exist yet.
+    pub fn set_write_validator(
+        &self,
+        collection: CollectionId,
+        validator: Option<WriteValidatorPtr>,
+    ) {
+        let mut validators = self.validators.write();
+        match validator {
+            Some(validator) => {
+                validators.insert(collection, Arc::new(CollectionValidator::new(validator)));
+            }
+            None => {
+                validators.remove(&collection);
+            }
+        }
+    }
+
+    /// Reject a write with InvalidArguments if the collection's validator
+    /// does
+    fn validate_write(
+        &self,
+        collection: CollectionId,
+        key: &[u8],
+        value: &[u8],
+        datatype: Datatype,
+    ) -> EngineResult<()> {
+        let Some(validator) = self.validators.read().get(&collection).cloned() else {
+            return Ok(());
+        };
+        if validator.validate(key, value, datatype) {
+            Ok(())
+        } else {
+            self.stats.writes_rejected.fetch_add(1, Ordering::Relaxed);
+            Err(EngineError::InvalidArguments)
+        }
+    }
+
+    /// Each validated collection's rejections
+    fn get_validation_stats(&self, add_stat: &mut dyn FnMut(&str, &str)) {
+        let validators = self.validators.read();
+        let mut collections: Vec<&CollectionId> = validators.keys().collect();
+        collections.sort();
+        for collection in collections {
+            validators[collection].add_stats(&format!("collection_{collection:#x}"), add_stat);
+        }
+    }
+
+    /// Give every document in the collection a new expiry time, on a
+    /// background task. The ttl is as a client gives it, seconds from when
+    /// each document is touched or an absolute time, and max_ttl still
+    /// applies. Only the vbuckets active now are updated, and only one
+    /// update runs at a time. Its progress is also reported by the
+    /// ttl_update stats group.
+    pub fn update_collection_ttl(
+        bucket: &EPBucketPtr,
+        collection: CollectionId,
+        ttl: u32,
+    ) -> EngineResult<Arc<TtlUpdateProgress>> {
+        if bucket.is_degraded_mode() || bucket.is_read_only() {
+            return Err(EngineError::TemporaryFailure);
+        }
+        let mut latest = bucket.ttl_update.lock();
+        if latest
+            .as_ref()
+            .is_some_and(|progress| progress.is_running())
+        {
+            return Err(EngineError::TemporaryFailure);
+        }
+        let vbuckets = bucket.vbucket_map.get_buckets_in_state(State::Active);
+        let exists = vbuckets.iter().any(|vbid| {
+            bucket
+                .get_vbucket(*vbid)
+                .is_some_and(|vb| vb.get_collection(collection).is_some())
+        });
+        if !exists {
+            return Err(EngineError::UnknownCollection);
+        }
+
+        let progress = Arc::new(TtlUpdateProgress::new(collection, ttl, vbuckets));
+        *latest = Some(progress.clone());
+        let weak = Arc::downgrade(bucket);
+        let task_progress = progress.clone();
+        bucket.schedule_task("ttl_update", move || ttl_update::run(weak, &task_progress));
+        Ok(progress)
+    }
+
+    /// Compact every vbucket, or only those worth purging the tombstones
+    /// of, on a background task running the given number at once. The
+    /// returned progress also cancels it, and is reported by the
+    /// compact_all stats group. Only one runs at a time.
+    pub fn compact_all(
+        bucket: &EPBucketPtr,
+        options: CompactAllOptions,
+    ) -> EngineResult<Arc<CompactAllProgress>> {
+        if bucket.is_degraded_mode() || bucket.is_read_only() || bucket.is_paused() {
+            return Err(EngineError::TemporaryFailure);
+        }
+        let mut latest = bucket.compact_all.lock();
+        if latest
+            .as_ref()
+            .is_some_and(|progress| progress.is_running())
+        {
+            return Err(EngineError::TemporaryFailure);
+        }
+        let vbuckets = match options.only_candidates {
+            true => bucket.tombstone_purge_candidates(),
+            false => bucket.vbucket_map.get_buckets(),
+        };
+
+        let progress = Arc::new(CompactAllProgress::new(options, vbuckets));
+        *latest = Some(progress.clone());
+        let weak = Arc::downgrade(bucket);
+        let task_progress = progress.clone();
+        bucket.schedule_task("compact_all", move || compaction::run(weak, &task_progress));
+        Ok(progress)
+    }
+
+    /// The absolute expiry time to store for one a client gave, limited by
+    /// the bucket's max_ttl
+    pub(crate) fn cap_expiry(&self, expiry_time: u32, now: u32) -> u32 {
+        let expiry_time = item::to_absolute_expiry(expiry_time, now);
+        if self.config.max_ttl == 0 {
+            return expiry_time;
+        }
+        let limit = now.saturating_add(self.config.max_ttl);
+        if expiry_time == 0 || expiry_time > limit {
+            limit
+        } else {
+            expiry_time
+        }
+    }
+
+    /// Drop the collection from every active vbucket
+    pub fn drop_collection(&self, id: CollectionId) -> EngineResult<()> {
+        self.for_each_active_vbucket(|vb| vb.drop_collection(id))
+    }
+
+    fn for_each_active_vbucket(
+        &self,
+        mut f: impl FnMut(&VBucket) -> EngineResult<u64>,
+    ) -> EngineResult<()> {
+        for vbid in self.vbucket_map.get_buckets() {
+            match self.get_vbucket(vbid) {
+                Some(vb) if vb.state() == State::Active => {
+                    f(&vb)?;
+                }
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+}
+
+/// A vbucket's persistence progress, for clients waiting for their
+/// mutations to be durable
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ObserveSeqno {
+    pub vb_uuid: u64,
+    pub last_persisted_seqno: u64,
+    pub current_seqno: u64,
+}
+
+/// Which seqno of each vbucket a MutationTokenVector holds
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TokenSeqno {
+    /// The last mutation
+    High,
+    /// The last mutation on disk
+    Persisted,
+}
+
+/// A point in a vbucket's history: the seqno on the branch vb_uuid
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MutationToken {
+    pub vbid: Vbid,
+    pub vb_uuid: u64,
+    pub seqno: u64,
+}
+
+/// What a write was given: its CAS, and the token of its seqno for reads
+/// which must see it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MutationInfo {
+    pub cas: u64,
+    pub token: MutationToken,
+}
+
+/// The tokens of all the active vbuckets at one instant, in vbucket order
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct MutationTokenVector {
+    pub tokens: Vec<MutationToken>,
+}
+
+impl MutationTokenVector {
+    pub fn get(&self, vbid: Vbid) -> Option<&MutationToken> {
+        self.tokens.iter().find(|token| token.vbid == vbid)
+    }
+
+    /// Has every vbucket in required reached its seqno, on the same branch
+    /// of history. A vbucket which failed over since must be waited for
+    /// some other way.
+    pub fn covers(&self, required: &MutationTokenVector) -> bool {
+        required.tokens.iter().all(|required| {
+            self.get(required.vbid).is_some_and(|token| {
+                token.vb_uuid == required.vb_uuid && token.seqno >= required.seqno
+            })
+        })
+    }
+}
+
+/// A write applied by multi_mutate. A non-zero cas must match the key's.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Mutation {
+    /// Store an uncompressed value
+    Set {
+        key: Vec<u8>,
+        value: Vec<u8>,
+        flags: u32,
+        expiry_time: u32,
+        cas: u64,
+    },
+    Delete {
+        key: Vec<u8>,
+        cas: u64,
+    },
+}
+
+impl Mutation {
+    fn key(&self) -> &[u8] {
+        match self {
+            Mutation::Set { key, .. } | Mutation::Delete { key, .. } => key,
+        }
+    }
+}
+
+/// Keys listed by list_keys
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct KeyPage {
+    pub keys: Vec<Vec<u8>>,
+    /// Where the next page starts, None if this is the last
+    pub continuation: Option<Vec<u8>>,
 }
 
 pub type EPBucketPtr = Arc<EPBucket>;
@@ -75,10 +2564,1704 @@ impl Deref for LockedVbucketPtr<'_> {
     }
 }
 
-pub fn v_bucket_hash(key: &[u8], num_vbuckets: u32) -> u16 {
-    let mut hasher = crc32fast::Hasher::new();
-    hasher.update(key);
-    let crc = hasher.finalize();
-    let hash = (((crc) >> 16) & 0x7fff) & (num_vbuckets - 1);
-    hash as u16
+// TODO: This is a hack to get around the fact that we don't have
+// collection support yet. We need to add support for collections
+/// Clients other than the lock holder read a locked value with an invalid
+/// CAS, so they can't modify it
+fn hide_lock_cas(vb: &VBucket, mut value: StoredValue) -> StoredValue {
+    if vb.is_locked(&value) {
+        value.cas = u64::MAX;
+    }
+    value
+}
+
+fn key_with_default_collection(key: Vec<u8>) -> Vec<u8> {
+    let mut key_with_collection_id = Vec::from("\0");
+    key_with_collection_id.extend(key);
+    key_with_collection_id
+}
+
+/// The first key after every key starting with prefix, None if there is
+/// none
+fn prefix_end(prefix: &[u8]) -> Option<Vec<u8>> {
+    let mut end = prefix.to_vec();
+    while let Some(last) = end.pop() {
+        if last != u8::MAX {
+            end.push(last + 1);
+            return Some(end);
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{
+        clock::VirtualClock,
+        item::MAX_RELATIVE_EXPIRY,
+        kv_store::{Backend, ScanErrorPolicy, ValueFilter},
+    };
+    use std::collections::HashMap;
+
+    fn make_bucket(dir: &tempfile::TempDir, config: Config) -> EPBucketPtr {
+        let config = Config {
+            max_vbuckets: 4,
+            max_shards: 1,
+            dbname: dir.path().to_str().unwrap().to_string(),
+            ..config
+        };
+        let bucket = EPBucket::new(config);
+        for vbid in 0..4u16 {
+            bucket.vbucket_map.add_bucket(bucket.make_vbucket(
+                Vbid::from(vbid),
+                State::Active,
+                FailoverTable::new_empty(25),
+                0,
+                0,
+            ));
+        }
+        bucket
+    }
+
+    #[test]
+    fn test_traffic_gating() {
+        let dir = tempfile::tempdir().unwrap();
+        let bucket = make_bucket(
+            &dir,
+            Config {
+                max_size: 1024 * 1024,
+                ..Default::default()
+            },
+        );
+
+        // Warmup hasn't completed so all ops are rejected
+        assert_eq!(
+            bucket.set(b"key".to_vec(), b"value".to_vec(), 0, 0),
+            Err(EngineError::TemporaryFailure)
+        );
+        assert_eq!(
+            bucket.get(b"key".to_vec()).unwrap_err(),
+            EngineError::TemporaryFailure
+        );
+
+        assert!(bucket.enable_traffic());
+        assert!(!bucket.enable_traffic());
+
+        let cas = bucket
+            .set(b"key".to_vec(), b"value".to_vec(), 0, 0)
+            .unwrap();
+        let value = bucket.get(b"key".to_vec()).unwrap();
+        assert_eq!(value.cas, cas);
+        assert_eq!(value.value.as_deref(), Some(&b"value"[..]));
+
+        // Push memory usage over the mutation threshold, writes are
+        // rejected but reads are still served
+        bucket
+            .stats
+            .memory
+            .mem_allocated(MemoryDomain::Other, 1024 * 1024);
+        assert_eq!(
+            bucket.set(b"key".to_vec(), b"value2".to_vec(), 0, 0),
+            Err(EngineError::TemporaryFailure)
+        );
+        assert!(bucket.get(b"key".to_vec()).is_ok());
+
+        bucket
+            .stats
+            .memory
+            .mem_deallocated(MemoryDomain::Other, 1024 * 1024);
+        assert!(bucket
+            .set(b"key".to_vec(), b"value2".to_vec(), 0, 0)
+            .is_ok());
+    }
+
+    #[test]
+    fn test_checkpoint_memory_recovery() {
+        let dir = tempfile::tempdir().unwrap();
+        let bucket = make_bucket(
+            &dir,
+            Config {
+                max_size: 100 * 1024,
+                checkpoint_max_items: 10,
+                ..Default::default()
+            },
+        );
+        bucket.enable_traffic();
+        for vbid in bucket.vbucket_map.get_buckets() {
+            let vb = bucket.get_vbucket(vbid).unwrap();
+            vb.checkpoint_manager.register_cursor("replication", 0);
+        }
+
+        // Fill the checkpoint quota (50K) past the upper mark
+        for i in 0..100 {
+            let key = format!("key_{i}").into_bytes();
+            let vb = bucket.get_vbucket(vbucket_for_key(&key, 4)).unwrap();
+            vb.set(Item {
+                key,
+                value: Some(vec![0; 500]),
+                cas: 0,
+                expiry_time: 0,
+                flags: 0,
+                by_seqno: 0,
+                rev_seqno: 0,
+                delete_source: DeleteSource::Explicit,
+                datatype: Datatype::empty(),
+            })
+            .unwrap();
+        }
+        let checkpoint_memory = || bucket.stats.memory.domain_used(MemoryDomain::Checkpoint);
+        let before = checkpoint_memory();
+        let quota = bucket.get_checkpoint_memory_quota();
+        assert!(before > quota * 9 / 10);
+
+        // Everything is persisted but the replication cursors pin every
+        // checkpoint, so only dropping them can free memory
+        for vbid in bucket.vbucket_map.get_buckets() {
+            let vb = bucket.get_vbucket(vbid).unwrap();
+            vb.checkpoint_manager
+                .get_items_for_cursor(PERSISTENCE_CURSOR)
+                .unwrap();
+        }
+
+        let freed = bucket.recover_checkpoint_memory();
+        assert_eq!(checkpoint_memory(), before - freed);
+        assert!(checkpoint_memory() <= quota * 6 / 10);
+        let dropped = bucket.stats.cursors_dropped.load(Ordering::Relaxed);
+        assert!(dropped > 0 && dropped < 4);
+    }
+
+    #[test]
+    fn test_shutdown() {
+        let dir = tempfile::tempdir().unwrap();
+        let bucket = make_bucket(&dir, Config::default());
+        bucket.enable_traffic();
+        for i in 0..10 {
+            bucket
+                .set(format!("key_{i}").into_bytes(), vec![0; 10], 0, 0)
+                .unwrap();
+        }
+        let task_bucket = bucket.clone();
+        bucket.schedule_task("test_task", move || {
+            while !task_bucket.is_shutting_down() {
+                std::thread::sleep(Duration::from_millis(1));
+            }
+        });
+
+        bucket.shutdown(false);
+        assert!(bucket.tasks.lock().is_empty());
+        assert_eq!(
+            bucket.set(b"key".to_vec(), vec![], 0, 0),
+            Err(EngineError::TemporaryFailure)
+        );
+
+        // Every vbucket's state is persisted, along with its mutations
+        let mut persisted = 0;
+        for vbid in bucket.vbucket_map.get_buckets() {
+            let store = bucket.vbucket_map.get_shard_by_vb_id(vbid).store();
+            assert!(store.get_cached_vb_state(vbid).is_some());
+            store.scan(
+                vbid,
+                0,
+                ValueFilter::KeysOnly,
+                ScanErrorPolicy::Abort,
+                &mut |_| persisted += 1,
+            );
+        }
+        assert_eq!(persisted, 10);
+
+        let mut stats = HashMap::new();
+        bucket
+            .get_stats_group("kvstore", &mut |key, value| {
+                stats.insert(key.to_string(), value.to_string());
+            })
+            .unwrap();
+        assert!(stats["rw_0:commits"].parse::<u64>().unwrap() > 0);
+        assert!(stats["rw_0:io_total_write_bytes"].parse::<u64>().unwrap() > 0);
+
+        stats.clear();
+        bucket
+            .get_stats_group("headers", &mut |key, value| {
+                stats.insert(key.to_string(), value.to_string());
+            })
+            .unwrap();
+        assert!(stats
+            .keys()
+            .any(|key| key.ends_with(":header_0:high_seqno")));
+        assert_eq!(
+            bucket.get_stats_group("nonexistent", &mut |_, _| {}),
+            Err(EngineError::KeyNotFound)
+        );
+    }
+
+    #[test]
+    fn test_headers_stats() {
+        let dir = tempfile::tempdir().unwrap();
+        let bucket = make_bucket(&dir, Config::default());
+        bucket.enable_traffic();
+        let vbid = vbucket_for_key(b"key", 4);
+        let vb = bucket.get_vbucket(vbid).unwrap();
+        for value in [b"value1", b"value2"] {
+            bucket.set(b"key".to_vec(), value.to_vec(), 0, 0).unwrap();
+            assert_eq!(bucket.flush_vbucket(vbid), 1);
+        }
+        let high_seqno = vb.get_high_seqno();
+
+        // Newest first, each commit further into the file
+        let mut stats = HashMap::new();
+        bucket
+            .get_stats_group("headers", &mut |key, value| {
+                stats.insert(key.to_string(), value.to_string());
+            })
+            .unwrap();
+        let stat = |n: usize, field: &str| -> u64 {
+            stats[&format!("vb_{vbid}:header_{n}:{field}")]
+                .parse()
+                .unwrap()
+        };
+        assert_eq!(stat(0, "high_seqno"), high_seqno);
+        assert_eq!(stat(1, "high_seqno"), high_seqno - 1);
+        assert!(stat(0, "position") > stat(1, "position"));
+        assert_eq!(stat(0, "file_revision"), stat(1, "file_revision"));
+        assert!(stat(0, "timestamp") >= stat(1, "timestamp"));
+        // Nothing has been committed to the other vbuckets
+        let other = Vbid::new((u16::from(vbid) + 1) % 4);
+        assert!(!stats
+            .keys()
+            .any(|key| key.starts_with(&format!("vb_{other}:"))));
+    }
+
+    #[test]
+    fn test_pause() {
+        let dir = tempfile::tempdir().unwrap();
+        let bucket = make_bucket(&dir, Config::default());
+        bucket.enable_traffic();
+        let persisted = || {
+            let mut persisted = 0;
+            for vbid in bucket.vbucket_map.get_buckets() {
+                let store = bucket.vbucket_map.get_shard_by_vb_id(vbid).store();
+                store.scan(
+                    vbid,
+                    0,
+                    ValueFilter::KeysOnly,
+                    ScanErrorPolicy::Abort,
+                    &mut |_| persisted += 1,
+                );
+            }
+            persisted
+        };
+        bucket.set(b"key_0".to_vec(), vec![], 0, 0).unwrap();
+
+        // Pausing persists what is outstanding, then nothing more
+        assert!(bucket.pause());
+        assert!(!bucket.pause());
+        assert_eq!(persisted(), 1);
+        bucket.set(b"key_1".to_vec(), vec![], 0, 0).unwrap();
+        let vbid = vbucket_for_key(b"key_1", 4);
+        assert_eq!(bucket.flush_vbucket(vbid), 0);
+        assert_eq!(persisted(), 1);
+        assert_eq!(
+            bucket.purge_tombstones(vbid).err(),
+            Some(EngineError::TemporaryFailure)
+        );
+
+        // Resuming persists what was held back
+        assert!(bucket.resume());
+        assert!(!bucket.resume());
+        assert_eq!(persisted(), 2);
+    }
+
+    #[test]
+    fn test_purge_tombstones() {
+        let dir = tempfile::tempdir().unwrap();
+        let bucket = make_bucket(
+            &dir,
+            Config {
+                metadata_purge_interval: 0,
+                ..Default::default()
+            },
+        );
+        bucket.enable_traffic();
+        let vbid = vbucket_for_key(b"key_0", 4);
+        let keys: Vec<Vec<u8>> = (0..)
+            .map(|i| format!("key_{i}").into_bytes())
+            .filter(|key| vbucket_for_key(key, 4) == vbid)
+            .take(4)
+            .collect();
+        for key in &keys {
+            bucket.set(key.clone(), b"value".to_vec(), 0, 0).unwrap();
+        }
+        // Deleted at seqnos 5 to 7
+        for key in &keys[..3] {
+            bucket.delete(key.clone(), 0).unwrap();
+        }
+        bucket.flush_vbucket(vbid);
+
+        let vb = bucket.get_vbucket(vbid).unwrap();
+        vb.checkpoint_manager.register_cursor("stream", 5);
+        bucket.set_backup_cursor(vbid, "daily", 6).unwrap();
+        let result = bucket.purge_tombstones(vbid).unwrap();
+        assert_eq!(
+            (result.purged, result.retained, result.remaining),
+            (1, 2, 2)
+        );
+        let mut stats = HashMap::new();
+        bucket.get_stats(&mut |key, value| {
+            stats.insert(key.to_string(), value.to_string());
+        });
+        assert_eq!(stats["ep_tombstones_retained"], "2");
+        // The file's reduction agrees with what the purge saw
+        assert_eq!(stats["ep_on_disk_deletes"], "2");
+        assert_eq!(
+            stats["ep_tombstone_bytes"],
+            result.remaining_bytes.to_string()
+        );
+
+        // Only the backup needs the rest, bar the latest item
+        vb.checkpoint_manager.remove_cursor("stream");
+        let result = bucket.purge_tombstones(vbid).unwrap();
+        assert_eq!(
+            (result.purged, result.retained, result.remaining),
+            (1, 1, 1)
+        );
+        assert_eq!(vb.get_purge_seqno(), 6);
+        assert_eq!(bucket.stats.tombstones_purged.load(Ordering::Relaxed), 2);
+        let mut seqnos = Vec::new();
+        bucket.get_store_by_shard(0).scan(
+            vbid,
+            0,
+            ValueFilter::KeysOnly,
+            ScanErrorPolicy::Abort,
+            &mut |item| seqnos.push(item.by_seqno),
+        );
+        assert_eq!(seqnos, vec![4, 7]);
+    }
+
+    #[test]
+    fn test_tombstone_purge_candidates() {
+        let dir = tempfile::tempdir().unwrap();
+        let bucket = make_bucket(
+            &dir,
+            Config {
+                tombstone_purge_ratio: 0.25,
+                ..Default::default()
+            },
+        );
+        bucket.enable_traffic();
+        let keys_of = |vbid: Vbid| -> Vec<Vec<u8>> {
+            (0..)
+                .map(|i| format!("key_{i}").into_bytes())
+                .filter(|key| vbucket_for_key(key, 4) == vbid)
+                .take(8)
+                .collect()
+        };
+        // Half of one vbucket's documents deleted, one of another's
+        let (many, few) = (Vbid::from(0u16), Vbid::from(1u16));
+        for (vbid, deletes) in [(many, 4), (few, 1)] {
+            let keys = keys_of(vbid);
+            for key in &keys {
+                bucket.set(key.clone(), b"value".to_vec(), 0, 0).unwrap();
+            }
+            for key in &keys[..deletes] {
+                bucket.delete(key.clone(), 0).unwrap();
+            }
+            bucket.flush_vbucket(vbid);
+        }
+        assert_eq!(bucket.tombstone_purge_candidates(), vec![many]);
+
+        let mut stats = HashMap::new();
+        bucket
+            .get_stats_group("tombstones", &mut |key, value| {
+                stats.insert(key.to_string(), value.to_string());
+            })
+            .unwrap();
+        assert_eq!(stats["ep_tombstone_purge_candidates"], "1");
+        assert_eq!(stats[&format!("vb_{many}:on_disk_deletes")], "4");
+        assert_eq!(stats[&format!("vb_{few}:on_disk_deletes")], "1");
+        assert_eq!(stats[&format!("vb_{many}:purge_candidate")], "true");
+        assert_eq!(stats[&format!("vb_{few}:purge_candidate")], "false");
+        let bytes = |vbid: Vbid| -> u64 {
+            stats[&format!("vb_{vbid}:tombstone_bytes")]
+                .parse()
+                .unwrap()
+        };
+        assert!(bytes(many) > bytes(few) && bytes(few) > 0);
+
+        // Nothing is worth purging while history is kept
+        let dir = tempfile::tempdir().unwrap();
+        let bucket = make_bucket(
+            &dir,
+            Config {
+                history_retention: true,
+                tombstone_purge_ratio: 0.0,
+                ..Default::default()
+            },
+        );
+        assert!(bucket.tombstone_purge_candidates().is_empty());
+    }
+
+    #[test]
+    fn test_rename() {
+        let dir = tempfile::tempdir().unwrap();
+        let bucket = make_bucket(&dir, Config::default());
+        bucket.enable_traffic();
+        let vbid = vbucket_for_key(b"key_0", 4);
+        let keys: Vec<Vec<u8>> = (0..)
+            .map(|i| format!("key_{i}").into_bytes())
+            .filter(|key| vbucket_for_key(key, 4) == vbid)
+            .take(3)
+            .collect();
+        let elsewhere = (0..)
+            .map(|i| format!("key_{i}").into_bytes())
+            .find(|key| vbucket_for_key(key, 4) != vbid)
+            .unwrap();
+        let (old, new, taken) = (&keys[0], &keys[1], &keys[2]);
+        bucket.set(old.clone(), b"value".to_vec(), 7, 1000).unwrap();
+        bucket.set(taken.clone(), b"other".to_vec(), 0, 0).unwrap();
+        let before = bucket.get(old.clone()).unwrap();
+        bucket.flush_vbucket(vbid);
+
+        assert_eq!(
+            bucket.rename(old.clone(), elsewhere, 0),
+            Err(EngineError::InvalidArguments)
+        );
+        assert_eq!(
+            bucket.rename(old.clone(), taken.clone(), 0),
+            Err(EngineError::KeyExists)
+        );
+        assert_eq!(
+            bucket.rename(old.clone(), new.clone(), before.cas + 1),
+            Err(EngineError::KeyExists)
+        );
+        assert_eq!(
+            bucket.rename(new.clone(), old.clone(), 0),
+            Err(EngineError::KeyNotFound)
+        );
+
+        // The value is fetched back from disk to be moved
+        let vb = bucket.get_vbucket(vbid).unwrap();
+        let stored_old = key_with_default_collection(old.clone());
+        vb.hash_table
+            .lock()
+            .map
+            .get_mut(&stored_old)
+            .unwrap()
+            .mark_not_resident();
+        let high_seqno = vb.get_high_seqno();
+        let cas = bucket.rename(old.clone(), new.clone(), before.cas).unwrap();
+        assert_eq!(
+            bucket.get(old.clone()).err(),
+            Some(EngineError::KeyNotFound)
+        );
+        let moved = bucket.get(new.clone()).unwrap();
+        assert_eq!(moved.cas, cas);
+        assert_eq!(moved.value.as_deref(), Some(&b"value"[..]));
+        assert_eq!(
+            (moved.flags, moved.expiry_time),
+            (before.flags, before.expiry_time)
+        );
+
+        // Both changes are in the one batch, with consecutive seqnos
+        assert_eq!(bucket.flush_vbucket(vbid), 2);
+        let mut items = Vec::new();
+        bucket.get_store_by_shard(0).scan(
+            vbid,
+            high_seqno + 1,
+            ValueFilter::ValuesDecompressed,
+            ScanErrorPolicy::Abort,
+            &mut |item| items.push((item.by_seqno, item.key, item.value.is_none())),
+        );
+        let stored_new = key_with_default_collection(new.clone());
+        assert_eq!(
+            items,
+            vec![
+                (high_seqno + 1, stored_new, false),
+                (high_seqno + 2, stored_old, true)
+            ]
+        );
+    }
+
+    #[test]
+    fn test_multi_mutate() {
+        let dir = tempfile::tempdir().unwrap();
+        let bucket = make_bucket(&dir, Config::default());
+        bucket.enable_traffic();
+        let vbid = vbucket_for_key(b"key_0", 4);
+        let keys: Vec<Vec<u8>> = (0..)
+            .map(|i| format!("key_{i}").into_bytes())
+            .filter(|key| vbucket_for_key(key, 4) == vbid)
+            .take(3)
+            .collect();
+        let elsewhere = (0..)
+            .map(|i| format!("key_{i}").into_bytes())
+            .find(|key| vbucket_for_key(key, 4) != vbid)
+            .unwrap();
+        let set = |key: &Vec<u8>, value: &[u8], cas: u64| Mutation::Set {
+            key: key.clone(),
+            value: value.to_vec(),
+            flags: 0,
+            expiry_time: 0,
+            cas,
+        };
+        let cas_0 = bucket.set(keys[0].clone(), b"a".to_vec(), 0, 0).unwrap();
+        bucket.set(keys[1].clone(), b"b".to_vec(), 0, 0).unwrap();
+        bucket.flush_vbucket(vbid);
+        let vb = bucket.get_vbucket(vbid).unwrap();
+        let high_seqno = vb.get_high_seqno();
+
+        // A stale CAS, a missing key to delete, a key of another vbucket
+        // or the same key twice fail the whole batch
+        let failures = [
+            (
+                vec![set(&keys[2], b"c", 0), set(&keys[0], b"x", cas_0 + 1)],
+                EngineError::KeyExists,
+            ),
+            (
+                vec![
+                    set(&keys[0], b"x", cas_0),
+                    Mutation::Delete {
+                        key: keys[2].clone(),
+                        cas: 0,
+                    },
+                ],
+                EngineError::KeyNotFound,
+            ),
+            (
+                vec![set(&keys[0], b"x", 0), set(&elsewhere, b"y", 0)],
+                EngineError::InvalidArguments,
+            ),
+            (
+                vec![set(&keys[2], b"x", 0), set(&keys[2], b"y", 0)],
+                EngineError::InvalidArguments,
+            ),
+        ];
+        for (mutations, error) in failures {
+            if mutations
+                .iter()
+                .any(|m| vbucket_for_key(m.key(), 4) != vbid)
+                && error != EngineError::InvalidArguments
+            {
+                continue;
+            }
+            assert_eq!(bucket.multi_mutate(vbid, mutations), Err(error));
+        }
+        assert_eq!(vb.get_high_seqno(), high_seqno);
+        assert_eq!(
+            bucket.get(keys[0].clone()).unwrap().value.as_deref(),
+            Some(&b"a"[..])
+        );
+        assert_eq!(
+            bucket.get(keys[2].clone()).err(),
+            Some(EngineError::KeyNotFound)
+        );
+
+        let cas = bucket
+            .multi_mutate(
+                vbid,
+                vec![
+                    set(&keys[0], b"x", cas_0),
+                    Mutation::Delete {
+                        key: keys[1].clone(),
+                        cas: 0,
+                    },
+                    set(&keys[2], b"z", 0),
+                ],
+            )
+            .unwrap();
+        assert_eq!(cas.len(), 3);
+        assert_eq!(bucket.get(keys[0].clone()).unwrap().cas, cas[0]);
+        assert_eq!(
+            bucket.get(keys[1].clone()).err(),
+            Some(EngineError::KeyNotFound)
+        );
+        assert_eq!(
+            bucket.get(keys[2].clone()).unwrap().value.as_deref(),
+            Some(&b"z"[..])
+        );
+        // Persisted in one commit
+        assert_eq!(bucket.flush_vbucket(vbid), 3);
+        assert_eq!(vb.get_persisted_seqno(), high_seqno + 3);
+    }
+
+    #[test]
+    fn test_wait_for_token() {
+        let dir = tempfile::tempdir().unwrap();
+        let bucket = make_bucket(&dir, Config::default());
+        bucket.enable_traffic();
+        let info = bucket
+            .set_with_token(
+                b"key".to_vec(),
+                b"value".to_vec(),
+                Datatype::empty(),
+                0,
+                0,
+                0,
+            )
+            .unwrap();
+        let token = info.token;
+        assert_eq!(token.vbid, vbucket_for_key(b"key", 4));
+        assert_eq!(token.seqno, 1);
+        bucket
+            .wait_for_token(&token, Duration::from_millis(1))
+            .unwrap();
+
+        // A replica which hasn't received the write yet holds the read up
+        let vb = bucket.get_vbucket(token.vbid).unwrap();
+        let replica_dir = tempfile::tempdir().unwrap();
+        let replica = make_bucket(&replica_dir, Config::default());
+        replica.enable_traffic();
+        replica
+            .set_vbucket_state(token.vbid, State::Replica)
+            .unwrap();
+        assert_eq!(
+            replica.wait_for_token(&token, Duration::from_millis(1)),
+            Err(EngineError::TemporaryFailure)
+        );
+        let replica_vb = replica.get_vbucket(token.vbid).unwrap();
+        let stored = vb
+            .get(&key_with_default_collection(b"key".to_vec()))
+            .unwrap();
+        std::thread::scope(|scope| {
+            let waiter = scope.spawn(|| replica.wait_for_token(&token, Duration::from_secs(10)));
+            replica_vb.create_snapshot(1, 1).unwrap();
+            replica_vb
+                .set_with_meta(Item {
+                    key: key_with_default_collection(b"key".to_vec()),
+                    value: stored.value,
+                    cas: stored.cas,
+                    expiry_time: 0,
+                    flags: 0,
+                    by_seqno: 1,
+                    rev_seqno: 1,
+                    delete_source: DeleteSource::Explicit,
+                    datatype: Datatype::empty(),
+                })
+                .unwrap();
+            assert_eq!(waiter.join().unwrap(), Ok(()));
+        });
+
+        // A write on a branch of history this vbucket left was lost
+        vb.failover_table.create_entry(0);
+        assert_eq!(
+            bucket.wait_for_token(&token, Duration::from_millis(1)),
+            Err(EngineError::Rollback(0))
+        );
+    }
+
+    #[test]
+    fn test_flush_deduplication() {
+        // Couchstore doesn't deduplicate, so the flusher does unless the
+        // store keeps history. The memory backend deduplicates itself.
+        for (backend, history_retention, flushed) in [
+            (Backend::Couchstore, false, 1),
+            (Backend::Couchstore, true, 2),
+            (Backend::Memory, false, 2),
+        ] {
+            let dir = tempfile::tempdir().unwrap();
+            let bucket = make_bucket(
+                &dir,
+                Config {
+                    backend,
+                    history_retention,
+                    ..Default::default()
+                },
+            );
+            bucket.enable_traffic();
+            let vbid = vbucket_for_key(b"key", 4);
+            for value in [b"value1", b"value2"] {
+                bucket.set(b"key".to_vec(), value.to_vec(), 0, 0).unwrap();
+            }
+            assert_eq!(bucket.flush_vbucket(vbid), flushed, "{backend:?}");
+            assert_eq!(
+                bucket.get(b"key".to_vec()).unwrap().value.as_deref(),
+                Some(&b"value2"[..])
+            );
+        }
+    }
+
+    #[test]
+    fn test_expiry_pager() {
+        let dir = tempfile::tempdir().unwrap();
+        let bucket = make_bucket(
+            &dir,
+            Config {
+                nexus_secondary_backend: Some(Backend::Memory),
+                ..Default::default()
+            },
+        );
+        bucket.enable_traffic();
+        let vbid = vbucket_for_key(b"key_0", 4);
+        let keys: Vec<Vec<u8>> = (0..)
+            .map(|i| format!("key_{i}").into_bytes())
+            .filter(|key| vbucket_for_key(key, 4) == vbid)
+            .take(4)
+            .collect();
+        let vb = bucket.get_vbucket(vbid).unwrap();
+        let now = vb.now_secs();
+        assert!(now - 10 > MAX_RELATIVE_EXPIRY);
+
+        bucket
+            .set(keys[0].clone(), b"value".to_vec(), 0, now - 10)
+            .unwrap();
+        bucket
+            .set(keys[1].clone(), b"value".to_vec(), 0, now + 1000)
+            .unwrap();
+        bucket
+            .set(keys[2].clone(), b"value".to_vec(), 0, 0)
+            .unwrap();
+        bucket
+            .set(keys[3].clone(), b"value".to_vec(), 0, now - 10)
+            .unwrap();
+        bucket.flush_vbucket(vbid);
+        // No longer expires, so leaves the index
+        bucket
+            .set(keys[3].clone(), b"value".to_vec(), 0, 0)
+            .unwrap();
+        bucket.flush_vbucket(vbid);
+
+        let store = bucket.get_store_by_shard(0);
+        assert_eq!(
+            store.get_expired_keys(vbid, now).unwrap(),
+            vec![key_with_default_collection(keys[0].clone())]
+        );
+        assert_eq!(store.get_expired_keys(vbid, now + 1000).unwrap().len(), 2);
+
+        assert_eq!(bucket.run_expiry_pager(), 1);
+        assert_eq!(bucket.stats.expired_pager.load(Ordering::Relaxed), 1);
+        assert!(matches!(
+            bucket.get(keys[0].clone()),
+            Err(EngineError::KeyNotFound)
+        ));
+        assert!(bucket.get(keys[3].clone()).is_ok());
+        // The deletion takes the key out of the index
+        bucket.flush_vbucket(vbid);
+        assert!(store.get_expired_keys(vbid, now).unwrap().is_empty());
+        assert_eq!(bucket.run_expiry_pager(), 0);
+    }
+
+    #[test]
+    fn test_virtual_clock() {
+        let dir = tempfile::tempdir().unwrap();
+        let clock = VirtualClock::new(Duration::from_secs(1_700_000_000));
+        let bucket = make_bucket(
+            &dir,
+            Config {
+                clock: clock.clone(),
+                exp_pager_stime: 60,
+                ..Default::default()
+            },
+        );
+        bucket.enable_traffic();
+        let vbid = vbucket_for_key(b"key", 4);
+        let vb = bucket.get_vbucket(vbid).unwrap();
+        assert_eq!(vb.now_secs(), 1_700_000_000);
+
+        bucket.set(b"key".to_vec(), vec![], 0, 100).unwrap();
+        bucket.flush_vbucket(vbid);
+        clock.advance(Duration::from_secs(50));
+        assert!(bucket.get(b"key".to_vec()).is_ok());
+
+        // The pager's first run is a minute after it starts, by which time
+        // the item has expired
+        EPBucket::start_expiry_pager(&bucket);
+        clock.advance(Duration::from_secs(60));
+        let started = Instant::now();
+        while bucket.stats.expired_pager.load(Ordering::Relaxed) == 0 {
+            assert!(started.elapsed() < Duration::from_secs(10));
+            std::thread::sleep(Duration::from_millis(1));
+        }
+        assert_eq!(
+            bucket.get(b"key".to_vec()).unwrap_err(),
+            EngineError::KeyNotFound
+        );
+        assert_eq!(bucket.stats.expired_access.load(Ordering::Relaxed), 0);
+    }
+
+    #[test]
+    fn test_scrub_vbuckets() {
+        let dir = tempfile::tempdir().unwrap();
+        let bucket = make_bucket(&dir, Config::default());
+        bucket.enable_traffic();
+        for i in 0..100 {
+            bucket
+                .set(format!("key_{i}").into_bytes(), b"value".to_vec(), 0, 0)
+                .unwrap();
+        }
+        for vbid in bucket.vbucket_map.get_buckets() {
+            bucket.flush_vbucket(vbid);
+        }
+        assert_eq!(bucket.scrub_vbuckets(), 0);
+        assert_eq!(bucket.stats.scrub_vbuckets_done.load(Ordering::Relaxed), 4);
+        assert!(bucket.stats.scrub_bytes_read.load(Ordering::Relaxed) > 0);
+
+        // Corrupt a document body
+        let vbid = vbucket_for_key(b"key_0", 4);
+        let path = format!("{}/{vbid}.couch.1", bucket.config.dbname);
+        let bp = couchstore::Db::open(&path, couchstore::DBOpenOptions::default().read_only())
+            .unwrap()
+            .docinfo_by_id(key_with_default_collection(b"key_0".to_vec()))
+            .unwrap()
+            .unwrap()
+            .bp;
+        let mut file = std::fs::OpenOptions::new().write(true).open(&path).unwrap();
+        std::io::Seek::seek(&mut file, std::io::SeekFrom::Start(bp + 10)).unwrap();
+        std::io::Write::write_all(&mut file, b"X").unwrap();
+
+        // A vbucket with unpersisted mutations is skipped
+        let busy_key = (0..)
+            .map(|i| format!("key_{i}").into_bytes())
+            .find(|key| vbucket_for_key(key, 4) != vbid)
+            .unwrap();
+        bucket
+            .set(busy_key.clone(), b"value".to_vec(), 0, 0)
+            .unwrap();
+        assert_eq!(bucket.scrub_vbuckets(), 1);
+        assert_eq!(bucket.stats.scrub_vbuckets_done.load(Ordering::Relaxed), 3);
+        bucket.flush_vbucket(vbucket_for_key(&busy_key, 4));
+        assert_eq!(bucket.scrub_vbuckets(), 1);
+        assert_eq!(bucket.stats.scrub_passes.load(Ordering::Relaxed), 3);
+        assert_eq!(bucket.stats.scrub_corrupt_chunks.load(Ordering::Relaxed), 2);
+    }
+
+    #[test]
+    fn test_recover_corrupt_vbucket() {
+        let dir = tempfile::tempdir().unwrap();
+        let bucket = make_bucket(
+            &dir,
+            Config {
+                corrupt_file_recovery: true,
+                ..Default::default()
+            },
+        );
+        bucket.enable_traffic();
+        let vbid = vbucket_for_key(b"key_0", 4);
+        let keys: Vec<Vec<u8>> = (0..)
+            .map(|i| format!("key_{i}").into_bytes())
+            .filter(|key| vbucket_for_key(key, 4) == vbid)
+            .take(2)
+            .collect();
+        for key in &keys {
+            bucket.set(key.clone(), b"value".to_vec(), 0, 0).unwrap();
+            bucket.flush_vbucket(vbid);
+        }
+        let old_vb = bucket.get_vbucket(vbid).unwrap();
+
+        // Corrupt the body written by the second commit
+        let path = format!("{}/{vbid}.couch.1", bucket.config.dbname);
+        let bp = couchstore::Db::open(&path, couchstore::DBOpenOptions::default().read_only())
+            .unwrap()
+            .docinfo_by_id(key_with_default_collection(keys[1].clone()))
+            .unwrap()
+            .unwrap()
+            .bp;
+        let mut file = std::fs::OpenOptions::new().write(true).open(&path).unwrap();
+        std::io::Seek::seek(&mut file, std::io::SeekFrom::Start(bp + 10)).unwrap();
+        std::io::Write::write_all(&mut file, b"X").unwrap();
+
+        // Skipping the corrupt item still finds the other, and the vbucket
+        // is marked to be rebuilt from another copy
+        let store = bucket.get_store_by_shard(0);
+        let mut found = 0;
+        let result = store.scan(
+            vbid,
+            0,
+            ValueFilter::ValuesDecompressed,
+            ScanErrorPolicy::SkipAndReport,
+            &mut |_| found += 1,
+        );
+        assert_eq!((found, result.errors.len(), result.aborted), (1, 1, false));
+        bucket.on_scan_errors(vbid, &result);
+        bucket.on_scan_errors(vbid, &result);
+        assert!(old_vb.needs_rereplication());
+        assert_eq!(bucket.stats.scan_corrupt_items.load(Ordering::Relaxed), 2);
+        assert_eq!(
+            bucket
+                .stats
+                .vbuckets_need_rereplication
+                .load(Ordering::Relaxed),
+            1
+        );
+
+        assert_eq!(bucket.scrub_vbuckets(), 1);
+        assert_eq!(old_vb.state(), State::Dead);
+        let vb = bucket.get_vbucket(vbid).unwrap();
+        assert_eq!(vb.get_high_seqno(), 1);
+        let failover_log = vb.failover_table.get_failover_log();
+        assert_eq!(failover_log.len(), 2);
+        assert_eq!(failover_log[0].by_seqno, 1);
+        assert!(matches!(
+            bucket.get(keys[1].clone()),
+            Err(EngineError::KeyNotFound)
+        ));
+        assert!(!matches!(
+            bucket.get(keys[0].clone()),
+            Err(EngineError::KeyNotFound)
+        ));
+        assert_eq!(
+            bucket
+                .stats
+                .corrupt_vbuckets_recovered
+                .load(Ordering::Relaxed),
+            1
+        );
+        assert_eq!(
+            bucket.stats.corruption_seqnos_lost.load(Ordering::Relaxed),
+            1
+        );
+
+        // The new failover entry is persisted, and the file is clean
+        let store = bucket.get_store_by_shard(0);
+        let vb_state = store.get_cached_vb_state(vbid).unwrap();
+        assert_eq!(vb_state.high_seqno, 1);
+        assert_eq!(vb_state.failover_table, vb.failover_table.to_json());
+        assert_eq!(bucket.scrub_vbuckets(), 0);
+    }
+
+    #[test]
+    fn test_commit_failure() {
+        let dir = tempfile::tempdir().unwrap();
+        let bucket = make_bucket(
+            &dir,
+            Config {
+                flusher_commit_retries: 2,
+                flusher_retry_backoff_ms: 1,
+                ..Default::default()
+            },
+        );
+        bucket.enable_traffic();
+        let keys: Vec<Vec<u8>> = (0..)
+            .map(|i| format!("key_{i}").into_bytes())
+            .filter(|key| vbucket_for_key(key, 4) == Vbid::new(0))
+            .take(3)
+            .collect();
+        let vbid = Vbid::new(0);
+        bucket
+            .set_durable(keys[0].clone(), b"value".to_vec(), 0, 0)
+            .unwrap();
+
+        // A vbucket file without a header can't be read from
+        let path = format!("{}/{vbid}.couch.1", bucket.config.dbname);
+        std::fs::rename(&path, format!("{path}.bak")).unwrap();
+        std::fs::write(&path, vec![0; 8192]).unwrap();
+        assert_eq!(
+            bucket.trace_doc(keys[0].clone()),
+            Err(EngineError::TemporaryFailure)
+        );
+
+        // Make the vbucket file impossible to open
+        std::fs::remove_file(&path).unwrap();
+        std::fs::create_dir(&path).unwrap();
+
+        assert!(matches!(
+            bucket.set_durable(keys[1].clone(), b"value".to_vec(), 0, 0),
+            Err(EngineError::TemporaryFailure)
+        ));
+        let vb = bucket.get_vbucket(vbid).unwrap();
+        assert!(vb.is_persistence_failing());
+        assert_eq!(vb.get_persisted_seqno(), 1);
+        assert_eq!(bucket.stats.item_commit_failed.load(Ordering::Relaxed), 3);
+        assert_eq!(
+            bucket
+                .stats
+                .vbuckets_persistence_failing
+                .load(Ordering::Relaxed),
+            1
+        );
+        // Durable writes are refused until it recovers, others are accepted
+        assert!(matches!(
+            bucket.set_durable(keys[2].clone(), b"value".to_vec(), 0, 0),
+            Err(EngineError::TemporaryFailure)
+        ));
+        bucket
+            .set(keys[2].clone(), b"value".to_vec(), 0, 0)
+            .unwrap();
+
+        // Once the disk recovers the mutations kept in memory are persisted
+        std::fs::remove_dir(&path).unwrap();
+        std::fs::rename(format!("{path}.bak"), &path).unwrap();
+        assert_eq!(bucket.flush_vbucket(vbid), 2);
+        assert!(!vb.is_persistence_failing());
+        assert_eq!(vb.get_persisted_seqno(), 3);
+        assert_eq!(
+            bucket
+                .stats
+                .vbuckets_persistence_failing
+                .load(Ordering::Relaxed),
+            0
+        );
+        let store = bucket.get_store_by_shard(0);
+        assert_eq!(store.get_cached_vb_state(vbid).unwrap().high_seqno, 3);
+    }
+
+    #[test]
+    fn test_commit_retry_backoff_unlocked() {
+        let dir = tempfile::tempdir().unwrap();
+        let bucket = make_bucket(
+            &dir,
+            Config {
+                flusher_commit_retries: 1,
+                flusher_retry_backoff_ms: 500,
+                ..Default::default()
+            },
+        );
+        bucket.enable_traffic();
+        let vbid = vbucket_for_key(b"key_0", 4);
+        bucket
+            .set(b"key_0".to_vec(), b"value".to_vec(), 0, 0)
+            .unwrap();
+        bucket.flush_vbucket(vbid);
+        let path = format!("{}/{vbid}.couch.1", bucket.config.dbname);
+        std::fs::rename(&path, format!("{path}.bak")).unwrap();
+        std::fs::create_dir(&path).unwrap();
+        bucket
+            .set(b"key_0".to_vec(), b"value".to_vec(), 0, 0)
+            .unwrap();
+
+        // The vbucket can be locked while the flush backs off
+        std::thread::scope(|scope| {
+            let flush = scope.spawn(|| bucket.flush_vbucket(vbid));
+            std::thread::sleep(Duration::from_millis(100));
+            let start = Instant::now();
+            drop(bucket.get_locked_vbucket(vbid));
+            assert!(start.elapsed() < Duration::from_millis(300));
+            assert_eq!(flush.join().unwrap(), 0);
+        });
+        assert_eq!(bucket.stats.item_commit_failed.load(Ordering::Relaxed), 2);
+        assert!(bucket.get_vbucket(vbid).unwrap().is_persistence_failing());
+    }
+
+    #[test]
+    fn test_quarantine() {
+        let dir = tempfile::tempdir().unwrap();
+        let bucket = make_bucket(
+            &dir,
+            Config {
+                flusher_commit_retries: 0,
+                flusher_retry_backoff_ms: 1,
+                quarantine_flush_failures: 3,
+                ..Default::default()
+            },
+        );
+        bucket.enable_traffic();
+        let keys: Vec<Vec<u8>> = (0..)
+            .map(|i| format!("key_{i}").into_bytes())
+            .filter(|key| vbucket_for_key(key, 4) == Vbid::new(1))
+            .take(2)
+            .collect();
+        let vbid = Vbid::new(1);
+        bucket
+            .set_durable(keys[0].clone(), b"value".to_vec(), 0, 0)
+            .unwrap();
+        assert_eq!(
+            bucket.release_quarantine(vbid),
+            Err(EngineError::InvalidArguments)
+        );
+
+        let path = format!("{}/{vbid}.couch.1", bucket.config.dbname);
+        std::fs::rename(&path, format!("{path}.bak")).unwrap();
+        std::fs::create_dir(&path).unwrap();
+        bucket
+            .set(keys[1].clone(), b"value".to_vec(), 0, 0)
+            .unwrap();
+        for _ in 0..2 {
+            assert_eq!(bucket.flush_vbucket(vbid), 0);
+        }
+        let vb = bucket.get_vbucket(vbid).unwrap();
+        assert!(!vb.is_quarantined());
+
+        // The third failure in a row quarantines it, and it's left alone
+        assert_eq!(bucket.flush_vbucket(vbid), 0);
+        assert!(vb.is_quarantined());
+        assert_eq!(vb.state(), State::Dead);
+        assert_eq!(bucket.quarantined_vbuckets(), vec![vbid]);
+        assert_eq!(bucket.stats.vbuckets_quarantined.load(Ordering::Relaxed), 1);
+        let failed = bucket.stats.item_commit_failed.load(Ordering::Relaxed);
+        bucket.flush_vbucket(vbid);
+        assert_eq!(
+            bucket.stats.item_commit_failed.load(Ordering::Relaxed),
+            failed
+        );
+        assert_eq!(
+            bucket.get(keys[0].clone()).err(),
+            Some(EngineError::NotMyVbucket)
+        );
+        assert_eq!(
+            bucket.set(keys[0].clone(), b"value".to_vec(), 0, 0),
+            Err(EngineError::NotMyVbucket)
+        );
+        assert_eq!(
+            bucket.purge_tombstones(vbid).err(),
+            Some(EngineError::TemporaryFailure)
+        );
+
+        // It can't be released while the disk is still broken
+        assert_eq!(
+            bucket.release_quarantine(vbid),
+            Err(EngineError::TemporaryFailure)
+        );
+        assert!(vb.is_quarantined());
+
+        std::fs::remove_dir(&path).unwrap();
+        std::fs::rename(format!("{path}.bak"), &path).unwrap();
+        bucket.release_quarantine(vbid).unwrap();
+        assert!(!vb.is_quarantined());
+        assert_eq!(vb.state(), State::Active);
+        assert_eq!(vb.get_persisted_seqno(), 2);
+        assert!(bucket.quarantined_vbuckets().is_empty());
+        assert_eq!(bucket.stats.vbuckets_quarantined.load(Ordering::Relaxed), 0);
+        assert!(bucket.get(keys[1].clone()).is_ok());
+    }
+
+    #[test]
+    fn test_disk_read_only() {
+        let dir = tempfile::tempdir().unwrap();
+        let bucket = make_bucket(
+            &dir,
+            Config {
+                disk_min_free_bytes: 1000,
+                ..Default::default()
+            },
+        );
+        bucket.enable_traffic();
+        let vbid = vbucket_for_key(b"key", 4);
+        bucket
+            .set(b"key".to_vec(), b"value".to_vec(), 0, 0)
+            .unwrap();
+
+        // Below the minimum writes are refused and nothing is flushed, but
+        // reads carry on
+        bucket.set_free_disk_space(999);
+        assert!(bucket.is_read_only());
+        assert!(matches!(
+            bucket.set(b"key".to_vec(), b"value".to_vec(), 0, 0),
+            Err(EngineError::TemporaryFailure)
+        ));
+        assert!(matches!(
+            bucket.delete(b"key".to_vec(), 0),
+            Err(EngineError::TemporaryFailure)
+        ));
+        assert!(bucket.get(b"key".to_vec()).is_ok());
+        assert_eq!(bucket.flush_vbucket(vbid), 0);
+        let mut stats = HashMap::new();
+        bucket.get_stats(&mut |key, value| {
+            stats.insert(key.to_string(), value.to_string());
+        });
+        assert_eq!(stats["ep_disk_read_only"], "true");
+        assert_eq!(stats["ep_disk_free_bytes"], "999");
+
+        // Just above the minimum isn't enough to recover
+        bucket.set_free_disk_space(1050);
+        assert!(bucket.is_read_only());
+        bucket.set_free_disk_space(1100);
+        assert!(!bucket.is_read_only());
+        assert_eq!(bucket.flush_vbucket(vbid), 1);
+        bucket.delete(b"key".to_vec(), 0).unwrap();
+    }
+
+    #[test]
+    fn test_refresh_vbucket_state() {
+        #[derive(Default)]
+        struct Recorder(Mutex<Vec<(Vbid, u64)>>);
+        impl EngineObserver for Recorder {
+            fn on_vbucket_state_refreshed(&self, vbid: Vbid, vb_state: &VBucketState) {
+                self.0.lock().push((vbid, vb_state.max_cas));
+            }
+        }
+
+        let dir = tempfile::tempdir().unwrap();
+        let bucket = make_bucket(&dir, Config::default());
+        bucket.enable_traffic();
+        let recorder = Arc::new(Recorder::default());
+        bucket.register_observer(recorder.clone());
+        let vbid = vbucket_for_key(b"key", 4);
+        bucket
+            .set(b"key".to_vec(), b"value".to_vec(), 0, 0)
+            .unwrap();
+        bucket.flush_vbucket(vbid);
+        let store = bucket.vbucket_map.get_shard_by_vb_id(vbid).store();
+        let before = store.get_cached_vb_state(vbid).unwrap();
+
+        // A tool rewrites the file as a new revision with another state
+        let layout = couchstore::DbNameLayout::new(dir.path());
+        let rewritten = layout.path(vbid.into(), 2);
+        std::fs::copy(layout.path(vbid.into(), 1), &rewritten).unwrap();
+        let mut db =
+            couchstore::Db::open(&rewritten, couchstore::DBOpenOptions::default()).unwrap();
+        let mut changed = before.clone();
+        changed.max_cas = before.max_cas + 1000;
+        let json = serde_json::to_vec(&changed).unwrap();
+        db.save_local_document(couchstore::LocalDoc::new("_local/vbstate", json))
+            .unwrap();
+        db.commit();
+        drop(db);
+        assert_eq!(store.get_cached_vb_state(vbid), Some(before.clone()));
+
+        let refreshed = bucket.refresh_vbucket_state(vbid).unwrap().unwrap();
+        assert_eq!(refreshed.max_cas, before.max_cas + 1000);
+        assert_eq!(refreshed.high_seqno, 1);
+        assert_eq!(store.get_cached_vb_state(vbid), Some(refreshed));
+        assert_eq!(*recorder.0.lock(), [(vbid, before.max_cas + 1000)]);
+        // The store writes to the new revision from now on
+        bucket
+            .set(b"key".to_vec(), b"value2".to_vec(), 0, 0)
+            .unwrap();
+        bucket.flush_vbucket(vbid);
+        let db = couchstore::Db::open(&rewritten, couchstore::DBOpenOptions::default().read_only())
+            .unwrap();
+        assert_eq!(db.header().update_seq, 2);
+
+        // A vbucket which was never persisted has no state
+        let other = Vbid::new((u16::from(vbid) + 1) % 4);
+        assert_eq!(bucket.refresh_vbucket_state(other), Ok(None));
+    }
+
+    #[test]
+    fn test_observers() {
+        #[derive(Default)]
+        struct Recorder(Mutex<Vec<String>>);
+        impl EngineObserver for Recorder {
+            fn on_flush_complete(&self, vbid: Vbid, items: usize, high_seqno: u64) {
+                self.0
+                    .lock()
+                    .push(format!("flush {vbid} {items} {high_seqno}"));
+            }
+            fn on_vbucket_state_change(&self, vbid: Vbid, old: State, new: State) {
+                self.0.lock().push(format!("state {vbid} {old:?} {new:?}"));
+            }
+            fn on_compaction_start(&self, vbid: Vbid) {
+                self.0.lock().push(format!("compaction start {vbid}"));
+            }
+            fn on_compaction_end(&self, vbid: Vbid, result: &PurgeResult) {
+                self.0
+                    .lock()
+                    .push(format!("compaction end {vbid} {}", result.purged));
+            }
+            fn on_item_expired(&self, vbid: Vbid, key: &[u8]) {
+                let key = String::from_utf8_lossy(key).replace('\0', "");
+                self.0.lock().push(format!("expired {vbid} {key}"));
+            }
+        }
+
+        let dir = tempfile::tempdir().unwrap();
+        let bucket = make_bucket(
+            &dir,
+            Config {
+                metadata_purge_interval: 0,
+                ..Default::default()
+            },
+        );
+        bucket.enable_traffic();
+        let recorder = Arc::new(Recorder::default());
+        bucket.register_observer(recorder.clone());
+
+        let vbid = vbucket_for_key(b"key", 4);
+        let now = bucket.get_vbucket(vbid).unwrap().now_secs();
+        bucket
+            .set(b"key".to_vec(), b"value".to_vec(), 0, now - 10)
+            .unwrap();
+        bucket.flush_vbucket(vbid);
+        assert!(matches!(
+            bucket.get(b"key".to_vec()),
+            Err(EngineError::KeyNotFound)
+        ));
+        bucket.flush_vbucket(vbid);
+        bucket.purge_tombstones(vbid).unwrap();
+        bucket.set_vbucket_state(vbid, State::Replica).unwrap();
+        bucket.set_vbucket_state(vbid, State::Replica).unwrap();
+
+        let events = recorder.0.lock().clone();
+        assert_eq!(
+            events,
+            vec![
+                format!("flush {vbid} 1 1"),
+                format!("expired {vbid} key"),
+                format!("flush {vbid} 1 2"),
+                format!("compaction start {vbid}"),
+                format!("compaction end {vbid} 0"),
+                format!("state {vbid} Active Replica"),
+            ]
+        );
+        assert_eq!(
+            bucket
+                .get_store_by_shard(0)
+                .get_cached_vb_state(vbid)
+                .unwrap()
+                .state,
+            State::Replica
+        );
+    }
+
+    #[test]
+    fn test_mutation_tokens() {
+        let dir = tempfile::tempdir().unwrap();
+        let bucket = make_bucket(&dir, Config::default());
+        bucket.enable_traffic();
+        for i in 0..20 {
+            bucket
+                .set(format!("key_{i}").into_bytes(), b"value".to_vec(), 0, 0)
+                .unwrap();
+        }
+        let replica = Vbid::new(3);
+        bucket.set_vbucket_state(replica, State::Replica).unwrap();
+        let vbid = Vbid::new(0);
+        bucket.flush_vbucket(vbid);
+
+        let high = bucket.get_mutation_tokens(TokenSeqno::High);
+        let persisted = bucket.get_mutation_tokens(TokenSeqno::Persisted);
+        assert_eq!(high.tokens.len(), 3);
+        assert!(high.get(replica).is_none());
+        for token in &high.tokens {
+            let observed = bucket.observe_seqno(token.vbid).unwrap();
+            assert_eq!(token.vb_uuid, observed.vb_uuid);
+            assert_eq!(token.seqno, observed.current_seqno);
+            let on_disk = persisted.get(token.vbid).unwrap().seqno;
+            assert_eq!(on_disk, if token.vbid == vbid { token.seqno } else { 0 });
+        }
+        let replica_seqno = bucket.get_vbucket(replica).unwrap().get_high_seqno();
+        assert_eq!(
+            high.tokens.iter().map(|token| token.seqno).sum::<u64>(),
+            20 - replica_seqno
+        );
+
+        // An at_plus query waits until the persisted seqnos catch up
+        assert!(high.covers(&persisted));
+        assert!(!persisted.covers(&high));
+        for vbid in 0..4 {
+            bucket.flush_vbucket(Vbid::new(vbid));
+        }
+        assert!(bucket
+            .get_mutation_tokens(TokenSeqno::Persisted)
+            .covers(&high));
+    }
+
+    #[test]
+    fn test_key_transform() {
+        /// Reverses the key behind a marker, which is enough to tell the
+        /// stored keys from the clients'
+        #[derive(Debug)]
+        struct Reverse;
+        impl crate::key_transform::KeyTransform for Reverse {
+            fn encode(&self, key: &[u8]) -> Vec<u8> {
+                let mut stored = b"rev:".to_vec();
+                stored.extend(key.iter().rev());
+                stored
+            }
+            fn decode(&self, stored: &[u8]) -> Option<Vec<u8>> {
+                Some(
+                    stored
+                        .strip_prefix(b"rev:")?
+                        .iter()
+                        .rev()
+                        .copied()
+                        .collect(),
+                )
+            }
+        }
+
+        let dir = tempfile::tempdir().unwrap();
+        let bucket = make_bucket(
+            &dir,
+            Config {
+                key_transform: Some(Arc::new(Reverse)),
+                ..Default::default()
+            },
+        );
+        bucket.enable_traffic();
+        let keys: Vec<Vec<u8>> = (0..8).map(|i| format!("key_{i}").into_bytes()).collect();
+        for key in &keys {
+            bucket.set(key.clone(), b"value".to_vec(), 0, 0).unwrap();
+        }
+        bucket.delete(keys[1].clone(), 0).unwrap();
+        assert_eq!(
+            bucket.get(keys[0].clone()).unwrap().value.unwrap(),
+            b"value"
+        );
+        assert!(bucket.get_multi(keys.clone())[2].is_ok());
+        let locked = bucket.get_locked(keys[3].clone(), 0).unwrap();
+        bucket.unlock(keys[3].clone(), locked.cas).unwrap();
+
+        // The client's key picks the vbucket, and only the transformed key
+        // is stored
+        let vbid = vbucket_for_key(&keys[0], 4);
+        let vb = bucket.get_vbucket(vbid).unwrap();
+        assert!(vb.get(b"\0rev:0_yek").is_some());
+        assert!(vb.get(b"\0key_0").is_none());
+
+        let mut scanned = Vec::new();
+        for vbid in (0..4).map(Vbid::new) {
+            bucket.flush_vbucket(vbid);
+            scanned.extend(bucket.scan_keys(vbid).unwrap());
+        }
+        scanned.sort();
+        let mut live = keys.clone();
+        live.remove(1);
+        assert_eq!(scanned, live);
+        assert_eq!(
+            bucket.list_keys(b"key_", 10, None),
+            Err(EngineError::InvalidArguments)
+        );
+    }
+
+    #[test]
+    fn test_list_keys() {
+        let dir = tempfile::tempdir().unwrap();
+        let bucket = make_bucket(&dir, Config::default());
+        bucket.enable_traffic();
+        for i in 0..20 {
+            bucket
+                .set(format!("user_{i:02}").into_bytes(), b"{}".to_vec(), 0, 0)
+                .unwrap();
+            bucket
+                .set(format!("order_{i:02}").into_bytes(), b"{}".to_vec(), 0, 0)
+                .unwrap();
+        }
+        bucket.delete(b"user_03".to_vec(), 0).unwrap();
+        for vbid in (0..4).map(Vbid::new) {
+            bucket.flush_vbucket(vbid);
+        }
+        // Not persisted yet
+        bucket
+            .set(b"user_99".to_vec(), b"{}".to_vec(), 0, 0)
+            .unwrap();
+
+        let mut listed = Vec::new();
+        let mut continuation = None;
+        loop {
+            let page = bucket
+                .list_keys(b"user_", 6, continuation.as_deref())
+                .unwrap();
+            assert!(page.keys.len() <= 6);
+            listed.extend(page.keys);
+            continuation = page.continuation;
+            if continuation.is_none() {
+                break;
+            }
+        }
+        let expected: Vec<Vec<u8>> = (0..20)
+            .filter(|i| *i != 3)
+            .map(|i| format!("user_{i:02}").into_bytes())
+            .collect();
+        assert_eq!(listed, expected);
+
+        let page = bucket.list_keys(b"", 3, None).unwrap();
+        assert_eq!(page.keys, [b"order_00", b"order_01", b"order_02"]);
+        assert_eq!(page.continuation.as_deref(), Some(&b"order_02"[..]));
+        assert!(bucket.list_keys(b"none_", 3, None).unwrap().keys.is_empty());
+        assert_eq!(
+            bucket.list_keys(b"user_", 0, None),
+            Err(EngineError::InvalidArguments)
+        );
+    }
+
+    #[test]
+    fn test_get_locked() {
+        let dir = tempfile::tempdir().unwrap();
+        let bucket = make_bucket(&dir, Config::default());
+        bucket.enable_traffic();
+        let key = b"key".to_vec();
+        let cas = bucket.set(key.clone(), b"value".to_vec(), 0, 0).unwrap();
+
+        let locked = bucket.get_locked(key.clone(), 0).unwrap();
+        assert_eq!(locked.value.as_deref(), Some(&b"value"[..]));
+        assert_ne!(locked.cas, cas);
+        assert_eq!(
+            bucket.get_locked(key.clone(), 0).unwrap_err(),
+            EngineError::Locked
+        );
+        // Other readers can't see the lock's CAS
+        assert_eq!(bucket.get(key.clone()).unwrap().cas, u64::MAX);
+
+        // Mutations without the lock's CAS fail
+        assert_eq!(
+            bucket.set(key.clone(), b"other".to_vec(), 0, 0),
+            Err(EngineError::Locked)
+        );
+        assert_eq!(bucket.delete(key.clone(), cas), Err(EngineError::Locked));
+        assert_eq!(bucket.unlock(key.clone(), cas), Err(EngineError::Locked));
+        assert_eq!(bucket.stats.lock_errors.load(Ordering::Relaxed), 4);
+
+        bucket.unlock(key.clone(), locked.cas).unwrap();
+        assert_eq!(
+            bucket.unlock(key.clone(), locked.cas),
+            Err(EngineError::NotLocked)
+        );
+        assert_eq!(bucket.get(key.clone()).unwrap().cas, locked.cas);
+
+        // Storing with the lock's CAS releases it
+        let locked = bucket.get_locked(key.clone(), 10).unwrap();
+        let cas = bucket
+            .set_with_datatype(
+                key.clone(),
+                b"new".to_vec(),
+                Datatype::empty(),
+                0,
+                0,
+                locked.cas,
+            )
+            .unwrap();
+        assert_eq!(bucket.get(key.clone()).unwrap().cas, cas);
+        assert_eq!(
+            bucket.delete(key.clone(), locked.cas),
+            Err(EngineError::KeyExists)
+        );
+        bucket.delete(key, cas).unwrap();
+        assert_eq!(bucket.stats.locks_taken.load(Ordering::Relaxed), 2);
+    }
+
+    #[test]
+    fn test_lazy_expiry() {
+        let dir = tempfile::tempdir().unwrap();
+        let bucket = make_bucket(&dir, Config::default());
+        bucket.enable_traffic();
+
+        // A relative expiry is stored as an absolute time
+        bucket.set(b"relative".to_vec(), vec![], 0, 100).unwrap();
+        let expiry_time = bucket.get(b"relative".to_vec()).unwrap().expiry_time;
+        assert!(expiry_time > MAX_RELATIVE_EXPIRY);
+
+        // An absolute time in the past has already expired
+        bucket
+            .set(b"expired".to_vec(), vec![], 0, MAX_RELATIVE_EXPIRY + 1)
+            .unwrap();
+        assert_eq!(
+            bucket.get(b"expired".to_vec()).unwrap_err(),
+            EngineError::KeyNotFound
+        );
+        assert_eq!(
+            bucket.get(b"expired".to_vec()).unwrap_err(),
+            EngineError::KeyNotFound
+        );
+        assert_eq!(bucket.stats.expired_access.load(Ordering::Relaxed), 1);
+
+        // The expiry is persisted as a TTL deletion
+        let vbid = vbucket_for_key(b"expired", 4);
+        bucket.flush_vbucket(vbid);
+        let mut deleted = None;
+        bucket.get_store_by_shard(0).scan(
+            vbid,
+            0,
+            ValueFilter::KeysOnly,
+            ScanErrorPolicy::Abort,
+            &mut |item| {
+                if item.key == b"\0expired" {
+                    deleted = Some(item)
+                }
+            },
+        );
+        let deleted = deleted.unwrap();
+        assert_eq!(deleted.rev_seqno, 2);
+        assert_eq!(deleted.delete_source, DeleteSource::Ttl);
+    }
+
+    #[test]
+    fn test_size_limits() {
+        let dir = tempfile::tempdir().unwrap();
+        let bucket = make_bucket(&dir, Config::default());
+        bucket.enable_traffic();
+        assert_eq!(
+            bucket.set(vec![b'k'; 251], vec![], 0, 0),
+            Err(EngineError::TooBig)
+        );
+        bucket.set(vec![b'k'; 250], vec![], 0, 0).unwrap();
+
+        // The limits can be changed per bucket
+        let dir = tempfile::tempdir().unwrap();
+        let bucket = make_bucket(
+            &dir,
+            Config {
+                max_key_size: 4,
+                max_item_size: 8,
+                ..Default::default()
+            },
+        );
+        bucket.enable_traffic();
+        assert_eq!(
+            bucket.set(b"key_0".to_vec(), vec![], 0, 0),
+            Err(EngineError::TooBig)
+        );
+        assert_eq!(
+            bucket.set(b"key".to_vec(), vec![0; 9], 0, 0),
+            Err(EngineError::TooBig)
+        );
+        bucket.set(b"key".to_vec(), vec![0; 8], 0, 0).unwrap();
+        let vbid = vbucket_for_key(b"key", 4);
+        assert_eq!(bucket.flush_vbucket(vbid), 1);
+    }
+
+    #[test]
+    fn test_compression_modes() {
+        let value = vec![b'a'; 1000];
+        let compressed = compression::compress(&value);
+        let make = |dir, compression_mode| {
+            let bucket = make_bucket(
+                dir,
+                Config {
+                    compression_mode,
+                    ..Default::default()
+                },
+            );
+            bucket.enable_traffic();
+            bucket
+        };
+
+        // Active compresses on write, inflating for clients without snappy
+        let dir = tempfile::tempdir().unwrap();
+        let bucket = make(&dir, CompressionMode::Active);
+        bucket.set(b"key".to_vec(), value.clone(), 0, 0).unwrap();
+        let stored = bucket.get_for_client(b"key".to_vec(), true).unwrap();
+        assert_eq!(stored.datatype, Datatype::SNAPPY);
+        assert_eq!(stored.value.unwrap(), compressed);
+        let inflated = bucket.get(b"key".to_vec()).unwrap();
+        assert_eq!(inflated.datatype, Datatype::empty());
+        assert_eq!(inflated.value.unwrap(), value);
+        let mut stats = HashMap::new();
+        bucket.get_stats(&mut |key, value| {
+            stats.insert(key.to_string(), value.to_string());
+        });
+        assert_eq!(stats["ep_values_inflated"], "1");
+        assert!(stats["ep_compression_ratio"].parse::<f64>().unwrap() > 10.0);
+        // Values are persisted decompressed
+        let vbid = vbucket_for_key(b"key", 4);
+        bucket.flush_vbucket(vbid);
+        bucket.get_store_by_shard(0).scan(
+            vbid,
+            0,
+            ValueFilter::ValuesDecompressed,
+            ScanErrorPolicy::Abort,
+            &mut |item| {
+                assert_eq!(item.value.unwrap(), value);
+                assert_eq!(item.datatype, Datatype::empty());
+            },
+        );
+
+        // Passive keeps what the client sent, which must be valid
+        let dir = tempfile::tempdir().unwrap();
+        let bucket = make(&dir, CompressionMode::Passive);
+        bucket
+            .set_with_datatype(
+                b"key".to_vec(),
+                compressed.clone(),
+                Datatype::SNAPPY,
+                0,
+                0,
+                0,
+            )
+            .unwrap();
+        let stored = bucket.get_for_client(b"key".to_vec(), true).unwrap();
+        assert_eq!(stored.value.unwrap(), compressed);
+        assert_eq!(
+            bucket.set_with_datatype(b"key".to_vec(), value.clone(), Datatype::SNAPPY, 0, 0, 0),
+            Err(EngineError::InvalidArguments)
+        );
+
+        // Off stores everything uncompressed
+        let dir = tempfile::tempdir().unwrap();
+        let bucket = make(&dir, CompressionMode::Off);
+        bucket
+            .set_with_datatype(b"key".to_vec(), compressed, Datatype::SNAPPY, 0, 0, 0)
+            .unwrap();
+        let stored = bucket.get_for_client(b"key".to_vec(), true).unwrap();
+        assert_eq!(stored.datatype, Datatype::empty());
+        assert_eq!(stored.value.unwrap(), value);
+    }
 }
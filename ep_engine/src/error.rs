@@ -0,0 +1,50 @@
+use thiserror::Error;
+
+/// Errors returned by engine operations
+#[derive(Error, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EngineError {
+    #[error("key not found")]
+    KeyNotFound,
+    #[error("key exists")]
+    KeyExists,
+    #[error("invalid arguments")]
+    InvalidArguments,
+    #[error("not my vbucket")]
+    NotMyVbucket,
+    /// The operation cannot be served right now (e.g. during warmup, when
+    /// memory is too high or the disk nearly full) and the client should
+    /// retry
+    #[error("temporary failure")]
+    TemporaryFailure,
+    /// The operation must wait for a background task (e.g. a disk fetch)
+    #[error("would block")]
+    WouldBlock,
+    #[error("out of range")]
+    OutOfRange,
+    /// The key or value is over the bucket's size limit
+    #[error("too big")]
+    TooBig,
+    /// The client must roll back to the given seqno before it can resume
+    /// its stream
+    #[error("rollback to {0}")]
+    Rollback(u64),
+    /// The key is locked by GET_LOCKED and the request didn't give the
+    /// lock's CAS
+    #[error("locked")]
+    Locked,
+    #[error("not locked")]
+    NotLocked,
+    #[error("unknown scope")]
+    UnknownScope,
+    #[error("unknown collection")]
+    UnknownCollection,
+    /// The bucket's data directory was written by an incompatible engine
+    /// or belongs to another bucket. The details are logged.
+    #[error("incompatible data directory")]
+    IncompatibleDataDir,
+    /// The connection should be closed
+    #[error("disconnect")]
+    Disconnect,
+}
+
+pub type EngineResult<T> = Result<T, EngineError>;
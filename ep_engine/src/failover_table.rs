@@ -41,7 +41,97 @@ impl FailoverTable {
         table
     }
 
-    fn create_entry(&self, high_seqno: u64) {
+    pub fn get_latest_uuid(&self) -> u64 {
+        self.latest_uuid.load(Ordering::SeqCst)
+    }
+
+    /// Entries from newest to oldest
+    pub fn get_failover_log(&self) -> Vec<FailoverEntry> {
+        self.state.lock().table.iter().copied().collect()
+    }
+
+    /// Decide whether a client resuming a stream from start_seqno, having
+    /// received the given snapshot on the branch of history identified by
+    /// vb_uuid, must roll back before it can continue from this vbucket.
+    /// Returns the seqno to roll back to and the reason.
+    #[allow(clippy::too_many_arguments)]
+    pub fn needs_rollback(
+        &self,
+        start_seqno: u64,
+        cur_seqno: u64,
+        vb_uuid: u64,
+        snap_start_seqno: u64,
+        snap_end_seqno: u64,
+        purge_seqno: u64,
+        strict_vb_uuid_match: bool,
+    ) -> Option<(u64, String)> {
+        // A client starting from scratch has nothing to roll back, unless it
+        // asked for its (non-zero) vb_uuid to be checked strictly
+        if start_seqno == 0 && (!strict_vb_uuid_match || vb_uuid == 0) {
+            return None;
+        }
+
+        if start_seqno != 0 && start_seqno < purge_seqno {
+            return Some((
+                0,
+                format!(
+                    "purge seqno ({purge_seqno}) is greater than start seqno - could miss purged deletions"
+                ),
+            ));
+        }
+
+        let table = &self.state.lock().table;
+
+        // The client's branch of history is valid up to the seqno at which
+        // the next (newer) entry branched off, or our high seqno if it is the
+        // latest entry
+        let position = table.iter().position(|entry| entry.vb_uuid == vb_uuid);
+        let Some(position) = position else {
+            return Some((
+                0,
+                "vBucket UUID not found in failover table, consumer and producer have no common history"
+                    .to_string(),
+            ));
+        };
+        let upper = if position == 0 {
+            cur_seqno
+        } else {
+            table[position - 1].by_seqno
+        };
+
+        if snap_end_seqno <= upper {
+            return None;
+        }
+
+        // The client's snapshot extends past the point at which its branch
+        // diverged from ours. It was only consistent at the start of that
+        // snapshot, or at the branch point if that is earlier.
+        Some((
+            snap_start_seqno.min(upper),
+            format!("consumer ahead of producer - producer upper at {upper}"),
+        ))
+    }
+
+    /// Where the branch of history vb_uuid diverged from ours before
+    /// reaching seqno, losing the mutation at seqno on it. None if the
+    /// mutation is on our branch, or will be once we have caught up, or if
+    /// the branch isn't in the table so there's no telling.
+    pub fn lost_at(&self, vb_uuid: u64, seqno: u64) -> Option<u64> {
+        let table = &self.state.lock().table;
+        let position = table.iter().position(|entry| entry.vb_uuid == vb_uuid)?;
+        // The next (newer) entry is where the branch was left
+        let branch_end = table.get(position.checked_sub(1)?)?.by_seqno;
+        (seqno > branch_end).then_some(branch_end)
+    }
+
+    /// The table as stored in the persisted vbucket state
+    pub fn to_json(&self) -> serde_json::Value {
+        serde_json::to_value(&self.state.lock().table).unwrap()
+    }
+
+    /// Start a new branch of history at high_seqno, dropping the entries of
+    /// any branch beyond it
+    pub fn create_entry(&self, high_seqno: u64) {
         let table = &mut self.state.lock().table;
 
         // Our failover table represents only *our* branch of history.
@@ -75,3 +165,50 @@ pub struct FailoverEntry {
     #[serde(rename = "seq")]
     pub by_seqno: u64,
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_needs_rollback() {
+        // History branched at seqno 10 (uuid 2) and again at seqno 20 (uuid 3)
+        let json = serde_json::json!([
+            {"id": 3, "seq": 20},
+            {"id": 2, "seq": 10},
+            {"id": 1, "seq": 0},
+        ]);
+        let table = FailoverTable::new(json, 25, 30);
+        let rollback_seqno = |start, vb_uuid, snap_start, snap_end| {
+            table
+                .needs_rollback(start, 30, vb_uuid, snap_start, snap_end, 0, false)
+                .map(|(seqno, _)| seqno)
+        };
+
+        assert_eq!(rollback_seqno(0, 99, 0, 0), None);
+        assert_eq!(rollback_seqno(25, 3, 25, 25), None);
+        assert_eq!(rollback_seqno(15, 99, 15, 15), Some(0));
+        // Entirely before the uuid's branch ended
+        assert_eq!(rollback_seqno(8, 1, 5, 10), None);
+        // Snapshot spans the branch point, roll back to its start
+        assert_eq!(rollback_seqno(18, 2, 15, 25), Some(15));
+        assert_eq!(rollback_seqno(22, 1, 12, 25), Some(10));
+        // Consumer ahead of the latest branch
+        assert_eq!(rollback_seqno(35, 3, 31, 40), Some(30));
+
+        assert_eq!(
+            table
+                .needs_rollback(5, 30, 3, 5, 5, 8, false)
+                .map(|(seqno, _)| seqno),
+            Some(0)
+        );
+
+        // Mutations on the branches left at seqnos 10 and 20
+        assert_eq!(table.lost_at(1, 10), None);
+        assert_eq!(table.lost_at(1, 11), Some(10));
+        assert_eq!(table.lost_at(2, 25), Some(20));
+        // Or on the latest, which may not have reached it yet
+        assert_eq!(table.lost_at(3, 40), None);
+        assert_eq!(table.lost_at(99, 5), None);
+    }
+}
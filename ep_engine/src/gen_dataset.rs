@@ -0,0 +1,564 @@
+//! Generating synthetic datasets shaped like the travel-sample bucket, for
+//! benchmarks and tests which need realistic data without checking it in.
+//! The documents are airlines, airports, hotels, routes and landmarks, in
+//! roughly travel-sample's proportions, optionally with some binary values
+//! and some expiring. They are written through the KVStore's commit path
+//! into a new data directory, which a bucket can then warm up from.
+//!
+//! A dataset is determined by its spec, including the seed, apart from the
+//! CAS and expiry times, which are based on the time it was generated.
+
+use crate::{
+    collections::{self, CollectionEntry, CollectionId, DEFAULT_COLLECTION},
+    item::{Datatype, DeleteSource, Item, DEFAULT_MAX_ITEM_SIZE, DEFAULT_MAX_KEY_SIZE},
+    kv_store::{CommitError, CouchKVStore, CouchKVStoreConfig, KVStore},
+    vbucket::{State, VBucketState, Vbid},
+    vbucket_hash::vbucket_for_key,
+};
+use rand::{rngs::StdRng, seq::SliceRandom, Rng, SeedableRng};
+use serde_json::{json, Value};
+use std::{
+    io,
+    ops::Range,
+    sync::Arc,
+    time::{SystemTime, UNIX_EPOCH},
+};
+use thiserror::Error;
+
+/// Items written to a vbucket per commit
+const BATCH_SIZE: usize = 1000;
+
+/// The scope the generated collections are in
+const INVENTORY_SCOPE: collections::ScopeId = 8;
+
+#[derive(Error, Debug)]
+pub enum DatasetError {
+    #[error("invalid dataset spec: {0}")]
+    InvalidSpec(&'static str),
+    #[error("{0} already holds vbucket files")]
+    DestinationNotEmpty(String),
+    #[error(transparent)]
+    Io(#[from] io::Error),
+    #[error("failed to write vbucket {vbid}: {source}")]
+    Write { vbid: Vbid, source: CommitError },
+}
+
+/// The kinds of document in a dataset, as in travel-sample
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DocType {
+    Airline,
+    Airport,
+    Hotel,
+    Route,
+    Landmark,
+}
+
+impl DocType {
+    pub const ALL: [DocType; 5] = [
+        DocType::Airline,
+        DocType::Airport,
+        DocType::Hotel,
+        DocType::Route,
+        DocType::Landmark,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            DocType::Airline => "airline",
+            DocType::Airport => "airport",
+            DocType::Hotel => "hotel",
+            DocType::Route => "route",
+            DocType::Landmark => "landmark",
+        }
+    }
+
+    /// How many of the type there are relative to the others
+    fn weight(self) -> u32 {
+        match self {
+            DocType::Airline => 1,
+            DocType::Airport => 10,
+            DocType::Hotel => 5,
+            DocType::Route => 120,
+            DocType::Landmark => 22,
+        }
+    }
+
+    /// The type's collection, when the dataset has collections
+    pub fn collection_id(self) -> CollectionId {
+        let index = DocType::ALL.iter().position(|&t| t == self).unwrap();
+        collections::FIRST_USER_COLLECTION + index as CollectionId
+    }
+}
+
+/// What to generate
+#[derive(Debug, Clone, PartialEq)]
+pub struct DatasetSpec {
+    pub num_vbuckets: u16,
+    pub documents: u64,
+    /// Put each type of document in a collection of its own, named after
+    /// the type, rather than all in the default collection
+    pub collections: bool,
+    /// The fraction of documents with a random binary value in place of
+    /// their JSON
+    pub binary_fraction: f64,
+    /// Sizes of the binary values in bytes
+    pub binary_size: Range<usize>,
+    /// The fraction of documents which expire
+    pub ttl_fraction: f64,
+    /// Seconds from generation until expiring documents expire
+    pub ttl: Range<u32>,
+    pub seed: u64,
+}
+
+impl Default for DatasetSpec {
+    fn default() -> Self {
+        Self {
+            num_vbuckets: 1024,
+            documents: 31_591,
+            collections: false,
+            binary_fraction: 0.0,
+            binary_size: 16..4096,
+            ttl_fraction: 0.0,
+            ttl: 3600..86_400,
+            seed: 0,
+        }
+    }
+}
+
+/// What a generation wrote
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct DatasetSummary {
+    pub documents: u64,
+    pub binary: u64,
+    pub with_ttl: u64,
+    /// The collections created, besides the default collection
+    pub collections: u64,
+}
+
+const CITIES: [(&str, &str, &str); 12] = [
+    ("San Francisco", "United States", "America/Los_Angeles"),
+    ("New York", "United States", "America/New_York"),
+    ("Chicago", "United States", "America/Chicago"),
+    ("Seattle", "United States", "America/Los_Angeles"),
+    ("London", "United Kingdom", "Europe/London"),
+    ("Manchester", "United Kingdom", "Europe/London"),
+    ("Edinburgh", "United Kingdom", "Europe/London"),
+    ("Paris", "France", "Europe/Paris"),
+    ("Lyon", "France", "Europe/Paris"),
+    ("Nice", "France", "Europe/Paris"),
+    ("Marseille", "France", "Europe/Paris"),
+    ("Toulouse", "France", "Europe/Paris"),
+];
+
+const WORDS: [&str; 24] = [
+    "the",
+    "a",
+    "view",
+    "old",
+    "harbour",
+    "market",
+    "square",
+    "quiet",
+    "busy",
+    "station",
+    "garden",
+    "museum",
+    "river",
+    "bridge",
+    "friendly",
+    "staff",
+    "room",
+    "clean",
+    "breakfast",
+    "walk",
+    "from",
+    "centre",
+    "great",
+    "small",
+];
+
+const ACTIVITIES: [&str; 5] = ["see", "do", "eat", "drink", "buy"];
+
+const EQUIPMENT: [&str; 6] = ["320", "738", "73H", "CR9", "E90", "777"];
+
+fn code(rng: &mut StdRng, len: usize) -> String {
+    (0..len)
+        .map(|_| rng.gen_range(b'A'..=b'Z') as char)
+        .collect()
+}
+
+fn sentence(rng: &mut StdRng, words: Range<usize>) -> String {
+    let len = rng.gen_range(words);
+    let words: Vec<&str> = (0..len).map(|_| *WORDS.choose(rng).unwrap()).collect();
+    words.join(" ")
+}
+
+/// The JSON of document id of the type
+fn document(rng: &mut StdRng, doc_type: DocType, id: u64) -> Value {
+    let (city, country, tz) = *CITIES.choose(rng).unwrap();
+    let name = |rng: &mut StdRng| {
+        let mut name = sentence(rng, 1..3);
+        name[..1].make_ascii_uppercase();
+        name
+    };
+    let mut doc = match doc_type {
+        DocType::Airline => json!({
+            "name": format!("{} Air", name(rng)),
+            "iata": code(rng, 2),
+            "icao": code(rng, 3),
+            "callsign": code(rng, 6),
+            "country": country,
+        }),
+        DocType::Airport => json!({
+            "airportname": format!("{city} {}", name(rng)),
+            "city": city,
+            "country": country,
+            "faa": code(rng, 3),
+            "icao": code(rng, 4),
+            "tz": tz,
+            "geo": {
+                "lat": rng.gen_range(-60.0..70.0),
+                "lon": rng.gen_range(-180.0..180.0),
+                "alt": rng.gen_range(0..2000),
+            },
+        }),
+        DocType::Hotel => {
+            let reviews: Vec<Value> = (0..rng.gen_range(0..6))
+                .map(|_| {
+                    json!({
+                        "author": name(rng),
+                        "content": sentence(rng, 10..60),
+                        "ratings": {
+                            "Overall": rng.gen_range(1..=5),
+                            "Cleanliness": rng.gen_range(1..=5),
+                            "Service": rng.gen_range(1..=5),
+                        },
+                    })
+                })
+                .collect();
+            json!({
+                "name": format!("{} Hotel", name(rng)),
+                "address": format!("{} {} Street", rng.gen_range(1..300), name(rng)),
+                "city": city,
+                "country": country,
+                "description": sentence(rng, 5..30),
+                "price": format!("{}", rng.gen_range(40..400)),
+                "free_breakfast": rng.gen_bool(0.5),
+                "free_parking": rng.gen_bool(0.5),
+                "vacancy": rng.gen_bool(0.8),
+                "reviews": reviews,
+            })
+        }
+        DocType::Route => {
+            let airline = code(rng, 2);
+            let schedule: Vec<Value> = (0..rng.gen_range(1..15))
+                .map(|_| {
+                    json!({
+                        "day": rng.gen_range(0..7),
+                        "utc": format!("{:02}:{:02}:00", rng.gen_range(0..24), rng.gen_range(0..60)),
+                        "flight": format!("{airline}{:03}", rng.gen_range(0..1000)),
+                    })
+                })
+                .collect();
+            json!({
+                "airline": airline,
+                "sourceairport": code(rng, 3),
+                "destinationairport": code(rng, 3),
+                "stops": rng.gen_range(0..2),
+                "equipment": *EQUIPMENT.choose(rng).unwrap(),
+                "distance": rng.gen_range(100.0..12000.0),
+                "schedule": schedule,
+            })
+        }
+        DocType::Landmark => json!({
+            "name": name(rng),
+            "city": city,
+            "country": country,
+            "activity": *ACTIVITIES.choose(rng).unwrap(),
+            "content": sentence(rng, 10..80),
+            "hours": format!("{}am-{}pm", rng.gen_range(7..11), rng.gen_range(4..11)),
+        }),
+    };
+    doc["id"] = id.into();
+    doc["type"] = doc_type.name().into();
+    doc
+}
+
+/// The vbuckets being written, each in batches
+struct Writer {
+    store: CouchKVStore,
+    states: Vec<VBucketState>,
+    batches: Vec<Vec<Arc<Item>>>,
+}
+
+impl Writer {
+    fn write(&mut self, vbid: Vbid, mut item: Item) -> Result<(), DatasetError> {
+        let state = &mut self.states[usize::from(vbid)];
+        state.high_seqno += 1;
+        state.snap_start = state.high_seqno as u64;
+        state.snap_end = state.high_seqno as u64;
+        state.max_cas = state.max_cas.max(item.cas);
+        item.by_seqno = state.high_seqno as u64;
+
+        let batch = &mut self.batches[usize::from(vbid)];
+        batch.push(Arc::new(item));
+        if batch.len() >= BATCH_SIZE {
+            self.commit(vbid)?;
+        }
+        Ok(())
+    }
+
+    fn commit(&mut self, vbid: Vbid) -> Result<(), DatasetError> {
+        let items = std::mem::take(&mut self.batches[usize::from(vbid)]);
+        self.store
+            .commit(vbid, &items, &self.states[usize::from(vbid)])
+            .map_err(|source| DatasetError::Write { vbid, source })
+    }
+}
+
+/// Generate the dataset into dir, a new data directory, with every
+/// vbucket active
+pub fn generate(dir: &str, spec: &DatasetSpec) -> Result<DatasetSummary, DatasetError> {
+    if spec.num_vbuckets == 0 {
+        return Err(DatasetError::InvalidSpec("no vbuckets"));
+    }
+    if !(0.0..=1.0).contains(&spec.binary_fraction) || !(0.0..=1.0).contains(&spec.ttl_fraction) {
+        return Err(DatasetError::InvalidSpec("fractions must be from 0 to 1"));
+    }
+    if (spec.binary_fraction > 0.0 && spec.binary_size.is_empty())
+        || (spec.ttl_fraction > 0.0 && spec.ttl.is_empty())
+    {
+        return Err(DatasetError::InvalidSpec("empty range"));
+    }
+    std::fs::create_dir_all(dir)?;
+    if !couchstore::DbNameLayout::new(dir).list()?.is_empty() {
+        return Err(DatasetError::DestinationNotEmpty(dir.to_string()));
+    }
+
+    let vbuckets = spec.num_vbuckets as usize;
+    let mut writer = Writer {
+        store: CouchKVStore::new(CouchKVStoreConfig {
+            max_vbuckets: spec.num_vbuckets,
+            db_name: dir.to_string(),
+            data_paths: Vec::new(),
+            max_shards: 1,
+            shard_id: 0,
+            history_retention: false,
+            max_key_size: DEFAULT_MAX_KEY_SIZE,
+            max_item_size: DEFAULT_MAX_ITEM_SIZE,
+            pitr_max_history_age: None,
+            sync_policy: couchstore::SyncPolicy::EveryCommit,
+            dsync: false,
+            btree_tuning: couchstore::BtreeTuning::default(),
+            front_coded_keys: false,
+            compaction_direct_io: false,
+        }),
+        states: vec![VBucketState::new(State::Active); vbuckets],
+        batches: vec![Vec::new(); vbuckets],
+    };
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+    let mut cas = now.as_nanos() as u64;
+    let mut summary = DatasetSummary::default();
+    let item = |key, value, datatype, cas, expiry_time| Item {
+        key,
+        value: Some(value),
+        cas,
+        expiry_time,
+        flags: 0,
+        by_seqno: 0,
+        rev_seqno: 1,
+        delete_source: DeleteSource::Explicit,
+        datatype,
+    };
+
+    // Every vbucket records the collections, before any of their items
+    if spec.collections {
+        for doc_type in DocType::ALL {
+            let entry = CollectionEntry {
+                name: doc_type.name().to_string(),
+                scope_id: INVENTORY_SCOPE,
+                max_ttl: 0,
+            };
+            let value = serde_json::to_vec(&entry).unwrap();
+            let key = collections::collection_event_key(doc_type.collection_id());
+            cas += 1;
+            for vbid in (0..spec.num_vbuckets).map(Vbid::new) {
+                writer.write(
+                    vbid,
+                    item(key.clone(), value.clone(), Datatype::JSON, cas, 0),
+                )?;
+            }
+            summary.collections += 1;
+        }
+    }
+
+    let mut rng = StdRng::seed_from_u64(spec.seed);
+    let total_weight: u32 = DocType::ALL.iter().map(|t| t.weight()).sum();
+    for id in 0..spec.documents {
+        let mut pick = rng.gen_range(0..total_weight);
+        let doc_type = *DocType::ALL
+            .iter()
+            .find(|t| match pick.checked_sub(t.weight()) {
+                Some(rest) => {
+                    pick = rest;
+                    false
+                }
+                None => true,
+            })
+            .unwrap();
+        let key = format!("{}_{id}", doc_type.name());
+
+        let (value, datatype) = if rng.gen_bool(spec.binary_fraction) {
+            summary.binary += 1;
+            let mut value = vec![0; rng.gen_range(spec.binary_size.clone())];
+            rng.fill(&mut value[..]);
+            (value, Datatype::empty())
+        } else {
+            let doc = document(&mut rng, doc_type, id);
+            (serde_json::to_vec(&doc).unwrap(), Datatype::JSON)
+        };
+        let expiry_time = if rng.gen_bool(spec.ttl_fraction) {
+            summary.with_ttl += 1;
+            now.as_secs() as u32 + rng.gen_range(spec.ttl.clone())
+        } else {
+            0
+        };
+
+        let collection = match spec.collections {
+            true => doc_type.collection_id(),
+            false => DEFAULT_COLLECTION,
+        };
+        let vbid = vbucket_for_key(key.as_bytes(), spec.num_vbuckets);
+        cas += 1;
+        let key = collections::make_key(collection, key.as_bytes());
+        writer.write(vbid, item(key, value, datatype, cas, expiry_time))?;
+        summary.documents += 1;
+    }
+
+    // Empty vbuckets are written too, so all of them have a state
+    for vbid in (0..spec.num_vbuckets).map(Vbid::new) {
+        writer.commit(vbid)?;
+    }
+    Ok(summary)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{
+        ep_bucket::EPBucket,
+        kv_store::{ScanErrorPolicy, ValueFilter},
+        warmup::Warmup,
+        Config,
+    };
+
+    fn scan(dir: &str, num_vbuckets: u16) -> Vec<Item> {
+        let store = CouchKVStore::new(CouchKVStoreConfig {
+            max_vbuckets: num_vbuckets,
+            db_name: dir.to_string(),
+            data_paths: Vec::new(),
+            max_shards: 1,
+            shard_id: 0,
+            history_retention: false,
+            max_key_size: DEFAULT_MAX_KEY_SIZE,
+            max_item_size: DEFAULT_MAX_ITEM_SIZE,
+            pitr_max_history_age: None,
+            sync_policy: couchstore::SyncPolicy::EveryCommit,
+            dsync: false,
+            btree_tuning: couchstore::BtreeTuning::default(),
+            front_coded_keys: false,
+            compaction_direct_io: false,
+        });
+        let mut items = Vec::new();
+        for vbid in (0..num_vbuckets).map(Vbid::new) {
+            let result = store.scan(
+                vbid,
+                0,
+                ValueFilter::ValuesDecompressed,
+                ScanErrorPolicy::Abort,
+                &mut |item| items.push(item),
+            );
+            assert!(result.is_complete());
+        }
+        items
+    }
+
+    #[test]
+    fn test_generate() {
+        let dirs: Vec<tempfile::TempDir> = (0..3).map(|_| tempfile::tempdir().unwrap()).collect();
+        let path = |i: usize| dirs[i].path().to_str().unwrap().to_string();
+        let spec = DatasetSpec {
+            num_vbuckets: 8,
+            documents: 500,
+            collections: true,
+            binary_fraction: 0.1,
+            ttl_fraction: 0.2,
+            seed: 42,
+            ..Default::default()
+        };
+
+        let summary = generate(&path(0), &spec).unwrap();
+        assert_eq!(summary.documents, 500);
+        assert_eq!(summary.collections, 5);
+        assert!(summary.binary > 20 && summary.binary < 80);
+        assert!(summary.with_ttl > 60 && summary.with_ttl < 140);
+        assert!(matches!(
+            generate(&path(0), &spec),
+            Err(DatasetError::DestinationNotEmpty(_))
+        ));
+
+        let items = scan(&path(0), 8);
+        assert_eq!(items.len(), 500 + 5 * 8);
+        let (events, docs): (Vec<_>, Vec<_>) = items
+            .iter()
+            .partition(|item| collections::collection_event_id(&item.key).is_some());
+        assert_eq!(events.len(), 40);
+        for item in &docs {
+            let (collection, key) = collections::split_key(&item.key).unwrap();
+            let doc_type = DocType::ALL
+                .into_iter()
+                .find(|t| t.collection_id() == collection)
+                .unwrap();
+            assert!(key.starts_with(doc_type.name().as_bytes()));
+            if item.datatype.contains(Datatype::JSON) {
+                let doc: Value = serde_json::from_slice(item.value.as_ref().unwrap()).unwrap();
+                assert_eq!(doc["type"], doc_type.name());
+            }
+        }
+        assert_eq!(
+            docs.iter().filter(|item| item.expiry_time != 0).count() as u64,
+            summary.with_ttl
+        );
+
+        // The same seed gives the same documents
+        generate(&path(1), &spec).unwrap();
+        let contents = |items: Vec<Item>| -> Vec<_> {
+            items
+                .into_iter()
+                .map(|item| (item.key, item.value, item.by_seqno))
+                .collect()
+        };
+        assert_eq!(contents(scan(&path(1), 8)), contents(items));
+
+        // A bucket warms up from the dataset
+        let config = Config {
+            max_vbuckets: 8,
+            max_shards: 1,
+            dbname: path(2),
+            ..Default::default()
+        };
+        let spec = DatasetSpec {
+            num_vbuckets: 8,
+            documents: 100,
+            ..Default::default()
+        };
+        generate(&path(2), &spec).unwrap();
+        let bucket = EPBucket::new(config.clone());
+        Warmup::new(bucket.clone(), config).warmup();
+        assert_eq!(bucket.vbucket_map.get_num_alive_vbuckets(), 8);
+        let item = scan(&path(2), 8).swap_remove(0);
+        let key = collections::split_key(&item.key).unwrap().1.to_vec();
+        let value = bucket.get(key).unwrap().value.unwrap();
+        assert!(serde_json::from_slice::<Value>(&value).is_ok());
+    }
+}
@@ -1,26 +1,116 @@
 use std::collections::HashMap;
 
-use crate::{item::Item, stored_value::StoredValue};
+use crate::{
+    collections::{self, CollectionId},
+    item::Item,
+    memory_tracker::MemoryDomain,
+    stats::EPStatsPtr,
+    stored_value::StoredValue,
+};
 
-#[derive(Debug, Default)]
+#[derive(Debug)]
 pub struct HashTable {
     pub map: HashMap<Vec<u8>, StoredValue>,
+    stats: EPStatsPtr,
+    /// Memory used by the keys, values and metadata in this table
+    mem_size: usize,
+    /// Items which aren't deleted, kept up to date as items are stored
+    num_items: usize,
+    /// The same by collection
+    collection_items: HashMap<CollectionId, usize>,
 }
 
 impl HashTable {
+    pub fn new(stats: EPStatsPtr) -> Self {
+        Self {
+            map: HashMap::new(),
+            stats,
+            mem_size: 0,
+            num_items: 0,
+            collection_items: HashMap::new(),
+        }
+    }
+
     pub fn insert_from_warmup(&mut self, item: Item) {
         if let Some(v) = self.map.get_mut(&item.key) {
             assert!(v.cas == item.cas);
             assert!(!v.is_resident());
 
+            let was_live = !v.is_deleted();
+            let old_size = v.size();
+            let key = item.key.clone();
             v.restore_value(item);
+            let is_live = !v.is_deleted();
+            let new_size = v.size();
+            self.mem_resized(old_size, new_size);
+            self.count_change(&key, was_live, is_live);
 
             return;
         }
 
+        let key = item.key.clone();
         let value = self.add_new_stored_value(item);
 
         value.mark_not_resident();
+        self.count_change(&key, false, true);
+    }
+
+    /// Load a key read from disk along with its value, for a warmup which
+    /// reads them together, so it is resident. Deleted keys load as such.
+    pub fn load_from_warmup(&mut self, item: Item) {
+        let key_len = item.key.len();
+        let existing = self.map.get(&item.key);
+        let old_size = existing.map_or(0, |v| key_len + v.size());
+        let was_live = existing.is_some_and(|v| !v.is_deleted());
+
+        let key = item.key.clone();
+        let value = self.map.entry(item.key.clone()).or_default();
+        value.restore_value(item);
+        let is_live = !value.is_deleted();
+        let new_size = key_len + value.size();
+
+        self.mem_resized(old_size, new_size);
+        self.count_change(&key, was_live, is_live);
+    }
+
+    /// Restore a value fetched from disk, unless the key has been modified
+    /// since or its value is already resident
+    pub fn restore_fetched(&mut self, item: Item) {
+        let Some(v) = self.map.get_mut(&item.key) else {
+            return;
+        };
+        if v.is_resident() || v.cas != item.cas {
+            return;
+        }
+        // Warmup's key dump can't tell deletions apart, so a key it loaded
+        // may turn out to be deleted
+        let was_live = !v.is_deleted();
+        let old_size = v.size();
+        let key = item.key.clone();
+        v.restore_value(item);
+        let is_live = !v.is_deleted();
+        let new_size = v.size();
+        self.mem_resized(old_size, new_size);
+        self.count_change(&key, was_live, is_live);
+    }
+
+    /// Insert or replace the value for the item's key, marking it dirty so
+    /// it will be persisted.
+    pub fn set(&mut self, item: Item) {
+        let key_len = item.key.len();
+        let existing = self.map.get(&item.key);
+        let old_size = existing.map_or(0, |v| key_len + v.size());
+        let was_live = existing.is_some_and(|v| !v.is_deleted());
+
+        let key = item.key.clone();
+        let value = self.map.entry(item.key.clone()).or_default();
+        value.restore_value(item);
+        value.mark_dirty();
+        let is_live = !value.is_deleted();
+        let new_size = key_len + value.size();
+
+        self.mem_resized(old_size, new_size);
+        self.count_change(&key, was_live, is_live);
     }
 
     fn add_new_stored_value(&mut self, item: Item) -> &mut StoredValue {
@@ -31,8 +121,60 @@ impl HashTable {
             expiry_time: item.expiry_time,
             flags: item.flags,
             rev_seqno: item.rev_seqno,
+            datatype: item.datatype,
+            lock_expiry: 0,
             bits: Default::default(),
         };
+        let size = item.key.len() + value.size();
+        self.mem_resized(0, size);
         self.map.entry(item.key).or_insert(value)
     }
+
+    /// Memory used by the keys, values and metadata in this table
+    pub fn mem_size(&self) -> usize {
+        self.mem_size
+    }
+
+    /// The items which aren't deleted, as counted while they were stored
+    pub fn num_items(&self) -> usize {
+        self.num_items
+    }
+
+    /// The same for each collection with any
+    pub fn collection_items(&self) -> &HashMap<CollectionId, usize> {
+        &self.collection_items
+    }
+
+    fn count_change(&mut self, key: &[u8], was_live: bool, is_live: bool) {
+        if was_live == is_live {
+            return;
+        }
+        let collection = collections::collection_id(key).unwrap_or_default();
+        let count = self.collection_items.entry(collection).or_default();
+        if is_live {
+            self.num_items += 1;
+            *count += 1;
+        } else {
+            self.num_items -= 1;
+            *count -= 1;
+            if *count == 0 {
+                self.collection_items.remove(&collection);
+            }
+        }
+    }
+
+    fn mem_resized(&mut self, old_size: usize, new_size: usize) {
+        self.mem_size = self.mem_size + new_size - old_size;
+        self.stats
+            .memory
+            .mem_resized(MemoryDomain::HashTable, old_size, new_size);
+    }
+}
+
+impl Drop for HashTable {
+    fn drop(&mut self) {
+        self.stats
+            .memory
+            .mem_deallocated(MemoryDomain::HashTable, self.mem_size);
+    }
 }
@@ -0,0 +1,159 @@
+//! A report of whether the engine's buckets are fit to serve, for
+//! readiness probes and operators. Each bucket reports the state of its
+//! warmup, disk and background tasks, and lists what is wrong with it, so
+//! a probe only has to check that nothing is.
+
+use serde::Serialize;
+use std::collections::BTreeMap;
+
+use crate::{vbucket::Vbid, warmup::WarmupPhase};
+
+/// A periodic background task
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct TaskHealth {
+    pub name: String,
+    /// Its thread is still running. A periodic task only stops when the
+    /// bucket shuts down, or if it panicked.
+    pub alive: bool,
+    /// Milliseconds since it last finished a run, None if it hasn't yet
+    pub last_run_age_ms: Option<u64>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct ShardHealth {
+    pub shard: usize,
+    /// Milliseconds since the shard's store last committed, None if it
+    /// hasn't since the bucket was opened
+    pub last_commit_age_ms: Option<u64>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct BucketHealth {
+    pub warmup: WarmupPhase,
+    pub traffic_enabled: bool,
+    pub shutting_down: bool,
+    pub read_only: bool,
+    pub quarantined_vbuckets: Vec<Vbid>,
+    pub vbuckets_persistence_failing: u64,
+    /// Free bytes on the fullest data disk, None if it couldn't be read
+    pub disk_free_bytes: Option<u64>,
+    /// How far that is above the minimum below which the bucket turns
+    /// read-only, negative once below it
+    pub disk_headroom_bytes: Option<i64>,
+    pub tasks: Vec<TaskHealth>,
+    pub shards: Vec<ShardHealth>,
+    /// What stops the bucket serving fully, empty if nothing does
+    pub problems: Vec<String>,
+}
+
+impl BucketHealth {
+    /// Fill in the problems from the rest of the report
+    pub(crate) fn diagnose(mut self) -> Self {
+        let mut problems = Vec::new();
+        if self.shutting_down {
+            problems.push("shutting down".to_string());
+        } else if self.warmup != WarmupPhase::Done || !self.traffic_enabled {
+            problems.push(format!("warming up, in phase {}", self.warmup.name()));
+        }
+        if self.read_only {
+            problems.push("read-only, the disk is nearly full".to_string());
+        }
+        if !self.quarantined_vbuckets.is_empty() {
+            let vbuckets: Vec<String> = self
+                .quarantined_vbuckets
+                .iter()
+                .map(|vbid| vbid.to_string())
+                .collect();
+            problems.push(format!("quarantined vbuckets {}", vbuckets.join(", ")));
+        }
+        if self.vbuckets_persistence_failing > 0 {
+            problems.push(format!(
+                "{} vbuckets failing to persist",
+                self.vbuckets_persistence_failing
+            ));
+        }
+        if !self.shutting_down {
+            for task in self.tasks.iter().filter(|task| !task.alive) {
+                problems.push(format!("background task {} stopped", task.name));
+            }
+        }
+        self.problems = problems;
+        self
+    }
+
+    pub fn is_healthy(&self) -> bool {
+        self.problems.is_empty()
+    }
+}
+
+/// The health of each open bucket, by name
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct EngineHealth {
+    pub buckets: BTreeMap<String, BucketHealth>,
+}
+
+impl EngineHealth {
+    pub fn is_healthy(&self) -> bool {
+        self.buckets.values().all(BucketHealth::is_healthy)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{api::Engine, Config};
+
+    #[test]
+    fn test_engine_health() {
+        let dir = tempfile::tempdir().unwrap();
+        let engine = Engine::new(Config {
+            max_vbuckets: 4,
+            max_shards: 2,
+            dbname: dir.path().to_str().unwrap().to_string(),
+            disk_min_free_bytes: 0,
+            ..Default::default()
+        });
+        assert!(engine.health().buckets.is_empty());
+        let bucket = engine.bucket("default").unwrap();
+
+        let health = engine.health();
+        assert!(health.is_healthy(), "{health:?}");
+        let report = &health.buckets["default"];
+        assert_eq!(report.warmup, WarmupPhase::Done);
+        assert!(report.disk_headroom_bytes.unwrap() >= 0);
+        let tasks: Vec<&str> = report.tasks.iter().map(|task| task.name.as_str()).collect();
+        assert_eq!(tasks, ["disk_monitor", "expiry_pager", "reconciler"]);
+        assert!(report.tasks.iter().all(|task| task.alive));
+        assert_eq!(report.shards.len(), 2);
+
+        bucket.set(b"key", b"value", 0, 0).unwrap();
+        bucket.flush();
+        let report = &engine.health().buckets["default"];
+        assert!(report
+            .shards
+            .iter()
+            .any(|shard| shard.last_commit_age_ms.is_some_and(|age| age < 10_000)));
+        let json = serde_json::to_value(report).unwrap();
+        assert_eq!(json["warmup"], "done");
+        assert_eq!(json["problems"], serde_json::json!([]));
+
+        // Each problem is listed
+        let mut broken = report.clone();
+        broken.read_only = true;
+        broken.quarantined_vbuckets = vec![Vbid::new(1), Vbid::new(3)];
+        broken.tasks[0].alive = false;
+        let broken = broken.diagnose();
+        assert!(!broken.is_healthy());
+        assert_eq!(
+            broken.problems,
+            [
+                "read-only, the disk is nearly full",
+                "quarantined vbuckets 1, 3",
+                "background task disk_monitor stopped",
+            ]
+        );
+
+        engine.shutdown();
+        assert!(engine.health().buckets.is_empty());
+    }
+}
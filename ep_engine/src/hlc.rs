@@ -0,0 +1,52 @@
+use crate::clock::ClockPtr;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// Hybrid logical clock used to generate CAS values.
+///
+/// The upper 48 bits are physical time in nanoseconds and the lower 16 bits
+/// are reserved for a logical counter, so CAS values are always increasing
+/// even when the wall clock stalls or goes backwards.
+#[derive(Debug)]
+pub struct HLC {
+    max_hlc: AtomicU64,
+    clock: ClockPtr,
+}
+
+impl HLC {
+    pub fn new(initial: u64, clock: ClockPtr) -> Self {
+        Self {
+            max_hlc: AtomicU64::new(initial),
+            clock,
+        }
+    }
+
+    pub fn next_hlc(&self) -> u64 {
+        let now = self.physical_time() & !0xFFFF;
+        let previous = self
+            .max_hlc
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |max| {
+                Some(now.max(max + 1))
+            })
+            .unwrap();
+        now.max(previous + 1)
+    }
+
+    /// Move the clock forward if a peer has seen a later time
+    pub fn set_max_hlc(&self, hlc: u64) {
+        self.max_hlc.fetch_max(hlc, Ordering::SeqCst);
+    }
+
+    pub fn max_hlc(&self) -> u64 {
+        self.max_hlc.load(Ordering::SeqCst)
+    }
+
+    /// The current time in seconds since the epoch, never earlier than a
+    /// CAS the clock has already handed out
+    pub fn now_secs(&self) -> u32 {
+        (self.physical_time().max(self.max_hlc()) / 1_000_000_000) as u32
+    }
+
+    fn physical_time(&self) -> u64 {
+        self.clock.now().as_nanos() as u64
+    }
+}
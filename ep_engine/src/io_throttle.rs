@@ -0,0 +1,154 @@
+use parking_lot::Mutex;
+use std::{
+    sync::atomic::{AtomicU64, Ordering},
+    time::{Duration, Instant},
+};
+
+/// Limits a rate to the configured units per second, allowing a burst of up
+/// to one second's worth. A request larger than the available tokens is
+/// admitted and the debt delays the following requests.
+#[derive(Debug)]
+struct TokenBucket {
+    /// Units per second, 0 for unlimited
+    rate: AtomicU64,
+    state: Mutex<TokenBucketState>,
+}
+
+#[derive(Debug)]
+struct TokenBucketState {
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl TokenBucket {
+    fn new(rate: u64, now: Instant) -> Self {
+        Self {
+            rate: AtomicU64::new(rate),
+            state: Mutex::new(TokenBucketState {
+                tokens: rate as f64,
+                last_refill: now,
+            }),
+        }
+    }
+
+    fn set_rate(&self, rate: u64) {
+        self.rate.store(rate, Ordering::Relaxed);
+    }
+
+    fn rate(&self) -> u64 {
+        self.rate.load(Ordering::Relaxed)
+    }
+
+    /// Take the units, returning how long the caller must wait before using
+    /// them
+    fn acquire(&self, units: u64, now: Instant) -> Duration {
+        let rate = self.rate();
+        if rate == 0 {
+            return Duration::ZERO;
+        }
+        let rate = rate as f64;
+        let mut state = self.state.lock();
+        let elapsed = now.saturating_duration_since(state.last_refill);
+        state.tokens = (state.tokens + elapsed.as_secs_f64() * rate).min(rate);
+        state.last_refill = now.max(state.last_refill);
+        state.tokens -= units as f64;
+        if state.tokens >= 0.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64(-state.tokens / rate)
+        }
+    }
+}
+
+/// Rate limits the disk IO of background tasks, such as backfills and
+/// warmup, so they don't starve front-end operations. Shared by all the
+/// tasks of a bucket and adjustable at runtime.
+#[derive(Debug)]
+pub struct IOThrottle {
+    bytes: TokenBucket,
+    ops: TokenBucket,
+    /// Total time callers have been delayed, in microseconds
+    total_wait_us: AtomicU64,
+}
+
+impl IOThrottle {
+    /// A limit of 0 disables that limit
+    pub fn new(bytes_per_sec: u64, ops_per_sec: u64) -> Self {
+        let now = Instant::now();
+        Self {
+            bytes: TokenBucket::new(bytes_per_sec, now),
+            ops: TokenBucket::new(ops_per_sec, now),
+            total_wait_us: AtomicU64::new(0),
+        }
+    }
+
+    pub fn set_bytes_per_sec(&self, rate: u64) {
+        self.bytes.set_rate(rate);
+    }
+
+    pub fn set_ops_per_sec(&self, rate: u64) {
+        self.ops.set_rate(rate);
+    }
+
+    /// Account for one operation transferring the given number of bytes,
+    /// blocking until it is within the limits
+    pub fn acquire(&self, bytes: usize) {
+        let delay = self.acquire_at(bytes, Instant::now());
+        if !delay.is_zero() {
+            std::thread::sleep(delay);
+        }
+    }
+
+    /// How long the operation must be delayed by to stay within the limits
+    fn acquire_at(&self, bytes: usize, now: Instant) -> Duration {
+        let delay = self
+            .bytes
+            .acquire(bytes as u64, now)
+            .max(self.ops.acquire(1, now));
+        self.total_wait_us
+            .fetch_add(delay.as_micros() as u64, Ordering::Relaxed);
+        delay
+    }
+
+    pub fn add_stats(&self, add_stat: &mut dyn FnMut(&str, &str)) {
+        add_stat(
+            "ep_io_throttle_bytes_per_sec",
+            &self.bytes.rate().to_string(),
+        );
+        add_stat("ep_io_throttle_ops_per_sec", &self.ops.rate().to_string());
+        add_stat(
+            "ep_io_throttle_wait_us",
+            &self.total_wait_us.load(Ordering::Relaxed).to_string(),
+        );
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_io_throttle() {
+        let throttle = IOThrottle::new(0, 0);
+        let now = Instant::now();
+        assert_eq!(throttle.acquire_at(1 << 30, now), Duration::ZERO);
+
+        // The first second's worth is a burst, then the ops are spaced out
+        throttle.set_ops_per_sec(10);
+        let now = now + Duration::from_secs(1);
+        for _ in 0..10 {
+            assert_eq!(throttle.acquire_at(0, now), Duration::ZERO);
+        }
+        assert_eq!(throttle.acquire_at(0, now), Duration::from_millis(100));
+        let now = now + Duration::from_secs(1);
+        assert_eq!(throttle.acquire_at(0, now), Duration::ZERO);
+
+        // A large read is admitted but delays what follows
+        throttle.set_ops_per_sec(0);
+        throttle.set_bytes_per_sec(1000);
+        let now = now + Duration::from_secs(1);
+        assert_eq!(throttle.acquire_at(3000, now), Duration::from_secs(2));
+        assert_eq!(throttle.acquire_at(1000, now), Duration::from_secs(3));
+        assert!(throttle.total_wait_us.load(Ordering::Relaxed) > 0);
+    }
+}
@@ -1,9 +1,94 @@
+use bitflags::bitflags;
+
+use crate::compression;
+
+/// Expiry times up to this many seconds are relative to the time of the
+/// write, later ones are absolute unix times, as in memcached
+pub const MAX_RELATIVE_EXPIRY: u32 = 30 * 24 * 60 * 60;
+
+/// Default limit on the length of a client's key, without its collection
+pub const DEFAULT_MAX_KEY_SIZE: usize = 250;
+
+/// Default limit on the size of an item's value
+pub const DEFAULT_MAX_ITEM_SIZE: usize = 20 * 1024 * 1024;
+
+bitflags! {
+    /// How the value is encoded, with the bits of the memcached protocol
+    #[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Hash)]
+    pub struct Datatype: u8 {
+        const JSON = 0x01;
+        const SNAPPY = 0x02;
+        const XATTR = 0x04;
+    }
+}
+
+#[derive(Debug, Clone)]
 pub struct Item {
     pub key: Vec<u8>,
     pub value: Option<Vec<u8>>,
     pub cas: u64,
+    /// For deleted items this is the time of the deletion
     pub expiry_time: u32,
     pub flags: u32,
     pub by_seqno: u64,
     pub rev_seqno: u64,
+    /// Why the item was deleted, only meaningful for deleted items
+    pub delete_source: DeleteSource,
+    pub datatype: Datatype,
+}
+
+impl Item {
+    /// Decompress the value if it is snappy compressed. Values are checked
+    /// when they're written, so they always decompress.
+    pub fn inflate(&mut self) {
+        compression::inflate_in_place(&mut self.value, &mut self.datatype);
+    }
+}
+
+/// The absolute expiry time for an expiry time given by a client, where 0
+/// means the item never expires
+pub fn to_absolute_expiry(expiry_time: u32, now: u32) -> u32 {
+    if expiry_time == 0 || expiry_time > MAX_RELATIVE_EXPIRY {
+        expiry_time
+    } else {
+        now.saturating_add(expiry_time)
+    }
+}
+
+/// Has an absolute expiry time passed
+pub fn is_expired(expiry_time: u32, now: u32) -> bool {
+    expiry_time != 0 && expiry_time <= now
+}
+
+/// What caused an item to be deleted
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum DeleteSource {
+    /// Deleted by a client
+    #[default]
+    Explicit,
+    /// The item's expiry time passed
+    Ttl,
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_to_absolute_expiry() {
+        let now = 1_700_000_000;
+        assert_eq!(to_absolute_expiry(0, now), 0);
+        assert_eq!(to_absolute_expiry(10, now), now + 10);
+        assert_eq!(
+            to_absolute_expiry(MAX_RELATIVE_EXPIRY, now),
+            now + MAX_RELATIVE_EXPIRY
+        );
+        assert_eq!(
+            to_absolute_expiry(MAX_RELATIVE_EXPIRY + 1, now),
+            MAX_RELATIVE_EXPIRY + 1
+        );
+        assert!(!is_expired(0, now));
+        assert!(is_expired(now, now));
+        assert!(!is_expired(now + 1, now));
+    }
 }
@@ -0,0 +1,23 @@
+//! Transforming document keys at the engine boundary, for deployments which
+//! mustn't store the keys clients use, for example by encrypting them. The
+//! front-end operations transform the keys they are given, and everything
+//! behind them (the hash table, checkpoints, DCP and the vbucket files)
+//! only sees the transformed keys. Key scans reverse the transform.
+//!
+//! The vbucket is still chosen by hashing the client's key, as clients do,
+//! and the collection prefix is kept in front of the transformed key, so
+//! the stored keys stay ordered by collection.
+
+use std::{fmt::Debug, sync::Arc};
+
+pub trait KeyTransform: Send + Sync + Debug {
+    /// The key as stored. It must be deterministic, so a key is found again,
+    /// and injective, so no two keys are stored under the same one.
+    fn encode(&self, key: &[u8]) -> Vec<u8>;
+
+    /// The client's key from a stored one, or None if it wasn't written
+    /// through this transform
+    fn decode(&self, stored: &[u8]) -> Option<Vec<u8>>;
+}
+
+pub type KeyTransformPtr = Arc<dyn KeyTransform>;
@@ -1,16 +1,20 @@
+#[cfg(feature = "rocksdb")]
+use crate::rocksdb_kv_store::RocksDBKVStore;
 use crate::{
-    kv_store::{CouchKVStore, CouchKVStoreConfig},
+    kv_store::{Backend, CouchKVStore, CouchKVStoreConfig, KVStore},
+    memory_kv_store::MemoryKVStore,
+    nexus_kv_store::NexusKVStore,
     vbucket::{VBucketPtr, Vbid},
     Config,
 };
 use parking_lot::{Mutex, MutexGuard};
-use std::sync::Arc;
+use std::{path::Path, sync::Arc};
 
 #[derive(Debug)]
 pub struct KVShard {
     config: CouchKVStoreConfig,
     vbuckets: Vec<Mutex<Option<VBucketPtr>>>,
-    store: CouchKVStore,
+    store: Box<dyn KVStore>,
 }
 
 impl KVShard {
@@ -19,12 +23,38 @@ impl KVShard {
             max_vbuckets: config.max_vbuckets,
             max_shards: num_shards,
             db_name: config.dbname.clone(),
+            data_paths: config.data_paths.clone(),
             shard_id,
+            history_retention: config.history_retention,
+            max_key_size: config.max_key_size,
+            max_item_size: config.max_item_size,
+            pitr_max_history_age: config.pitr_enabled.then_some(config.pitr_max_history_age),
+            sync_policy: config.sync_policy,
+            dsync: config.dsync,
+            btree_tuning: config.btree_tuning,
+            front_coded_keys: config.front_coded_keys,
+            compaction_direct_io: config.compaction_direct_io,
         };
         let num_vbuckets = (config.max_vbuckets as f64 / config.max_shards as f64).ceil() as usize;
         let mut vbuckets = Vec::with_capacity(num_vbuckets);
         vbuckets.resize_with(num_vbuckets, Default::default);
-        let store = CouchKVStore::new(kv_config.clone());
+        let mut store = make_store(config.backend, kv_config.clone());
+        if let Some(backend) = config.nexus_secondary_backend {
+            let db_name = Path::new(&config.dbname)
+                .join("nexus")
+                .to_string_lossy()
+                .into_owned();
+            std::fs::create_dir_all(&db_name).unwrap();
+            let secondary = make_store(
+                backend,
+                CouchKVStoreConfig {
+                    db_name,
+                    data_paths: Vec::new(),
+                    ..kv_config.clone()
+                },
+            );
+            store = Box::new(NexusKVStore::new(store, secondary));
+        }
         KVShard {
             config: kv_config,
             vbuckets,
@@ -59,15 +89,23 @@ impl KVShard {
         self.get_locked_bucket(vb.id).replace(vb);
     }
 
-    fn get_locked_bucket(&self, id: Vbid) -> MutexGuard<Option<VBucketPtr>> {
-        assert_eq!(u16::from(id) % self.config.max_shards, self.config.shard_id);
-        let idx = (u16::from(id) / self.config.max_shards) as usize;
-        let bucket = &self.vbuckets[idx];
+    fn get_locked_bucket(&self, id: Vbid) -> MutexGuard<'_, Option<VBucketPtr>> {
+        assert_eq!(id.shard(self.config.max_shards), self.config.shard_id);
+        let bucket = &self.vbuckets[id.index_in_shard(self.config.max_shards)];
         bucket.lock()
     }
 
-    pub fn store(&self) -> &CouchKVStore {
-        &self.store
+    pub fn store(&self) -> &dyn KVStore {
+        &*self.store
+    }
+}
+
+fn make_store(backend: Backend, config: CouchKVStoreConfig) -> Box<dyn KVStore> {
+    match backend {
+        Backend::Couchstore => Box::new(CouchKVStore::new(config)),
+        Backend::Memory => Box::new(MemoryKVStore::new(config)),
+        #[cfg(feature = "rocksdb")]
+        Backend::RocksDB => Box::new(RocksDBKVStore::new(config)),
     }
 }
 
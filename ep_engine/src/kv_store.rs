@@ -1,48 +1,682 @@
-use crate::vbucket::{VBucketState, Vbid};
-use byteorder::{BigEndian, LittleEndian, ReadBytesExt};
-use parking_lot::RwLock;
+use crate::{
+    checkpoint_manager::QueuedItem,
+    collections,
+    item::{Datatype, DeleteSource, Item},
+    vbucket::{State, VBucketState, Vbid},
+};
+use byteorder::{BigEndian, LittleEndian, ReadBytesExt, WriteBytesExt};
+use parking_lot::{Mutex, RwLock};
 use std::{
     cmp::Ordering,
-    collections::{HashMap, HashSet},
-    io,
-    sync::Arc,
+    collections::{BTreeMap, HashMap, HashSet, VecDeque},
+    io::{self, Read},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{self, AtomicU64},
+        Arc,
+    },
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
 };
+use thiserror::Error;
 
 #[derive(Debug, Clone)]
 pub struct CouchKVStoreConfig {
     pub max_vbuckets: u16,
     pub db_name: String,
+    /// More directories to spread the shards' files over, each shard's
+    /// going in one of db_name and these
+    pub data_paths: Vec<String>,
     pub max_shards: u16,
     pub shard_id: u16,
+    /// Keep every version of each key in the by-seq index
+    pub history_retention: bool,
+    /// Longest key that may be saved, not counting its collection prefix
+    pub max_key_size: usize,
+    /// Largest (uncompressed) value that may be saved
+    pub max_item_size: usize,
+    /// Headers committed within this many seconds are retained for point in
+    /// time recovery, None to retain only the latest
+    pub pitr_max_history_age: Option<u64>,
+    pub sync_policy: couchstore::SyncPolicy,
+    /// Open files with O_DSYNC
+    pub dsync: bool,
+    pub btree_tuning: couchstore::BtreeTuning,
+    /// Write files at disk version 14, with front-coded keys
+    pub front_coded_keys: bool,
+    /// Write compacted files with O_DIRECT
+    pub compaction_direct_io: bool,
+}
+
+/// The version couchstore files are written at, 14 if their keys are
+/// front-coded
+pub fn couchstore_disk_version(front_coded_keys: bool) -> couchstore::DiskVersion {
+    if front_coded_keys {
+        couchstore::DiskVersion::Fourteen
+    } else {
+        couchstore::DiskVersion::default()
+    }
 }
 
 impl CouchKVStoreConfig {
     fn get_cache_size(&self) -> usize {
         (self.max_vbuckets as f64 / self.max_shards as f64).ceil() as usize
     }
+
+    /// The version files are written at
+    pub fn disk_version(&self) -> couchstore::DiskVersion {
+        couchstore_disk_version(self.front_coded_keys)
+    }
+
+    /// Directory holding this shard's files
+    pub fn data_path(&self) -> &str {
+        shard_data_path(&self.db_name, &self.data_paths, self.shard_id)
+    }
+}
+
+/// Directory the shard's files go in. The shards are dealt out over the
+/// directories in turn, starting with db_name.
+pub fn shard_data_path<'a>(db_name: &'a str, data_paths: &'a [String], shard_id: u16) -> &'a str {
+    match shard_id as usize % (data_paths.len() + 1) {
+        0 => db_name,
+        i => &data_paths[i - 1],
+    }
+}
+
+/// A shard whose files belong in a different directory after the data paths
+/// change
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ShardMove {
+    pub shard_id: u16,
+    pub from: String,
+    pub to: String,
+}
+
+/// The shards whose files must be moved, with the bucket stopped, before
+/// it's restarted with the new data paths. Until they are, a shard doesn't
+/// see the vbucket files left in its old directory.
+pub fn plan_data_paths_change(
+    db_name: &str,
+    max_shards: u16,
+    old_paths: &[String],
+    new_paths: &[String],
+) -> Vec<ShardMove> {
+    (0..max_shards)
+        .filter_map(|shard_id| {
+            let from = shard_data_path(db_name, old_paths, shard_id);
+            let to = shard_data_path(db_name, new_paths, shard_id);
+            (from != to).then(|| ShardMove {
+                shard_id,
+                from: from.to_string(),
+                to: to.to_string(),
+            })
+        })
+        .collect()
+}
+
+/// Which storage engine a bucket's data is kept in
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Backend {
+    #[default]
+    Couchstore,
+    /// Nothing is persisted, for ephemeral buckets
+    Memory,
+    #[cfg(feature = "rocksdb")]
+    RocksDB,
+}
+
+impl Backend {
+    pub fn name(self) -> &'static str {
+        match self {
+            Backend::Couchstore => "couchstore",
+            Backend::Memory => "memory",
+            #[cfg(feature = "rocksdb")]
+            Backend::RocksDB => "rocksdb",
+        }
+    }
 }
 
 type RevisionMap = RwLock<Vec<u64>>;
 
+/// The latest persisted state of each of a shard's vbuckets, by cache slot.
+/// Each slot has its own lock, so reading a vbucket's state (for stats or
+/// routing) never waits behind a commit of another's.
+#[derive(Debug, Default)]
+pub(crate) struct VBStateCache {
+    slots: Vec<RwLock<Option<VBucketState>>>,
+}
+
+impl VBStateCache {
+    pub(crate) fn new(size: usize) -> Self {
+        std::iter::repeat_with(|| None).take(size).collect()
+    }
+
+    pub(crate) fn get(&self, slot: usize) -> Option<VBucketState> {
+        self.slots[slot].read().clone()
+    }
+
+    pub(crate) fn set(&self, slot: usize, vb_state: Option<VBucketState>) {
+        *self.slots[slot].write() = vb_state;
+    }
+
+    /// Every slot's state. They are read one at a time, so a commit made
+    /// meanwhile may or may not be seen.
+    pub(crate) fn snapshot(&self) -> Vec<Option<VBucketState>> {
+        self.slots.iter().map(|slot| slot.read().clone()).collect()
+    }
+}
+
+impl FromIterator<Option<VBucketState>> for VBStateCache {
+    fn from_iter<I: IntoIterator<Item = Option<VBucketState>>>(iter: I) -> Self {
+        Self {
+            slots: iter.into_iter().map(RwLock::new).collect(),
+        }
+    }
+}
+
+/// How many of each vbucket's latest commits KVStoreStats keeps
+pub const RECENT_HEADERS: usize = 10;
+
+/// How much of a vbucket file a sequential scan reads at a time
+const SEQUENTIAL_SCAN_READ_SIZE: usize = 4 * 1024 * 1024;
+
+/// A vbucket's commit, as kept for the headers stat group
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RecentHeader {
+    /// Revision of the vbucket's file the header was written to
+    pub file_revision: u64,
+    /// Where the header is in the file
+    pub position: u64,
+    /// The highest seqno persisted by the commit
+    pub high_seqno: u64,
+    /// When the commit was made, in nanoseconds since the epoch
+    pub timestamp: u64,
+}
+
+/// Disk activity of a store, which is one shard's worth of vbuckets
+#[derive(Debug, Default)]
+pub struct KVStoreStats {
+    pub commits: AtomicU64,
+    /// Total time spent in commits, in microseconds
+    pub commit_time_us: AtomicU64,
+    pub bytes_written: AtomicU64,
+    pub fsyncs: AtomicU64,
+    pub open_failures: AtomicU64,
+    /// Files rewritten at the configured disk version by compaction
+    pub files_upgraded: AtomicU64,
+    pub compactions: AtomicU64,
+    /// Total time spent in compactions, in microseconds
+    pub compaction_duration_us: AtomicU64,
+    /// Average uncompressed sizes of the B-tree nodes in the file written
+    /// by the latest compaction, to see the shape the tuning gives
+    pub compaction_avg_kv_node_size: AtomicU64,
+    pub compaction_avg_kp_node_size: AtomicU64,
+    /// Bytes compaction wrote with O_DIRECT and through the page cache
+    pub compaction_direct_bytes: AtomicU64,
+    pub compaction_buffered_bytes: AtomicU64,
+    /// Compactions which asked for O_DIRECT but wrote through the page
+    /// cache, as the filesystem refused it
+    pub compaction_direct_io_fallbacks: AtomicU64,
+    /// Reads scans made of the vbucket files, and the bytes they read
+    pub scan_reads: AtomicU64,
+    pub scan_bytes_read: AtomicU64,
+    /// The disk version of each vbucket's file when it was last opened
+    disk_versions: Mutex<HashMap<Vbid, couchstore::DiskVersion>>,
+    /// Each vbucket's latest commits, oldest first
+    recent_headers: Mutex<HashMap<Vbid, VecDeque<RecentHeader>>>,
+    /// When the latest commit finished
+    last_commit: Mutex<Option<Instant>>,
+}
+
+impl KVStoreStats {
+    pub(crate) fn record_commit(&self, start: Instant, bytes_written: u64, fsyncs: u64) {
+        self.commits.fetch_add(1, atomic::Ordering::Relaxed);
+        self.commit_time_us.fetch_add(
+            start.elapsed().as_micros() as u64,
+            atomic::Ordering::Relaxed,
+        );
+        self.bytes_written
+            .fetch_add(bytes_written, atomic::Ordering::Relaxed);
+        self.fsyncs.fetch_add(fsyncs, atomic::Ordering::Relaxed);
+        *self.last_commit.lock() = Some(Instant::now());
+    }
+
+    /// How long ago the latest commit finished, None if there hasn't been
+    /// one since the store was opened
+    pub fn last_commit_age(&self) -> Option<Duration> {
+        self.last_commit.lock().map(|at| at.elapsed())
+    }
+
+    pub(crate) fn record_header(&self, vbid: Vbid, header: RecentHeader) {
+        let mut recent_headers = self.recent_headers.lock();
+        let headers = recent_headers.entry(vbid).or_default();
+        if headers.len() == RECENT_HEADERS {
+            headers.pop_front();
+        }
+        headers.push_back(header);
+    }
+
+    /// The vbucket's latest commits since the store was opened, newest
+    /// first. Unlike retained headers, these may have been compacted away.
+    pub fn recent_headers(&self, vbid: Vbid) -> Vec<RecentHeader> {
+        self.recent_headers
+            .lock()
+            .get(&vbid)
+            .map_or_else(Vec::new, |headers| headers.iter().rev().copied().collect())
+    }
+
+    pub(crate) fn record_compaction(&self, start: Instant) {
+        self.compactions.fetch_add(1, atomic::Ordering::Relaxed);
+        self.compaction_duration_us.fetch_add(
+            start.elapsed().as_micros() as u64,
+            atomic::Ordering::Relaxed,
+        );
+    }
+
+    pub(crate) fn record_compaction_nodes(&self, node_stats: couchstore::NodeStats) {
+        self.compaction_avg_kv_node_size
+            .store(node_stats.avg_kv_node_size(), atomic::Ordering::Relaxed);
+        self.compaction_avg_kp_node_size
+            .store(node_stats.avg_kp_node_size(), atomic::Ordering::Relaxed);
+    }
+
+    pub(crate) fn record_compaction_writes(&self, file_stats: couchstore::FileStats) {
+        self.compaction_direct_bytes
+            .fetch_add(file_stats.direct_bytes_written, atomic::Ordering::Relaxed);
+        self.compaction_buffered_bytes.fetch_add(
+            file_stats.bytes_written - file_stats.direct_bytes_written,
+            atomic::Ordering::Relaxed,
+        );
+        if file_stats.direct_io_fallback {
+            self.compaction_direct_io_fallbacks
+                .fetch_add(1, atomic::Ordering::Relaxed);
+        }
+    }
+
+    pub(crate) fn record_scan_reads(&self, file_stats: couchstore::FileStats) {
+        self.scan_reads
+            .fetch_add(file_stats.reads, atomic::Ordering::Relaxed);
+        self.scan_bytes_read
+            .fetch_add(file_stats.bytes_read, atomic::Ordering::Relaxed);
+    }
+
+    pub(crate) fn record_disk_version(&self, vbid: Vbid, version: couchstore::DiskVersion) {
+        self.disk_versions.lock().insert(vbid, version);
+    }
+
+    /// How many of the vbuckets' files are at each disk version, to follow
+    /// an upgrade's progress. Every version is included, even without files.
+    pub fn files_by_disk_version(&self) -> BTreeMap<u8, u64> {
+        let mut counts: BTreeMap<u8, u64> = couchstore::DiskVersion::ALL
+            .into_iter()
+            .map(|version| (version.into(), 0))
+            .collect();
+        for &version in self.disk_versions.lock().values() {
+            *counts.entry(version.into()).or_default() += 1;
+        }
+        counts
+    }
+
+    /// The stats, named as in the kvstore stat group without the shard
+    /// prefix
+    pub fn add_stats(&self, add_stat: &mut dyn FnMut(&str, &str)) {
+        let load = |stat: &AtomicU64| stat.load(atomic::Ordering::Relaxed).to_string();
+        add_stat("commits", &load(&self.commits));
+        add_stat("commit_time_us", &load(&self.commit_time_us));
+        add_stat("io_total_write_bytes", &load(&self.bytes_written));
+        add_stat("fsyncs", &load(&self.fsyncs));
+        add_stat("failure_open", &load(&self.open_failures));
+        add_stat("files_upgraded", &load(&self.files_upgraded));
+        add_stat("compactions", &load(&self.compactions));
+        add_stat(
+            "compaction_duration_us",
+            &load(&self.compaction_duration_us),
+        );
+        add_stat(
+            "compaction_avg_kv_node_size",
+            &load(&self.compaction_avg_kv_node_size),
+        );
+        add_stat(
+            "compaction_avg_kp_node_size",
+            &load(&self.compaction_avg_kp_node_size),
+        );
+        add_stat(
+            "compaction_direct_bytes",
+            &load(&self.compaction_direct_bytes),
+        );
+        add_stat(
+            "compaction_buffered_bytes",
+            &load(&self.compaction_buffered_bytes),
+        );
+        add_stat(
+            "compaction_direct_io_fallbacks",
+            &load(&self.compaction_direct_io_fallbacks),
+        );
+        add_stat("scan_reads", &load(&self.scan_reads));
+        add_stat("scan_bytes_read", &load(&self.scan_bytes_read));
+        for (version, files) in self.files_by_disk_version() {
+            add_stat(&format!("files_disk_version_{version}"), &files.to_string());
+        }
+    }
+}
+
+/// What a storage backend is capable of, so the engine can adapt to it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StorageProperties {
+    /// Whether older versions of keys can be read back, which point in time
+    /// recovery and history streams need
+    pub historical_snapshots: bool,
+    /// Whether documents can be scanned in key order as well as seqno order
+    pub by_id_scan: bool,
+    /// Whether the store can be compacted while it is being written to
+    pub concurrent_write_compaction: bool,
+    /// Whether the store keeps only the latest of several mutations of a
+    /// key in one commit. If not the flusher has to remove the older ones.
+    pub automatic_deduplication: bool,
+}
+
+/// Why a commit failed. None of the batch is persisted, so it can be
+/// committed again.
+#[derive(Error, Debug)]
+pub enum CommitError {
+    #[error(transparent)]
+    Couchstore(#[from] couchstore::CouchstoreError),
+    #[cfg(feature = "rocksdb")]
+    #[error(transparent)]
+    RocksDB(#[from] rocksdb::Error),
+    #[error(transparent)]
+    Io(#[from] io::Error),
+}
+
+/// Storage for one shard's worth of vbuckets
+pub trait KVStore: std::fmt::Debug + Send + Sync {
+    /// Persist a batch of items for the vbucket along with its new state.
+    /// The items must be in seqno order.
+    fn commit(
+        &self,
+        vbid: Vbid,
+        items: &[QueuedItem],
+        vb_state: &VBucketState,
+    ) -> Result<(), CommitError>;
+
+    /// Persist the vbucket's state without any items
+    fn snapshot_vbucket(&self, vbid: Vbid, vb_state: &VBucketState) -> Result<(), CommitError>;
+
+    fn get_cached_vb_state(&self, vbid: Vbid) -> Option<VBucketState>;
+
+    /// Re-read the vbucket's persisted state, for when something other than
+    /// the store, such as an external tool, changed its files. The cached
+    /// state is replaced with it, or dropped if the vbucket has no files.
+    /// Stores whose data only they write have nothing to re-read. As with
+    /// commit, the caller must not flush the vbucket at the same time.
+    fn refresh_vbucket_state(&self, vbid: Vbid) -> Result<Option<VBucketState>, String> {
+        Ok(self.get_cached_vb_state(vbid))
+    }
+
+    /// The persisted state of each of the shard's vbuckets, in vbid order
+    fn list_persisted_vbuckets(&self) -> Vec<Option<VBucketState>>;
+
+    /// Read the persisted items with a seqno of at least start_seqno, in
+    /// seqno order. Deleted items have no value, nor does any item when only
+    /// keys are requested. on_error says what to do about a corrupt item.
+    fn scan(
+        &self,
+        vbid: Vbid,
+        start_seqno: u64,
+        value_filter: ValueFilter,
+        on_error: ScanErrorPolicy,
+        callback: &mut dyn FnMut(Item),
+    ) -> ScanResult;
+
+    /// As scan, but stopping once the callback returns false. Stores which
+    /// can't stop part way read on to the end, without returning the rest.
+    fn scan_while(
+        &self,
+        vbid: Vbid,
+        start_seqno: u64,
+        value_filter: ValueFilter,
+        on_error: ScanErrorPolicy,
+        callback: &mut dyn FnMut(Item) -> bool,
+    ) -> ScanResult {
+        let mut stopped = false;
+        self.scan(vbid, start_seqno, value_filter, on_error, &mut |item| {
+            if !stopped {
+                stopped = !callback(item);
+            }
+        })
+    }
+
+    /// As scan, for a scan of most of the vbucket, which stores that can
+    /// read its file in large sequential chunks do rather than reading each
+    /// item on its own. Others just scan.
+    fn scan_sequential(
+        &self,
+        vbid: Vbid,
+        start_seqno: u64,
+        value_filter: ValueFilter,
+        on_error: ScanErrorPolicy,
+        callback: &mut dyn FnMut(Item),
+    ) -> ScanResult {
+        self.scan(vbid, start_seqno, value_filter, on_error, callback)
+    }
+
+    /// As scan, but with every version of each key kept by history
+    /// retention. Stores without history only have the latest versions.
+    fn scan_all_versions(
+        &self,
+        vbid: Vbid,
+        start_seqno: u64,
+        value_filter: ValueFilter,
+        on_error: ScanErrorPolicy,
+        callback: &mut dyn FnMut(Item),
+    ) -> ScanResult {
+        self.scan(vbid, start_seqno, value_filter, on_error, callback)
+    }
+
+    /// Read the persisted version of each of the keys in one pass, with
+    /// values decompressed. Keys which were never persisted are None, and
+    /// deleted keys have no value.
+    fn get_multi(&self, vbid: Vbid, keys: &[Vec<u8>]) -> io::Result<Vec<Option<Item>>>;
+
+    /// The vbucket's named backup cursors, each the last seqno the backup
+    /// of that name has copied
+    fn get_backup_cursors(&self, vbid: Vbid) -> io::Result<BTreeMap<String, u64>>;
+
+    /// Replace the vbucket's backup cursors. As with commit, the caller
+    /// must not flush the vbucket at the same time.
+    fn set_backup_cursors(&self, vbid: Vbid, cursors: &BTreeMap<String, u64>) -> io::Result<()>;
+
+    /// Remove the vbucket's deletions made by purge_before (seconds
+    /// since the epoch) with a seqno of at most max_seqno, raising its purge
+    /// seqno. As with commit, the caller must not flush the vbucket at the
+    /// same time.
+    fn purge_tombstones(
+        &self,
+        vbid: Vbid,
+        purge_before: u32,
+        max_seqno: u64,
+    ) -> io::Result<PurgeResult>;
+
+    /// Rewrite the vbucket's file without the space taken by superseded
+    /// versions and purged tombstones, returning how many bytes that
+    /// reclaimed. Stores which reclaim space as they go have nothing to do.
+    /// As with commit, the caller must not flush the vbucket at the same
+    /// time. With point in time recovery, files with earlier headers still
+    /// within the retention window aren't compacted, as that would lose them.
+    fn compact(&self, _vbid: Vbid) -> u64 {
+        0
+    }
+
+    /// The keys whose persisted live versions expire at or before now (in
+    /// seconds since the epoch), soonest first. The stores keep an index by
+    /// expiry time up to date in commit, so this doesn't read every item.
+    fn get_expired_keys(&self, vbid: Vbid, now: u32) -> io::Result<Vec<Vec<u8>>>;
+
+    /// Up to limit keys of the vbucket's live persisted documents, from
+    /// start_key up to, not including, end_key (or the last, if None) in key
+    /// order. Only the by-id index is read, not the values.
+    fn list_keys(
+        &self,
+        vbid: Vbid,
+        start_key: &[u8],
+        end_key: Option<&[u8]>,
+        limit: usize,
+    ) -> io::Result<Vec<Vec<u8>>>;
+
+    /// Read back everything the vbucket has persisted to check it for
+    /// corruption, calling on_read with the size of each chunk read so the
+    /// caller can limit the bandwidth. Stores without files of their own
+    /// have nothing to scrub.
+    fn scrub(&self, _vbid: Vbid, _on_read: &mut dyn FnMut(usize)) -> ScrubResult {
+        ScrubResult::default()
+    }
+
+    /// Discard the vbucket's commits after the latest one which scrubs
+    /// cleanly, so a corrupt file is usable again. None if the store can't,
+    /// or no commit is clean. As with commit, the caller must not flush the
+    /// vbucket at the same time.
+    fn truncate_to_clean_commit(&self, _vbid: Vbid) -> Option<TruncatedCommits> {
+        None
+    }
+
+    /// Sync the commits which the sync policy has left unsynced, for when
+    /// the policy's interval passes without a commit or the bucket shuts
+    /// down. Stores which sync every commit have nothing to do.
+    fn sync_pending_commits(&self) {}
+
+    /// The vbucket's commits which can still be read, newest first: the
+    /// latest and, with point in time recovery, every earlier one within
+    /// the retention window. Stores without headers have none.
+    fn list_retained_headers(&self, _vbid: Vbid) -> io::Result<Vec<RetainedHeader>> {
+        Ok(Vec::new())
+    }
+
+    /// The counts of the vbucket's persisted documents, as the store keeps
+    /// them rather than by reading the documents. None if the store doesn't
+    /// keep them, or can't for the vbucket's file as it stands.
+    fn get_doc_counts(&self, _vbid: Vbid) -> Option<couchstore::DocCounts> {
+        None
+    }
+
+    fn get_storage_properties(&self) -> StorageProperties;
+
+    fn get_stats(&self) -> &KVStoreStats;
+}
+
+/// Tombstones removed by purge_tombstones, and those old enough to go but
+/// kept for having a seqno above the limit
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct PurgeResult {
+    pub purged: u64,
+    pub retained: u64,
+    /// The tombstones left on disk, retained or too young to purge
+    pub remaining: u64,
+    /// Their size on disk, as the store measures it
+    pub remaining_bytes: u64,
+}
+
+impl PurgeResult {
+    /// Count a tombstone taking size bytes on disk, returning whether it is
+    /// to be purged
+    pub fn check(
+        &mut self,
+        seqno: u64,
+        deleted_at: u32,
+        size: u64,
+        purge_before: u32,
+        max_seqno: u64,
+    ) -> bool {
+        if deleted_at <= purge_before && seqno <= max_seqno {
+            self.purged += 1;
+            return true;
+        }
+        if deleted_at <= purge_before {
+            self.retained += 1;
+        }
+        self.remaining += 1;
+        self.remaining_bytes += size;
+        false
+    }
+}
+
+/// What a scan does on reading a corrupt item
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ScanErrorPolicy {
+    /// Stop at the item, for scans which can't leave gaps such as DCP
+    /// backfills. The caller decides how to recover, e.g. by having the
+    /// vbucket re-replicated.
+    #[default]
+    Abort,
+    /// Skip the item and carry on, for tools which want whatever can still
+    /// be read. Corruption in the index rather than an item still stops
+    /// the scan, as there's no way past it.
+    SkipAndReport,
+}
+
+/// The corruption a scan found. If it was aborted, the items after the
+/// last one returned weren't read.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ScanResult {
+    /// A description of each corrupt item skipped, or of what stopped it
+    pub errors: Vec<String>,
+    pub aborted: bool,
+}
+
+impl ScanResult {
+    /// Whether every item was read
+    pub fn is_complete(&self) -> bool {
+        self.errors.is_empty()
+    }
+}
+
+/// What a scrub read, and a description of each corrupt chunk it found
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ScrubResult {
+    pub bytes_read: u64,
+    pub corrupt_chunks: Vec<String>,
+}
+
+/// The seqnos lost by truncate_to_clean_commit: those after new_high_seqno
+/// up to old_high_seqno
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TruncatedCommits {
+    pub old_high_seqno: u64,
+    pub new_high_seqno: u64,
+}
+
+/// A commit of a vbucket which can be read back for point in time recovery
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RetainedHeader {
+    /// The highest seqno persisted by the commit
+    pub high_seqno: u64,
+    /// When the commit was made, in nanoseconds since the epoch
+    pub timestamp: u64,
+    /// Where the header is in the vbucket's file
+    pub position: u64,
+}
+
 #[derive(Debug)]
 pub struct CouchKVStore {
     config: CouchKVStoreConfig,
+    /// Names the files in the shard's data directory
+    layout: couchstore::DbNameLayout,
     db_file_rev_map: Arc<RevisionMap>,
-    cached_vb_states: Vec<Option<VBucketState>>,
+    cached_vb_states: VBStateCache,
+    /// What each vbucket has committed since its file was last synced
+    sync_states: Mutex<HashMap<Vbid, couchstore::SyncState>>,
+    stats: KVStoreStats,
 }
 
 impl CouchKVStore {
     pub fn new(config: CouchKVStoreConfig) -> Self {
-        let mut store = Self {
+        let store = Self {
             db_file_rev_map: make_revision_map(&config),
+            layout: couchstore::DbNameLayout::new(config.data_path()),
+            cached_vb_states: VBStateCache::new(config.get_cache_size()),
             config,
-            cached_vb_states: Vec::new(),
+            sync_states: Mutex::default(),
+            stats: KVStoreStats::default(),
         };
 
-        let cache_size = store.config.get_cache_size();
-
-        store.cached_vb_states.resize(cache_size, None);
-
         // 1) populate the dbFileRevMap which can remove old revisions, this returns
         //    a map, which the keys (vbid) will be needed for step 3 and 4.
         let map = store.populate_rev_map_and_remove_stale_files();
@@ -55,34 +689,98 @@ impl CouchKVStore {
         // 3) continue to intialise the store (reads vbstate etc...)
         store.initialise(map);
 
+        store.warn_misplaced_files();
+
         store
     }
 
-    fn initialise(&mut self, map: HashMap<Vbid, HashSet<u64>>) {
-        for &vbid in map.keys() {
-            let options = couchstore::DBOpenOptions::default().read_only();
+    /// Read the state of each vbucket with a file. Only the files' headers
+    /// and vbstates are read, on several threads, so starting up with many
+    /// files is quick.
+    fn initialise(&self, map: HashMap<Vbid, HashSet<u64>>) {
+        let vbids: Vec<Vbid> = map.into_keys().collect();
+        let threads = std::thread::available_parallelism()
+            .map_or(1, |threads| threads.get())
+            .min(vbids.len());
+        if threads == 0 {
+            return;
+        }
+        let store = self;
+        std::thread::scope(|scope| {
+            for chunk in vbids.chunks(vbids.len().div_ceil(threads)) {
+                scope.spawn(move || {
+                    for &vbid in chunk {
+                        store.initialise_vbucket(vbid);
+                    }
+                });
+            }
+        });
+    }
 
-            let mut db = self.open_db(vbid, options);
+    fn initialise_vbucket(&self, vbid: Vbid) {
+        let file_name = self.db_file_path(vbid, self.get_db_revision(vbid));
+        let (header, local_doc) =
+            match couchstore::Db::open_header_only(&file_name, LOCAL_DOC_KEY_VBSTATE) {
+                Ok(read) => read,
+                Err(e) => {
+                    println!("Failed to open {vbid}, it won't be warmed up: {e}");
+                    self.stats
+                        .open_failures
+                        .fetch_add(1, atomic::Ordering::Relaxed);
+                    return;
+                }
+            };
+        self.stats.record_disk_version(vbid, header.disk_version());
+        let vb_state = match vb_state_from_header(&header, local_doc) {
+            Ok(vb_state) => vb_state,
+            Err(e) => {
+                println!("Failed to read the vbucket state of {vbid}, it won't be warmed up: {e}");
+                self.stats
+                    .open_failures
+                    .fetch_add(1, atomic::Ordering::Relaxed);
+                return;
+            }
+        };
+        self.update_cached_vb_state(vbid, vb_state);
+    }
 
-            self.read_vb_state_and_update_cache(&mut db, vbid);
+    /// Files of this shard's vbuckets in the other data directories are left
+    /// over from a change of data paths, and aren't seen until moved here
+    fn warn_misplaced_files(&self) {
+        let data_path = self.config.data_path();
+        let dirs = std::iter::once(&self.config.db_name).chain(&self.config.data_paths);
+        for dir in dirs.filter(|dir| *dir != data_path) {
+            let layout = couchstore::DbNameLayout::new(dir);
+            let Ok(names) = layout.list() else {
+                continue;
+            };
+            for (vbid, revs) in self.get_vbucket_revision(names) {
+                for rev in revs {
+                    println!(
+                        "{} belongs in {data_path} since the data paths changed, move it there with the bucket stopped",
+                        layout.path(vbid.into(), rev).display()
+                    );
+                }
+            }
         }
     }
 
-    fn read_vb_state_and_update_cache(
-        &mut self,
-        db: &mut couchstore::Db,
-        vbid: Vbid,
-    ) -> &VBucketState {
+    fn read_vb_state_and_update_cache(&self, db: &couchstore::Db, vbid: Vbid) {
         let vb_state = self.read_vb_state(db, vbid);
+        self.update_cached_vb_state(vbid, vb_state);
+    }
 
+    fn update_cached_vb_state(&self, vbid: Vbid, vb_state: VBucketState) {
         let slot = self.get_cache_slot(vbid);
-        self.cached_vb_states[slot] = Some(vb_state);
-
-        self.cached_vb_states[slot].as_ref().unwrap()
+        self.cached_vb_states.set(slot, Some(vb_state));
     }
 
     fn populate_rev_map_and_remove_stale_files(&self) -> HashMap<Vbid, HashSet<u64>> {
-        let map = self.get_vbucket_revision(discover_db_files(&self.config.db_name));
+        let names = self
+            .layout
+            .list()
+            .unwrap_or_else(|e| panic!("Failed to list {}: {e}", self.layout.dir().display()));
+        let map = self.get_vbucket_revision(names);
 
         for (&vbid, revs) in &map {
             for &revision in revs {
@@ -102,11 +800,11 @@ impl CouchKVStore {
                 }
 
                 // stale file left behind to be removed
-                let stale_file = get_db_file_name(&self.config.db_name, vbid, current);
+                let stale_file = self.db_file_path(vbid, current);
 
                 if std::fs::metadata(&stale_file).is_ok() {
                     std::fs::remove_file(&stale_file).unwrap();
-                    println!("Removed stale file {}", stale_file);
+                    println!("Removed stale file {}", stale_file.display());
                 }
             }
         }
@@ -114,39 +812,39 @@ impl CouchKVStore {
         map
     }
 
+    /// Path of the vbucket's file at the revision
+    fn db_file_path(&self, vbid: Vbid, revision: u64) -> PathBuf {
+        self.layout.path(vbid.into(), revision)
+    }
+
     fn get_db_revision(&self, vbid: Vbid) -> u64 {
         let map = self.db_file_rev_map.read();
         map[self.get_cache_slot(vbid)]
     }
 
     fn get_cache_slot(&self, vbid: Vbid) -> usize {
-        (u16::from(vbid) / self.config.max_shards) as usize
+        vbid.index_in_shard(self.config.max_shards)
     }
 
-    fn get_vbucket_revision(&self, filenames: Vec<String>) -> HashMap<Vbid, HashSet<u64>> {
+    fn get_vbucket_revision(
+        &self,
+        names: Vec<couchstore::DbFileName>,
+    ) -> HashMap<Vbid, HashSet<u64>> {
         let mut vbids = HashMap::new();
-        for filename in filenames {
-            let parts: Vec<&str> = filename.split('.').collect();
-            assert_eq!(parts.len(), 3);
-            // master.couch.x is expected and can be silently ignored
-            if parts[0] == "master" {
+        for name in names {
+            if name.kind != couchstore::DbFileKind::Data {
                 continue;
             }
-            // TODO: Error handling
-            let vbid = Vbid::new(parts[0].parse().unwrap());
-            let rev = parts[2].parse().unwrap();
-
-            if vbid % self.config.max_shards != self.config.shard_id {
-                // Either doesn't belong to this shard or is the last element
-                // (case where max vB % shards != 0) which we now need to check
-                // for
-                if u16::from(vbid)
-                    != (((self.config.max_vbuckets / self.config.max_shards)
-                        * self.config.max_shards)
-                        + self.config.shard_id)
-                {
-                    continue;
-                }
+            let vbid = Vbid::from(name.vbid);
+            let rev = name.revision;
+
+            // Ignore files for vbuckets beyond the bucket's
+            if Vbid::new_checked(name.vbid, self.config.max_vbuckets).is_err() {
+                println!("Ignoring file {name} for out of range vbucket");
+                continue;
+            }
+            if vbid.shard(self.config.max_shards) != self.config.shard_id {
+                continue;
             }
 
             vbids.entry(vbid).or_insert_with(HashSet::new).insert(rev);
@@ -161,67 +859,216 @@ impl CouchKVStore {
 
     fn maybe_remove_compact_file(&self, vbid: Vbid) {
         let revision = self.get_db_revision(vbid);
-        let compact_file = get_db_file_name(&self.config.db_name, vbid, revision) + ".compact";
+        let compact_file = self.layout.path_of(&couchstore::DbFileName {
+            vbid: vbid.into(),
+            revision,
+            kind: couchstore::DbFileKind::Compact,
+        });
         if std::fs::metadata(&compact_file).is_ok() {
             std::fs::remove_file(&compact_file).unwrap();
-            println!("Removed compact file {}", compact_file);
+            println!("Removed compact file {}", compact_file.display());
+        }
+    }
+
+    /// Rewrite the vbucket's file at the configured disk version, as its next
+    /// revision. Files of older versions are read and written in their own
+    /// format until then. On failure the old file stays in use.
+    fn upgrade_db_file(&self, vbid: Vbid, db: &couchstore::Db) {
+        let old_version = db.header().disk_version();
+        let new_file = match self.rewrite_db_file(vbid, db) {
+            Ok((new_file, _)) => new_file,
+            Err(e) => {
+                println!("Failed to upgrade the file of {vbid}: {e}");
+                return;
+            }
+        };
+        self.stats
+            .files_upgraded
+            .fetch_add(1, atomic::Ordering::Relaxed);
+        println!(
+            "Upgraded {} from disk version {} to {}",
+            new_file.display(),
+            u8::from(old_version),
+            u8::from(self.config.disk_version())
+        );
+    }
+
+    /// Compact the vbucket's file into its next revision, at the configured
+    /// disk version, and switch to it. Returns the new file's path and
+    /// handle.
+    fn rewrite_db_file(
+        &self,
+        vbid: Vbid,
+        db: &couchstore::Db,
+    ) -> couchstore::CouchstoreResult<(PathBuf, couchstore::Db)> {
+        // The new file has only the latest header, so the earlier states
+        // kept for point in time recovery must age out first
+        if self.config.pitr_max_history_age.is_some() {
+            let earlier = self.list_retained_headers(vbid)?.len().saturating_sub(1);
+            if earlier > 0 {
+                return Err(io::Error::other(format!(
+                    "{earlier} earlier headers are within the point in time recovery window"
+                ))
+                .into());
+            }
+        }
+        let revision = self.get_db_revision(vbid);
+        let compact_file = self.layout.path_of(&couchstore::DbFileName {
+            vbid: vbid.into(),
+            revision,
+            kind: couchstore::DbFileKind::Compact,
+        });
+        let mut options = couchstore::DBOpenOptions::default()
+            .btree_tuning(self.config.btree_tuning)
+            .disk_version(self.config.disk_version());
+        if self.config.dsync {
+            options = options.dsync();
+        }
+        if self.config.compaction_direct_io {
+            options = options.direct_io();
+        }
+        let new_file = self.db_file_path(vbid, revision + 1);
+        let start = Instant::now();
+        let result = db.compact_to(&compact_file, options).and_then(|new_db| {
+            std::fs::rename(&compact_file, &new_file)?;
+            Ok(new_db)
+        });
+        let new_db = match result {
+            Ok(new_db) => new_db,
+            Err(e) => {
+                let _ = std::fs::remove_file(&compact_file);
+                return Err(e);
+            }
+        };
+        self.update_db_file_map(vbid, revision + 1);
+        self.record_header(vbid, &new_db);
+        self.stats.record_compaction(start);
+        self.stats.record_compaction_writes(new_db.file_stats());
+        if new_db.file_stats().direct_io_fallback {
+            println!(
+                "Compacting {vbid} wrote through the page cache, as {} refused O_DIRECT",
+                new_file.display()
+            );
+        }
+        // Left for the next startup to remove as stale
+        let old_file = self.db_file_path(vbid, revision);
+        if let Err(e) = std::fs::remove_file(&old_file) {
+            println!(
+                "Failed to remove {} after compacting {vbid}: {e}",
+                old_file.display()
+            );
+        }
+
+        self.stats
+            .record_disk_version(vbid, self.config.disk_version());
+        match new_db.node_stats() {
+            Ok(node_stats) => self.stats.record_compaction_nodes(node_stats),
+            Err(e) => println!("Failed to read the nodes of {}: {e}", new_file.display()),
         }
+        Ok((new_file, new_db))
     }
 
-    fn open_db(&self, vbid: Vbid, options: couchstore::DBOpenOptions) -> couchstore::Db {
+    fn open_db(
+        &self,
+        vbid: Vbid,
+        options: couchstore::DBOpenOptions,
+    ) -> couchstore::CouchstoreResult<couchstore::Db> {
         let rev_map = self.db_file_rev_map.read();
         let file_rev = rev_map[self.get_cache_slot(vbid)];
-        let file_name = get_db_file_name(&self.config.db_name, vbid, file_rev);
+        let file_name = self.db_file_path(vbid, file_rev);
         self.open_specific_db_file(vbid, file_rev, options, file_name)
     }
 
     fn open_specific_db_file(
         &self,
-        _vbid: Vbid,
+        vbid: Vbid,
         _file_rev: u64,
-        options: couchstore::DBOpenOptions,
-        file_name: String,
-    ) -> couchstore::Db {
+        mut options: couchstore::DBOpenOptions,
+        file_name: PathBuf,
+    ) -> couchstore::CouchstoreResult<couchstore::Db> {
         // TODO: args used for loggin
-        couchstore::Db::open(file_name, options)
+        if self.config.dsync {
+            options = options.dsync();
+        }
+        options = options
+            .btree_tuning(self.config.btree_tuning)
+            .disk_version(self.config.disk_version());
+        let db = couchstore::Db::open(&file_name, options).inspect_err(|e| {
+            println!("Failed to open {}: {e}", file_name.display());
+            self.stats
+                .open_failures
+                .fetch_add(1, atomic::Ordering::Relaxed);
+        })?;
+        self.stats
+            .record_disk_version(vbid, db.header().disk_version());
+        Ok(db)
     }
 
-    fn read_vb_state(&self, db: &mut couchstore::Db, _vbid: Vbid) -> VBucketState {
-        let header = self.read_header(db);
-        let high_seqno = header.update_seq as i64;
-        let purge_seqno = header.purge_seq;
-
-        let vb_state = get_local_vb_state(db);
-
-        let mut vb_state: VBucketState = serde_json::from_value(vb_state).unwrap();
-
-        vb_state.high_seqno = high_seqno;
-        vb_state.purge_seqno = purge_seqno;
+    fn read_vb_state(&self, db: &couchstore::Db, vbid: Vbid) -> VBucketState {
+        self.try_read_vb_state(db)
+            .unwrap_or_else(|e| panic!("Failed to read the vbucket state of {vbid}: {e}"))
+    }
 
-        // MB-17517: If the maxCas on disk was invalid then don't use it -
-        // instead rebuild from the items we load from disk (i.e. as per
-        // an upgrade from an earlier version).
-        if vb_state.max_cas == std::u64::MAX {
-            vb_state.max_cas = 0;
-        }
+    fn try_read_vb_state(&self, db: &couchstore::Db) -> Result<VBucketState, String> {
+        let local_doc = db
+            .open_local_document(LOCAL_DOC_KEY_VBSTATE)
+            .map_err(|e| e.to_string())?;
+        vb_state_from_header(self.read_header(db), local_doc)
+    }
 
-        vb_state
+    /// Keep the commit just made for the headers stat group
+    fn record_header(&self, vbid: Vbid, db: &couchstore::Db) {
+        let header = db.header();
+        self.stats.record_header(
+            vbid,
+            RecentHeader {
+                file_revision: self.get_db_revision(vbid),
+                position: header.position(),
+                high_seqno: header.update_seq,
+                timestamp: header.timestamp(),
+            },
+        );
     }
 
     fn read_header<'a>(&self, db: &'a couchstore::Db) -> &'a couchstore::Header {
         db.header()
     }
 
-    pub fn list_persisted_vbuckets(&self) -> Vec<&Option<VBucketState>> {
-        let mut res = Vec::new();
-        for vb in &self.cached_vb_states {
-            res.push(vb);
-        }
-        res
+    fn commit_vb_state(
+        &self,
+        vbid: Vbid,
+        db: &mut couchstore::Db,
+        vb_state: &VBucketState,
+    ) -> couchstore::CouchstoreResult<()> {
+        let json = serde_json::to_vec(vb_state).unwrap();
+        db.save_local_document(couchstore::LocalDoc::new(LOCAL_DOC_KEY_VBSTATE, json))?;
+        // Taken out so the other vbuckets' commits don't wait on this one's
+        // write and sync
+        let mut sync_state = self.sync_states.lock().remove(&vbid).unwrap_or_default();
+        let result = db.commit_with_policy(self.config.sync_policy, &mut sync_state);
+        self.sync_states.lock().insert(vbid, sync_state);
+        result?;
+        self.record_header(vbid, db);
+
+        let mut vb_state = vb_state.clone();
+        vb_state.high_seqno = db.header().update_seq as i64;
+        vb_state.purge_seqno = db.header().purge_seq;
+        self.update_cached_vb_state(vbid, vb_state);
+        Ok(())
     }
 
-    pub fn init_by_seqno_scan_context(&self, vbid: Vbid, start_seqno: u64) -> BySeqnoScanContext {
-        let mut db = self.open_db(vbid, couchstore::DBOpenOptions::default().read_only());
+    /// Open the vbucket's file to scan, reading read_ahead bytes at a time
+    /// if not 0
+    pub fn init_by_seqno_scan_context(
+        &self,
+        vbid: Vbid,
+        start_seqno: u64,
+        read_ahead: usize,
+    ) -> io::Result<BySeqnoScanContext> {
+        let options = couchstore::DBOpenOptions::default()
+            .read_only()
+            .read_ahead(read_ahead);
+        let db = self.open_db(vbid, options).map_err(io::Error::other)?;
 
         let couchstore::Header {
             update_seq,
@@ -231,9 +1078,9 @@ impl CouchKVStore {
         // TODO: get from couchstore_changes_count
         let count = 0;
 
-        let vb_state = self.read_vb_state(&mut db, vbid);
+        let vb_state = self.try_read_vb_state(&db).map_err(io::Error::other)?;
 
-        BySeqnoScanContext {
+        Ok(BySeqnoScanContext {
             vbid,
             db,
             start_seqno,
@@ -242,7 +1089,687 @@ impl CouchKVStore {
             documnent_filter: DocumentFilter::AllItems,
             vbucket_state: vb_state,
             document_count: count,
+        })
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn scan_by_seqno(
+        &self,
+        vbid: Vbid,
+        start_seqno: u64,
+        value_filter: ValueFilter,
+        source: SnapshotSource,
+        on_error: ScanErrorPolicy,
+        read_ahead: usize,
+        callback: &mut dyn FnMut(Item) -> bool,
+    ) -> ScanResult {
+        let mut scan_result = ScanResult::default();
+        let file_name = self.db_file_path(vbid, self.get_db_revision(vbid));
+        if std::fs::metadata(file_name).is_err() {
+            // Nothing has been persisted for the vbucket
+            return scan_result;
+        }
+
+        let ctx = match self.init_by_seqno_scan_context(vbid, start_seqno, read_ahead) {
+            Ok(ctx) => ctx,
+            Err(e) => {
+                println!("Failed to open {vbid} to scan it: {e}");
+                scan_result.errors.push(e.to_string());
+                scan_result.aborted = true;
+                return scan_result;
+            }
+        };
+        // With history retention the by-seq index also has the keys' older
+        // versions, which a head scan skips
+        let skip_old_versions =
+            self.config.history_retention && source != SnapshotSource::HeadAllVersions;
+        let mut stopped = false;
+        let result = ctx.db.changes_since(start_seqno, |db, doc_info| {
+            // The rest of the index is still walked, but no more documents
+            // are read
+            if stopped {
+                return Ok(());
+            }
+            if skip_old_versions {
+                let latest = db.docinfo_by_id(doc_info.id.clone())?;
+                if latest.is_some_and(|latest| latest.db_seq != doc_info.db_seq) {
+                    return Ok(());
+                }
+            }
+            let seqno = doc_info.db_seq;
+            match make_item(db, doc_info, value_filter) {
+                Ok(item) => stopped = !callback(item),
+                Err(e) if on_error == ScanErrorPolicy::SkipAndReport => {
+                    scan_result.errors.push(format!("seqno {seqno}: {e}"));
+                }
+                Err(e) => return Err(e),
+            }
+            Ok(())
+        });
+        self.stats.record_scan_reads(ctx.db.file_stats());
+        if let Err(e) = result {
+            println!("Failed to scan {vbid}: {e}");
+            scan_result.errors.push(e.to_string());
+            scan_result.aborted = true;
+        }
+        scan_result
+    }
+
+    fn lookup_by_id(
+        &self,
+        vbid: Vbid,
+        keys: &[Vec<u8>],
+    ) -> couchstore::CouchstoreResult<Vec<Option<Item>>> {
+        let file_name = self.db_file_path(vbid, self.get_db_revision(vbid));
+        if std::fs::metadata(file_name).is_err() {
+            return Ok(vec![None; keys.len()]);
+        }
+
+        let db = self.open_db(vbid, couchstore::DBOpenOptions::default().read_only())?;
+        let mut doc_infos = HashMap::with_capacity(keys.len());
+        db.docinfos_by_id(keys.to_vec(), |key, doc_info| {
+            if let Some(doc_info) = doc_info {
+                doc_infos.insert(key.to_vec(), doc_info);
+            }
+        })?;
+        keys.iter()
+            .map(|key| {
+                doc_infos
+                    .remove(key)
+                    .map(|doc_info| make_item(&db, doc_info, ValueFilter::ValuesDecompressed))
+                    .transpose()
+            })
+            .collect()
+    }
+
+    /// Write a point in time copy of the vbucket's file into dest_dir for
+    /// backup tools, returning its path. A commit is forced first so the
+    /// copy ends with a header. As with commit, the caller must not flush
+    /// the vbucket at the same time.
+    ///
+    /// The file is copied up to that header rather than hard linked, as a
+    /// link would share everything appended to the file afterwards.
+    pub fn snapshot_to(&self, vbid: Vbid, dest_dir: impl AsRef<Path>) -> io::Result<PathBuf> {
+        let mut db = self
+            .open_db(vbid, couchstore::DBOpenOptions::default())
+            .map_err(io::Error::other)?;
+        db.try_commit().map_err(io::Error::other)?;
+        self.record_header(vbid, &db);
+        let len = db.file_pos();
+        drop(db);
+
+        let file_name = self.db_file_path(vbid, self.get_db_revision(vbid));
+        let dest = dest_dir
+            .as_ref()
+            .join(Path::new(&file_name).file_name().unwrap());
+        let mut src = std::fs::File::open(&file_name)?.take(len);
+        let mut dest_file = std::fs::File::create(&dest)?;
+        io::copy(&mut src, &mut dest_file)?;
+        dest_file.sync_all()?;
+        Ok(dest)
+    }
+
+    /// Start loading a vbucket which has no items, as restores do. Its
+    /// file's trees are built in one pass as the items are added, rather
+    /// than modified commit by commit, and nothing is visible until
+    /// finish_bulk_load commits it.
+    pub fn begin_bulk_load(&self, vbid: Vbid) -> Result<VBucketBulkLoad, CommitError> {
+        let options = couchstore::DBOpenOptions::default()
+            .max_key_size(self.config.max_key_size + collections::MAX_PREFIX_SIZE)
+            .max_value_size(self.config.max_item_size);
+        let loader = self
+            .open_db(vbid, options)?
+            .bulk_load(couchstore::SaveOptions::COMPRESS_DOC_BODIES)?;
+        Ok(VBucketBulkLoad {
+            vbid,
+            loader,
+            expiry_changes: Vec::new(),
+            start: Instant::now(),
+        })
+    }
+
+    /// Write the rest of the bulk load and commit it with the vbucket's
+    /// new state
+    pub fn finish_bulk_load(
+        &self,
+        load: VBucketBulkLoad,
+        vb_state: &VBucketState,
+    ) -> Result<(), CommitError> {
+        let mut db = load.loader.finish()?;
+        if !load.expiry_changes.is_empty() {
+            db.create_aux_tree(EXPIRY_TREE)?;
+            db.modify_aux_tree(EXPIRY_TREE, load.expiry_changes)?;
+        }
+        self.commit_vb_state(load.vbid, &mut db, vb_state)?;
+        let file_stats = db.file_stats();
+        self.stats
+            .record_commit(load.start, file_stats.bytes_written, file_stats.syncs);
+        Ok(())
+    }
+}
+
+/// A vbucket being loaded by CouchKVStore::begin_bulk_load
+#[derive(Debug)]
+pub struct VBucketBulkLoad {
+    vbid: Vbid,
+    loader: couchstore::BulkLoader,
+    expiry_changes: Vec<(Vec<u8>, Option<Vec<u8>>)>,
+    start: Instant,
+}
+
+impl VBucketBulkLoad {
+    /// Add an item, which must have a higher seqno than those already
+    /// added and a key none of them has
+    pub fn add(&mut self, item: &Item) -> Result<(), CommitError> {
+        if item.value.is_some() && item.expiry_time != 0 {
+            self.expiry_changes
+                .push((expiry_index_key(item.expiry_time, &item.key), Some(vec![])));
+        }
+        let (doc, info) = couchstore_doc(item);
+        self.loader.add(doc.as_ref(), &info)?;
+        Ok(())
+    }
+}
+
+impl KVStore for CouchKVStore {
+    /// Persist a batch of items for the vbucket along with its new state.
+    /// The items must be in seqno order.
+    fn commit(
+        &self,
+        vbid: Vbid,
+        items: &[QueuedItem],
+        vb_state: &VBucketState,
+    ) -> Result<(), CommitError> {
+        let start = Instant::now();
+        let options = couchstore::DBOpenOptions::default()
+            .max_key_size(self.config.max_key_size + collections::MAX_PREFIX_SIZE)
+            .max_value_size(self.config.max_item_size);
+        let mut db = self.open_db(vbid, options)?;
+
+        // The keys' previous versions leave the expiry index
+        let mut expiry_changes = Vec::new();
+        db.docinfos_by_id(
+            items.iter().map(|item| item.key.clone()).collect(),
+            |key, info| {
+                let Some(info) = info.filter(|info| !info.deleted) else {
+                    return;
+                };
+                let expiry_time = Metadata::decode(&info.rev_meta[..]).expiry_time;
+                if expiry_time != 0 {
+                    expiry_changes.push((expiry_index_key(expiry_time, key), None));
+                }
+            },
+        )?;
+
+        let mut docs = Vec::with_capacity(items.len());
+        let mut infos = Vec::with_capacity(items.len());
+        for item in items {
+            if item.value.is_some() && item.expiry_time != 0 {
+                expiry_changes.push((expiry_index_key(item.expiry_time, &item.key), Some(vec![])));
+            }
+            let (doc, info) = couchstore_doc(item);
+            docs.push(doc);
+            infos.push(info);
+        }
+        let mut options =
+            couchstore::SaveOptions::SEQUENCE_AS_IS | couchstore::SaveOptions::COMPRESS_DOC_BODIES;
+        if self.config.history_retention {
+            options |= couchstore::SaveOptions::KEEP_HISTORY;
+        }
+        db.save_documents(docs, infos, options)?;
+        if !expiry_changes.is_empty() {
+            db.create_aux_tree(EXPIRY_TREE)?;
+            db.modify_aux_tree(EXPIRY_TREE, expiry_changes)?;
+        }
+
+        self.commit_vb_state(vbid, &mut db, vb_state)?;
+        let file_stats = db.file_stats();
+        self.stats
+            .record_commit(start, file_stats.bytes_written, file_stats.syncs);
+        Ok(())
+    }
+
+    /// Persist the vbucket's state without any items
+    fn snapshot_vbucket(&self, vbid: Vbid, vb_state: &VBucketState) -> Result<(), CommitError> {
+        let start = Instant::now();
+        let mut db = self.open_db(vbid, couchstore::DBOpenOptions::default())?;
+        self.commit_vb_state(vbid, &mut db, vb_state)?;
+        let file_stats = db.file_stats();
+        self.stats
+            .record_commit(start, file_stats.bytes_written, file_stats.syncs);
+        Ok(())
+    }
+
+    fn get_cached_vb_state(&self, vbid: Vbid) -> Option<VBucketState> {
+        self.cached_vb_states.get(self.get_cache_slot(vbid))
+    }
+
+    /// The file's latest revision is picked up too, in case the tool
+    /// rewrote it as a new one
+    fn refresh_vbucket_state(&self, vbid: Vbid) -> Result<Option<VBucketState>, String> {
+        let names = self.layout.list().map_err(|e| e.to_string())?;
+        let latest = self
+            .get_vbucket_revision(names)
+            .remove(&vbid)
+            .and_then(|revs| revs.into_iter().max());
+        let Some(revision) = latest else {
+            self.cached_vb_states.set(self.get_cache_slot(vbid), None);
+            return Ok(None);
+        };
+        if revision > self.get_db_revision(vbid) {
+            self.update_db_file_map(vbid, revision);
+        }
+
+        let db = self
+            .open_db(vbid, couchstore::DBOpenOptions::default().read_only())
+            .map_err(|e| e.to_string())?;
+        let vb_state = self.try_read_vb_state(&db)?;
+        self.record_header(vbid, &db);
+        self.update_cached_vb_state(vbid, vb_state.clone());
+        Ok(Some(vb_state))
+    }
+
+    fn list_persisted_vbuckets(&self) -> Vec<Option<VBucketState>> {
+        self.cached_vb_states.snapshot()
+    }
+
+    fn get_storage_properties(&self) -> StorageProperties {
+        StorageProperties {
+            historical_snapshots: true,
+            by_id_scan: true,
+            // Compaction copies the file then switches to the copy, without
+            // catching up on commits made meanwhile, so the vbucket can't be
+            // flushed until it's done
+            concurrent_write_compaction: false,
+            automatic_deduplication: false,
+        }
+    }
+
+    fn get_stats(&self) -> &KVStoreStats {
+        &self.stats
+    }
+
+    fn scan(
+        &self,
+        vbid: Vbid,
+        start_seqno: u64,
+        value_filter: ValueFilter,
+        on_error: ScanErrorPolicy,
+        callback: &mut dyn FnMut(Item),
+    ) -> ScanResult {
+        self.scan_by_seqno(
+            vbid,
+            start_seqno,
+            value_filter,
+            SnapshotSource::Head,
+            on_error,
+            0,
+            &mut |item| {
+                callback(item);
+                true
+            },
+        )
+    }
+
+    fn scan_while(
+        &self,
+        vbid: Vbid,
+        start_seqno: u64,
+        value_filter: ValueFilter,
+        on_error: ScanErrorPolicy,
+        callback: &mut dyn FnMut(Item) -> bool,
+    ) -> ScanResult {
+        self.scan_by_seqno(
+            vbid,
+            start_seqno,
+            value_filter,
+            SnapshotSource::Head,
+            on_error,
+            0,
+            callback,
+        )
+    }
+
+    fn scan_sequential(
+        &self,
+        vbid: Vbid,
+        start_seqno: u64,
+        value_filter: ValueFilter,
+        on_error: ScanErrorPolicy,
+        callback: &mut dyn FnMut(Item),
+    ) -> ScanResult {
+        self.scan_by_seqno(
+            vbid,
+            start_seqno,
+            value_filter,
+            SnapshotSource::Head,
+            on_error,
+            SEQUENTIAL_SCAN_READ_SIZE,
+            &mut |item| {
+                callback(item);
+                true
+            },
+        )
+    }
+
+    fn scan_all_versions(
+        &self,
+        vbid: Vbid,
+        start_seqno: u64,
+        value_filter: ValueFilter,
+        on_error: ScanErrorPolicy,
+        callback: &mut dyn FnMut(Item),
+    ) -> ScanResult {
+        self.scan_by_seqno(
+            vbid,
+            start_seqno,
+            value_filter,
+            SnapshotSource::HeadAllVersions,
+            on_error,
+            0,
+            &mut |item| {
+                callback(item);
+                true
+            },
+        )
+    }
+
+    fn get_backup_cursors(&self, vbid: Vbid) -> io::Result<BTreeMap<String, u64>> {
+        let file_name = self.db_file_path(vbid, self.get_db_revision(vbid));
+        if std::fs::metadata(file_name).is_err() {
+            return Ok(BTreeMap::new());
+        }
+        let db = self
+            .open_db(vbid, couchstore::DBOpenOptions::default().read_only())
+            .map_err(io::Error::other)?;
+        let doc = db
+            .open_local_document(LOCAL_DOC_KEY_BACKUP_CURSORS)
+            .map_err(io::Error::other)?;
+        doc.and_then(|doc| doc.json)
+            .map_or_else(|| Ok(BTreeMap::new()), |json| decode_backup_cursors(&json))
+    }
+
+    fn set_backup_cursors(&self, vbid: Vbid, cursors: &BTreeMap<String, u64>) -> io::Result<()> {
+        let mut db = self
+            .open_db(vbid, couchstore::DBOpenOptions::default())
+            .map_err(io::Error::other)?;
+        db.save_local_document(couchstore::LocalDoc::new(
+            LOCAL_DOC_KEY_BACKUP_CURSORS,
+            encode_backup_cursors(cursors),
+        ))
+        .map_err(io::Error::other)?;
+        db.try_commit().map_err(io::Error::other)?;
+        self.record_header(vbid, &db);
+        Ok(())
+    }
+
+    fn purge_tombstones(
+        &self,
+        vbid: Vbid,
+        purge_before: u32,
+        max_seqno: u64,
+    ) -> io::Result<PurgeResult> {
+        let mut result = PurgeResult::default();
+        let file_name = self.db_file_path(vbid, self.get_db_revision(vbid));
+        if std::fs::metadata(file_name).is_err() {
+            return Ok(result);
+        }
+
+        let mut db = self
+            .open_db(vbid, couchstore::DBOpenOptions::default())
+            .map_err(io::Error::other)?;
+        let mut purged = Vec::new();
+        db.changes_since(0, |_, doc_info| {
+            let deleted_at = Metadata::decode(&doc_info.rev_meta[..]).expiry_time;
+            if doc_info.deleted
+                && result.check(
+                    doc_info.db_seq,
+                    deleted_at,
+                    doc_info.disk_size(),
+                    purge_before,
+                    max_seqno,
+                )
+            {
+                purged.push(doc_info);
+            }
+            Ok(())
+        })
+        .map_err(io::Error::other)?;
+        if !purged.is_empty() {
+            db.purge_documents(&purged).map_err(io::Error::other)?;
+            db.try_commit().map_err(io::Error::other)?;
+            self.record_header(vbid, &db);
+            if let Some(mut vb_state) = self.get_cached_vb_state(vbid) {
+                vb_state.purge_seqno = db.header().purge_seq;
+                self.update_cached_vb_state(vbid, vb_state);
+            }
+        }
+        if db.header().disk_version() < self.config.disk_version() {
+            self.upgrade_db_file(vbid, &db);
+        }
+        Ok(result)
+    }
+
+    fn compact(&self, vbid: Vbid) -> u64 {
+        let file_name = self.db_file_path(vbid, self.get_db_revision(vbid));
+        let Ok(before) = std::fs::metadata(file_name).map(|m| m.len()) else {
+            return 0;
+        };
+        let db = match self.open_db(vbid, couchstore::DBOpenOptions::default().read_only()) {
+            Ok(db) => db,
+            Err(e) => {
+                println!("Failed to open the file of {vbid} to compact it: {e}");
+                return 0;
+            }
+        };
+        match self.rewrite_db_file(vbid, &db) {
+            Ok((new_file, new_db)) => {
+                let after = std::fs::metadata(new_file).map_or(before, |m| m.len());
+                let fragmentation = |info: couchstore::DbInfo| {
+                    info.fragmentation()
+                        .map_or("unknown".to_string(), |f| format!("{:.1}%", f * 100.0))
+                };
+                println!(
+                    "Compacted {vbid} from {before} to {after} bytes, fragmentation {} to {}",
+                    fragmentation(db.info()),
+                    fragmentation(new_db.info())
+                );
+                before.saturating_sub(after)
+            }
+            Err(e) => {
+                println!("Failed to compact the file of {vbid}: {e}");
+                0
+            }
+        }
+    }
+
+    fn get_expired_keys(&self, vbid: Vbid, now: u32) -> io::Result<Vec<Vec<u8>>> {
+        let file_name = self.db_file_path(vbid, self.get_db_revision(vbid));
+        if std::fs::metadata(file_name).is_err() {
+            return Ok(Vec::new());
+        }
+        let db = self
+            .open_db(vbid, couchstore::DBOpenOptions::default().read_only())
+            .map_err(io::Error::other)?;
+        let mut keys = Vec::new();
+        let result =
+            db.fold_aux_tree_range(EXPIRY_TREE, &[], &expiry_index_end(now), |_, key, _| {
+                keys.push(key[4..].to_vec());
+                Ok(())
+            });
+        match result {
+            // Nothing which expires has been persisted
+            Ok(()) | Err(couchstore::CouchstoreError::NoSuchTree(_)) => Ok(keys),
+            Err(e) => Err(io::Error::other(e)),
+        }
+    }
+
+    fn list_keys(
+        &self,
+        vbid: Vbid,
+        start_key: &[u8],
+        end_key: Option<&[u8]>,
+        limit: usize,
+    ) -> io::Result<Vec<Vec<u8>>> {
+        let file_name = self.db_file_path(vbid, self.get_db_revision(vbid));
+        if limit == 0 || std::fs::metadata(file_name).is_err() {
+            return Ok(Vec::new());
+        }
+        let db = self
+            .open_db(vbid, couchstore::DBOpenOptions::default().read_only())
+            .map_err(io::Error::other)?;
+        let mut keys = Vec::new();
+        db.docinfos_by_id_range(start_key, end_key, |doc_info| {
+            if !doc_info.deleted {
+                keys.push(doc_info.id);
+            }
+            keys.len() < limit
+        })
+        .map_err(io::Error::other)?;
+        Ok(keys)
+    }
+
+    fn scrub(&self, vbid: Vbid, on_read: &mut dyn FnMut(usize)) -> ScrubResult {
+        let file_name = self.db_file_path(vbid, self.get_db_revision(vbid));
+        if std::fs::metadata(&file_name).is_err() {
+            return ScrubResult::default();
+        }
+        let db = match self.open_db(vbid, couchstore::DBOpenOptions::default().read_only()) {
+            Ok(db) => db,
+            Err(e) => {
+                return ScrubResult {
+                    bytes_read: 0,
+                    corrupt_chunks: vec![format!("{}: {e}", file_name.display())],
+                }
+            }
+        };
+        let report = db.scrub(on_read);
+        ScrubResult {
+            bytes_read: report.bytes,
+            corrupt_chunks: report
+                .findings
+                .into_iter()
+                .map(|finding| {
+                    format!(
+                        "{} {} tree at {}: {}",
+                        file_name.display(),
+                        finding.tree,
+                        finding.position,
+                        finding.problem
+                    )
+                })
+                .collect(),
+        }
+    }
+
+    /// From the by-id tree's reduction, which files written before it was
+    /// kept lack until they are compacted
+    fn get_doc_counts(&self, vbid: Vbid) -> Option<couchstore::DocCounts> {
+        let file_name = self.db_file_path(vbid, self.get_db_revision(vbid));
+        if std::fs::metadata(file_name).is_err() {
+            return Some(couchstore::DocCounts::default());
         }
+        self.open_db(vbid, couchstore::DBOpenOptions::default().read_only())
+            .ok()?
+            .doc_counts()
+    }
+
+    fn truncate_to_clean_commit(&self, vbid: Vbid) -> Option<TruncatedCommits> {
+        let file_name = self.db_file_path(vbid, self.get_db_revision(vbid));
+        let mut db = self
+            .open_db(vbid, couchstore::DBOpenOptions::default().read_only())
+            .ok()?;
+        let old_high_seqno = db.header().update_seq;
+        let latest_end = db.header_end();
+        while !db.scrub(|_| {}).findings.is_empty() {
+            if let Err(e) = db.rewind_header() {
+                println!("No clean commit in {}: {e}", file_name.display());
+                return None;
+            }
+        }
+        let new_high_seqno = db.header().update_seq;
+        if db.header_end() < latest_end {
+            let truncate = || -> io::Result<()> {
+                let file = std::fs::OpenOptions::new().write(true).open(&file_name)?;
+                file.set_len(db.header_end())?;
+                file.sync_all()
+            };
+            if let Err(e) = truncate() {
+                println!("Failed to truncate {}: {e}", file_name.display());
+                return None;
+            }
+        }
+
+        let db = self
+            .open_db(vbid, couchstore::DBOpenOptions::default().read_only())
+            .ok()?;
+        self.read_vb_state_and_update_cache(&db, vbid);
+        Some(TruncatedCommits {
+            old_high_seqno,
+            new_high_seqno,
+        })
+    }
+
+    fn sync_pending_commits(&self) {
+        let mut sync_states = self.sync_states.lock();
+        sync_states.retain(|&vbid, sync_state| {
+            if sync_state.unsynced_bytes() == 0 {
+                return true;
+            }
+            // A vbucket whose file has gone has nothing left to sync
+            let Ok(mut db) = self.open_db(vbid, couchstore::DBOpenOptions::default().read_only())
+            else {
+                return false;
+            };
+            // Left unsynced to try again next time
+            if let Err(e) = db.sync(sync_state) {
+                println!("Failed to sync {vbid}: {e}");
+                return true;
+            }
+            self.stats.fsyncs.fetch_add(1, atomic::Ordering::Relaxed);
+            true
+        });
+    }
+
+    fn list_retained_headers(&self, vbid: Vbid) -> io::Result<Vec<RetainedHeader>> {
+        let file_name = self.db_file_path(vbid, self.get_db_revision(vbid));
+        if std::fs::metadata(file_name).is_err() {
+            return Ok(Vec::new());
+        }
+        let mut db = self
+            .open_db(vbid, couchstore::DBOpenOptions::default().read_only())
+            .map_err(io::Error::other)?;
+        let cutoff = self.config.pitr_max_history_age.map(|age| {
+            let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+            now.saturating_sub(Duration::from_secs(age)).as_nanos() as u64
+        });
+
+        let mut headers = Vec::new();
+        loop {
+            let header = db.header();
+            // The header written when the file was created has no data
+            if header.timestamp() == 0 {
+                break;
+            }
+            if !headers.is_empty() && cutoff.is_none_or(|cutoff| header.timestamp() < cutoff) {
+                break;
+            }
+            headers.push(RetainedHeader {
+                high_seqno: header.update_seq,
+                timestamp: header.timestamp(),
+                position: header.position(),
+            });
+            match db.rewind_header() {
+                Ok(()) => {}
+                Err(couchstore::CouchstoreError::NoHeader) => break,
+                Err(e) => return Err(io::Error::other(e)),
+            }
+        }
+        Ok(headers)
+    }
+
+    fn get_multi(&self, vbid: Vbid, keys: &[Vec<u8>]) -> io::Result<Vec<Option<Item>>> {
+        self.lookup_by_id(vbid, keys).map_err(io::Error::other)
     }
 }
 
@@ -258,6 +1785,7 @@ pub struct BySeqnoScanContext {
     pub document_count: u64,
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum ValueFilter {
     KeysOnly,
     ValuesCompressed,
@@ -271,6 +1799,7 @@ pub enum DocumentFilter {
     AllItemsAndDroppedCollections,
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum SnapshotSource {
     // Required for PITR
     Historical,
@@ -282,57 +1811,227 @@ pub enum SnapshotSource {
 
 pub struct Metadata {
     pub cas: u64,
+    /// The delete time for deleted documents
     pub expiry_time: u32,
     pub flags: u32,
+    pub delete_source: DeleteSource,
+    pub datatype: Datatype,
 }
 
 impl Metadata {
+    /// Size of the V1 encoding, which adds the flex code and datatype
+    pub const ENCODED_SIZE: usize = 18;
+
+    /// Size of the V3 encoding, which adds the conflict resolution mode and
+    /// the delete source. Only used for documents deleted by expiry.
+    pub const ENCODED_SIZE_V3: usize = 20;
+
+    /// Flex code marking the V1 metadata encoding
+    const FLEX_META_CODE: u8 = 0x01;
+
+    /// Delete source byte of the V3 encoding
+    const DELETE_SOURCE_TTL: u8 = 0x01;
+
     pub fn decode<R: io::Read>(mut r: R) -> Self {
         let cas = r.read_u64::<BigEndian>().unwrap();
         let expiry_time = r.read_u32::<BigEndian>().unwrap();
         let flags = r.read_u32::<LittleEndian>().unwrap();
+        // Older encodings end before the datatype or the delete source
+        let mut flex_code_and_datatype = [0; 2];
+        let datatype = match r.read_exact(&mut flex_code_and_datatype) {
+            Ok(()) => Datatype::from_bits_truncate(flex_code_and_datatype[1]),
+            Err(_) => Datatype::empty(),
+        };
+        let mut conflict_resolution_mode = [0; 1];
+        let delete_source = match r
+            .read_exact(&mut conflict_resolution_mode)
+            .and_then(|_| r.read_u8())
+        {
+            Ok(Self::DELETE_SOURCE_TTL) => DeleteSource::Ttl,
+            _ => DeleteSource::Explicit,
+        };
         Metadata {
             cas,
             expiry_time,
             flags,
+            delete_source,
+            datatype,
         }
     }
-}
 
-fn discover_db_files(dir: &str) -> Vec<String> {
-    let mut filenames = Vec::new();
-    for entry in std::fs::read_dir(dir).unwrap() {
-        let entry = entry.unwrap();
-        let file_name = entry.file_name();
-        let file_name = file_name.to_str().unwrap();
-        if file_name.contains(".couch.") && !file_name.ends_with(".compact") {
-            filenames.push(file_name.to_string());
+    pub fn encode<W: io::Write>(&self, mut w: W) -> io::Result<()> {
+        w.write_u64::<BigEndian>(self.cas)?;
+        w.write_u32::<BigEndian>(self.expiry_time)?;
+        w.write_u32::<LittleEndian>(self.flags)?;
+        w.write_u8(Self::FLEX_META_CODE)?;
+        w.write_u8(self.datatype.bits())?;
+        if self.delete_source == DeleteSource::Ttl {
+            // Conflict resolution mode, revision seqno
+            w.write_u8(0)?;
+            w.write_u8(Self::DELETE_SOURCE_TTL)?;
         }
+        Ok(())
     }
-    filenames
+}
+
+/// Build an item from a document's info, reading its value if needed
+fn make_item(
+    db: &couchstore::Db,
+    doc_info: couchstore::DocInfo,
+    value_filter: ValueFilter,
+) -> couchstore::CouchstoreResult<Item> {
+    let value = match value_filter {
+        _ if doc_info.deleted => None,
+        ValueFilter::KeysOnly => None,
+        ValueFilter::ValuesCompressed => db
+            .open_doc_with_docinfo(&doc_info, couchstore::OpenOptions::empty())?
+            .map(|doc| doc.data),
+        ValueFilter::ValuesDecompressed => db
+            .open_doc_with_docinfo(&doc_info, couchstore::OpenOptions::DECOMPRESS_DOC_BODIES)?
+            .map(|doc| doc.data),
+    };
+    let metadata = Metadata::decode(&doc_info.rev_meta[..]);
+    Ok(Item {
+        key: doc_info.id,
+        value,
+        cas: metadata.cas,
+        expiry_time: metadata.expiry_time,
+        flags: metadata.flags,
+        by_seqno: doc_info.db_seq,
+        rev_seqno: doc_info.rev_seq,
+        delete_source: metadata.delete_source,
+        datatype: metadata.datatype,
+    })
 }
 
 fn make_revision_map(config: &CouchKVStoreConfig) -> Arc<RevisionMap> {
     let map = Arc::new(RevisionMap::default());
-    map.write().resize(config.get_cache_size(), 0);
+    // New vbucket files start at revision 1
+    map.write().resize(config.get_cache_size(), 1);
     map
 }
 
-fn get_db_file_name(db_name: &str, vbid: Vbid, rev: u64) -> String {
-    format!("{}/{}.couch.{}", db_name, vbid, rev)
+/// The document and info an item is saved as
+fn couchstore_doc(item: &Item) -> (Option<couchstore::Doc>, couchstore::DocInfo) {
+    // Couchstore compresses the bodies itself
+    let mut item = item.clone();
+    item.inflate();
+    let mut rev_meta = Vec::with_capacity(Metadata::ENCODED_SIZE_V3);
+    Metadata {
+        cas: item.cas,
+        expiry_time: item.expiry_time,
+        flags: item.flags,
+        delete_source: if item.value.is_none() {
+            item.delete_source
+        } else {
+            DeleteSource::Explicit
+        },
+        datatype: item.datatype,
+    }
+    .encode(&mut rev_meta)
+    .unwrap();
+
+    let info = couchstore::DocInfo {
+        id: item.key.clone(),
+        db_seq: item.by_seqno,
+        rev_seq: item.rev_seqno,
+        rev_meta,
+        deleted: item.value.is_none(),
+        content_meta: couchstore::ContentMetaFlag::IS_COMPRESSED,
+        bp: 0,
+        physical_size: 0,
+    };
+    let doc = item
+        .value
+        .map(|data| couchstore::Doc { id: item.key, data });
+    (doc, info)
+}
+
+pub(crate) const LOCAL_DOC_KEY_VBSTATE: &str = "_local/vbstate";
+pub(crate) const LOCAL_DOC_KEY_BACKUP_CURSORS: &str = "_local/backup_cursors";
+/// The auxiliary tree holding the expiry index
+pub(crate) const EXPIRY_TREE: &str = "expiry";
+
+/// An item's entry in the expiry index: the big endian expiry time then the
+/// key, so entries are in expiry order. Only live items which expire have
+/// one.
+pub(crate) fn expiry_index_key(expiry_time: u32, key: &[u8]) -> Vec<u8> {
+    let mut index_key = Vec::with_capacity(4 + key.len());
+    index_key.extend_from_slice(&expiry_time.to_be_bytes());
+    index_key.extend_from_slice(key);
+    index_key
+}
+
+/// Every entry for an item expiring at or before now is below this
+pub(crate) fn expiry_index_end(now: u32) -> Vec<u8> {
+    now.saturating_add(1).to_be_bytes().to_vec()
 }
 
-const LOCAL_DOC_KEY_VBSTATE: &str = "_local/vbstate";
+/// Backup cursors are kept as JSON with the seqnos as strings, as in the
+/// vbucket state
+pub(crate) fn encode_backup_cursors(cursors: &BTreeMap<String, u64>) -> Vec<u8> {
+    let cursors: BTreeMap<&String, String> = cursors
+        .iter()
+        .map(|(name, seqno)| (name, seqno.to_string()))
+        .collect();
+    serde_json::to_vec(&cursors).unwrap()
+}
+
+pub(crate) fn decode_backup_cursors(json: &[u8]) -> io::Result<BTreeMap<String, u64>> {
+    let invalid = |e: String| io::Error::new(io::ErrorKind::InvalidData, e);
+    let cursors: BTreeMap<String, String> =
+        serde_json::from_slice(json).map_err(|e| invalid(format!("bad backup cursors: {e}")))?;
+    cursors
+        .into_iter()
+        .map(|(name, seqno)| match seqno.parse() {
+            Ok(seqno) => Ok((name, seqno)),
+            Err(_) => Err(invalid(format!(
+                "bad seqno {seqno:?} of backup cursor {name}"
+            ))),
+        })
+        .collect()
+}
+
+/// A vbucket's persisted state from its file's header and vbstate local
+/// document
+fn vb_state_from_header(
+    header: &couchstore::Header,
+    local_doc: Option<couchstore::LocalDoc>,
+) -> Result<VBucketState, String> {
+    // A file which never had its state written
+    let mut vb_state =
+        parse_local_vb_state(local_doc)?.unwrap_or_else(|| VBucketState::new(State::Dead));
+
+    vb_state.high_seqno = header.update_seq as i64;
+    vb_state.purge_seqno = header.purge_seq;
+
+    // MB-17517: If the maxCas on disk was invalid then don't use it -
+    // instead rebuild from the items we load from disk (i.e. as per
+    // an upgrade from an earlier version).
+    if vb_state.max_cas == u64::MAX {
+        vb_state.max_cas = 0;
+    }
+
+    Ok(vb_state)
+}
 
-fn get_local_vb_state(db: &mut couchstore::Db) -> serde_json::Value {
-    let doc: couchstore::LocalDoc = db.open_local_document(LOCAL_DOC_KEY_VBSTATE).unwrap();
-    let json = doc.json.unwrap();
-    serde_json::from_slice(&json).unwrap()
+fn parse_local_vb_state(doc: Option<couchstore::LocalDoc>) -> Result<Option<VBucketState>, String> {
+    let Some(json) = doc.and_then(|doc| doc.json) else {
+        return Ok(None);
+    };
+    let value = serde_json::from_slice(&json).map_err(|e| e.to_string())?;
+    if !crate::namespace_upgrade::namespaces_supported(&value) {
+        return Err("written before collections, upgrade it with couchfile_upgrade".to_string());
+    }
+    VBucketState::from_json(&json)
+        .map(Some)
+        .map_err(|e| e.to_string())
 }
 
 #[cfg(test)]
 mod test {
     use super::*;
+    use crate::item::{DEFAULT_MAX_ITEM_SIZE, DEFAULT_MAX_KEY_SIZE};
 
     /// Test that a store can be initialised from an existing travel sample bucket
     #[test]
@@ -340,9 +2039,795 @@ mod test {
         let config = CouchKVStoreConfig {
             max_vbuckets: 1024,
             db_name: "../test-data/travel-sample".to_string(),
+            data_paths: Vec::new(),
             max_shards: 1,
             shard_id: 0,
+            history_retention: false,
+            max_key_size: DEFAULT_MAX_KEY_SIZE,
+            max_item_size: DEFAULT_MAX_ITEM_SIZE,
+            pitr_max_history_age: None,
+            sync_policy: couchstore::SyncPolicy::EveryCommit,
+            dsync: false,
+            btree_tuning: couchstore::BtreeTuning::default(),
+            front_coded_keys: false,
+            compaction_direct_io: false,
         };
         CouchKVStore::new(config);
     }
+
+    #[test]
+    fn test_unreadable_vb_state() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = CouchKVStoreConfig {
+            max_vbuckets: 4,
+            db_name: dir.path().to_str().unwrap().to_string(),
+            data_paths: Vec::new(),
+            max_shards: 1,
+            shard_id: 0,
+            history_retention: false,
+            max_key_size: DEFAULT_MAX_KEY_SIZE,
+            max_item_size: DEFAULT_MAX_ITEM_SIZE,
+            pitr_max_history_age: None,
+            sync_policy: couchstore::SyncPolicy::EveryCommit,
+            dsync: false,
+            btree_tuning: couchstore::BtreeTuning::default(),
+            front_coded_keys: false,
+            compaction_direct_io: false,
+        };
+        {
+            let store = CouchKVStore::new(config.clone());
+            let vb_state = VBucketState::new(State::Active);
+            for vbid in [1, 2] {
+                store.commit(Vbid::new(vbid), &[], &vb_state).unwrap();
+            }
+            let mut db = store
+                .open_db(Vbid::new(1), couchstore::DBOpenOptions::default())
+                .unwrap();
+            db.save_local_document(couchstore::LocalDoc::new(
+                LOCAL_DOC_KEY_VBSTATE,
+                b"nonsense".to_vec(),
+            ))
+            .unwrap();
+            db.try_commit().unwrap();
+        }
+
+        // The vbucket is left out of warmup rather than failing the others
+        let store = CouchKVStore::new(config);
+        assert_eq!(store.get_cached_vb_state(Vbid::new(1)), None);
+        assert!(store.get_cached_vb_state(Vbid::new(2)).is_some());
+        assert_eq!(
+            store
+                .get_stats()
+                .open_failures
+                .load(atomic::Ordering::Relaxed),
+            1
+        );
+    }
+
+    #[test]
+    fn test_vb_state_cache() {
+        let cache = VBStateCache::new(2);
+        assert_eq!(cache.snapshot(), [None, None]);
+        let vb_state = VBucketState::new(State::Active);
+        cache.set(0, Some(vb_state.clone()));
+        // A slot being written doesn't hold up reads of the others
+        let writing = cache.slots[1].write();
+        assert_eq!(cache.get(0), Some(vb_state.clone()));
+        drop(writing);
+
+        // Commits to each vbucket from their own threads, while another
+        // reads the states
+        let dir = tempfile::tempdir().unwrap();
+        let store = CouchKVStore::new(CouchKVStoreConfig {
+            max_vbuckets: 4,
+            db_name: dir.path().to_str().unwrap().to_string(),
+            data_paths: Vec::new(),
+            max_shards: 1,
+            shard_id: 0,
+            history_retention: false,
+            max_key_size: DEFAULT_MAX_KEY_SIZE,
+            max_item_size: DEFAULT_MAX_ITEM_SIZE,
+            pitr_max_history_age: None,
+            sync_policy: couchstore::SyncPolicy::EveryCommit,
+            dsync: false,
+            btree_tuning: couchstore::BtreeTuning::default(),
+            front_coded_keys: false,
+            compaction_direct_io: false,
+        });
+        let done = atomic::AtomicBool::new(false);
+        std::thread::scope(|scope| {
+            let reader = scope.spawn(|| {
+                let mut reads = 0;
+                while !done.load(atomic::Ordering::Relaxed) {
+                    for state in store.list_persisted_vbuckets().into_iter().flatten() {
+                        assert!((1..=10).contains(&state.high_seqno));
+                    }
+                    reads += 1;
+                }
+                reads
+            });
+            let writers: Vec<_> = (0..4)
+                .map(|vbid| {
+                    let (store, vb_state) = (&store, &vb_state);
+                    scope.spawn(move || {
+                        for seqno in 1..=10 {
+                            let item = Arc::new(Item {
+                                key: format!("key_{seqno}").into_bytes(),
+                                value: Some(b"value".to_vec()),
+                                cas: seqno,
+                                expiry_time: 0,
+                                flags: 0,
+                                by_seqno: seqno,
+                                rev_seqno: 1,
+                                delete_source: DeleteSource::Explicit,
+                                datatype: Datatype::empty(),
+                            });
+                            store.commit(Vbid::new(vbid), &[item], vb_state).unwrap();
+                        }
+                    })
+                })
+                .collect();
+            for writer in writers {
+                writer.join().unwrap();
+            }
+            done.store(true, atomic::Ordering::Relaxed);
+            assert!(reader.join().unwrap() > 0);
+        });
+        for vbid in 0..4 {
+            let state = store.get_cached_vb_state(Vbid::new(vbid)).unwrap();
+            assert_eq!(state.high_seqno, 10);
+        }
+    }
+
+    #[test]
+    fn test_snapshot_to() {
+        let dir = tempfile::tempdir().unwrap();
+        let backup_dir = tempfile::tempdir().unwrap();
+        let store = CouchKVStore::new(CouchKVStoreConfig {
+            max_vbuckets: 4,
+            db_name: dir.path().to_str().unwrap().to_string(),
+            data_paths: Vec::new(),
+            max_shards: 1,
+            shard_id: 0,
+            history_retention: false,
+            max_key_size: DEFAULT_MAX_KEY_SIZE,
+            max_item_size: DEFAULT_MAX_ITEM_SIZE,
+            pitr_max_history_age: None,
+            sync_policy: couchstore::SyncPolicy::EveryCommit,
+            dsync: false,
+            btree_tuning: couchstore::BtreeTuning::default(),
+            front_coded_keys: false,
+            compaction_direct_io: false,
+        });
+        let vbid = Vbid::new(1);
+        let vb_state = VBucketState::new(State::Active);
+        let item = |seqno| {
+            Arc::new(Item {
+                key: format!("key_{seqno}").into_bytes(),
+                value: Some(vec![0; 100]),
+                cas: seqno,
+                expiry_time: 0,
+                flags: 0,
+                by_seqno: seqno,
+                rev_seqno: 1,
+                delete_source: DeleteSource::Explicit,
+                datatype: Datatype::empty(),
+            })
+        };
+        store.commit(vbid, &[item(1), item(2)], &vb_state).unwrap();
+
+        let snapshot = store.snapshot_to(vbid, backup_dir.path()).unwrap();
+        // Later commits don't reach the snapshot
+        store.commit(vbid, &[item(3)], &vb_state).unwrap();
+        let db = couchstore::Db::open(&snapshot, couchstore::DBOpenOptions::default().read_only())
+            .unwrap();
+        assert_eq!(db.header().update_seq, 2);
+    }
+
+    #[test]
+    fn test_bulk_load() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = CouchKVStore::new(CouchKVStoreConfig {
+            max_vbuckets: 4,
+            db_name: dir.path().to_str().unwrap().to_string(),
+            data_paths: Vec::new(),
+            max_shards: 1,
+            shard_id: 0,
+            history_retention: false,
+            max_key_size: DEFAULT_MAX_KEY_SIZE,
+            max_item_size: DEFAULT_MAX_ITEM_SIZE,
+            pitr_max_history_age: None,
+            sync_policy: couchstore::SyncPolicy::EveryCommit,
+            dsync: false,
+            btree_tuning: couchstore::BtreeTuning::default(),
+            front_coded_keys: false,
+            compaction_direct_io: false,
+        });
+        let items: Vec<_> = (1..=500)
+            .map(|seqno| {
+                Arc::new(Item {
+                    key: format!("key_{}", 500 - seqno).into_bytes(),
+                    value: (seqno % 50 != 0).then(|| vec![seqno as u8; 100]),
+                    cas: seqno,
+                    expiry_time: if seqno % 7 == 0 { 1000 } else { 0 },
+                    flags: 0,
+                    by_seqno: seqno,
+                    rev_seqno: 1,
+                    delete_source: DeleteSource::Explicit,
+                    datatype: Datatype::empty(),
+                })
+            })
+            .collect();
+        let mut vb_state = VBucketState::new(State::Active);
+        vb_state.high_seqno = 500;
+
+        // Loaded into one vbucket, committed into another, they're the same
+        let loaded = Vbid::new(1);
+        let mut load = store.begin_bulk_load(loaded).unwrap();
+        for item in &items {
+            load.add(item).unwrap();
+        }
+        assert!(store.get_cached_vb_state(loaded).is_none());
+        store.finish_bulk_load(load, &vb_state).unwrap();
+        let committed = Vbid::new(2);
+        store.commit(committed, &items, &vb_state).unwrap();
+
+        let scan = |vbid| {
+            let mut scanned = Vec::new();
+            store.scan(
+                vbid,
+                0,
+                ValueFilter::ValuesDecompressed,
+                ScanErrorPolicy::Abort,
+                &mut |item| {
+                    scanned.push((
+                        item.key,
+                        item.value,
+                        item.by_seqno,
+                        item.cas,
+                        item.expiry_time,
+                    ))
+                },
+            );
+            scanned
+        };
+        assert_eq!(scan(loaded).len(), 500);
+        assert_eq!(scan(loaded), scan(committed));
+        assert_eq!(store.get_cached_vb_state(loaded).unwrap().high_seqno, 500);
+        assert_eq!(store.get_expired_keys(loaded, 2000).unwrap().len(), 70);
+        assert_eq!(
+            store.get_expired_keys(loaded, 2000).unwrap(),
+            store.get_expired_keys(committed, 2000).unwrap()
+        );
+
+        // Only a vbucket without items can be loaded
+        assert!(store.begin_bulk_load(loaded).is_err());
+    }
+
+    #[test]
+    fn test_data_paths() {
+        let dirs: Vec<tempfile::TempDir> = (0..2).map(|_| tempfile::tempdir().unwrap()).collect();
+        let paths: Vec<String> = dirs
+            .iter()
+            .map(|dir| dir.path().to_str().unwrap().to_string())
+            .collect();
+        let vb_state = VBucketState::new(State::Active);
+        for shard_id in 0..2 {
+            let store = CouchKVStore::new(CouchKVStoreConfig {
+                max_vbuckets: 4,
+                db_name: paths[0].clone(),
+                data_paths: vec![paths[1].clone()],
+                max_shards: 2,
+                shard_id,
+                history_retention: false,
+                max_key_size: DEFAULT_MAX_KEY_SIZE,
+                max_item_size: DEFAULT_MAX_ITEM_SIZE,
+                pitr_max_history_age: None,
+                sync_policy: couchstore::SyncPolicy::EveryCommit,
+                dsync: false,
+                btree_tuning: couchstore::BtreeTuning::default(),
+                front_coded_keys: false,
+                compaction_direct_io: false,
+            });
+            store.commit(Vbid::new(shard_id), &[], &vb_state).unwrap();
+        }
+        // Each shard's files are in its own directory
+        assert!(Path::new(&format!("{}/0.couch.1", paths[0])).exists());
+        assert!(!Path::new(&format!("{}/1.couch.1", paths[0])).exists());
+        assert!(Path::new(&format!("{}/1.couch.1", paths[1])).exists());
+
+        // Adding a third path moves the shards it and the second now get
+        let third = "/data3".to_string();
+        let moves = plan_data_paths_change(
+            &paths[0],
+            4,
+            &[paths[1].clone()],
+            &[paths[1].clone(), third.clone()],
+        );
+        assert_eq!(
+            moves,
+            vec![
+                ShardMove {
+                    shard_id: 2,
+                    from: paths[0].clone(),
+                    to: third,
+                },
+                ShardMove {
+                    shard_id: 3,
+                    from: paths[1].clone(),
+                    to: paths[0].clone(),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_retained_headers() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = CouchKVStoreConfig {
+            max_vbuckets: 4,
+            db_name: dir.path().to_str().unwrap().to_string(),
+            data_paths: Vec::new(),
+            max_shards: 1,
+            shard_id: 0,
+            history_retention: false,
+            max_key_size: DEFAULT_MAX_KEY_SIZE,
+            max_item_size: DEFAULT_MAX_ITEM_SIZE,
+            pitr_max_history_age: Some(3600),
+            sync_policy: couchstore::SyncPolicy::EveryCommit,
+            dsync: false,
+            btree_tuning: couchstore::BtreeTuning::default(),
+            front_coded_keys: false,
+            compaction_direct_io: false,
+        };
+        let store = CouchKVStore::new(config.clone());
+        let vbid = Vbid::new(1);
+        assert!(store.list_retained_headers(vbid).unwrap().is_empty());
+        let vb_state = VBucketState::new(State::Active);
+        for seqno in 1..=3 {
+            let item = Arc::new(Item {
+                key: format!("key_{seqno}").into_bytes(),
+                value: Some(vec![0; 10]),
+                cas: seqno,
+                expiry_time: 0,
+                flags: 0,
+                by_seqno: seqno,
+                rev_seqno: 1,
+                delete_source: DeleteSource::Explicit,
+                datatype: Datatype::empty(),
+            });
+            store.commit(vbid, &[item], &vb_state).unwrap();
+        }
+
+        let headers = store.list_retained_headers(vbid).unwrap();
+        let seqnos: Vec<u64> = headers.iter().map(|header| header.high_seqno).collect();
+        assert_eq!(seqnos, [3, 2, 1]);
+        assert!(headers.windows(2).all(|w| w[0].timestamp >= w[1].timestamp));
+        // Compacting would lose them
+        assert_eq!(store.compact(vbid), 0);
+        assert_eq!(store.get_db_revision(vbid), 1);
+        assert_eq!(store.list_retained_headers(vbid).unwrap(), headers);
+        assert_eq!(
+            store
+                .get_stats()
+                .compactions
+                .load(atomic::Ordering::Relaxed),
+            0
+        );
+
+        // Without point in time recovery only the latest is retained
+        let store = CouchKVStore::new(CouchKVStoreConfig {
+            pitr_max_history_age: None,
+            sync_policy: couchstore::SyncPolicy::EveryCommit,
+            dsync: false,
+            btree_tuning: couchstore::BtreeTuning::default(),
+            front_coded_keys: false,
+            compaction_direct_io: false,
+            ..config
+        });
+        assert_eq!(store.list_retained_headers(vbid).unwrap(), headers[..1]);
+        assert!(store.compact(vbid) > 0);
+        let mut stats = BTreeMap::new();
+        store.get_stats().add_stats(&mut |name, value| {
+            stats.insert(name.to_string(), value.to_string());
+        });
+        assert_eq!(stats["compactions"], "1");
+        assert!(stats.contains_key("compaction_duration_us"));
+        let seqnos: Vec<u64> = store
+            .list_retained_headers(vbid)
+            .unwrap()
+            .iter()
+            .map(|header| header.high_seqno)
+            .collect();
+        assert_eq!(seqnos, [3]);
+
+        // A file which can't be opened is skipped
+        let path = store.db_file_path(vbid, store.get_db_revision(vbid));
+        std::fs::write(&path, vec![0; 8192]).unwrap();
+        assert_eq!(store.compact(vbid), 0);
+        assert_eq!(store.get_db_revision(vbid), 2);
+    }
+
+    #[test]
+    fn test_backup_cursors() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = CouchKVStoreConfig {
+            max_vbuckets: 4,
+            db_name: dir.path().to_str().unwrap().to_string(),
+            data_paths: Vec::new(),
+            max_shards: 1,
+            shard_id: 0,
+            history_retention: false,
+            max_key_size: DEFAULT_MAX_KEY_SIZE,
+            max_item_size: DEFAULT_MAX_ITEM_SIZE,
+            pitr_max_history_age: None,
+            sync_policy: couchstore::SyncPolicy::EveryCommit,
+            dsync: false,
+            btree_tuning: couchstore::BtreeTuning::default(),
+            front_coded_keys: false,
+            compaction_direct_io: false,
+        };
+        let vbid = Vbid::new(1);
+        let cursors = BTreeMap::from([("daily".to_string(), 10), ("weekly".to_string(), 3)]);
+        let stores: [Box<dyn KVStore>; 2] = [
+            Box::new(CouchKVStore::new(config.clone())),
+            Box::new(crate::memory_kv_store::MemoryKVStore::new(config.clone())),
+        ];
+        for store in stores {
+            assert!(store.get_backup_cursors(vbid).unwrap().is_empty());
+            store.set_backup_cursors(vbid, &cursors).unwrap();
+            assert_eq!(store.get_backup_cursors(vbid).unwrap(), cursors);
+            // Committing items keeps the cursors
+            store
+                .commit(vbid, &[], &VBucketState::new(State::Active))
+                .unwrap();
+            assert_eq!(store.get_backup_cursors(vbid).unwrap(), cursors);
+        }
+
+        // The cursors are persisted
+        let store = CouchKVStore::new(config);
+        assert_eq!(store.get_backup_cursors(vbid).unwrap(), cursors);
+
+        // A malformed document is an error rather than a panic
+        for json in [&br#"{"daily": "ten"}"#[..], b"[1"] {
+            assert_eq!(
+                decode_backup_cursors(json).unwrap_err().kind(),
+                io::ErrorKind::InvalidData
+            );
+        }
+    }
+
+    #[test]
+    fn test_history_retention() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = CouchKVStoreConfig {
+            max_vbuckets: 4,
+            db_name: dir.path().to_str().unwrap().to_string(),
+            data_paths: Vec::new(),
+            max_shards: 1,
+            shard_id: 0,
+            history_retention: true,
+            max_key_size: DEFAULT_MAX_KEY_SIZE,
+            max_item_size: DEFAULT_MAX_ITEM_SIZE,
+            pitr_max_history_age: None,
+            sync_policy: couchstore::SyncPolicy::EveryCommit,
+            dsync: false,
+            btree_tuning: couchstore::BtreeTuning::default(),
+            front_coded_keys: false,
+            compaction_direct_io: false,
+        };
+        let vbid = Vbid::new(1);
+        let vb_state = VBucketState::new(State::Active);
+        let item = |key: &str, seqno: u64| {
+            Arc::new(Item {
+                key: key.as_bytes().to_vec(),
+                value: Some(seqno.to_string().into_bytes()),
+                cas: seqno,
+                expiry_time: 0,
+                flags: 0,
+                by_seqno: seqno,
+                rev_seqno: seqno,
+                delete_source: DeleteSource::Explicit,
+                datatype: Datatype::empty(),
+            })
+        };
+        let stores: [Box<dyn KVStore>; 2] = [
+            Box::new(CouchKVStore::new(config.clone())),
+            Box::new(crate::memory_kv_store::MemoryKVStore::new(config)),
+        ];
+        for store in stores {
+            store
+                .commit(vbid, &[item("a", 1), item("b", 2)], &vb_state)
+                .unwrap();
+            store.commit(vbid, &[item("a", 3)], &vb_state).unwrap();
+
+            let mut head = Vec::new();
+            store.scan(
+                vbid,
+                0,
+                ValueFilter::KeysOnly,
+                ScanErrorPolicy::Abort,
+                &mut |item| head.push(item.by_seqno),
+            );
+            assert_eq!(head, vec![2, 3]);
+            let mut all = Vec::new();
+            store.scan_all_versions(
+                vbid,
+                0,
+                ValueFilter::ValuesDecompressed,
+                ScanErrorPolicy::Abort,
+                &mut |item| all.push(item.value.unwrap()),
+            );
+            assert_eq!(all, vec![b"1".to_vec(), b"2".to_vec(), b"3".to_vec()]);
+        }
+    }
+
+    #[test]
+    fn test_sync_policy() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = CouchKVStore::new(CouchKVStoreConfig {
+            max_vbuckets: 4,
+            db_name: dir.path().to_str().unwrap().to_string(),
+            data_paths: Vec::new(),
+            max_shards: 1,
+            shard_id: 0,
+            history_retention: false,
+            max_key_size: DEFAULT_MAX_KEY_SIZE,
+            max_item_size: DEFAULT_MAX_ITEM_SIZE,
+            pitr_max_history_age: None,
+            sync_policy: couchstore::SyncPolicy::Bytes(16 * 1024),
+            dsync: false,
+            btree_tuning: couchstore::BtreeTuning::default(),
+            front_coded_keys: false,
+            compaction_direct_io: false,
+        });
+        let vbid = Vbid::new(1);
+        let vb_state = VBucketState::new(State::Active);
+        for seqno in 1..=22 {
+            let item = Arc::new(Item {
+                key: format!("key_{seqno}").into_bytes(),
+                // Hashed so compression doesn't shrink it away
+                value: Some(
+                    (0..4096u64)
+                        .map(|i| {
+                            ((i + seqno * 4096).wrapping_mul(0x9e37_79b9_7f4a_7c15) >> 56) as u8
+                        })
+                        .collect(),
+                ),
+                cas: seqno,
+                expiry_time: 0,
+                flags: 0,
+                by_seqno: seqno,
+                rev_seqno: 1,
+                delete_source: DeleteSource::Explicit,
+                datatype: Datatype::empty(),
+            });
+            store.commit(vbid, &[item], &vb_state).unwrap();
+        }
+        let stats = store.get_stats();
+        let fsyncs = stats.fsyncs.load(atomic::Ordering::Relaxed);
+        assert_eq!(stats.commits.load(atomic::Ordering::Relaxed), 22);
+        assert!(fsyncs > 0 && fsyncs < 22, "{fsyncs} fsyncs");
+        // Only the latest commits are kept for the headers stats
+        let headers = stats.recent_headers(vbid);
+        assert_eq!(headers.len(), RECENT_HEADERS);
+        assert_eq!(headers[0].high_seqno, 22);
+        assert!(headers[0].position > headers[1].position);
+
+        store.sync_pending_commits();
+        assert_eq!(stats.fsyncs.load(atomic::Ordering::Relaxed), fsyncs + 1);
+        store.sync_pending_commits();
+        assert_eq!(stats.fsyncs.load(atomic::Ordering::Relaxed), fsyncs + 1);
+
+        let items = store
+            .get_multi(vbid, &[b"key_1".to_vec(), b"key_22".to_vec()])
+            .unwrap();
+        assert!(items.iter().all(Option::is_some));
+        assert_eq!(store.get_cached_vb_state(vbid).unwrap().high_seqno, 22);
+    }
+
+    #[test]
+    fn test_disk_version_upgrade() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = CouchKVStoreConfig {
+            max_vbuckets: 4,
+            db_name: dir.path().to_str().unwrap().to_string(),
+            data_paths: Vec::new(),
+            max_shards: 1,
+            shard_id: 0,
+            history_retention: false,
+            max_key_size: DEFAULT_MAX_KEY_SIZE,
+            max_item_size: DEFAULT_MAX_ITEM_SIZE,
+            pitr_max_history_age: None,
+            sync_policy: couchstore::SyncPolicy::EveryCommit,
+            dsync: false,
+            btree_tuning: couchstore::BtreeTuning::default(),
+            front_coded_keys: false,
+            compaction_direct_io: false,
+        };
+        // A file left by an older engine
+        let vbid = Vbid::new(1);
+        let old_file = dir.path().join("1.couch.1");
+        couchstore::Db::open(
+            &old_file,
+            couchstore::DBOpenOptions::default().disk_version(couchstore::DiskVersion::Eleven),
+        )
+        .unwrap()
+        .commit();
+
+        let store = CouchKVStore::new(config.clone());
+        let item = Arc::new(Item {
+            key: b"key".to_vec(),
+            value: Some(b"value".to_vec()),
+            cas: 1,
+            expiry_time: 0,
+            flags: 0,
+            by_seqno: 1,
+            rev_seqno: 1,
+            delete_source: DeleteSource::Explicit,
+            datatype: Datatype::empty(),
+        });
+        // It's written in its own format until compacted
+        store
+            .commit(vbid, &[item], &VBucketState::new(State::Active))
+            .unwrap();
+        let stats = store.get_stats();
+        assert_eq!(stats.files_by_disk_version()[&11], 1);
+        // Every version is counted, even without files
+        assert_eq!(
+            stats
+                .files_by_disk_version()
+                .into_keys()
+                .collect::<Vec<_>>(),
+            [11, 12, 13, 14, 15]
+        );
+
+        store.purge_tombstones(vbid, 0, u64::MAX).unwrap();
+        assert!(!old_file.exists());
+        assert_eq!(stats.files_by_disk_version()[&11], 0);
+        assert_eq!(stats.files_by_disk_version()[&13], 1);
+        assert_eq!(stats.files_upgraded.load(atomic::Ordering::Relaxed), 1);
+        // The one item's leaves, with no interior nodes
+        assert!(
+            stats
+                .compaction_avg_kv_node_size
+                .load(atomic::Ordering::Relaxed)
+                > 0
+        );
+        assert_eq!(
+            stats
+                .compaction_avg_kp_node_size
+                .load(atomic::Ordering::Relaxed),
+            0
+        );
+
+        let store = CouchKVStore::new(config.clone());
+        let items = store.get_multi(vbid, &[b"key".to_vec()]).unwrap();
+        assert_eq!(
+            items[0].as_ref().unwrap().value.as_deref(),
+            Some(&b"value"[..])
+        );
+        assert_eq!(store.get_cached_vb_state(vbid).unwrap().high_seqno, 1);
+
+        // Front-coding keys moves it on to version 14, this time written
+        // with O_DIRECT
+        let store = CouchKVStore::new(CouchKVStoreConfig {
+            front_coded_keys: true,
+            compaction_direct_io: true,
+            ..config
+        });
+        store.purge_tombstones(vbid, 0, u64::MAX).unwrap();
+        let stats = store.get_stats();
+        assert_eq!(stats.files_by_disk_version()[&14], 1);
+        let direct = stats
+            .compaction_direct_bytes
+            .load(atomic::Ordering::Relaxed);
+        let buffered = stats
+            .compaction_buffered_bytes
+            .load(atomic::Ordering::Relaxed);
+        if stats
+            .compaction_direct_io_fallbacks
+            .load(atomic::Ordering::Relaxed)
+            == 0
+            && cfg!(target_os = "linux")
+        {
+            assert!(direct > 0 && buffered == 0);
+        } else {
+            assert!(buffered > 0);
+        }
+        let items = store.get_multi(vbid, &[b"key".to_vec()]).unwrap();
+        assert!(items[0].is_some());
+    }
+
+    #[test]
+    fn test_scan_error_policy() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = CouchKVStore::new(CouchKVStoreConfig {
+            max_vbuckets: 4,
+            db_name: dir.path().to_str().unwrap().to_string(),
+            data_paths: Vec::new(),
+            max_shards: 1,
+            shard_id: 0,
+            history_retention: false,
+            max_key_size: DEFAULT_MAX_KEY_SIZE,
+            max_item_size: DEFAULT_MAX_ITEM_SIZE,
+            pitr_max_history_age: None,
+            sync_policy: couchstore::SyncPolicy::EveryCommit,
+            dsync: false,
+            btree_tuning: couchstore::BtreeTuning::default(),
+            front_coded_keys: false,
+            compaction_direct_io: false,
+        });
+        let vbid = Vbid::new(1);
+        // Hashed so compression leaves it whole to be found in the file
+        let value = |seqno: u64| -> Vec<u8> {
+            (0..256u64)
+                .map(|i| ((i + seqno * 256).wrapping_mul(0x9e37_79b9_7f4a_7c15) >> 56) as u8)
+                .collect()
+        };
+        let items: Vec<_> = (1..=3)
+            .map(|seqno| {
+                Arc::new(Item {
+                    key: format!("key_{seqno}").into_bytes(),
+                    value: Some(value(seqno)),
+                    cas: seqno,
+                    expiry_time: 0,
+                    flags: 0,
+                    by_seqno: seqno,
+                    rev_seqno: 1,
+                    delete_source: DeleteSource::Explicit,
+                    datatype: Datatype::empty(),
+                })
+            })
+            .collect();
+        store
+            .commit(vbid, &items, &VBucketState::new(State::Active))
+            .unwrap();
+
+        // Damage the second item's body
+        let path = dir.path().join("1.couch.1");
+        let mut file = std::fs::read(&path).unwrap();
+        let body = value(2);
+        let pos = file
+            .windows(body.len())
+            .position(|window| window == body)
+            .unwrap();
+        file[pos + 100] ^= 0xff;
+        std::fs::write(&path, file).unwrap();
+
+        let scan = |on_error| {
+            let mut seqnos = Vec::new();
+            let result = store.scan(
+                vbid,
+                0,
+                ValueFilter::ValuesDecompressed,
+                on_error,
+                &mut |item| seqnos.push(item.by_seqno),
+            );
+            (seqnos, result)
+        };
+        let (seqnos, result) = scan(ScanErrorPolicy::Abort);
+        assert_eq!(seqnos, [1]);
+        assert!(result.aborted);
+        assert!(!result.is_complete());
+
+        let (seqnos, result) = scan(ScanErrorPolicy::SkipAndReport);
+        assert_eq!(seqnos, [1, 3]);
+        assert!(!result.aborted);
+        assert_eq!(result.errors.len(), 1);
+        assert!(
+            result.errors[0].starts_with("seqno 2:"),
+            "{:?}",
+            result.errors
+        );
+
+        // A file which can't be opened ends the scan whatever the policy
+        std::fs::write(&path, vec![0; 8192]).unwrap();
+        for on_error in [ScanErrorPolicy::Abort, ScanErrorPolicy::SkipAndReport] {
+            let (seqnos, result) = scan(on_error);
+            assert!(seqnos.is_empty());
+            assert!(result.aborted);
+            assert_eq!(result.errors.len(), 1);
+        }
+    }
 }
@@ -1,11 +1,44 @@
+pub mod api;
+pub mod audit;
+pub mod checkpoint_manager;
+pub mod clock;
+pub mod collections;
+pub mod compaction;
+pub mod compression;
+pub mod data_dir;
+pub mod dcp;
+pub mod disk_space;
+pub mod doc_trace;
 pub mod ep_bucket;
+pub mod error;
 pub mod failover_table;
+pub mod gen_dataset;
 pub mod hash_table;
+pub mod health;
+pub mod hlc;
+pub mod io_throttle;
 pub mod item;
+pub mod key_transform;
 pub mod kv_shard;
 pub mod kv_store;
+pub mod memory_kv_store;
+pub mod memory_tracker;
+pub mod namespace_upgrade;
+pub mod nexus_kv_store;
+pub mod observer;
+pub mod op_trace;
+pub mod reconcile;
+pub mod reshard;
+#[cfg(feature = "rocksdb")]
+pub mod rocksdb_kv_store;
+pub mod seqno_allocator;
+pub mod stats;
 pub mod stored_value;
+pub mod ttl_update;
+pub mod typed_bucket;
+pub mod validation;
 pub mod vbucket;
+pub mod vbucket_hash;
 pub mod vbucket_map;
 pub mod warmup;
 
@@ -14,4 +47,222 @@ pub struct Config {
     pub max_vbuckets: u16,
     pub max_shards: u16,
     pub dbname: String,
+    /// More directories for the data files, on other disks. The shards are
+    /// spread over dbname and these, so changing them moves some shards'
+    /// files (see kv_store::plan_data_paths_change).
+    pub data_paths: Vec<String>,
+    /// Storage engine the data is persisted with
+    pub backend: kv_store::Backend,
+    /// When set, every storage operation is repeated on this backend (in a
+    /// nexus subdirectory) and the results are checked against the primary's
+    pub nexus_secondary_backend: Option<kv_store::Backend>,
+    /// Keep every version of each key on disk rather than only the latest,
+    /// where the backend supports it
+    pub history_retention: bool,
+    /// Keep the states of the data over the last pitr_max_history_age
+    /// seconds, so it can be restored to any commit in that window. A
+    /// vbucket's file isn't compacted while it has commits in the window.
+    pub pitr_enabled: bool,
+    pub pitr_max_history_age: u64,
+    /// Seconds a deletion is kept on disk before it may be purged, so
+    /// consumers which were away for less than this still see it
+    pub metadata_purge_interval: u64,
+    /// A vbucket is worth compacting to purge its tombstones once they are
+    /// at least this fraction of its documents on disk
+    pub tombstone_purge_ratio: f64,
+    /// Writes of longer keys fail with TooBig
+    pub max_key_size: usize,
+    /// Writes of larger values fail with TooBig
+    pub max_item_size: usize,
+    /// Whether values are stored compressed
+    pub compression_mode: compression::CompressionMode,
+    /// In active mode a value is only stored compressed if it shrinks by at
+    /// least this factor
+    pub min_compression_ratio: f64,
+    /// Seconds a GET_LOCKED lock is held for when the request doesn't say
+    pub getl_default_timeout: u32,
+    /// Longest GET_LOCKED lock in seconds, longer requests get the default
+    pub getl_max_timeout: u32,
+    /// Bucket memory quota in bytes
+    pub max_size: usize,
+    /// Low watermark as a fraction of max_size
+    pub mem_low_wat: f64,
+    /// High watermark as a fraction of max_size
+    pub mem_high_wat: f64,
+    /// Mutations are temporarily rejected once memory usage exceeds this
+    /// fraction of max_size
+    pub mutation_mem_threshold: f64,
+    /// Percentage of items that must be loaded before warmup enables traffic
+    pub warmup_min_items_threshold: u8,
+    /// Percentage of max_size that may be filled before warmup enables traffic
+    pub warmup_min_memory_threshold: u8,
+    /// Whether warmup loads the keys before the values, or both together in
+    /// one sequential pass over each vbucket file
+    pub warmup_mode: warmup::WarmupMode,
+    /// Maximum number of items in a checkpoint before a new one is opened
+    pub checkpoint_max_items: usize,
+    /// Fraction of max_size available to checkpoints
+    pub checkpoint_memory_ratio: f64,
+    /// Fraction of the checkpoint quota at which memory recovery starts
+    pub checkpoint_memory_recovery_upper_mark: f64,
+    /// Fraction of the checkpoint quota at which memory recovery stops
+    pub checkpoint_memory_recovery_lower_mark: f64,
+    /// Seconds between no-ops sent on an idle DCP connection
+    pub dcp_noop_tx_interval: u64,
+    /// Seconds without hearing from a DCP client (with no-ops enabled)
+    /// before its connection is closed
+    pub dcp_idle_timeout: u64,
+    /// Bytes of items a DCP producer's backfills may read from disk ahead
+    /// of sending them, across all its streams. A backfill whose items
+    /// would take it over pauses until the stream catches up. Clients can
+    /// change it per connection with the backfill_byte_limit control.
+    pub dcp_backfill_byte_limit: usize,
+    /// DCP consumers stop applying replicated messages, buffering them and
+    /// holding back their acknowledgements so the producer pauses, while
+    /// memory usage exceeds this fraction of max_size
+    pub replication_throttle_threshold: f64,
+    /// They also stop while more than this many items are waiting to be
+    /// persisted, 0 for no limit
+    pub replication_throttle_queue_cap: usize,
+    /// Disk bytes per second background tasks may read, 0 for unlimited
+    pub background_io_bytes_per_sec: u64,
+    /// Disk operations per second background tasks may perform, 0 for
+    /// unlimited
+    pub background_io_ops_per_sec: u64,
+    /// Seconds shutdown waits for background tasks to finish
+    pub shutdown_timeout: u64,
+    /// Seconds the scrubber waits between passes over the data files, 0 to
+    /// not scrub
+    pub scrubber_interval: u64,
+    /// Seconds between runs of the expiry pager, 0 to not run it
+    pub exp_pager_stime: u64,
+    /// Seconds between checks that the hash tables' and vbucket files'
+    /// counts of documents agree, 0 to not check
+    pub reconcile_interval: u64,
+    /// Disk bytes per second the scrubber may read, 0 for unlimited. It is
+    /// also subject to the background IO limits.
+    pub scrubber_bytes_per_sec: u64,
+    /// When the scrubber finds a corrupt vbucket file, truncate it to its
+    /// latest clean commit, losing the mutations after that
+    pub corrupt_file_recovery: bool,
+    /// When commits sync the vbucket files. Syncing less often than every
+    /// commit risks losing the latest mutations on a crash.
+    pub sync_policy: couchstore::SyncPolicy,
+    /// Open vbucket files with O_DSYNC (write-through on Windows), so every
+    /// write waits for the disk
+    pub dsync: bool,
+    /// Target sizes of the vbucket files' B-tree nodes and their minimum
+    /// fan-out. Files take the new shape as they are rewritten.
+    pub btree_tuning: couchstore::BtreeTuning,
+    /// Write vbucket files at disk version 14, which front-codes the keys
+    /// in the B-tree leaves. Existing files are rewritten at it when
+    /// compacted, after which engines before it can't read them.
+    pub front_coded_keys: bool,
+    /// Compaction writes the new vbucket files with O_DIRECT, so it doesn't
+    /// evict the page cache the bucket reads through. Where the filesystem
+    /// refuses it they are written through the cache, as without it.
+    pub compaction_direct_io: bool,
+    /// The bucket turns read-only when the data directory's disk has less
+    /// than this many bytes free, and writable again once it has a tenth
+    /// more than this
+    pub disk_min_free_bytes: u64,
+    /// Seconds between checks of the free disk space, 0 to not check
+    pub disk_check_interval: u64,
+    /// Times the flusher retries a failed commit before giving up until its
+    /// next run and marking the vbucket as failing to persist
+    pub flusher_commit_retries: u32,
+    /// Milliseconds before the first retry of a failed commit, doubling for
+    /// each retry after it
+    pub flusher_retry_backoff_ms: u64,
+    /// Flushes of a vbucket which may fail in a row before it is
+    /// quarantined, marked dead until an admin releases it, 0 to never
+    /// quarantine
+    pub quarantine_flush_failures: u32,
+    /// Front-end operations taking at least this many milliseconds are
+    /// logged with a breakdown of where the time went, 0 to not log any
+    pub slow_op_threshold_ms: u64,
+    /// File the engine's audit log is written to, None to not audit
+    pub audit_log: Option<String>,
+    /// Size in bytes at which the audit log is rotated
+    pub audit_rotate_size: u64,
+    /// Events left out of the audit log
+    pub audit_disabled_events: Vec<audit::AuditEventId>,
+    /// Where the bucket gets the time from
+    pub clock: clock::ClockPtr,
+    /// Applied to the keys of front-end operations before they are stored.
+    /// A bucket must always be opened with the same one.
+    pub key_transform: Option<key_transform::KeyTransformPtr>,
+    /// The most seconds a document may live: writes without an expiry, or
+    /// with a later one, expire this long after they are made. 0 for no
+    /// limit.
+    pub max_ttl: u32,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            max_vbuckets: 1024,
+            max_shards: 4,
+            dbname: "./data".to_string(),
+            data_paths: Vec::new(),
+            backend: kv_store::Backend::Couchstore,
+            nexus_secondary_backend: None,
+            history_retention: false,
+            pitr_enabled: false,
+            pitr_max_history_age: 24 * 60 * 60,
+            metadata_purge_interval: 3 * 24 * 60 * 60,
+            tombstone_purge_ratio: 0.1,
+            max_key_size: item::DEFAULT_MAX_KEY_SIZE,
+            max_item_size: item::DEFAULT_MAX_ITEM_SIZE,
+            compression_mode: compression::CompressionMode::Passive,
+            min_compression_ratio: 1.2,
+            getl_default_timeout: 15,
+            getl_max_timeout: 30,
+            max_size: 100 * 1024 * 1024,
+            mem_low_wat: 0.75,
+            mem_high_wat: 0.85,
+            mutation_mem_threshold: 0.93,
+            warmup_min_items_threshold: 100,
+            warmup_min_memory_threshold: 100,
+            warmup_mode: warmup::WarmupMode::KeyDump,
+            checkpoint_max_items: 10000,
+            checkpoint_memory_ratio: 0.5,
+            checkpoint_memory_recovery_upper_mark: 0.9,
+            checkpoint_memory_recovery_lower_mark: 0.6,
+            dcp_noop_tx_interval: 20,
+            dcp_idle_timeout: 360,
+            dcp_backfill_byte_limit: 20 * 1024 * 1024,
+            replication_throttle_threshold: 0.9,
+            replication_throttle_queue_cap: 0,
+            background_io_bytes_per_sec: 0,
+            background_io_ops_per_sec: 0,
+            shutdown_timeout: 10,
+            scrubber_interval: 0,
+            exp_pager_stime: 3600,
+            reconcile_interval: 3600,
+            scrubber_bytes_per_sec: 1024 * 1024,
+            corrupt_file_recovery: false,
+            sync_policy: couchstore::SyncPolicy::EveryCommit,
+            dsync: false,
+            btree_tuning: couchstore::BtreeTuning::default(),
+            front_coded_keys: false,
+            compaction_direct_io: false,
+            disk_min_free_bytes: 256 * 1024 * 1024,
+            disk_check_interval: 10,
+            flusher_commit_retries: 3,
+            flusher_retry_backoff_ms: 10,
+            quarantine_flush_failures: 10,
+            slow_op_threshold_ms: 500,
+            audit_log: None,
+            audit_rotate_size: 20 * 1024 * 1024,
+            audit_disabled_events: vec![
+                audit::AuditEventId::DocumentRead,
+                audit::AuditEventId::DocumentModify,
+                audit::AuditEventId::DocumentDelete,
+            ],
+            clock: std::sync::Arc::new(clock::SystemClock),
+            key_transform: None,
+            max_ttl: 0,
+        }
+    }
 }
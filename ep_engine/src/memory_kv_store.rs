@@ -0,0 +1,339 @@
+//! A KVStore that keeps everything in memory, for ephemeral buckets and for
+//! testing the engine without touching disk. Nothing survives a restart.
+
+use crate::{
+    checkpoint_manager::QueuedItem,
+    item::Item,
+    kv_store::{
+        expiry_index_end, expiry_index_key, CommitError, CouchKVStoreConfig, KVStore, KVStoreStats,
+        PurgeResult, ScanErrorPolicy, ScanResult, StorageProperties, ValueFilter,
+    },
+    vbucket::{VBucketState, Vbid},
+};
+use parking_lot::RwLock;
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    io,
+    ops::Bound,
+    time::Instant,
+};
+
+#[derive(Debug, Default)]
+struct MemoryVBucket {
+    state: Option<VBucketState>,
+    /// The seqno of each key's latest version
+    by_id: BTreeMap<Vec<u8>, u64>,
+    /// The latest version of each key in seqno order, for backfills, along
+    /// with the older versions if history is retained
+    by_seqno: BTreeMap<u64, QueuedItem>,
+    backup_cursors: BTreeMap<String, u64>,
+    /// The expiry index entries of the latest versions which expire
+    by_expiry: BTreeSet<Vec<u8>>,
+}
+
+#[derive(Debug)]
+pub struct MemoryKVStore {
+    config: CouchKVStoreConfig,
+    vbuckets: Vec<RwLock<MemoryVBucket>>,
+    stats: KVStoreStats,
+}
+
+impl MemoryKVStore {
+    pub fn new(config: CouchKVStoreConfig) -> Self {
+        let num_vbuckets =
+            Vbid::iter_for_shard(config.shard_id, config.max_shards, config.max_vbuckets).count();
+        let mut vbuckets = Vec::with_capacity(num_vbuckets);
+        vbuckets.resize_with(num_vbuckets, Default::default);
+        Self {
+            config,
+            vbuckets,
+            stats: KVStoreStats::default(),
+        }
+    }
+
+    fn get_vbucket(&self, vbid: Vbid) -> &RwLock<MemoryVBucket> {
+        &self.vbuckets[vbid.index_in_shard(self.config.max_shards)]
+    }
+}
+
+impl KVStore for MemoryKVStore {
+    fn commit(
+        &self,
+        vbid: Vbid,
+        items: &[QueuedItem],
+        vb_state: &VBucketState,
+    ) -> Result<(), CommitError> {
+        let start = Instant::now();
+        let mut vb = self.get_vbucket(vbid).write();
+        let mut high_seqno = vb.state.as_ref().map_or(0, |state| state.high_seqno);
+        for item in items {
+            let previous = vb.by_id.insert(item.key.clone(), item.by_seqno);
+            if let Some(previous) = previous.and_then(|seqno| vb.by_seqno.get(&seqno)).cloned() {
+                if previous.value.is_some() && previous.expiry_time != 0 {
+                    vb.by_expiry
+                        .remove(&expiry_index_key(previous.expiry_time, &previous.key));
+                }
+                if !self.config.history_retention {
+                    vb.by_seqno.remove(&previous.by_seqno);
+                }
+            }
+            if item.value.is_some() && item.expiry_time != 0 {
+                vb.by_expiry
+                    .insert(expiry_index_key(item.expiry_time, &item.key));
+            }
+            vb.by_seqno.insert(item.by_seqno, item.clone());
+            high_seqno = high_seqno.max(item.by_seqno as i64);
+        }
+
+        let mut vb_state = vb_state.clone();
+        vb_state.high_seqno = high_seqno;
+        vb.state = Some(vb_state);
+        self.stats.record_commit(start, 0, 0);
+        Ok(())
+    }
+
+    fn snapshot_vbucket(&self, vbid: Vbid, vb_state: &VBucketState) -> Result<(), CommitError> {
+        self.commit(vbid, &[], vb_state)
+    }
+
+    fn get_cached_vb_state(&self, vbid: Vbid) -> Option<VBucketState> {
+        self.get_vbucket(vbid).read().state.clone()
+    }
+
+    fn list_persisted_vbuckets(&self) -> Vec<Option<VBucketState>> {
+        self.vbuckets
+            .iter()
+            .map(|vb| vb.read().state.clone())
+            .collect()
+    }
+
+    fn scan(
+        &self,
+        vbid: Vbid,
+        start_seqno: u64,
+        value_filter: ValueFilter,
+        _on_error: ScanErrorPolicy,
+        callback: &mut dyn FnMut(Item),
+    ) -> ScanResult {
+        self.scan_by_seqno(vbid, start_seqno, value_filter, false, callback);
+        // Nothing in memory is corrupt
+        ScanResult::default()
+    }
+
+    fn scan_all_versions(
+        &self,
+        vbid: Vbid,
+        start_seqno: u64,
+        value_filter: ValueFilter,
+        _on_error: ScanErrorPolicy,
+        callback: &mut dyn FnMut(Item),
+    ) -> ScanResult {
+        self.scan_by_seqno(vbid, start_seqno, value_filter, true, callback);
+        // Nothing in memory is corrupt
+        ScanResult::default()
+    }
+
+    fn get_multi(&self, vbid: Vbid, keys: &[Vec<u8>]) -> io::Result<Vec<Option<Item>>> {
+        let vb = self.get_vbucket(vbid).read();
+        Ok(keys
+            .iter()
+            .map(|key| {
+                let seqno = vb.by_id.get(key)?;
+                let mut item = Item::clone(&vb.by_seqno[seqno]);
+                item.inflate();
+                Some(item)
+            })
+            .collect())
+    }
+
+    fn get_backup_cursors(&self, vbid: Vbid) -> io::Result<BTreeMap<String, u64>> {
+        Ok(self.get_vbucket(vbid).read().backup_cursors.clone())
+    }
+
+    fn set_backup_cursors(&self, vbid: Vbid, cursors: &BTreeMap<String, u64>) -> io::Result<()> {
+        self.get_vbucket(vbid).write().backup_cursors = cursors.clone();
+        Ok(())
+    }
+
+    fn purge_tombstones(
+        &self,
+        vbid: Vbid,
+        purge_before: u32,
+        max_seqno: u64,
+    ) -> io::Result<PurgeResult> {
+        let mut result = PurgeResult::default();
+        let mut vb = self.get_vbucket(vbid).write();
+        let purged: Vec<QueuedItem> = vb
+            .by_seqno
+            .iter()
+            .filter(|(&seqno, item)| {
+                item.value.is_none()
+                    && vb.by_id[&item.key] == seqno
+                    && result.check(
+                        seqno,
+                        item.expiry_time,
+                        item.key.len() as u64,
+                        purge_before,
+                        max_seqno,
+                    )
+            })
+            .map(|(_, item)| item.clone())
+            .collect();
+        for item in &purged {
+            vb.by_id.remove(&item.key);
+            vb.by_seqno.remove(&item.by_seqno);
+        }
+        if let (Some(state), Some(last)) = (vb.state.as_mut(), purged.last()) {
+            state.purge_seqno = state.purge_seqno.max(last.by_seqno);
+        }
+        Ok(result)
+    }
+
+    fn get_expired_keys(&self, vbid: Vbid, now: u32) -> io::Result<Vec<Vec<u8>>> {
+        Ok(self
+            .get_vbucket(vbid)
+            .read()
+            .by_expiry
+            .range(..expiry_index_end(now))
+            .map(|entry| entry[4..].to_vec())
+            .collect())
+    }
+
+    fn list_keys(
+        &self,
+        vbid: Vbid,
+        start_key: &[u8],
+        end_key: Option<&[u8]>,
+        limit: usize,
+    ) -> io::Result<Vec<Vec<u8>>> {
+        let vb = self.get_vbucket(vbid).read();
+        let end = end_key.map_or(Bound::Unbounded, Bound::Excluded);
+        Ok(vb
+            .by_id
+            .range::<[u8], _>((Bound::Included(start_key), end))
+            .filter(|(_, seqno)| vb.by_seqno[*seqno].value.is_some())
+            .map(|(key, _)| key.clone())
+            .take(limit)
+            .collect())
+    }
+
+    fn get_doc_counts(&self, vbid: Vbid) -> Option<couchstore::DocCounts> {
+        let vb = self.get_vbucket(vbid).read();
+        let mut counts = couchstore::DocCounts::default();
+        for seqno in vb.by_id.values() {
+            let item = &vb.by_seqno[seqno];
+            match &item.value {
+                Some(value) => {
+                    counts.live += 1;
+                    counts.size += value.len() as u64;
+                }
+                None => {
+                    counts.deleted += 1;
+                    counts.deleted_size += item.key.len() as u64;
+                }
+            }
+        }
+        Some(counts)
+    }
+
+    fn get_storage_properties(&self) -> StorageProperties {
+        StorageProperties {
+            historical_snapshots: true,
+            by_id_scan: true,
+            concurrent_write_compaction: false,
+            automatic_deduplication: true,
+        }
+    }
+
+    fn get_stats(&self) -> &KVStoreStats {
+        &self.stats
+    }
+}
+
+impl MemoryKVStore {
+    fn scan_by_seqno(
+        &self,
+        vbid: Vbid,
+        start_seqno: u64,
+        value_filter: ValueFilter,
+        all_versions: bool,
+        callback: &mut dyn FnMut(Item),
+    ) {
+        // Copy the items out so the callback can't deadlock with a commit
+        let items: Vec<QueuedItem> = {
+            let vb = self.get_vbucket(vbid).read();
+            vb.by_seqno
+                .range(start_seqno..)
+                .filter(|(&seqno, item)| all_versions || vb.by_id[&item.key] == seqno)
+                .map(|(_, item)| item.clone())
+                .collect()
+        };
+        for item in items {
+            let mut item = Item::clone(&item);
+            match value_filter {
+                ValueFilter::KeysOnly => item.value = None,
+                ValueFilter::ValuesCompressed => {}
+                ValueFilter::ValuesDecompressed => item.inflate(),
+            }
+            callback(item);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{
+        ep_bucket::EPBucket, failover_table::FailoverTable, kv_store::Backend, vbucket::State,
+        Config,
+    };
+
+    #[test]
+    fn test_memory_backend() {
+        let bucket = EPBucket::new(Config {
+            max_vbuckets: 4,
+            max_shards: 1,
+            backend: Backend::Memory,
+            ..Default::default()
+        });
+        let vbid = Vbid::new(0);
+        bucket.vbucket_map.add_bucket(bucket.make_vbucket(
+            vbid,
+            State::Active,
+            FailoverTable::new_empty(25),
+            0,
+            0,
+        ));
+        bucket.enable_traffic();
+
+        for (key, value) in [("a", "1"), ("b", "2"), ("a", "3")] {
+            let vb = bucket.get_vbucket(vbid).unwrap();
+            vb.set(Item {
+                key: key.as_bytes().to_vec(),
+                value: Some(value.as_bytes().to_vec()),
+                cas: 0,
+                expiry_time: 0,
+                flags: 0,
+                by_seqno: 0,
+                rev_seqno: 0,
+                delete_source: Default::default(),
+                datatype: Default::default(),
+            })
+            .unwrap();
+        }
+        // The store deduplicates the batch itself
+        assert_eq!(bucket.flush_vbucket(vbid), 3);
+
+        let store = bucket.get_store_by_shard(0);
+        assert_eq!(store.get_cached_vb_state(vbid).unwrap().high_seqno, 3);
+        let mut items = Vec::new();
+        store.scan(
+            vbid,
+            0,
+            ValueFilter::ValuesDecompressed,
+            ScanErrorPolicy::Abort,
+            &mut |item| items.push((item.by_seqno, item.value.unwrap())),
+        );
+        assert_eq!(items, vec![(2, b"2".to_vec()), (3, b"3".to_vec())]);
+    }
+}
@@ -0,0 +1,361 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// Subsystems of a bucket whose memory usage is accounted separately
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum MemoryDomain {
+    /// Keys, values and metadata held in the hash tables
+    HashTable,
+    /// Items queued in checkpoints waiting to be persisted or replicated
+    Checkpoint,
+    /// Everything else owned by the bucket
+    Other,
+}
+
+impl MemoryDomain {
+    pub const ALL: [MemoryDomain; 3] = [
+        MemoryDomain::HashTable,
+        MemoryDomain::Checkpoint,
+        MemoryDomain::Other,
+    ];
+
+    fn index(self) -> usize {
+        match self {
+            MemoryDomain::HashTable => 0,
+            MemoryDomain::Checkpoint => 1,
+            MemoryDomain::Other => 2,
+        }
+    }
+
+    /// Name of the stat reporting the memory used by this domain
+    pub fn stat_name(self) -> &'static str {
+        match self {
+            MemoryDomain::HashTable => "ep_kv_size",
+            MemoryDomain::Checkpoint => "ep_checkpoint_memory",
+            MemoryDomain::Other => "ep_mem_other",
+        }
+    }
+}
+
+/// Per-bucket memory accounting.
+///
+/// Each subsystem reports the bytes it allocates and frees, which gives an
+/// estimate of the bucket's memory usage that is cheap to read from the hot
+/// path. When the `tracking-allocator` feature is enabled each bucket also
+/// gets an arena of the allocator, and the precise figure is what was
+/// allocated while in the bucket's [`MemoryScope`], so one bucket's memory
+/// doesn't show in another's.
+#[derive(Debug)]
+pub struct MemoryTracker {
+    domains: [AtomicUsize; 3],
+    /// None if every arena is taken, leaving only the estimate
+    #[cfg(feature = "tracking-allocator")]
+    arena: Option<tracking_allocator::Arena>,
+}
+
+/// Charges the thread's allocations to a bucket until dropped
+#[must_use]
+pub struct MemoryScope {
+    #[cfg(feature = "tracking-allocator")]
+    _guard: Option<tracking_allocator::ArenaGuard>,
+}
+
+impl Default for MemoryTracker {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl MemoryTracker {
+    pub fn new() -> Self {
+        Self {
+            domains: Default::default(),
+            #[cfg(feature = "tracking-allocator")]
+            arena: tracking_allocator::Arena::new(),
+        }
+    }
+
+    /// Charge the thread's allocations to this bucket, until the scope is
+    /// dropped. Memory stays charged to the bucket until it's freed,
+    /// wherever that happens.
+    pub fn enter(&self) -> MemoryScope {
+        MemoryScope {
+            #[cfg(feature = "tracking-allocator")]
+            _guard: self.arena.as_ref().map(|arena| arena.enter()),
+        }
+    }
+
+    pub fn mem_allocated(&self, domain: MemoryDomain, size: usize) {
+        self.domains[domain.index()].fetch_add(size, Ordering::Relaxed);
+    }
+
+    pub fn mem_deallocated(&self, domain: MemoryDomain, size: usize) {
+        // Saturate rather than wrap if a subsystem over-reports a free
+        let _ = self.domains[domain.index()].fetch_update(
+            Ordering::Relaxed,
+            Ordering::Relaxed,
+            |current| Some(current.saturating_sub(size)),
+        );
+    }
+
+    /// Account for an object changing size from `old_size` to `new_size`
+    pub fn mem_resized(&self, domain: MemoryDomain, old_size: usize, new_size: usize) {
+        if new_size > old_size {
+            self.mem_allocated(domain, new_size - old_size);
+        } else {
+            self.mem_deallocated(domain, old_size - new_size);
+        }
+    }
+
+    pub fn domain_used(&self, domain: MemoryDomain) -> usize {
+        self.domains[domain.index()].load(Ordering::Relaxed)
+    }
+
+    /// Sum of the memory reported by all subsystems
+    pub fn estimated_total(&self) -> usize {
+        MemoryDomain::ALL
+            .iter()
+            .map(|&domain| self.domain_used(domain))
+            .sum()
+    }
+
+    /// The most accurate figure available for the memory in use. With the
+    /// tracking allocator in use this is the number of bytes currently
+    /// allocated in the bucket's arena, otherwise it falls back to the
+    /// estimate.
+    pub fn precise_total(&self) -> usize {
+        #[cfg(feature = "tracking-allocator")]
+        if let Some(arena) = self.arena.as_ref().filter(|_| tracking_allocator::in_use()) {
+            return arena.allocated();
+        }
+        self.estimated_total()
+    }
+
+    /// The arena the bucket's allocations are counted in, 0 when there's
+    /// none
+    pub fn arena_id(&self) -> usize {
+        #[cfg(feature = "tracking-allocator")]
+        if let Some(arena) = &self.arena {
+            return arena.id();
+        }
+        0
+    }
+}
+
+#[cfg(feature = "tracking-allocator")]
+pub use tracking_allocator::TrackingAllocator;
+
+#[cfg(feature = "tracking-allocator")]
+mod tracking_allocator {
+    use std::{
+        alloc::{GlobalAlloc, Layout, System},
+        cell::Cell,
+        sync::atomic::{AtomicBool, AtomicIsize, AtomicUsize, Ordering},
+    };
+
+    /// Arena 0 is memory not allocated on behalf of any bucket
+    const MAX_ARENAS: usize = 64;
+
+    static ALLOCATED: AtomicUsize = AtomicUsize::new(0);
+    /// Signed, as a bucket's arena may be handed to another bucket while
+    /// memory the first allocated is still to be freed
+    static ARENA_ALLOCATED: [AtomicIsize; MAX_ARENAS] = [const { AtomicIsize::new(0) }; MAX_ARENAS];
+    static ARENA_IN_USE: [AtomicBool; MAX_ARENAS] = [const { AtomicBool::new(false) }; MAX_ARENAS];
+
+    thread_local! {
+        static CURRENT_ARENA: Cell<usize> = const { Cell::new(0) };
+    }
+
+    /// Global allocator wrapping the system allocator which counts the bytes
+    /// currently allocated, in total and in each arena. Each allocation
+    /// starts with a header saying which arena it was made in, so it's
+    /// credited back to that arena when freed, whichever thread frees it.
+    /// Binaries opt in with
+    /// `#[global_allocator] static ALLOC: TrackingAllocator = TrackingAllocator;`
+    pub struct TrackingAllocator;
+
+    /// Room for the arena id, keeping the allocation aligned
+    fn header_size(layout: Layout) -> usize {
+        layout.align().max(size_of::<usize>())
+    }
+
+    /// The layout with the header in front, None if too large
+    fn with_header(layout: Layout, size: usize) -> Option<Layout> {
+        let size = size.checked_add(header_size(layout))?;
+        Layout::from_size_align(size, layout.align()).ok()
+    }
+
+    fn record(arena: usize, size: isize) {
+        ALLOCATED.fetch_add(size as usize, Ordering::Relaxed);
+        ARENA_ALLOCATED[arena].fetch_add(size, Ordering::Relaxed);
+    }
+
+    /// Stamp a new allocation with the thread's arena, returning the
+    /// pointer handed to the caller
+    unsafe fn start(base: *mut u8, layout: Layout) -> *mut u8 {
+        if base.is_null() {
+            return base;
+        }
+        // The thread local is gone while the thread is being torn down
+        let arena = CURRENT_ARENA.try_with(Cell::get).unwrap_or(0);
+        let ptr = base.add(header_size(layout));
+        ptr.cast::<usize>().sub(1).write_unaligned(arena);
+        record(arena, layout.size() as isize);
+        ptr
+    }
+
+    /// The start of the allocation and the arena it was made in
+    unsafe fn header(ptr: *mut u8, layout: Layout) -> (*mut u8, usize) {
+        let arena = ptr.cast::<usize>().sub(1).read_unaligned();
+        (ptr.sub(header_size(layout)), arena)
+    }
+
+    unsafe impl GlobalAlloc for TrackingAllocator {
+        unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+            match with_header(layout, layout.size()) {
+                Some(full) => start(System.alloc(full), layout),
+                None => std::ptr::null_mut(),
+            }
+        }
+
+        unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+            let (base, arena) = header(ptr, layout);
+            System.dealloc(base, with_header(layout, layout.size()).unwrap());
+            record(arena, -(layout.size() as isize));
+        }
+
+        unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+            match with_header(layout, layout.size()) {
+                Some(full) => start(System.alloc_zeroed(full), layout),
+                None => std::ptr::null_mut(),
+            }
+        }
+
+        unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+            let Some(new_full) = with_header(layout, new_size) else {
+                return std::ptr::null_mut();
+            };
+            let (base, arena) = header(ptr, layout);
+            let full = with_header(layout, layout.size()).unwrap();
+            // The header moves with the data, so the memory stays in its arena
+            let new_base = System.realloc(base, full, new_full.size());
+            if new_base.is_null() {
+                return new_base;
+            }
+            record(arena, new_size as isize - layout.size() as isize);
+            new_base.add(header_size(layout))
+        }
+    }
+
+    pub fn allocated() -> usize {
+        ALLOCATED.load(Ordering::Relaxed)
+    }
+
+    /// Whether the process's allocations go through the tracking allocator
+    pub fn in_use() -> bool {
+        allocated() != 0
+    }
+
+    /// A bucket's share of the arenas, given back when dropped
+    #[derive(Debug)]
+    pub struct Arena {
+        id: usize,
+    }
+
+    impl Arena {
+        /// None if every arena is taken
+        pub fn new() -> Option<Self> {
+            let id = (1..MAX_ARENAS).find(|&id| {
+                ARENA_IN_USE[id]
+                    .compare_exchange(false, true, Ordering::SeqCst, Ordering::SeqCst)
+                    .is_ok()
+            })?;
+            ARENA_ALLOCATED[id].store(0, Ordering::Relaxed);
+            Some(Self { id })
+        }
+
+        pub fn id(&self) -> usize {
+            self.id
+        }
+
+        pub fn allocated(&self) -> usize {
+            ARENA_ALLOCATED[self.id].load(Ordering::Relaxed).max(0) as usize
+        }
+
+        pub fn enter(&self) -> ArenaGuard {
+            ArenaGuard {
+                previous: CURRENT_ARENA.replace(self.id),
+            }
+        }
+    }
+
+    impl Drop for Arena {
+        fn drop(&mut self) {
+            ARENA_IN_USE[self.id].store(false, Ordering::SeqCst);
+        }
+    }
+
+    /// Puts the thread back in the arena it was in before
+    pub struct ArenaGuard {
+        previous: usize,
+    }
+
+    impl Drop for ArenaGuard {
+        fn drop(&mut self) {
+            CURRENT_ARENA.set(self.previous);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_domain_accounting() {
+        let tracker = MemoryTracker::new();
+        tracker.mem_allocated(MemoryDomain::HashTable, 100);
+        tracker.mem_allocated(MemoryDomain::Checkpoint, 50);
+        tracker.mem_resized(MemoryDomain::HashTable, 40, 10);
+        assert_eq!(tracker.domain_used(MemoryDomain::HashTable), 70);
+        assert_eq!(tracker.estimated_total(), 120);
+
+        // Freeing more than was allocated must not wrap around
+        tracker.mem_deallocated(MemoryDomain::Checkpoint, 80);
+        assert_eq!(tracker.domain_used(MemoryDomain::Checkpoint), 0);
+    }
+
+    #[cfg(feature = "tracking-allocator")]
+    #[test]
+    fn test_arenas() {
+        use std::alloc::{GlobalAlloc, Layout};
+
+        let first = MemoryTracker::new();
+        let second = MemoryTracker::new();
+        assert_ne!(first.arena_id(), second.arena_id());
+        let allocated = |tracker: &MemoryTracker| tracker.arena.as_ref().unwrap().allocated();
+
+        // Calling the allocator directly, as the test binary doesn't use it
+        let layout = Layout::from_size_align(1000, 8).unwrap();
+        let ptr = {
+            let _scope = first.enter();
+            let ptr = unsafe { TrackingAllocator.alloc(layout) };
+            {
+                let _scope = second.enter();
+                let ptr = unsafe { TrackingAllocator.alloc(layout) };
+                unsafe { TrackingAllocator.dealloc(ptr, layout) };
+            }
+            ptr
+        };
+        assert_eq!(allocated(&first), 1000);
+        assert_eq!(allocated(&second), 0);
+
+        // Growing it, then freeing it in another bucket's scope
+        let ptr = unsafe { TrackingAllocator.realloc(ptr, layout, 3000) };
+        assert_eq!(allocated(&first), 3000);
+        let _scope = second.enter();
+        let layout = Layout::from_size_align(3000, 8).unwrap();
+        unsafe { TrackingAllocator.dealloc(ptr, layout) };
+        assert_eq!(allocated(&first), 0);
+        assert_eq!(allocated(&second), 0);
+    }
+}
@@ -0,0 +1,297 @@
+//! Upgrading vbucket files written before collections. Their keys have no
+//! collection prefix, so the collection-aware engine can't read them; the
+//! upgrade rewrites every key into the default collection, which is where a
+//! pre-collections bucket's data belongs, and marks the file as upgraded.
+
+use crate::{
+    collections::{self, VBucketManifest, DEFAULT_COLLECTION},
+    kv_store::{
+        expiry_index_key, Metadata, EXPIRY_TREE, LOCAL_DOC_KEY_BACKUP_CURSORS,
+        LOCAL_DOC_KEY_VBSTATE,
+    },
+};
+use std::{
+    collections::HashMap,
+    io,
+    path::{Path, PathBuf},
+};
+use thiserror::Error;
+
+/// Holds the vbucket's collections as the upgrade left them, for tools
+/// inspecting the file. The engine rebuilds them from the system events.
+pub const LOCAL_DOC_KEY_MANIFEST: &str = "_local/collections/manifest";
+
+#[derive(Error, Debug)]
+pub enum UpgradeError {
+    #[error(transparent)]
+    Couchstore(#[from] couchstore::CouchstoreError),
+    #[error(transparent)]
+    Io(#[from] io::Error),
+    #[error("unreadable vbucket state: {0}")]
+    CorruptState(#[from] serde_json::Error),
+    /// The file's keys already have collection prefixes
+    #[error("already collection-aware")]
+    AlreadyUpgraded,
+}
+
+/// What an upgrade rewrote
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct UpgradeSummary {
+    pub documents: u64,
+    pub high_seqno: u64,
+}
+
+/// Whether a vbucket state was written by a collection-aware engine. States
+/// without the flag predate it, so predate collections too.
+pub fn namespaces_supported(vb_state: &serde_json::Value) -> bool {
+    vb_state
+        .get("namespaces_supported")
+        .and_then(serde_json::Value::as_bool)
+        .unwrap_or(false)
+}
+
+/// Rewrite the pre-collections file at src into a new file at dest, with
+/// the keys in the default collection. Seqnos, metadata and bodies are kept
+/// as they are, and the expiry index is rebuilt for the new keys.
+pub fn upgrade_file(src: &Path, dest: &Path) -> Result<UpgradeSummary, UpgradeError> {
+    let src_db = couchstore::Db::open(src, couchstore::DBOpenOptions::default().read_only())?;
+    let mut vb_state = match src_db.open_local_document(LOCAL_DOC_KEY_VBSTATE)? {
+        Some(couchstore::LocalDoc {
+            json: Some(json), ..
+        }) => serde_json::from_slice(&json)?,
+        _ => serde_json::json!({}),
+    };
+    if namespaces_supported(&vb_state) {
+        return Err(UpgradeError::AlreadyUpgraded);
+    }
+    vb_state["namespaces_supported"] = true.into();
+
+    // Bodies are copied as stored, compressed or not
+    let mut loader = couchstore::Db::open(dest, couchstore::DBOpenOptions::default())?
+        .bulk_load(couchstore::SaveOptions::empty())?;
+    let mut expiry_changes = Vec::new();
+    let mut documents = 0;
+    src_db.changes_since(0, |db, mut info| {
+        let doc = db.open_doc_with_docinfo(&info, couchstore::OpenOptions::empty())?;
+        info.id = collections::make_key(DEFAULT_COLLECTION, &info.id);
+        if !info.deleted {
+            let expiry_time = Metadata::decode(&info.rev_meta[..]).expiry_time;
+            if expiry_time != 0 {
+                expiry_changes.push((expiry_index_key(expiry_time, &info.id), Some(vec![])));
+            }
+        }
+        let doc = doc.map(|doc| couchstore::Doc {
+            id: info.id.clone(),
+            data: doc.data,
+        });
+        loader.add(doc.as_ref(), &info)?;
+        documents += 1;
+        Ok(())
+    })?;
+    loader.set_purge_seq(src_db.header().purge_seq);
+
+    let mut db = loader.finish()?;
+    if !expiry_changes.is_empty() {
+        db.create_aux_tree(EXPIRY_TREE)?;
+        db.modify_aux_tree(EXPIRY_TREE, expiry_changes)?;
+    }
+    if let Some(couchstore::LocalDoc {
+        json: Some(json), ..
+    }) = src_db.open_local_document(LOCAL_DOC_KEY_BACKUP_CURSORS)?
+    {
+        db.save_local_document(couchstore::LocalDoc::new(
+            LOCAL_DOC_KEY_BACKUP_CURSORS,
+            json,
+        ))?;
+    }
+    let manifest = serde_json::to_vec(&VBucketManifest::default())?;
+    db.save_local_document(couchstore::LocalDoc::new(LOCAL_DOC_KEY_MANIFEST, manifest))?;
+    db.save_local_document(couchstore::LocalDoc::new(
+        LOCAL_DOC_KEY_VBSTATE,
+        serde_json::to_vec(&vb_state)?,
+    ))?;
+    db.try_commit()?;
+    Ok(UpgradeSummary {
+        documents,
+        high_seqno: db.header().update_seq,
+    })
+}
+
+/// Upgrade the latest file of each vbucket in a data directory, with the
+/// engine stopped. Each is rewritten at the next revision, so the old file
+/// is only removed once its replacement is complete. Vbuckets already
+/// upgraded are left alone.
+pub fn upgrade_data_dir(
+    dir: &Path,
+) -> io::Result<Vec<(u16, Result<UpgradeSummary, UpgradeError>)>> {
+    let layout = couchstore::DbNameLayout::new(dir);
+    let mut latest: HashMap<u16, u64> = HashMap::new();
+    for name in layout.list()? {
+        if name.kind == couchstore::DbFileKind::Data {
+            let revision = latest.entry(name.vbid).or_default();
+            *revision = (*revision).max(name.revision);
+        }
+    }
+    let mut vbuckets: Vec<_> = latest.into_iter().collect();
+    vbuckets.sort();
+
+    Ok(vbuckets
+        .into_iter()
+        .map(|(vbid, revision)| (vbid, upgrade_vbucket(&layout, vbid, revision)))
+        .collect())
+}
+
+fn upgrade_vbucket(
+    layout: &couchstore::DbNameLayout,
+    vbid: u16,
+    revision: u64,
+) -> Result<UpgradeSummary, UpgradeError> {
+    let src = layout.path(vbid, revision);
+    let prepare: PathBuf = layout.path_of(&couchstore::DbFileName {
+        vbid,
+        revision: revision + 1,
+        kind: couchstore::DbFileKind::Prepare,
+    });
+    // Left by an upgrade which didn't finish
+    let _ = std::fs::remove_file(&prepare);
+    let summary = upgrade_file(&src, &prepare).inspect_err(|_| {
+        let _ = std::fs::remove_file(&prepare);
+    })?;
+    std::fs::rename(&prepare, layout.path(vbid, revision + 1))?;
+    std::fs::remove_file(&src)?;
+    Ok(summary)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{
+        item::{DEFAULT_MAX_ITEM_SIZE, DEFAULT_MAX_KEY_SIZE},
+        kv_store::{CouchKVStore, CouchKVStoreConfig, KVStore, ScanErrorPolicy, ValueFilter},
+        vbucket::{State, VBucketState, Vbid},
+    };
+
+    /// A vbucket file as an engine before collections wrote it
+    fn write_legacy_file(path: &Path) {
+        let mut db = couchstore::Db::open(path, couchstore::DBOpenOptions::default()).unwrap();
+        let mut docs = Vec::new();
+        let mut infos = Vec::new();
+        for seqno in 1..=10u64 {
+            let mut rev_meta = Vec::new();
+            Metadata {
+                cas: seqno,
+                expiry_time: if seqno == 3 { 100 } else { 0 },
+                flags: 0,
+                delete_source: crate::item::DeleteSource::Explicit,
+                datatype: crate::item::Datatype::empty(),
+            }
+            .encode(&mut rev_meta)
+            .unwrap();
+            let id = format!("key_{seqno}").into_bytes();
+            infos.push(couchstore::DocInfo {
+                id: id.clone(),
+                db_seq: seqno,
+                rev_seq: 1,
+                rev_meta,
+                deleted: seqno == 10,
+                content_meta: couchstore::ContentMetaFlag::IS_COMPRESSED,
+                bp: 0,
+                physical_size: 0,
+            });
+            docs.push((seqno != 10).then(|| couchstore::Doc {
+                id,
+                data: format!("value {seqno}").into_bytes(),
+            }));
+        }
+        db.save_documents(
+            docs,
+            infos,
+            couchstore::SaveOptions::SEQUENCE_AS_IS | couchstore::SaveOptions::COMPRESS_DOC_BODIES,
+        )
+        .unwrap();
+        let mut vb_state = VBucketState::new(State::Active);
+        vb_state.max_cas = 10;
+        vb_state.namespaces_supported = false;
+        db.save_local_document(couchstore::LocalDoc::new(
+            LOCAL_DOC_KEY_VBSTATE,
+            serde_json::to_vec(&vb_state).unwrap(),
+        ))
+        .unwrap();
+        db.commit();
+    }
+
+    #[test]
+    fn test_upgrade_data_dir() {
+        let dir = tempfile::tempdir().unwrap();
+        write_legacy_file(&dir.path().join("1.couch.3"));
+
+        let upgraded = upgrade_data_dir(dir.path()).unwrap();
+        assert_eq!(upgraded.len(), 1);
+        let (vbid, summary) = &upgraded[0];
+        assert_eq!(*vbid, 1);
+        assert_eq!(
+            *summary.as_ref().unwrap(),
+            UpgradeSummary {
+                documents: 10,
+                high_seqno: 10
+            }
+        );
+        assert!(!dir.path().join("1.couch.3").exists());
+
+        let path = dir.path().join("1.couch.4");
+        let db =
+            couchstore::Db::open(&path, couchstore::DBOpenOptions::default().read_only()).unwrap();
+        let local_doc = |key| {
+            let json = db.open_local_document(key).unwrap().unwrap().json.unwrap();
+            serde_json::from_slice::<serde_json::Value>(&json).unwrap()
+        };
+        assert!(namespaces_supported(&local_doc(LOCAL_DOC_KEY_VBSTATE)));
+        assert_eq!(local_doc(LOCAL_DOC_KEY_VBSTATE)["max_cas"], "10");
+        assert_eq!(
+            local_doc(LOCAL_DOC_KEY_MANIFEST)["collections"]["0"]["name"],
+            "_default"
+        );
+        drop(db);
+
+        // Upgraded files are left alone
+        assert!(matches!(
+            upgrade_data_dir(dir.path()).unwrap()[0].1,
+            Err(UpgradeError::AlreadyUpgraded)
+        ));
+        assert!(path.exists());
+
+        // The engine reads the keys in the default collection
+        let store = CouchKVStore::new(CouchKVStoreConfig {
+            max_vbuckets: 4,
+            db_name: dir.path().to_str().unwrap().to_string(),
+            data_paths: Vec::new(),
+            max_shards: 1,
+            shard_id: 0,
+            history_retention: false,
+            max_key_size: DEFAULT_MAX_KEY_SIZE,
+            max_item_size: DEFAULT_MAX_ITEM_SIZE,
+            pitr_max_history_age: None,
+            sync_policy: couchstore::SyncPolicy::EveryCommit,
+            dsync: false,
+            btree_tuning: couchstore::BtreeTuning::default(),
+            front_coded_keys: false,
+            compaction_direct_io: false,
+        });
+        let mut items = Vec::new();
+        store.scan(
+            Vbid::new(1),
+            0,
+            ValueFilter::ValuesDecompressed,
+            ScanErrorPolicy::Abort,
+            &mut |item| items.push(item),
+        );
+        assert_eq!(items.len(), 10);
+        assert_eq!(items[2].key, b"\0key_3");
+        assert_eq!(items[2].value.as_deref(), Some(&b"value 3"[..]));
+        assert!(items[9].value.is_none());
+        assert_eq!(
+            store.get_expired_keys(Vbid::new(1), 200).unwrap(),
+            [b"\0key_3"]
+        );
+    }
+}
@@ -0,0 +1,381 @@
+//! A KVStore which runs two backends in lock-step and panics as soon as they
+//! disagree, for differential testing of a new backend against a trusted
+//! one. The primary's results are the ones returned.
+
+use crate::{
+    checkpoint_manager::QueuedItem,
+    item::Item,
+    kv_store::{
+        CommitError, KVStore, KVStoreStats, PurgeResult, RetainedHeader, ScanErrorPolicy,
+        ScanResult, ScrubResult, StorageProperties, ValueFilter,
+    },
+    vbucket::{VBucketState, Vbid},
+};
+use std::{collections::BTreeMap, io};
+
+#[derive(Debug)]
+pub struct NexusKVStore {
+    primary: Box<dyn KVStore>,
+    secondary: Box<dyn KVStore>,
+}
+
+impl NexusKVStore {
+    pub fn new(primary: Box<dyn KVStore>, secondary: Box<dyn KVStore>) -> Self {
+        Self { primary, secondary }
+    }
+}
+
+impl KVStore for NexusKVStore {
+    fn commit(
+        &self,
+        vbid: Vbid,
+        items: &[QueuedItem],
+        vb_state: &VBucketState,
+    ) -> Result<(), CommitError> {
+        self.primary.commit(vbid, items, vb_state)?;
+        self.secondary.commit(vbid, items, vb_state)?;
+        // Each store derives the persisted state itself, so check they agree
+        self.get_cached_vb_state(vbid);
+        Ok(())
+    }
+
+    fn snapshot_vbucket(&self, vbid: Vbid, vb_state: &VBucketState) -> Result<(), CommitError> {
+        self.primary.snapshot_vbucket(vbid, vb_state)?;
+        self.secondary.snapshot_vbucket(vbid, vb_state)
+    }
+
+    fn get_cached_vb_state(&self, vbid: Vbid) -> Option<VBucketState> {
+        let primary = self.primary.get_cached_vb_state(vbid);
+        let secondary = self.secondary.get_cached_vb_state(vbid);
+        assert_eq!(primary, secondary, "Nexus: vb {vbid} states differ");
+        primary
+    }
+
+    fn refresh_vbucket_state(&self, vbid: Vbid) -> Result<Option<VBucketState>, String> {
+        let primary = self.primary.refresh_vbucket_state(vbid)?;
+        let secondary = self.secondary.refresh_vbucket_state(vbid)?;
+        assert_eq!(primary, secondary, "Nexus: vb {vbid} states differ");
+        Ok(primary)
+    }
+
+    fn list_persisted_vbuckets(&self) -> Vec<Option<VBucketState>> {
+        let primary = self.primary.list_persisted_vbuckets();
+        let secondary = self.secondary.list_persisted_vbuckets();
+        assert_eq!(primary, secondary, "Nexus: persisted vbuckets differ");
+        primary
+    }
+
+    /// Corruption in either backend is reported
+    fn scan(
+        &self,
+        vbid: Vbid,
+        start_seqno: u64,
+        value_filter: ValueFilter,
+        on_error: ScanErrorPolicy,
+        callback: &mut dyn FnMut(Item),
+    ) -> ScanResult {
+        let mut primary = Vec::new();
+        let mut result =
+            self.primary
+                .scan(vbid, start_seqno, value_filter, on_error, &mut |item| {
+                    primary.push(item)
+                });
+        let mut secondary = Vec::new();
+        let secondary_result =
+            self.secondary
+                .scan(vbid, start_seqno, value_filter, on_error, &mut |item| {
+                    secondary.push(item)
+                });
+        // The items can only be compared if both read them all
+        if result.is_complete() && secondary_result.is_complete() {
+            check_scans(vbid, start_seqno, value_filter, &primary, &secondary);
+        }
+        result.errors.extend(secondary_result.errors);
+        result.aborted |= secondary_result.aborted;
+        primary.into_iter().for_each(callback);
+        result
+    }
+
+    /// Corruption in either backend is reported
+    fn scan_all_versions(
+        &self,
+        vbid: Vbid,
+        start_seqno: u64,
+        value_filter: ValueFilter,
+        on_error: ScanErrorPolicy,
+        callback: &mut dyn FnMut(Item),
+    ) -> ScanResult {
+        let mut primary = Vec::new();
+        let mut result = self.primary.scan_all_versions(
+            vbid,
+            start_seqno,
+            value_filter,
+            on_error,
+            &mut |item| primary.push(item),
+        );
+        let mut secondary = Vec::new();
+        let secondary_result = self.secondary.scan_all_versions(
+            vbid,
+            start_seqno,
+            value_filter,
+            on_error,
+            &mut |item| secondary.push(item),
+        );
+        // The items can only be compared if both read them all
+        if result.is_complete() && secondary_result.is_complete() {
+            check_scans(vbid, start_seqno, value_filter, &primary, &secondary);
+        }
+        result.errors.extend(secondary_result.errors);
+        result.aborted |= secondary_result.aborted;
+        primary.into_iter().for_each(callback);
+        result
+    }
+
+    fn get_multi(&self, vbid: Vbid, keys: &[Vec<u8>]) -> io::Result<Vec<Option<Item>>> {
+        let primary = self.primary.get_multi(vbid, keys)?;
+        let secondary = self.secondary.get_multi(vbid, keys)?;
+        for (primary, secondary) in primary.iter().zip(&secondary) {
+            let same = match (primary, secondary) {
+                (Some(primary), Some(secondary)) => same_item(primary, secondary, true),
+                (primary, secondary) => primary.is_none() && secondary.is_none(),
+            };
+            assert!(
+                same,
+                "Nexus: vb {vbid} gets differ, {primary:?} != {secondary:?}"
+            );
+        }
+        Ok(primary)
+    }
+
+    fn get_backup_cursors(&self, vbid: Vbid) -> io::Result<BTreeMap<String, u64>> {
+        let primary = self.primary.get_backup_cursors(vbid)?;
+        let secondary = self.secondary.get_backup_cursors(vbid)?;
+        assert_eq!(primary, secondary, "Nexus: vb {vbid} backup cursors differ");
+        Ok(primary)
+    }
+
+    fn set_backup_cursors(&self, vbid: Vbid, cursors: &BTreeMap<String, u64>) -> io::Result<()> {
+        self.primary.set_backup_cursors(vbid, cursors)?;
+        self.secondary.set_backup_cursors(vbid, cursors)
+    }
+
+    fn purge_tombstones(
+        &self,
+        vbid: Vbid,
+        purge_before: u32,
+        max_seqno: u64,
+    ) -> io::Result<PurgeResult> {
+        let primary = self
+            .primary
+            .purge_tombstones(vbid, purge_before, max_seqno)?;
+        let secondary = self
+            .secondary
+            .purge_tombstones(vbid, purge_before, max_seqno)?;
+        // The stores measure the tombstones' sizes differently
+        assert_eq!(
+            (primary.purged, primary.retained, primary.remaining),
+            (secondary.purged, secondary.retained, secondary.remaining),
+            "Nexus: vb {vbid} purged different tombstones"
+        );
+        Ok(primary)
+    }
+
+    fn compact(&self, vbid: Vbid) -> u64 {
+        // The stores lay their files out differently, so only the primary's
+        // reclaimed space is reported
+        self.secondary.compact(vbid);
+        self.primary.compact(vbid)
+    }
+
+    fn get_expired_keys(&self, vbid: Vbid, now: u32) -> io::Result<Vec<Vec<u8>>> {
+        let primary = self.primary.get_expired_keys(vbid, now)?;
+        let secondary = self.secondary.get_expired_keys(vbid, now)?;
+        assert_eq!(primary, secondary, "Nexus: vb {vbid} expired keys differ");
+        Ok(primary)
+    }
+
+    fn list_keys(
+        &self,
+        vbid: Vbid,
+        start_key: &[u8],
+        end_key: Option<&[u8]>,
+        limit: usize,
+    ) -> io::Result<Vec<Vec<u8>>> {
+        let primary = self.primary.list_keys(vbid, start_key, end_key, limit)?;
+        let secondary = self.secondary.list_keys(vbid, start_key, end_key, limit)?;
+        assert_eq!(primary, secondary, "Nexus: vb {vbid} keys differ");
+        Ok(primary)
+    }
+
+    /// Each backend's files are scrubbed, as either could be corrupt
+    fn scrub(&self, vbid: Vbid, on_read: &mut dyn FnMut(usize)) -> ScrubResult {
+        let mut result = self.primary.scrub(vbid, on_read);
+        let secondary = self.secondary.scrub(vbid, on_read);
+        result.bytes_read += secondary.bytes_read;
+        result.corrupt_chunks.extend(secondary.corrupt_chunks);
+        result
+    }
+
+    fn sync_pending_commits(&self) {
+        self.primary.sync_pending_commits();
+        self.secondary.sync_pending_commits();
+    }
+
+    /// The backends' headers can't be compared, so these are the primary's
+    fn list_retained_headers(&self, vbid: Vbid) -> io::Result<Vec<RetainedHeader>> {
+        self.primary.list_retained_headers(vbid)
+    }
+
+    fn get_doc_counts(&self, vbid: Vbid) -> Option<couchstore::DocCounts> {
+        self.primary.get_doc_counts(vbid)
+    }
+
+    /// Only what both backends support
+    fn get_storage_properties(&self) -> StorageProperties {
+        let primary = self.primary.get_storage_properties();
+        let secondary = self.secondary.get_storage_properties();
+        StorageProperties {
+            historical_snapshots: primary.historical_snapshots && secondary.historical_snapshots,
+            by_id_scan: primary.by_id_scan && secondary.by_id_scan,
+            concurrent_write_compaction: primary.concurrent_write_compaction
+                && secondary.concurrent_write_compaction,
+            automatic_deduplication: primary.automatic_deduplication
+                && secondary.automatic_deduplication,
+        }
+    }
+
+    fn get_stats(&self) -> &KVStoreStats {
+        self.primary.get_stats()
+    }
+}
+
+fn check_scans(
+    vbid: Vbid,
+    start_seqno: u64,
+    value_filter: ValueFilter,
+    primary: &[Item],
+    secondary: &[Item],
+) {
+    // Backends are free to compress values differently
+    let compare_values = value_filter != ValueFilter::ValuesCompressed;
+    assert_eq!(
+        primary.len(),
+        secondary.len(),
+        "Nexus: vb {vbid} scans from {start_seqno} return different item counts"
+    );
+    for (primary, secondary) in primary.iter().zip(secondary) {
+        assert!(
+            same_item(primary, secondary, compare_values),
+            "Nexus: vb {vbid} scans differ, {primary:?} != {secondary:?}"
+        );
+    }
+}
+
+fn same_item(a: &Item, b: &Item, compare_values: bool) -> bool {
+    a.key == b.key
+        && a.by_seqno == b.by_seqno
+        && a.rev_seqno == b.rev_seqno
+        && a.cas == b.cas
+        && a.flags == b.flags
+        && a.expiry_time == b.expiry_time
+        && a.value.is_none() == b.value.is_none()
+        && (!compare_values || a.value == b.value)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{
+        item::{Datatype, DeleteSource, DEFAULT_MAX_ITEM_SIZE, DEFAULT_MAX_KEY_SIZE},
+        kv_store::{CouchKVStore, CouchKVStoreConfig},
+        memory_kv_store::MemoryKVStore,
+        vbucket::State,
+    };
+    use std::sync::Arc;
+
+    fn make_item(key: &str, seqno: u64) -> QueuedItem {
+        Arc::new(Item {
+            key: key.as_bytes().to_vec(),
+            value: Some(vec![b'x'; 10]),
+            cas: seqno,
+            expiry_time: 0,
+            flags: 0,
+            by_seqno: seqno,
+            rev_seqno: 1,
+            delete_source: DeleteSource::Explicit,
+            datatype: Datatype::empty(),
+        })
+    }
+
+    fn make_config(dir: &tempfile::TempDir) -> CouchKVStoreConfig {
+        CouchKVStoreConfig {
+            max_vbuckets: 4,
+            db_name: dir.path().to_str().unwrap().to_string(),
+            data_paths: Vec::new(),
+            max_shards: 1,
+            shard_id: 0,
+            history_retention: false,
+            max_key_size: DEFAULT_MAX_KEY_SIZE,
+            max_item_size: DEFAULT_MAX_ITEM_SIZE,
+            pitr_max_history_age: None,
+            sync_policy: couchstore::SyncPolicy::EveryCommit,
+            dsync: false,
+            btree_tuning: couchstore::BtreeTuning::default(),
+            front_coded_keys: false,
+            compaction_direct_io: false,
+        }
+    }
+
+    #[test]
+    fn test_nexus() {
+        let dir = tempfile::tempdir().unwrap();
+        let vbid = Vbid::new(2);
+        let vb_state = VBucketState::new(State::Active);
+        let nexus = NexusKVStore::new(
+            Box::new(CouchKVStore::new(make_config(&dir))),
+            Box::new(MemoryKVStore::new(make_config(&dir))),
+        );
+        // Only the memory backend deduplicates
+        assert!(!nexus.get_storage_properties().automatic_deduplication);
+        nexus
+            .commit(vbid, &[make_item("a", 1), make_item("b", 2)], &vb_state)
+            .unwrap();
+        nexus.commit(vbid, &[make_item("a", 3)], &vb_state).unwrap();
+        let mut scanned = 0;
+        nexus.scan(
+            vbid,
+            0,
+            ValueFilter::ValuesDecompressed,
+            ScanErrorPolicy::Abort,
+            &mut |_| scanned += 1,
+        );
+        assert_eq!(scanned, 2);
+    }
+
+    #[test]
+    #[should_panic(expected = "Nexus: vb 2 scans from 0 return different item counts")]
+    fn test_nexus_mismatch() {
+        let dir = tempfile::tempdir().unwrap();
+        let vbid = Vbid::new(2);
+        let primary = MemoryKVStore::new(make_config(&dir));
+        // A commit the secondary never saw
+        primary
+            .commit(
+                vbid,
+                &[make_item("a", 1)],
+                &VBucketState::new(State::Active),
+            )
+            .unwrap();
+        let nexus = NexusKVStore::new(
+            Box::new(primary),
+            Box::new(MemoryKVStore::new(make_config(&dir))),
+        );
+        nexus.scan(
+            vbid,
+            0,
+            ValueFilter::KeysOnly,
+            ScanErrorPolicy::Abort,
+            &mut |_| {},
+        );
+    }
+}
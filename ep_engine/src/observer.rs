@@ -0,0 +1,52 @@
+//! Hooks for embedders to follow what the engine does, e.g. to keep an
+//! external index or cache in step, without changing the engine. Observers
+//! are called synchronously on the thread doing the work, often with a
+//! vbucket lock held, so they should be quick and must not call back into
+//! the bucket.
+
+use parking_lot::RwLock;
+use std::sync::Arc;
+
+use crate::{
+    kv_store::PurgeResult,
+    vbucket::{State, VBucketState, Vbid},
+};
+
+/// Every method does nothing by default, so observers only implement the
+/// events they care about
+pub trait EngineObserver: Send + Sync {
+    /// A batch of the vbucket's mutations, up to high_seqno, was persisted
+    fn on_flush_complete(&self, _vbid: Vbid, _items: usize, _high_seqno: u64) {}
+
+    fn on_vbucket_state_change(&self, _vbid: Vbid, _old: State, _new: State) {}
+
+    /// The vbucket's persisted state was read again from disk, after its
+    /// files were changed outside the engine
+    fn on_vbucket_state_refreshed(&self, _vbid: Vbid, _vb_state: &VBucketState) {}
+
+    /// Compaction is about to purge the vbucket's old tombstones
+    fn on_compaction_start(&self, _vbid: Vbid) {}
+
+    fn on_compaction_end(&self, _vbid: Vbid, _result: &PurgeResult) {}
+
+    /// The key was found to have expired, by the expiry pager or an access
+    fn on_item_expired(&self, _vbid: Vbid, _key: &[u8]) {}
+}
+
+#[derive(Default)]
+pub struct Observers {
+    observers: RwLock<Vec<Arc<dyn EngineObserver>>>,
+}
+
+impl Observers {
+    pub fn register(&self, observer: Arc<dyn EngineObserver>) {
+        self.observers.write().push(observer);
+    }
+
+    /// Call each observer in the order they were registered
+    pub(crate) fn notify(&self, event: impl Fn(&dyn EngineObserver)) {
+        for observer in self.observers.read().iter() {
+            event(observer.as_ref());
+        }
+    }
+}
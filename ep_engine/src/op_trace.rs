@@ -0,0 +1,106 @@
+//! Timing front-end operations to report the slow ones. Each operation runs
+//! in a tracing span, with a child span for each phase (the hash table, a
+//! background fetch from disk), and an operation over the bucket's
+//! threshold is logged with how long each phase took.
+
+use std::{
+    fmt::Write,
+    sync::atomic::{AtomicU64, Ordering},
+    time::{Duration, Instant},
+};
+
+pub struct OpTrace<'a> {
+    op: &'static str,
+    start: Instant,
+    phases: Vec<(&'static str, Duration)>,
+    /// None to not report slow operations
+    threshold: Option<Duration>,
+    slow_ops: &'a AtomicU64,
+    _span: tracing::span::EnteredSpan,
+}
+
+impl<'a> OpTrace<'a> {
+    /// Start timing the operation, counting it in slow_ops if it takes
+    /// threshold or longer
+    pub fn new(op: &'static str, threshold: Option<Duration>, slow_ops: &'a AtomicU64) -> Self {
+        Self {
+            op,
+            start: Instant::now(),
+            phases: Vec::new(),
+            threshold,
+            slow_ops,
+            _span: tracing::info_span!("engine_op", op).entered(),
+        }
+    }
+
+    /// Run one phase of the operation, timing it
+    pub fn phase<T>(&mut self, name: &'static str, f: impl FnOnce() -> T) -> T {
+        let _span = tracing::debug_span!("phase", name).entered();
+        let start = Instant::now();
+        let result = f();
+        self.phases.push((name, start.elapsed()));
+        result
+    }
+
+    /// What the slow operation log says about the operation
+    fn describe(&self, duration: Duration) -> String {
+        let mut description = format!(
+            "Slow operation: {} took {} us",
+            self.op,
+            duration.as_micros()
+        );
+        let mut separator = " (";
+        for (name, duration) in &self.phases {
+            write!(description, "{separator}{name}={} us", duration.as_micros()).unwrap();
+            separator = ", ";
+        }
+        if !self.phases.is_empty() {
+            description.push(')');
+        }
+        description
+    }
+}
+
+impl Drop for OpTrace<'_> {
+    fn drop(&mut self) {
+        let duration = self.start.elapsed();
+        if self
+            .threshold
+            .is_some_and(|threshold| duration >= threshold)
+        {
+            self.slow_ops.fetch_add(1, Ordering::Relaxed);
+            println!("{}", self.describe(duration));
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_op_trace() {
+        let slow_ops = AtomicU64::new(0);
+        {
+            let mut trace = OpTrace::new("get", Some(Duration::ZERO), &slow_ops);
+            assert_eq!(trace.phase("hash_table", || 1), 1);
+            trace.phase("bg_fetch", || std::thread::sleep(Duration::from_millis(2)));
+            let description = trace.describe(Duration::from_millis(3));
+            assert!(
+                description.starts_with("Slow operation: get took 3000 us (hash_table="),
+                "{description}"
+            );
+            assert!(description.contains(", bg_fetch="), "{description}");
+            assert!(trace.phases[1].1 >= Duration::from_millis(2));
+        }
+        assert_eq!(slow_ops.load(Ordering::Relaxed), 1);
+
+        drop(OpTrace::new(
+            "set",
+            Some(Duration::from_secs(60)),
+            &slow_ops,
+        ));
+        drop(OpTrace::new("set", None, &slow_ops));
+        assert_eq!(slow_ops.load(Ordering::Relaxed), 1);
+    }
+}
@@ -0,0 +1,288 @@
+//! Checking that the engine's counts of each vbucket's documents agree. The
+//! hash table counts its items as they are stored, by collection too, and
+//! the vbucket file counts the documents it holds in its by-id tree. They
+//! are kept by different code, so a difference between them, or with a
+//! recount of the hash table, points at an accounting bug in a write path
+//! long before it shows up as documents going missing or coming back.
+
+use parking_lot::Mutex;
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt::{self, Display},
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+use crate::{collections::CollectionId, vbucket::Vbid};
+
+/// A vbucket's counts, taken while it was neither written to nor flushed
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct VBucketCounts {
+    /// Items in the hash table which aren't deleted, counted one by one
+    pub items: usize,
+    /// The same by collection
+    pub collection_items: HashMap<CollectionId, usize>,
+    /// The hash table's own count of them
+    pub tracked_items: usize,
+    /// The same by collection
+    pub tracked_collection_items: HashMap<CollectionId, usize>,
+    /// Documents on disk which aren't deleted, less the collections'
+    /// system events. None if the store doesn't count them.
+    pub disk_items: Option<u64>,
+}
+
+/// A difference between two of a vbucket's counts
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Drift {
+    /// The hash table's count of its items is wrong
+    HashTable { counted: usize, tracked: usize },
+    /// Its count of a collection's items is wrong
+    Collection {
+        collection: CollectionId,
+        counted: usize,
+        tracked: usize,
+    },
+    /// The vbucket file holds a different number of documents than the
+    /// hash table. Warming up without values loads deleted keys as live,
+    /// so this is expected until they are fetched.
+    Disk { memory: usize, disk: u64 },
+}
+
+impl Display for Drift {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Drift::HashTable { counted, tracked } => {
+                write!(f, "hash table has {counted} items but counts {tracked}")
+            }
+            Drift::Collection {
+                collection,
+                counted,
+                tracked,
+            } => write!(
+                f,
+                "collection {collection:#x} has {counted} items but counts {tracked}"
+            ),
+            Drift::Disk { memory, disk } => {
+                write!(f, "hash table has {memory} items but disk {disk}")
+            }
+        }
+    }
+}
+
+impl VBucketCounts {
+    /// Where the counts disagree
+    pub fn drifts(&self) -> Vec<Drift> {
+        let mut drifts = Vec::new();
+        if self.items != self.tracked_items {
+            drifts.push(Drift::HashTable {
+                counted: self.items,
+                tracked: self.tracked_items,
+            });
+        }
+        let collections: BTreeMap<CollectionId, ()> = self
+            .collection_items
+            .keys()
+            .chain(self.tracked_collection_items.keys())
+            .map(|collection| (*collection, ()))
+            .collect();
+        for &collection in collections.keys() {
+            let count = |counts: &HashMap<CollectionId, usize>| {
+                counts.get(&collection).copied().unwrap_or(0)
+            };
+            let counted = count(&self.collection_items);
+            let tracked = count(&self.tracked_collection_items);
+            if counted != tracked {
+                drifts.push(Drift::Collection {
+                    collection,
+                    counted,
+                    tracked,
+                });
+            }
+        }
+        if let Some(disk) = self.disk_items {
+            if disk != self.items as u64 {
+                drifts.push(Drift::Disk {
+                    memory: self.items,
+                    disk,
+                });
+            }
+        }
+        drifts
+    }
+}
+
+/// The progress and findings of the reconciliation passes
+#[derive(Debug, Default)]
+pub struct ReconcileStats {
+    passes: AtomicU64,
+    vbuckets_checked: AtomicU64,
+    /// Vbuckets with mutations waiting to be persisted, so their counts
+    /// can't be compared
+    vbuckets_skipped: AtomicU64,
+    /// Drifts found by every pass
+    drifts: AtomicU64,
+    /// Those found by the latest complete pass
+    latest: Mutex<BTreeMap<Vbid, Vec<Drift>>>,
+}
+
+impl ReconcileStats {
+    pub(crate) fn record_checked(&self) {
+        self.vbuckets_checked.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn record_skipped(&self) {
+        self.vbuckets_skipped.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn record_pass(&self, drifts: BTreeMap<Vbid, Vec<Drift>>) {
+        let found = drifts.values().map(Vec::len).sum::<usize>();
+        self.drifts.fetch_add(found as u64, Ordering::Relaxed);
+        self.passes.fetch_add(1, Ordering::Relaxed);
+        *self.latest.lock() = drifts;
+    }
+
+    pub fn drifts(&self) -> u64 {
+        self.drifts.load(Ordering::Relaxed)
+    }
+
+    /// The reconcile stats group, with each drift the latest pass found as
+    /// vb_<vbid>:drift_<n>
+    pub fn add_stats(&self, add_stat: &mut dyn FnMut(&str, &str)) {
+        let load = |counter: &AtomicU64| counter.load(Ordering::Relaxed).to_string();
+        add_stat("ep_reconcile_passes", &load(&self.passes));
+        add_stat(
+            "ep_reconcile_vbuckets_checked",
+            &load(&self.vbuckets_checked),
+        );
+        add_stat(
+            "ep_reconcile_vbuckets_skipped",
+            &load(&self.vbuckets_skipped),
+        );
+        add_stat("ep_reconcile_drifts", &load(&self.drifts));
+        let latest = self.latest.lock();
+        add_stat("ep_reconcile_vbuckets_drifted", &latest.len().to_string());
+        for (vbid, drifts) in latest.iter() {
+            for (i, drift) in drifts.iter().enumerate() {
+                add_stat(&format!("vb_{vbid}:drift_{i}"), &drift.to_string());
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{
+        collections::{make_key, DEFAULT_COLLECTION, FIRST_USER_COLLECTION},
+        ep_bucket::EPBucket,
+        failover_table::FailoverTable,
+        item::{Datatype, DeleteSource, Item},
+        vbucket::State,
+        Config,
+    };
+
+    fn item(key: Vec<u8>) -> Item {
+        Item {
+            key,
+            value: Some(b"value".to_vec()),
+            cas: 0,
+            expiry_time: 0,
+            flags: 0,
+            by_seqno: 0,
+            rev_seqno: 0,
+            delete_source: DeleteSource::Explicit,
+            datatype: Datatype::empty(),
+        }
+    }
+
+    #[test]
+    fn test_reconcile_counts() {
+        let dir = tempfile::tempdir().unwrap();
+        let bucket = EPBucket::new(Config {
+            max_vbuckets: 2,
+            max_shards: 1,
+            dbname: dir.path().to_str().unwrap().to_string(),
+            ..Default::default()
+        });
+        for vbid in 0..2u16 {
+            bucket.vbucket_map.add_bucket(bucket.make_vbucket(
+                Vbid::from(vbid),
+                State::Active,
+                FailoverTable::new_empty(25),
+                0,
+                0,
+            ));
+        }
+        bucket.enable_traffic();
+        bucket
+            .create_collection(FIRST_USER_COLLECTION, 0, "orders", 0)
+            .unwrap();
+        let vbids = [Vbid::from(0u16), Vbid::from(1u16)];
+        for vbid in vbids {
+            let vb = bucket.get_vbucket(vbid).unwrap();
+            for i in 0..10 {
+                vb.set(item(make_key(
+                    DEFAULT_COLLECTION,
+                    format!("k{i}").as_bytes(),
+                )))
+                .unwrap();
+                vb.set(item(make_key(
+                    FIRST_USER_COLLECTION,
+                    format!("o{i}").as_bytes(),
+                )))
+                .unwrap();
+            }
+            for i in 0..3 {
+                vb.delete(
+                    &make_key(FIRST_USER_COLLECTION, format!("o{i}").as_bytes()),
+                    0,
+                )
+                .unwrap();
+            }
+            bucket.flush_vbucket(vbid);
+        }
+        assert!(bucket.reconcile_counts().is_empty());
+
+        // A vbucket with a mutation not yet persisted can't be checked
+        let vb = bucket.get_vbucket(vbids[1]).unwrap();
+        let lost = make_key(DEFAULT_COLLECTION, b"lost");
+        vb.set(item(lost.clone())).unwrap();
+        assert!(bucket.reconcile_counts().is_empty());
+        bucket.flush_vbucket(vbids[1]);
+
+        // An item dropped from the hash table without being accounted for
+        vb.hash_table.lock().map.remove(&lost);
+        let drifts = bucket.reconcile_counts();
+        assert_eq!(
+            drifts[&vbids[1]],
+            vec![
+                Drift::HashTable {
+                    counted: 17,
+                    tracked: 18
+                },
+                Drift::Collection {
+                    collection: DEFAULT_COLLECTION,
+                    counted: 10,
+                    tracked: 11
+                },
+                Drift::Disk {
+                    memory: 17,
+                    disk: 18
+                },
+            ]
+        );
+        assert!(!drifts.contains_key(&vbids[0]));
+
+        let mut stats = std::collections::HashMap::new();
+        bucket
+            .get_stats_group("reconcile", &mut |key, value| {
+                stats.insert(key.to_string(), value.to_string());
+            })
+            .unwrap();
+        assert_eq!(stats["ep_reconcile_passes"], "3");
+        assert_eq!(stats["ep_reconcile_vbuckets_checked"], "5");
+        assert_eq!(stats["ep_reconcile_vbuckets_skipped"], "1");
+        assert_eq!(stats["ep_reconcile_drifts"], "3");
+        assert_eq!(stats["ep_reconcile_vbuckets_drifted"], "1");
+        assert_eq!(stats["vb_1:drift_2"], "hash table has 17 items but disk 18");
+    }
+}
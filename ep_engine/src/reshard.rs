@@ -0,0 +1,338 @@
+//! Changing the number of vbuckets of a stopped bucket's data directory,
+//! for example from 64 to 1024. Each key is rehashed to the vbucket clients
+//! will look for it in, and written into a new directory with new seqnos,
+//! keeping its CAS and revision. The collections' system events are copied
+//! into every vbucket, as each vbucket records the bucket's collections.
+
+use crate::{
+    collections,
+    item::{Item, DEFAULT_MAX_ITEM_SIZE, DEFAULT_MAX_KEY_SIZE},
+    kv_store::{
+        CommitError, CouchKVStore, CouchKVStoreConfig, KVStore, ScanErrorPolicy, ValueFilter,
+    },
+    vbucket::{State, VBucketState, Vbid},
+    vbucket_hash::vbucket_for_stored_key,
+};
+use std::{collections::BTreeMap, io, sync::Arc};
+use thiserror::Error;
+
+/// Items written to a destination vbucket per commit
+const BATCH_SIZE: usize = 1000;
+
+#[derive(Error, Debug)]
+pub enum ReshardError {
+    #[error("invalid vbucket count {0}")]
+    InvalidVBucketCount(u16),
+    #[error("{0} already holds vbucket files")]
+    DestinationNotEmpty(String),
+    #[error(transparent)]
+    Io(#[from] io::Error),
+    #[error("failed to read vbucket {vbid}: {errors}")]
+    Read { vbid: Vbid, errors: String },
+    #[error("failed to write vbucket {vbid}: {source}")]
+    Write { vbid: Vbid, source: CommitError },
+    /// Validation found the destination doesn't hold what was read
+    #[error("read {read} items but the destination holds {written}")]
+    CountMismatch { read: u64, written: u64 },
+    #[error("a key in vbucket {found} belongs in {expected}")]
+    Misplaced { found: Vbid, expected: Vbid },
+}
+
+/// What a reshard copied
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ReshardSummary {
+    /// Items and deletions, not counting system events
+    pub items: u64,
+    /// The system events copied into each vbucket
+    pub collection_events: u64,
+}
+
+fn open_store(dir: &str, max_vbuckets: u16) -> CouchKVStore {
+    CouchKVStore::new(CouchKVStoreConfig {
+        max_vbuckets,
+        db_name: dir.to_string(),
+        data_paths: Vec::new(),
+        max_shards: 1,
+        shard_id: 0,
+        history_retention: false,
+        max_key_size: DEFAULT_MAX_KEY_SIZE,
+        max_item_size: DEFAULT_MAX_ITEM_SIZE,
+        pitr_max_history_age: None,
+        sync_policy: couchstore::SyncPolicy::EveryCommit,
+        dsync: false,
+        btree_tuning: couchstore::BtreeTuning::default(),
+        front_coded_keys: false,
+        compaction_direct_io: false,
+    })
+}
+
+/// Scan a source vbucket, stopping at the first corrupt item
+fn scan(
+    store: &CouchKVStore,
+    vbid: Vbid,
+    callback: &mut dyn FnMut(Item),
+) -> Result<(), ReshardError> {
+    let result = store.scan(
+        vbid,
+        0,
+        ValueFilter::ValuesDecompressed,
+        ScanErrorPolicy::Abort,
+        callback,
+    );
+    if !result.is_complete() {
+        return Err(ReshardError::Read {
+            vbid,
+            errors: result.errors.join(", "),
+        });
+    }
+    Ok(())
+}
+
+/// The destination's vbuckets, each written in batches
+struct Destination {
+    store: CouchKVStore,
+    states: Vec<VBucketState>,
+    batches: Vec<Vec<Arc<Item>>>,
+}
+
+impl Destination {
+    fn write(&mut self, vbid: Vbid, mut item: Item) -> Result<(), ReshardError> {
+        let state = &mut self.states[usize::from(vbid)];
+        state.high_seqno += 1;
+        state.snap_start = state.high_seqno as u64;
+        state.snap_end = state.high_seqno as u64;
+        state.max_cas = state.max_cas.max(item.cas);
+        item.by_seqno = state.high_seqno as u64;
+
+        let batch = &mut self.batches[usize::from(vbid)];
+        batch.push(Arc::new(item));
+        if batch.len() >= BATCH_SIZE {
+            self.commit(vbid)?;
+        }
+        Ok(())
+    }
+
+    fn commit(&mut self, vbid: Vbid) -> Result<(), ReshardError> {
+        let items = std::mem::take(&mut self.batches[usize::from(vbid)]);
+        self.store
+            .commit(vbid, &items, &self.states[usize::from(vbid)])
+            .map_err(|source| ReshardError::Write { vbid, source })
+    }
+}
+
+/// Rewrite the data directory src_dir, of a bucket with src_vbuckets, into
+/// the new directory dest_dir with dest_vbuckets. Once written, each
+/// destination vbucket is read back to check every item is in the vbucket
+/// it hashes to and none were lost.
+pub fn reshard(
+    src_dir: &str,
+    src_vbuckets: u16,
+    dest_dir: &str,
+    dest_vbuckets: u16,
+) -> Result<ReshardSummary, ReshardError> {
+    if dest_vbuckets == 0 {
+        return Err(ReshardError::InvalidVBucketCount(dest_vbuckets));
+    }
+    std::fs::create_dir_all(dest_dir)?;
+    if !couchstore::DbNameLayout::new(dest_dir).list()?.is_empty() {
+        return Err(ReshardError::DestinationNotEmpty(dest_dir.to_string()));
+    }
+
+    let source = open_store(src_dir, src_vbuckets);
+    let src_vbids: Vec<Vbid> = source
+        .list_persisted_vbuckets()
+        .iter()
+        .enumerate()
+        .filter(|(_, state)| state.is_some())
+        .map(|(vbid, _)| Vbid::new(vbid as u16))
+        .collect();
+
+    // Every vbucket has the bucket's system events, so one has them all,
+    // which are kept in seqno order
+    let mut events = BTreeMap::new();
+    if let Some(&vbid) = src_vbids.first() {
+        scan(&source, vbid, &mut |item| {
+            if collections::collection_event_id(&item.key).is_some() {
+                events.insert(item.by_seqno, item);
+            }
+        })?;
+    }
+
+    let mut dest = Destination {
+        store: open_store(dest_dir, dest_vbuckets),
+        states: vec![VBucketState::new(State::Active); dest_vbuckets as usize],
+        batches: vec![Vec::new(); dest_vbuckets as usize],
+    };
+    // The events come first, so warmup knows the collections before it
+    // loads their items
+    for vbid in (0..dest_vbuckets).map(Vbid::new) {
+        for event in events.values() {
+            dest.write(vbid, event.clone())?;
+        }
+        dest.commit(vbid)?;
+    }
+
+    let mut items = 0;
+    for &vbid in &src_vbids {
+        let mut result = Ok(());
+        scan(&source, vbid, &mut |item| {
+            if result.is_err() || collections::collection_event_id(&item.key).is_some() {
+                return;
+            }
+            items += 1;
+            result = dest.write(vbucket_for_stored_key(&item.key, dest_vbuckets), item);
+        })?;
+        result?;
+    }
+    for vbid in (0..dest_vbuckets).map(Vbid::new) {
+        dest.commit(vbid)?;
+    }
+
+    // Read back what was written
+    let mut written = 0;
+    for vbid in (0..dest_vbuckets).map(Vbid::new) {
+        let mut misplaced = None;
+        let result = dest.store.scan(
+            vbid,
+            0,
+            ValueFilter::KeysOnly,
+            ScanErrorPolicy::Abort,
+            &mut |item| {
+                if collections::collection_event_id(&item.key).is_some() {
+                    return;
+                }
+                written += 1;
+                let expected = vbucket_for_stored_key(&item.key, dest_vbuckets);
+                if expected != vbid {
+                    misplaced.get_or_insert(expected);
+                }
+            },
+        );
+        if !result.is_complete() {
+            return Err(ReshardError::Read {
+                vbid,
+                errors: result.errors.join(", "),
+            });
+        }
+        if let Some(expected) = misplaced {
+            return Err(ReshardError::Misplaced {
+                found: vbid,
+                expected,
+            });
+        }
+    }
+    if written != items {
+        return Err(ReshardError::CountMismatch {
+            read: items,
+            written,
+        });
+    }
+
+    Ok(ReshardSummary {
+        items,
+        collection_events: events.len() as u64,
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::item::{Datatype, DeleteSource};
+
+    fn item(key: Vec<u8>, value: Option<Vec<u8>>, cas: u64) -> Item {
+        Item {
+            key,
+            value,
+            cas,
+            expiry_time: 0,
+            flags: 0,
+            by_seqno: 0,
+            rev_seqno: 3,
+            delete_source: DeleteSource::Explicit,
+            datatype: Datatype::empty(),
+        }
+    }
+
+    #[test]
+    fn test_reshard() {
+        let dirs: Vec<tempfile::TempDir> = (0..3).map(|_| tempfile::tempdir().unwrap()).collect();
+        let path = |i: usize| dirs[i].path().to_str().unwrap().to_string();
+
+        // A bucket of 4 vbuckets with a collection, and a deletion
+        let mut src = Destination {
+            store: open_store(&path(0), 4),
+            states: vec![VBucketState::new(State::Active); 4],
+            batches: vec![Vec::new(); 4],
+        };
+        let entry = collections::CollectionEntry {
+            name: "beers".to_string(),
+            scope_id: collections::DEFAULT_SCOPE,
+            max_ttl: 0,
+        };
+        let event = item(
+            collections::collection_event_key(8),
+            Some(serde_json::to_vec(&entry).unwrap()),
+            1,
+        );
+        for vbid in (0..4).map(Vbid::new) {
+            src.write(vbid, event.clone()).unwrap();
+        }
+        for i in 0..200u64 {
+            let collection = if i % 2 == 0 { 0 } else { 8 };
+            let key = collections::make_key(collection, format!("key_{i}").as_bytes());
+            let value = (i != 7).then(|| format!("value {i}").into_bytes());
+            src.write(vbucket_for_stored_key(&key, 4), item(key, value, 100 + i))
+                .unwrap();
+        }
+        for vbid in (0..4).map(Vbid::new) {
+            src.commit(vbid).unwrap();
+        }
+        drop(src);
+
+        // Split, then merge back down
+        let summary = reshard(&path(0), 4, &path(1), 16).unwrap();
+        assert_eq!(
+            summary,
+            ReshardSummary {
+                items: 200,
+                collection_events: 1
+            }
+        );
+        assert_eq!(reshard(&path(1), 16, &path(2), 2).unwrap(), summary);
+
+        let store = open_store(&path(2), 2);
+        let mut items = Vec::new();
+        for vbid in (0..2).map(Vbid::new) {
+            let state = store.get_cached_vb_state(vbid).unwrap();
+            assert!(state.max_cas >= 100);
+            let result = store.scan(
+                vbid,
+                0,
+                ValueFilter::ValuesDecompressed,
+                ScanErrorPolicy::Abort,
+                &mut |item| items.push(item),
+            );
+            assert!(result.is_complete());
+            // The event comes first
+            assert_eq!(
+                items[items.len() - (state.high_seqno as usize)].key,
+                event.key
+            );
+        }
+        assert_eq!(items.len(), 202);
+        let key_7 = collections::make_key(8, b"key_7");
+        let item_7 = items.iter().find(|item| item.key == key_7).unwrap();
+        assert!(item_7.value.is_none());
+        assert_eq!((item_7.cas, item_7.rev_seqno), (107, 3));
+
+        // The destination must be new
+        assert!(matches!(
+            reshard(&path(0), 4, &path(2), 2),
+            Err(ReshardError::DestinationNotEmpty(_))
+        ));
+        assert!(matches!(
+            reshard(&path(0), 4, &path(1), 0),
+            Err(ReshardError::InvalidVBucketCount(0))
+        ));
+    }
+}
@@ -0,0 +1,646 @@
+//! A KVStore on RocksDB, for comparing against couchstore and to keep the
+//! KVStore abstraction honest about what is couchstore specific.
+//!
+//! Each shard is one database. The by-id index, the by-seqno index and the
+//! local documents are column families whose keys start with the big endian
+//! vbid, so a vbucket's entries are contiguous and in order, as is the
+//! expiry index.
+
+use crate::{
+    checkpoint_manager::QueuedItem,
+    item::{DeleteSource, Item},
+    kv_store::{
+        decode_backup_cursors, encode_backup_cursors, expiry_index_end, expiry_index_key,
+        CommitError, CouchKVStoreConfig, KVStore, KVStoreStats, Metadata, PurgeResult,
+        ScanErrorPolicy, ScanResult, StorageProperties, VBStateCache, ValueFilter,
+    },
+    vbucket::{VBucketState, Vbid},
+};
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+use parking_lot::Mutex;
+use rocksdb::{
+    ColumnFamily, ColumnFamilyDescriptor, Direction, IteratorMode, Options, WriteBatch,
+    WriteOptions, DB,
+};
+use std::{collections::BTreeMap, io, path::Path, sync::atomic, time::Instant};
+
+/// Key to document, which is the document's seqno followed by its record
+const BY_ID: &str = "by_id";
+/// Vbid and seqno to key, only for the latest revision of each key
+const BY_SEQNO: &str = "by_seqno";
+/// Vbid to the vbucket state, and vbid followed by a name to other local
+/// documents
+const LOCAL: &str = "local";
+/// Vbid followed by an expiry index entry, with no value
+const BY_EXPIRY: &str = "by_expiry";
+
+#[derive(Debug)]
+pub struct RocksDBKVStore {
+    config: CouchKVStoreConfig,
+    db: DB,
+    cached_vb_states: VBStateCache,
+    /// What has been written since the write ahead log was last synced
+    sync_state: Mutex<couchstore::SyncState>,
+    stats: KVStoreStats,
+}
+
+impl RocksDBKVStore {
+    pub fn new(config: CouchKVStoreConfig) -> Self {
+        let path = Path::new(config.data_path()).join(format!("rocksdb.{}", config.shard_id));
+        let mut options = Options::default();
+        options.create_if_missing(true);
+        options.create_missing_column_families(true);
+        let column_families = [BY_ID, BY_SEQNO, LOCAL, BY_EXPIRY]
+            .map(|name| ColumnFamilyDescriptor::new(name, Options::default()));
+        let db = DB::open_cf_descriptors(&options, &path, column_families)
+            .unwrap_or_else(|e| panic!("Failed to open {}: {e}", path.display()));
+
+        let mut store = Self {
+            config,
+            db,
+            cached_vb_states: VBStateCache::default(),
+            sync_state: Mutex::default(),
+            stats: KVStoreStats::default(),
+        };
+
+        let vbids = Vbid::iter_for_shard(
+            store.config.shard_id,
+            store.config.max_shards,
+            store.config.max_vbuckets,
+        );
+        store.cached_vb_states = vbids
+            .map(|vbid| {
+                store.read_vb_state(vbid).unwrap_or_else(|e| {
+                    println!(
+                        "Failed to read the vbucket state of {vbid}, it won't be warmed up: {e}"
+                    );
+                    store
+                        .stats
+                        .open_failures
+                        .fetch_add(1, atomic::Ordering::Relaxed);
+                    None
+                })
+            })
+            .collect();
+
+        store
+    }
+
+    fn cf(&self, name: &str) -> &ColumnFamily {
+        self.db
+            .cf_handle(name)
+            .expect("column families are created on open")
+    }
+
+    fn read_vb_state(&self, vbid: Vbid) -> io::Result<Option<VBucketState>> {
+        let Some(json) = self
+            .db
+            .get_cf(self.cf(LOCAL), vbid_key(vbid))
+            .map_err(io::Error::other)?
+        else {
+            return Ok(None);
+        };
+        let mut vb_state = VBucketState::from_json(&json)?;
+        vb_state.high_seqno = self.read_high_seqno(vbid)? as i64;
+        Ok(Some(vb_state))
+    }
+
+    /// The latest seqno is always live, being the newest revision of its key
+    fn read_high_seqno(&self, vbid: Vbid) -> io::Result<u64> {
+        let end = seqno_key(vbid, u64::MAX);
+        let mut iter = self.db.iterator_cf(
+            self.cf(BY_SEQNO),
+            IteratorMode::From(&end, Direction::Reverse),
+        );
+        match iter.next().transpose().map_err(io::Error::other)? {
+            Some((key, _)) if key.starts_with(&vbid_key(vbid)) => (&key[2..])
+                .read_u64::<BigEndian>()
+                .map_err(|_| malformed(format!("{vbid} seqno index key {key:?}"))),
+            _ => Ok(0),
+        }
+    }
+
+    fn get_cache_slot(&self, vbid: Vbid) -> usize {
+        vbid.index_in_shard(self.config.max_shards)
+    }
+}
+
+impl KVStore for RocksDBKVStore {
+    fn commit(
+        &self,
+        vbid: Vbid,
+        items: &[QueuedItem],
+        vb_state: &VBucketState,
+    ) -> Result<(), CommitError> {
+        let start = Instant::now();
+        let mut batch = WriteBatch::default();
+        for item in items {
+            let id_key = id_key(vbid, &item.key);
+            // The key's previous revision leaves the seqno and expiry indexes
+            if let Some(previous) = self.db.get_cf(self.cf(BY_ID), &id_key)? {
+                let previous = decode_record(item.key.clone(), &previous)?;
+                batch.delete_cf(self.cf(BY_SEQNO), seqno_key(vbid, previous.by_seqno));
+                if previous.value.is_some() && previous.expiry_time != 0 {
+                    batch.delete_cf(
+                        self.cf(BY_EXPIRY),
+                        expiry_key(vbid, previous.expiry_time, &previous.key),
+                    );
+                }
+            }
+            batch.put_cf(self.cf(BY_ID), &id_key, encode_record(item));
+            batch.put_cf(self.cf(BY_SEQNO), seqno_key(vbid, item.by_seqno), &item.key);
+            if item.value.is_some() && item.expiry_time != 0 {
+                batch.put_cf(
+                    self.cf(BY_EXPIRY),
+                    expiry_key(vbid, item.expiry_time, &item.key),
+                    b"",
+                );
+            }
+        }
+        let json = serde_json::to_vec(vb_state).unwrap();
+        batch.put_cf(self.cf(LOCAL), vbid_key(vbid), json);
+
+        let bytes_written = batch.size_in_bytes() as u64;
+        let mut sync_state = self.sync_state.lock();
+        sync_state.wrote(bytes_written);
+        let sync = sync_state.is_due(self.config.sync_policy);
+        let mut write_options = WriteOptions::default();
+        write_options.set_sync(sync);
+        self.db.write_opt(batch, &write_options)?;
+        if sync {
+            sync_state.synced();
+        }
+        drop(sync_state);
+
+        let mut vb_state = vb_state.clone();
+        if let Some(last) = items.last() {
+            vb_state.high_seqno = vb_state.high_seqno.max(last.by_seqno as i64);
+        }
+        if let Some(cached) = self.get_cached_vb_state(vbid) {
+            vb_state.high_seqno = vb_state.high_seqno.max(cached.high_seqno);
+        }
+        self.cached_vb_states
+            .set(self.get_cache_slot(vbid), Some(vb_state));
+        self.stats
+            .record_commit(start, bytes_written, u64::from(sync));
+        Ok(())
+    }
+
+    fn snapshot_vbucket(&self, vbid: Vbid, vb_state: &VBucketState) -> Result<(), CommitError> {
+        self.commit(vbid, &[], vb_state)
+    }
+
+    fn get_cached_vb_state(&self, vbid: Vbid) -> Option<VBucketState> {
+        self.cached_vb_states.get(self.get_cache_slot(vbid))
+    }
+
+    fn list_persisted_vbuckets(&self) -> Vec<Option<VBucketState>> {
+        self.cached_vb_states.snapshot()
+    }
+
+    fn scan(
+        &self,
+        vbid: Vbid,
+        start_seqno: u64,
+        value_filter: ValueFilter,
+        on_error: ScanErrorPolicy,
+        callback: &mut dyn FnMut(Item),
+    ) -> ScanResult {
+        let mut result = ScanResult::default();
+        let start = seqno_key(vbid, start_seqno);
+        let iter = self.db.iterator_cf(
+            self.cf(BY_SEQNO),
+            IteratorMode::From(&start, Direction::Forward),
+        );
+        for entry in iter {
+            let (seqno_key, key) = match entry {
+                Ok(entry) => entry,
+                Err(e) => {
+                    println!("Failed to scan {vbid}: {e}");
+                    result.errors.push(e.to_string());
+                    result.aborted = true;
+                    return result;
+                }
+            };
+            if !seqno_key.starts_with(&vbid_key(vbid)) {
+                break;
+            }
+            let item = self
+                .db
+                .get_cf(self.cf(BY_ID), id_key(vbid, &key))
+                .map_err(io::Error::other)
+                .and_then(|record| {
+                    let record = record.ok_or_else(|| {
+                        malformed(format!("{vbid} seqno index refers to a missing key"))
+                    })?;
+                    decode_record(key.to_vec(), &record)
+                });
+            let mut item = match item {
+                Ok(item) => item,
+                Err(e) if on_error == ScanErrorPolicy::SkipAndReport => {
+                    result.errors.push(format!("key {key:?}: {e}"));
+                    continue;
+                }
+                Err(e) => {
+                    println!("Failed to scan {vbid}: {e}");
+                    result.errors.push(e.to_string());
+                    result.aborted = true;
+                    return result;
+                }
+            };
+            // Values are stored as they were written
+            match value_filter {
+                ValueFilter::KeysOnly => item.value = None,
+                ValueFilter::ValuesCompressed => {}
+                ValueFilter::ValuesDecompressed => item.inflate(),
+            }
+            callback(item);
+        }
+        result
+    }
+
+    fn get_multi(&self, vbid: Vbid, keys: &[Vec<u8>]) -> io::Result<Vec<Option<Item>>> {
+        keys.iter()
+            .map(|key| {
+                let Some(record) = self
+                    .db
+                    .get_cf(self.cf(BY_ID), id_key(vbid, key))
+                    .map_err(io::Error::other)?
+                else {
+                    return Ok(None);
+                };
+                let mut item = decode_record(key.clone(), &record)?;
+                item.inflate();
+                Ok(Some(item))
+            })
+            .collect()
+    }
+
+    fn get_backup_cursors(&self, vbid: Vbid) -> io::Result<BTreeMap<String, u64>> {
+        self.db
+            .get_cf(self.cf(LOCAL), backup_cursors_key(vbid))
+            .map_err(io::Error::other)?
+            .map_or_else(|| Ok(BTreeMap::new()), |json| decode_backup_cursors(&json))
+    }
+
+    fn set_backup_cursors(&self, vbid: Vbid, cursors: &BTreeMap<String, u64>) -> io::Result<()> {
+        let mut batch = WriteBatch::default();
+        batch.put_cf(
+            self.cf(LOCAL),
+            backup_cursors_key(vbid),
+            encode_backup_cursors(cursors),
+        );
+        let mut write_options = WriteOptions::default();
+        write_options.set_sync(true);
+        self.db
+            .write_opt(batch, &write_options)
+            .map_err(io::Error::other)
+    }
+
+    fn purge_tombstones(
+        &self,
+        vbid: Vbid,
+        purge_before: u32,
+        max_seqno: u64,
+    ) -> io::Result<PurgeResult> {
+        let mut result = PurgeResult::default();
+        let Some(mut vb_state) = self.get_cached_vb_state(vbid) else {
+            return Ok(result);
+        };
+        let mut batch = WriteBatch::default();
+        let start = seqno_key(vbid, 0);
+        let iter = self.db.iterator_cf(
+            self.cf(BY_SEQNO),
+            IteratorMode::From(&start, Direction::Forward),
+        );
+        for entry in iter {
+            let (seqno_key, key) = entry.map_err(io::Error::other)?;
+            if !seqno_key.starts_with(&vbid_key(vbid)) {
+                break;
+            }
+            let id_key = id_key(vbid, &key);
+            let record = self
+                .db
+                .get_cf(self.cf(BY_ID), &id_key)
+                .map_err(io::Error::other)?
+                .ok_or_else(|| malformed(format!("{vbid} seqno index refers to a missing key")))?;
+            let size = (id_key.len() + record.len()) as u64;
+            let item = decode_record(key.into_vec(), &record)?;
+            if item.value.is_none()
+                && result.check(
+                    item.by_seqno,
+                    item.expiry_time,
+                    size,
+                    purge_before,
+                    max_seqno,
+                )
+            {
+                batch.delete_cf(self.cf(BY_ID), &id_key);
+                batch.delete_cf(self.cf(BY_SEQNO), &seqno_key);
+                vb_state.purge_seqno = vb_state.purge_seqno.max(item.by_seqno);
+            }
+        }
+        if result.purged == 0 {
+            return Ok(result);
+        }
+
+        let json = serde_json::to_vec(&vb_state)?;
+        batch.put_cf(self.cf(LOCAL), vbid_key(vbid), json);
+        let mut write_options = WriteOptions::default();
+        write_options.set_sync(true);
+        self.db
+            .write_opt(batch, &write_options)
+            .map_err(io::Error::other)?;
+        self.cached_vb_states
+            .set(self.get_cache_slot(vbid), Some(vb_state));
+        Ok(result)
+    }
+
+    fn get_expired_keys(&self, vbid: Vbid, now: u32) -> io::Result<Vec<Vec<u8>>> {
+        let end = id_key(vbid, &expiry_index_end(now));
+        let start = vbid_key(vbid);
+        let iter = self.db.iterator_cf(
+            self.cf(BY_EXPIRY),
+            IteratorMode::From(&start, Direction::Forward),
+        );
+        let mut keys = Vec::new();
+        for entry in iter {
+            let (expiry_key, _) = entry.map_err(io::Error::other)?;
+            if expiry_key[..] >= end[..] {
+                break;
+            }
+            keys.push(expiry_key[6..].to_vec());
+        }
+        Ok(keys)
+    }
+
+    fn list_keys(
+        &self,
+        vbid: Vbid,
+        start_key: &[u8],
+        end_key: Option<&[u8]>,
+        limit: usize,
+    ) -> io::Result<Vec<Vec<u8>>> {
+        let start = id_key(vbid, start_key);
+        let end = end_key.map(|end_key| id_key(vbid, end_key));
+        let iter = self.db.iterator_cf(
+            self.cf(BY_ID),
+            IteratorMode::From(&start, Direction::Forward),
+        );
+        let mut keys = Vec::new();
+        for entry in iter {
+            if keys.len() == limit {
+                break;
+            }
+            let (id_key, record) = entry.map_err(io::Error::other)?;
+            // The next vbucket's keys follow
+            if id_key[..2] != vbid_key(vbid)
+                || end.as_ref().is_some_and(|end| id_key[..] >= end[..])
+            {
+                break;
+            }
+            let key = id_key[2..].to_vec();
+            if decode_record(key.clone(), &record)?.value.is_some() {
+                keys.push(key);
+            }
+        }
+        Ok(keys)
+    }
+
+    fn sync_pending_commits(&self) {
+        let mut sync_state = self.sync_state.lock();
+        if sync_state.unsynced_bytes() == 0 {
+            return;
+        }
+        // Left unsynced to try again next time
+        if let Err(e) = self.db.flush_wal(true) {
+            println!(
+                "Failed to sync the write ahead log of shard {}: {e}",
+                self.config.shard_id
+            );
+            return;
+        }
+        sync_state.synced();
+        self.stats.fsyncs.fetch_add(1, atomic::Ordering::Relaxed);
+    }
+
+    fn get_storage_properties(&self) -> StorageProperties {
+        StorageProperties {
+            historical_snapshots: false,
+            by_id_scan: true,
+            concurrent_write_compaction: true,
+            automatic_deduplication: false,
+        }
+    }
+
+    fn get_stats(&self) -> &KVStoreStats {
+        &self.stats
+    }
+}
+
+fn vbid_key(vbid: Vbid) -> [u8; 2] {
+    u16::from(vbid).to_be_bytes()
+}
+
+/// Follows the vbucket's state in the local column family
+fn backup_cursors_key(vbid: Vbid) -> Vec<u8> {
+    let mut key = vbid_key(vbid).to_vec();
+    key.extend_from_slice(b"backup_cursors");
+    key
+}
+
+fn id_key(vbid: Vbid, key: &[u8]) -> Vec<u8> {
+    let mut id_key = Vec::with_capacity(2 + key.len());
+    id_key.extend_from_slice(&vbid_key(vbid));
+    id_key.extend_from_slice(key);
+    id_key
+}
+
+fn seqno_key(vbid: Vbid, seqno: u64) -> Vec<u8> {
+    let mut seqno_key = vbid_key(vbid).to_vec();
+    seqno_key.extend_from_slice(&seqno.to_be_bytes());
+    seqno_key
+}
+
+fn expiry_key(vbid: Vbid, expiry_time: u32, key: &[u8]) -> Vec<u8> {
+    id_key(vbid, &expiry_index_key(expiry_time, key))
+}
+
+/// seqno, rev seqno, metadata length, metadata, deleted flag, then the value
+fn encode_record(item: &Item) -> Vec<u8> {
+    let mut metadata = Vec::with_capacity(Metadata::ENCODED_SIZE_V3);
+    Metadata {
+        cas: item.cas,
+        expiry_time: item.expiry_time,
+        flags: item.flags,
+        delete_source: if item.value.is_none() {
+            item.delete_source
+        } else {
+            DeleteSource::Explicit
+        },
+        datatype: item.datatype,
+    }
+    .encode(&mut metadata)
+    .unwrap();
+
+    let value = item.value.as_deref().unwrap_or_default();
+    let mut record = Vec::with_capacity(18 + metadata.len() + value.len());
+    record.write_u64::<BigEndian>(item.by_seqno).unwrap();
+    record.write_u64::<BigEndian>(item.rev_seqno).unwrap();
+    record.write_u8(metadata.len() as u8).unwrap();
+    record.extend_from_slice(&metadata);
+    record.write_u8(item.value.is_none() as u8).unwrap();
+    record.extend_from_slice(value);
+    record
+}
+
+fn malformed(what: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, what)
+}
+
+/// The smallest metadata encoding holds the cas, expiry time and flags
+const MIN_METADATA_SIZE: usize = 16;
+
+fn decode_record(key: Vec<u8>, mut record: &[u8]) -> io::Result<Item> {
+    let truncated = || malformed(format!("truncated record of key {key:?}"));
+    let by_seqno = record.read_u64::<BigEndian>().map_err(|_| truncated())?;
+    let rev_seqno = record.read_u64::<BigEndian>().map_err(|_| truncated())?;
+    let metadata_len = record.read_u8().map_err(|_| truncated())? as usize;
+    if metadata_len < MIN_METADATA_SIZE || record.len() <= metadata_len {
+        return Err(truncated());
+    }
+    let metadata = Metadata::decode(&record[..metadata_len]);
+    record = &record[metadata_len..];
+    let deleted = record.read_u8().map_err(|_| truncated())? != 0;
+    let value = if deleted { None } else { Some(record.to_vec()) };
+    Ok(Item {
+        key,
+        value,
+        cas: metadata.cas,
+        expiry_time: metadata.expiry_time,
+        flags: metadata.flags,
+        by_seqno,
+        rev_seqno,
+        delete_source: metadata.delete_source,
+        datatype: metadata.datatype,
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{
+        item::{Datatype, DEFAULT_MAX_ITEM_SIZE, DEFAULT_MAX_KEY_SIZE},
+        vbucket::State,
+    };
+    use std::sync::Arc;
+
+    fn test_config(dir: &tempfile::TempDir) -> CouchKVStoreConfig {
+        CouchKVStoreConfig {
+            max_vbuckets: 4,
+            db_name: dir.path().to_str().unwrap().to_string(),
+            data_paths: Vec::new(),
+            max_shards: 1,
+            shard_id: 0,
+            history_retention: false,
+            max_key_size: DEFAULT_MAX_KEY_SIZE,
+            max_item_size: DEFAULT_MAX_ITEM_SIZE,
+            pitr_max_history_age: None,
+            sync_policy: couchstore::SyncPolicy::EveryCommit,
+            dsync: false,
+            btree_tuning: couchstore::BtreeTuning::default(),
+            front_coded_keys: false,
+            compaction_direct_io: false,
+        }
+    }
+
+    fn item(key: &str, seqno: u64, value: Option<&str>) -> QueuedItem {
+        Arc::new(Item {
+            key: key.as_bytes().to_vec(),
+            value: value.map(|value| value.as_bytes().to_vec()),
+            cas: seqno,
+            expiry_time: 0,
+            flags: 0,
+            by_seqno: seqno,
+            rev_seqno: 1,
+            delete_source: DeleteSource::Explicit,
+            datatype: Datatype::empty(),
+        })
+    }
+
+    #[test]
+    fn test_commit_and_scan() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = test_config(&dir);
+        let vbid = Vbid::new(1);
+
+        {
+            let store = RocksDBKVStore::new(config.clone());
+            let vb_state = VBucketState::new(State::Active);
+            store
+                .commit(
+                    vbid,
+                    &[item("a", 1, Some("1")), item("b", 2, Some("2"))],
+                    &vb_state,
+                )
+                .unwrap();
+            store
+                .commit(vbid, &[item("a", 3, None)], &vb_state)
+                .unwrap();
+        }
+
+        // Only the latest revision of each key remains after a reopen
+        let store = RocksDBKVStore::new(config);
+        assert_eq!(store.get_cached_vb_state(vbid).unwrap().high_seqno, 3);
+        let mut items = Vec::new();
+        store.scan(
+            vbid,
+            0,
+            ValueFilter::ValuesDecompressed,
+            ScanErrorPolicy::Abort,
+            &mut |item| items.push((item.by_seqno, item.value)),
+        );
+        assert_eq!(items, vec![(2, Some(b"2".to_vec())), (3, None)]);
+    }
+
+    #[test]
+    fn test_malformed_records() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = RocksDBKVStore::new(test_config(&dir));
+        let vbid = Vbid::new(1);
+        store
+            .commit(
+                vbid,
+                &[item("a", 1, Some("1"))],
+                &VBucketState::new(State::Active),
+            )
+            .unwrap();
+        // A seqno index entry without its record, and a truncated record
+        let db = &store.db;
+        db.put_cf(store.cf(BY_SEQNO), seqno_key(vbid, 2), b"gone")
+            .unwrap();
+        db.put_cf(store.cf(BY_SEQNO), seqno_key(vbid, 3), b"bad")
+            .unwrap();
+        db.put_cf(store.cf(BY_ID), id_key(vbid, b"bad"), 3u64.to_be_bytes())
+            .unwrap();
+
+        let scan = |on_error| {
+            let mut keys = Vec::new();
+            let result = store.scan(vbid, 0, ValueFilter::KeysOnly, on_error, &mut |item| {
+                keys.push(item.key)
+            });
+            (keys, result)
+        };
+        let (keys, result) = scan(ScanErrorPolicy::SkipAndReport);
+        assert_eq!(keys, vec![b"a".to_vec()]);
+        assert_eq!(result.errors.len(), 2);
+        assert!(!result.aborted);
+        let (keys, result) = scan(ScanErrorPolicy::Abort);
+        assert_eq!(keys, vec![b"a".to_vec()]);
+        assert_eq!(result.errors.len(), 1);
+        assert!(result.aborted);
+
+        let e = store.get_multi(vbid, &[b"bad".to_vec()]).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+    }
+}
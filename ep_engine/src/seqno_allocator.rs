@@ -0,0 +1,134 @@
+//! Handing out a vbucket's seqnos. An active vbucket numbers its own
+//! mutations, a replica takes the seqnos the active assigned, within the
+//! snapshot it is receiving. Either way seqnos only go up: the allocator
+//! starts from the high seqno persisted in the vbucket state, which is at
+//! least every seqno that reached disk, so none is reused after a crash.
+
+use std::sync::atomic::{AtomicU64, Ordering};
+use thiserror::Error;
+
+#[derive(Error, Debug, PartialEq, Eq)]
+pub enum SeqnoError {
+    #[error("seqno {seqno} is not above the high seqno {high_seqno}")]
+    NotAboveHigh { seqno: u64, high_seqno: u64 },
+    #[error("seqno {seqno} is past the end of the snapshot at {snap_end}")]
+    PastSnapshot { seqno: u64, snap_end: u64 },
+    #[error("invalid snapshot {snap_start}-{snap_end}")]
+    InvalidSnapshot { snap_start: u64, snap_end: u64 },
+}
+
+#[derive(Debug)]
+pub struct SeqnoAllocator {
+    high_seqno: AtomicU64,
+    /// End of the snapshot a replica is receiving, which the active vbucket
+    /// has already numbered
+    snap_end: AtomicU64,
+}
+
+impl SeqnoAllocator {
+    /// Continue after high_seqno, the highest seqno the vbucket has used
+    pub fn new(high_seqno: u64) -> Self {
+        Self {
+            high_seqno: AtomicU64::new(high_seqno),
+            snap_end: AtomicU64::new(high_seqno),
+        }
+    }
+
+    pub fn high_seqno(&self) -> u64 {
+        self.high_seqno.load(Ordering::SeqCst)
+    }
+
+    /// The seqno for a new mutation
+    pub fn next(&self) -> u64 {
+        self.high_seqno.fetch_add(1, Ordering::SeqCst) + 1
+    }
+
+    /// Set aside the seqnos up to snap_end for a snapshot received from the
+    /// active vbucket, which accept then takes in order. The snapshot may
+    /// start at or below the high seqno when resuming one received in part.
+    pub fn reserve_snapshot(&self, snap_start: u64, snap_end: u64) -> Result<(), SeqnoError> {
+        if snap_start > snap_end || snap_end < self.high_seqno() {
+            return Err(SeqnoError::InvalidSnapshot {
+                snap_start,
+                snap_end,
+            });
+        }
+        self.snap_end.store(snap_end, Ordering::SeqCst);
+        Ok(())
+    }
+
+    /// Take a seqno assigned by the active vbucket, which must be above the
+    /// high seqno and within the reserved snapshot
+    pub fn accept(&self, seqno: u64) -> Result<(), SeqnoError> {
+        let snap_end = self.snap_end.load(Ordering::SeqCst);
+        if seqno > snap_end {
+            return Err(SeqnoError::PastSnapshot { seqno, snap_end });
+        }
+        self.high_seqno
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |high_seqno| {
+                (seqno > high_seqno).then_some(seqno)
+            })
+            .map(|_| ())
+            .map_err(|high_seqno| SeqnoError::NotAboveHigh { seqno, high_seqno })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::collections::HashSet;
+
+    #[test]
+    fn test_concurrent_allocation() {
+        let allocator = SeqnoAllocator::new(100);
+        let seqnos: Vec<Vec<u64>> = std::thread::scope(|scope| {
+            let handles: Vec<_> = (0..8)
+                .map(|_| {
+                    scope.spawn(|| {
+                        let seqnos: Vec<u64> = (0..1000).map(|_| allocator.next()).collect();
+                        // Each thread sees its own seqnos go up
+                        assert!(seqnos.windows(2).all(|pair| pair[0] < pair[1]));
+                        seqnos
+                    })
+                })
+                .collect();
+            handles.into_iter().map(|h| h.join().unwrap()).collect()
+        });
+
+        let all: HashSet<u64> = seqnos.into_iter().flatten().collect();
+        assert_eq!(all.len(), 8000);
+        assert_eq!(all, (101..=8100).collect());
+        assert_eq!(allocator.high_seqno(), 8100);
+    }
+
+    #[test]
+    fn test_reserve_snapshot() {
+        let allocator = SeqnoAllocator::new(5);
+        assert_eq!(
+            allocator.accept(6),
+            Err(SeqnoError::PastSnapshot {
+                seqno: 6,
+                snap_end: 5
+            })
+        );
+        assert!(allocator.reserve_snapshot(10, 4).is_err());
+        assert!(allocator.reserve_snapshot(1, 4).is_err());
+
+        // Resuming a snapshot received up to seqno 5
+        allocator.reserve_snapshot(3, 10).unwrap();
+        assert_eq!(
+            allocator.accept(5),
+            Err(SeqnoError::NotAboveHigh {
+                seqno: 5,
+                high_seqno: 5
+            })
+        );
+        // Deduplication leaves gaps
+        allocator.accept(7).unwrap();
+        allocator.accept(10).unwrap();
+        assert!(allocator.accept(9).is_err());
+        assert!(allocator.accept(11).is_err());
+        assert_eq!(allocator.high_seqno(), 10);
+        assert_eq!(allocator.next(), 11);
+    }
+}
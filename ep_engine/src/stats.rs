@@ -0,0 +1,274 @@
+use crate::{
+    memory_tracker::{MemoryDomain, MemoryTracker},
+    reconcile::ReconcileStats,
+    warmup::WarmupProgress,
+    Config,
+};
+use std::sync::{
+    atomic::{AtomicU64, AtomicUsize, Ordering},
+    Arc,
+};
+
+/// Statistics for a bucket, shared by all of its components
+#[derive(Debug)]
+pub struct EPStats {
+    pub memory: MemoryTracker,
+    /// Bucket memory quota in bytes
+    max_data_size: AtomicUsize,
+    /// Once memory usage drops below this the item pager stops evicting
+    mem_low_wat: AtomicUsize,
+    /// Once memory usage exceeds this the item pager starts evicting
+    mem_high_wat: AtomicUsize,
+    /// Items expelled from checkpoints to free memory
+    pub items_expelled_from_checkpoints: AtomicU64,
+    /// Bytes freed by expelling items from checkpoints
+    pub mem_freed_by_checkpoint_item_expel: AtomicU64,
+    /// Bytes freed by removing closed, unreferenced checkpoints
+    pub mem_freed_by_checkpoint_removal: AtomicU64,
+    /// Replication cursors dropped to free checkpoint memory
+    pub cursors_dropped: AtomicU64,
+    /// Reads which found the item's expiry time had passed and expired it
+    pub expired_access: AtomicU64,
+    /// Total size of the values written, before any compression
+    pub value_bytes_uncompressed: AtomicU64,
+    /// Total size of the values written, as stored
+    pub value_bytes_stored: AtomicU64,
+    /// Compressed values inflated for clients which can't receive them
+    pub values_inflated: AtomicU64,
+    /// Locks taken by GET_LOCKED
+    pub locks_taken: AtomicU64,
+    /// Operations rejected because the key was locked
+    pub lock_errors: AtomicU64,
+    /// Writes rejected by their collection's validator
+    pub writes_rejected: AtomicU64,
+    /// Values read back from disk because they weren't resident
+    pub bg_fetched: AtomicU64,
+    /// Bulk reads issued to fetch non-resident values
+    pub bg_fetch_batches: AtomicU64,
+    /// Deletions purged from disk
+    pub tombstones_purged: AtomicU64,
+    /// Items deleted by the expiry pager
+    pub expired_pager: AtomicU64,
+    /// Completed scrubs of every vbucket
+    pub scrub_passes: AtomicU64,
+    /// Vbuckets scrubbed so far in the current pass
+    pub scrub_vbuckets_done: AtomicU64,
+    pub scrub_bytes_read: AtomicU64,
+    /// Chunks scrubbing found to be unreadable or inconsistent
+    pub scrub_corrupt_chunks: AtomicU64,
+    /// Vbuckets whose files were truncated to recover from corruption
+    pub corrupt_vbuckets_recovered: AtomicU64,
+    /// Persisted seqnos discarded by those truncations
+    pub corruption_seqnos_lost: AtomicU64,
+    /// Front-end operations which took longer than the slow op threshold
+    pub slow_ops: AtomicU64,
+    /// Flusher commits which failed, counting each retry
+    pub item_commit_failed: AtomicU64,
+    /// Vbuckets whose latest flush failed, so have mutations which can't be
+    /// persisted yet
+    pub vbuckets_persistence_failing: AtomicU64,
+    /// Vbuckets marked dead after too many flushes failed in a row, until
+    /// released
+    pub vbuckets_quarantined: AtomicU64,
+    /// Bytes free on the data directory's disk when last checked
+    pub disk_free_bytes: AtomicU64,
+    /// Corrupt items found by scans of the vbuckets' files
+    pub scan_corrupt_items: AtomicU64,
+    /// Vbuckets marked for re-replication after a scan hit corruption
+    pub vbuckets_need_rereplication: AtomicU64,
+    pub warmup: WarmupProgress,
+    pub reconcile: ReconcileStats,
+}
+
+pub type EPStatsPtr = Arc<EPStats>;
+
+impl EPStats {
+    pub fn new(config: &Config) -> Self {
+        let stats = Self {
+            memory: MemoryTracker::new(),
+            max_data_size: AtomicUsize::new(0),
+            mem_low_wat: AtomicUsize::new(0),
+            mem_high_wat: AtomicUsize::new(0),
+            items_expelled_from_checkpoints: AtomicU64::new(0),
+            mem_freed_by_checkpoint_item_expel: AtomicU64::new(0),
+            mem_freed_by_checkpoint_removal: AtomicU64::new(0),
+            cursors_dropped: AtomicU64::new(0),
+            expired_access: AtomicU64::new(0),
+            value_bytes_uncompressed: AtomicU64::new(0),
+            value_bytes_stored: AtomicU64::new(0),
+            values_inflated: AtomicU64::new(0),
+            locks_taken: AtomicU64::new(0),
+            lock_errors: AtomicU64::new(0),
+            writes_rejected: AtomicU64::new(0),
+            bg_fetched: AtomicU64::new(0),
+            bg_fetch_batches: AtomicU64::new(0),
+            tombstones_purged: AtomicU64::new(0),
+            expired_pager: AtomicU64::new(0),
+            scrub_passes: AtomicU64::new(0),
+            scrub_vbuckets_done: AtomicU64::new(0),
+            scrub_bytes_read: AtomicU64::new(0),
+            scrub_corrupt_chunks: AtomicU64::new(0),
+            corrupt_vbuckets_recovered: AtomicU64::new(0),
+            corruption_seqnos_lost: AtomicU64::new(0),
+            slow_ops: AtomicU64::new(0),
+            item_commit_failed: AtomicU64::new(0),
+            vbuckets_persistence_failing: AtomicU64::new(0),
+            vbuckets_quarantined: AtomicU64::new(0),
+            scan_corrupt_items: AtomicU64::new(0),
+            vbuckets_need_rereplication: AtomicU64::new(0),
+            disk_free_bytes: AtomicU64::new(0),
+            warmup: WarmupProgress::default(),
+            reconcile: ReconcileStats::default(),
+        };
+        stats.set_max_data_size(config.max_size, config.mem_low_wat, config.mem_high_wat);
+        stats
+    }
+
+    /// Set the bucket quota, recalculating the watermarks from the given
+    /// fractions of the quota.
+    pub fn set_max_data_size(&self, size: usize, low_wat_ratio: f64, high_wat_ratio: f64) {
+        assert!(low_wat_ratio <= high_wat_ratio && high_wat_ratio <= 1.0);
+        self.max_data_size.store(size, Ordering::Relaxed);
+        self.mem_low_wat
+            .store((size as f64 * low_wat_ratio) as usize, Ordering::Relaxed);
+        self.mem_high_wat
+            .store((size as f64 * high_wat_ratio) as usize, Ordering::Relaxed);
+    }
+
+    pub fn get_max_data_size(&self) -> usize {
+        self.max_data_size.load(Ordering::Relaxed)
+    }
+
+    pub fn get_mem_low_wat(&self) -> usize {
+        self.mem_low_wat.load(Ordering::Relaxed)
+    }
+
+    pub fn get_mem_high_wat(&self) -> usize {
+        self.mem_high_wat.load(Ordering::Relaxed)
+    }
+
+    pub fn get_estimated_total_memory_used(&self) -> usize {
+        self.memory.estimated_total()
+    }
+
+    pub fn get_precise_total_memory_used(&self) -> usize {
+        self.memory.precise_total()
+    }
+
+    /// Should the item pager run to bring memory usage back down
+    pub fn is_above_high_watermark(&self) -> bool {
+        self.get_precise_total_memory_used() > self.get_mem_high_wat()
+    }
+
+    /// Has the item pager freed enough memory to stop
+    pub fn is_below_low_watermark(&self) -> bool {
+        self.get_precise_total_memory_used() < self.get_mem_low_wat()
+    }
+
+    pub fn add_stats(&self, add_stat: &mut dyn FnMut(&str, &str)) {
+        add_stat(
+            "mem_used",
+            &self.get_precise_total_memory_used().to_string(),
+        );
+        add_stat(
+            "mem_used_estimate",
+            &self.get_estimated_total_memory_used().to_string(),
+        );
+        for domain in MemoryDomain::ALL {
+            add_stat(
+                domain.stat_name(),
+                &self.memory.domain_used(domain).to_string(),
+            );
+        }
+        add_stat("ep_arena", &self.memory.arena_id().to_string());
+        add_stat("ep_max_size", &self.get_max_data_size().to_string());
+        add_stat("ep_mem_low_wat", &self.get_mem_low_wat().to_string());
+        add_stat("ep_mem_high_wat", &self.get_mem_high_wat().to_string());
+        add_stat(
+            "ep_items_expelled_from_checkpoints",
+            &load(&self.items_expelled_from_checkpoints),
+        );
+        add_stat(
+            "ep_checkpoint_memory_freed_by_expel",
+            &load(&self.mem_freed_by_checkpoint_item_expel),
+        );
+        add_stat(
+            "ep_checkpoint_memory_freed_by_removal",
+            &load(&self.mem_freed_by_checkpoint_removal),
+        );
+        add_stat("ep_cursors_dropped", &load(&self.cursors_dropped));
+        add_stat("ep_expired_access", &load(&self.expired_access));
+        let uncompressed = self.value_bytes_uncompressed.load(Ordering::Relaxed);
+        let stored = self.value_bytes_stored.load(Ordering::Relaxed);
+        add_stat("ep_value_bytes_uncompressed", &uncompressed.to_string());
+        add_stat("ep_value_bytes_stored", &stored.to_string());
+        // Uncompressed over stored size, 1 until values are written
+        let ratio = if stored == 0 {
+            1.0
+        } else {
+            uncompressed as f64 / stored as f64
+        };
+        add_stat("ep_compression_ratio", &format!("{ratio:.2}"));
+        add_stat("ep_values_inflated", &load(&self.values_inflated));
+        add_stat("ep_locks_taken", &load(&self.locks_taken));
+        add_stat("ep_lock_errors", &load(&self.lock_errors));
+        add_stat("ep_writes_rejected", &load(&self.writes_rejected));
+        add_stat("ep_bg_fetched", &load(&self.bg_fetched));
+        add_stat("ep_bg_fetch_batches", &load(&self.bg_fetch_batches));
+        add_stat("ep_tombstones_purged", &load(&self.tombstones_purged));
+        add_stat("ep_expired_pager", &load(&self.expired_pager));
+        add_stat("ep_scrub_passes", &load(&self.scrub_passes));
+        add_stat("ep_scrub_vbuckets_done", &load(&self.scrub_vbuckets_done));
+        add_stat("ep_scrub_bytes_read", &load(&self.scrub_bytes_read));
+        add_stat("ep_scrub_corrupt_chunks", &load(&self.scrub_corrupt_chunks));
+        add_stat(
+            "ep_corrupt_vbuckets_recovered",
+            &load(&self.corrupt_vbuckets_recovered),
+        );
+        add_stat(
+            "ep_corruption_seqnos_lost",
+            &load(&self.corruption_seqnos_lost),
+        );
+        add_stat("ep_slow_ops", &load(&self.slow_ops));
+        add_stat("ep_item_commit_failed", &load(&self.item_commit_failed));
+        add_stat(
+            "ep_vbuckets_persistence_failing",
+            &load(&self.vbuckets_persistence_failing),
+        );
+        add_stat("ep_vbuckets_quarantined", &load(&self.vbuckets_quarantined));
+        add_stat("ep_disk_free_bytes", &load(&self.disk_free_bytes));
+        add_stat("ep_scan_corrupt_items", &load(&self.scan_corrupt_items));
+        add_stat(
+            "ep_vbuckets_need_rereplication",
+            &load(&self.vbuckets_need_rereplication),
+        );
+        add_stat("ep_warmup_state", self.warmup.phase().name());
+    }
+}
+
+fn load(stat: &AtomicU64) -> String {
+    stat.load(Ordering::Relaxed).to_string()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_watermarks() {
+        let config = Config {
+            max_size: 1000,
+            ..Default::default()
+        };
+        let stats = EPStats::new(&config);
+        assert_eq!(stats.get_mem_low_wat(), 750);
+        assert_eq!(stats.get_mem_high_wat(), 850);
+
+        stats.memory.mem_allocated(MemoryDomain::HashTable, 900);
+        assert!(stats.is_above_high_watermark());
+
+        stats.memory.mem_deallocated(MemoryDomain::HashTable, 200);
+        assert!(!stats.is_above_high_watermark());
+        assert!(stats.is_below_low_watermark());
+    }
+}
@@ -1,8 +1,11 @@
-use crate::item::Item;
+use crate::{
+    compression,
+    item::{Datatype, Item},
+};
 use bitflags::bitflags;
 
 /// Value that is stored in the hash table
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Default)]
 pub struct StoredValue {
     /// The value itself, None if the item has been
     /// evicted from memory
@@ -12,20 +15,29 @@ pub struct StoredValue {
     pub expiry_time: u32,
     pub flags: u32,
     pub rev_seqno: u64,
+    pub datatype: Datatype,
+    /// When the key is locked by GET_LOCKED, the time (in seconds) the lock
+    /// expires
+    pub lock_expiry: u32,
     pub(crate) bits: StoredValueBits,
 }
 
 bitflags! {
     #[derive(Default, Debug, Clone, Copy)]
     pub struct StoredValueBits: u8 {
-        const IS_DIRTY = 0;
-        const IS_DELETED = 1;
-        const IS_RESIDENT = 2;
-        const IS_STALE = 3;
+        const IS_DIRTY = 1 << 0;
+        const IS_DELETED = 1 << 1;
+        const IS_RESIDENT = 1 << 2;
+        const IS_STALE = 1 << 3;
     }
 }
 
 impl StoredValue {
+    /// Memory used by this value and its metadata, excluding the key
+    pub fn size(&self) -> usize {
+        std::mem::size_of::<Self>() + self.value.as_ref().map_or(0, |value| value.len())
+    }
+
     pub fn mark_not_resident(&mut self) {
         self.value = None;
         self.bits.remove(StoredValueBits::IS_RESIDENT);
@@ -39,17 +51,45 @@ impl StoredValue {
         self.bits.remove(StoredValueBits::IS_DIRTY);
     }
 
+    pub fn mark_dirty(&mut self) {
+        self.bits.insert(StoredValueBits::IS_DIRTY);
+    }
+
+    pub fn is_dirty(&self) -> bool {
+        self.bits.contains(StoredValueBits::IS_DIRTY)
+    }
+
     pub fn mark_resident(&mut self) {
         self.bits.insert(StoredValueBits::IS_RESIDENT);
     }
 
+    pub fn is_deleted(&self) -> bool {
+        self.bits.contains(StoredValueBits::IS_DELETED)
+    }
+
+    /// Whether a GET_LOCKED lock is held on the key at time `now`
+    pub fn is_locked(&self, now: u32) -> bool {
+        self.lock_expiry > now
+    }
+
+    /// Decompress the value if it is snappy compressed, for a client which
+    /// can't receive compressed values
+    pub fn inflate(&mut self) {
+        compression::inflate_in_place(&mut self.value, &mut self.datatype);
+    }
+
     pub fn restore_value(&mut self, item: Item) {
+        self.bits
+            .set(StoredValueBits::IS_DELETED, item.value.is_none());
         self.value = item.value;
         self.cas = item.cas;
         self.by_seqno = item.by_seqno;
         self.expiry_time = item.expiry_time;
         self.flags = item.flags;
         self.rev_seqno = item.rev_seqno;
+        self.datatype = item.datatype;
+        // Any mutation releases the lock
+        self.lock_expiry = 0;
 
         self.mark_resident();
     }
@@ -0,0 +1,317 @@
+//! Giving every document in a collection a new expiry time, as an admin
+//! operation. A background task touches the collection's documents a
+//! vbucket at a time, each queued as a mutation with a new CAS and seqno so
+//! the new expiry is persisted and replicated like any write. Documents
+//! evicted from memory are fetched back first, since their values are
+//! written again with the new expiry.
+
+use parking_lot::Mutex;
+use std::{
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Weak,
+    },
+    time::{Duration, Instant},
+};
+
+use crate::{
+    collections::CollectionId,
+    ep_bucket::EPBucket,
+    error::EngineError,
+    vbucket::{State, Vbid},
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TtlUpdateState {
+    Running,
+    Done,
+    /// The bucket shut down before every vbucket was updated
+    Stopped,
+}
+
+impl TtlUpdateState {
+    pub fn name(&self) -> &'static str {
+        match self {
+            TtlUpdateState::Running => "running",
+            TtlUpdateState::Done => "done",
+            TtlUpdateState::Stopped => "stopped",
+        }
+    }
+}
+
+/// How far an update of a collection's expiry times has got
+#[derive(Debug)]
+pub struct TtlUpdateProgress {
+    pub collection: CollectionId,
+    /// The expiry time as the client gave it, seconds from when each
+    /// document is touched or an absolute time
+    pub ttl: u32,
+    state: Mutex<(TtlUpdateState, Option<Duration>)>,
+    started: Instant,
+    vbuckets: Vec<Vbid>,
+    vbuckets_done: AtomicUsize,
+    items_touched: AtomicU64,
+    /// Documents locked, or which couldn't be fetched back from disk
+    items_skipped: AtomicU64,
+}
+
+impl TtlUpdateProgress {
+    pub(crate) fn new(collection: CollectionId, ttl: u32, vbuckets: Vec<Vbid>) -> Self {
+        Self {
+            collection,
+            ttl,
+            state: Mutex::new((TtlUpdateState::Running, None)),
+            started: Instant::now(),
+            vbuckets,
+            vbuckets_done: AtomicUsize::new(0),
+            items_touched: AtomicU64::new(0),
+            items_skipped: AtomicU64::new(0),
+        }
+    }
+
+    pub fn state(&self) -> TtlUpdateState {
+        self.state.lock().0
+    }
+
+    pub fn is_running(&self) -> bool {
+        self.state() == TtlUpdateState::Running
+    }
+
+    pub fn vbuckets_total(&self) -> usize {
+        self.vbuckets.len()
+    }
+
+    pub fn vbuckets_done(&self) -> usize {
+        self.vbuckets_done.load(Ordering::Relaxed)
+    }
+
+    pub fn items_touched(&self) -> u64 {
+        self.items_touched.load(Ordering::Relaxed)
+    }
+
+    pub fn items_skipped(&self) -> u64 {
+        self.items_skipped.load(Ordering::Relaxed)
+    }
+
+    fn finish(&self, state: TtlUpdateState) {
+        *self.state.lock() = (state, Some(self.started.elapsed()));
+    }
+
+    /// The ttl_update stats group
+    pub fn add_stats(&self, add_stat: &mut dyn FnMut(&str, &str)) {
+        let (state, duration) = *self.state.lock();
+        add_stat("ep_ttl_update_state", state.name());
+        add_stat("ep_ttl_update_collection", &self.collection.to_string());
+        add_stat("ep_ttl_update_ttl", &self.ttl.to_string());
+        add_stat(
+            "ep_ttl_update_vbuckets_total",
+            &self.vbuckets_total().to_string(),
+        );
+        add_stat(
+            "ep_ttl_update_vbuckets_done",
+            &self.vbuckets_done().to_string(),
+        );
+        add_stat(
+            "ep_ttl_update_items_touched",
+            &self.items_touched().to_string(),
+        );
+        add_stat(
+            "ep_ttl_update_items_skipped",
+            &self.items_skipped().to_string(),
+        );
+        let duration = duration.unwrap_or_else(|| self.started.elapsed());
+        add_stat("ep_ttl_update_time_us", &duration.as_micros().to_string());
+    }
+}
+
+/// The background task: touch the collection's documents in each vbucket
+/// in turn, stopping if the bucket shuts down
+pub(crate) fn run(bucket: Weak<EPBucket>, progress: &TtlUpdateProgress) {
+    for &vbid in &progress.vbuckets {
+        match bucket.upgrade() {
+            Some(bucket) if !bucket.is_shutting_down() => update_vbucket(&bucket, vbid, progress),
+            _ => {
+                println!(
+                    "TTL update of collection {} stopped after {} of {} vbuckets",
+                    progress.collection,
+                    progress.vbuckets_done(),
+                    progress.vbuckets_total()
+                );
+                progress.finish(TtlUpdateState::Stopped);
+                return;
+            }
+        }
+        progress.vbuckets_done.fetch_add(1, Ordering::Relaxed);
+    }
+    println!(
+        "TTL update of collection {} touched {} items, skipped {}",
+        progress.collection,
+        progress.items_touched(),
+        progress.items_skipped()
+    );
+    progress.finish(TtlUpdateState::Done);
+}
+
+fn update_vbucket(bucket: &EPBucket, vbid: Vbid, progress: &TtlUpdateProgress) {
+    // A vbucket which moved away since is updated by its new active, if
+    // the update is run there
+    let Some(vb) = bucket.get_vbucket(vbid) else {
+        return;
+    };
+    if vb.state() != State::Active {
+        return;
+    }
+    let expiry_time = bucket.cap_expiry(progress.ttl, vb.now_secs());
+    let touch = |key: &[u8]| -> bool {
+        match vb.touch(key, expiry_time) {
+            Ok(_) => {
+                progress.items_touched.fetch_add(1, Ordering::Relaxed);
+            }
+            Err(EngineError::WouldBlock) => return false,
+            // Deleted since the keys were listed
+            Err(EngineError::KeyNotFound) => {}
+            Err(_) => {
+                progress.items_skipped.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+        true
+    };
+    let to_fetch: Vec<Vec<u8>> = vb
+        .collection_keys(progress.collection)
+        .into_iter()
+        .filter(|key| !touch(key))
+        .collect();
+    if to_fetch.is_empty() {
+        return;
+    }
+
+    let store = bucket.vbucket_map.get_shard_by_vb_id(vbid).store();
+    match store.get_multi(vbid, &to_fetch) {
+        Ok(fetched) => vb.restore_fetched(fetched.into_iter().flatten()),
+        // Each key is counted as skipped below
+        Err(e) => println!(
+            "Failed to fetch {} keys of {vbid} for the TTL update: {e}",
+            to_fetch.len()
+        ),
+    }
+    for key in &to_fetch {
+        // Evicted again already
+        if !touch(key) {
+            progress.items_skipped.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{
+        collections::{self, FIRST_USER_COLLECTION},
+        failover_table::FailoverTable,
+        item::{Datatype, DeleteSource, Item},
+        Config,
+    };
+    use std::collections::HashMap;
+
+    #[test]
+    fn test_update_collection_ttl() {
+        let dir = tempfile::tempdir().unwrap();
+        let bucket = EPBucket::new(Config {
+            max_vbuckets: 2,
+            max_shards: 1,
+            dbname: dir.path().to_str().unwrap().to_string(),
+            max_ttl: 1000,
+            ..Default::default()
+        });
+        for vbid in 0..2u16 {
+            bucket.vbucket_map.add_bucket(bucket.make_vbucket(
+                Vbid::from(vbid),
+                State::Active,
+                FailoverTable::new_empty(25),
+                0,
+                0,
+            ));
+        }
+        bucket.enable_traffic();
+
+        // Writes without an expiry, or a later one, get the max
+        let vb0 = bucket.get_vbucket(Vbid::from(0u16)).unwrap();
+        let now = vb0.now_secs();
+        for (expiry_time, expected) in [(0, now + 1000), (5000, now + 1000), (10, now + 10)] {
+            bucket
+                .set(b"key".to_vec(), b"value".to_vec(), 0, expiry_time)
+                .unwrap();
+            let stored = bucket.get(b"key".to_vec()).unwrap();
+            // The clock may have ticked since now was read
+            assert!((expected..=expected + 1).contains(&stored.expiry_time));
+        }
+
+        assert_eq!(
+            EPBucket::update_collection_ttl(&bucket, FIRST_USER_COLLECTION, 60).err(),
+            Some(EngineError::UnknownCollection)
+        );
+        bucket
+            .create_collection(FIRST_USER_COLLECTION, 0, "sessions", 0)
+            .unwrap();
+        let mut keys = Vec::new();
+        for vbid in 0..2u16 {
+            let vb = bucket.get_vbucket(Vbid::from(vbid)).unwrap();
+            for i in 0..3 {
+                let key =
+                    collections::make_key(FIRST_USER_COLLECTION, format!("s{vbid}{i}").as_bytes());
+                vb.set(Item {
+                    key: key.clone(),
+                    value: Some(b"session".to_vec()),
+                    cas: 0,
+                    expiry_time: 0,
+                    flags: 7,
+                    by_seqno: 0,
+                    rev_seqno: 0,
+                    delete_source: DeleteSource::Explicit,
+                    datatype: Datatype::empty(),
+                })
+                .unwrap();
+                keys.push((vb.clone(), key));
+            }
+            bucket.flush_vbucket(Vbid::from(vbid));
+        }
+        // One has to be fetched back from disk to be touched
+        let (evicted_vb, evicted_key) = &keys[4];
+        evicted_vb
+            .hash_table
+            .lock()
+            .map
+            .get_mut(evicted_key)
+            .unwrap()
+            .mark_not_resident();
+
+        let progress = EPBucket::update_collection_ttl(&bucket, FIRST_USER_COLLECTION, 60).unwrap();
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while progress.is_running() {
+            assert!(Instant::now() < deadline);
+            std::thread::sleep(Duration::from_millis(1));
+        }
+        assert_eq!(progress.state(), TtlUpdateState::Done);
+        assert_eq!(progress.vbuckets_done(), 2);
+        assert_eq!(progress.items_touched(), 6);
+        assert_eq!(progress.items_skipped(), 0);
+        for (vb, key) in &keys {
+            let value = vb.get(key).unwrap();
+            assert!((now + 60..=now + 61).contains(&value.expiry_time));
+            assert_eq!(value.value.as_deref(), Some(&b"session"[..]));
+            assert_eq!(value.flags, 7);
+        }
+        // The default collection's key is left alone
+        assert!(bucket.get(b"key".to_vec()).unwrap().expiry_time <= now + 11);
+
+        let mut stats = HashMap::new();
+        bucket
+            .get_stats_group("ttl_update", &mut |key, value| {
+                stats.insert(key.to_string(), value.to_string());
+            })
+            .unwrap();
+        assert_eq!(stats["ep_ttl_update_state"], "done");
+        assert_eq!(stats["ep_ttl_update_items_touched"], "6");
+        assert_eq!(stats["ep_ttl_update_vbuckets_total"], "2");
+    }
+}
@@ -0,0 +1,159 @@
+//! Typed access to a bucket's documents for embedders using the engine as
+//! a local store: values are Rust types, stored as JSON with the JSON
+//! datatype and the common flags the SDKs give JSON documents, so other
+//! clients read them as JSON too.
+
+use serde::{de::DeserializeOwned, Serialize};
+use std::marker::PhantomData;
+use thiserror::Error;
+
+use crate::{api::Bucket, error::EngineError, item::Datatype};
+
+/// The common flags of a JSON document, in the format bits the SDKs set
+pub const JSON_COMMON_FLAGS: u32 = 0x0200_0000;
+
+#[derive(Error, Debug)]
+pub enum TypedError {
+    #[error(transparent)]
+    Engine(#[from] EngineError),
+    /// The value couldn't be serialized, or the stored document isn't
+    /// JSON of the type
+    #[error("invalid document: {0}")]
+    Json(#[from] serde_json::Error),
+}
+
+pub type TypedResult<T> = Result<T, TypedError>;
+
+/// A document read through a [`TypedBucket`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TypedDocument<T> {
+    pub value: T,
+    pub cas: u64,
+    pub flags: u32,
+    /// Absolute expiry time in seconds, 0 if the document doesn't expire
+    pub expiry_time: u32,
+}
+
+/// A bucket whose documents are values of type T, from [`Bucket::typed`]
+pub struct TypedBucket<T> {
+    bucket: Bucket,
+    _value: PhantomData<fn() -> T>,
+}
+
+impl<T> Clone for TypedBucket<T> {
+    fn clone(&self) -> Self {
+        Self {
+            bucket: self.bucket.clone(),
+            _value: PhantomData,
+        }
+    }
+}
+
+impl<T: Serialize + DeserializeOwned> TypedBucket<T> {
+    pub(crate) fn new(bucket: Bucket) -> Self {
+        Self {
+            bucket,
+            _value: PhantomData,
+        }
+    }
+
+    /// Get a document, deserializing its value
+    pub fn get(&self, key: &[u8]) -> TypedResult<TypedDocument<T>> {
+        let doc = self.bucket.get(key)?;
+        Ok(TypedDocument {
+            value: serde_json::from_slice(&doc.value)?,
+            cas: doc.cas,
+            flags: doc.flags,
+            expiry_time: doc.expiry_time,
+        })
+    }
+
+    /// Store a document whether or not it exists, returning its new CAS.
+    /// The expiry time is as for [`Bucket::set`].
+    pub fn upsert(&self, key: &[u8], value: &T, expiry_time: u32) -> TypedResult<u64> {
+        self.replace_with_cas(key, value, expiry_time, 0)
+    }
+
+    /// As upsert, but only if the document's CAS matches, so a value read
+    /// with get can be changed without losing a concurrent write
+    pub fn replace_with_cas(
+        &self,
+        key: &[u8],
+        value: &T,
+        expiry_time: u32,
+        cas: u64,
+    ) -> TypedResult<u64> {
+        let value = serde_json::to_vec(value)?;
+        Ok(self.bucket.set_with_datatype(
+            key,
+            &value,
+            Datatype::JSON,
+            JSON_COMMON_FLAGS,
+            expiry_time,
+            cas,
+        )?)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::api::Engine;
+    use serde::Deserialize;
+
+    #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+    struct Airline {
+        name: String,
+        country: String,
+        fleet: u32,
+    }
+
+    #[test]
+    fn test_typed_bucket() {
+        let dir = tempfile::tempdir().unwrap();
+        let engine = Engine::new(crate::Config {
+            max_vbuckets: 4,
+            max_shards: 1,
+            dbname: dir.path().to_str().unwrap().to_string(),
+            ..Default::default()
+        });
+        let bucket = engine.bucket("travel").unwrap();
+        let airlines = bucket.typed::<Airline>();
+        let mut airline = Airline {
+            name: "Air Rust".to_string(),
+            country: "Iceland".to_string(),
+            fleet: 12,
+        };
+        let cas = airlines.upsert(b"airline_1", &airline, 0).unwrap();
+        let doc = airlines.get(b"airline_1").unwrap();
+        assert_eq!(doc.value, airline);
+        assert_eq!(doc.cas, cas);
+        assert_eq!(doc.flags, JSON_COMMON_FLAGS);
+
+        // Other clients read it as JSON
+        let raw = bucket.get(b"airline_1").unwrap();
+        let json: serde_json::Value = serde_json::from_slice(&raw.value).unwrap();
+        assert_eq!(json["fleet"], 12);
+
+        airline.fleet += 1;
+        assert!(matches!(
+            airlines.replace_with_cas(b"airline_1", &airline, 0, cas + 1),
+            Err(TypedError::Engine(EngineError::KeyExists))
+        ));
+        airlines
+            .replace_with_cas(b"airline_1", &airline, 0, cas)
+            .unwrap();
+        assert_eq!(airlines.get(b"airline_1").unwrap().value.fleet, 13);
+
+        assert!(matches!(
+            airlines.get(b"airline_2"),
+            Err(TypedError::Engine(EngineError::KeyNotFound))
+        ));
+        bucket.set(b"airline_2", b"not json", 0, 0).unwrap();
+        assert!(matches!(
+            airlines.get(b"airline_2"),
+            Err(TypedError::Json(_))
+        ));
+        engine.shutdown();
+    }
+}
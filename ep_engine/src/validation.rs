@@ -0,0 +1,165 @@
+//! Validating the values written to a collection before they are accepted,
+//! for embedders which want a stricter store than one holding any bytes,
+//! for example one whose documents must be JSON or follow a schema. A
+//! collection has at most one validator, which sees each front-end write's
+//! uncompressed value and may reject it. Replicated mutations aren't
+//! validated again, as the active accepted them.
+
+use parking_lot::Mutex;
+use std::{
+    fmt::Debug,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+};
+
+use crate::item::Datatype;
+
+pub trait WriteValidator: Send + Sync + Debug {
+    /// Check a value about to be written under the client's key, returning
+    /// why it is rejected if it is. The value is never compressed, so the
+    /// datatype doesn't have SNAPPY.
+    fn validate(&self, key: &[u8], value: &[u8], datatype: Datatype) -> Result<(), String>;
+}
+
+pub type WriteValidatorPtr = Arc<dyn WriteValidator>;
+
+/// Accepts only well-formed JSON values
+#[derive(Debug, Default)]
+pub struct JsonValidator;
+
+impl WriteValidator for JsonValidator {
+    fn validate(&self, _key: &[u8], value: &[u8], _datatype: Datatype) -> Result<(), String> {
+        serde_json::from_slice::<serde::de::IgnoredAny>(value)
+            .map(|_| ())
+            .map_err(|e| format!("invalid JSON: {e}"))
+    }
+}
+
+/// A collection's validator, with its rejections for the validation stats
+/// group
+#[derive(Debug)]
+pub(crate) struct CollectionValidator {
+    validator: WriteValidatorPtr,
+    rejected: AtomicU64,
+    last_rejection: Mutex<Option<String>>,
+}
+
+impl CollectionValidator {
+    pub(crate) fn new(validator: WriteValidatorPtr) -> Self {
+        Self {
+            validator,
+            rejected: AtomicU64::new(0),
+            last_rejection: Mutex::default(),
+        }
+    }
+
+    /// Validate a write, returning whether it is accepted
+    pub(crate) fn validate(&self, key: &[u8], value: &[u8], datatype: Datatype) -> bool {
+        match self.validator.validate(key, value, datatype) {
+            Ok(()) => true,
+            Err(reason) => {
+                self.rejected.fetch_add(1, Ordering::Relaxed);
+                *self.last_rejection.lock() = Some(reason);
+                false
+            }
+        }
+    }
+
+    pub(crate) fn add_stats(&self, prefix: &str, add_stat: &mut dyn FnMut(&str, &str)) {
+        add_stat(
+            &format!("{prefix}:rejected"),
+            &self.rejected.load(Ordering::Relaxed).to_string(),
+        );
+        if let Some(reason) = &*self.last_rejection.lock() {
+            add_stat(&format!("{prefix}:last_rejection"), reason);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{
+        collections::DEFAULT_COLLECTION,
+        compression,
+        ep_bucket::EPBucket,
+        error::EngineError,
+        failover_table::FailoverTable,
+        vbucket::{State, Vbid},
+        Config,
+    };
+    use std::collections::HashMap;
+
+    /// Values must be JSON objects with a "type" field
+    #[derive(Debug)]
+    struct Typed;
+
+    impl WriteValidator for Typed {
+        fn validate(&self, _key: &[u8], value: &[u8], _datatype: Datatype) -> Result<(), String> {
+            let value: serde_json::Value =
+                serde_json::from_slice(value).map_err(|e| e.to_string())?;
+            match value.get("type") {
+                Some(serde_json::Value::String(_)) => Ok(()),
+                _ => Err("no type".to_string()),
+            }
+        }
+    }
+
+    #[test]
+    fn test_write_validator() {
+        let dir = tempfile::tempdir().unwrap();
+        let bucket = EPBucket::new(Config {
+            max_vbuckets: 1,
+            max_shards: 1,
+            dbname: dir.path().to_str().unwrap().to_string(),
+            ..Default::default()
+        });
+        bucket.vbucket_map.add_bucket(bucket.make_vbucket(
+            Vbid::from(0u16),
+            State::Active,
+            FailoverTable::new_empty(25),
+            0,
+            0,
+        ));
+        bucket.enable_traffic();
+        let set = |value: &[u8]| bucket.set(b"key".to_vec(), value.to_vec(), 0, 0);
+
+        bucket.set_write_validator(DEFAULT_COLLECTION, Some(Arc::new(JsonValidator)));
+        set(br#"{"type": "order"}"#).unwrap();
+        assert_eq!(set(b"not json"), Err(EngineError::InvalidArguments));
+        // Compressed values are validated as the client will read them
+        let compressed = compression::compress(br#"[1, 2, 3]"#);
+        bucket
+            .set_with_datatype(b"key".to_vec(), compressed, Datatype::SNAPPY, 0, 0, 0)
+            .unwrap();
+        let bad = compression::compress(b"{");
+        assert_eq!(
+            bucket.set_with_datatype(b"key".to_vec(), bad, Datatype::SNAPPY, 0, 0, 0),
+            Err(EngineError::InvalidArguments)
+        );
+
+        bucket.set_write_validator(DEFAULT_COLLECTION, Some(Arc::new(Typed)));
+        set(br#"{"type": "order"}"#).unwrap();
+        assert_eq!(set(br#"{"id": 1}"#), Err(EngineError::InvalidArguments));
+        // The rejected write left the key as it was
+        assert_eq!(
+            bucket.get(b"key".to_vec()).unwrap().value.as_deref(),
+            Some(&br#"{"type": "order"}"#[..])
+        );
+
+        let mut stats = HashMap::new();
+        bucket
+            .get_stats_group("validation", &mut |key, value| {
+                stats.insert(key.to_string(), value.to_string());
+            })
+            .unwrap();
+        assert_eq!(stats["collection_0x0:rejected"], "1");
+        assert_eq!(stats["collection_0x0:last_rejection"], "no type");
+        assert_eq!(bucket.stats.writes_rejected.load(Ordering::Relaxed), 3);
+
+        bucket.set_write_validator(DEFAULT_COLLECTION, None);
+        set(b"anything").unwrap();
+    }
+}
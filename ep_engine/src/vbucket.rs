@@ -1,14 +1,29 @@
 use crate::{
-    failover_table::FailoverTable, hash_table::HashTable, item::Item, stored_value::StoredValue,
+    checkpoint_manager::{CheckpointConfig, CheckpointManager, QueuedItem},
+    clock::ClockPtr,
+    collections::{self, CollectionEntry, CollectionId, VBucketManifest, FIRST_USER_COLLECTION},
+    ep_bucket::{MutationInfo, MutationToken},
+    error::{EngineError, EngineResult},
+    failover_table::FailoverTable,
+    hash_table::HashTable,
+    hlc::HLC,
+    item::{is_expired, to_absolute_expiry, Datatype, DeleteSource, Item},
+    seqno_allocator::SeqnoAllocator,
+    stats::EPStatsPtr,
+    stored_value::StoredValue,
 };
 use crossbeam_utils::atomic::AtomicCell;
-use parking_lot::{Mutex, MutexGuard};
+use parking_lot::{Condvar, Mutex, MutexGuard};
 use serde::{Deserialize, Serializer};
 use std::{
+    collections::HashSet,
     fmt::{self, Display},
-    ops::Rem,
     str::FromStr,
-    sync::Arc,
+    sync::{
+        atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering},
+        Arc,
+    },
+    time::Instant,
 };
 
 #[derive(Debug)]
@@ -16,22 +31,203 @@ pub struct VBucket {
     pub id: Vbid,
     pub hash_table: Mutex<HashTable>,
     state: AtomicCell<State>,
-    _failover_table: FailoverTable,
+    pub failover_table: FailoverTable,
     // Can state just be inside the mutex??
     state_lock: Mutex<()>,
+    seqnos: SeqnoAllocator,
+    /// Signalled as replicated items arrive and when the state changes, for
+    /// readers waiting for a seqno
+    seqno_lock: Mutex<()>,
+    seqno_changed: Condvar,
+    /// Deletes up to this seqno have been purged from disk
+    purge_seqno: AtomicU64,
+    /// Mutations up to this seqno have been persisted
+    persisted_seqno: AtomicU64,
+    /// The latest flush failed to commit, so mutations are only in memory
+    persistence_failing: AtomicBool,
+    /// Flushes which have failed in a row
+    flush_failures: AtomicU32,
+    /// The state the vbucket had before it was quarantined, marked dead
+    /// for failing to persist too many times
+    quarantined_from: AtomicCell<Option<State>>,
+    /// A scan found the vbucket's file corrupt, so it should be rebuilt
+    /// from another copy
+    needs_rereplication: AtomicBool,
+    /// Tombstones old enough to purge which the last purge kept for a
+    /// consumer that hasn't read them
+    retained_tombstones: AtomicU64,
+    /// Tombstones in the vbucket's file and the bytes they take, as last
+    /// counted by the store or seen by a purge
+    on_disk_deletes: AtomicU64,
+    tombstone_bytes: AtomicU64,
+    hlc: HLC,
+    pub checkpoint_manager: CheckpointManager,
+    manifest: Mutex<VBucketManifest>,
 }
 
 impl VBucket {
-    pub fn new(id: Vbid, state: State, failover_table: FailoverTable) -> Self {
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        id: Vbid,
+        state: State,
+        failover_table: FailoverTable,
+        stats: EPStatsPtr,
+        last_seqno: u64,
+        max_cas: u64,
+        checkpoint_config: CheckpointConfig,
+        clock: ClockPtr,
+    ) -> Self {
         Self {
             id,
-            hash_table: Mutex::new(Default::default()),
+            hash_table: Mutex::new(HashTable::new(stats.clone())),
+            checkpoint_manager: CheckpointManager::new(stats, last_seqno, checkpoint_config),
             state: AtomicCell::new(state),
-            _failover_table: failover_table,
+            failover_table,
             state_lock: Mutex::new(()),
+            seqnos: SeqnoAllocator::new(last_seqno),
+            seqno_lock: Mutex::new(()),
+            seqno_changed: Condvar::new(),
+            purge_seqno: AtomicU64::new(0),
+            persisted_seqno: AtomicU64::new(last_seqno),
+            persistence_failing: AtomicBool::new(false),
+            flush_failures: AtomicU32::new(0),
+            quarantined_from: AtomicCell::new(None),
+            needs_rereplication: AtomicBool::new(false),
+            retained_tombstones: AtomicU64::new(0),
+            on_disk_deletes: AtomicU64::new(0),
+            tombstone_bytes: AtomicU64::new(0),
+            hlc: HLC::new(max_cas, clock),
+            manifest: Mutex::default(),
         }
     }
 
+    pub fn get_high_seqno(&self) -> u64 {
+        self.seqnos.high_seqno()
+    }
+
+    /// Wait until the vbucket has reached seqno, as a replica does while it
+    /// receives items. Returns whether it has by the deadline.
+    pub fn wait_for_seqno(&self, seqno: u64, deadline: Instant) -> bool {
+        let mut guard = self.seqno_lock.lock();
+        while self.get_high_seqno() < seqno {
+            if self
+                .seqno_changed
+                .wait_until(&mut guard, deadline)
+                .timed_out()
+            {
+                return self.get_high_seqno() >= seqno;
+            }
+        }
+        true
+    }
+
+    fn notify_seqno_waiters(&self) {
+        let _guard = self.seqno_lock.lock();
+        self.seqno_changed.notify_all();
+    }
+
+    /// The token of a mutation of this vbucket at seqno
+    fn mutation_token(&self, seqno: u64) -> MutationToken {
+        MutationToken {
+            vbid: self.id,
+            vb_uuid: self.failover_table.get_latest_uuid(),
+            seqno,
+        }
+    }
+
+    pub fn get_purge_seqno(&self) -> u64 {
+        self.purge_seqno.load(Ordering::SeqCst)
+    }
+
+    pub fn set_purge_seqno(&self, seqno: u64) {
+        self.purge_seqno.store(seqno, Ordering::SeqCst);
+    }
+
+    pub fn get_persisted_seqno(&self) -> u64 {
+        self.persisted_seqno.load(Ordering::SeqCst)
+    }
+
+    pub fn set_persisted_seqno(&self, seqno: u64) {
+        self.persisted_seqno.fetch_max(seqno, Ordering::SeqCst);
+    }
+
+    pub fn is_persistence_failing(&self) -> bool {
+        self.persistence_failing.load(Ordering::SeqCst)
+    }
+
+    /// Returns whether it was failing before
+    pub fn set_persistence_failing(&self, failing: bool) -> bool {
+        self.persistence_failing.swap(failing, Ordering::SeqCst)
+    }
+
+    /// Count a failed flush, returning how many have failed in a row
+    pub fn record_flush_failure(&self) -> u32 {
+        self.flush_failures.fetch_add(1, Ordering::SeqCst) + 1
+    }
+
+    pub fn reset_flush_failures(&self) {
+        self.flush_failures.store(0, Ordering::SeqCst);
+    }
+
+    pub fn is_quarantined(&self) -> bool {
+        self.quarantined_from.load().is_some()
+    }
+
+    /// The state it had before it was quarantined, None if it isn't
+    pub fn quarantined_from(&self) -> Option<State> {
+        self.quarantined_from.load()
+    }
+
+    /// Quarantine it from the state, returning false if it already was
+    pub fn quarantine(&self, state: State) -> bool {
+        self.quarantined_from
+            .compare_exchange(None, Some(state))
+            .is_ok()
+    }
+
+    pub fn release_quarantine(&self) {
+        self.quarantined_from.store(None);
+    }
+
+    pub fn needs_rereplication(&self) -> bool {
+        self.needs_rereplication.load(Ordering::SeqCst)
+    }
+
+    /// Returns whether it was already marked
+    pub fn set_needs_rereplication(&self, needed: bool) -> bool {
+        self.needs_rereplication.swap(needed, Ordering::SeqCst)
+    }
+
+    pub fn get_retained_tombstones(&self) -> u64 {
+        self.retained_tombstones.load(Ordering::SeqCst)
+    }
+
+    pub fn set_retained_tombstones(&self, count: u64) {
+        self.retained_tombstones.store(count, Ordering::SeqCst);
+    }
+
+    pub fn get_on_disk_deletes(&self) -> u64 {
+        self.on_disk_deletes.load(Ordering::SeqCst)
+    }
+
+    pub fn get_tombstone_bytes(&self) -> u64 {
+        self.tombstone_bytes.load(Ordering::SeqCst)
+    }
+
+    pub fn set_on_disk_deletes(&self, count: u64, bytes: u64) {
+        self.on_disk_deletes.store(count, Ordering::SeqCst);
+        self.tombstone_bytes.store(bytes, Ordering::SeqCst);
+    }
+
+    /// The current time in seconds since the epoch, as deletions record it
+    pub fn now_secs(&self) -> u32 {
+        self.hlc.now_secs()
+    }
+
+    pub fn get_max_cas(&self) -> u64 {
+        self.hlc.max_hlc()
+    }
+
     pub fn state(&self) -> State {
         self.state.load()
     }
@@ -43,30 +239,584 @@ impl VBucket {
     pub fn set_state(&self, state: State) {
         let _guard = self.get_state_lock();
         self.set_state_unlocked(state);
+        self.notify_seqno_waiters();
     }
 
     fn set_state_unlocked(&self, state: State) {
         self.state.store(state);
     }
 
+    /// Load a persisted item into the hash table. System events are
+    /// skipped, they are replayed with replay_system_event.
     pub fn insert_from_warmup(&self, item: Item) {
+        if collections::collection_event_id(&item.key).is_some() {
+            return;
+        }
         self.hash_table.lock().insert_from_warmup(item);
     }
 
+    /// Load a persisted item into the hash table with its value, see
+    /// HashTable::load_from_warmup. System events are skipped.
+    pub fn load_from_warmup(&self, item: Item) {
+        if collections::collection_event_id(&item.key).is_some() {
+            return;
+        }
+        self.hash_table.lock().load_from_warmup(item);
+    }
+
+    /// Update the collections from a persisted system event
+    pub fn replay_system_event(&self, item: &Item) {
+        self.manifest.lock().apply_event(item);
+    }
+
+    pub fn get_collection(&self, id: CollectionId) -> Option<CollectionEntry> {
+        self.manifest.lock().get(id).cloned()
+    }
+
+    pub fn collection_ids(&self) -> Vec<CollectionId> {
+        self.manifest.lock().ids()
+    }
+
+    /// The expiry time to store a mutation of the key with: the one given,
+    /// or if there is none, the maxTTL of the key's collection
+    pub fn default_expiry(&self, key: &[u8], expiry_time: u32) -> u32 {
+        match expiry_time {
+            0 => self.manifest.lock().max_ttl(key),
+            _ => expiry_time,
+        }
+    }
+
     pub fn get(&self, key: &[u8]) -> Option<StoredValue> {
         self.hash_table.lock().map.get(key).cloned()
     }
+
+    /// Look up several keys under one hash table lock
+    pub fn get_multi(&self, keys: &[Vec<u8>]) -> Vec<Option<StoredValue>> {
+        let hash_table = self.hash_table.lock();
+        keys.iter()
+            .map(|key| hash_table.map.get(key).cloned())
+            .collect()
+    }
+
+    /// Make the values fetched from disk resident again
+    pub fn restore_fetched(&self, items: impl IntoIterator<Item = Item>) {
+        let mut hash_table = self.hash_table.lock();
+        for item in items {
+            hash_table.restore_fetched(item);
+        }
+    }
+
+    /// Store the item, assigning it the next seqno and a new CAS. An item
+    /// without an expiry time gets its collection's maxTTL, and a relative
+    /// expiry time is made absolute using the time of the new CAS.
+    /// Returns the CAS of the stored item and the token of its seqno.
+    pub fn set(&self, mut item: Item) -> EngineResult<MutationInfo> {
+        let _state_lock = self.get_state_lock();
+        if self.state() != State::Active {
+            return Err(EngineError::NotMyVbucket);
+        }
+
+        let mut hash_table = self.hash_table.lock();
+        check_cas(hash_table.map.get(&item.key), item.cas, self.hlc.now_secs())?;
+        item.rev_seqno = hash_table
+            .map
+            .get(&item.key)
+            .map_or(1, |existing| existing.rev_seqno + 1);
+        item.by_seqno = self.seqnos.next();
+        item.cas = self.hlc.next_hlc();
+        let expiry_time = self.default_expiry(&item.key, item.expiry_time);
+        item.expiry_time = to_absolute_expiry(expiry_time, self.hlc.now_secs());
+        let info = MutationInfo {
+            cas: item.cas,
+            token: self.mutation_token(item.by_seqno),
+        };
+        hash_table.set(item.clone());
+        self.checkpoint_manager.queue_dirty(QueuedItem::new(item));
+        Ok(info)
+    }
+
+    /// Store or delete several keys as a unit, an item without a value
+    /// deleting its key. Each item's cas, if non-zero, must match its key's,
+    /// and if any item fails its checks none are applied. Under the one
+    /// hash table lock they take consecutive seqnos and are queued as one
+    /// batch, so readers, the flusher and streams see all of them or none.
+    /// Returns each item's new CAS.
+    pub fn multi_mutate(&self, items: Vec<Item>) -> EngineResult<Vec<u64>> {
+        let _state_lock = self.get_state_lock();
+        if self.state() != State::Active {
+            return Err(EngineError::NotMyVbucket);
+        }
+
+        let mut hash_table = self.hash_table.lock();
+        let now = self.hlc.now_secs();
+        let mut keys = HashSet::new();
+        for item in &items {
+            // Which of two writes to the same key wins would be unclear
+            if !keys.insert(&item.key) {
+                return Err(EngineError::InvalidArguments);
+            }
+            let existing = hash_table.map.get(&item.key);
+            check_cas(existing, item.cas, now)?;
+            if item.value.is_none() && existing.is_none_or(|existing| existing.is_deleted()) {
+                return Err(EngineError::KeyNotFound);
+            }
+        }
+
+        let mut queued = Vec::with_capacity(items.len());
+        for mut item in items {
+            let existing = hash_table.map.get(&item.key);
+            item.rev_seqno = existing.map_or(1, |existing| existing.rev_seqno + 1);
+            match &item.value {
+                Some(_) => {
+                    let expiry_time = self.default_expiry(&item.key, item.expiry_time);
+                    item.expiry_time = to_absolute_expiry(expiry_time, now);
+                }
+                None => {
+                    // The delete time
+                    item.expiry_time = now;
+                    item.flags = existing.map_or(0, |existing| existing.flags);
+                }
+            }
+            item.by_seqno = self.seqnos.next();
+            item.cas = self.hlc.next_hlc();
+            hash_table.set(item.clone());
+            queued.push(QueuedItem::new(item));
+        }
+        let cas = queued.iter().map(|item| item.cas).collect();
+        self.checkpoint_manager.queue_dirty_batch(queued);
+        Ok(cas)
+    }
+
+    /// Delete the key, queueing a deletion with the next seqno. Returns the
+    /// CAS of the deletion and the token of its seqno. A non-zero cas must
+    /// match the key's.
+    pub fn delete(&self, key: &[u8], cas: u64) -> EngineResult<MutationInfo> {
+        let _state_lock = self.get_state_lock();
+        if self.state() != State::Active {
+            return Err(EngineError::NotMyVbucket);
+        }
+
+        let mut hash_table = self.hash_table.lock();
+        let existing = match hash_table.map.get(key) {
+            Some(existing) if !existing.is_deleted() => existing,
+            _ => return Err(EngineError::KeyNotFound),
+        };
+        check_cas(Some(existing), cas, self.hlc.now_secs())?;
+        let item = Item {
+            key: key.to_vec(),
+            value: None,
+            cas: self.hlc.next_hlc(),
+            // The delete time
+            expiry_time: self.hlc.now_secs(),
+            flags: existing.flags,
+            by_seqno: self.seqnos.next(),
+            rev_seqno: existing.rev_seqno + 1,
+            delete_source: DeleteSource::Explicit,
+            datatype: Datatype::empty(),
+        };
+        let info = MutationInfo {
+            cas: item.cas,
+            token: self.mutation_token(item.by_seqno),
+        };
+        hash_table.set(item.clone());
+        self.checkpoint_manager.queue_dirty(QueuedItem::new(item));
+        Ok(info)
+    }
+
+    /// Give the key a new expiry time, keeping its value. It is queued as a
+    /// mutation with the next seqno and a new CAS, so the new expiry is
+    /// persisted and replicated. Returns the CAS of the mutation.
+    pub fn touch(&self, key: &[u8], expiry_time: u32) -> EngineResult<u64> {
+        let _state_lock = self.get_state_lock();
+        if self.state() != State::Active {
+            return Err(EngineError::NotMyVbucket);
+        }
+
+        let mut hash_table = self.hash_table.lock();
+        let now = self.hlc.now_secs();
+        let existing = match hash_table.map.get(key) {
+            Some(existing) if !existing.is_deleted() => existing,
+            _ => return Err(EngineError::KeyNotFound),
+        };
+        if existing.is_locked(now) {
+            return Err(EngineError::Locked);
+        }
+        if !existing.is_resident() {
+            return Err(EngineError::WouldBlock);
+        }
+        let item = Item {
+            key: key.to_vec(),
+            value: existing.value.clone(),
+            cas: self.hlc.next_hlc(),
+            expiry_time: to_absolute_expiry(expiry_time, now),
+            flags: existing.flags,
+            by_seqno: self.seqnos.next(),
+            rev_seqno: existing.rev_seqno + 1,
+            delete_source: DeleteSource::Explicit,
+            datatype: existing.datatype,
+        };
+        let cas = item.cas;
+        hash_table.set(item.clone());
+        self.checkpoint_manager.queue_dirty(QueuedItem::new(item));
+        Ok(cas)
+    }
+
+    /// Move the key's document to new_key, keeping its value, flags, expiry
+    /// time and datatype. The new key's mutation and the old key's deletion
+    /// take consecutive seqnos and are queued together, so they are
+    /// persisted in the same flush batch and streamed in the same snapshot.
+    /// A non-zero cas must match the old key's. Returns the new key's CAS.
+    pub fn rename(&self, old_key: &[u8], new_key: &[u8], cas: u64) -> EngineResult<u64> {
+        let _state_lock = self.get_state_lock();
+        if self.state() != State::Active {
+            return Err(EngineError::NotMyVbucket);
+        }
+
+        let mut hash_table = self.hash_table.lock();
+        let now = self.hlc.now_secs();
+        let existing = match hash_table.map.get(old_key) {
+            Some(existing) if !existing.is_deleted() => existing.clone(),
+            _ => return Err(EngineError::KeyNotFound),
+        };
+        check_cas(Some(&existing), cas, now)?;
+        if !existing.is_resident() {
+            return Err(EngineError::WouldBlock);
+        }
+        let rev_seqno = match hash_table.map.get(new_key) {
+            Some(target) if !target.is_deleted() => return Err(EngineError::KeyExists),
+            Some(tombstone) => tombstone.rev_seqno + 1,
+            None => 1,
+        };
+        let moved = Item {
+            key: new_key.to_vec(),
+            value: existing.value.clone(),
+            cas: self.hlc.next_hlc(),
+            expiry_time: existing.expiry_time,
+            flags: existing.flags,
+            by_seqno: self.seqnos.next(),
+            rev_seqno,
+            delete_source: DeleteSource::Explicit,
+            datatype: existing.datatype,
+        };
+        let deletion = Item {
+            key: old_key.to_vec(),
+            value: None,
+            cas: self.hlc.next_hlc(),
+            // The delete time
+            expiry_time: now,
+            flags: existing.flags,
+            by_seqno: self.seqnos.next(),
+            rev_seqno: existing.rev_seqno + 1,
+            delete_source: DeleteSource::Explicit,
+            datatype: Datatype::empty(),
+        };
+        let cas = moved.cas;
+        hash_table.set(moved.clone());
+        hash_table.set(deletion.clone());
+        self.checkpoint_manager
+            .queue_dirty_batch(vec![QueuedItem::new(moved), QueuedItem::new(deletion)]);
+        Ok(cas)
+    }
+
+    /// The keys of the collection's live documents in the hash table,
+    /// resident or not
+    pub fn collection_keys(&self, id: CollectionId) -> Vec<Vec<u8>> {
+        self.hash_table
+            .lock()
+            .map
+            .iter()
+            .filter(|(key, value)| {
+                !value.is_deleted() && collections::collection_id(key) == Some(id)
+            })
+            .map(|(key, _)| key.clone())
+            .collect()
+    }
+
+    /// Lock the key for lock_timeout seconds, giving it a new CAS which
+    /// mutations and unlock must present until the lock expires
+    pub fn get_locked(&self, key: &[u8], lock_timeout: u32) -> EngineResult<StoredValue> {
+        let _state_lock = self.get_state_lock();
+        if self.state() != State::Active {
+            return Err(EngineError::NotMyVbucket);
+        }
+
+        let mut hash_table = self.hash_table.lock();
+        let now = self.hlc.now_secs();
+        let value = hash_table
+            .map
+            .get_mut(key)
+            .filter(|value| !value.is_deleted())
+            .ok_or(EngineError::KeyNotFound)?;
+        if value.is_locked(now) {
+            return Err(EngineError::Locked);
+        }
+        if !value.is_resident() {
+            return Err(EngineError::WouldBlock);
+        }
+        value.cas = self.hlc.next_hlc();
+        value.lock_expiry = now + lock_timeout;
+        Ok(value.clone())
+    }
+
+    /// Whether the key's latest mutation has been persisted, and its CAS.
+    /// Replicas answer too, so clients can wait for a mutation to reach
+    /// their disks.
+    pub fn observe(&self, key: &[u8]) -> EngineResult<(KeyState, u64)> {
+        if self.state() == State::Dead {
+            return Err(EngineError::NotMyVbucket);
+        }
+        let hash_table = self.hash_table.lock();
+        let Some(value) = hash_table.map.get(key) else {
+            return Ok((KeyState::NotFound, 0));
+        };
+        let key_state = match (value.is_deleted(), value.is_dirty()) {
+            (false, false) => KeyState::Persisted,
+            (false, true) => KeyState::NotPersisted,
+            (true, true) => KeyState::LogicalDeleted,
+            (true, false) => KeyState::NotFound,
+        };
+        Ok((key_state, value.cas))
+    }
+
+    /// Release a lock taken by get_locked, which returned the given CAS
+    pub fn unlock(&self, key: &[u8], cas: u64) -> EngineResult<()> {
+        let _state_lock = self.get_state_lock();
+        if self.state() != State::Active {
+            return Err(EngineError::NotMyVbucket);
+        }
+
+        let mut hash_table = self.hash_table.lock();
+        let now = self.hlc.now_secs();
+        let value = hash_table
+            .map
+            .get_mut(key)
+            .filter(|value| !value.is_deleted())
+            .ok_or(EngineError::KeyNotFound)?;
+        if !value.is_locked(now) {
+            return Err(EngineError::NotLocked);
+        }
+        if value.cas != cas {
+            return Err(EngineError::Locked);
+        }
+        value.lock_expiry = 0;
+        Ok(())
+    }
+
+    /// Whether a GET_LOCKED lock is held on the value now
+    pub fn is_locked(&self, value: &StoredValue) -> bool {
+        value.is_locked(self.hlc.now_secs())
+    }
+
+    /// Start receiving a snapshot from a replication stream, reserving its
+    /// seqnos for set_with_meta
+    pub fn create_snapshot(&self, snap_start: u64, snap_end: u64) -> EngineResult<()> {
+        let _state_lock = self.get_state_lock();
+        self.seqnos
+            .reserve_snapshot(snap_start, snap_end)
+            .map_err(|_| EngineError::OutOfRange)?;
+        self.checkpoint_manager
+            .create_snapshot(snap_start, snap_end);
+        Ok(())
+    }
+
+    /// Store an item received from a replication stream, keeping the seqno
+    /// and CAS assigned by the active vbucket. The item must belong to the
+    /// snapshot most recently created with create_snapshot.
+    pub fn set_with_meta(&self, mut item: Item) -> EngineResult<()> {
+        let _state_lock = self.get_state_lock();
+        if !matches!(self.state(), State::Replica | State::Pending) {
+            return Err(EngineError::NotMyVbucket);
+        }
+
+        let mut hash_table = self.hash_table.lock();
+        if let Err(e) = self.seqnos.accept(item.by_seqno) {
+            println!("{}: rejecting a replicated item: {e}", self.id);
+            return Err(EngineError::OutOfRange);
+        }
+        self.hlc.set_max_hlc(item.cas);
+        if collections::collection_event_id(&item.key).is_some() {
+            self.manifest.lock().apply_event(&item);
+        } else {
+            // As the manifest is at this point in the stream. A deletion's
+            // expiry time is its delete time.
+            if item.value.is_some() {
+                let expiry_time = self.default_expiry(&item.key, item.expiry_time);
+                item.expiry_time = to_absolute_expiry(expiry_time, self.hlc.now_secs());
+            }
+            hash_table.set(item.clone());
+        }
+        self.checkpoint_manager.queue_dirty(QueuedItem::new(item));
+        self.notify_seqno_waiters();
+        Ok(())
+    }
+
+    /// Create the collection, queueing a system event which gives the
+    /// creation a seqno so it is persisted and replicated like a mutation.
+    /// Returns the event's seqno.
+    pub fn create_collection(&self, id: CollectionId, entry: CollectionEntry) -> EngineResult<u64> {
+        if id < FIRST_USER_COLLECTION {
+            return Err(EngineError::InvalidArguments);
+        }
+        let value = serde_json::to_vec(&entry).unwrap();
+        self.queue_system_event(id, Some(value), 0)
+    }
+
+    /// Change an existing collection's settings, queueing a system event
+    /// with the new entry. Returns the event's seqno.
+    pub fn modify_collection(&self, id: CollectionId, entry: CollectionEntry) -> EngineResult<u64> {
+        let value = serde_json::to_vec(&entry).unwrap();
+        self.queue_system_event(id, Some(value), collections::MODIFIED_EVENT_FLAGS)
+    }
+
+    /// Drop the collection, queueing a deleted system event. Returns the
+    /// event's seqno.
+    pub fn drop_collection(&self, id: CollectionId) -> EngineResult<u64> {
+        self.queue_system_event(id, None, 0)
+    }
+
+    fn queue_system_event(
+        &self,
+        id: CollectionId,
+        value: Option<Vec<u8>>,
+        flags: u32,
+    ) -> EngineResult<u64> {
+        let _state_lock = self.get_state_lock();
+        if self.state() != State::Active {
+            return Err(EngineError::NotMyVbucket);
+        }
+
+        // Taken so the seqno is queued in order with concurrent mutations
+        let _hash_table = self.hash_table.lock();
+        let mut manifest = self.manifest.lock();
+        let modify = flags == collections::MODIFIED_EVENT_FLAGS;
+        match (manifest.exists(id), value.is_some()) {
+            (true, true) if !modify => return Err(EngineError::KeyExists),
+            (false, true) if modify => return Err(EngineError::UnknownCollection),
+            (false, false) => return Err(EngineError::UnknownCollection),
+            _ => {}
+        }
+        let item = Item {
+            key: collections::collection_event_key(id),
+            value,
+            cas: self.hlc.next_hlc(),
+            expiry_time: 0,
+            flags,
+            by_seqno: self.seqnos.next(),
+            rev_seqno: 1,
+            delete_source: DeleteSource::Explicit,
+            datatype: Datatype::empty(),
+        };
+        let seqno = item.by_seqno;
+        manifest.apply_event(&item);
+        self.checkpoint_manager.queue_dirty(QueuedItem::new(item));
+        Ok(seqno)
+    }
+
+    /// Delete the key if its expiry time has passed, returning whether it
+    /// had expired. Only the active vbucket deletes, replicas wait for the
+    /// deletion to be replicated.
+    pub fn expire_if_needed(&self, key: &[u8]) -> bool {
+        let _state_lock = self.get_state_lock();
+        let mut hash_table = self.hash_table.lock();
+        let now = self.hlc.now_secs();
+        let Some(existing) = hash_table.map.get(key) else {
+            return false;
+        };
+        if existing.is_deleted() || !is_expired(existing.expiry_time, now) {
+            return false;
+        }
+        if self.state() != State::Active {
+            return true;
+        }
+
+        let item = Item {
+            key: key.to_vec(),
+            value: None,
+            cas: self.hlc.next_hlc(),
+            // The delete time
+            expiry_time: now,
+            flags: existing.flags,
+            by_seqno: self.seqnos.next(),
+            rev_seqno: existing.rev_seqno + 1,
+            delete_source: DeleteSource::Ttl,
+            datatype: Datatype::empty(),
+        };
+        hash_table.set(item.clone());
+        self.checkpoint_manager.queue_dirty(QueuedItem::new(item));
+        true
+    }
+
+    /// Mark the values of the persisted items clean, unless they have been
+    /// modified again since.
+    pub fn mark_persisted(&self, items: &[QueuedItem]) {
+        let mut hash_table = self.hash_table.lock();
+        for item in items {
+            if let Some(value) = hash_table.map.get_mut(&item.key) {
+                if value.by_seqno == item.by_seqno {
+                    value.mark_clean();
+                }
+            }
+        }
+    }
+}
+
+/// Check a mutation's CAS against the key's current value. A locked key can
+/// only be modified with the CAS its lock returned, otherwise a CAS of 0
+/// matches any value.
+fn check_cas(existing: Option<&StoredValue>, cas: u64, now: u32) -> EngineResult<()> {
+    match existing.filter(|existing| !existing.is_deleted()) {
+        Some(existing) if existing.is_locked(now) && existing.cas != cas => {
+            Err(EngineError::Locked)
+        }
+        Some(existing) if cas != 0 && existing.cas != cas => Err(EngineError::KeyExists),
+        None if cas != 0 => Err(EngineError::KeyNotFound),
+        _ => Ok(()),
+    }
 }
 
 pub type VBucketPtr = Arc<VBucket>;
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+#[derive(
+    Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, serde::Serialize, serde::Deserialize,
+)]
+#[serde(transparent)]
 pub struct Vbid(u16);
 
 impl Vbid {
     pub fn new(id: u16) -> Self {
         Self(id)
     }
+
+    /// The vbid, if it is within a bucket of max_vbuckets
+    pub fn new_checked(id: u16, max_vbuckets: u16) -> EngineResult<Self> {
+        if id < max_vbuckets {
+            Ok(Self(id))
+        } else {
+            Err(EngineError::NotMyVbucket)
+        }
+    }
+
+    /// The shard which owns the vbucket. Vbuckets are assigned to shards
+    /// round robin.
+    pub fn shard(self, max_shards: u16) -> u16 {
+        self.0 % max_shards
+    }
+
+    /// The position of the vbucket amongst those owned by its shard
+    pub fn index_in_shard(self, max_shards: u16) -> usize {
+        (self.0 / max_shards) as usize
+    }
+
+    /// The vbuckets owned by the shard, in the order of their index_in_shard
+    pub fn iter_for_shard(
+        shard_id: u16,
+        max_shards: u16,
+        max_vbuckets: u16,
+    ) -> impl Iterator<Item = Vbid> {
+        (shard_id..max_vbuckets)
+            .step_by(max_shards as usize)
+            .map(Vbid)
+    }
 }
 
 impl From<Vbid> for usize {
@@ -99,11 +849,11 @@ impl Display for Vbid {
     }
 }
 
-impl Rem<u16> for Vbid {
-    type Output = u16;
+impl FromStr for Vbid {
+    type Err = std::num::ParseIntError;
 
-    fn rem(self, rhs: u16) -> Self::Output {
-        self.0 % rhs
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        s.parse().map(Self)
     }
 }
 
@@ -193,6 +943,23 @@ pub struct VBucketState {
 
     pub checkpoint_type: CheckpointType,
 
+    /// Checkpoint holding the last persisted mutation. Warmup numbers the
+    /// vbucket's checkpoints on from it, treating it as closed.
+    #[serde(
+        default,
+        serialize_with = "serialize_num_as_str",
+        deserialize_with = "deserialize_num_as_str"
+    )]
+    pub checkpoint_id: u64,
+
+    /// Every mutation up to this seqno is on disk
+    #[serde(
+        default,
+        serialize_with = "serialize_num_as_str",
+        deserialize_with = "deserialize_num_as_str"
+    )]
+    pub persisted_seqno: u64,
+
     pub state: State,
 
     pub failover_table: serde_json::Value,
@@ -200,6 +967,58 @@ pub struct VBucketState {
     pub replication_topology: serde_json::Value,
 }
 
+impl VBucketState {
+    pub const CURRENT_VERSION: usize = 4;
+
+    /// State for a vbucket which has never been persisted
+    pub fn new(state: State) -> Self {
+        Self {
+            max_deleted_seqno: 0,
+            high_seqno: 0,
+            purge_seqno: 0,
+            snap_start: 0,
+            snap_end: 0,
+            max_cas: 0,
+            hlc_epoch: HLC_CAS_SEQNO_UNINITIALISED,
+            might_contain_xattrs: false,
+            namespaces_supported: true,
+            version: Self::CURRENT_VERSION,
+            completed_seqno: 0,
+            prepared_seqno: 0,
+            high_prepared_seqno: 0,
+            max_visible_seqno: 0,
+            on_disk_prepares: 0,
+            on_disk_prepare_bytes: 0,
+            checkpoint_type: CheckpointType::default(),
+            checkpoint_id: 0,
+            persisted_seqno: 0,
+            state,
+            failover_table: serde_json::Value::Null,
+            replication_topology: serde_json::Value::Null,
+        }
+    }
+
+    /// Parse the state persisted in a vbucket's _local/vbstate document
+    pub fn from_json(json: &[u8]) -> serde_json::Result<Self> {
+        serde_json::from_slice(json)
+    }
+}
+
+/// What OBSERVE reports about a key, with the protocol's values
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum KeyState {
+    NotPersisted = 0x00,
+    Persisted = 0x01,
+    /// Absent, or deleted with the deletion persisted
+    NotFound = 0x80,
+    /// Deleted, but the deletion isn't persisted yet
+    LogicalDeleted = 0x81,
+}
+
+/// hlc_epoch of a vbucket created before CAS values were HLC based
+pub const HLC_CAS_SEQNO_UNINITIALISED: i64 = -1;
+
 #[derive(Default, Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
 pub enum CheckpointType {
     #[default]
@@ -233,3 +1052,32 @@ where
     let s = String::deserialize(d)?;
     s.parse().map_err(serde::de::Error::custom)
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_vbid() {
+        // Every vbucket belongs to exactly one shard, at its index
+        let mut seen = Vec::new();
+        for shard_id in 0..3 {
+            for (index, vbid) in Vbid::iter_for_shard(shard_id, 3, 10).enumerate() {
+                assert_eq!(vbid.shard(3), shard_id);
+                assert_eq!(vbid.index_in_shard(3), index);
+                seen.push(u16::from(vbid));
+            }
+        }
+        seen.sort();
+        assert_eq!(seen, (0..10).collect::<Vec<_>>());
+
+        assert_eq!(Vbid::new_checked(9, 10), Ok(Vbid::new(9)));
+        assert_eq!(Vbid::new_checked(10, 10), Err(EngineError::NotMyVbucket));
+
+        let vbid: Vbid = "42".parse().unwrap();
+        assert_eq!(vbid.to_string(), "42");
+        assert!("vb:42".parse::<Vbid>().is_err());
+        assert_eq!(serde_json::to_string(&vbid).unwrap(), "42");
+        assert_eq!(serde_json::from_str::<Vbid>("42").unwrap(), vbid);
+    }
+}
@@ -0,0 +1,43 @@
+//! Which vbucket a key belongs in. Clients, the server and the tools must
+//! all agree, so they place keys with these: the CRC32 based hash of the
+//! Couchbase SDKs, of the key as the client knows it.
+
+use crate::{collections, vbucket::Vbid};
+
+/// The vbucket of a client's key, which has no collection prefix: bits 16
+/// to 30 of the key's CRC32, modulo the number of vbuckets
+pub fn vbucket_for_key(key: &[u8], num_vbuckets: u16) -> Vbid {
+    let crc = crc32fast::hash(key);
+    Vbid::new((((crc >> 16) & 0x7fff) % u32::from(num_vbuckets)) as u16)
+}
+
+/// The vbucket of a key as the engine stores it, prefixed with its
+/// collection's ID, which clients don't hash
+pub fn vbucket_for_stored_key(key: &[u8], num_vbuckets: u16) -> Vbid {
+    let key = collections::split_key(key).map_or(key, |(_, key)| key);
+    vbucket_for_key(key, num_vbuckets)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_vbucket_for_key() {
+        // As the SDKs place them
+        for (key, num_vbuckets, vbid) in [
+            (&b"foo"[..], 1024, 115),
+            (b"hello", 1024, 528),
+            (b"hello", 64, 16),
+            (b"key_0", 1024, 226),
+            (b"foo", 1000, 187),
+        ] {
+            assert_eq!(vbucket_for_key(key, num_vbuckets), Vbid::new(vbid));
+        }
+        assert_eq!(vbucket_for_stored_key(b"\0foo", 1024), Vbid::new(115));
+        assert_eq!(
+            vbucket_for_stored_key(&collections::make_key(0x8a, b"foo"), 1024),
+            Vbid::new(115)
+        );
+    }
+}
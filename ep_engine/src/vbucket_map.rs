@@ -48,8 +48,7 @@ impl VBucketMap {
     }
 
     pub fn get_shard_by_vb_id(&self, id: Vbid) -> &Arc<KVShard> {
-        let idx = usize::from(id) % self.shards.len();
-        &self.shards[idx]
+        &self.shards[id.shard(self.shards.len() as u16) as usize]
     }
 
     pub fn add_bucket(&self, vb: VBucketPtr) {
@@ -64,7 +63,7 @@ impl VBucketMap {
         }
     }
 
-    fn inc_vb_state_count(&self, state: State) {
+    pub fn inc_vb_state_count(&self, state: State) {
         self.vb_state_count[vb_state_to_index(state)].fetch_add(1, Ordering::Relaxed);
     }
 
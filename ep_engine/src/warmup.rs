@@ -1,17 +1,233 @@
 use crate::{
-    ep_bucket::EPBucketPtr,
+    collections,
+    ep_bucket::{EPBucket, EPBucketPtr},
     failover_table::FailoverTable,
-    item::Item,
-    kv_store::Metadata,
+    kv_store::{KVStore, ScanErrorPolicy, ValueFilter},
     vbucket::{self, VBucket, VBucketPtr, VBucketState, Vbid},
     Config,
 };
 use dashmap::DashMap;
+use parking_lot::Mutex;
 use rand::{
     distributions::{Bernoulli, Distribution},
     SeedableRng,
 };
-use std::collections::HashMap;
+use std::{
+    collections::HashMap,
+    sync::atomic::{AtomicU64, Ordering},
+    time::{Duration, Instant},
+};
+
+/// The phases of warmup, in the order they run
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum WarmupPhase {
+    /// The bucket hasn't warmed up
+    NotStarted,
+    /// Reading the persisted vbucket states and creating the vbuckets
+    Initialize,
+    /// Loading every vbucket's keys and metadata
+    LoadKeys,
+    /// Loading values, until all are in or the load thresholds are reached
+    LoadData,
+    /// Loading the keys with their values, which the sequential mode does
+    /// instead of the two phases above
+    LoadKvPairs,
+    Done,
+}
+
+impl WarmupPhase {
+    pub fn name(self) -> &'static str {
+        match self {
+            WarmupPhase::NotStarted => "not_started",
+            WarmupPhase::Initialize => "initialize",
+            WarmupPhase::LoadKeys => "load_keys",
+            WarmupPhase::LoadData => "load_data",
+            WarmupPhase::LoadKvPairs => "load_kv_pairs",
+            WarmupPhase::Done => "done",
+        }
+    }
+}
+
+/// How warmup reads the vbucket files
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum WarmupMode {
+    /// Load every vbucket's keys, then their values, so traffic can start
+    /// as soon as the keys are in and the load thresholds are reached
+    #[default]
+    KeyDump,
+    /// Load each vbucket's keys along with their values in one pass over
+    /// its by-seqno index, reading the file in large sequential chunks,
+    /// which is quicker on spinning disks and for files of compressed
+    /// values. Traffic waits for every key, and once the memory threshold
+    /// is reached the rest are loaded without their values.
+    Sequential,
+}
+
+impl WarmupMode {
+    pub fn name(self) -> &'static str {
+        match self {
+            WarmupMode::KeyDump => "key_dump",
+            WarmupMode::Sequential => "sequential",
+        }
+    }
+}
+
+#[derive(Debug)]
+struct PhaseTimes {
+    mode: WarmupMode,
+    phase: WarmupPhase,
+    /// When warmup started
+    started: Option<Instant>,
+    /// When the current phase started
+    phase_started: Option<Instant>,
+    /// How long each finished phase took
+    durations: Vec<(WarmupPhase, Duration)>,
+}
+
+/// How far the bucket's warmup has got, kept in its stats so operators can
+/// follow a restart
+#[derive(Debug)]
+pub struct WarmupProgress {
+    times: Mutex<PhaseTimes>,
+    /// Upper bound on the keys to load, the sum of the vbuckets' high
+    /// seqnos
+    estimated_key_count: AtomicU64,
+    /// Keys loaded by the key dump, which is how many values there are to
+    /// load
+    keys_loaded: AtomicU64,
+    /// Values loaded during the load data phase
+    values_loaded: AtomicU64,
+    /// Reads made of the vbucket files, and the bytes they read
+    disk_reads: AtomicU64,
+    disk_read_bytes: AtomicU64,
+}
+
+impl Default for WarmupProgress {
+    fn default() -> Self {
+        Self {
+            times: Mutex::new(PhaseTimes {
+                mode: WarmupMode::default(),
+                phase: WarmupPhase::NotStarted,
+                started: None,
+                phase_started: None,
+                durations: Vec::new(),
+            }),
+            estimated_key_count: AtomicU64::new(0),
+            keys_loaded: AtomicU64::new(0),
+            values_loaded: AtomicU64::new(0),
+            disk_reads: AtomicU64::new(0),
+            disk_read_bytes: AtomicU64::new(0),
+        }
+    }
+}
+
+impl WarmupProgress {
+    pub fn phase(&self) -> WarmupPhase {
+        self.times.lock().phase
+    }
+
+    /// Start warming up in the mode
+    fn start(&self, mode: WarmupMode) {
+        self.times.lock().mode = mode;
+        self.enter(WarmupPhase::Initialize);
+    }
+
+    /// Finish the current phase and start the next
+    fn enter(&self, phase: WarmupPhase) {
+        let mut times = self.times.lock();
+        let now = Instant::now();
+        if let Some(phase_started) = times.phase_started {
+            let finished = times.phase;
+            times.durations.push((finished, now - phase_started));
+        }
+        times.started.get_or_insert(now);
+        times.phase_started = (phase != WarmupPhase::Done).then_some(now);
+        times.phase = phase;
+    }
+
+    /// A rough guess at how long warmup will take to finish, at the rate
+    /// the current phase is loading items, None until that rate is known.
+    /// While the keys load, loading each value is guessed to take as long
+    /// as loading its key.
+    pub fn estimated_time_remaining(&self) -> Option<Duration> {
+        let (phase, elapsed) = {
+            let times = self.times.lock();
+            (
+                times.phase,
+                times.phase_started.map(|start| start.elapsed()),
+            )
+        };
+        let estimated = self.estimated_key_count.load(Ordering::Relaxed);
+        let keys = self.keys_loaded.load(Ordering::Relaxed);
+        let values = self.values_loaded.load(Ordering::Relaxed);
+        let (done, remaining) = match phase {
+            WarmupPhase::Done => return Some(Duration::ZERO),
+            WarmupPhase::LoadKeys => (keys, estimated.saturating_sub(keys) + estimated),
+            WarmupPhase::LoadData => (values, keys.saturating_sub(values)),
+            WarmupPhase::LoadKvPairs => (keys, estimated.saturating_sub(keys)),
+            WarmupPhase::NotStarted | WarmupPhase::Initialize => return None,
+        };
+        if done == 0 {
+            return None;
+        }
+        Some(elapsed?.mul_f64(remaining as f64 / done as f64))
+    }
+
+    /// The stats of the warmup group
+    pub fn add_stats(&self, add_stat: &mut dyn FnMut(&str, &str)) {
+        {
+            let times = self.times.lock();
+            add_stat("ep_warmup_state", times.phase.name());
+            add_stat("ep_warmup_mode", times.mode.name());
+            for (phase, duration) in &times.durations {
+                add_stat(
+                    &format!("ep_warmup_{}_time_us", phase.name()),
+                    &duration.as_micros().to_string(),
+                );
+            }
+            if let Some(phase_started) = times.phase_started {
+                add_stat(
+                    &format!("ep_warmup_{}_time_us", times.phase.name()),
+                    &phase_started.elapsed().as_micros().to_string(),
+                );
+            }
+            if let Some(started) = times.started {
+                let total = match times.phase {
+                    WarmupPhase::Done => times.durations.iter().map(|(_, d)| *d).sum(),
+                    _ => started.elapsed(),
+                };
+                add_stat("ep_warmup_time_us", &total.as_micros().to_string());
+            }
+        }
+        add_stat(
+            "ep_warmup_estimated_key_count",
+            &self.estimated_key_count.load(Ordering::Relaxed).to_string(),
+        );
+        add_stat(
+            "ep_warmup_key_count",
+            &self.keys_loaded.load(Ordering::Relaxed).to_string(),
+        );
+        add_stat(
+            "ep_warmup_value_count",
+            &self.values_loaded.load(Ordering::Relaxed).to_string(),
+        );
+        add_stat(
+            "ep_warmup_disk_reads",
+            &self.disk_reads.load(Ordering::Relaxed).to_string(),
+        );
+        add_stat(
+            "ep_warmup_disk_read_bytes",
+            &self.disk_read_bytes.load(Ordering::Relaxed).to_string(),
+        );
+        if let Some(remaining) = self.estimated_time_remaining() {
+            add_stat(
+                "ep_warmup_estimated_time_remaining_us",
+                &remaining.as_micros().to_string(),
+            );
+        }
+    }
+}
 
 pub struct Warmup {
     store: EPBucketPtr,
@@ -38,7 +254,13 @@ impl Warmup {
         }
     }
 
+    fn progress(&self) -> &WarmupProgress {
+        &self.store.stats().warmup
+    }
+
     pub fn warmup(&mut self) {
+        let mode = self.store.config().warmup_mode;
+        self.progress().start(mode);
         self.initialise();
         for shard_id in 0..self.store.vbucket_map.get_num_shards() {
             self.create_vbuckets(shard_id);
@@ -49,18 +271,92 @@ impl Warmup {
         for shard_id in 0..self.store.vbucket_map.get_num_shards() {
             self.populate_vbucket_map(shard_id);
         }
-        for shard_id in 0..self.store.vbucket_map.get_num_shards() {
-            self.key_dump(shard_id);
+        let num_shards = self.store.vbucket_map.get_num_shards();
+        match mode {
+            WarmupMode::KeyDump => {
+                self.progress().enter(WarmupPhase::LoadKeys);
+                for shard_id in 0..num_shards {
+                    self.count_reads(shard_id, || self.key_dump(shard_id));
+                }
+                // // self.load_access_log();
+                self.progress().enter(WarmupPhase::LoadData);
+                for shard_id in 0..num_shards {
+                    self.count_reads(shard_id, || self.load_data(shard_id));
+                }
+            }
+            WarmupMode::Sequential => {
+                self.progress().enter(WarmupPhase::LoadKvPairs);
+                for shard_id in 0..num_shards {
+                    self.count_reads(shard_id, || self.load_kv_pairs(shard_id));
+                }
+            }
         }
-        // // self.load_access_log();
-        for shard_id in 0..self.store.vbucket_map.get_num_shards() {
-            self.load_data(shard_id);
+        self.done();
+    }
+
+    /// Add the reads the shard's store makes of its files during load to
+    /// warmup's
+    fn count_reads(&self, shard_id: usize, load: impl FnOnce()) {
+        let stats = self.store.get_store_by_shard(shard_id).get_stats();
+        let reads = stats.scan_reads.load(Ordering::Relaxed);
+        let bytes = stats.scan_bytes_read.load(Ordering::Relaxed);
+        load();
+        self.progress().disk_reads.fetch_add(
+            stats.scan_reads.load(Ordering::Relaxed) - reads,
+            Ordering::Relaxed,
+        );
+        self.progress().disk_read_bytes.fetch_add(
+            stats.scan_bytes_read.load(Ordering::Relaxed) - bytes,
+            Ordering::Relaxed,
+        );
+    }
+
+    /// Warmup has finished, allow front-end traffic if the load thresholds
+    /// did not already do so.
+    fn done(&self) {
+        self.progress().enter(WarmupPhase::Done);
+        if self.store.enable_traffic() {
+            println!(
+                "Warmup completed: {} of {} items loaded",
+                self.progress().values_loaded.load(Ordering::Relaxed),
+                self.progress().keys_loaded.load(Ordering::Relaxed)
+            );
+        }
+    }
+
+    /// Have enough items been loaded (or enough memory used) for traffic to
+    /// be enabled before every value has been loaded
+    fn has_reached_threshold(&self) -> bool {
+        let config = self.store.config();
+        let estimated = self.progress().keys_loaded.load(Ordering::Relaxed);
+        let loaded = self.progress().values_loaded.load(Ordering::Relaxed);
+        if loaded * 100 >= estimated * config.warmup_min_items_threshold as u64 {
+            return true;
         }
+        self.has_reached_memory_threshold()
+    }
+
+    /// Have the values loaded filled as much of the quota as warmup may
+    fn has_reached_memory_threshold(&self) -> bool {
+        let config = self.store.config();
+        let mem_used = self.store.stats().get_estimated_total_memory_used();
+        mem_used * 100
+            >= self.store.stats().get_max_data_size() * config.warmup_min_memory_threshold as usize
     }
 
     pub fn initialise(&mut self) {
         // TODO: Warmup collection manifest
         self.populate_shard_vb_states();
+        // Every key on disk has its own seqno
+        let estimated_keys: u64 = self
+            .shard_vb_states
+            .iter()
+            .flat_map(|states| states.values())
+            .map(|state| state.high_seqno.max(0) as u64)
+            .sum();
+        self.progress()
+            .estimated_key_count
+            .store(estimated_keys, Ordering::Relaxed);
     }
 
     fn get_num_kv_stores(&self) -> usize {
@@ -69,21 +365,21 @@ impl Warmup {
 
     fn populate_shard_vb_states(&mut self) {
         let num_kvs = self.get_num_kv_stores();
+        let max_vbuckets = self.store.config().max_vbuckets;
         for shard_id in 0..num_kvs {
             let kv_store_vb_states = self
                 .store
                 .get_store_by_shard(shard_id)
                 .list_persisted_vbuckets();
-            for (i, &state) in kv_store_vb_states.iter().enumerate() {
+            let vbids = Vbid::iter_for_shard(shard_id as u16, num_kvs as u16, max_vbuckets);
+            for (vb, state) in vbids.zip(kv_store_vb_states) {
                 let state = if let Some(state) = state {
                     state
                 } else {
                     continue;
                 };
-                let vb = (i * num_kvs) + shard_id;
-                let shard_vb =
-                    &mut self.shard_vb_states[vb % self.store.vbucket_map.get_num_shards()];
-                shard_vb.insert(Vbid::from(vb), state.clone());
+                let shard_vb = &mut self.shard_vb_states[vb.shard(num_kvs as u16) as usize];
+                shard_vb.insert(vb, state);
             }
         }
 
@@ -152,7 +448,27 @@ impl Warmup {
                 };
                 let _shard = self.store.get_vbuckets().get_shard_by_vb_id(vbid);
                 // TODO: get collection manifest
-                let vb = VBucketPtr::new(VBucket::new(vbid, state.state, table));
+                let vb = self.store.make_vbucket(
+                    vbid,
+                    state.state,
+                    table,
+                    state.high_seqno as u64,
+                    state.max_cas,
+                );
+                vb.checkpoint_manager.resume_from(state.checkpoint_id);
+                vb.set_persisted_seqno(state.persisted_seqno);
+                // A replica may have persisted part of a snapshot, it must
+                // receive the rest before its data is consistent
+                if vb
+                    .create_snapshot(state.snap_start, state.snap_end)
+                    .is_err()
+                {
+                    println!(
+                        "{vbid}: ignoring persisted snapshot {}-{} below high seqno {}",
+                        state.snap_start, state.snap_end, state.high_seqno
+                    );
+                }
+                vb.set_purge_seqno(state.purge_seqno);
 
                 self.warmed_up_vbuckets.insert(vbid, vb.clone());
 
@@ -209,22 +525,11 @@ impl Warmup {
         let vbucket_map = &self.store.vbucket_map;
         let vbucket_filter = &self.shard_vb_ids[shard_id];
         for &vbid in vbucket_filter {
-            let mut ctx = store.init_by_seqno_scan_context(vbid, 0);
-            // TODO: Do this properly (in batches) like kv_engine
-            ctx.db.changes_since(0, |_, doc_info| {
-                let vb = vbucket_map.get_bucket(vbid).unwrap();
-                let metadata = Metadata::decode(&doc_info.rev_meta[..]);
-                let item = Item {
-                    key: doc_info.id,
-                    value: None,
-                    cas: metadata.cas,
-                    expiry_time: metadata.expiry_time,
-                    flags: metadata.flags,
-                    by_seqno: doc_info.db_seq,
-                    rev_seqno: doc_info.rev_seq,
-                };
-                vb.insert_from_warmup(item);
-            });
+            let vb = vbucket_map.get_bucket(vbid).unwrap();
+            let count = dump_keys(&self.store, store, &vb);
+            self.progress()
+                .keys_loaded
+                .fetch_add(count as u64, Ordering::Relaxed);
         }
     }
 
@@ -233,39 +538,142 @@ impl Warmup {
         let vbucket_map = &self.store.vbucket_map;
         let vbucket_filter = &self.shard_vb_ids[shard_id];
         for &vbid in vbucket_filter {
-            let mut ctx = store.init_by_seqno_scan_context(vbid, 0);
+            let vb = vbucket_map.get_bucket(vbid).unwrap();
             // TODO: Do this properly (in batches) like kv_engine
-            ctx.db.changes_since(0, move |db, doc_info| {
-                let doc = if let Some(doc) = db.open_doc_with_docinfo(
-                    &doc_info,
-                    couchstore::OpenOptions::DECOMPRESS_DOC_BODIES,
-                ) {
-                    doc
-                } else {
-                    return;
-                };
+            // A value which can't be read is left to fail when fetched
+            let policy = ScanErrorPolicy::SkipAndReport;
+            let result = store.scan(
+                vbid,
+                0,
+                ValueFilter::ValuesDecompressed,
+                policy,
+                &mut |item| {
+                    if self.store.is_traffic_enabled() || self.store.is_shutting_down() {
+                        // The load thresholds were reached, the remaining values
+                        // will be fetched from disk on demand.
+                        return;
+                    }
+                    let Some(value) = &item.value else {
+                        return;
+                    };
+                    // System events were replayed by the key dump, and aren't
+                    // part of the estimated item count
+                    if collections::collection_event_id(&item.key).is_some() {
+                        return;
+                    }
+                    self.store
+                        .io_throttle()
+                        .acquire(item.key.len() + value.len());
+                    vb.insert_from_warmup(item);
 
-                let vb = vbucket_map.get_bucket(vbid).unwrap();
-                let metadata = Metadata::decode(&doc_info.rev_meta[..]);
-                let item = Item {
-                    key: doc_info.id,
-                    value: Some(doc.data),
-                    cas: metadata.cas,
-                    expiry_time: metadata.expiry_time,
-                    flags: metadata.flags,
-                    by_seqno: doc_info.db_seq,
-                    rev_seqno: doc_info.rev_seq,
-                };
-                vb.insert_from_warmup(item);
-            });
+                    self.progress()
+                        .values_loaded
+                        .fetch_add(1, Ordering::Relaxed);
+                    if self.has_reached_threshold() && self.store.enable_traffic() {
+                        println!("Warmup load thresholds reached, enabling traffic");
+                    }
+                },
+            );
+            self.store.on_scan_errors(vbid, &result);
+        }
+    }
+
+    /// Load each vbucket's keys with their values in one sequential pass,
+    /// replaying its collection events as they come. Every key has to be
+    /// loaded, so a corrupt item aborts the scan as it does the key dump.
+    fn load_kv_pairs(&self, shard_id: usize) {
+        let store = self.store.get_store_by_shard(shard_id);
+        let vbucket_map = &self.store.vbucket_map;
+        for &vbid in &self.shard_vb_ids[shard_id] {
+            let vb = vbucket_map.get_bucket(vbid).unwrap();
+            let result = store.scan_sequential(
+                vbid,
+                0,
+                ValueFilter::ValuesDecompressed,
+                ScanErrorPolicy::Abort,
+                &mut |item| {
+                    if self.store.is_shutting_down() {
+                        return;
+                    }
+                    self.store
+                        .io_throttle()
+                        .acquire(item.key.len() + item.value.as_ref().map_or(0, Vec::len));
+                    if collections::collection_event_id(&item.key).is_some() {
+                        vb.replay_system_event(&item);
+                        return;
+                    }
+                    if item.value.is_some() && self.has_reached_memory_threshold() {
+                        // Left to be fetched from disk on demand
+                        vb.insert_from_warmup(item);
+                    } else {
+                        if item.value.is_some() {
+                            self.progress()
+                                .values_loaded
+                                .fetch_add(1, Ordering::Relaxed);
+                        }
+                        vb.load_from_warmup(item);
+                    }
+                    self.progress().keys_loaded.fetch_add(1, Ordering::Relaxed);
+                },
+            );
+            self.store.on_scan_errors(vbid, &result);
         }
     }
 }
 
+/// Load the keys and metadata the vbucket's store has persisted into its
+/// hash table, and replay its collection events, returning how many items
+/// were loaded. Values are left to be read from disk.
+pub(crate) fn dump_keys(bucket: &EPBucket, store: &dyn KVStore, vb: &VBucket) -> usize {
+    let mut count = 0;
+    let mut first_system_event = None;
+    // TODO: Do this properly (in batches) like kv_engine
+    let result = store.scan(
+        vb.id,
+        0,
+        ValueFilter::KeysOnly,
+        ScanErrorPolicy::Abort,
+        &mut |item| {
+            if bucket.is_shutting_down() {
+                return;
+            }
+            bucket.io_throttle().acquire(item.key.len());
+            if collections::collection_event_id(&item.key).is_some() {
+                first_system_event.get_or_insert(item.by_seqno);
+                return;
+            }
+            vb.insert_from_warmup(item);
+            count += 1;
+        },
+    );
+    bucket.on_scan_errors(vb.id, &result);
+
+    // Without values a created collection can't be told apart from a
+    // dropped one, so read the system events again with theirs
+    if let Some(start_seqno) = first_system_event {
+        let result = store.scan(
+            vb.id,
+            start_seqno,
+            ValueFilter::ValuesDecompressed,
+            ScanErrorPolicy::Abort,
+            &mut |item| vb.replay_system_event(&item),
+        );
+        bucket.on_scan_errors(vb.id, &result);
+    }
+    count
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
-    use crate::{ep_bucket::EPBucket, vbucket};
+    use crate::{
+        ep_bucket::EPBucket,
+        error::EngineError,
+        gen_dataset,
+        item::{Datatype, DeleteSource, Item},
+        vbucket::{self, KeyState},
+        vbucket_hash::vbucket_for_key,
+    };
 
     #[test]
     fn test_warmup() {
@@ -273,10 +681,16 @@ mod test {
             max_vbuckets: 1024,
             max_shards: 1,
             dbname: "../test-data/travel-sample".to_string(),
+            ..Default::default()
         };
         let store = EPBucket::new(config.clone());
         let mut warmup = Warmup::new(store.clone(), config);
+        assert_eq!(
+            store.get(Vec::from("landmark_25686")).unwrap_err(),
+            EngineError::TemporaryFailure
+        );
         warmup.warmup();
+        assert!(store.is_traffic_enabled());
         assert_eq!(
             warmup.shard_vb_states[0]
                 .get(&Vbid::from(0usize))
@@ -290,5 +704,317 @@ mod test {
         assert_eq!(val.cas, 1693175504558817280);
         assert!(val.value.is_some());
         assert!(val.is_resident());
+
+        let mut mem_used = None;
+        store.get_stats(&mut |key, value| {
+            if key == "ep_kv_size" {
+                mem_used = Some(value.parse::<usize>().unwrap());
+            }
+        });
+        assert!(mem_used.unwrap() > 0);
+    }
+
+    #[test]
+    fn test_warmup_mid_snapshot() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = Config {
+            max_vbuckets: 4,
+            max_shards: 1,
+            dbname: dir.path().to_str().unwrap().to_string(),
+            ..Default::default()
+        };
+        let vbid = Vbid::from(0usize);
+        let receive = |vb: &VBucketPtr, seqno: u64| {
+            vb.set_with_meta(Item {
+                key: format!("key_{seqno}").into_bytes(),
+                value: Some(Vec::from("value")),
+                cas: seqno,
+                expiry_time: 0,
+                flags: 0,
+                by_seqno: seqno,
+                rev_seqno: 1,
+                delete_source: DeleteSource::Explicit,
+                datatype: Datatype::empty(),
+            })
+            .unwrap();
+        };
+
+        // A replica persists part of a snapshot then crashes
+        let store = EPBucket::new(config.clone());
+        let vb = store.make_vbucket(
+            vbid,
+            vbucket::State::Replica,
+            FailoverTable::new_empty(25),
+            0,
+            0,
+        );
+        store.vbucket_map.add_bucket(vb.clone());
+        vb.create_snapshot(1, 10).unwrap();
+        for seqno in 1..=6 {
+            receive(&vb, seqno);
+        }
+        assert_eq!(store.flush_vbucket(vbid), 6);
+        drop(vb);
+        drop(store);
+
+        let store = EPBucket::new(config.clone());
+        Warmup::new(store.clone(), config).warmup();
+        let vb = store.get_vbucket(vbid).unwrap();
+        assert_eq!(vb.state(), vbucket::State::Replica);
+        assert_eq!(vb.get_high_seqno(), 6);
+        // The stream must resume inside the partially received snapshot
+        let info = vb.checkpoint_manager.get_snapshot_info();
+        assert_eq!((info.start, info.snap_start, info.snap_end), (6, 1, 10));
+
+        for seqno in 7..=10 {
+            receive(&vb, seqno);
+        }
+        assert_eq!(store.flush_vbucket(vbid), 4);
+        let vb_state = store
+            .get_store_by_shard(0)
+            .get_cached_vb_state(vbid)
+            .unwrap();
+        assert_eq!(vb_state.high_seqno, 10);
+        assert_eq!((vb_state.snap_start, vb_state.snap_end), (10, 10));
+        let info = vb.checkpoint_manager.get_snapshot_info();
+        assert_eq!((info.start, info.snap_start, info.snap_end), (10, 10, 10));
+    }
+
+    #[test]
+    fn test_warmup_collections() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = Config {
+            max_vbuckets: 4,
+            max_shards: 1,
+            dbname: dir.path().to_str().unwrap().to_string(),
+            ..Default::default()
+        };
+        let vbid = Vbid::from(0usize);
+
+        let store = EPBucket::new(config.clone());
+        store.vbucket_map.add_bucket(store.make_vbucket(
+            vbid,
+            vbucket::State::Active,
+            FailoverTable::new_empty(25),
+            0,
+            0,
+        ));
+        store.create_collection(8, 0, "beers", 0).unwrap();
+        store.create_collection(9, 0, "wines", 0).unwrap();
+        store.drop_collection(8).unwrap();
+        store.flush_vbucket(vbid);
+        drop(store);
+
+        // The collections are rebuilt from the persisted system events
+        let store = EPBucket::new(config.clone());
+        Warmup::new(store.clone(), config).warmup();
+        let vb = store.get_vbucket(vbid).unwrap();
+        assert!(vb.get_collection(8).is_none());
+        assert_eq!(vb.get_collection(9).unwrap().name, "wines");
+        assert!(vb.hash_table.lock().map.is_empty());
+    }
+
+    #[test]
+    fn test_warmup_persistence_state() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = Config {
+            max_vbuckets: 4,
+            max_shards: 1,
+            dbname: dir.path().to_str().unwrap().to_string(),
+            ..Default::default()
+        };
+        let vbid = Vbid::from(0usize);
+        let keys: Vec<Vec<u8>> = (0..)
+            .map(|i| format!("key_{i}").into_bytes())
+            .filter(|key| vbucket_for_key(key, 4) == Vbid::new(0))
+            .take(3)
+            .collect();
+
+        let store = EPBucket::new(config.clone());
+        let vb = store.make_vbucket(
+            vbid,
+            vbucket::State::Active,
+            FailoverTable::new_empty(25),
+            0,
+            0,
+        );
+        store.vbucket_map.add_bucket(vb.clone());
+        store.enable_traffic();
+        store.set(keys[0].clone(), b"value".to_vec(), 0, 0).unwrap();
+        vb.checkpoint_manager.create_new_checkpoint();
+        store.set(keys[0].clone(), b"value".to_vec(), 0, 0).unwrap();
+        store.flush_vbucket(vbid);
+        let persisted_checkpoint = vb.checkpoint_manager.get_open_checkpoint_id();
+        assert_eq!(persisted_checkpoint, 2);
+        // Lost in the crash
+        store.set(keys[1].clone(), vec![], 0, 0).unwrap();
+        assert_eq!(
+            store.observe(keys[1].clone()).unwrap().0,
+            KeyState::NotPersisted
+        );
+        drop(vb);
+        drop(store);
+
+        let store = EPBucket::new(config.clone());
+        Warmup::new(store.clone(), config).warmup();
+        let vb = store.get_vbucket(vbid).unwrap();
+        assert!(vb.checkpoint_manager.get_open_checkpoint_id() > persisted_checkpoint);
+        let (key_state, cas) = store.observe(keys[0].clone()).unwrap();
+        assert_eq!(key_state, KeyState::Persisted);
+        assert_eq!(cas, store.get(keys[0].clone()).unwrap().cas);
+        assert_eq!(
+            store.observe(keys[1].clone()).unwrap().0,
+            KeyState::NotFound
+        );
+        let observed = store.observe_seqno(vbid).unwrap();
+        assert_eq!(
+            (observed.last_persisted_seqno, observed.current_seqno),
+            (2, 2)
+        );
+
+        store.delete(keys[0].clone(), 0).unwrap();
+        store.set(keys[2].clone(), vec![], 0, 0).unwrap();
+        assert_eq!(
+            store.observe(keys[0].clone()).unwrap().0,
+            KeyState::LogicalDeleted
+        );
+        let observed = store.observe_seqno(vbid).unwrap();
+        assert_eq!(
+            (observed.last_persisted_seqno, observed.current_seqno),
+            (2, 4)
+        );
+        store.flush_vbucket(vbid);
+        assert_eq!(
+            store.observe(keys[2].clone()).unwrap().0,
+            KeyState::Persisted
+        );
+        assert_eq!(store.observe_seqno(vbid).unwrap().last_persisted_seqno, 4);
+    }
+
+    #[test]
+    fn test_warmup_stats() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = Config {
+            max_vbuckets: 4,
+            max_shards: 1,
+            dbname: dir.path().to_str().unwrap().to_string(),
+            ..Default::default()
+        };
+        let spec = gen_dataset::DatasetSpec {
+            num_vbuckets: 4,
+            documents: 300,
+            ..Default::default()
+        };
+        gen_dataset::generate(&config.dbname, &spec).unwrap();
+
+        let store = EPBucket::new(config.clone());
+        let stats = |store: &EPBucket| {
+            let mut stats = HashMap::new();
+            store
+                .get_stats_group("warmup", &mut |key, value| {
+                    stats.insert(key.to_string(), value.to_string());
+                })
+                .unwrap();
+            stats
+        };
+        let before = stats(&store);
+        assert_eq!(before["ep_warmup_state"], "not_started");
+        assert!(!before.contains_key("ep_warmup_time_us"));
+        assert!(!before.contains_key("ep_warmup_estimated_time_remaining_us"));
+
+        Warmup::new(store.clone(), config).warmup();
+        let after = stats(&store);
+        assert_eq!(after["ep_warmup_state"], "done");
+        assert_eq!(after["ep_warmup_estimated_key_count"], "300");
+        assert_eq!(after["ep_warmup_key_count"], "300");
+        assert_eq!(after["ep_warmup_value_count"], "300");
+        assert_eq!(after["ep_warmup_estimated_time_remaining_us"], "0");
+        let time = |phase: &str| after[&format!("ep_warmup_{phase}_time_us")].parse::<u64>();
+        let phases: u64 = ["initialize", "load_keys", "load_data"]
+            .iter()
+            .map(|phase| time(phase).unwrap())
+            .sum();
+        // Each phase's time is rounded down to the microsecond
+        let total = after["ep_warmup_time_us"].parse::<u64>().unwrap();
+        assert!((phases..=phases + 3).contains(&total));
+        assert!(!after.contains_key("ep_warmup_done_time_us"));
+
+        let mut state = None;
+        store.get_stats(&mut |key, value| {
+            if key == "ep_warmup_state" {
+                state = Some(value.to_string());
+            }
+        });
+        assert_eq!(state.as_deref(), Some("done"));
+    }
+
+    #[test]
+    fn test_warmup_sequential() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = Config {
+            max_vbuckets: 4,
+            max_shards: 1,
+            dbname: dir.path().to_str().unwrap().to_string(),
+            ..Default::default()
+        };
+        let spec = gen_dataset::DatasetSpec {
+            num_vbuckets: 4,
+            documents: 2000,
+            collections: true,
+            ..Default::default()
+        };
+        gen_dataset::generate(&config.dbname, &spec).unwrap();
+
+        let warm_up = |warmup_mode| {
+            let config = Config {
+                warmup_mode,
+                ..config.clone()
+            };
+            let store = EPBucket::new(config.clone());
+            Warmup::new(store.clone(), config).warmup();
+            assert!(store.is_traffic_enabled());
+            let mut stats = HashMap::new();
+            store
+                .get_stats_group("warmup", &mut |key, value| {
+                    stats.insert(key.to_string(), value.to_string());
+                })
+                .unwrap();
+            let mut items = Vec::new();
+            let mut collections = Vec::new();
+            for vbid in 0..4usize {
+                let vb = store.get_vbucket(Vbid::from(vbid)).unwrap();
+                let mut vb_items: Vec<_> = vb
+                    .hash_table
+                    .lock()
+                    .map
+                    .iter()
+                    .map(|(key, v)| (key.clone(), v.value.clone(), v.cas, v.is_resident()))
+                    .collect();
+                vb_items.sort();
+                items.push(vb_items);
+                collections.push(vb.get_collection(8).map(|collection| collection.name));
+            }
+            (stats, items, collections)
+        };
+        let (key_dump, key_dump_items, key_dump_collections) = warm_up(WarmupMode::KeyDump);
+        let (sequential, items, collections) = warm_up(WarmupMode::Sequential);
+
+        // The same items and collections load either way
+        assert_eq!(items, key_dump_items);
+        assert_eq!(collections, key_dump_collections);
+        assert!(collections.iter().all(Option::is_some));
+        assert!(items.iter().flatten().all(|(.., resident)| *resident));
+        assert_eq!(key_dump["ep_warmup_mode"], "key_dump");
+        assert_eq!(sequential["ep_warmup_mode"], "sequential");
+        assert_eq!(sequential["ep_warmup_key_count"], "2000");
+        assert_eq!(sequential["ep_warmup_value_count"], "2000");
+        assert!(sequential.contains_key("ep_warmup_load_kv_pairs_time_us"));
+        assert!(!sequential.contains_key("ep_warmup_load_keys_time_us"));
+
+        // In far fewer reads
+        let reads =
+            |stats: &HashMap<String, String>| stats["ep_warmup_disk_reads"].parse::<u64>().unwrap();
+        assert!(reads(&sequential) > 0);
+        assert!(reads(&sequential) * 10 < reads(&key_dump));
     }
 }
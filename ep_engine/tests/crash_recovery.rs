@@ -0,0 +1,158 @@
+//! Kills the crash workload with SIGKILL at random points, restarts it and
+//! checks what survived on disk. Set CRASH_RECOVERY_SEED to replay a run.
+
+use std::{
+    collections::{HashMap, HashSet},
+    io::{BufRead, BufReader},
+    path::Path,
+    process::{Command, Stdio},
+    time::Duration,
+};
+
+use rand::{Rng, SeedableRng};
+
+const WORKLOAD: &str = env!("CARGO_BIN_EXE_crash_workload");
+const RUNS: u64 = 8;
+
+/// What the workload acknowledged as persisted before it was killed
+#[derive(Default)]
+struct Acked {
+    /// The latest (run, counter) written to each key
+    values: HashMap<String, (u64, u64)>,
+    high_seqnos: HashMap<u16, i64>,
+}
+
+impl Acked {
+    fn parse_line(&mut self, line: &str) {
+        let fields: Vec<&str> = line.split(' ').collect();
+        match fields[..] {
+            ["ack", key, run, counter] => {
+                if let (Ok(run), Ok(counter)) = (run.parse(), counter.parse()) {
+                    self.values.insert(key.to_string(), (run, counter));
+                }
+            }
+            ["persisted", vbid, high_seqno] => {
+                if let (Ok(vbid), Ok(high_seqno)) = (vbid.parse(), high_seqno.parse()) {
+                    let high = self.high_seqnos.entry(vbid).or_default();
+                    *high = (*high).max(high_seqno);
+                }
+            }
+            // Log lines, or a line cut short by the kill
+            _ => {}
+        }
+    }
+}
+
+/// What is on disk after the crash
+#[derive(Default)]
+struct Persisted {
+    values: HashMap<String, (u64, u64)>,
+    high_seqnos: HashMap<u16, i64>,
+}
+
+fn run_workload(dir: &Path, seed: u64, run: u64, runtime: Duration, acked: &mut Acked) {
+    let mut child = Command::new(WORKLOAD)
+        .args([
+            "run",
+            dir.to_str().unwrap(),
+            &seed.to_string(),
+            &run.to_string(),
+        ])
+        .stdout(Stdio::piped())
+        .spawn()
+        .unwrap();
+    let stdout = child.stdout.take().unwrap();
+    let reader = std::thread::spawn(move || {
+        BufReader::new(stdout)
+            .lines()
+            .map_while(Result::ok)
+            .collect::<Vec<_>>()
+    });
+
+    std::thread::sleep(runtime);
+    child.kill().unwrap();
+    child.wait().unwrap();
+    for line in reader.join().unwrap() {
+        acked.parse_line(&line);
+    }
+}
+
+fn dump(dir: &Path) -> Persisted {
+    let output = Command::new(WORKLOAD)
+        .args(["dump", dir.to_str().unwrap()])
+        .output()
+        .unwrap();
+    assert!(output.status.success(), "dump failed: {output:?}");
+
+    let mut persisted = Persisted::default();
+    let mut seqnos = HashSet::new();
+    for line in String::from_utf8(output.stdout).unwrap().lines() {
+        let fields: Vec<&str> = line.split(' ').collect();
+        match fields[..] {
+            ["vb", vbid, high_seqno] => {
+                persisted
+                    .high_seqnos
+                    .insert(vbid.parse().unwrap(), high_seqno.parse().unwrap());
+            }
+            ["item", vbid, seqno, key, value] => {
+                let vbid: u16 = vbid.parse().unwrap();
+                let seqno: i64 = seqno.parse().unwrap();
+                assert!(
+                    seqnos.insert((vbid, seqno)),
+                    "duplicate seqno {seqno} in vb {vbid}"
+                );
+                assert!(seqno <= persisted.high_seqnos[&vbid]);
+                let (run, counter) = value.split_once(':').unwrap();
+                let previous = persisted.values.insert(
+                    key.to_string(),
+                    (run.parse().unwrap(), counter.parse().unwrap()),
+                );
+                assert!(previous.is_none(), "{key} has more than one live revision");
+            }
+            _ => panic!("unexpected dump output {line}"),
+        }
+    }
+    persisted
+}
+
+#[test]
+fn test_crash_recovery() {
+    let seed = std::env::var("CRASH_RECOVERY_SEED")
+        .map(|seed| seed.parse().unwrap())
+        .unwrap_or_else(|_| rand::random());
+    println!("CRASH_RECOVERY_SEED={seed}");
+    let mut rng = rand::rngs::StdRng::seed_from_u64(seed);
+
+    let dir = tempfile::tempdir().unwrap();
+    let mut acked = Acked::default();
+    let mut last_high_seqnos: HashMap<u16, i64> = HashMap::new();
+
+    for run in 0..RUNS {
+        let runtime = Duration::from_millis(rng.gen_range(50..300));
+        run_workload(dir.path(), rng.gen(), run, runtime, &mut acked);
+        let persisted = dump(dir.path());
+
+        for (vbid, &high_seqno) in &persisted.high_seqnos {
+            let last = last_high_seqnos.get(vbid).copied().unwrap_or_default();
+            assert!(
+                high_seqno >= last,
+                "vb {vbid} high seqno went back from {last} to {high_seqno}"
+            );
+            let acked_high = acked.high_seqnos.get(vbid).copied().unwrap_or_default();
+            assert!(
+                high_seqno >= acked_high,
+                "vb {vbid} lost persisted seqnos, {high_seqno} < {acked_high}"
+            );
+        }
+        last_high_seqnos = persisted.high_seqnos;
+
+        // A later, unacknowledged write may have replaced an acknowledged one
+        for (key, &acked_value) in &acked.values {
+            let value = persisted.values.get(key);
+            assert!(
+                value.is_some_and(|&value| value >= acked_value),
+                "{key} lost its acknowledged write {acked_value:?}, found {value:?}"
+            );
+        }
+    }
+}
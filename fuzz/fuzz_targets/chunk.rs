@@ -0,0 +1,24 @@
+//! Reads a chunk at an arbitrary position of an arbitrary file, exercising
+//! the block prefix skipping, length, checksum and snappy framing.
+
+#![no_main]
+
+use std::io::Write;
+
+use couchstore::{DBOpenOptions, TreeFile};
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    let Some((pos, contents)) = data.split_first_chunk::<2>() else {
+        return;
+    };
+    let pos = u16::from_be_bytes(*pos) as usize;
+
+    let mut file = tempfile::tempfile().unwrap();
+    file.write_all(contents).unwrap();
+    let file = TreeFile::new(file, DBOpenOptions::default().read_only());
+
+    let _ = file.read_compressed(pos);
+    let _ = file.read_uncompressed(pos);
+    let _ = file.read_header(pos, 1024);
+});
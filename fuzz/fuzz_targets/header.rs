@@ -0,0 +1,20 @@
+//! Decodes a file header, then opens a file ending in that header to
+//! exercise the search back for the last valid header.
+
+#![no_main]
+
+use std::io::Write;
+
+use couchstore::{DBOpenOptions, Db};
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    let _ = couchstore::fuzzing::decode_header(data, 4096);
+
+    let mut file = tempfile::NamedTempFile::new().unwrap();
+    file.write_all(data).unwrap();
+    if let Ok(db) = Db::open(file.path(), DBOpenOptions::default().read_only()) {
+        let _ = db.changes_since(0, |_, _| Ok(()));
+        let _ = db.open_local_document("_local/vbstate");
+    }
+});
@@ -0,0 +1,9 @@
+//! Decodes a B-tree node and its items as index values.
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    let _ = couchstore::fuzzing::decode_node(data);
+});
@@ -0,0 +1,10 @@
+//! Parses a _local/vbstate document.
+
+#![no_main]
+
+use ep_engine::vbucket::VBucketState;
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    let _ = VBucketState::from_json(data);
+});
@@ -0,0 +1,514 @@
+//! An HTTP admin API, so tooling in the style of the cluster manager can
+//! orchestrate the engine without speaking MCBP's admin opcodes. Each
+//! connection carries one HTTP/1.1 request, and bodies are JSON:
+//!
+//! - `GET /buckets` lists the open buckets
+//! - `POST /buckets/{name}` creates a bucket, or opens it from disk
+//! - `DELETE /buckets/{name}` deletes a bucket and its files
+//! - `GET /buckets/{name}/stats[/{group}]` returns a bucket's stats
+//! - `POST /buckets/{name}/compact` starts compacting every vbucket, with
+//!   an optional body of `{"concurrency": 4, "purge_tombstones": true}`
+//! - `PUT /buckets/{name}/vbuckets/{vbid}` sets a vbucket's state, from a
+//!   body such as `{"state": "replica"}`
+//!
+//! Errors are returned as `{"error": "..."}`. There is no authentication,
+//! so [`bind`] only listens on loopback unless remote clients are allowed.
+
+use ep_engine::{
+    api::{Bucket, CompactAllOptions, Engine, EngineError},
+    vbucket::{State, Vbid},
+};
+use serde::{de::DeserializeOwned, Deserialize};
+use serde_json::{json, Value};
+use std::{
+    io::{self, BufRead, BufReader, Read, Write},
+    net::{Ipv4Addr, SocketAddr, TcpListener, TcpStream, ToSocketAddrs},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+use thiserror::Error;
+
+/// Largest request body accepted
+const MAX_BODY_SIZE: usize = 1024 * 1024;
+
+/// Longest request or header line accepted, with its line ending
+const MAX_LINE_SIZE: usize = 8 * 1024;
+
+/// Most bytes accepted for the request line and headers together
+const MAX_HEADER_SIZE: usize = 64 * 1024;
+
+/// Connections served at once, any more being turned away
+const MAX_CONNECTIONS: usize = 16;
+
+/// How long a client has to send its request
+const READ_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Listen for the admin API on the address, or on loopback if it's just a
+/// port. Addresses other than loopback are refused unless allow_remote is
+/// set, as anyone who can connect can delete buckets.
+pub fn bind(address: &str, allow_remote: bool) -> io::Result<TcpListener> {
+    let addresses: Vec<SocketAddr> = match address.parse::<u16>() {
+        Ok(port) => vec![SocketAddr::from((Ipv4Addr::LOCALHOST, port))],
+        Err(_) => address.to_socket_addrs()?.collect(),
+    };
+    if !allow_remote {
+        if let Some(remote) = addresses.iter().find(|addr| !addr.ip().is_loopback()) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("refusing to listen on {remote}, which isn't loopback"),
+            ));
+        }
+    }
+    TcpListener::bind(addresses.as_slice())
+}
+
+#[derive(Error, Debug)]
+pub enum RequestError {
+    #[error("{0}")]
+    Io(#[from] io::Error),
+    #[error("malformed request")]
+    Malformed,
+    #[error("request body over {MAX_BODY_SIZE} bytes")]
+    TooLarge,
+    #[error("request line over {MAX_LINE_SIZE} bytes")]
+    RequestLineTooLong,
+    #[error("header line over {MAX_LINE_SIZE} bytes or headers over {MAX_HEADER_SIZE} bytes")]
+    HeadersTooLarge,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Request {
+    pub method: String,
+    /// Without the query string, which is ignored
+    pub path: String,
+    pub body: Vec<u8>,
+}
+
+impl Request {
+    pub fn read(reader: &mut impl BufRead) -> Result<Self, RequestError> {
+        let mut line = String::new();
+        if !read_limited_line(reader, &mut line, MAX_LINE_SIZE)? {
+            return Err(RequestError::RequestLineTooLong);
+        }
+        let mut remaining = MAX_HEADER_SIZE - line.len();
+        let mut parts = line.split_whitespace();
+        let (Some(method), Some(target), Some(_version)) =
+            (parts.next(), parts.next(), parts.next())
+        else {
+            return Err(RequestError::Malformed);
+        };
+        let method = method.to_string();
+        let path = target.split('?').next().unwrap_or_default().to_string();
+
+        let mut content_length = 0;
+        loop {
+            if !read_limited_line(reader, &mut line, MAX_LINE_SIZE.min(remaining))? {
+                return Err(RequestError::HeadersTooLarge);
+            }
+            remaining -= line.len();
+            let header = line.trim_end();
+            if header.is_empty() {
+                break;
+            }
+            let (name, value) = header.split_once(':').ok_or(RequestError::Malformed)?;
+            if name.trim().eq_ignore_ascii_case("content-length") {
+                content_length = value.trim().parse().map_err(|_| RequestError::Malformed)?;
+            }
+        }
+        if content_length > MAX_BODY_SIZE {
+            return Err(RequestError::TooLarge);
+        }
+        let mut body = vec![0; content_length];
+        reader.read_exact(&mut body)?;
+        Ok(Self { method, path, body })
+    }
+}
+
+/// Read a line of at most limit bytes into line, false if it's longer
+fn read_limited_line(
+    reader: &mut impl BufRead,
+    line: &mut String,
+    limit: usize,
+) -> io::Result<bool> {
+    line.clear();
+    let read = reader.by_ref().take(limit as u64).read_line(line)?;
+    Ok(read < limit || line.ends_with('\n'))
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Response {
+    pub status: u16,
+    pub body: Value,
+}
+
+impl Response {
+    fn ok(body: Value) -> Self {
+        Self { status: 200, body }
+    }
+
+    fn error(status: u16, message: impl ToString) -> Self {
+        Self {
+            status,
+            body: json!({ "error": message.to_string() }),
+        }
+    }
+
+    pub fn write(&self, writer: &mut impl Write) -> io::Result<()> {
+        let reason = match self.status {
+            200 => "OK",
+            202 => "Accepted",
+            400 => "Bad Request",
+            404 => "Not Found",
+            409 => "Conflict",
+            413 => "Payload Too Large",
+            431 => "Request Header Fields Too Large",
+            503 => "Service Unavailable",
+            _ => "Internal Server Error",
+        };
+        let body = self.body.to_string();
+        write!(
+            writer,
+            "HTTP/1.1 {} {reason}\r\nContent-Type: application/json\r\n\
+             Content-Length: {}\r\nConnection: close\r\n\r\n{body}",
+            self.status,
+            body.len()
+        )?;
+        writer.flush()
+    }
+}
+
+impl From<EngineError> for Response {
+    fn from(e: EngineError) -> Self {
+        let status = match e {
+            EngineError::InvalidArguments => 400,
+            EngineError::KeyNotFound | EngineError::NotMyVbucket => 404,
+            EngineError::KeyExists | EngineError::IncompatibleDataDir => 409,
+            EngineError::TemporaryFailure => 503,
+            _ => 500,
+        };
+        Response::error(status, e)
+    }
+}
+
+/// The body of a compaction request, any field left out taking the
+/// default option
+#[derive(Debug, Default, Deserialize)]
+struct CompactBody {
+    concurrency: Option<usize>,
+    purge_tombstones: Option<bool>,
+    only_candidates: Option<bool>,
+}
+
+#[derive(Debug, Deserialize)]
+struct VbucketStateBody {
+    state: State,
+}
+
+/// Parse a JSON body, an empty one being the default
+fn parse_body<T: Default + DeserializeOwned>(body: &[u8]) -> Result<T, Response> {
+    if body.is_empty() {
+        return Ok(T::default());
+    }
+    serde_json::from_slice(body).map_err(|e| Response::error(400, e))
+}
+
+pub struct AdminApi {
+    engine: Arc<Engine>,
+    /// Connections being served
+    connections: AtomicUsize,
+}
+
+/// One of the MAX_CONNECTIONS, given back when dropped
+struct ConnectionSlot(Arc<AdminApi>);
+
+impl Drop for ConnectionSlot {
+    fn drop(&mut self) {
+        self.0.connections.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+impl AdminApi {
+    pub fn new(engine: Arc<Engine>) -> Arc<Self> {
+        Arc::new(Self {
+            engine,
+            connections: AtomicUsize::new(0),
+        })
+    }
+
+    /// Serve each connection on a thread of its own, up to MAX_CONNECTIONS
+    /// at once. Those over the limit are answered 503.
+    pub fn serve(self: &Arc<Self>, listener: TcpListener) {
+        for stream in listener.incoming() {
+            let Ok(stream) = stream else {
+                continue;
+            };
+            let slot = ConnectionSlot(self.clone());
+            if self.connections.fetch_add(1, Ordering::Relaxed) >= MAX_CONNECTIONS {
+                drop(slot);
+                let _ = Response::error(503, "too many connections").write(&mut &stream);
+                continue;
+            }
+            std::thread::spawn(move || slot.0.handle_connection(stream));
+        }
+    }
+
+    fn handle_connection(&self, stream: TcpStream) {
+        let _ = stream.set_read_timeout(Some(READ_TIMEOUT));
+        let response = match Request::read(&mut BufReader::new(&stream)) {
+            Ok(request) => self.handle(&request),
+            Err(RequestError::Io(_)) => return,
+            Err(e @ RequestError::TooLarge) => Response::error(413, e),
+            Err(e @ RequestError::HeadersTooLarge) => Response::error(431, e),
+            Err(e) => Response::error(400, e),
+        };
+        let _ = response.write(&mut &stream);
+    }
+
+    pub fn handle(&self, request: &Request) -> Response {
+        self.route(request).unwrap_or_else(|response| response)
+    }
+
+    fn route(&self, request: &Request) -> Result<Response, Response> {
+        let segments: Vec<&str> = request.path.trim_matches('/').split('/').collect();
+        let body = match (request.method.as_str(), segments.as_slice()) {
+            ("GET", ["buckets"]) => {
+                let mut names = self.engine.bucket_names();
+                names.sort();
+                json!(names)
+            }
+            ("POST", ["buckets", name]) => {
+                self.engine.bucket(name)?;
+                json!({ "name": name })
+            }
+            ("DELETE", ["buckets", name]) => {
+                self.engine.delete_bucket(name)?;
+                json!({})
+            }
+            ("GET", ["buckets", name, "stats"]) => json!(self.open_bucket(name)?.stats()),
+            ("GET", ["buckets", name, "stats", group]) => {
+                json!(self.open_bucket(name)?.stats_group(group)?)
+            }
+            ("POST", ["buckets", name, "compact"]) => return self.compact(name, &request.body),
+            ("PUT", ["buckets", name, "vbuckets", vbid]) => {
+                self.set_vbucket_state(name, vbid, &request.body)?;
+                json!({})
+            }
+            _ => {
+                return Err(Response::error(
+                    404,
+                    format!("no endpoint {} {}", request.method, request.path),
+                ))
+            }
+        };
+        Ok(Response::ok(body))
+    }
+
+    /// A bucket the engine has open. Reads don't create buckets, unlike
+    /// Engine::bucket.
+    fn open_bucket(&self, name: &str) -> Result<Bucket, Response> {
+        if !self.engine.bucket_names().iter().any(|open| open == name) {
+            return Err(Response::error(404, format!("bucket {name} isn't open")));
+        }
+        Ok(self.engine.bucket(name)?)
+    }
+
+    fn compact(&self, name: &str, body: &[u8]) -> Result<Response, Response> {
+        let bucket = self.open_bucket(name)?;
+        let body: CompactBody = parse_body(body)?;
+        let defaults = CompactAllOptions::default();
+        let options = CompactAllOptions {
+            concurrency: body.concurrency.unwrap_or(defaults.concurrency),
+            purge_tombstones: body.purge_tombstones.unwrap_or(defaults.purge_tombstones),
+            only_candidates: body.only_candidates.unwrap_or(defaults.only_candidates),
+        };
+        let progress = bucket.compact_all(options)?;
+        Ok(Response {
+            status: 202,
+            body: json!({ "vbuckets": progress.vbuckets_total() }),
+        })
+    }
+
+    fn set_vbucket_state(&self, name: &str, vbid: &str, body: &[u8]) -> Result<(), Response> {
+        let bucket = self.open_bucket(name)?;
+        let vbid: u16 = vbid
+            .parse()
+            .map_err(|_| Response::error(400, format!("invalid vbucket {vbid}")))?;
+        let body: VbucketStateBody =
+            serde_json::from_slice(body).map_err(|e| Response::error(400, e))?;
+        bucket.set_vbucket_state(Vbid::new(vbid), body.state)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use ep_engine::vbucket_hash::vbucket_for_key;
+
+    fn request(method: &str, path: &str, body: &str) -> Request {
+        Request {
+            method: method.to_string(),
+            path: path.to_string(),
+            body: body.as_bytes().to_vec(),
+        }
+    }
+
+    #[test]
+    fn test_admin_api() {
+        let dir = tempfile::tempdir().unwrap();
+        let engine = Arc::new(Engine::new(ep_engine::Config {
+            max_vbuckets: 4,
+            max_shards: 1,
+            dbname: dir.path().to_str().unwrap().to_string(),
+            ..Default::default()
+        }));
+        let api = AdminApi::new(engine.clone());
+        let handle = |method, path, body| api.handle(&request(method, path, body));
+
+        assert_eq!(handle("GET", "/buckets/x/stats", "").status, 404);
+        assert_eq!(handle("POST", "/buckets/..", "").status, 400);
+        assert_eq!(
+            handle("POST", "/buckets/x", ""),
+            Response::ok(json!({ "name": "x" }))
+        );
+        assert_eq!(handle("GET", "/buckets", ""), Response::ok(json!(["x"])));
+
+        let stats = handle("GET", "/buckets/x/stats", "");
+        assert_eq!(stats.status, 200);
+        assert!(stats.body["ep_value_bytes_stored"].is_string());
+        assert_eq!(handle("GET", "/buckets/x/stats/nonsense", "").status, 404);
+
+        let set_state = handle("PUT", "/buckets/x/vbuckets/2", r#"{"state": "replica"}"#);
+        assert_eq!(set_state.status, 200);
+        let key = (0..)
+            .map(|i| format!("key{i}"))
+            .find(|key| vbucket_for_key(key.as_bytes(), 4) == Vbid::new(2))
+            .unwrap();
+        assert_eq!(
+            engine
+                .bucket("x")
+                .unwrap()
+                .set(key.as_bytes(), b"value", 0, 0),
+            Err(EngineError::NotMyVbucket)
+        );
+        assert_eq!(
+            handle("PUT", "/buckets/x/vbuckets/9", r#"{"state": "active"}"#).status,
+            404
+        );
+        assert_eq!(
+            handle("PUT", "/buckets/x/vbuckets/2", r#"{"state": "asleep"}"#).status,
+            400
+        );
+
+        let compact = handle("POST", "/buckets/x/compact", r#"{"concurrency": 2}"#);
+        assert_eq!(compact.status, 202);
+        assert_eq!(compact.body["vbuckets"], 4);
+        let progress = handle("GET", "/buckets/x/stats/compact_all", "");
+        assert!(progress.body["ep_compact_all_state"].is_string());
+
+        assert_eq!(handle("DELETE", "/buckets/x", "").status, 200);
+        assert_eq!(handle("GET", "/buckets", ""), Response::ok(json!([])));
+        assert!(!dir.path().join("x").exists());
+        assert_eq!(handle("DELETE", "/buckets/x", "").status, 404);
+        assert_eq!(handle("PATCH", "/buckets", "").status, 404);
+    }
+
+    #[test]
+    fn test_admin_http() {
+        let raw = "PUT /buckets/x/vbuckets/1?pretty HTTP/1.1\r\nHost: localhost\r\n\
+                   content-length: 19\r\n\r\n{\"state\": \"active\"}";
+        let request = Request::read(&mut raw.as_bytes()).unwrap();
+        assert_eq!(request.method, "PUT");
+        assert_eq!(request.path, "/buckets/x/vbuckets/1");
+        assert_eq!(request.body, br#"{"state": "active"}"#);
+
+        assert!(matches!(
+            Request::read(&mut "nonsense\r\n\r\n".as_bytes()),
+            Err(RequestError::Malformed)
+        ));
+        let huge = format!(
+            "POST / HTTP/1.1\r\nContent-Length: {}\r\n\r\n",
+            MAX_BODY_SIZE + 1
+        );
+        assert!(matches!(
+            Request::read(&mut huge.as_bytes()),
+            Err(RequestError::TooLarge)
+        ));
+        let long_target = format!("GET /{} HTTP/1.1\r\n\r\n", "x".repeat(MAX_LINE_SIZE));
+        assert!(matches!(
+            Request::read(&mut long_target.as_bytes()),
+            Err(RequestError::RequestLineTooLong)
+        ));
+        let long_header = format!(
+            "GET / HTTP/1.1\r\nX-Long: {}\r\n\r\n",
+            "x".repeat(MAX_LINE_SIZE)
+        );
+        assert!(matches!(
+            Request::read(&mut long_header.as_bytes()),
+            Err(RequestError::HeadersTooLarge)
+        ));
+        // Each header fits, but not all of them
+        let many_headers = format!(
+            "GET / HTTP/1.1\r\n{}\r\n",
+            "X-Many: x\r\n".repeat(MAX_HEADER_SIZE / 10)
+        );
+        assert!(matches!(
+            Request::read(&mut many_headers.as_bytes()),
+            Err(RequestError::HeadersTooLarge)
+        ));
+
+        let mut out = Vec::new();
+        Response::error(404, "gone").write(&mut out).unwrap();
+        let out = String::from_utf8(out).unwrap();
+        assert!(out.starts_with("HTTP/1.1 404 Not Found\r\n"));
+        assert!(out.contains("Content-Length: 16\r\n"));
+        assert!(out.ends_with("\r\n\r\n{\"error\":\"gone\"}"));
+    }
+
+    #[test]
+    fn test_admin_connection_limit() {
+        let dir = tempfile::tempdir().unwrap();
+        let engine = Arc::new(Engine::new(ep_engine::Config {
+            max_vbuckets: 4,
+            max_shards: 1,
+            dbname: dir.path().to_str().unwrap().to_string(),
+            ..Default::default()
+        }));
+        let listener = bind("0", false).unwrap();
+        let address = listener.local_addr().unwrap();
+        let api = AdminApi::new(engine);
+        std::thread::spawn(move || api.serve(listener));
+
+        // Connections which haven't sent their request yet hold their slots
+        let idle: Vec<_> = (0..MAX_CONNECTIONS)
+            .map(|_| TcpStream::connect(address).unwrap())
+            .collect();
+        let mut response = String::new();
+        TcpStream::connect(address)
+            .unwrap()
+            .read_to_string(&mut response)
+            .unwrap();
+        assert!(
+            response.starts_with("HTTP/1.1 503 Service Unavailable\r\n"),
+            "{response}"
+        );
+        drop(idle);
+    }
+
+    #[test]
+    fn test_admin_bind() {
+        // A port alone listens on loopback
+        let listener = bind("0", false).unwrap();
+        assert!(listener.local_addr().unwrap().ip().is_loopback());
+        bind("127.0.0.1:0", false).unwrap();
+        assert_eq!(
+            bind("0.0.0.0:0", false).unwrap_err().kind(),
+            io::ErrorKind::InvalidInput
+        );
+        let listener = bind("0.0.0.0:0", true).unwrap();
+        assert!(listener.local_addr().unwrap().ip().is_unspecified());
+    }
+}
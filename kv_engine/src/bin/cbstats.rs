@@ -0,0 +1,323 @@
+use std::{collections::BTreeMap, net::TcpStream, process::exit};
+
+use kv_engine::{
+    connection::Connection,
+    operations::{
+        select_bucket::SelectBucketRequest,
+        stat::{StatRequest, StatResponse},
+    },
+};
+use memcached_codec::Status;
+
+const USAGE: &str = "Usage: cbstats <host:port> [group] [options]
+
+Groups:
+  all        The server's general stats (default)
+  vbucket    The state and high seqno of each vbucket in the bucket
+  dcp        The bucket's DCP connections and their streams
+  timings    Count and latency percentiles of each opcode the server handled
+  <name>     Any other group the server knows, printed as is
+
+Options:
+  --bucket <name>        Bucket to select before asking for the stats
+  --username <name>      (default: Administrator)
+  --password <password>  (default: password)
+  --json                 Print the stats as a JSON object";
+
+struct Options {
+    address: String,
+    group: String,
+    bucket: Option<String>,
+    username: String,
+    password: String,
+    json: bool,
+}
+
+fn parse_args(args: impl IntoIterator<Item = String>) -> Result<Options, String> {
+    let mut args = args.into_iter();
+    let mut positional = Vec::new();
+    let mut options = Options {
+        address: String::new(),
+        group: String::new(),
+        bucket: None,
+        username: "Administrator".to_string(),
+        password: "password".to_string(),
+        json: false,
+    };
+
+    while let Some(arg) = args.next() {
+        if !arg.starts_with("--") {
+            positional.push(arg);
+            continue;
+        }
+        if arg == "--json" {
+            options.json = true;
+            continue;
+        }
+        let value = args
+            .next()
+            .ok_or_else(|| format!("Missing value for {arg}"))?;
+        match arg.as_str() {
+            "--bucket" => options.bucket = Some(value),
+            "--username" => options.username = value,
+            "--password" => options.password = value,
+            _ => return Err(format!("Unknown option {arg}")),
+        }
+    }
+
+    let mut positional = positional.into_iter();
+    options.address = positional.next().ok_or(USAGE)?;
+    options.group = match positional.next().as_deref() {
+        None | Some("all") => String::new(),
+        Some(group) => group.to_string(),
+    };
+    if positional.next().is_some() {
+        return Err(USAGE.to_string());
+    }
+    Ok(options)
+}
+
+fn connect(options: &Options) -> Result<Connection, String> {
+    let stream = TcpStream::connect(&options.address)
+        .map_err(|e| format!("Failed to connect to {}: {e}", options.address))?;
+    let mut client = Connection::new(stream);
+    client.hello();
+    let auth = client.auth(options.username.clone(), options.password.clone());
+    if auth.status != Status::Success {
+        return Err(format!("Failed to authenticate as {}", options.username));
+    }
+    if let Some(bucket) = &options.bucket {
+        client.send(
+            SelectBucketRequest {
+                bucket: bucket.clone(),
+            }
+            .encode(),
+        );
+        if client.recv().try_status().ok() != Some(Status::Success) {
+            return Err(format!("Failed to select bucket {bucket}"));
+        }
+    }
+    Ok(client)
+}
+
+fn get_stats(client: &mut Connection, group: &str) -> Result<BTreeMap<String, String>, String> {
+    client.send(
+        StatRequest {
+            group: group.to_string(),
+        }
+        .encode(),
+    );
+    let mut stats = BTreeMap::new();
+    loop {
+        let resp = client.recv();
+        match resp.try_status() {
+            Ok(Status::Success) => {}
+            Ok(Status::KeyNotFound) => return Err(format!("Unknown stat group {group:?}")),
+            Ok(Status::NoAccess) => return Err("Not authorized to read stats".to_string()),
+            status => return Err(format!("Failed to get stats: {status:?}")),
+        }
+        match StatResponse::decode(&resp).unwrap() {
+            Some(stat) => stats.insert(stat.key, stat.value),
+            None => return Ok(stats),
+        };
+    }
+}
+
+/// One stat per line, with the values lined up
+fn print_stats(stats: &BTreeMap<String, String>) {
+    let width = stats.keys().map(|key| key.len()).max().unwrap_or(0);
+    for (key, value) in stats {
+        println!(" {key:width$}  {value}");
+    }
+}
+
+/// Each vbucket's fields, from the vb_<id> stat of its state and the
+/// vb_<id>:<field> stats
+fn group_vbuckets(stats: &BTreeMap<String, String>) -> BTreeMap<u16, BTreeMap<&str, &str>> {
+    let mut vbuckets: BTreeMap<u16, BTreeMap<&str, &str>> = BTreeMap::new();
+    for (key, value) in stats {
+        let Some(rest) = key.strip_prefix("vb_") else {
+            continue;
+        };
+        let (vbid, field) = rest.split_once(':').unwrap_or((rest, "state"));
+        if let Ok(vbid) = vbid.parse() {
+            vbuckets.entry(vbid).or_default().insert(field, value);
+        }
+    }
+    vbuckets
+}
+
+/// A row per vbucket, then how many are in each state
+fn print_vbuckets(stats: &BTreeMap<String, String>) {
+    let vbuckets = group_vbuckets(stats);
+    if vbuckets.is_empty() {
+        println!("No vbuckets");
+        return;
+    }
+
+    println!(" {:>7}  {:<8}  {:>10}", "vbucket", "state", "high_seqno");
+    for (vbid, fields) in &vbuckets {
+        let field = |name| fields.get(name).copied().unwrap_or("-");
+        println!(
+            " {vbid:>7}  {:<8}  {:>10}",
+            field("state"),
+            field("high_seqno")
+        );
+    }
+    let mut counts: BTreeMap<&str, usize> = BTreeMap::new();
+    for fields in vbuckets.values() {
+        *counts
+            .entry(fields.get("state").copied().unwrap_or("-"))
+            .or_default() += 1;
+    }
+    let counts: Vec<String> = counts
+        .into_iter()
+        .map(|(state, count)| format!("{count} {state}"))
+        .collect();
+    println!("{} vbuckets: {}", vbuckets.len(), counts.join(", "));
+}
+
+#[derive(Debug, Default, PartialEq)]
+struct DcpConnection<'a> {
+    stats: Vec<(&'a str, &'a str)>,
+    /// The fields of each vbucket's stream
+    streams: BTreeMap<u16, Vec<(&'a str, &'a str)>>,
+}
+
+/// The stats of each DCP connection, eq_dcpq:<name>:<stat>, with the
+/// stream_<vbucket>_<field> stats gathered by stream
+fn group_dcp_connections(stats: &BTreeMap<String, String>) -> BTreeMap<&str, DcpConnection<'_>> {
+    let mut connections: BTreeMap<&str, DcpConnection> = BTreeMap::new();
+    for (key, value) in stats {
+        let Some((name, stat)) = key
+            .strip_prefix("eq_dcpq:")
+            .and_then(|rest| rest.rsplit_once(':'))
+        else {
+            continue;
+        };
+        let connection = connections.entry(name).or_default();
+        let stream = stat
+            .strip_prefix("stream_")
+            .and_then(|rest| rest.split_once('_'))
+            .and_then(|(vbid, field)| Some((vbid.parse().ok()?, field)));
+        match stream {
+            Some((vbid, field)) => connection
+                .streams
+                .entry(vbid)
+                .or_default()
+                .push((field, value)),
+            None => connection.stats.push((stat, value)),
+        }
+    }
+    connections
+}
+
+/// Each DCP connection's stats, then a line per stream
+fn print_dcp(stats: &BTreeMap<String, String>) {
+    let connections = group_dcp_connections(stats);
+    if connections.is_empty() {
+        println!("No DCP connections");
+        return;
+    }
+
+    for (name, connection) in connections {
+        println!("{name}");
+        let width = connection
+            .stats
+            .iter()
+            .map(|(stat, _)| stat.len())
+            .max()
+            .unwrap_or(0);
+        for (stat, value) in connection.stats {
+            println!("   {stat:width$}  {value}");
+        }
+        for (vbid, fields) in connection.streams {
+            let fields: Vec<String> = fields
+                .into_iter()
+                .map(|(field, value)| format!("{field}={value}"))
+                .collect();
+            println!("   stream vb {vbid}: {}", fields.join(" "));
+        }
+    }
+}
+
+fn main() {
+    let options = parse_args(std::env::args().skip(1)).unwrap_or_else(|e| {
+        println!("{e}");
+        exit(1);
+    });
+    let stats = connect(&options)
+        .and_then(|mut client| get_stats(&mut client, &options.group))
+        .unwrap_or_else(|e| {
+            println!("{e}");
+            exit(1);
+        });
+
+    if options.json {
+        println!("{}", serde_json::to_string_pretty(&stats).unwrap());
+        return;
+    }
+    match options.group.as_str() {
+        "vbucket" => print_vbuckets(&stats),
+        "dcp" => print_dcp(&stats),
+        _ => print_stats(&stats),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn stats(stats: &[(&str, &str)]) -> BTreeMap<String, String> {
+        stats
+            .iter()
+            .map(|(key, value)| (key.to_string(), value.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn test_cbstats() {
+        let args = |args: &[&str]| parse_args(args.iter().map(|arg| arg.to_string()));
+        let options = args(&["localhost:11210", "vbucket", "--bucket", "b", "--json"]).unwrap();
+        assert_eq!(options.address, "localhost:11210");
+        assert_eq!(options.group, "vbucket");
+        assert_eq!(options.bucket.as_deref(), Some("b"));
+        assert!(options.json);
+        assert_eq!(args(&["localhost:11210", "all"]).unwrap().group, "");
+        assert!(args(&[]).is_err());
+        assert!(args(&["localhost:11210", "all", "dcp"]).is_err());
+        assert!(args(&["localhost:11210", "--bucket"]).is_err());
+
+        let vbucket_stats = stats(&[
+            ("vb_0", "active"),
+            ("vb_0:high_seqno", "5"),
+            ("vb_10", "replica"),
+            ("vb_x", "dead"),
+            ("mem_used", "100"),
+        ]);
+        let vbuckets = group_vbuckets(&vbucket_stats);
+        assert_eq!(vbuckets.keys().copied().collect::<Vec<_>>(), [0, 10]);
+        assert_eq!(vbuckets[&0]["state"], "active");
+        assert_eq!(vbuckets[&0]["high_seqno"], "5");
+        assert_eq!(vbuckets[&10]["state"], "replica");
+
+        let dcp_stats = stats(&[
+            ("eq_dcpq:replication:a:items_sent", "3"),
+            ("eq_dcpq:replication:a:stream_1_state", "in-memory"),
+            ("eq_dcpq:replication:a:stream_1_last_sent_seqno", "7"),
+            ("eq_dcpq:replication:a:stream_x_state", "dead"),
+            ("ep_dcp_count", "1"),
+        ]);
+        let connections = group_dcp_connections(&dcp_stats);
+        assert_eq!(connections.len(), 1);
+        let connection = &connections["replication:a"];
+        assert_eq!(
+            connection.stats,
+            [("items_sent", "3"), ("stream_x_state", "dead")]
+        );
+        assert_eq!(
+            connection.streams[&1],
+            [("last_sent_seqno", "7"), ("state", "in-memory")]
+        );
+    }
+}
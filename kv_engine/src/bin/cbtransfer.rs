@@ -0,0 +1,552 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    net::TcpStream,
+    process::exit,
+    sync::Arc,
+};
+
+use bytes::Bytes;
+use ep_engine::{
+    io_throttle::IOThrottle,
+    item::{Item, DEFAULT_MAX_ITEM_SIZE, DEFAULT_MAX_KEY_SIZE},
+    kv_store::{
+        CouchKVStore, CouchKVStoreConfig, KVStore, ScanErrorPolicy, VBucketBulkLoad, ValueFilter,
+    },
+    vbucket::{State, VBucketState, Vbid},
+    vbucket_hash::vbucket_for_stored_key,
+};
+use kv_engine::{
+    connection::Connection,
+    operations::{
+        select_bucket::SelectBucketRequest,
+        with_meta::{DelWithMetaRequest, SetWithMetaRequest},
+    },
+};
+use memcached_codec::{DataType, Status};
+
+const USAGE: &str =
+    "Usage: cbtransfer <source dir> <destination dir | couchbase://host:port> [options]
+
+Options:
+  --bucket <name>             Bucket to write to on the server (default: default)
+  --username <name>           (default: Administrator)
+  --password <password>       (default: password)
+  --vbuckets <count>          Number of vbuckets in the source (default: 1024)
+  --vbucket-map <src=dst,..>  Only transfer the listed vbuckets, into the given vbuckets
+  --dest-vbuckets <count>     Rehash keys for a destination with this many vbuckets
+  --rate <items/sec>          Limit how fast items are written
+  --backup-cursor <name>      Only transfer what changed since the last run with this
+                              cursor, then record it in the source
+  --on-corrupt <abort|skip>   Stop at a corrupt item, or skip it and report it at the
+                              end (default: abort)";
+
+/// Items written to a destination directory per commit
+const BATCH_SIZE: usize = 1000;
+
+struct Options {
+    source: String,
+    destination: String,
+    bucket: String,
+    username: String,
+    password: String,
+    source_vbuckets: u16,
+    vbucket_map: Option<HashMap<u16, u16>>,
+    dest_vbuckets: Option<u16>,
+    rate: Option<u32>,
+    backup_cursor: Option<String>,
+    on_corrupt: ScanErrorPolicy,
+}
+
+fn parse_args(args: impl IntoIterator<Item = String>) -> Result<Options, String> {
+    let mut args = args.into_iter();
+    let mut positional = Vec::new();
+    let mut options = Options {
+        source: String::new(),
+        destination: String::new(),
+        bucket: "default".to_string(),
+        username: "Administrator".to_string(),
+        password: "password".to_string(),
+        source_vbuckets: 1024,
+        vbucket_map: None,
+        dest_vbuckets: None,
+        rate: None,
+        backup_cursor: None,
+        on_corrupt: ScanErrorPolicy::Abort,
+    };
+
+    while let Some(arg) = args.next() {
+        if !arg.starts_with("--") {
+            positional.push(arg);
+            continue;
+        }
+        let value = args
+            .next()
+            .ok_or_else(|| format!("Missing value for {arg}"))?;
+        let invalid = || format!("Invalid value for {arg}: {value}");
+        match arg.as_str() {
+            "--bucket" => options.bucket = value,
+            "--username" => options.username = value,
+            "--password" => options.password = value,
+            "--vbuckets" => options.source_vbuckets = value.parse().map_err(|_| invalid())?,
+            "--vbucket-map" => {
+                let mut map = HashMap::new();
+                for pair in value.split(',') {
+                    let (src, dst) = pair.split_once('=').ok_or_else(invalid)?;
+                    let src = src.trim().parse().map_err(|_| invalid())?;
+                    let dst = dst.trim().parse().map_err(|_| invalid())?;
+                    map.insert(src, dst);
+                }
+                options.vbucket_map = Some(map);
+            }
+            "--dest-vbuckets" => {
+                let count: u16 = value.parse().map_err(|_| invalid())?;
+                if !count.is_power_of_two() {
+                    return Err(format!("{arg} must be a power of two"));
+                }
+                options.dest_vbuckets = Some(count);
+            }
+            "--rate" => options.rate = Some(value.parse().map_err(|_| invalid())?),
+            "--backup-cursor" => options.backup_cursor = Some(value),
+            "--on-corrupt" => {
+                options.on_corrupt = match value.as_str() {
+                    "abort" => ScanErrorPolicy::Abort,
+                    "skip" => ScanErrorPolicy::SkipAndReport,
+                    _ => return Err(invalid()),
+                }
+            }
+            _ => return Err(format!("Unknown option {arg}")),
+        }
+    }
+
+    if positional.len() != 2 {
+        return Err(USAGE.to_string());
+    }
+    if options.vbucket_map.is_some() && options.dest_vbuckets.is_some() {
+        return Err("--vbucket-map and --dest-vbuckets can't be used together".to_string());
+    }
+    options.destination = positional.pop().unwrap();
+    options.source = positional.pop().unwrap();
+    Ok(options)
+}
+
+impl Options {
+    /// The vbucket the item is written to in the destination, None if the
+    /// source vbucket isn't being transferred
+    fn map_vbucket(&self, vbid: u16, key: &[u8]) -> Option<u16> {
+        if let Some(count) = self.dest_vbuckets {
+            return Some(vbucket_for_stored_key(key, count).into());
+        }
+        match &self.vbucket_map {
+            Some(map) => map.get(&vbid).copied(),
+            None => Some(vbid),
+        }
+    }
+
+    fn dest_vbuckets(&self) -> u16 {
+        match (&self.vbucket_map, self.dest_vbuckets) {
+            (_, Some(count)) => count,
+            (Some(map), None) => map
+                .values()
+                .map(|&vbid| vbid + 1)
+                .max()
+                .unwrap_or(0)
+                .max(self.source_vbuckets),
+            (None, None) => self.source_vbuckets,
+        }
+    }
+}
+
+trait Destination {
+    /// Write the item, returning false if the destination rejected it
+    fn write(&mut self, vbid: u16, item: Item) -> bool;
+
+    /// Called after each source vbucket is transferred
+    fn end_source_vbucket(&mut self) {}
+
+    fn finish(&mut self);
+}
+
+/// Writes into another data directory. Items get new seqnos in the
+/// destination vbucket, keeping their CAS and revision. Vbuckets new to the
+/// directory are bulk loaded, others are written in batches.
+struct DirDestination {
+    store: CouchKVStore,
+    batches: HashMap<u16, Vec<Arc<Item>>>,
+    /// Bulk loads of the vbuckets being written, finished at the end of
+    /// each source vbucket
+    loads: HashMap<u16, VBucketBulkLoad>,
+    /// Off when rehashing, as each source vbucket then writes to every
+    /// destination vbucket and a load holds its file open
+    bulk_load: bool,
+    states: HashMap<u16, VBucketState>,
+}
+
+impl DirDestination {
+    fn new(dir: &str, max_vbuckets: u16, bulk_load: bool) -> Self {
+        std::fs::create_dir_all(dir).unwrap();
+        Self {
+            store: CouchKVStore::new(CouchKVStoreConfig {
+                max_vbuckets,
+                db_name: dir.to_string(),
+                data_paths: Vec::new(),
+                max_shards: 1,
+                shard_id: 0,
+                history_retention: false,
+                max_key_size: DEFAULT_MAX_KEY_SIZE,
+                max_item_size: DEFAULT_MAX_ITEM_SIZE,
+                pitr_max_history_age: None,
+                sync_policy: couchstore::SyncPolicy::EveryCommit,
+                dsync: false,
+                btree_tuning: couchstore::BtreeTuning::default(),
+                front_coded_keys: false,
+                compaction_direct_io: false,
+            }),
+            batches: HashMap::new(),
+            loads: HashMap::new(),
+            bulk_load,
+            states: HashMap::new(),
+        }
+    }
+
+    fn finish_loads(&mut self) {
+        for (vbid, load) in self.loads.drain() {
+            if let Err(e) = self.store.finish_bulk_load(load, &self.states[&vbid]) {
+                println!("Failed to write vb {vbid}: {e}");
+                exit(1);
+            }
+        }
+    }
+
+    fn commit(&mut self, vbid: u16) {
+        let Some(items) = self.batches.remove(&vbid) else {
+            return;
+        };
+        let state = &self.states[&vbid];
+        if let Err(e) = self.store.commit(Vbid::new(vbid), &items, state) {
+            println!("Failed to write vb {vbid}: {e}");
+            exit(1);
+        }
+    }
+}
+
+impl Destination for DirDestination {
+    fn write(&mut self, vbid: u16, mut item: Item) -> bool {
+        let store = &self.store;
+        let (bulk_load, loads) = (self.bulk_load, &mut self.loads);
+        let state = self.states.entry(vbid).or_insert_with(|| {
+            store
+                .get_cached_vb_state(Vbid::new(vbid))
+                .unwrap_or_else(|| {
+                    if bulk_load {
+                        match store.begin_bulk_load(Vbid::new(vbid)) {
+                            Ok(load) => {
+                                loads.insert(vbid, load);
+                            }
+                            Err(e) => println!(
+                                "Failed to bulk load vb {vbid}, writing it in batches: {e}"
+                            ),
+                        }
+                    }
+                    VBucketState::new(State::Active)
+                })
+        });
+        state.high_seqno += 1;
+        state.snap_start = state.high_seqno as u64;
+        state.snap_end = state.high_seqno as u64;
+        state.max_cas = state.max_cas.max(item.cas);
+        item.by_seqno = state.high_seqno as u64;
+
+        if let Some(load) = self.loads.get_mut(&vbid) {
+            if let Err(e) = load.add(&item) {
+                println!("Failed to write vb {vbid}: {e}");
+                exit(1);
+            }
+            return true;
+        }
+        let batch = self.batches.entry(vbid).or_default();
+        batch.push(Arc::new(item));
+        if batch.len() >= BATCH_SIZE {
+            self.commit(vbid);
+        }
+        true
+    }
+
+    fn end_source_vbucket(&mut self) {
+        self.finish_loads();
+    }
+
+    fn finish(&mut self) {
+        self.finish_loads();
+        let vbids: Vec<u16> = self.batches.keys().copied().collect();
+        for vbid in vbids {
+            self.commit(vbid);
+        }
+    }
+}
+
+/// Writes to a running server with the with-meta operations, which keep
+/// the items' CAS and revision
+struct ServerDestination {
+    client: Connection,
+}
+
+impl ServerDestination {
+    fn new(address: &str, options: &Options) -> Self {
+        let stream = TcpStream::connect(address).unwrap_or_else(|e| {
+            println!("Failed to connect to {address}: {e}");
+            exit(1);
+        });
+        let mut client = Connection::new(stream);
+        client.hello();
+        client.auth(options.username.clone(), options.password.clone());
+        client.send(
+            SelectBucketRequest {
+                bucket: options.bucket.clone(),
+            }
+            .encode(),
+        );
+        let resp = client.recv();
+        if resp.try_status().ok() != Some(Status::Success) {
+            println!("Failed to select bucket {}", options.bucket);
+            exit(1);
+        }
+        Self { client }
+    }
+}
+
+impl Destination for ServerDestination {
+    fn write(&mut self, vbid: u16, item: Item) -> bool {
+        let key = Bytes::from(item.key);
+        let message = match item.value {
+            Some(value) => SetWithMetaRequest {
+                key,
+                value: Bytes::from(value),
+                vbucket: vbid,
+                flags: item.flags,
+                expiry: item.expiry_time,
+                rev_seqno: item.rev_seqno,
+                cas: item.cas.into(),
+                // Scanned decompressed, so never snappy
+                data_type: DataType::from_bits_truncate(item.datatype.bits()),
+            }
+            .encode(),
+            None => DelWithMetaRequest {
+                key,
+                vbucket: vbid,
+                delete_time: item.expiry_time,
+                rev_seqno: item.rev_seqno,
+                cas: item.cas.into(),
+            }
+            .encode(),
+        };
+        self.client.send(message);
+        match self.client.recv().try_status() {
+            Ok(Status::Success) => true,
+            // The destination already has a newer revision
+            Ok(Status::KeyExists) => false,
+            status => {
+                println!("Failed to write item to vbucket {vbid}: {status:?}");
+                false
+            }
+        }
+    }
+
+    fn finish(&mut self) {}
+}
+
+fn read_backup_cursors(source: &CouchKVStore, vbid: Vbid) -> BTreeMap<String, u64> {
+    source.get_backup_cursors(vbid).unwrap_or_else(|e| {
+        println!("Failed to read the backup cursors of {vbid}: {e}");
+        exit(1);
+    })
+}
+
+fn main() {
+    let options = parse_args(std::env::args().skip(1)).unwrap_or_else(|e| {
+        println!("{e}");
+        exit(1);
+    });
+
+    let source = CouchKVStore::new(CouchKVStoreConfig {
+        max_vbuckets: options.source_vbuckets,
+        db_name: options.source.clone(),
+        data_paths: Vec::new(),
+        max_shards: 1,
+        shard_id: 0,
+        history_retention: false,
+        max_key_size: DEFAULT_MAX_KEY_SIZE,
+        max_item_size: DEFAULT_MAX_ITEM_SIZE,
+        pitr_max_history_age: None,
+        sync_policy: couchstore::SyncPolicy::EveryCommit,
+        dsync: false,
+        btree_tuning: couchstore::BtreeTuning::default(),
+        front_coded_keys: false,
+        compaction_direct_io: false,
+    });
+    let mut destination: Box<dyn Destination> =
+        match options.destination.strip_prefix("couchbase://") {
+            Some(address) => Box::new(ServerDestination::new(address, &options)),
+            None => Box::new(DirDestination::new(
+                &options.destination,
+                options.dest_vbuckets(),
+                options.dest_vbuckets.is_none(),
+            )),
+        };
+    let throttle = IOThrottle::new(0, options.rate.unwrap_or(0) as u64);
+
+    let mut transferred = 0u64;
+    let mut skipped = 0u64;
+    let mut corrupt = Vec::new();
+    let mut backed_up = Vec::new();
+    for (vbid, state) in source.list_persisted_vbuckets().into_iter().enumerate() {
+        let Some(state) = state else {
+            continue;
+        };
+        let vbid = vbid as u16;
+        // Incremental from the cursor's last run, if it has one
+        let start_seqno = options.backup_cursor.as_ref().map_or(0, |name| {
+            read_backup_cursors(&source, Vbid::new(vbid))
+                .get(name)
+                .map_or(0, |seqno| seqno + 1)
+        });
+        backed_up.push((vbid, state.high_seqno as u64));
+        if start_seqno > state.high_seqno as u64 {
+            println!("Vbucket {vbid} is unchanged");
+            continue;
+        }
+        let result = source.scan(
+            Vbid::new(vbid),
+            start_seqno,
+            ValueFilter::ValuesDecompressed,
+            options.on_corrupt,
+            &mut |item| {
+                let Some(dest_vbid) = options.map_vbucket(vbid, &item.key) else {
+                    return;
+                };
+                throttle.acquire(0);
+                if destination.write(dest_vbid, item) {
+                    transferred += 1;
+                } else {
+                    skipped += 1;
+                }
+            },
+        );
+        if result.aborted {
+            println!(
+                "Failed to read vbucket {vbid}: {}",
+                result.errors.join(", ")
+            );
+            exit(1);
+        }
+        corrupt.extend(
+            result
+                .errors
+                .into_iter()
+                .map(|error| format!("vbucket {vbid} {error}")),
+        );
+        destination.end_source_vbucket();
+        println!("Transferred vbucket {vbid}");
+    }
+    destination.finish();
+
+    // Only once everything is written, so a failed run is repeated in full
+    if let Some(name) = &options.backup_cursor {
+        for (vbid, high_seqno) in backed_up {
+            let vbid = Vbid::new(vbid);
+            let mut cursors = read_backup_cursors(&source, vbid);
+            cursors.insert(name.clone(), high_seqno);
+            if let Err(e) = source.set_backup_cursors(vbid, &cursors) {
+                println!("Failed to update the backup cursor of {vbid}: {e}");
+                exit(1);
+            }
+        }
+    }
+
+    println!("Transferred {transferred} items, skipped {skipped}");
+    if !corrupt.is_empty() {
+        println!("Skipped {} corrupt items:", corrupt.len());
+        for item in corrupt {
+            println!("  {item}");
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use ep_engine::item::{Datatype, DeleteSource};
+
+    fn args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|arg| arg.to_string()).collect()
+    }
+
+    fn item(key: &str, cas: u64) -> Item {
+        Item {
+            key: key.as_bytes().to_vec(),
+            value: Some(b"value".to_vec()),
+            cas,
+            expiry_time: 0,
+            flags: 0,
+            by_seqno: 100,
+            rev_seqno: 7,
+            delete_source: DeleteSource::Explicit,
+            datatype: Datatype::empty(),
+        }
+    }
+
+    #[test]
+    fn test_transfer_to_dir() {
+        let options = parse_args(args(&[
+            "src",
+            "dst",
+            "--vbuckets",
+            "4",
+            "--vbucket-map",
+            "0=2, 1=5",
+        ]))
+        .unwrap();
+        assert_eq!(
+            (options.source.as_str(), options.destination.as_str()),
+            ("src", "dst")
+        );
+        assert_eq!(options.map_vbucket(0, b"key"), Some(2));
+        assert_eq!(options.map_vbucket(2, b"key"), None);
+        assert_eq!(options.dest_vbuckets(), 6);
+        assert!(parse_args(args(&["src", "dst", "--dest-vbuckets", "3"])).is_err());
+        assert!(parse_args(args(&[
+            "src",
+            "dst",
+            "--vbucket-map",
+            "0=1",
+            "--dest-vbuckets",
+            "4"
+        ]))
+        .is_err());
+        assert!(parse_args(args(&["src"])).is_err());
+
+        // A new vbucket is bulk loaded, then written in batches, with new
+        // seqnos but the items' CAS and revision
+        let dir = tempfile::tempdir().unwrap();
+        let mut destination = DirDestination::new(dir.path().to_str().unwrap(), 4, true);
+        assert!(destination.write(2, item("a", 10)));
+        assert!(destination.write(2, item("b", 20)));
+        destination.end_source_vbucket();
+        assert!(destination.write(2, item("c", 5)));
+        destination.finish();
+
+        let vbid = Vbid::new(2);
+        let state = destination.store.get_cached_vb_state(vbid).unwrap();
+        assert_eq!(state.high_seqno, 3);
+        assert_eq!(state.max_cas, 20);
+        let keys: Vec<Vec<u8>> = ["a", "b", "c"].map(|key| key.as_bytes().to_vec()).into();
+        let items = destination.store.get_multi(vbid, &keys).unwrap();
+        let read: Vec<(u64, u64, u64)> = items
+            .into_iter()
+            .map(|item| {
+                let item = item.unwrap();
+                (item.by_seqno, item.cas, item.rev_seqno)
+            })
+            .collect();
+        assert_eq!(read, [(1, 10, 7), (2, 20, 7), (3, 5, 7)]);
+    }
+}
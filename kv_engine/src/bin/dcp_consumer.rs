@@ -1,9 +1,14 @@
 use std::net::TcpStream;
 
+use memcached_codec::Opcode;
+use tracing::info;
+
 use kv_engine::{
     connection::Connection,
     operations::{
-        dcp::{DcpOpenConnectionRequest, DcpOpenFlag, DcpStreamAddFlag, DcpStreamRequest},
+        dcp::{
+            DcpDeletion, DcpOpenConnectionRequest, DcpOpenFlag, DcpStreamAddFlag, DcpStreamRequest,
+        },
         select_bucket::SelectBucketRequest,
     },
 };
@@ -23,12 +28,13 @@ fn main() {
     client.send(
         DcpOpenConnectionRequest {
             stream_name: "test".to_string(),
-            flags: DcpOpenFlag::PRODUCER,
+            flags: DcpOpenFlag::PRODUCER | DcpOpenFlag::INCLUDE_DELETE_TIMES,
         }
         .encode(),
     );
     client.send_dcp_control("enable_noop".to_string(), "true".to_string());
     client.send_dcp_control("set_noop_interval".to_string(), "180".to_string());
+    client.send_dcp_control("enable_expiry_opcode".to_string(), "true".to_string());
     client.send(
         DcpStreamRequest {
             vbucket: 0,
@@ -38,10 +44,14 @@ fn main() {
             vb_uuid: 0,
             snap_start_seqno: 0,
             snap_end_seqno: 0,
+            filter: None,
         }
         .encode(),
     );
     loop {
-        client.recv();
+        let message = client.recv();
+        if matches!(message.opcode, Opcode::DcpDeletion | Opcode::DcpExpiration) {
+            info!("Deleted: {:?}", DcpDeletion::decode(&message).unwrap());
+        }
     }
 }
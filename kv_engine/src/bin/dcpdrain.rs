@@ -0,0 +1,374 @@
+//! Drains a bucket over DCP in the style of dcpdrain, for measuring
+//! replication throughput. A stream is opened on every vbucket up to its
+//! current high seqno, and once they have all ended the items and bytes
+//! each one sent are reported with their rates. The streams are served
+//! by the engine's producer directly given a data directory, or over MCBP
+//! given a couchbase:// address, which needs a server that speaks DCP.
+
+use std::{
+    collections::BTreeMap,
+    net::TcpStream,
+    process::exit,
+    time::{Duration, Instant},
+};
+
+use ep_engine::{
+    api::{
+        DcpOpenFlags, DcpResponse, EndStreamStatus, Engine, EngineConfig, StreamRequest,
+        StreamRequestFlags,
+    },
+    vbucket::Vbid,
+};
+use kv_engine::{
+    connection::Connection,
+    operations::{
+        dcp::{DcpOpenConnectionRequest, DcpOpenFlag, DcpStreamAddFlag, DcpStreamRequest},
+        select_bucket::SelectBucketRequest,
+    },
+};
+use memcached_codec::{McbpMessage, Opcode, Status};
+
+const USAGE: &str = "Usage: dcpdrain <data dir | couchbase://host:port> [options]
+
+Options:
+  --bucket <name>         (default: default)
+  --username <name>       (default: Administrator)
+  --password <password>   (default: password)
+  --name <name>           Name of the DCP connection (default: dcpdrain)
+  --vbuckets <count>      Number of vbuckets to stream (default: 1024)
+  --no-value              Stream mutations without their values";
+
+/// Bytes of an MCBP header
+const HEADER_SIZE: usize = 24;
+
+struct Options {
+    target: String,
+    bucket: String,
+    username: String,
+    password: String,
+    name: String,
+    vbuckets: u16,
+    no_value: bool,
+}
+
+fn parse_args() -> Result<Options, String> {
+    let mut args = std::env::args().skip(1);
+    let mut positional = Vec::new();
+    let mut options = Options {
+        target: String::new(),
+        bucket: "default".to_string(),
+        username: "Administrator".to_string(),
+        password: "password".to_string(),
+        name: "dcpdrain".to_string(),
+        vbuckets: 1024,
+        no_value: false,
+    };
+
+    while let Some(arg) = args.next() {
+        if !arg.starts_with("--") {
+            positional.push(arg);
+            continue;
+        }
+        if arg == "--no-value" {
+            options.no_value = true;
+            continue;
+        }
+        let value = args
+            .next()
+            .ok_or_else(|| format!("Missing value for {arg}"))?;
+        let invalid = || format!("Invalid value for {arg}: {value}");
+        match arg.as_str() {
+            "--bucket" => options.bucket = value,
+            "--username" => options.username = value,
+            "--password" => options.password = value,
+            "--name" => options.name = value,
+            "--vbuckets" => options.vbuckets = value.parse().map_err(|_| invalid())?,
+            _ => return Err(format!("Unknown option {arg}")),
+        }
+    }
+
+    if positional.len() != 1 {
+        return Err(USAGE.to_string());
+    }
+    if options.vbuckets == 0 {
+        return Err("--vbuckets must be at least 1".to_string());
+    }
+    options.target = positional.pop().unwrap();
+    Ok(options)
+}
+
+/// What one vbucket's stream has sent
+struct VbucketStats {
+    items: u64,
+    bytes: u64,
+    requested: Instant,
+    /// From the stream request to the stream end, once it has ended
+    elapsed: Option<Duration>,
+}
+
+/// The streams' progress, by vbucket
+#[derive(Default)]
+struct Drain {
+    streams: BTreeMap<u16, VbucketStats>,
+    /// Vbuckets whose stream request failed, and why
+    skipped: BTreeMap<u16, String>,
+    /// When the first stream was requested
+    started: Option<Instant>,
+    /// From the first stream request until the last stream ended
+    elapsed: Duration,
+}
+
+impl Drain {
+    fn requested(&mut self, vbid: u16) {
+        self.started.get_or_insert_with(Instant::now);
+        self.streams.insert(
+            vbid,
+            VbucketStats {
+                items: 0,
+                bytes: 0,
+                requested: Instant::now(),
+                elapsed: None,
+            },
+        );
+    }
+
+    fn skip(&mut self, vbid: u16, reason: String) {
+        self.streams.remove(&vbid);
+        self.skipped.insert(vbid, reason);
+    }
+
+    /// Count a message the stream sent, and whether it carries an item
+    fn record(&mut self, vbid: u16, bytes: usize, item: bool) {
+        if let Some(stats) = self.streams.get_mut(&vbid) {
+            stats.bytes += bytes as u64;
+            stats.items += u64::from(item);
+        }
+    }
+
+    fn end(&mut self, vbid: u16) {
+        if let Some(stats) = self.streams.get_mut(&vbid) {
+            stats
+                .elapsed
+                .get_or_insert_with(|| stats.requested.elapsed());
+        }
+        if self.open_streams() == 0 {
+            self.elapsed = self
+                .started
+                .map_or(Duration::ZERO, |started| started.elapsed());
+        }
+    }
+
+    fn open_streams(&self) -> usize {
+        self.streams
+            .values()
+            .filter(|stats| stats.elapsed.is_none())
+            .count()
+    }
+
+    fn report(&self) {
+        let elapsed = self.elapsed;
+        println!(
+            "{:>6} {:>12} {:>14} {:>12} {:>14}",
+            "vb", "items", "bytes", "items/s", "bytes/s"
+        );
+        let rate = |count: u64, elapsed: Duration| match elapsed.as_secs_f64() {
+            0.0 => 0.0,
+            secs => count as f64 / secs,
+        };
+        for (vbid, stats) in &self.streams {
+            let vb_elapsed = stats.elapsed.unwrap_or(elapsed);
+            println!(
+                "{vbid:>6} {:>12} {:>14} {:>12.0} {:>14.0}",
+                stats.items,
+                stats.bytes,
+                rate(stats.items, vb_elapsed),
+                rate(stats.bytes, vb_elapsed)
+            );
+        }
+        for (vbid, reason) in &self.skipped {
+            println!("{vbid:>6} skipped: {reason}");
+        }
+        let items = self.streams.values().map(|stats| stats.items).sum();
+        let bytes = self.streams.values().map(|stats| stats.bytes).sum();
+        println!(
+            "Total: {items} items, {bytes} bytes from {} vbuckets in {elapsed:?}, \
+             {:.0} items/s, {:.0} bytes/s",
+            self.streams.len(),
+            rate(items, elapsed),
+            rate(bytes, elapsed)
+        );
+    }
+}
+
+/// Stream every vbucket through the engine's producer
+fn drain_engine(options: &Options) -> Result<Drain, String> {
+    let engine = Engine::new(EngineConfig {
+        dbname: options.target.clone(),
+        ..Default::default()
+    });
+    let bucket = engine
+        .bucket(&options.bucket)
+        .map_err(|e| format!("Failed to open bucket {}: {e}", options.bucket))?;
+    let mut flags = DcpOpenFlags::empty();
+    if options.no_value {
+        flags |= DcpOpenFlags::NO_VALUE;
+    }
+    let producer = bucket.dcp_producer(&options.name, flags);
+
+    let mut drain = Drain::default();
+    for vbid in 0..options.vbuckets {
+        drain.requested(vbid);
+        let request = StreamRequest {
+            flags: StreamRequestFlags::LATEST,
+            start_seqno: 0,
+            end_seqno: 0,
+            vb_uuid: 0,
+            snap_start_seqno: 0,
+            snap_end_seqno: 0,
+        };
+        if let Err(e) = producer.stream_request(vbid.into(), Vbid::new(vbid), request, None) {
+            drain.skip(vbid, e.to_string());
+        }
+    }
+
+    while drain.open_streams() > 0 {
+        let response = match producer.step() {
+            Ok(Some(response)) => response,
+            Ok(None) => {
+                std::thread::sleep(Duration::from_millis(1));
+                continue;
+            }
+            Err(e) => return Err(format!("Failed to step the producer: {e}")),
+        };
+        let Some(vbid) = response.vbid() else {
+            continue;
+        };
+        let vbid = u16::from(vbid);
+        let item = matches!(
+            response,
+            DcpResponse::Mutation { .. }
+                | DcpResponse::Deletion { .. }
+                | DcpResponse::Expiration { .. }
+        );
+        drain.record(vbid, response.message_size(), item);
+        if let DcpResponse::StreamEnd { status, .. } = response {
+            drain.end(vbid);
+            if status != EndStreamStatus::Ok {
+                println!("Stream for vb {vbid} ended early: {status:?}");
+            }
+        }
+    }
+    engine.shutdown();
+    Ok(drain)
+}
+
+fn message_size(message: &McbpMessage) -> usize {
+    HEADER_SIZE
+        + message.framing_extras.len()
+        + message.extras.len()
+        + message.key.len()
+        + message.value.len()
+}
+
+/// Stream every vbucket from a server over MCBP
+fn drain_server(address: &str, options: &Options) -> Result<Drain, String> {
+    let stream =
+        TcpStream::connect(address).map_err(|e| format!("Failed to connect to {address}: {e}"))?;
+    let mut client = Connection::new(stream);
+    client.hello();
+    let auth = client.auth(options.username.clone(), options.password.clone());
+    if auth.status != Status::Success {
+        return Err(format!("Failed to authenticate as {}", options.username));
+    }
+    client.send(
+        SelectBucketRequest {
+            bucket: options.bucket.clone(),
+        }
+        .encode(),
+    );
+    if client.recv().try_status().ok() != Some(Status::Success) {
+        return Err(format!("Failed to select bucket {}", options.bucket));
+    }
+    let mut flags = DcpOpenFlag::PRODUCER;
+    if options.no_value {
+        flags |= DcpOpenFlag::NO_VALUE;
+    }
+    client.send(
+        DcpOpenConnectionRequest {
+            stream_name: options.name.clone(),
+            flags,
+        }
+        .encode(),
+    );
+    match client.recv().try_status() {
+        Ok(Status::Success) => {}
+        status => return Err(format!("Failed to open the DCP connection: {status:?}")),
+    }
+
+    // Each stream's messages carry the opaque of its request, the vbucket
+    let mut drain = Drain::default();
+    for vbid in 0..options.vbuckets {
+        let mut request = DcpStreamRequest {
+            vbucket: vbid,
+            flags: DcpStreamAddFlag::LATEST,
+            start_seqno: 0,
+            end_seqno: 0,
+            vb_uuid: 0,
+            snap_start_seqno: 0,
+            snap_end_seqno: 0,
+            filter: None,
+        }
+        .encode();
+        request.opaque = vbid.into();
+        drain.requested(vbid);
+        client.send(request);
+    }
+
+    while drain.open_streams() > 0 {
+        let message = client.try_recv().ok_or_else(|| {
+            format!(
+                "The server closed the connection with {} streams open",
+                drain.open_streams()
+            )
+        })?;
+        let vbid = message.opaque as u16;
+        match message.opcode {
+            Opcode::DcpStreamRequest => match message.try_status() {
+                Ok(Status::Success) => {}
+                status => drain.skip(vbid, format!("{status:?}")),
+            },
+            Opcode::DcpMutation | Opcode::DcpDeletion | Opcode::DcpExpiration => {
+                drain.record(vbid, message_size(&message), true)
+            }
+            Opcode::DcpSnapshotMarker | Opcode::DcpSystemEvent => {
+                drain.record(vbid, message_size(&message), false)
+            }
+            Opcode::DcpStreamEnd => {
+                drain.record(vbid, message_size(&message), false);
+                drain.end(vbid);
+            }
+            _ => {}
+        }
+    }
+    Ok(drain)
+}
+
+fn main() {
+    let options = parse_args().unwrap_or_else(|e| {
+        println!("{e}");
+        exit(1);
+    });
+
+    println!("Draining {} vbuckets", options.vbuckets);
+    let drain = match options.target.strip_prefix("couchbase://") {
+        Some(address) => drain_server(address, &options),
+        None => drain_engine(&options),
+    };
+    match drain {
+        Ok(drain) => drain.report(),
+        Err(e) => {
+            println!("{e}");
+            exit(1);
+        }
+    }
+}
@@ -1,48 +1,225 @@
 use bytes::Bytes;
-use couchstore::{DBOpenOptions, Db, OpenOptions};
+use couchstore::{DBOpenOptions, Db, DbFileKind, DbNameLayout, OpenOptions};
+use ep_engine::{
+    api::Engine,
+    audit::{AuditEvent, AuditLog},
+    vbucket::{self, VBucketState},
+};
 use kv_engine::{
+    admin::{self, AdminApi},
     connection::Connection,
+    manifest::Manifest,
+    network::{ConnectionLimiter, NetworkConfig},
     operations::{
         cluster_config::{ClusterConfig, GetClusterConfigResponse, Node, VBucketServerMap},
         get::{GetRequest, GetResponse},
         hello::HelloResponse,
+        sasl_auth::SaslAuthRequest,
         select_bucket::{SelectBucketRequest, SelectBucketResponse},
         set::{SetRequest, SetResponse},
+        stat::{StatRequest, StatResponse},
     },
+    rbac::{Privilege, Rbac},
+    timings::{ConnectionTimings, Timings, AGGREGATION_INTERVAL},
 };
 use memcached_codec::{
     feature::Feature, Cas, DataType, Magic, McbpMessage, McbpMessageBuilder, Opcode, Status,
 };
-use std::net::TcpListener;
+use std::{collections::BTreeMap, net::TcpListener, path::Path, sync::Arc, time::Instant};
 
 const DATA_PATH: &str = "./data";
 
+/// Keys are in the default collection, as collections aren't negotiated
+const DEFAULT_SCOPE: &str = "_default";
+const DEFAULT_COLLECTION: &str = "_default";
+
+/// What every connection shares
+struct Server {
+    /// Users and their privileges, from the file given with --rbac.
+    /// Without one any client may do anything.
+    rbac: Option<Rbac>,
+    audit: AuditLog,
+    /// The interfaces and connection limits, from the file given with
+    /// --network. Without one the server listens on localhost.
+    limiter: Arc<ConnectionLimiter>,
+    /// How long each opcode takes to handle
+    timings: Arc<Timings>,
+}
+
 fn main() {
-    let listener = TcpListener::bind("127.0.0.1:11210").unwrap();
-    println!("Listening on port 11210");
+    let mut rbac = None;
+    let mut network = NetworkConfig::default();
+    let mut admin = None;
+    let mut admin_allow_remote = false;
+    let mut manifest = None;
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        let value = args.next().expect("missing value");
+        match arg.as_str() {
+            "--rbac" => rbac = Some(Rbac::load(value).unwrap()),
+            "--network" => {
+                network = serde_json::from_str(&std::fs::read_to_string(value).unwrap()).unwrap()
+            }
+            "--admin" => admin = Some(value),
+            "--admin-allow-remote" => admin_allow_remote = value.parse().unwrap(),
+            "--manifest" => manifest = Some(Manifest::load(value).unwrap()),
+            _ => panic!("unknown argument {arg}"),
+        }
+    }
+    std::fs::create_dir_all(DATA_PATH).unwrap();
+    let config = ep_engine::Config::default();
+    let audit = AuditLog::open(
+        format!("{DATA_PATH}/audit.log"),
+        config.audit_rotate_size,
+        &config.audit_disabled_events,
+    )
+    .unwrap();
+
+    // The manifest and the admin API manage the buckets through the
+    // engine. The MCBP handlers still read and write the vbucket files
+    // directly, so a bucket shouldn't be written both ways at once.
+    if admin.is_some() || manifest.is_some() {
+        let engine = Arc::new(Engine::new(ep_engine::Config {
+            dbname: DATA_PATH.to_string(),
+            ..config.clone()
+        }));
+        if let Some(manifest) = manifest {
+            manifest.apply(&engine).unwrap();
+        }
+        match admin {
+            Some(address) => {
+                let listener = admin::bind(&address, admin_allow_remote).unwrap();
+                println!("Admin API listening on {}", listener.local_addr().unwrap());
+                let api = AdminApi::new(engine);
+                std::thread::spawn(move || api.serve(listener));
+            }
+            // Nothing else uses the engine, so close its files
+            None => engine.shutdown(),
+        }
+    }
+
+    let server = Arc::new(Server {
+        rbac,
+        audit,
+        limiter: ConnectionLimiter::new(network),
+        timings: Timings::new(),
+    });
+
+    let listeners: Vec<_> = (0..server.limiter.config().interfaces.len())
+        .map(|interface| {
+            let server = server.clone();
+            std::thread::spawn(move || listen(server, interface))
+        })
+        .collect();
+    for listener in listeners {
+        listener.join().unwrap();
+    }
+}
+
+/// Accept connections on the interface at the given index of the network
+/// config
+fn listen(server: Arc<Server>, interface: usize) {
+    let address = {
+        let interface = &server.limiter.config().interfaces[interface];
+        format!("{}:{}", interface.host, interface.port)
+    };
+    let listener = TcpListener::bind(&address).unwrap();
+    println!("Listening on {address}");
 
     for stream in listener.incoming() {
-        std::thread::spawn(|| {
-            let stream = stream.unwrap();
+        let stream = stream.unwrap();
+        let remote = stream.peer_addr().unwrap();
+        let permit = match server.limiter.accept(interface, remote.ip()) {
+            Ok(permit) => permit,
+            Err(rejection) => {
+                println!("Rejected connection from {remote} on {address}: {rejection}");
+                continue;
+            }
+        };
+        let server = server.clone();
+        std::thread::spawn(move || {
+            let _permit = permit;
             let connection = Connection::new(stream);
-            handle_connection(connection);
+            handle_connection(&server, connection, remote.to_string());
         });
     }
 }
 
-#[derive(Default)]
 struct State {
     bucket: Option<String>,
+    /// The user the connection authenticated as
+    user: Option<String>,
+    remote: String,
+}
+
+impl Server {
+    /// Whether the connection's user has the privilege, recording a
+    /// privilege failure in the audit log if not
+    fn check(
+        &self,
+        state: &State,
+        privilege: Privilege,
+        bucket: &str,
+        scope: Option<&str>,
+        collection: Option<&str>,
+    ) -> bool {
+        let Some(rbac) = &self.rbac else {
+            return true;
+        };
+        let user = state.user.as_deref().unwrap_or_default();
+        if rbac.check(user, privilege, bucket, scope, collection) {
+            return true;
+        }
+        self.audit.record(AuditEvent::PrivilegeFailure {
+            user: user.to_string(),
+            bucket: Some(bucket.to_string()),
+            privilege: privilege.name().to_string(),
+        });
+        false
+    }
+
+    /// Check the privilege on a key in the selected bucket
+    fn check_key(&self, state: &State, privilege: Privilege) -> bool {
+        let bucket = state.bucket.as_deref().unwrap_or_default();
+        self.check(
+            state,
+            privilege,
+            bucket,
+            Some(DEFAULT_SCOPE),
+            Some(DEFAULT_COLLECTION),
+        )
+    }
 }
 
-fn handle_connection(mut connection: Connection) {
-    let mut state = State::default();
+fn no_access(opcode: Opcode) -> Option<McbpMessage> {
+    Some(
+        McbpMessageBuilder::new(opcode)
+            .status(Status::NoAccess)
+            .build(),
+    )
+}
 
-    loop {
-        let req = connection.recv();
+fn handle_connection(server: &Server, mut connection: Connection, remote: String) {
+    let mut state = State {
+        bucket: None,
+        user: None,
+        remote,
+    };
+    let mut timings = ConnectionTimings::new(server.timings.clone(), AGGREGATION_INTERVAL);
 
+    while let Some(req) = connection.try_recv() {
+        let start = Instant::now();
         println!("Received message: {:?}", req);
-        let to_send = handle_message(&mut state, &req);
+        if req.opcode == Opcode::Stat {
+            for mut resp in handle_stat(server, &state, &req) {
+                resp.opaque = req.opaque;
+                resp.magic = Magic::ClientResponse;
+                connection.send(resp);
+            }
+            timings.record(req.opcode, start.elapsed());
+            continue;
+        }
+        let to_send = handle_message(server, &mut state, &req);
         if let Some(mut resp) = to_send {
             resp.opaque = req.opaque;
             resp.magic = Magic::ClientResponse;
@@ -51,23 +228,111 @@ fn handle_connection(mut connection: Connection) {
 
             connection.send(resp);
         }
+        timings.record(req.opcode, start.elapsed());
     }
 }
 
-fn handle_message(state: &mut State, message: &McbpMessage) -> Option<McbpMessage> {
+/// Stats are sent one per response, ending with an empty one. The default
+/// group is the connection stats; the vbucket group has the states and high
+/// seqnos of the selected bucket's vbuckets; the timings group has the
+/// latencies of each opcode the server has handled. The server doesn't
+/// produce DCP streams, so the dcp group is empty.
+fn handle_stat(server: &Server, state: &State, message: &McbpMessage) -> Vec<McbpMessage> {
+    let req = StatRequest::decode(message).unwrap();
+    let bucket = state.bucket.as_deref().unwrap_or_default();
+    if !server.check(state, Privilege::Stats, bucket, None, None) {
+        return no_access(Opcode::Stat).into_iter().collect();
+    }
+    let mut stats = BTreeMap::new();
+    let mut add_stat = |key: &str, value: &str| {
+        stats.insert(key.to_string(), value.to_string());
+    };
+    match req.group.as_str() {
+        "" => server.limiter.get_stats(&mut add_stat),
+        "vbucket" if state.bucket.is_some() => vbucket_stats(bucket, &mut add_stat),
+        "dcp" => {}
+        "timings" => server.timings.get_stats(&mut add_stat),
+        _ => {
+            let resp = McbpMessageBuilder::new(Opcode::Stat)
+                .status(Status::KeyNotFound)
+                .build();
+            return vec![resp];
+        }
+    }
+    stats
+        .into_iter()
+        .map(|(key, value)| StatResponse { key, value }.encode())
+        .chain([StatResponse::encode_end()])
+        .collect()
+}
+
+/// Where the bucket's vbucket files are
+fn bucket_layout(bucket: &str) -> DbNameLayout {
+    DbNameLayout::new(Path::new(DATA_PATH).join(bucket))
+}
+
+/// The state of each of the bucket's vbuckets on disk. Files without a
+/// persisted state were written by this server, whose vbuckets are all
+/// active.
+fn vbucket_stats(bucket: &str, add_stat: &mut dyn FnMut(&str, &str)) {
+    let layout = bucket_layout(bucket);
+    let Ok(names) = layout.list() else {
+        return;
+    };
+    // The latest revision of each vbucket's file
+    let mut files: BTreeMap<u16, u64> = BTreeMap::new();
+    for name in names
+        .into_iter()
+        .filter(|name| name.kind == DbFileKind::Data)
+    {
+        let latest = files.entry(name.vbid).or_default();
+        *latest = name.revision.max(*latest);
+    }
+
+    for (vbid, revision) in files {
+        let path = layout.path(vbid, revision);
+        let Ok(db) = Db::open(&path, DBOpenOptions::default().read_only()) else {
+            continue;
+        };
+        let vb_state = db
+            .open_local_document("_local/vbstate")
+            .ok()
+            .flatten()
+            .and_then(|doc| doc.json)
+            .and_then(|json| VBucketState::from_json(&json).ok());
+        let vb_state = vb_state.map_or(vbucket::State::Active, |vb_state| vb_state.state);
+        let vb_state = serde_json::to_value(vb_state).unwrap();
+        add_stat(&format!("vb_{vbid}"), vb_state.as_str().unwrap());
+        add_stat(
+            &format!("vb_{vbid}:high_seqno"),
+            &db.header().update_seq.to_string(),
+        );
+    }
+}
+
+fn handle_message(
+    server: &Server,
+    state: &mut State,
+    message: &McbpMessage,
+) -> Option<McbpMessage> {
     match message.opcode {
         Opcode::Get => {
+            if !server.check_key(state, Privilege::Read) {
+                return no_access(message.opcode);
+            }
             let req = GetRequest::decode(message).unwrap();
             let vbucket = req.vbucket;
             let key = req.key;
             let bucket = state.bucket.as_ref().unwrap();
-            let mut db = Db::open(
-                format!("{DATA_PATH}/{bucket}/{vbucket}.couch.1"),
+            let db = Db::open(
+                bucket_layout(bucket).path(vbucket, 1),
                 DBOpenOptions::default(),
-            );
-            if let Some(docinfo) = db.docinfo_by_id(key.to_vec()) {
+            )
+            .unwrap();
+            if let Some(docinfo) = db.docinfo_by_id(key.to_vec()).unwrap() {
                 let value = db
                     .open_doc_with_docinfo(&docinfo, OpenOptions::DECOMPRESS_DOC_BODIES)
+                    .unwrap()
                     .unwrap();
 
                 let resp = GetResponse {
@@ -89,16 +354,20 @@ fn handle_message(state: &mut State, message: &McbpMessage) -> Option<McbpMessag
             }
         }
         Opcode::Upsert => {
+            if !server.check_key(state, Privilege::Write) {
+                return no_access(message.opcode);
+            }
             let req = SetRequest::decode(message).unwrap();
             let vbucket = req.vbucket;
             let key = req.key;
             let value = req.value;
             let bucket = state.bucket.as_ref().unwrap();
             let mut db = Db::open(
-                format!("{DATA_PATH}/{bucket}/{vbucket}.couch.1"),
+                bucket_layout(bucket).path(vbucket, 1),
                 DBOpenOptions::default(),
-            );
-            db.set(key.to_vec(), value.to_vec());
+            )
+            .unwrap();
+            db.set(key.to_vec(), value.to_vec()).unwrap();
             db.commit();
             let resp = SetResponse {
                 cas: Cas::default(),
@@ -116,8 +385,19 @@ fn handle_message(state: &mut State, message: &McbpMessage) -> Option<McbpMessag
         Opcode::SelectBucket => {
             let req: SelectBucketRequest = SelectBucketRequest::decode(message).unwrap();
             let bucket = req.bucket;
+            if let Some(rbac) = &server.rbac {
+                let user = state.user.as_deref().unwrap_or_default();
+                if !rbac.can_access_bucket(user, &bucket) {
+                    server.audit.record(AuditEvent::PrivilegeFailure {
+                        user: user.to_string(),
+                        bucket: Some(bucket),
+                        privilege: "select_bucket".to_string(),
+                    });
+                    return no_access(message.opcode);
+                }
+            }
 
-            std::fs::create_dir_all(format!("{DATA_PATH}/{bucket}")).unwrap();
+            std::fs::create_dir_all(bucket_layout(&bucket).dir()).unwrap();
 
             state.bucket = Some(bucket);
 
@@ -140,8 +420,36 @@ fn handle_message(state: &mut State, message: &McbpMessage) -> Option<McbpMessag
             Some(resp)
         }
         Opcode::SaslAuth => {
+            let status = match SaslAuthRequest::decode(message) {
+                Some(SaslAuthRequest::Plain { username, password }) => {
+                    let authenticated = server
+                        .rbac
+                        .as_ref()
+                        .is_none_or(|rbac| rbac.authenticate(&username, &password));
+                    let mechanism = "PLAIN".to_string();
+                    let remote = state.remote.clone();
+                    if authenticated {
+                        server.audit.record(AuditEvent::AuthenticationSucceeded {
+                            user: username.clone(),
+                            mechanism,
+                            remote,
+                        });
+                        state.user = Some(username);
+                        Status::Success
+                    } else {
+                        server.audit.record(AuditEvent::AuthenticationFailed {
+                            user: username,
+                            mechanism,
+                            remote,
+                            reason: "unknown user or wrong password".to_string(),
+                        });
+                        Status::AuthenticationError
+                    }
+                }
+                None => Status::AuthenticationError,
+            };
             let resp = McbpMessageBuilder::new(Opcode::SaslAuth)
-                .status(Status::Success)
+                .status(status)
                 .build();
             Some(resp)
         }
@@ -201,12 +509,11 @@ fn default_bucket_config(state: &State) -> ClusterConfig {
         node_locator: Some("vbucket".to_string()),
         uuid: Some("c4730ffcb639bd2c54d11944c80ffb31".to_string()),
         ddocs: None,
-        v_bucket_server_map: Some(VBucketServerMap {
-            hash_algorithm: "CRC".to_string(),
-            num_replicas: 0,
-            server_list: vec!["127.0.0.1:11210".to_string()],
-            v_bucket_map: vec![vec![0]; 1024],
-        }),
+        v_bucket_server_map: Some(VBucketServerMap::new(
+            vec!["127.0.0.1:11210".to_string()],
+            1024,
+            0,
+        )),
     }
 }
 
@@ -246,11 +553,10 @@ fn default_cluster_config() -> ClusterConfig {
         node_locator: Some("vbucket".to_string()),
         uuid: Some("c4730ffcb639bd2c54d11944c80ffb31".to_string()),
         ddocs: None,
-        v_bucket_server_map: Some(VBucketServerMap {
-            hash_algorithm: "CRC".to_string(),
-            num_replicas: 0,
-            server_list: vec!["127.0.0.1:11210".to_string()],
-            v_bucket_map: vec![vec![0]; 1024],
-        }),
+        v_bucket_server_map: Some(VBucketServerMap::new(
+            vec!["127.0.0.1:11210".to_string()],
+            1024,
+            0,
+        )),
     }
 }
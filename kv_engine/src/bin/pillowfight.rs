@@ -0,0 +1,544 @@
+//! Load generator in the style of cbc-pillowfight. Threads run a mix of
+//! gets and sets on a fixed set of keys, against a data directory through
+//! the engine API or against a server over MCBP, and the throughput and
+//! latency percentiles of each operation are reported at the end.
+
+use std::{
+    net::TcpStream,
+    process::exit,
+    sync::atomic::{AtomicBool, AtomicU64, Ordering},
+    time::{Duration, Instant},
+};
+
+use bytes::Bytes;
+use ep_engine::api::{Bucket, Engine, EngineConfig, EngineError};
+use kv_engine::{
+    connection::Connection,
+    operations::{get::GetRequest, select_bucket::SelectBucketRequest, set::SetRequest},
+};
+use memcached_codec::Status;
+use rand::{rngs::StdRng, Rng, SeedableRng};
+
+const USAGE: &str = "Usage: pillowfight <data dir | couchbase://host:port> [options]
+
+Options:
+  --bucket <name>         (default: default)
+  --username <name>       (default: Administrator)
+  --password <password>   (default: password)
+  --num-items <count>     Number of distinct keys (default: 1000)
+  --min-size <bytes>      Smallest value written (default: 50)
+  --max-size <bytes>      Largest value written (default: 5000)
+  --set-pct <percent>     Percentage of operations which are sets (default: 33)
+  --skew <exponent>       Zipf exponent of the key popularity, 0 for uniform
+                          (default: 0)
+  --threads <count>       (default: 1)
+  --duration <seconds>    How long to run for (default: 10)
+  --no-populate           Don't write every key before the run
+  --seed <number>         Seed for the random choices (default: 0)";
+
+struct Options {
+    target: String,
+    bucket: String,
+    username: String,
+    password: String,
+    num_items: usize,
+    min_size: usize,
+    max_size: usize,
+    set_pct: u32,
+    skew: f64,
+    threads: usize,
+    duration: Duration,
+    populate: bool,
+    seed: u64,
+}
+
+fn parse_args(args: impl IntoIterator<Item = String>) -> Result<Options, String> {
+    let mut args = args.into_iter();
+    let mut positional = Vec::new();
+    let mut options = Options {
+        target: String::new(),
+        bucket: "default".to_string(),
+        username: "Administrator".to_string(),
+        password: "password".to_string(),
+        num_items: 1000,
+        min_size: 50,
+        max_size: 5000,
+        set_pct: 33,
+        skew: 0.0,
+        threads: 1,
+        duration: Duration::from_secs(10),
+        populate: true,
+        seed: 0,
+    };
+
+    while let Some(arg) = args.next() {
+        if !arg.starts_with("--") {
+            positional.push(arg);
+            continue;
+        }
+        if arg == "--no-populate" {
+            options.populate = false;
+            continue;
+        }
+        let value = args
+            .next()
+            .ok_or_else(|| format!("Missing value for {arg}"))?;
+        let invalid = || format!("Invalid value for {arg}: {value}");
+        match arg.as_str() {
+            "--bucket" => options.bucket = value,
+            "--username" => options.username = value,
+            "--password" => options.password = value,
+            "--num-items" => options.num_items = value.parse().map_err(|_| invalid())?,
+            "--min-size" => options.min_size = value.parse().map_err(|_| invalid())?,
+            "--max-size" => options.max_size = value.parse().map_err(|_| invalid())?,
+            "--set-pct" => options.set_pct = value.parse().map_err(|_| invalid())?,
+            "--skew" => options.skew = value.parse().map_err(|_| invalid())?,
+            "--threads" => options.threads = value.parse().map_err(|_| invalid())?,
+            "--duration" => {
+                options.duration = Duration::from_secs(value.parse().map_err(|_| invalid())?)
+            }
+            "--seed" => options.seed = value.parse().map_err(|_| invalid())?,
+            _ => return Err(format!("Unknown option {arg}")),
+        }
+    }
+
+    if positional.len() != 1 {
+        return Err(USAGE.to_string());
+    }
+    if options.num_items == 0 || options.threads == 0 {
+        return Err("--num-items and --threads must be at least 1".to_string());
+    }
+    if options.min_size > options.max_size {
+        return Err("--min-size can't be above --max-size".to_string());
+    }
+    if options.set_pct > 100 || options.skew < 0.0 {
+        return Err("--set-pct must be at most 100 and --skew at least 0".to_string());
+    }
+    options.target = positional.pop().unwrap();
+    Ok(options)
+}
+
+/// Where the operations go. Gets return whether the key was found.
+trait Target: Send {
+    fn get(&mut self, key: &str) -> Result<bool, String>;
+
+    fn set(&mut self, key: &str, value: &[u8]) -> Result<(), String>;
+}
+
+impl Target for Bucket {
+    fn get(&mut self, key: &str) -> Result<bool, String> {
+        match Bucket::get(self, key.as_bytes()) {
+            Ok(_) => Ok(true),
+            Err(EngineError::KeyNotFound) => Ok(false),
+            Err(e) => Err(e.to_string()),
+        }
+    }
+
+    fn set(&mut self, key: &str, value: &[u8]) -> Result<(), String> {
+        Bucket::set(self, key.as_bytes(), value, 0, 0)
+            .map(|_| ())
+            .map_err(|e| e.to_string())
+    }
+}
+
+struct ServerTarget {
+    client: Connection,
+}
+
+impl ServerTarget {
+    fn connect(address: &str, options: &Options) -> Result<Self, String> {
+        let stream = TcpStream::connect(address)
+            .map_err(|e| format!("Failed to connect to {address}: {e}"))?;
+        let mut client = Connection::new(stream);
+        client.hello();
+        let auth = client.auth(options.username.clone(), options.password.clone());
+        if auth.status != Status::Success {
+            return Err(format!("Failed to authenticate as {}", options.username));
+        }
+        client.send(
+            SelectBucketRequest {
+                bucket: options.bucket.clone(),
+            }
+            .encode(),
+        );
+        if client.recv().try_status().ok() != Some(Status::Success) {
+            return Err(format!("Failed to select bucket {}", options.bucket));
+        }
+        Ok(Self { client })
+    }
+}
+
+impl Target for ServerTarget {
+    fn get(&mut self, key: &str) -> Result<bool, String> {
+        self.client.send(
+            GetRequest {
+                key: Bytes::copy_from_slice(key.as_bytes()),
+                vbucket: 0,
+            }
+            .encode(),
+        );
+        match self.client.recv().try_status() {
+            Ok(Status::Success) => Ok(true),
+            Ok(Status::KeyNotFound) => Ok(false),
+            status => Err(format!("{status:?}")),
+        }
+    }
+
+    fn set(&mut self, key: &str, value: &[u8]) -> Result<(), String> {
+        self.client.send(
+            SetRequest {
+                key: Bytes::copy_from_slice(key.as_bytes()),
+                value: Bytes::copy_from_slice(value),
+                vbucket: 0,
+            }
+            .encode(),
+        );
+        match self.client.recv().try_status() {
+            Ok(Status::Success) => Ok(()),
+            status => Err(format!("{status:?}")),
+        }
+    }
+}
+
+/// Picks keys by rank, rank i having weight 1 / (i + 1)^skew
+struct KeyChooser {
+    /// Cumulative weights, normalised to end at 1
+    cdf: Vec<f64>,
+}
+
+impl KeyChooser {
+    fn new(num_items: usize, skew: f64) -> Self {
+        let mut total = 0.0;
+        let mut cdf: Vec<f64> = (0..num_items)
+            .map(|i| {
+                total += 1.0 / ((i + 1) as f64).powf(skew);
+                total
+            })
+            .collect();
+        for weight in &mut cdf {
+            *weight /= total;
+        }
+        Self { cdf }
+    }
+
+    fn choose(&self, rng: &mut impl Rng) -> usize {
+        let point: f64 = rng.gen();
+        self.cdf
+            .partition_point(|&weight| weight < point)
+            .min(self.cdf.len() - 1)
+    }
+}
+
+fn key_name(i: usize) -> String {
+    format!("pillowfight_{i:08}")
+}
+
+/// Latencies of one kind of operation, in nanoseconds
+#[derive(Default)]
+struct Latencies {
+    samples: Vec<u64>,
+    errors: u64,
+    misses: u64,
+}
+
+impl Latencies {
+    fn record(&mut self, start: Instant) {
+        self.samples.push(start.elapsed().as_nanos() as u64);
+    }
+
+    fn merge(&mut self, other: Latencies) {
+        self.samples.extend(other.samples);
+        self.errors += other.errors;
+        self.misses += other.misses;
+    }
+
+    fn report(&mut self, name: &str, elapsed: Duration) {
+        if self.samples.is_empty() {
+            println!("{name}: no operations");
+            return;
+        }
+        self.samples.sort_unstable();
+        let percentile = |p: f64| {
+            let index = ((self.samples.len() as f64 * p).ceil() as usize).max(1) - 1;
+            Duration::from_nanos(self.samples[index])
+        };
+        println!(
+            "{name}: {} ops, {:.0} ops/s, {} errors{}",
+            self.samples.len(),
+            self.samples.len() as f64 / elapsed.as_secs_f64(),
+            self.errors,
+            if name == "get" {
+                format!(", {} misses", self.misses)
+            } else {
+                String::new()
+            }
+        );
+        println!(
+            "  latency p50 {:?}  p95 {:?}  p99 {:?}  p99.9 {:?}  max {:?}",
+            percentile(0.5),
+            percentile(0.95),
+            percentile(0.99),
+            percentile(0.999),
+            percentile(1.0)
+        );
+    }
+}
+
+struct Worker<'a> {
+    options: &'a Options,
+    chooser: &'a KeyChooser,
+    /// Random bytes values are sliced from
+    data: &'a [u8],
+    rng: StdRng,
+}
+
+impl Worker<'_> {
+    fn value(&mut self) -> &[u8] {
+        let size = self
+            .rng
+            .gen_range(self.options.min_size..=self.options.max_size);
+        let start = self.rng.gen_range(0..=self.data.len() - size);
+        &self.data[start..start + size]
+    }
+
+    /// Run operations until stopped, counting them in ops
+    fn run(
+        &mut self,
+        target: &mut dyn Target,
+        stop: &AtomicBool,
+        ops: &AtomicU64,
+    ) -> (Latencies, Latencies) {
+        let mut gets = Latencies::default();
+        let mut sets = Latencies::default();
+        while !stop.load(Ordering::Relaxed) {
+            let key = key_name(self.chooser.choose(&mut self.rng));
+            if self.rng.gen_range(0..100) < self.options.set_pct {
+                let value = self.value().to_vec();
+                let start = Instant::now();
+                let result = target.set(&key, &value);
+                sets.record(start);
+                if result.is_err() {
+                    sets.errors += 1;
+                }
+            } else {
+                let start = Instant::now();
+                let result = target.get(&key);
+                gets.record(start);
+                match result {
+                    Ok(true) => {}
+                    Ok(false) => gets.misses += 1,
+                    Err(_) => gets.errors += 1,
+                }
+            }
+            ops.fetch_add(1, Ordering::Relaxed);
+        }
+        (gets, sets)
+    }
+}
+
+fn main() {
+    let options = parse_args(std::env::args().skip(1)).unwrap_or_else(|e| {
+        println!("{e}");
+        exit(1);
+    });
+
+    let address = options.target.strip_prefix("couchbase://");
+    let engine = match address {
+        Some(_) => None,
+        None => Some(Engine::new(EngineConfig {
+            dbname: options.target.clone(),
+            ..Default::default()
+        })),
+    };
+    let connect = || -> Box<dyn Target> {
+        let target = match (&engine, address) {
+            (Some(engine), _) => engine
+                .bucket(&options.bucket)
+                .map(|bucket| Box::new(bucket) as Box<dyn Target>)
+                .map_err(|e| format!("Failed to open bucket {}: {e}", options.bucket)),
+            (None, Some(address)) => ServerTarget::connect(address, &options)
+                .map(|target| Box::new(target) as Box<dyn Target>),
+            (None, None) => unreachable!(),
+        };
+        target.unwrap_or_else(|e| {
+            println!("{e}");
+            exit(1);
+        })
+    };
+
+    let mut rng = StdRng::seed_from_u64(options.seed);
+    let data: Vec<u8> = (0..options.max_size.max(1) * 4)
+        .map(|_| rng.gen_range(b'a'..=b'z'))
+        .collect();
+    let chooser = KeyChooser::new(options.num_items, options.skew);
+
+    if options.populate {
+        println!("Writing {} keys", options.num_items);
+        let mut target = connect();
+        let mut worker = Worker {
+            options: &options,
+            chooser: &chooser,
+            data: &data,
+            rng: StdRng::seed_from_u64(options.seed),
+        };
+        for i in 0..options.num_items {
+            let value = worker.value().to_vec();
+            if let Err(e) = target.set(&key_name(i), &value) {
+                println!("Failed to write {}: {e}", key_name(i));
+                exit(1);
+            }
+        }
+    }
+
+    println!(
+        "Running {} threads for {:?}",
+        options.threads, options.duration
+    );
+    let stop = AtomicBool::new(false);
+    let ops = AtomicU64::new(0);
+    let start = Instant::now();
+    let (mut gets, mut sets) = std::thread::scope(|scope| {
+        let handles: Vec<_> = (0..options.threads)
+            .map(|thread| {
+                let mut target = connect();
+                let mut worker = Worker {
+                    options: &options,
+                    chooser: &chooser,
+                    data: &data,
+                    rng: StdRng::seed_from_u64(options.seed + 1 + thread as u64),
+                };
+                let (stop, ops) = (&stop, &ops);
+                scope.spawn(move || worker.run(target.as_mut(), stop, ops))
+            })
+            .collect();
+
+        let mut last = 0;
+        for second in 1..=options.duration.as_secs() {
+            std::thread::sleep(Duration::from_secs(1));
+            let total = ops.load(Ordering::Relaxed);
+            println!("{second:>4}s  {:>10} ops/s", total - last);
+            last = total;
+        }
+        stop.store(true, Ordering::Relaxed);
+
+        let mut gets = Latencies::default();
+        let mut sets = Latencies::default();
+        for handle in handles {
+            let (thread_gets, thread_sets) = handle.join().unwrap();
+            gets.merge(thread_gets);
+            sets.merge(thread_sets);
+        }
+        (gets, sets)
+    });
+    let elapsed = start.elapsed();
+
+    println!(
+        "Total: {} ops in {elapsed:?}, {:.0} ops/s",
+        gets.samples.len() + sets.samples.len(),
+        (gets.samples.len() + sets.samples.len()) as f64 / elapsed.as_secs_f64()
+    );
+    gets.report("get", elapsed);
+    sets.report("set", elapsed);
+    if let Some(engine) = engine {
+        engine.shutdown();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::collections::HashMap;
+
+    /// Stores values in memory, stopping the run after a number of
+    /// operations
+    struct MapTarget<'a> {
+        values: HashMap<String, Vec<u8>>,
+        ops_left: usize,
+        stop: &'a AtomicBool,
+    }
+
+    impl MapTarget<'_> {
+        fn count_op(&mut self) {
+            self.ops_left -= 1;
+            if self.ops_left == 0 {
+                self.stop.store(true, Ordering::Relaxed);
+            }
+        }
+    }
+
+    impl Target for MapTarget<'_> {
+        fn get(&mut self, key: &str) -> Result<bool, String> {
+            self.count_op();
+            Ok(self.values.contains_key(key))
+        }
+
+        fn set(&mut self, key: &str, value: &[u8]) -> Result<(), String> {
+            self.count_op();
+            self.values.insert(key.to_string(), value.to_vec());
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_pillowfight() {
+        let args = |args: &[&str]| parse_args(args.iter().map(|arg| arg.to_string()));
+        assert!(args(&["dir", "--min-size", "10", "--max-size", "5"]).is_err());
+        assert!(args(&["dir", "--set-pct", "101"]).is_err());
+        assert!(args(&["dir", "--threads", "0"]).is_err());
+        let options = args(&[
+            "dir",
+            "--num-items",
+            "100",
+            "--min-size",
+            "5",
+            "--max-size",
+            "20",
+            "--set-pct",
+            "50",
+            "--skew",
+            "1.5",
+        ])
+        .unwrap();
+
+        // Skewed, the first keys are far more popular than the last
+        let chooser = KeyChooser::new(options.num_items, options.skew);
+        let mut rng = StdRng::seed_from_u64(0);
+        let mut counts = vec![0; options.num_items];
+        for _ in 0..10_000 {
+            counts[chooser.choose(&mut rng)] += 1;
+        }
+        assert!(counts[0] > 10 * counts[99]);
+        let uniform = KeyChooser::new(options.num_items, 0.0);
+        assert!(uniform.cdf.windows(2).all(|w| w[1] > w[0]));
+        assert!((uniform.cdf[49] - 0.5).abs() < 1e-9);
+
+        let data: Vec<u8> = (0..80).map(|i| i as u8).collect();
+        let mut worker = Worker {
+            options: &options,
+            chooser: &chooser,
+            data: &data,
+            rng: StdRng::seed_from_u64(1),
+        };
+        let stop = AtomicBool::new(false);
+        let ops = AtomicU64::new(0);
+        let mut target = MapTarget {
+            values: HashMap::new(),
+            ops_left: 1000,
+            stop: &stop,
+        };
+        let (gets, sets) = worker.run(&mut target, &stop, &ops);
+        assert_eq!(ops.load(Ordering::Relaxed), 1000);
+        assert_eq!(gets.samples.len() + sets.samples.len(), 1000);
+        assert!(gets.samples.len() > 400 && sets.samples.len() > 400);
+        // Nothing was written beforehand, so the first gets miss
+        assert!(gets.misses > 0 && gets.misses < gets.samples.len() as u64);
+        assert_eq!(gets.errors + sets.errors, 0);
+        assert!(
+            target
+                .values
+                .iter()
+                .all(|(key, value)| key.starts_with("pillowfight_")
+                    && (5..=20).contains(&value.len()))
+        );
+    }
+}
@@ -0,0 +1,529 @@
+//! Replicates a bucket between two engines over TCP, for watching the DCP
+//! producer and consumer work end to end. One node serves its bucket's
+//! vbuckets as the active copies, streaming them over MCBP to any node
+//! replicating from it, which applies them to its replica vbuckets and
+//! acknowledges the producer's flow control buffer as the items are
+//! persisted. Run each node on its own data directory:
+//!
+//! ```text
+//! replication serve ./active --write-rate 100
+//! replication replicate ./replica 127.0.0.1:11999
+//! ```
+//!
+//! The replica has no way to roll back to a point it shares with the
+//! active, so its bucket is deleted and streamed from the start on every
+//! run.
+
+use std::{
+    net::{TcpListener, TcpStream},
+    process::exit,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+use bytes::{BufMut, BytesMut};
+use ep_engine::{
+    api::{
+        Bucket, DcpOpenFlags, DcpProducer, DcpResponse, Engine, EngineConfig, EngineError,
+        StreamRequest,
+    },
+    vbucket::{State, Vbid},
+};
+use kv_engine::{
+    connection::Connection,
+    operations::dcp::{
+        DcpBufferAcknowledgementRequest, DcpControlRequest, DcpOpenConnectionRequest, DcpOpenFlag,
+        DcpStreamAddFlag, DcpStreamMessage, DcpStreamRequest,
+    },
+};
+use memcached_codec::{Magic, McbpMessage, McbpMessageBuilder, Opcode, Status};
+
+const USAGE: &str = "Usage: replication serve <data dir> [options]
+       replication replicate <data dir> <host:port> [options]
+
+Options:
+  --bucket <name>         (default: default)
+  --listen <host:port>    Where to serve the bucket (default: 127.0.0.1:11999)
+  --write-rate <ops/s>    When serving, keep setting keys at this rate so
+                          there are mutations to replicate (default: 0)
+  --num-items <count>     Number of distinct keys written (default: 1000)
+  --buffer-size <bytes>   When replicating, the flow control buffer the
+                          producer may fill (default: 10485760)";
+
+/// How often the nodes persist their mutations, the replica acknowledging
+/// those it has persisted
+const FLUSH_INTERVAL: Duration = Duration::from_millis(100);
+
+/// The MCBP statuses the engine's errors have no variant for
+const STATUS_OUT_OF_RANGE: u16 = 0x22;
+const STATUS_ROLLBACK: u16 = 0x23;
+const STATUS_INTERNAL_ERROR: u16 = 0x84;
+
+enum Mode {
+    Serve,
+    Replicate(String),
+}
+
+struct Options {
+    mode: Mode,
+    data_dir: String,
+    bucket: String,
+    listen: String,
+    write_rate: u64,
+    num_items: u64,
+    buffer_size: u32,
+}
+
+fn parse_args() -> Result<Options, String> {
+    let mut args = std::env::args().skip(1);
+    let mut positional = Vec::new();
+    let mut options = Options {
+        mode: Mode::Serve,
+        data_dir: String::new(),
+        bucket: "default".to_string(),
+        listen: "127.0.0.1:11999".to_string(),
+        write_rate: 0,
+        num_items: 1000,
+        buffer_size: 10 * 1024 * 1024,
+    };
+
+    while let Some(arg) = args.next() {
+        if !arg.starts_with("--") {
+            positional.push(arg);
+            continue;
+        }
+        let value = args
+            .next()
+            .ok_or_else(|| format!("Missing value for {arg}"))?;
+        let invalid = || format!("Invalid value for {arg}: {value}");
+        match arg.as_str() {
+            "--bucket" => options.bucket = value,
+            "--listen" => options.listen = value,
+            "--write-rate" => options.write_rate = value.parse().map_err(|_| invalid())?,
+            "--num-items" => options.num_items = value.parse().map_err(|_| invalid())?,
+            "--buffer-size" => options.buffer_size = value.parse().map_err(|_| invalid())?,
+            _ => return Err(format!("Unknown option {arg}")),
+        }
+    }
+
+    let mut positional = positional.into_iter();
+    options.mode = match (positional.next().as_deref(), positional.len()) {
+        (Some("serve"), 1) => Mode::Serve,
+        (Some("replicate"), 2) => Mode::Replicate(positional.next_back().unwrap()),
+        _ => return Err(USAGE.to_string()),
+    };
+    options.data_dir = positional.next().unwrap();
+    if options.num_items == 0 {
+        return Err("--num-items must be at least 1".to_string());
+    }
+    Ok(options)
+}
+
+fn open_bucket(options: &Options) -> Result<(Engine, Bucket), String> {
+    let engine = Engine::new(EngineConfig {
+        dbname: options.data_dir.clone(),
+        ..Default::default()
+    });
+    let bucket = engine
+        .bucket(&options.bucket)
+        .map_err(|e| format!("Failed to open bucket {}: {e}", options.bucket))?;
+    Ok((engine, bucket))
+}
+
+/// Persist the bucket's mutations in the background, as the engine has no
+/// flusher of its own
+fn spawn_flusher(
+    bucket: Bucket,
+    done: Arc<AtomicBool>,
+    mut after_flush: impl FnMut() + Send + 'static,
+) {
+    thread::spawn(move || {
+        while !done.load(Ordering::SeqCst) {
+            thread::sleep(FLUSH_INTERVAL);
+            bucket.flush();
+            after_flush();
+        }
+    });
+}
+
+fn response(request: &McbpMessage, status: Status) -> McbpMessageBuilder {
+    McbpMessageBuilder::new(request.opcode)
+        .status(status)
+        .opaque(request.opaque)
+        .magic(Magic::ClientResponse)
+}
+
+fn error_status(error: &EngineError) -> Status {
+    match error {
+        EngineError::KeyNotFound => Status::KeyNotFound,
+        EngineError::KeyExists => Status::KeyExists,
+        EngineError::InvalidArguments => Status::InvalidArguments,
+        EngineError::NotMyVbucket => Status::NotMyVBucket,
+        EngineError::OutOfRange => Status::Unknown(STATUS_OUT_OF_RANGE),
+        EngineError::Rollback(_) => Status::Unknown(STATUS_ROLLBACK),
+        _ => Status::Unknown(STATUS_INTERNAL_ERROR),
+    }
+}
+
+/// Keep setting keys at the rate, until a set fails
+fn spawn_writer(bucket: Bucket, rate: u64, num_items: u64) {
+    let interval = Duration::from_secs_f64(1.0 / rate as f64);
+    thread::spawn(move || {
+        let mut next = Instant::now();
+        for n in 0u64.. {
+            let key = format!("key{}", n % num_items);
+            let value = format!("{{\"n\":{n}}}");
+            if let Err(e) = bucket.set(key.as_bytes(), value.as_bytes(), 0, 0) {
+                println!("Stopped writing, failed to set {key}: {e}");
+                return;
+            }
+            next += interval;
+            thread::sleep(next.saturating_duration_since(Instant::now()));
+        }
+    });
+}
+
+/// Serve the bucket's vbuckets to the nodes replicating from it
+fn serve(options: &Options) -> Result<(), String> {
+    let (_engine, bucket) = open_bucket(options)?;
+    spawn_flusher(bucket.clone(), Arc::new(AtomicBool::new(false)), || {});
+    if options.write_rate > 0 {
+        spawn_writer(bucket.clone(), options.write_rate, options.num_items);
+    }
+    let listener = TcpListener::bind(&options.listen)
+        .map_err(|e| format!("Failed to listen on {}: {e}", options.listen))?;
+    println!("Serving bucket {} on {}", options.bucket, options.listen);
+    for stream in listener.incoming() {
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(e) => {
+                println!("Failed to accept a connection: {e}");
+                continue;
+            }
+        };
+        let bucket = bucket.clone();
+        thread::spawn(move || {
+            let remote = stream
+                .peer_addr()
+                .map_or_else(|_| "unknown".to_string(), |addr| addr.to_string());
+            match serve_connection(&bucket, stream) {
+                Ok(()) => println!("{remote} disconnected"),
+                Err(e) => println!("{remote} disconnected: {e}"),
+            }
+        });
+    }
+    Ok(())
+}
+
+/// Run one replica's DCP connection. The producer is stepped on a thread
+/// of its own, as the client's messages arrive independently of the
+/// streams'.
+fn serve_connection(bucket: &Bucket, stream: TcpStream) -> Result<(), String> {
+    let writer = stream.try_clone().map_err(|e| e.to_string())?;
+    let writer = Arc::new(Mutex::new(Connection::new(writer)));
+    let mut reader = Connection::new(stream);
+
+    let open = reader.try_recv().ok_or("Closed before opening")?;
+    if open.opcode != Opcode::DcpOpenConnection {
+        return Err(format!("Expected a DCP open, got {:?}", open.opcode));
+    }
+    let request = DcpOpenConnectionRequest::decode(&open).map_err(|e| e.to_string())?;
+    if !request.flags.contains(DcpOpenFlag::PRODUCER) {
+        return Err("Only producer connections are served".to_string());
+    }
+    let mut flags = DcpOpenFlags::empty();
+    if request.flags.contains(DcpOpenFlag::INCLUDE_DELETE_TIMES) {
+        flags |= DcpOpenFlags::INCLUDE_DELETE_TIMES;
+    }
+    if request.flags.contains(DcpOpenFlag::NO_VALUE) {
+        flags |= DcpOpenFlags::NO_VALUE;
+    }
+    let producer = bucket.dcp_producer(&request.stream_name, flags);
+    let send = |message: McbpMessage| writer.lock().unwrap().try_send(message);
+    send(response(&open, Status::Success).build()).map_err(|e| e.to_string())?;
+    println!("Opened DCP connection {}", request.stream_name);
+
+    let done = Arc::new(AtomicBool::new(false));
+    let stepper = {
+        let producer = producer.clone();
+        let writer = writer.clone();
+        let done = done.clone();
+        thread::spawn(move || {
+            while !done.load(Ordering::SeqCst) {
+                match producer.step() {
+                    Ok(Some(response)) => {
+                        let message = DcpStreamMessage(response).encode();
+                        if writer.lock().unwrap().try_send(message).is_err() {
+                            break;
+                        }
+                    }
+                    Ok(None) => thread::sleep(Duration::from_millis(1)),
+                    Err(e) => {
+                        println!("Failed to step the producer: {e}");
+                        break;
+                    }
+                }
+            }
+        })
+    };
+
+    while let Some(message) = reader.try_recv() {
+        let reply = match message.opcode {
+            Opcode::DcpControl => {
+                let control = DcpControlRequest::decode(&message);
+                let status = match producer.control(&control.key, &control.value) {
+                    Ok(()) => Status::Success,
+                    Err(e) => error_status(&e),
+                };
+                Some(response(&message, status).build())
+            }
+            Opcode::DcpStreamRequest => Some(stream_request(&producer, &message)),
+            Opcode::DcpBufferAcknowledgement => {
+                match DcpBufferAcknowledgementRequest::decode(&message) {
+                    Ok(ack) => producer.buffer_acknowledgement(ack.bytes as usize),
+                    Err(e) => println!("Invalid buffer acknowledgement: {e}"),
+                }
+                None
+            }
+            // The client's replies to the producer's noops
+            Opcode::DcpNoop => None,
+            opcode => {
+                println!("Unexpected {opcode:?}");
+                Some(response(&message, Status::Unknown(STATUS_INTERNAL_ERROR)).build())
+            }
+        };
+        if let Some(reply) = reply {
+            if send(reply).is_err() {
+                break;
+            }
+        }
+    }
+    done.store(true, Ordering::SeqCst);
+    stepper.join().unwrap();
+    Ok(())
+}
+
+/// Open a stream, replying with the vbucket's failover log, or with the
+/// seqno to roll back to
+fn stream_request(producer: &DcpProducer, message: &McbpMessage) -> McbpMessage {
+    let request = match DcpStreamRequest::decode(message) {
+        Ok(request) => request,
+        Err(_) => return response(message, Status::InvalidArguments).build(),
+    };
+    let req = StreamRequest {
+        flags: Default::default(),
+        start_seqno: request.start_seqno,
+        end_seqno: request.end_seqno,
+        vb_uuid: request.vb_uuid,
+        snap_start_seqno: request.snap_start_seqno,
+        snap_end_seqno: request.snap_end_seqno,
+    };
+    let vbid = Vbid::new(request.vbucket);
+    match producer.stream_request(message.opaque, vbid, req, request.filter.as_deref()) {
+        Ok(failover_log) => {
+            let mut value = BytesMut::with_capacity(16 * failover_log.len());
+            for entry in failover_log {
+                value.put_u64(entry.vb_uuid);
+                value.put_u64(entry.by_seqno);
+            }
+            response(message, Status::Success).value(value).build()
+        }
+        Err(EngineError::Rollback(seqno)) => {
+            let mut value = BytesMut::with_capacity(8);
+            value.put_u64(seqno);
+            response(message, error_status(&EngineError::Rollback(seqno)))
+                .value(value)
+                .build()
+        }
+        Err(e) => response(message, error_status(&e)).build(),
+    }
+}
+
+/// What the replica has received, for the progress reports
+#[derive(Default)]
+struct Progress {
+    items: AtomicU64,
+    bytes: AtomicU64,
+    acknowledged: AtomicU64,
+}
+
+/// Replicate the bucket of the node at the address into the replica
+/// vbuckets of this one, until it disconnects
+fn replicate(options: &Options, address: &str) -> Result<(), String> {
+    let engine = Engine::new(EngineConfig {
+        dbname: options.data_dir.clone(),
+        ..Default::default()
+    });
+    match engine.delete_bucket(&options.bucket) {
+        Ok(()) | Err(EngineError::KeyNotFound) => {}
+        Err(e) => return Err(format!("Failed to delete bucket {}: {e}", options.bucket)),
+    }
+    let bucket = engine
+        .bucket(&options.bucket)
+        .map_err(|e| format!("Failed to open bucket {}: {e}", options.bucket))?;
+    let num_vbuckets = bucket.num_vbuckets();
+    for vbid in 0..num_vbuckets {
+        bucket
+            .set_vbucket_state(Vbid::new(vbid), State::Replica)
+            .map_err(|e| format!("Failed to make vb {vbid} a replica: {e}"))?;
+    }
+    let consumer = Arc::new(bucket.dcp_consumer("replication"));
+
+    let stream =
+        TcpStream::connect(address).map_err(|e| format!("Failed to connect to {address}: {e}"))?;
+    let writer = stream.try_clone().map_err(|e| e.to_string())?;
+    let writer = Arc::new(Mutex::new(Connection::new(writer)));
+    let mut reader = Connection::new(stream);
+    let mut request = |message: McbpMessage| {
+        writer.lock().unwrap().send(message);
+        reader.try_recv().map(|reply| reply.try_status())
+    };
+    let open = DcpOpenConnectionRequest {
+        stream_name: format!("replication:{}", options.bucket),
+        flags: DcpOpenFlag::PRODUCER | DcpOpenFlag::INCLUDE_DELETE_TIMES,
+    };
+    match request(open.encode()) {
+        Some(Ok(Status::Success)) => {}
+        status => return Err(format!("Failed to open the DCP connection: {status:?}")),
+    }
+    for (key, value) in [
+        ("connection_buffer_size", options.buffer_size.to_string()),
+        ("enable_expiry_opcode", "true".to_string()),
+    ] {
+        let control = DcpControlRequest {
+            key: key.to_string(),
+            value,
+        };
+        match request(control.encode()) {
+            Some(Ok(Status::Success)) => {}
+            status => return Err(format!("Failed to set {key}: {status:?}")),
+        }
+    }
+
+    // Each stream's messages carry the opaque of its request, the vbucket
+    for vbid in 0..num_vbuckets {
+        let mut request = DcpStreamRequest {
+            vbucket: vbid,
+            flags: DcpStreamAddFlag::empty(),
+            start_seqno: 0,
+            end_seqno: u64::MAX,
+            vb_uuid: 0,
+            snap_start_seqno: 0,
+            snap_end_seqno: 0,
+            filter: None,
+        }
+        .encode();
+        request.opaque = vbid.into();
+        writer.lock().unwrap().send(request);
+    }
+    println!("Replicating {num_vbuckets} vbuckets of {address}");
+
+    // Persisted items free the producer's buffer, letting it send more
+    let progress = Arc::new(Progress::default());
+    let done = Arc::new(AtomicBool::new(false));
+    {
+        let consumer = consumer.clone();
+        let writer = writer.clone();
+        let progress = progress.clone();
+        spawn_flusher(bucket.clone(), done.clone(), move || {
+            if let Err(e) = consumer.process_buffered() {
+                println!("Failed to apply buffered messages: {e}");
+            }
+            let bytes = consumer.take_buffer_acknowledgement() as u32;
+            if bytes > 0 {
+                let ack = DcpBufferAcknowledgementRequest { bytes }.encode();
+                if writer.lock().unwrap().try_send(ack).is_ok() {
+                    progress
+                        .acknowledged
+                        .fetch_add(bytes.into(), Ordering::Relaxed);
+                }
+            }
+        });
+    }
+    spawn_reporter(progress.clone(), done.clone());
+
+    let mut streams = 0;
+    let result = loop {
+        let Some(message) = reader.try_recv() else {
+            break Ok(());
+        };
+        if message.magic == Magic::ClientResponse {
+            if message.opcode == Opcode::DcpStreamRequest {
+                match message.try_status() {
+                    Ok(Status::Success) => streams += 1,
+                    status => println!("Stream for vb {} failed: {status:?}", message.opaque),
+                }
+                if message.opaque == u32::from(num_vbuckets) - 1 {
+                    println!("{streams} streams open");
+                }
+            }
+            continue;
+        }
+        let received = match DcpStreamMessage::decode(&message) {
+            Ok(DcpStreamMessage(received)) => received,
+            Err(e) => break Err(format!("Invalid {:?}: {e}", message.opcode)),
+        };
+        match &received {
+            DcpResponse::Noop { .. } => {
+                let reply = response(&message, Status::Success).build();
+                writer.lock().unwrap().send(reply);
+                continue;
+            }
+            DcpResponse::StreamEnd { vbid, status, .. } => {
+                println!("Stream for {vbid} ended: {status:?}");
+            }
+            _ => {}
+        }
+        if received.item().is_some() {
+            progress.items.fetch_add(1, Ordering::Relaxed);
+        }
+        progress
+            .bytes
+            .fetch_add(received.message_size() as u64, Ordering::Relaxed);
+        if let Err(e) = consumer.handle(&received) {
+            break Err(format!("Failed to apply {received:?}: {e}"));
+        }
+    };
+    done.store(true, Ordering::SeqCst);
+    engine.shutdown();
+    result
+}
+
+/// Print what has been received every second
+fn spawn_reporter(progress: Arc<Progress>, done: Arc<AtomicBool>) {
+    thread::spawn(move || {
+        let started = Instant::now();
+        let mut last_items = 0;
+        while !done.load(Ordering::SeqCst) {
+            thread::sleep(Duration::from_secs(1));
+            let items = progress.items.load(Ordering::Relaxed);
+            println!(
+                "{:>5}s {items:>10} items {:>8} items/s {:>12} bytes received {:>12} acknowledged",
+                started.elapsed().as_secs(),
+                items - last_items,
+                progress.bytes.load(Ordering::Relaxed),
+                progress.acknowledged.load(Ordering::Relaxed)
+            );
+            last_items = items;
+        }
+    });
+}
+
+fn main() {
+    let options = parse_args().unwrap_or_else(|e| {
+        println!("{e}");
+        exit(1);
+    });
+    let result = match &options.mode {
+        Mode::Serve => serve(&options),
+        Mode::Replicate(address) => replicate(&options, address),
+    };
+    if let Err(e) = result {
+        println!("{e}");
+        exit(1);
+    }
+}
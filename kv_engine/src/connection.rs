@@ -1,5 +1,5 @@
 use std::{
-    io::{Read, Write},
+    io::{self, Read, Write},
     net::TcpStream,
 };
 
@@ -32,24 +32,39 @@ impl Connection {
     }
 
     pub fn send(&mut self, message: McbpMessage) {
+        self.try_send(message).unwrap();
+    }
+
+    /// Send a message, failing if the peer has closed the connection
+    pub fn try_send(&mut self, message: McbpMessage) -> io::Result<()> {
         info!("Sent message: {:?}", message);
         self.mcbp_codec
             .encode(message, &mut self.write_buffer)
             .unwrap();
-        self.stream.write_all(&self.write_buffer).unwrap();
+        let result = self.stream.write_all(&self.write_buffer);
         self.write_buffer.clear();
+        result
     }
 
     pub fn recv(&mut self) -> McbpMessage {
+        self.try_recv().expect("connection closed")
+    }
+
+    /// Receive the next message, or None once the peer closes the
+    /// connection
+    pub fn try_recv(&mut self) -> Option<McbpMessage> {
         loop {
             match self.mcbp_codec.decode(&mut self.read_buffer) {
                 Ok(Some(message)) => {
                     info!("Received message: {:?}", message);
-                    return message;
+                    return Some(message);
                 }
                 Ok(None) => {
                     let mut buf = [0; 1024];
-                    let n = self.stream.read(&mut buf).unwrap();
+                    let n = self.stream.read(&mut buf).ok()?;
+                    if n == 0 {
+                        return None;
+                    }
                     self.read_buffer.extend_from_slice(&buf[..n]);
                 }
                 Err(e) => panic!("Error: {:?}", e),
@@ -1,2 +1,7 @@
+pub mod admin;
 pub mod connection;
+pub mod manifest;
+pub mod network;
 pub mod operations;
+pub mod rbac;
+pub mod timings;
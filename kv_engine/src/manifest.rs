@@ -0,0 +1,313 @@
+//! A startup manifest declaring the buckets the node serves, their memory
+//! quotas and the vbuckets it owns, so a standalone node comes up ready
+//! without any orchestration calls. It is loaded from a JSON file such as
+//!
+//! ```json
+//! {
+//!   "buckets": [
+//!     {
+//!       "name": "travel",
+//!       "memory_quota": 268435456,
+//!       "vbuckets": { "active": ["0-511"], "replica": ["512-1022", 1023] }
+//!     }
+//!   ]
+//! }
+//! ```
+//!
+//! Applying it opens each bucket, creating it if needed, and moves the
+//! vbuckets to their states. The vbuckets a bucket doesn't list aren't the
+//! node's, so become dead, but a bucket listing none keeps them all active.
+//! Every vbucket's state is then persisted, creating the files of those
+//! which had none.
+
+use ep_engine::{
+    api::{Engine, EngineError},
+    vbucket::{State, Vbid},
+};
+use serde::Deserialize;
+use std::{collections::HashSet, io, ops::RangeInclusive, path::Path};
+use thiserror::Error;
+
+#[derive(Error, Debug)]
+pub enum ManifestError {
+    #[error(transparent)]
+    Io(#[from] io::Error),
+    #[error("invalid manifest: {0}")]
+    Json(#[from] serde_json::Error),
+    #[error("bucket {0} is listed twice")]
+    DuplicateBucket(String),
+    #[error("bucket {bucket} lists vbucket {vbid} twice")]
+    DuplicateVbucket { bucket: String, vbid: u16 },
+    #[error("bucket {bucket} has no vbucket {vbid}")]
+    VbucketOutOfRange { bucket: String, vbid: u16 },
+    #[error("bucket {bucket}: {error}")]
+    Engine { bucket: String, error: EngineError },
+}
+
+#[derive(Error, Debug, PartialEq, Eq)]
+#[error("invalid vbucket range {0}")]
+pub struct VbucketRangeError(String);
+
+/// Vbuckets first to last inclusive, written as "first-last" or as a
+/// single vbucket's number
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "RangeSpec")]
+pub struct VbucketRange {
+    pub first: u16,
+    pub last: u16,
+}
+
+impl VbucketRange {
+    pub fn vbuckets(&self) -> RangeInclusive<u16> {
+        self.first..=self.last
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum RangeSpec {
+    One(u16),
+    Range(String),
+}
+
+impl TryFrom<RangeSpec> for VbucketRange {
+    type Error = VbucketRangeError;
+
+    fn try_from(spec: RangeSpec) -> Result<Self, Self::Error> {
+        let s = match spec {
+            RangeSpec::One(vbid) => {
+                return Ok(Self {
+                    first: vbid,
+                    last: vbid,
+                })
+            }
+            RangeSpec::Range(s) => s,
+        };
+        let (first, last) = s.split_once('-').unwrap_or((&s, &s));
+        match (first.trim().parse(), last.trim().parse()) {
+            (Ok(first), Ok(last)) if first <= last => Ok(Self { first, last }),
+            _ => Err(VbucketRangeError(s)),
+        }
+    }
+}
+
+/// The vbuckets the node owns, by state
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct VbucketStates {
+    pub active: Vec<VbucketRange>,
+    pub replica: Vec<VbucketRange>,
+    pub pending: Vec<VbucketRange>,
+}
+
+impl VbucketStates {
+    pub fn is_empty(&self) -> bool {
+        self.active.is_empty() && self.replica.is_empty() && self.pending.is_empty()
+    }
+
+    /// Each listed vbucket with its state
+    pub fn states(&self) -> impl Iterator<Item = (u16, State)> + '_ {
+        [
+            (&self.active, State::Active),
+            (&self.replica, State::Replica),
+            (&self.pending, State::Pending),
+        ]
+        .into_iter()
+        .flat_map(|(ranges, state)| {
+            ranges
+                .iter()
+                .flat_map(move |range| range.vbuckets().map(move |vbid| (vbid, state)))
+        })
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct BucketManifest {
+    pub name: String,
+    /// Bytes, the engine's default when not given
+    #[serde(default)]
+    pub memory_quota: Option<usize>,
+    #[serde(default)]
+    pub vbuckets: VbucketStates,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct Manifest {
+    pub buckets: Vec<BucketManifest>,
+}
+
+impl Manifest {
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, ManifestError> {
+        Self::from_json(&std::fs::read_to_string(path)?)
+    }
+
+    pub fn from_json(json: &str) -> Result<Self, ManifestError> {
+        let manifest: Manifest = serde_json::from_str(json)?;
+        let mut names = HashSet::new();
+        for bucket in &manifest.buckets {
+            if !names.insert(&bucket.name) {
+                return Err(ManifestError::DuplicateBucket(bucket.name.clone()));
+            }
+            let mut listed = HashSet::new();
+            if let Some((vbid, _)) = bucket
+                .vbuckets
+                .states()
+                .find(|&(vbid, _)| !listed.insert(vbid))
+            {
+                return Err(ManifestError::DuplicateVbucket {
+                    bucket: bucket.name.clone(),
+                    vbid,
+                });
+            }
+        }
+        Ok(manifest)
+    }
+
+    /// Open each bucket and bring it to the state the manifest declares
+    pub fn apply(&self, engine: &Engine) -> Result<(), ManifestError> {
+        for manifest in &self.buckets {
+            let engine_error = |error| ManifestError::Engine {
+                bucket: manifest.name.clone(),
+                error,
+            };
+            let bucket = engine.bucket(&manifest.name).map_err(engine_error)?;
+            if let Some(quota) = manifest.memory_quota {
+                bucket.set_memory_quota(quota);
+            }
+            if !manifest.vbuckets.is_empty() {
+                let num_vbuckets = bucket.num_vbuckets();
+                let mut states = vec![State::Dead; usize::from(num_vbuckets)];
+                for (vbid, state) in manifest.vbuckets.states() {
+                    if vbid >= num_vbuckets {
+                        return Err(ManifestError::VbucketOutOfRange {
+                            bucket: manifest.name.clone(),
+                            vbid,
+                        });
+                    }
+                    states[usize::from(vbid)] = state;
+                }
+                for (vbid, state) in states.into_iter().enumerate() {
+                    bucket
+                        .set_vbucket_state(Vbid::new(vbid as u16), state)
+                        .map_err(engine_error)?;
+                }
+            }
+            bucket.persist();
+            println!("Applied the manifest to bucket {}", manifest.name);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use ep_engine::vbucket_hash::vbucket_for_key;
+
+    #[test]
+    fn test_manifest_parse() {
+        let manifest = Manifest::from_json(
+            r#"{
+                "buckets": [
+                    { "name": "a", "memory_quota": 1048576,
+                      "vbuckets": { "active": ["0-2", 5], "replica": [" 3 - 4 "] } },
+                    { "name": "b" }
+                ]
+            }"#,
+        )
+        .unwrap();
+        assert_eq!(manifest.buckets[0].memory_quota, Some(1048576));
+        let states: Vec<(u16, State)> = manifest.buckets[0].vbuckets.states().collect();
+        assert_eq!(
+            states,
+            [
+                (0, State::Active),
+                (1, State::Active),
+                (2, State::Active),
+                (5, State::Active),
+                (3, State::Replica),
+                (4, State::Replica)
+            ]
+        );
+        assert!(manifest.buckets[1].vbuckets.is_empty());
+
+        let err = |json: &str| Manifest::from_json(json).unwrap_err().to_string();
+        assert_eq!(
+            err(r#"{"buckets": [{"name": "a"}, {"name": "a"}]}"#),
+            "bucket a is listed twice"
+        );
+        assert_eq!(
+            err(r#"{"buckets": [{"name": "a", "vbuckets": {"active": ["0-3"], "pending": [2]}}]}"#),
+            "bucket a lists vbucket 2 twice"
+        );
+        assert!(
+            err(r#"{"buckets": [{"name": "a", "vbuckets": {"active": ["3-1"]}}]}"#)
+                .contains("invalid vbucket range 3-1")
+        );
+        assert!(
+            err(r#"{"buckets": [{"name": "a", "vbuckets": {"dead": [1]}}]}"#)
+                .contains("unknown field")
+        );
+    }
+
+    #[test]
+    fn test_manifest_apply() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = ep_engine::Config {
+            max_vbuckets: 4,
+            max_shards: 1,
+            dbname: dir.path().to_str().unwrap().to_string(),
+            ..Default::default()
+        };
+        let manifest = Manifest::from_json(
+            r#"{
+                "buckets": [
+                    { "name": "a", "memory_quota": 50000000,
+                      "vbuckets": { "active": ["0-1"], "replica": [2] } }
+                ]
+            }"#,
+        )
+        .unwrap();
+        let engine = Engine::new(config.clone());
+        manifest.apply(&engine).unwrap();
+        let bucket = engine.bucket("a").unwrap();
+        assert_eq!(bucket.stats()["ep_max_size"], "50000000");
+        // Every vbucket has a file, even those never written to
+        let files = std::fs::read_dir(dir.path().join("a"))
+            .unwrap()
+            .filter(|entry| {
+                let name = entry.as_ref().unwrap().file_name();
+                name.to_str().unwrap().contains(".couch.")
+            })
+            .count();
+        assert_eq!(files, 4);
+        engine.shutdown();
+
+        // The states persist, so hold without the manifest
+        let engine = Engine::new(config);
+        let bucket = engine.bucket("a").unwrap();
+        let key_in = |vbid: u16| {
+            (0..)
+                .map(|i| format!("key{i}"))
+                .find(|key| vbucket_for_key(key.as_bytes(), 4) == Vbid::new(vbid))
+                .unwrap()
+        };
+        assert!(bucket.set(key_in(0).as_bytes(), b"value", 0, 0).is_ok());
+        for vbid in [2, 3] {
+            assert_eq!(
+                bucket.set(key_in(vbid).as_bytes(), b"value", 0, 0),
+                Err(EngineError::NotMyVbucket)
+            );
+        }
+
+        let out_of_range = Manifest::from_json(
+            r#"{"buckets": [{ "name": "a", "vbuckets": { "active": ["0-4"] } }]}"#,
+        )
+        .unwrap();
+        assert!(matches!(
+            out_of_range.apply(&engine),
+            Err(ManifestError::VbucketOutOfRange { vbid: 4, .. })
+        ));
+    }
+}
@@ -0,0 +1,349 @@
+//! Which connections the server accepts: the interfaces it listens on, how
+//! many connections each may have, how many a single client address may
+//! have, and allow and deny lists of CIDR ranges. Connections are checked
+//! as they're accepted, before anything is read from them.
+
+use serde::Deserialize;
+use std::{
+    collections::HashMap,
+    fmt,
+    net::IpAddr,
+    str::FromStr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+};
+use thiserror::Error;
+
+#[derive(Error, Debug, PartialEq, Eq)]
+pub enum CidrError {
+    #[error("invalid address in {0}")]
+    InvalidAddress(String),
+    #[error("invalid prefix length in {0}")]
+    InvalidPrefix(String),
+}
+
+/// A range of addresses, such as 10.0.0.0/8. A plain address is a range of
+/// one.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "String")]
+pub struct Cidr {
+    addr: IpAddr,
+    prefix: u8,
+}
+
+impl Cidr {
+    pub fn contains(&self, addr: IpAddr) -> bool {
+        // Clients connecting over IPv6 to a dual-stack socket may have
+        // IPv4-mapped addresses
+        match (self.addr, addr.to_canonical()) {
+            (IpAddr::V4(net), IpAddr::V4(addr)) => {
+                let mask = u32::MAX.checked_shl(32 - self.prefix as u32).unwrap_or(0);
+                u32::from(net) & mask == u32::from(addr) & mask
+            }
+            (IpAddr::V6(net), IpAddr::V6(addr)) => {
+                let mask = u128::MAX.checked_shl(128 - self.prefix as u32).unwrap_or(0);
+                u128::from(net) & mask == u128::from(addr) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+impl FromStr for Cidr {
+    type Err = CidrError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (addr, prefix) = match s.split_once('/') {
+            Some((addr, prefix)) => (addr, Some(prefix)),
+            None => (s, None),
+        };
+        let addr: IpAddr = addr
+            .parse()
+            .map_err(|_| CidrError::InvalidAddress(s.to_string()))?;
+        let max_prefix = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            Some(prefix) => prefix
+                .parse()
+                .ok()
+                .filter(|&prefix| prefix <= max_prefix)
+                .ok_or_else(|| CidrError::InvalidPrefix(s.to_string()))?,
+            None => max_prefix,
+        };
+        Ok(Cidr { addr, prefix })
+    }
+}
+
+impl TryFrom<String> for Cidr {
+    type Error = CidrError;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl fmt::Display for Cidr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.addr, self.prefix)
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct Interface {
+    pub host: String,
+    pub port: u16,
+    /// 0 for unlimited
+    #[serde(default)]
+    pub max_connections: usize,
+}
+
+/// The server's network settings, loaded from a JSON file
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct NetworkConfig {
+    pub interfaces: Vec<Interface>,
+    /// Connections each client address may have open, 0 for unlimited
+    pub max_connections_per_ip: usize,
+    /// When not empty, only clients in these ranges may connect
+    pub allow: Vec<Cidr>,
+    /// Clients in these ranges may not connect, even if allowed
+    pub deny: Vec<Cidr>,
+}
+
+impl Default for NetworkConfig {
+    fn default() -> Self {
+        Self {
+            interfaces: vec![Interface {
+                host: "127.0.0.1".to_string(),
+                port: 11210,
+                max_connections: 0,
+            }],
+            max_connections_per_ip: 0,
+            allow: Vec::new(),
+            deny: Vec::new(),
+        }
+    }
+}
+
+#[derive(Error, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Rejection {
+    #[error("address not allowed")]
+    Denied,
+    #[error("too many connections from the address")]
+    IpLimit,
+    #[error("too many connections on the interface")]
+    InterfaceLimit,
+}
+
+struct Connections {
+    per_ip: HashMap<IpAddr, usize>,
+    per_interface: Vec<usize>,
+}
+
+/// Counts the open connections to enforce the limits. Each accepted
+/// connection holds a [`ConnectionPermit`], which releases its place when
+/// dropped.
+pub struct ConnectionLimiter {
+    config: NetworkConfig,
+    connections: Mutex<Connections>,
+    rejected_denied: AtomicU64,
+    rejected_ip_limit: AtomicU64,
+    rejected_interface_limit: AtomicU64,
+}
+
+pub struct ConnectionPermit {
+    limiter: Arc<ConnectionLimiter>,
+    interface: usize,
+    peer: IpAddr,
+}
+
+impl ConnectionLimiter {
+    pub fn new(config: NetworkConfig) -> Arc<Self> {
+        let connections = Connections {
+            per_ip: HashMap::new(),
+            per_interface: vec![0; config.interfaces.len()],
+        };
+        Arc::new(Self {
+            config,
+            connections: Mutex::new(connections),
+            rejected_denied: AtomicU64::new(0),
+            rejected_ip_limit: AtomicU64::new(0),
+            rejected_interface_limit: AtomicU64::new(0),
+        })
+    }
+
+    pub fn config(&self) -> &NetworkConfig {
+        &self.config
+    }
+
+    /// Check a connection from peer accepted on the config's interface at
+    /// the given index
+    pub fn accept(
+        self: &Arc<Self>,
+        interface: usize,
+        peer: IpAddr,
+    ) -> Result<ConnectionPermit, Rejection> {
+        let peer = peer.to_canonical();
+        if let Err(rejection) = self.try_accept(interface, peer) {
+            let rejected = match rejection {
+                Rejection::Denied => &self.rejected_denied,
+                Rejection::IpLimit => &self.rejected_ip_limit,
+                Rejection::InterfaceLimit => &self.rejected_interface_limit,
+            };
+            rejected.fetch_add(1, Ordering::Relaxed);
+            return Err(rejection);
+        }
+        Ok(ConnectionPermit {
+            limiter: self.clone(),
+            interface,
+            peer,
+        })
+    }
+
+    fn try_accept(&self, interface: usize, peer: IpAddr) -> Result<(), Rejection> {
+        let allowed = self.config.allow.is_empty()
+            || self.config.allow.iter().any(|cidr| cidr.contains(peer));
+        if !allowed || self.config.deny.iter().any(|cidr| cidr.contains(peer)) {
+            return Err(Rejection::Denied);
+        }
+
+        let mut connections = self.connections.lock().unwrap();
+        let max_connections = self.config.interfaces[interface].max_connections;
+        if max_connections != 0 && connections.per_interface[interface] >= max_connections {
+            return Err(Rejection::InterfaceLimit);
+        }
+        let from_peer = connections.per_ip.get(&peer).copied().unwrap_or(0);
+        let max_per_ip = self.config.max_connections_per_ip;
+        if max_per_ip != 0 && from_peer >= max_per_ip {
+            return Err(Rejection::IpLimit);
+        }
+        connections.per_ip.insert(peer, from_peer + 1);
+        connections.per_interface[interface] += 1;
+        Ok(())
+    }
+
+    pub fn get_stats(&self, add_stat: &mut dyn FnMut(&str, &str)) {
+        let connections = self.connections.lock().unwrap();
+        let curr_connections: usize = connections.per_interface.iter().sum();
+        add_stat("curr_connections", &curr_connections.to_string());
+        for (interface, count) in self
+            .config
+            .interfaces
+            .iter()
+            .zip(&connections.per_interface)
+        {
+            add_stat(
+                &format!("curr_connections_{}:{}", interface.host, interface.port),
+                &count.to_string(),
+            );
+        }
+        add_stat(
+            "rejected_conns_denied",
+            &self.rejected_denied.load(Ordering::Relaxed).to_string(),
+        );
+        add_stat(
+            "rejected_conns_ip_limit",
+            &self.rejected_ip_limit.load(Ordering::Relaxed).to_string(),
+        );
+        add_stat(
+            "rejected_conns_interface_limit",
+            &self
+                .rejected_interface_limit
+                .load(Ordering::Relaxed)
+                .to_string(),
+        );
+    }
+}
+
+impl Drop for ConnectionPermit {
+    fn drop(&mut self) {
+        let mut connections = self.limiter.connections.lock().unwrap();
+        connections.per_interface[self.interface] -= 1;
+        let from_peer = connections.per_ip.get_mut(&self.peer).unwrap();
+        *from_peer -= 1;
+        if *from_peer == 0 {
+            connections.per_ip.remove(&self.peer);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_cidr() {
+        let cidr: Cidr = "10.1.0.0/16".parse().unwrap();
+        assert!(cidr.contains("10.1.200.3".parse().unwrap()));
+        assert!(!cidr.contains("10.2.0.1".parse().unwrap()));
+        assert!(cidr.contains("::ffff:10.1.0.1".parse().unwrap()));
+        assert!(!cidr.contains("fe80::1".parse().unwrap()));
+
+        let any: Cidr = "0.0.0.0/0".parse().unwrap();
+        assert!(any.contains("192.168.1.1".parse().unwrap()));
+        let one: Cidr = "fe80::1".parse().unwrap();
+        assert_eq!(one.to_string(), "fe80::1/128");
+        assert!(one.contains("fe80::1".parse().unwrap()));
+        assert!(!one.contains("fe80::2".parse().unwrap()));
+
+        assert_eq!(
+            "10.0.0.0/33".parse::<Cidr>(),
+            Err(CidrError::InvalidPrefix("10.0.0.0/33".to_string()))
+        );
+        assert!("10.0.0/8".parse::<Cidr>().is_err());
+    }
+
+    #[test]
+    fn test_connection_limiter() {
+        let config: NetworkConfig = serde_json::from_str(
+            r#"{
+                "interfaces": [
+                    { "host": "0.0.0.0", "port": 11210, "max_connections": 3 },
+                    { "host": "0.0.0.0", "port": 11207 }
+                ],
+                "max_connections_per_ip": 2,
+                "allow": ["10.0.0.0/8"],
+                "deny": ["10.0.5.0/24"]
+            }"#,
+        )
+        .unwrap();
+        let limiter = ConnectionLimiter::new(config);
+        let ip = |s: &str| s.parse().unwrap();
+
+        assert_eq!(
+            limiter.accept(0, ip("192.168.0.1")).err(),
+            Some(Rejection::Denied)
+        );
+        assert_eq!(
+            limiter.accept(0, ip("10.0.5.1")).err(),
+            Some(Rejection::Denied)
+        );
+        let first = limiter.accept(0, ip("10.0.0.1")).unwrap();
+        let _second = limiter.accept(1, ip("10.0.0.1")).unwrap();
+        assert_eq!(
+            limiter.accept(1, ip("10.0.0.1")).err(),
+            Some(Rejection::IpLimit)
+        );
+        let _third = limiter.accept(0, ip("10.0.0.2")).unwrap();
+        let _fourth = limiter.accept(0, ip("10.0.0.3")).unwrap();
+        assert_eq!(
+            limiter.accept(0, ip("10.0.0.4")).err(),
+            Some(Rejection::InterfaceLimit)
+        );
+
+        // Closing a connection makes room for another
+        drop(first);
+        let _fifth = limiter.accept(0, ip("10.0.0.1")).unwrap();
+
+        let mut stats = HashMap::new();
+        limiter.get_stats(&mut |key, value| {
+            stats.insert(key.to_string(), value.to_string());
+        });
+        assert_eq!(stats["curr_connections"], "4");
+        assert_eq!(stats["curr_connections_0.0.0.0:11210"], "3");
+        assert_eq!(stats["rejected_conns_denied"], "2");
+        assert_eq!(stats["rejected_conns_ip_limit"], "1");
+        assert_eq!(stats["rejected_conns_interface_limit"], "1");
+    }
+}
@@ -1,5 +1,7 @@
 use std::collections::HashMap;
 
+use ep_engine::{vbucket::Vbid, vbucket_hash::vbucket_for_key};
+
 #[derive(Debug, Clone, serde::Deserialize, serde::Serialize)]
 #[serde(rename_all = "camelCase")]
 pub struct ClusterConfig {
@@ -35,6 +37,53 @@ pub struct VBucketServerMap {
     pub v_bucket_map: Vec<Vec<i32>>,
 }
 
+impl VBucketServerMap {
+    /// Spread num_vbuckets over the servers, the actives round-robin and
+    /// each replica on the servers following its active. Replicas without a
+    /// server of their own are -1.
+    pub fn new(server_list: Vec<String>, num_vbuckets: u16, num_replicas: u32) -> Self {
+        let servers = server_list.len();
+        let v_bucket_map = (0..num_vbuckets as usize)
+            .map(|vbid| {
+                (0..=num_replicas as usize)
+                    .map(|copy| match copy < servers {
+                        true => ((vbid + copy) % servers) as i32,
+                        false => -1,
+                    })
+                    .collect()
+            })
+            .collect();
+        VBucketServerMap {
+            hash_algorithm: "CRC".to_string(),
+            num_replicas,
+            server_list,
+            v_bucket_map,
+        }
+    }
+
+    pub fn num_vbuckets(&self) -> u16 {
+        self.v_bucket_map.len() as u16
+    }
+
+    /// The vbucket a client's key is in
+    pub fn vbucket_for_key(&self, key: &[u8]) -> Vbid {
+        vbucket_for_key(key, self.num_vbuckets())
+    }
+
+    /// The server holding copy 0 (the active) or replica n of the vbucket,
+    /// None if it isn't assigned one
+    pub fn server(&self, vbid: Vbid, copy: usize) -> Option<&str> {
+        let index = *self.v_bucket_map.get(usize::from(vbid))?.get(copy)?;
+        let index = usize::try_from(index).ok()?;
+        self.server_list.get(index).map(String::as_str)
+    }
+
+    /// The server a client sends the key's operations to
+    pub fn active_server_for_key(&self, key: &[u8]) -> Option<&str> {
+        self.server(self.vbucket_for_key(key), 0)
+    }
+}
+
 #[derive(Debug, Clone, serde::Deserialize, serde::Serialize)]
 #[serde(rename_all = "camelCase")]
 pub struct Node {
@@ -76,3 +125,30 @@ impl GetClusterConfigResponse {
         Ok(GetClusterConfigResponse { config })
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_vbucket_server_map() {
+        let servers = vec!["a:11210".to_string(), "b:11210".to_string()];
+        let map = VBucketServerMap::new(servers, 1024, 2);
+        assert_eq!(map.num_vbuckets(), 1024);
+        assert_eq!(map.v_bucket_map[0], [0, 1, -1]);
+        assert_eq!(map.v_bucket_map[1], [1, 0, -1]);
+        assert_eq!(map.server(Vbid::new(1), 1), Some("a:11210"));
+        assert_eq!(map.server(Vbid::new(1), 2), None);
+        assert_eq!(map.server(Vbid::new(1024), 0), None);
+
+        // "foo" is in vbucket 115
+        assert_eq!(map.vbucket_for_key(b"foo"), Vbid::new(115));
+        assert_eq!(map.active_server_for_key(b"foo"), Some("b:11210"));
+
+        // Survives the round trip through the cluster config JSON
+        let json = serde_json::to_value(&map).unwrap();
+        assert_eq!(json["hashAlgorithm"], "CRC");
+        let map: VBucketServerMap = serde_json::from_value(json).unwrap();
+        assert_eq!(map.active_server_for_key(b"foo"), Some("b:11210"));
+    }
+}
@@ -1,6 +1,14 @@
+use std::sync::Arc;
+
 use bitflags::bitflags;
-use bytes::{BufMut, BytesMut};
-use memcached_codec::{McbpMessage, McbpMessageBuilder, Opcode};
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use ep_engine::{
+    api::{DcpResponse, EndStreamStatus},
+    dcp::response::{SnapshotMarkerFlags, SystemEventId},
+    item::{Datatype, DeleteSource, Item},
+    vbucket::{State, Vbid},
+};
+use memcached_codec::{Cas, DataType, McbpDecodeError, McbpMessage, McbpMessageBuilder, Opcode};
 
 pub type VbUuid = u64;
 
@@ -12,6 +20,8 @@ pub struct DcpStreamRequest {
     pub vb_uuid: VbUuid,
     pub snap_start_seqno: u64,
     pub snap_end_seqno: u64,
+    /// JSON selecting the collections to stream, e.g. {"collections":["8"]}
+    pub filter: Option<String>,
 }
 
 bitflags! {
@@ -38,8 +48,29 @@ impl DcpStreamRequest {
         McbpMessageBuilder::new(Opcode::DcpStreamRequest)
             .extras(extras)
             .vbucket(self.vbucket)
+            .value(self.filter.unwrap_or_default())
             .build()
     }
+
+    pub fn decode(msg: &McbpMessage) -> Result<DcpStreamRequest, McbpDecodeError> {
+        let mut extras = &msg.extras[..];
+        if extras.len() != 48 {
+            return Err(McbpDecodeError::InvalidExtrasLength(extras.len()));
+        }
+        let flags = DcpStreamAddFlag::from_bits_retain(extras.get_u32());
+        extras.advance(4);
+        Ok(DcpStreamRequest {
+            vbucket: msg.try_vbucket()?,
+            flags,
+            start_seqno: extras.get_u64(),
+            end_seqno: extras.get_u64(),
+            vb_uuid: extras.get_u64(),
+            snap_start_seqno: extras.get_u64(),
+            snap_end_seqno: extras.get_u64(),
+            filter: (!msg.value.is_empty())
+                .then(|| String::from_utf8_lossy(&msg.value).into_owned()),
+        })
+    }
 }
 
 pub struct DcpOpenConnectionRequest {
@@ -68,6 +99,18 @@ impl DcpOpenConnectionRequest {
             .extras(extras)
             .build()
     }
+
+    pub fn decode(msg: &McbpMessage) -> Result<DcpOpenConnectionRequest, McbpDecodeError> {
+        let mut extras = &msg.extras[..];
+        if extras.len() != 8 {
+            return Err(McbpDecodeError::InvalidExtrasLength(extras.len()));
+        }
+        extras.advance(4);
+        Ok(DcpOpenConnectionRequest {
+            stream_name: String::from_utf8_lossy(&msg.key).into_owned(),
+            flags: DcpOpenFlag::from_bits_retain(extras.get_u32()),
+        })
+    }
 }
 
 pub struct DcpControlRequest {
@@ -82,4 +125,425 @@ impl DcpControlRequest {
             .value(self.value)
             .build()
     }
+
+    pub fn decode(msg: &McbpMessage) -> DcpControlRequest {
+        DcpControlRequest {
+            key: String::from_utf8_lossy(&msg.key).into_owned(),
+            value: String::from_utf8_lossy(&msg.value).into_owned(),
+        }
+    }
+}
+
+/// Tells the producer the consumer has processed the bytes, freeing that
+/// much of the connection's flow control buffer
+pub struct DcpBufferAcknowledgementRequest {
+    pub bytes: u32,
+}
+
+impl DcpBufferAcknowledgementRequest {
+    pub fn encode(self) -> McbpMessage {
+        let mut extras = BytesMut::with_capacity(4);
+        extras.put_u32(self.bytes);
+        McbpMessageBuilder::new(Opcode::DcpBufferAcknowledgement)
+            .extras(extras)
+            .build()
+    }
+
+    pub fn decode(msg: &McbpMessage) -> Result<DcpBufferAcknowledgementRequest, McbpDecodeError> {
+        let mut extras = &msg.extras[..];
+        if extras.len() != 4 {
+            return Err(McbpDecodeError::InvalidExtrasLength(extras.len()));
+        }
+        Ok(DcpBufferAcknowledgementRequest {
+            bytes: extras.get_u32(),
+        })
+    }
+}
+
+/// A message of one of a producer's streams, as the engine's producer
+/// sends it and its consumer applies it. The extras are those counted by
+/// DcpResponse::message_size, so both ends agree on the flow control
+/// buffer's use.
+#[derive(Debug, Clone)]
+pub struct DcpStreamMessage(pub DcpResponse);
+
+fn vbucket_state_to_u8(state: State) -> u8 {
+    match state {
+        State::Active => 1,
+        State::Replica => 2,
+        State::Pending => 3,
+        State::Dead => 4,
+    }
+}
+
+fn vbucket_state_from_u8(state: u8) -> Result<State, McbpDecodeError> {
+    match state {
+        1 => Ok(State::Active),
+        2 => Ok(State::Replica),
+        3 => Ok(State::Pending),
+        4 => Ok(State::Dead),
+        _ => Err(McbpDecodeError::InvalidExtrasValue(state.into())),
+    }
+}
+
+fn end_stream_status_from_u32(status: u32) -> Result<EndStreamStatus, McbpDecodeError> {
+    Ok(match status {
+        0 => EndStreamStatus::Ok,
+        1 => EndStreamStatus::Closed,
+        2 => EndStreamStatus::StateChanged,
+        3 => EndStreamStatus::Disconnected,
+        4 => EndStreamStatus::Slow,
+        5 => EndStreamStatus::BackfillFailed,
+        _ => return Err(McbpDecodeError::InvalidExtrasValue(status)),
+    })
+}
+
+fn system_event_from_u32(event: u32) -> Result<SystemEventId, McbpDecodeError> {
+    Ok(match event {
+        0 => SystemEventId::CreateCollection,
+        1 => SystemEventId::DropCollection,
+        5 => SystemEventId::ModifyCollection,
+        _ => return Err(McbpDecodeError::InvalidExtrasValue(event)),
+    })
+}
+
+impl DcpStreamMessage {
+    pub fn encode(&self) -> McbpMessage {
+        let mut extras = BytesMut::new();
+        let (opcode, vbid, opaque) = match &self.0 {
+            DcpResponse::SnapshotMarker {
+                opaque,
+                vbid,
+                start_seqno,
+                end_seqno,
+                flags,
+            } => {
+                extras.put_u64(*start_seqno);
+                extras.put_u64(*end_seqno);
+                extras.put_u32(flags.bits());
+                (Opcode::DcpSnapshotMarker, *vbid, *opaque)
+            }
+            DcpResponse::Mutation { opaque, vbid, item } => {
+                extras.put_u64(item.by_seqno);
+                extras.put_u64(item.rev_seqno);
+                extras.put_u32(item.flags);
+                extras.put_u32(item.expiry_time);
+                // lock time, nmeta, nru
+                extras.put_u32(0);
+                extras.put_u16(0);
+                extras.put_u8(0);
+                (Opcode::DcpMutation, *vbid, *opaque)
+            }
+            DcpResponse::Deletion {
+                opaque,
+                vbid,
+                item,
+                include_delete_time,
+            } => {
+                extras.put_u64(item.by_seqno);
+                extras.put_u64(item.rev_seqno);
+                if *include_delete_time {
+                    extras.put_u32(item.expiry_time);
+                    extras.put_u8(0);
+                } else {
+                    extras.put_u16(0);
+                }
+                (Opcode::DcpDeletion, *vbid, *opaque)
+            }
+            DcpResponse::Expiration { opaque, vbid, item } => {
+                extras.put_u64(item.by_seqno);
+                extras.put_u64(item.rev_seqno);
+                extras.put_u32(item.expiry_time);
+                (Opcode::DcpExpiration, *vbid, *opaque)
+            }
+            DcpResponse::SystemEvent {
+                opaque,
+                vbid,
+                item,
+                event,
+            } => {
+                extras.put_u64(item.by_seqno);
+                extras.put_u32(*event as u32);
+                // version
+                extras.put_u8(0);
+                (Opcode::DcpSystemEvent, *vbid, *opaque)
+            }
+            DcpResponse::StreamEnd {
+                opaque,
+                vbid,
+                status,
+            } => {
+                extras.put_u32(*status as u32);
+                (Opcode::DcpStreamEnd, *vbid, *opaque)
+            }
+            DcpResponse::SetVBucketState {
+                opaque,
+                vbid,
+                state,
+            } => {
+                extras.put_u8(vbucket_state_to_u8(*state));
+                (Opcode::DcpSetVbucketState, *vbid, *opaque)
+            }
+            DcpResponse::Noop { opaque } => (Opcode::DcpNoop, Vbid::new(0), *opaque),
+        };
+        let mut builder = McbpMessageBuilder::new(opcode)
+            .vbucket(vbid.into())
+            .opaque(opaque)
+            .extras(extras);
+        if let Some(item) = self.0.item() {
+            builder = builder
+                .cas(Cas::from(item.cas))
+                .data_type(DataType::from_bits_retain(item.datatype.bits()))
+                .key(item.key.clone());
+            // Deletions and expirations carry no value
+            if matches!(
+                self.0,
+                DcpResponse::Mutation { .. } | DcpResponse::SystemEvent { .. }
+            ) {
+                builder = builder.value(item.value.clone().unwrap_or_default());
+            }
+        }
+        builder.build()
+    }
+
+    pub fn decode(msg: &McbpMessage) -> Result<DcpStreamMessage, McbpDecodeError> {
+        let mut extras = &msg.extras[..];
+        let expected_extras = match msg.opcode {
+            Opcode::DcpSnapshotMarker => 20,
+            Opcode::DcpMutation => 31,
+            Opcode::DcpDeletion if extras.len() == 21 => 21,
+            Opcode::DcpDeletion => 18,
+            Opcode::DcpExpiration => 20,
+            Opcode::DcpSystemEvent => 13,
+            Opcode::DcpStreamEnd => 4,
+            Opcode::DcpSetVbucketState => 1,
+            Opcode::DcpNoop => 0,
+            opcode => return Err(McbpDecodeError::InvalidOpcode(opcode.into())),
+        };
+        if extras.len() != expected_extras {
+            return Err(McbpDecodeError::InvalidExtrasLength(extras.len()));
+        }
+        let opaque = msg.opaque;
+        if msg.opcode == Opcode::DcpNoop {
+            return Ok(DcpStreamMessage(DcpResponse::Noop { opaque }));
+        }
+        let vbid = Vbid::new(msg.try_vbucket()?);
+        let item = |by_seqno, rev_seqno, value: Option<&Bytes>| Item {
+            key: msg.key.to_vec(),
+            value: value.map(|value| value.to_vec()),
+            cas: u64::from(msg.cas),
+            expiry_time: 0,
+            flags: 0,
+            by_seqno,
+            rev_seqno,
+            delete_source: DeleteSource::Explicit,
+            datatype: Datatype::from_bits_retain(msg.data_type.bits()),
+        };
+        let response = match msg.opcode {
+            Opcode::DcpSnapshotMarker => DcpResponse::SnapshotMarker {
+                opaque,
+                vbid,
+                start_seqno: extras.get_u64(),
+                end_seqno: extras.get_u64(),
+                flags: SnapshotMarkerFlags::from_bits_retain(extras.get_u32()),
+            },
+            Opcode::DcpMutation => {
+                let mut item = item(extras.get_u64(), extras.get_u64(), Some(&msg.value));
+                item.flags = extras.get_u32();
+                item.expiry_time = extras.get_u32();
+                DcpResponse::Mutation {
+                    opaque,
+                    vbid,
+                    item: Arc::new(item),
+                }
+            }
+            Opcode::DcpDeletion => {
+                let mut item = item(extras.get_u64(), extras.get_u64(), None);
+                let include_delete_time = expected_extras == 21;
+                if include_delete_time {
+                    item.expiry_time = extras.get_u32();
+                }
+                DcpResponse::Deletion {
+                    opaque,
+                    vbid,
+                    item: Arc::new(item),
+                    include_delete_time,
+                }
+            }
+            Opcode::DcpExpiration => {
+                let mut item = item(extras.get_u64(), extras.get_u64(), None);
+                item.expiry_time = extras.get_u32();
+                item.delete_source = DeleteSource::Ttl;
+                DcpResponse::Expiration {
+                    opaque,
+                    vbid,
+                    item: Arc::new(item),
+                }
+            }
+            Opcode::DcpSystemEvent => {
+                let item = item(extras.get_u64(), 0, Some(&msg.value));
+                DcpResponse::SystemEvent {
+                    opaque,
+                    vbid,
+                    item: Arc::new(item),
+                    event: system_event_from_u32(extras.get_u32())?,
+                }
+            }
+            Opcode::DcpStreamEnd => DcpResponse::StreamEnd {
+                opaque,
+                vbid,
+                status: end_stream_status_from_u32(extras.get_u32())?,
+            },
+            // DcpSetVbucketState, the only opcode left
+            _ => DcpResponse::SetVBucketState {
+                opaque,
+                vbid,
+                state: vbucket_state_from_u8(extras.get_u8())?,
+            },
+        };
+        Ok(DcpStreamMessage(response))
+    }
+}
+
+/// A deletion or expiration received from a DCP producer. The delete time is
+/// only sent if the connection was opened with INCLUDE_DELETE_TIMES.
+#[derive(Debug, Clone)]
+pub struct DcpDeletion {
+    pub vbucket: u16,
+    pub key: Bytes,
+    pub cas: Cas,
+    pub by_seqno: u64,
+    pub rev_seqno: u64,
+    pub delete_time: Option<u32>,
+    /// The item was deleted because its expiry time passed
+    pub expired: bool,
+}
+
+impl DcpDeletion {
+    pub fn decode(msg: &McbpMessage) -> Result<DcpDeletion, McbpDecodeError> {
+        let mut extras = &msg.extras[..];
+        let expired = msg.opcode == Opcode::DcpExpiration;
+        let with_delete_time = match (expired, extras.len()) {
+            // by_seqno, rev_seqno, nmeta
+            (false, 18) => false,
+            // by_seqno, rev_seqno, delete time, unused
+            (false, 21) => true,
+            // by_seqno, rev_seqno, delete time
+            (true, 20) => true,
+            (_, len) => return Err(McbpDecodeError::InvalidExtrasLength(len)),
+        };
+        let by_seqno = extras.get_u64();
+        let rev_seqno = extras.get_u64();
+        let delete_time = with_delete_time.then(|| extras.get_u32());
+        Ok(DcpDeletion {
+            vbucket: msg.try_vbucket()?,
+            key: msg.key.clone(),
+            cas: msg.cas,
+            by_seqno,
+            rev_seqno,
+            delete_time,
+            expired,
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use memcached_codec::McbpCodec;
+    use tokio_util::codec::{Decoder, Encoder};
+
+    #[test]
+    fn test_stream_message_round_trip() {
+        let item = Arc::new(Item {
+            key: b"key".to_vec(),
+            value: Some(b"{\"a\":1}".to_vec()),
+            cas: 1234,
+            expiry_time: 99,
+            flags: 7,
+            by_seqno: 12,
+            rev_seqno: 3,
+            delete_source: DeleteSource::Explicit,
+            datatype: Datatype::JSON,
+        });
+        // Only mutations carry the flags and expiry time, and system events
+        // have no revision either
+        let mut deleted = (*item).clone();
+        deleted.value = None;
+        deleted.flags = 0;
+        let mut event = deleted.clone();
+        event.value = Some(b"{}".to_vec());
+        event.expiry_time = 0;
+        event.rev_seqno = 0;
+        let mut expired = deleted.clone();
+        expired.delete_source = DeleteSource::Ttl;
+        let deleted = Arc::new(deleted);
+        let vbid = Vbid::new(5);
+        let responses = [
+            DcpResponse::SnapshotMarker {
+                opaque: 1,
+                vbid,
+                start_seqno: 10,
+                end_seqno: 20,
+                flags: SnapshotMarkerFlags::MEMORY | SnapshotMarkerFlags::CHECKPOINT,
+            },
+            DcpResponse::Mutation {
+                opaque: 1,
+                vbid,
+                item,
+            },
+            DcpResponse::Deletion {
+                opaque: 1,
+                vbid,
+                item: deleted,
+                include_delete_time: true,
+            },
+            DcpResponse::Expiration {
+                opaque: 1,
+                vbid,
+                item: Arc::new(expired),
+            },
+            DcpResponse::SystemEvent {
+                opaque: 1,
+                vbid,
+                item: Arc::new(event),
+                event: SystemEventId::ModifyCollection,
+            },
+            DcpResponse::StreamEnd {
+                opaque: 1,
+                vbid,
+                status: EndStreamStatus::StateChanged,
+            },
+            DcpResponse::SetVBucketState {
+                opaque: 1,
+                vbid,
+                state: State::Pending,
+            },
+            DcpResponse::Noop { opaque: 2 },
+        ];
+        let mut codec = McbpCodec::new();
+        for response in responses {
+            let mut buf = BytesMut::new();
+            codec
+                .encode(DcpStreamMessage(response.clone()).encode(), &mut buf)
+                .unwrap();
+            // The flow control buffer is charged what goes on the wire
+            assert_eq!(buf.len(), response.message_size(), "{response:?}");
+            let message = codec.decode(&mut buf).unwrap().unwrap();
+            let decoded = DcpStreamMessage::decode(&message).unwrap().0;
+            assert_eq!(format!("{decoded:?}"), format!("{response:?}"));
+        }
+
+        let mut bad = DcpStreamMessage(DcpResponse::StreamEnd {
+            opaque: 1,
+            vbid,
+            status: EndStreamStatus::Ok,
+        })
+        .encode();
+        bad.extras = Bytes::from_static(&[0, 0, 0, 9]);
+        assert!(matches!(
+            DcpStreamMessage::decode(&bad),
+            Err(McbpDecodeError::InvalidExtrasValue(9))
+        ));
+    }
 }
@@ -4,7 +4,7 @@ use memcached_codec::{
     Cas, DataType, McbpDecodeError, McbpMessage, McbpMessageBuilder, Opcode, Status,
 };
 
-use super::v_bucket_hash;
+use ep_engine::vbucket_hash::vbucket_for_key;
 
 #[derive(Debug)]
 pub struct GetRequest {
@@ -24,7 +24,7 @@ impl GetRequest {
     pub fn encode(&self) -> McbpMessage {
         McbpMessageBuilder::new(Opcode::Get)
             .key(self.key.clone())
-            .vbucket(v_bucket_hash(&self.key, 1024))
+            .vbucket(vbucket_for_key(&self.key, 1024).into())
             .build()
     }
 
@@ -1,12 +1,15 @@
 use bytes::{BufMut, BytesMut};
-use memcached_codec::{McbpDecodeError, McbpMessage, McbpMessageBuilder, Opcode};
+use memcached_codec::{McbpDecodeError, McbpMessage, McbpMessageBuilder, Opcode, Status};
 
+#[derive(Debug)]
 pub enum SaslAuthRequest {
     Plain { username: String, password: String },
 }
 
 #[derive(Debug, Clone, Copy)]
-pub struct SaslAuthResponse {}
+pub struct SaslAuthResponse {
+    pub status: Status,
+}
 
 impl SaslAuthRequest {
     pub fn encode(&self) -> McbpMessage {
@@ -26,10 +29,30 @@ impl SaslAuthRequest {
         }
         builder.build()
     }
+
+    /// Decode a PLAIN request, whose value is an optional authorization
+    /// identity, the username and the password, each preceded by a zero
+    /// byte. Returns None for other mechanisms or a malformed value.
+    pub fn decode(message: &McbpMessage) -> Option<SaslAuthRequest> {
+        if message.key != "PLAIN" {
+            return None;
+        }
+        let mut parts = message.value.split(|&byte| byte == 0);
+        let (_authzid, username, password) = (parts.next()?, parts.next()?, parts.next()?);
+        if parts.next().is_some() {
+            return None;
+        }
+        Some(SaslAuthRequest::Plain {
+            username: String::from_utf8(username.to_vec()).ok()?,
+            password: String::from_utf8(password.to_vec()).ok()?,
+        })
+    }
 }
 
 impl SaslAuthResponse {
-    pub fn decode(_message: &McbpMessage) -> Result<SaslAuthResponse, McbpDecodeError> {
-        Ok(SaslAuthResponse {})
+    pub fn decode(message: &McbpMessage) -> Result<SaslAuthResponse, McbpDecodeError> {
+        Ok(SaslAuthResponse {
+            status: message.try_status()?,
+        })
     }
 }
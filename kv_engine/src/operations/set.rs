@@ -4,7 +4,7 @@ use memcached_codec::{
     Cas, DataType, McbpDecodeError, McbpMessage, McbpMessageBuilder, Opcode, Status,
 };
 
-use super::v_bucket_hash;
+use ep_engine::vbucket_hash::vbucket_for_key;
 
 #[derive(Debug)]
 pub struct SetRequest {
@@ -23,7 +23,10 @@ impl SetRequest {
     pub fn encode(&self) -> McbpMessage {
         McbpMessageBuilder::new(Opcode::Upsert)
             .key(self.key.clone())
-            .vbucket(v_bucket_hash(&self.key, 1024))
+            .value(self.value.clone())
+            // Flags and expiry time
+            .extras(vec![0; 8])
+            .vbucket(vbucket_for_key(&self.key, 1024).into())
             .build()
     }
 
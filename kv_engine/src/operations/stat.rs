@@ -0,0 +1,56 @@
+use memcached_codec::{McbpDecodeError, McbpMessage, McbpMessageBuilder, Opcode, Status};
+
+/// Request a group of stats, or the default group if empty. The server
+/// sends a response per stat, then one with an empty key.
+#[derive(Debug)]
+pub struct StatRequest {
+    pub group: String,
+}
+
+#[derive(Debug, Clone)]
+pub struct StatResponse {
+    pub key: String,
+    pub value: String,
+}
+
+impl StatRequest {
+    pub fn encode(&self) -> McbpMessage {
+        McbpMessageBuilder::new(Opcode::Stat)
+            .key(self.group.clone())
+            .build()
+    }
+
+    pub fn decode(message: &McbpMessage) -> Result<StatRequest, McbpDecodeError> {
+        Ok(StatRequest {
+            group: String::from_utf8_lossy(&message.key).into_owned(),
+        })
+    }
+}
+
+impl StatResponse {
+    pub fn encode(&self) -> McbpMessage {
+        McbpMessageBuilder::new(Opcode::Stat)
+            .status(Status::Success)
+            .key(self.key.clone())
+            .value(self.value.clone())
+            .build()
+    }
+
+    /// The end of the stats
+    pub fn encode_end() -> McbpMessage {
+        McbpMessageBuilder::new(Opcode::Stat)
+            .status(Status::Success)
+            .build()
+    }
+
+    /// Decode a stat, None for the response ending the stats
+    pub fn decode(message: &McbpMessage) -> Result<Option<StatResponse>, McbpDecodeError> {
+        if message.key.is_empty() {
+            return Ok(None);
+        }
+        Ok(Some(StatResponse {
+            key: String::from_utf8_lossy(&message.key).into_owned(),
+            value: String::from_utf8_lossy(&message.value).into_owned(),
+        }))
+    }
+}
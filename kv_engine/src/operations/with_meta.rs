@@ -0,0 +1,69 @@
+use bytes::{BufMut, Bytes, BytesMut};
+
+use memcached_codec::{Cas, DataType, McbpMessage, McbpMessageBuilder, Opcode};
+
+/// Store a document keeping the CAS, revision and flags it had on another
+/// node, as done when replicating or restoring data
+#[derive(Debug)]
+pub struct SetWithMetaRequest {
+    pub key: Bytes,
+    pub value: Bytes,
+    pub vbucket: u16,
+    pub flags: u32,
+    pub expiry: u32,
+    pub rev_seqno: u64,
+    pub cas: Cas,
+    pub data_type: DataType,
+}
+
+/// Delete a document keeping the CAS and revision it had on another node
+#[derive(Debug)]
+pub struct DelWithMetaRequest {
+    pub key: Bytes,
+    pub vbucket: u16,
+    /// The time the document was deleted
+    pub delete_time: u32,
+    pub rev_seqno: u64,
+    pub cas: Cas,
+}
+
+fn encode_meta_extras(flags: u32, expiry: u32, rev_seqno: u64, cas: Cas) -> BytesMut {
+    let mut extras = BytesMut::with_capacity(24);
+    extras.put_u32(flags);
+    extras.put_u32(expiry);
+    extras.put_u64(rev_seqno);
+    extras.put_u64(cas.into());
+    extras
+}
+
+impl SetWithMetaRequest {
+    pub fn encode(&self) -> McbpMessage {
+        McbpMessageBuilder::new(Opcode::SetWithMeta)
+            .key(self.key.clone())
+            .value(self.value.clone())
+            .vbucket(self.vbucket)
+            .data_type(self.data_type)
+            .extras(encode_meta_extras(
+                self.flags,
+                self.expiry,
+                self.rev_seqno,
+                self.cas,
+            ))
+            .build()
+    }
+}
+
+impl DelWithMetaRequest {
+    pub fn encode(&self) -> McbpMessage {
+        McbpMessageBuilder::new(Opcode::DelWithMeta)
+            .key(self.key.clone())
+            .vbucket(self.vbucket)
+            .extras(encode_meta_extras(
+                0,
+                self.delete_time,
+                self.rev_seqno,
+                self.cas,
+            ))
+            .build()
+    }
+}
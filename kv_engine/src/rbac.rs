@@ -0,0 +1,216 @@
+//! Role-based access control: which users may do what, on which buckets,
+//! scopes and collections. Users and their grants are loaded from a JSON
+//! file such as
+//!
+//! ```json
+//! {
+//!   "users": {
+//!     "alice": {
+//!       "password": "secret",
+//!       "grants": [
+//!         { "bucket": "travel", "scope": "inventory", "privileges": ["read", "write"] },
+//!         { "bucket": "*", "privileges": ["stats"] }
+//!       ]
+//!     }
+//!   }
+//! }
+//! ```
+//!
+//! A grant without a scope covers the whole bucket, one without a
+//! collection the whole scope, and a bucket of "*" covers every bucket.
+
+use serde::Deserialize;
+use std::{collections::HashMap, io, path::Path};
+use thiserror::Error;
+
+#[derive(Error, Debug)]
+pub enum RbacError {
+    #[error(transparent)]
+    Io(#[from] io::Error),
+    #[error("invalid RBAC file: {0}")]
+    Json(#[from] serde_json::Error),
+    #[error("user {0} has a grant naming a collection but not its scope")]
+    CollectionWithoutScope(String),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Privilege {
+    Read,
+    Write,
+    /// Open DCP streams
+    Dcp,
+    Stats,
+    /// Every privilege on what the grant covers
+    Admin,
+}
+
+impl Privilege {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Privilege::Read => "read",
+            Privilege::Write => "write",
+            Privilege::Dcp => "dcp",
+            Privilege::Stats => "stats",
+            Privilege::Admin => "admin",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct Grant {
+    pub bucket: String,
+    #[serde(default)]
+    pub scope: Option<String>,
+    #[serde(default)]
+    pub collection: Option<String>,
+    pub privileges: Vec<Privilege>,
+}
+
+impl Grant {
+    /// Whether the grant covers the target. A target without a scope is
+    /// the bucket as a whole, which only a bucket-wide grant covers.
+    fn covers(&self, bucket: &str, scope: Option<&str>, collection: Option<&str>) -> bool {
+        let within = |granted: &Option<String>, target: Option<&str>| match granted {
+            None => true,
+            Some(granted) => target == Some(granted.as_str()),
+        };
+        (self.bucket == "*" || self.bucket == bucket)
+            && within(&self.scope, scope)
+            && within(&self.collection, collection)
+    }
+
+    fn allows(&self, privilege: Privilege) -> bool {
+        self.privileges
+            .iter()
+            .any(|&granted| granted == privilege || granted == Privilege::Admin)
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct User {
+    pub password: String,
+    #[serde(default)]
+    pub grants: Vec<Grant>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct Rbac {
+    users: HashMap<String, User>,
+}
+
+impl Rbac {
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, RbacError> {
+        Self::from_json(&std::fs::read_to_string(path)?)
+    }
+
+    pub fn from_json(json: &str) -> Result<Self, RbacError> {
+        let rbac: Rbac = serde_json::from_str(json)?;
+        for (name, user) in &rbac.users {
+            if user
+                .grants
+                .iter()
+                .any(|grant| grant.scope.is_none() && grant.collection.is_some())
+            {
+                return Err(RbacError::CollectionWithoutScope(name.clone()));
+            }
+        }
+        Ok(rbac)
+    }
+
+    pub fn authenticate(&self, user: &str, password: &str) -> bool {
+        self.users
+            .get(user)
+            .is_some_and(|user| user.password == password)
+    }
+
+    /// Whether the user has the privilege on the collection, or on the
+    /// scope or bucket as a whole if those are None
+    pub fn check(
+        &self,
+        user: &str,
+        privilege: Privilege,
+        bucket: &str,
+        scope: Option<&str>,
+        collection: Option<&str>,
+    ) -> bool {
+        self.grants(user)
+            .any(|grant| grant.covers(bucket, scope, collection) && grant.allows(privilege))
+    }
+
+    /// Whether the user has any privilege within the bucket, which they
+    /// need to select it
+    pub fn can_access_bucket(&self, user: &str, bucket: &str) -> bool {
+        self.grants(user).any(|grant| {
+            (grant.bucket == "*" || grant.bucket == bucket) && !grant.privileges.is_empty()
+        })
+    }
+
+    fn grants(&self, user: &str) -> impl Iterator<Item = &Grant> {
+        self.users
+            .get(user)
+            .into_iter()
+            .flat_map(|user| &user.grants)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_check() {
+        let rbac = Rbac::from_json(
+            r#"{
+                "users": {
+                    "alice": {
+                        "password": "secret",
+                        "grants": [
+                            { "bucket": "travel", "scope": "inventory", "privileges": ["read", "write"] },
+                            { "bucket": "travel", "scope": "tenants", "collection": "users", "privileges": ["read"] },
+                            { "bucket": "*", "privileges": ["stats"] }
+                        ]
+                    },
+                    "admin": {
+                        "password": "password",
+                        "grants": [{ "bucket": "travel", "privileges": ["admin"] }]
+                    }
+                }
+            }"#,
+        )
+        .unwrap();
+        assert!(rbac.authenticate("alice", "secret"));
+        assert!(!rbac.authenticate("alice", "password"));
+        assert!(!rbac.authenticate("bob", ""));
+
+        // On a collection in the travel bucket
+        let alice = |privilege, scope, collection| {
+            rbac.check("alice", privilege, "travel", Some(scope), Some(collection))
+        };
+        assert!(alice(Privilege::Write, "inventory", "hotels"));
+        assert!(!alice(Privilege::Dcp, "inventory", "hotels"));
+        assert!(alice(Privilege::Read, "tenants", "users"));
+        assert!(!alice(Privilege::Write, "tenants", "users"));
+        assert!(!alice(Privilege::Read, "tenants", "agents"));
+        // A scope grant doesn't cover the bucket as a whole
+        assert!(!rbac.check("alice", Privilege::Read, "travel", None, None));
+        assert!(rbac.check("alice", Privilege::Stats, "beer", None, None));
+        let beer = Some("_default");
+        assert!(!rbac.check("alice", Privilege::Read, "beer", beer, beer));
+
+        assert!(rbac.check("admin", Privilege::Dcp, "travel", None, None));
+        assert!(!rbac.check("admin", Privilege::Read, "beer", None, None));
+        assert!(rbac.can_access_bucket("admin", "travel"));
+        assert!(!rbac.can_access_bucket("admin", "beer"));
+        assert!(!rbac.check("bob", Privilege::Read, "travel", None, None));
+
+        assert!(matches!(
+            Rbac::from_json(
+                r#"{"users": {"carol": {"password": "", "grants": [
+                    { "bucket": "travel", "collection": "users", "privileges": ["read"] }
+                ]}}}"#
+            ),
+            Err(RbacError::CollectionWithoutScope(user)) if user == "carol"
+        ));
+    }
+}
@@ -0,0 +1,221 @@
+//! How long the server takes to handle each opcode, as memcached's timings
+//! stats. Each connection records its latencies into histograms of its
+//! own, without locking, and folds them into the server's every aggregation
+//! interval and when it closes, so the stats lag by at most the interval.
+
+use memcached_codec::Opcode;
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+/// Bucket 0 counts latencies under a microsecond, bucket i those from
+/// 2^(i-1) up to 2^i microseconds, and the last everything longer
+const BUCKETS: usize = 32;
+
+/// How often a connection folds its latencies into the server's
+pub const AGGREGATION_INTERVAL: Duration = Duration::from_secs(1);
+
+/// The percentiles reported, with the names of their stats
+const PERCENTILES: [(f64, &str); 4] = [(0.5, "p50"), (0.9, "p90"), (0.99, "p99"), (0.999, "p999")];
+
+/// Counts of latencies in power of two buckets of microseconds
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Histogram {
+    buckets: [u64; BUCKETS],
+    count: u64,
+    total_us: u64,
+    max_us: u64,
+}
+
+impl Histogram {
+    pub fn record(&mut self, latency: Duration) {
+        let us = latency.as_micros().min(u64::MAX as u128) as u64;
+        let bucket = (u64::BITS - us.leading_zeros()) as usize;
+        self.buckets[bucket.min(BUCKETS - 1)] += 1;
+        self.count += 1;
+        self.total_us = self.total_us.saturating_add(us);
+        self.max_us = self.max_us.max(us);
+    }
+
+    pub fn merge(&mut self, other: &Histogram) {
+        for (bucket, count) in self.buckets.iter_mut().zip(&other.buckets) {
+            *bucket += count;
+        }
+        self.count += other.count;
+        self.total_us = self.total_us.saturating_add(other.total_us);
+        self.max_us = self.max_us.max(other.max_us);
+    }
+
+    pub fn count(&self) -> u64 {
+        self.count
+    }
+
+    pub fn max_us(&self) -> u64 {
+        self.max_us
+    }
+
+    pub fn mean_us(&self) -> u64 {
+        self.total_us.checked_div(self.count).unwrap_or(0)
+    }
+
+    /// The latency in microseconds within which the fraction p of the
+    /// operations completed, to the top of its bucket
+    pub fn percentile_us(&self, p: f64) -> u64 {
+        if self.count == 0 {
+            return 0;
+        }
+        let rank = ((self.count as f64 * p).ceil() as u64).max(1);
+        let mut seen = 0;
+        for (bucket, count) in self.buckets.iter().enumerate() {
+            seen += count;
+            if seen >= rank {
+                let top = (1u64 << bucket) - 1;
+                return top.min(self.max_us);
+            }
+        }
+        self.max_us
+    }
+}
+
+/// The server's latencies, by opcode
+#[derive(Debug, Default)]
+pub struct Timings {
+    histograms: Mutex<HashMap<Opcode, Histogram>>,
+}
+
+impl Timings {
+    pub fn new() -> Arc<Self> {
+        Arc::new(Self::default())
+    }
+
+    /// The latencies of the opcode folded in so far
+    pub fn get(&self, opcode: Opcode) -> Option<Histogram> {
+        self.histograms.lock().unwrap().get(&opcode).cloned()
+    }
+
+    fn merge(&self, histograms: &mut HashMap<Opcode, Histogram>) {
+        let mut merged = self.histograms.lock().unwrap();
+        for (opcode, histogram) in histograms.drain() {
+            merged.entry(opcode).or_default().merge(&histogram);
+        }
+    }
+
+    /// The timings stats group: for each opcode handled, its count and
+    /// its mean, percentile and max latencies in microseconds
+    pub fn get_stats(&self, add_stat: &mut dyn FnMut(&str, &str)) {
+        let histograms = self.histograms.lock().unwrap();
+        for (opcode, histogram) in histograms.iter() {
+            let name = opcode_name(*opcode);
+            add_stat(&format!("{name}_count"), &histogram.count().to_string());
+            add_stat(&format!("{name}_mean_us"), &histogram.mean_us().to_string());
+            for (p, stat) in PERCENTILES {
+                add_stat(
+                    &format!("{name}_{stat}_us"),
+                    &histogram.percentile_us(p).to_string(),
+                );
+            }
+            add_stat(&format!("{name}_max_us"), &histogram.max_us().to_string());
+        }
+    }
+}
+
+/// The opcode's name in stats, such as dcp_mutation
+pub fn opcode_name(opcode: Opcode) -> String {
+    let mut name = String::new();
+    for c in format!("{opcode:?}").chars() {
+        if c.is_ascii_uppercase() && !name.is_empty() {
+            name.push('_');
+        }
+        name.push(c.to_ascii_lowercase());
+    }
+    name
+}
+
+/// A connection's latencies not yet folded into the server's
+pub struct ConnectionTimings {
+    timings: Arc<Timings>,
+    interval: Duration,
+    histograms: HashMap<Opcode, Histogram>,
+    last_aggregated: Instant,
+}
+
+impl ConnectionTimings {
+    pub fn new(timings: Arc<Timings>, interval: Duration) -> Self {
+        Self {
+            timings,
+            interval,
+            histograms: HashMap::new(),
+            last_aggregated: Instant::now(),
+        }
+    }
+
+    pub fn record(&mut self, opcode: Opcode, latency: Duration) {
+        self.histograms.entry(opcode).or_default().record(latency);
+        if self.last_aggregated.elapsed() >= self.interval {
+            self.timings.merge(&mut self.histograms);
+            self.last_aggregated = Instant::now();
+        }
+    }
+}
+
+impl Drop for ConnectionTimings {
+    fn drop(&mut self) {
+        self.timings.merge(&mut self.histograms);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_histogram() {
+        let mut histogram = Histogram::default();
+        assert_eq!(histogram.percentile_us(0.5), 0);
+        for us in 1..=100 {
+            histogram.record(Duration::from_micros(us));
+        }
+        histogram.record(Duration::from_millis(10));
+        assert_eq!(histogram.count(), 101);
+        assert_eq!(histogram.max_us(), 10_000);
+        assert_eq!(histogram.mean_us(), (5050 + 10_000) / 101);
+        // 51 is in the bucket from 32 to 63
+        assert_eq!(histogram.percentile_us(0.5), 63);
+        assert_eq!(histogram.percentile_us(0.99), 127);
+        assert_eq!(histogram.percentile_us(1.0), 10_000);
+
+        let mut merged = Histogram::default();
+        merged.record(Duration::from_nanos(10));
+        merged.merge(&histogram);
+        assert_eq!(merged.count(), 102);
+        assert_eq!(merged.percentile_us(0.0), 0);
+    }
+
+    #[test]
+    fn test_aggregation() {
+        let timings = Timings::new();
+        let mut connection = ConnectionTimings::new(timings.clone(), Duration::from_secs(3600));
+        connection.record(Opcode::Get, Duration::from_micros(5));
+        connection.record(Opcode::DcpMutation, Duration::from_micros(5));
+        // Not until the interval passes, or the connection closes
+        assert!(timings.get(Opcode::Get).is_none());
+        drop(connection);
+        assert_eq!(timings.get(Opcode::Get).unwrap().count(), 1);
+
+        let mut connection = ConnectionTimings::new(timings.clone(), Duration::ZERO);
+        connection.record(Opcode::Get, Duration::from_micros(9));
+        assert_eq!(timings.get(Opcode::Get).unwrap().count(), 2);
+
+        let mut stats = HashMap::new();
+        timings.get_stats(&mut |key, value| {
+            stats.insert(key.to_string(), value.to_string());
+        });
+        assert_eq!(stats["get_count"], "2");
+        // The top of the bucket from 4 to 7
+        assert_eq!(stats["get_p50_us"], "7");
+        assert_eq!(stats["get_max_us"], "9");
+        assert_eq!(stats["dcp_mutation_count"], "1");
+    }
+}
@@ -3,3 +3,15 @@
 /// Can be used in a compare and swap loop to safely mutate a document concurrently
 #[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
 pub struct Cas(pub(crate) u64);
+
+impl From<u64> for Cas {
+    fn from(cas: u64) -> Self {
+        Cas(cas)
+    }
+}
+
+impl From<Cas> for u64 {
+    fn from(cas: Cas) -> Self {
+        cas.0
+    }
+}
@@ -17,6 +17,12 @@ pub enum McbpDecodeError {
     MissingStatus,
     #[error("missing vbucket")]
     MissingVbucket,
+    #[error("invalid extras length ({0})")]
+    InvalidExtrasLength(usize),
+    /// A field of the extras holds a value it can't take, such as an
+    /// unknown enum
+    #[error("invalid extras value ({0})")]
+    InvalidExtrasValue(u32),
     #[error(transparent)]
     Io {
         #[from]
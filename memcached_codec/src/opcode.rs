@@ -1,7 +1,7 @@
 use crate::{error::McbpDecodeError, magic::Magic};
 
 /// Different types of operations that can be sent
-#[derive(Debug, PartialEq, Eq, Copy, Clone)]
+#[derive(Debug, PartialEq, Eq, Hash, Copy, Clone)]
 pub enum Opcode {
     // Client
     Get,
@@ -14,11 +14,16 @@ pub enum Opcode {
     SaslAuth,
     SaslStep,
     SelectBucket,
+    Stat,
     GetCollectionsManifest,
     GetCollectionId,
     GetScopeId,
     GetClusterConfig,
     GetErrorMap,
+    SetWithMeta,
+    DelWithMeta,
+    GetLocked,
+    UnlockKey,
 
     // DCP
     DcpOpenConnection,
@@ -60,10 +65,15 @@ impl From<Opcode> for u8 {
             Opcode::SaslListMechs => 0x20,
             Opcode::SaslAuth => 0x21,
             Opcode::SaslStep => 0x22,
+            Opcode::Stat => 0x10,
             Opcode::GetCollectionsManifest => 0xba,
             Opcode::GetCollectionId => 0xbb,
             Opcode::GetScopeId => 0xbc,
             Opcode::GetErrorMap => 0xfe,
+            Opcode::SetWithMeta => 0xa2,
+            Opcode::DelWithMeta => 0xa8,
+            Opcode::GetLocked => 0x94,
+            Opcode::UnlockKey => 0x95,
             Opcode::SelectBucket => 0x89,
             Opcode::GetClusterConfig => 0xb5,
 
@@ -112,6 +122,7 @@ impl Opcode {
             0x02 => Opcode::Insert,
             0x03 => Opcode::Replace,
             0x04 => Opcode::Remove,
+            0x10 => Opcode::Stat,
             0x1f => Opcode::Hello,
             0x20 => Opcode::SaslListMechs,
             0x21 => Opcode::SaslAuth,
@@ -122,6 +133,10 @@ impl Opcode {
             0xbc => Opcode::GetScopeId,
             0xb5 => Opcode::GetClusterConfig,
             0xfe => Opcode::GetErrorMap,
+            0xa2 => Opcode::SetWithMeta,
+            0xa8 => Opcode::DelWithMeta,
+            0x94 => Opcode::GetLocked,
+            0x95 => Opcode::UnlockKey,
 
             // DCP
             0x50 => Opcode::DcpOpenConnection,
@@ -175,7 +190,15 @@ impl Opcode {
     pub fn is_collection_command(&self) -> bool {
         matches!(
             self,
-            Opcode::Get | Opcode::Upsert | Opcode::Insert | Opcode::Replace | Opcode::Remove
+            Opcode::Get
+                | Opcode::Upsert
+                | Opcode::Insert
+                | Opcode::Replace
+                | Opcode::Remove
+                | Opcode::SetWithMeta
+                | Opcode::DelWithMeta
+                | Opcode::GetLocked
+                | Opcode::UnlockKey
         )
     }
 
@@ -17,9 +17,18 @@ pub enum Status {
     /// The server is not responsible for the requested vbucket
     NotMyVBucket,
 
+    /// The key is locked by GetLocked and the request didn't give its CAS
+    Locked,
+
+    /// UnlockKey was sent for a key which isn't locked
+    NotLocked,
+
     /// Could not authenticate successfully
     AuthenticationError,
 
+    /// The authenticated user doesn't have the privilege the command needs
+    NoAccess,
+
     /// An error we don't know about. Use the error map returned from the server to decode the status
     Unknown(u16),
 }
@@ -32,7 +41,10 @@ impl From<Status> for u16 {
             Status::KeyExists => 0x0002,
             Status::InvalidArguments => 0x0004,
             Status::NotMyVBucket => 0x0007,
+            Status::Locked => 0x0009,
+            Status::NotLocked => 0x000e,
             Status::AuthenticationError => 0x0020,
+            Status::NoAccess => 0x0024,
             Status::Unknown(status) => status,
         }
     }
@@ -46,7 +58,10 @@ impl From<u16> for Status {
             0x0002 => Status::KeyExists,
             0x0004 => Status::InvalidArguments,
             0x0007 => Status::NotMyVBucket,
+            0x0009 => Status::Locked,
+            0x000e => Status::NotLocked,
             0x0020 => Status::AuthenticationError,
+            0x0024 => Status::NoAccess,
             _ => Status::Unknown(status),
         }
     }